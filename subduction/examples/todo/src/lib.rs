@@ -0,0 +1,11 @@
+//! End-to-end example: a collaborative todo list.
+//!
+//! Browsers run the WASM bindings with the UI in `www/`, keeping the list in
+//! IndexedDB and syncing it through the relay that `cargo run -p subduction_todo`
+//! starts, which also serves the UI. See the README for a walkthrough.
+//!
+//! * [`map`]: the list's payload, shared by the UI and native peers
+//! * [`relay`]: a relay that accepts any number of peers, and the UI's file server
+
+pub mod map;
+pub mod relay;
@@ -0,0 +1,43 @@
+//! Run the todo example: a relay for the list, and a server for its UI.
+
+use std::{net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+use subduction_todo::relay;
+use tokio::net::TcpListener;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| "subduction_todo=info".into()),
+        )
+        .init();
+
+    let args = Arguments::parse();
+    let listener = TcpListener::bind(args.ws).await?;
+    tracing::info!("Relaying todo lists on ws://{}", args.ws);
+
+    tokio::try_join!(
+        relay::serve(relay::relay(), listener),
+        relay::serve_files(args.www, args.http),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Relay and UI for the collaborative todo example")]
+struct Arguments {
+    /// Where peers connect to the relay.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    ws: SocketAddr,
+
+    /// Where to serve the UI.
+    #[arg(long, default_value = "127.0.0.1:8000")]
+    http: SocketAddr,
+
+    /// The UI's files, including the `pkg/` built by `wasm-pack`.
+    #[arg(long, default_value = concat!(env!("CARGO_MANIFEST_DIR"), "/www"))]
+    www: PathBuf,
+}
@@ -0,0 +1,206 @@
+//! A map document built from commits, the payload of the todo list.
+//!
+//! Each commit carries one [`MapOp`] as JSON, setting or deleting one key. A
+//! [`MapDoc`] is what replaying a document's commits in causal order (parents
+//! first, ties broken by digest) leaves behind, so every peer holding the same
+//! commits sees the same map, whatever order they arrived in. The todo list
+//! keeps one key per item, whose value is `{ "title": ..., "done": ... }`.
+//!
+//! The browser UI in `www/` writes the same JSON, so the two interoperate.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use sedimentree_core::{Blob, BlobMeta, Digest, LooseCommit};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// A change to one key of a [`MapDoc`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "camelCase")]
+pub enum MapOp {
+    /// Set `key` to `value`.
+    Set {
+        /// The key to set.
+        key: String,
+
+        /// Its new value.
+        value: Value,
+    },
+
+    /// Remove `key`.
+    Delete {
+        /// The key to remove.
+        key: String,
+    },
+}
+
+impl MapOp {
+    /// The JSON a commit carries for this change.
+    ///
+    /// # Errors
+    ///
+    /// If the value can't be encoded as JSON.
+    pub fn encode(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// Read the change a commit carries.
+    ///
+    /// # Errors
+    ///
+    /// If `contents` isn't a change encoded by [`MapOp::encode`].
+    pub fn decode(contents: &[u8]) -> Result<Self, serde_json::Error> {
+        serde_json::from_slice(contents)
+    }
+
+    /// A commit making this change on top of `parents`, and its blob.
+    ///
+    /// The digest covers the parents' hashes as well as the contents, as the WASM
+    /// bindings' `commitHash` does, so making the same change twice gives two
+    /// distinct commits.
+    ///
+    /// # Errors
+    ///
+    /// If the value can't be encoded as JSON.
+    pub fn commit(&self, parents: Vec<Digest>) -> Result<(LooseCommit, Blob), serde_json::Error> {
+        let contents = self.encode()?;
+        let mut hashed = parents
+            .iter()
+            .flat_map(|parent| parent.to_string().into_bytes())
+            .collect::<Vec<_>>();
+        hashed.extend_from_slice(&contents);
+        let commit = LooseCommit::new(Digest::hash(&hashed), parents, BlobMeta::new(&contents));
+        Ok((commit, Blob::new(contents)))
+    }
+}
+
+/// A map from string keys to JSON values, built from [`MapOp`] commits.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MapDoc {
+    entries: BTreeMap<String, Value>,
+}
+
+impl MapDoc {
+    /// Replay `commits`, with their blobs, in causal order.
+    ///
+    /// # Errors
+    ///
+    /// If a blob isn't a [`MapOp`].
+    pub fn replay(commits: &[(LooseCommit, Blob)]) -> Result<Self, serde_json::Error> {
+        let mut doc = Self::default();
+        for index in causal_order(commits.iter().map(|(commit, _)| commit)) {
+            doc.apply(MapOp::decode(commits[index].1.as_slice())?);
+        }
+        Ok(doc)
+    }
+
+    /// Apply one change.
+    pub fn apply(&mut self, op: MapOp) {
+        match op {
+            MapOp::Set { key, value } => {
+                self.entries.insert(key, value);
+            }
+            MapOp::Delete { key } => {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// The value of `key`, if it's set.
+    #[must_use]
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.entries.get(key)
+    }
+
+    /// Every key and its value, in key order.
+    #[must_use]
+    pub const fn entries(&self) -> &BTreeMap<String, Value> {
+        &self.entries
+    }
+}
+
+/// The commits among `commits` that no other commit has as a parent, which a new
+/// commit should name as its parents.
+pub fn heads<'a>(commits: impl IntoIterator<Item = &'a LooseCommit>) -> Vec<Digest> {
+    let commits = commits.into_iter().collect::<Vec<_>>();
+    let parents = commits
+        .iter()
+        .flat_map(|commit| commit.parents().iter().copied())
+        .collect::<BTreeSet<_>>();
+    commits
+        .iter()
+        .map(|commit| commit.digest())
+        .filter(|digest| !parents.contains(digest))
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// The indices of `commits`, each after those of its parents, ties broken by digest.
+fn causal_order<'a>(commits: impl IntoIterator<Item = &'a LooseCommit>) -> Vec<usize> {
+    let commits = commits.into_iter().collect::<Vec<_>>();
+    let index = commits
+        .iter()
+        .enumerate()
+        .map(|(i, commit)| (commit.digest(), i))
+        .collect::<HashMap<_, _>>();
+
+    let mut waiting_on = vec![0usize; commits.len()];
+    let mut children = vec![Vec::new(); commits.len()];
+    for (i, commit) in commits.iter().enumerate() {
+        for parent in commit.parents() {
+            if let Some(&parent) = index.get(parent) {
+                waiting_on[i] += 1;
+                children[parent].push(i);
+            }
+        }
+    }
+
+    let mut ready = (0..commits.len())
+        .filter(|i| waiting_on[*i] == 0)
+        .map(|i| (commits[i].digest(), i))
+        .collect::<BTreeSet<_>>();
+    let mut ordered = Vec::with_capacity(commits.len());
+    while let Some((_, i)) = ready.pop_first() {
+        ordered.push(i);
+        for &child in &children[i] {
+            waiting_on[child] -= 1;
+            if waiting_on[child] == 0 {
+                ready.insert((commits[child].digest(), child));
+            }
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn replay_is_independent_of_arrival_order() -> Result<(), serde_json::Error> {
+        let create = MapOp::Set {
+            key: "milk".into(),
+            value: json!({ "title": "Buy milk", "done": false }),
+        }
+        .commit(vec![])?;
+        let finish = MapOp::Set {
+            key: "milk".into(),
+            value: json!({ "title": "Buy milk", "done": true }),
+        }
+        .commit(vec![create.0.digest()])?;
+        let remove = MapOp::Delete { key: "milk".into() }.commit(vec![finish.0.digest()])?;
+
+        let in_order = MapDoc::replay(&[create.clone(), finish.clone()])?;
+        let reversed = MapDoc::replay(&[finish.clone(), create.clone()])?;
+        assert_eq!(in_order, reversed);
+        assert_eq!(in_order.get("milk"), Some(&json!({ "title": "Buy milk", "done": true })));
+
+        let removed = MapDoc::replay(&[remove.clone(), create.clone(), finish.clone()])?;
+        assert!(removed.entries().is_empty());
+        assert_eq!(heads([&create.0, &finish.0, &remove.0]), vec![remove.0.digest()]);
+        Ok(())
+    }
+}
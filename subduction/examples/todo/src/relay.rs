@@ -0,0 +1,170 @@
+//! A relay for the todo list, and a file server for its UI.
+//!
+//! `subduction_cli start` serves a single WebSocket connection. This relay
+//! accepts any number of peers instead, knowing each by the key it proves it holds
+//! when it connects, so that what one peer pushes is forwarded to all the others. A connection is
+//! dropped once its socket closes, and a peer that comes back simply connects
+//! again and batch syncs to catch up.
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use async_tungstenite::tokio::accept_async;
+use sedimentree_core::{future::Sendable, storage::MemoryStorage};
+use subduction_core::{
+    connection::{handshake::SigningKey, Connection},
+    Subduction,
+};
+use subduction_websocket::tokio::{server::TokioWebSocketServer, start::Start};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+/// The engine behind the relay.
+pub type Relay = Subduction<Sendable, MemoryStorage, TokioWebSocketServer>;
+
+/// How long the relay waits on a peer's batch sync response.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many bytes of a peer's messages the relay holds before the peer must wait.
+const RECEIVE_WINDOW: u64 = 1024 * 1024;
+
+/// An empty relay.
+#[must_use]
+pub fn relay() -> Arc<Relay> {
+    Arc::new(Subduction::new(
+        Default::default(),
+        MemoryStorage::default(),
+        Default::default(),
+    ))
+}
+
+/// Accept peers on `listener` and relay between them, until an error stops the relay.
+///
+/// # Errors
+///
+/// If accepting a connection or relaying a message fails.
+pub async fn serve(relay: Arc<Relay>, listener: TcpListener) -> anyhow::Result<()> {
+    tokio::try_join!(accept_peers(relay.clone(), listener), async {
+        relay.run().await.map_err(|e| anyhow::anyhow!("{e}"))
+    })?;
+    Ok(())
+}
+
+/// Accept peer connections to `relay`, disconnecting each once its socket closes.
+async fn accept_peers(relay: Arc<Relay>, listener: TcpListener) -> anyhow::Result<()> {
+    let bound = listener.local_addr()?;
+    let key = SigningKey::from_bytes(&rand::random());
+    loop {
+        let (tcp, address) = listener.accept().await?;
+        let ws_stream = match accept_async(tcp).await {
+            Ok(ws_stream) => ws_stream,
+            Err(e) => {
+                tracing::warn!("WebSocket handshake with {address} failed: {e}");
+                continue;
+            }
+        };
+
+        let conn = match TokioWebSocketServer::new(bound, TIMEOUT, &key, ws_stream).await {
+            Ok(conn) => conn.with_receive_window(RECEIVE_WINDOW).ignore(),
+            Err(e) => {
+                tracing::warn!("Handshake with {address} failed: {e}");
+                continue;
+            }
+        };
+        let peer_id = conn.peer_id();
+        let listening = conn.start();
+        let (_, conn_id) = relay.register(conn).await?;
+        tracing::info!("Peer {peer_id} connected from {address}");
+        tokio::spawn({
+            let relay = relay.clone();
+            async move {
+                let _closed = listening.await;
+                tracing::info!("Peer {peer_id} disconnected");
+                relay.disconnect(&conn_id).await
+            }
+        });
+    }
+}
+
+/// Serve the files under `root` over HTTP on `address`.
+///
+/// # Errors
+///
+/// If `address` can't be bound.
+pub async fn serve_files(root: PathBuf, address: SocketAddr) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving {} on http://{address}", root.display());
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        let root = root.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_file(&root, tcp).await {
+                tracing::warn!("request from {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_file(root: &Path, tcp: TcpStream) -> io::Result<()> {
+    let (reader, mut writer) = tcp.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or("/");
+    let path = path.split('?').next().unwrap_or_default().trim_start_matches('/');
+    let path = if path.is_empty() { "index.html" } else { path };
+    let (status, content_type, body) = match resolve(root, path) {
+        Some(file) => match tokio::fs::read(&file).await {
+            Ok(body) => ("200 OK", content_type(&file), body),
+            Err(_) => ("404 Not Found", "text/plain", b"not found\n".to_vec()),
+        },
+        None => ("404 Not Found", "text/plain", b"not found\n".to_vec()),
+    };
+
+    writer
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    writer.write_all(&body).await?;
+    writer.flush().await
+}
+
+/// The file `path` names under `root`, unless it tries to leave `root`.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let relative = Path::new(path);
+    relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+        .then(|| root.join(relative))
+}
+
+fn content_type(file: &Path) -> &'static str {
+    match file.extension().and_then(|extension| extension.to_str()) {
+        Some("html") => "text/html; charset=utf-8",
+        Some("js" | "mjs") => "text/javascript",
+        Some("wasm") => "application/wasm",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,208 @@
+//! Two native peers keeping a todo list in sync through the example relay: edits
+//! forwarded live, edits queued while offline, and catching up after reconnecting.
+
+use std::{sync::Arc, time::Duration};
+
+use sedimentree_core::{future::Sendable, storage::MemoryStorage, Blob, LooseCommit, SedimentreeId};
+use serde_json::json;
+use subduction_core::{
+    connection::{handshake::SigningKey, id::ConnectionId, message::Message, Connection},
+    peer::id::PeerId,
+    Subduction,
+};
+use subduction_todo::{
+    map::{heads, MapDoc, MapOp},
+    relay::{self, Relay},
+};
+use subduction_websocket::tokio::client::TokioWebSocketClient;
+use testresult::TestResult;
+use tokio::{net::TcpListener, task::JoinHandle};
+use tungstenite::http::Uri;
+
+type Engine = Subduction<Sendable, MemoryStorage, TokioWebSocketClient>;
+
+/// How long to wait for an edit to reach another peer.
+const PATIENCE: Duration = Duration::from_secs(10);
+
+/// A native peer editing the todo list.
+struct Peer {
+    engine: Arc<Engine>,
+    author: PeerId,
+    /// The key the peer proves to the relay.
+    key: SigningKey,
+    list: SedimentreeId,
+    online: Option<Online>,
+    /// Edits made while offline, pushed once the peer reconnects.
+    queue: Vec<(LooseCommit, Blob)>,
+}
+
+/// A peer's connection to the relay, and the tasks serving it.
+struct Online {
+    conn_id: ConnectionId,
+    tasks: [JoinHandle<()>; 2],
+}
+
+impl Peer {
+    fn new(list: SedimentreeId) -> Self {
+        Self {
+            engine: Arc::new(Subduction::new(
+                Default::default(),
+                MemoryStorage::default(),
+                Default::default(),
+            )),
+            author: PeerId::new(rand::random()),
+            key: SigningKey::from_bytes(&rand::random()),
+            list,
+            online: None,
+            queue: Vec::new(),
+        }
+    }
+
+    /// Connect to the relay, push the edits queued while offline, and catch up on the
+    /// ones missed.
+    async fn connect(&mut self, uri: &Uri) -> anyhow::Result<()> {
+        let conn = TokioWebSocketClient::new(uri.clone(), PATIENCE, &self.key)
+            .await?
+            .ignore();
+        let listening = tokio::spawn({
+            let conn = conn.clone();
+            async move {
+                let _closed = conn.listen().await;
+            }
+        });
+        let (_, conn_id) = self.engine.register(conn.clone()).await?;
+        let running = tokio::spawn({
+            let engine = self.engine.clone();
+            async move {
+                let _stopped = engine.run().await;
+            }
+        });
+
+        for (commit, blob) in self.queue.drain(..) {
+            conn.send(Message::LooseCommit {
+                id: self.list,
+                commit,
+                blob,
+            })
+            .await?;
+        }
+        self.engine.request_all_batch_sync(self.list, None).await?;
+        self.online = Some(Online {
+            conn_id,
+            tasks: [listening, running],
+        });
+        Ok(())
+    }
+
+    /// Close the connection to the relay.
+    async fn disconnect(&mut self) -> anyhow::Result<()> {
+        if let Some(online) = self.online.take() {
+            for task in online.tasks {
+                task.abort();
+                let _aborted = task.await;
+            }
+            self.engine.disconnect(&online.conn_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Make an edit on top of everything this peer has seen, queuing it if offline.
+    async fn edit(&mut self, op: MapOp) -> anyhow::Result<()> {
+        let commits = self.engine.get_commits(self.list).await.unwrap_or_default();
+        let (commit, blob) = op.commit(heads(&commits))?;
+        self.engine
+            .recv_commit(&self.author, self.list, &commit, blob.clone())
+            .await?;
+        if self.online.is_none() {
+            self.queue.push((commit, blob));
+        }
+        Ok(())
+    }
+
+    /// The list as this peer sees it.
+    async fn doc(&self) -> anyhow::Result<MapDoc> {
+        let mut commits = Vec::new();
+        for commit in self.engine.get_commits(self.list).await.unwrap_or_default() {
+            if let Some(blob) = self.engine.get_local_blob(commit.blob().digest()).await? {
+                commits.push((commit, blob));
+            }
+        }
+        Ok(MapDoc::replay(&commits)?)
+    }
+
+    /// Wait until this peer sees `expected`.
+    async fn sees(&self, expected: &MapDoc) -> anyhow::Result<()> {
+        let waited = tokio::time::timeout(PATIENCE, async {
+            while self.doc().await? != *expected {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            Ok::<_, anyhow::Error>(())
+        })
+        .await;
+        match waited {
+            Ok(seen) => seen,
+            Err(_) => Err(anyhow::anyhow!(
+                "expected {expected:?}, but the peer has {:?}",
+                self.doc().await?
+            )),
+        }
+    }
+}
+
+/// Wait until `relay` has `count` peers connected.
+async fn peers_connected(relay: &Relay, count: usize) -> anyhow::Result<()> {
+    tokio::time::timeout(PATIENCE, async {
+        while relay.peer_ids().await.len() != count {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+fn set(key: &str, title: &str, done: bool) -> MapOp {
+    MapOp::Set {
+        key: key.into(),
+        value: json!({ "title": title, "done": done }),
+    }
+}
+
+#[tokio::test]
+async fn peers_converge_through_the_relay_across_a_reconnect() -> TestResult {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let uri = Uri::try_from(format!("ws://{}", listener.local_addr()?))?;
+    let relay = relay::relay();
+    tokio::spawn(relay::serve(relay.clone(), listener));
+
+    let list = SedimentreeId::new(rand::random());
+    let mut alice = Peer::new(list);
+    let mut bob = Peer::new(list);
+    alice.connect(&uri).await?;
+    bob.connect(&uri).await?;
+
+    // Edits are forwarded live, both ways
+    let mut expected = MapDoc::default();
+    alice.edit(set("milk", "Buy milk", false)).await?;
+    expected.apply(set("milk", "Buy milk", false));
+    bob.sees(&expected).await?;
+
+    bob.edit(set("docs", "Write docs", false)).await?;
+    expected.apply(set("docs", "Write docs", false));
+    alice.sees(&expected).await?;
+
+    // Bob works offline while Alice keeps going
+    bob.disconnect().await?;
+    peers_connected(&relay, 1).await?;
+    bob.edit(set("milk", "Buy milk", true)).await?;
+    alice.edit(MapOp::Delete { key: "docs".into() }).await?;
+    assert_eq!(bob.queue.len(), 1);
+
+    // Reconnecting pushes Bob's queued edit and catches Bob up on Alice's
+    bob.connect(&uri).await?;
+    expected.apply(set("milk", "Buy milk", true));
+    expected.apply(MapOp::Delete { key: "docs".into() });
+    bob.sees(&expected).await?;
+    alice.sees(&expected).await?;
+    assert!(bob.queue.is_empty());
+    Ok(())
+}
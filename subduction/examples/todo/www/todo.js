@@ -0,0 +1,307 @@
+// The todo list UI: two peers, Alice and Bob, each with their own handle.
+//
+// Every edit is a commit carrying one map operation as JSON, the same payload
+// as `MapOp` in `src/map.rs`: `{ op: "set", key, value }` or `{ op: "delete", key }`.
+// The list is what replaying the commits in causal order (parents first, ties
+// broken by hash) leaves behind, so both peers agree on it whatever order the
+// commits arrived in.
+
+import init, { Beelay, bundleCommits, commitHash } from "./pkg/subduction_wasm.js";
+
+const RELAY = `ws://${location.hostname}:8080`;
+const LIST = "todo-list";
+const RETRY_MIN = 500;
+const RETRY_MAX = 10_000;
+
+const encoder = new TextEncoder();
+const decoder = new TextDecoder();
+
+/// A commit making `op` on top of `parents`, hashed over both.
+function commit(op, parents) {
+  const contents = encoder.encode(JSON.stringify(op));
+  return { parents, hash: commitHash(parents, contents), contents: Array.from(contents) };
+}
+
+/// The commits no other commit names as a parent.
+function heads(commits) {
+  const parents = new Set(commits.flatMap((commit) => commit.parents));
+  return commits.map((commit) => commit.hash).filter((hash) => !parents.has(hash)).sort();
+}
+
+/// The map `commits` leave behind, replayed parents first with ties broken by hash.
+function replay(commits) {
+  const byHash = new Map(commits.map((commit) => [commit.hash, commit]));
+  const waiting = new Map();
+  const children = new Map();
+  for (const commit of byHash.values()) {
+    const parents = new Set(commit.parents.filter((parent) => byHash.has(parent)));
+    waiting.set(commit.hash, parents.size);
+    for (const parent of parents) {
+      children.set(parent, [...(children.get(parent) ?? []), commit.hash]);
+    }
+  }
+
+  const entries = new Map();
+  const ready = [...waiting].filter(([, count]) => count === 0).map(([hash]) => hash);
+  while (ready.length > 0) {
+    ready.sort();
+    const hash = ready.shift();
+    const op = JSON.parse(decoder.decode(new Uint8Array(byHash.get(hash).contents)));
+    if (op.op === "set") {
+      entries.set(op.key, op.value);
+    } else if (op.op === "delete") {
+      entries.delete(op.key);
+    }
+    for (const child of children.get(hash) ?? []) {
+      waiting.set(child, waiting.get(child) - 1);
+      if (waiting.get(child) === 0) {
+        ready.push(child);
+      }
+    }
+  }
+  return entries;
+}
+
+/// One person editing the list, in one pane of the page.
+class Peer {
+  constructor(name, pane) {
+    this.name = name;
+    this.pane = pane;
+    this.handle = null;
+    this.online = false;
+    this.wantOnline = true;
+    this.retry = RETRY_MIN;
+    this.pendingRequest = null;
+  }
+
+  /// Edits made while offline, kept across reloads until they reach the relay.
+  get queue() {
+    return JSON.parse(localStorage.getItem(`todo-${this.name}-queue`) ?? "[]");
+  }
+
+  set queue(commits) {
+    localStorage.setItem(`todo-${this.name}-queue`, JSON.stringify(commits));
+  }
+
+  /// The list, if this peer holds it.
+  get docId() {
+    const docId = localStorage.getItem(LIST);
+    const held = this.handle?.listDocuments().some((doc) => doc.docId === docId);
+    return held ? docId : null;
+  }
+
+  /// Open this peer's handle, connected to the relay if the peer wants to be online.
+  ///
+  /// If the relay can't be reached, the handle is opened offline and another
+  /// attempt is scheduled, backing off up to `RETRY_MAX`.
+  async open() {
+    this.handle?.stop();
+    this.handle = null;
+    const databaseName = `todo-${this.name}`;
+    if (this.wantOnline) {
+      try {
+        this.handle = await Beelay.load({
+          storage: "indexeddb",
+          databaseName,
+          syncServerUrl: RELAY,
+        });
+        this.online = true;
+        this.retry = RETRY_MIN;
+      } catch (err) {
+        console.warn(`${this.name} couldn't reach the relay`, err);
+      }
+    }
+    if (this.handle === null) {
+      this.handle = await Beelay.load({ storage: "indexeddb", databaseName });
+      this.online = false;
+      if (this.wantOnline) {
+        setTimeout(() => this.open(), this.retry);
+        this.retry = Math.min(this.retry * 2, RETRY_MAX);
+      }
+    }
+
+    const docId = this.docId;
+    if (docId !== null) {
+      this.handle.on(docId, () => this.render());
+      if (this.online) {
+        await this.flush();
+        await this.handle.waitUntilSynced("");
+      }
+    }
+    this.render();
+  }
+
+  /// Push the edits queued while offline.
+  async flush() {
+    const commits = this.queue;
+    if (commits.length > 0) {
+      await this.handle.addCommits({ docId: this.docId, commits });
+      this.queue = [];
+    }
+  }
+
+  /// Make an edit on top of everything this peer has seen.
+  async edit(op) {
+    const docId = this.docId;
+    if (docId === null) {
+      return;
+    }
+    const made = commit(op, heads(await this.commits()));
+    if (this.online) {
+      await this.handle.addCommits({ docId, commits: [made] });
+    } else {
+      this.queue = [...this.queue, made];
+    }
+    this.render();
+  }
+
+  /// Everything this peer has: the list's stored commits, with any bundles unpacked,
+  /// then its queued edits.
+  async commits() {
+    const docId = this.docId;
+    const stored = docId === null ? [] : await this.handle.loadDocument(docId);
+    const unpacked = stored.flatMap((entry) =>
+      entry.type === "bundle" ? bundleCommits(new Uint8Array(entry.contents)) : [entry],
+    );
+    return [...unpacked, ...this.queue];
+  }
+
+  /// Create the list, as its admin.
+  async create() {
+    const docId = await this.handle.createDoc({
+      initialCommit: commit({ op: "set", key: "title", value: "Our todo list" }, []),
+    });
+    localStorage.setItem(LIST, docId);
+    this.handle.on(docId, () => this.render());
+    this.render();
+  }
+
+  /// Ask the list's admin to let this peer in.
+  ask() {
+    this.pendingRequest = this.handle.requestAccess(
+      localStorage.getItem(LIST),
+      `${this.name} would like to help`,
+    );
+    this.render();
+  }
+
+  /// Let `other` in with write access, handing them the list as it stands.
+  async approve(request, other) {
+    this.handle.approve(request.requestId, "write");
+    const state = this.handle.exportDocState(request.docId);
+    const docId = await other.handle.adoptDocState(state);
+    other.pendingRequest = null;
+    other.handle.on(docId, () => other.render());
+    await other.handle.waitUntilSynced("");
+    this.render();
+    other.render();
+  }
+
+  async render() {
+    const pane = this.pane;
+    pane.querySelector("h2").textContent = this.name;
+    pane.querySelector(".status").textContent = this.online
+      ? "Online"
+      : `Offline, ${this.queue.length} edit(s) queued`;
+
+    const membership = pane.querySelector(".membership");
+    membership.replaceChildren();
+    const docId = this.docId;
+    if (docId === null && localStorage.getItem(LIST) === null) {
+      membership.append(button("Create a list", () => this.create()));
+    } else if (docId === null && this.pendingRequest === null) {
+      membership.append(button("Ask to join", () => this.ask()));
+    } else if (docId === null) {
+      membership.textContent = "Waiting to be let in…";
+    } else {
+      for (const request of this.handle.pendingAccessRequests()) {
+        const other = peers.find((peer) => peer.pendingRequest === request.requestId);
+        if (other !== undefined) {
+          membership.append(
+            `${request.message}. `,
+            button("Approve", () => this.approve(request, other)),
+          );
+        }
+      }
+    }
+
+    const list = pane.querySelector("ul");
+    const entries = replay(await this.commits());
+    list.replaceChildren(
+      ...[...entries]
+        .filter(([key]) => key.startsWith("item:"))
+        .map(([key, item]) => {
+          const li = document.createElement("li");
+          const done = document.createElement("input");
+          done.type = "checkbox";
+          done.checked = item.done;
+          done.onchange = () =>
+            this.edit({ op: "set", key, value: { ...item, done: done.checked } });
+          const title = document.createElement("span");
+          title.textContent = item.title;
+          title.className = item.done ? "done" : "";
+          li.append(done, title, button("✕", () => this.edit({ op: "delete", key })));
+          return li;
+        }),
+    );
+    pane.querySelector("form").hidden = docId === null;
+  }
+
+  mount() {
+    this.pane.append(document.getElementById("pane").content.cloneNode(true));
+    this.pane.querySelector("form").onsubmit = (event) => {
+      event.preventDefault();
+      const input = event.target.elements.title;
+      if (input.value.trim() !== "") {
+        const key = `item:${crypto.randomUUID()}`;
+        this.edit({ op: "set", key, value: { title: input.value.trim(), done: false } });
+        input.value = "";
+      }
+    };
+    this.pane.querySelector(".offline").onchange = (event) => {
+      this.wantOnline = !event.target.checked;
+      this.retry = RETRY_MIN;
+      this.open();
+    };
+  }
+}
+
+function button(label, onclick) {
+  const element = document.createElement("button");
+  element.textContent = label;
+  element.onclick = onclick;
+  return element;
+}
+
+await init();
+const peers = [
+  new Peer("Alice", document.getElementById("alice")),
+  new Peer("Bob", document.getElementById("bob")),
+];
+for (const peer of peers) {
+  peer.mount();
+  await peer.open();
+}
+
+// Access requests travel within the page, so check for new ones now and then
+setInterval(() => peers.forEach((peer) => peer.render()), 1000);
+
+// A handle doesn't reconnect by itself, so reopen any that lost the relay
+setInterval(async () => {
+  for (const peer of peers.filter((peer) => peer.online)) {
+    const { synced } = await peer.handle.waitUntilSynced("");
+    if (!synced) {
+      peer.online = false;
+      peer.retry = RETRY_MIN;
+      peer.open();
+    }
+  }
+}, 5000);
+
+// Reconnect as soon as the browser is back online
+window.addEventListener("online", () => {
+  for (const peer of peers.filter((peer) => peer.wantOnline && !peer.online)) {
+    peer.retry = RETRY_MIN;
+    peer.open();
+  }
+});
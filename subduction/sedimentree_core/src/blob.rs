@@ -56,6 +56,8 @@ impl BlobMeta {
     /// Generate metadata for the given contents.
     #[must_use]
     pub fn new(contents: &[u8]) -> Self {
+        #[cfg(feature = "perf-trace")]
+        let _span = crate::perf::span(crate::perf::Op::BlobHash);
         let digest = Digest::hash(contents);
         let size_bytes = contents.len() as u64;
         Self { digest, size_bytes }
@@ -127,6 +129,8 @@ impl FromStr for Digest {
     type Err = error::InvalidDigest;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(feature = "perf-trace")]
+        let _span = crate::perf::span(crate::perf::Op::DigestParse);
         let bytes = hex::decode(s).map_err(error::InvalidDigest::InvalidHex)?;
         if bytes.len() != 32 {
             return Err(error::InvalidDigest::InvalidLength);
@@ -11,6 +11,8 @@ pub(crate) struct CommitDag {
     nodes: Vec<Node>,
     node_map: HashMap<crate::Digest, NodeIdx>,
     edges: Vec<Edge>,
+    /// The parents of each commit that aren't in the DAG, such as the end of a chunk.
+    outside_parents: HashMap<NodeIdx, Vec<Digest>>,
 }
 
 #[derive(Debug, Clone)]
@@ -56,6 +58,7 @@ impl CommitDag {
             nodes,
             node_map,
             edges: Vec::new(),
+            outside_parents: HashMap::new(),
         };
 
         for commit in commits {
@@ -63,6 +66,11 @@ impl CommitDag {
             for parent in commit.parents() {
                 if let Some(parent) = dag.node_map.get(parent) {
                     dag.add_edge(*parent, child_idx);
+                } else {
+                    dag.outside_parents
+                        .entry(child_idx)
+                        .or_default()
+                        .push(*parent);
                 }
             }
         }
@@ -247,6 +255,7 @@ impl CommitDag {
             nodes,
             node_map,
             edges: Vec::new(),
+            outside_parents: HashMap::new(),
         };
 
         for (child_idx, commit) in remaining_commits.into_iter().enumerate() {
@@ -300,11 +309,11 @@ impl CommitDag {
     }
 
     /// All the commit hashes in this dag plus the stratum in the order in which they should
-    /// be bundled into strata
+    /// be bundled into strata: one sequence for each tip, walking back from it
     pub(crate) fn canonical_sequence<'a, I: Iterator<Item = &'a Chunk> + Clone + 'a>(
         &'a self,
         chunks: I,
-    ) -> impl Iterator<Item = Digest> + 'a {
+    ) -> impl Iterator<Item = impl Iterator<Item = Digest> + 'a> + 'a {
         // First find the tips of the DAG, which is the heads of the commit DAG,
         // plus the end hashes of any chunks which are not contained in the
         // commit DAG
@@ -322,7 +331,7 @@ impl CommitDag {
         // Then for each tip, do a reverse depth first traversal. When we reach
         // a commit which has a parent which is in a stratum, we just extend the
         // traversal with the commits and checkpoints from the given stratum
-        heads.into_iter().flat_map(move |head| {
+        heads.into_iter().map(move |head| {
             let mut stack = vec![head];
             let mut visited = HashSet::new();
             let chunks = chunks.clone();
@@ -336,6 +345,7 @@ impl CommitDag {
                         let mut parents = self
                             .parents(*idx)
                             .map(|i| self.nodes[i.0].hash)
+                            .chain(self.outside_parents.get(idx).into_iter().flatten().copied())
                             .collect::<Vec<_>>();
                         parents.sort();
                         stack.extend(parents);
@@ -346,10 +356,11 @@ impl CommitDag {
                             .collect::<Vec<_>>();
                         supporting_chunks.sort_by_key(|s| s.depth());
                         if let Some(chunk) = supporting_chunks.pop() {
+                            // Its checkpoints are oldest first, so the newest is next
+                            stack.push(chunk.head());
                             for commit in chunk.checkpoints() {
                                 stack.push(*commit);
                             }
-                            stack.push(chunk.head());
                         }
                     }
                     return Some(commit);
@@ -498,7 +509,7 @@ mod tests {
             let mut commits = HashMap::new();
             for (commit_name, commit_hash) in commit_hashes {
                 commits.insert(commit_hash, random_blob(rng));
-                nodes.insert(commit_name.to_string(), commit_hash);
+                nodes.insert(commit_name.clone(), commit_hash);
             }
             let mut parents = HashMap::new();
             #[allow(clippy::panic)]
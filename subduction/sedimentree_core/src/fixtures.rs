@@ -0,0 +1,390 @@
+//! Reproducible document histories for testing against realistic commit graphs.
+//!
+//! A [`Fixture`] is one document's history, generated from a [`Shape`] and a seed.
+//! The same shape and seed always give the same sedimentree ID, commits, and
+//! contents, on every platform. [`corpus`] generates the standard set of fixtures
+//! this crate's tests use, which `subduction_cli fixtures` writes out for apps
+//! to test against.
+//!
+//! Fixtures are saved as snapshot files: a [`StorageHeader`], then the document's
+//! commits in causal order, each with its contents:
+//!
+//! ```text
+//! header | sedimentree ID (32) | commit count (u32 LE) | commits
+//! commit = digest (32) | parent count (u16 LE) | parents (32 each) | contents length (u32 LE) | contents
+//! ```
+
+use thiserror::Error;
+
+use crate::{
+    storage::header::{HeaderError, StorageHeader},
+    Blob, BlobMeta, Digest, LooseCommit, Sedimentree, SedimentreeId,
+};
+
+/// The `created_by` of the snapshot files fixtures are saved as.
+const CREATED_BY: &str = concat!("sedimentree_core/", env!("CARGO_PKG_VERSION"), " fixtures");
+
+/// How many of the latest commits a [`Shape::DeepDag`] commit picks its parents from.
+const DEEP_DAG_WINDOW: usize = 8;
+
+/// The most parents an [`Shape::Adversarial`] merge commit has.
+const ADVERSARIAL_MAX_PARENTS: usize = 64;
+
+/// The shape of a generated history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Shape {
+    /// A single chain of `length` commits.
+    Linear {
+        /// The number of commits.
+        length: usize,
+    },
+
+    /// A root, `width` concurrent children of it, and a commit merging them all.
+    WideMerge {
+        /// The number of concurrent children.
+        width: usize,
+    },
+
+    /// A long history that keeps branching and merging: each commit builds on the one
+    /// before it, plus up to `max_parents - 1` others among the few before that.
+    DeepDag {
+        /// The number of commits.
+        commits: usize,
+
+        /// The most parents a commit has.
+        max_parents: usize,
+    },
+
+    /// A history shaped to trip up naive graph code: many unrelated roots, merges
+    /// of dozens of parents, parents from far back in history, and many commits
+    /// with identical or empty contents.
+    Adversarial {
+        /// The number of commits.
+        commits: usize,
+    },
+}
+
+impl Shape {
+    /// Bytes identifying the shape, to derive a fixture's sedimentree ID from.
+    fn tag(self) -> Vec<u8> {
+        let (kind, params) = match self {
+            Shape::Linear { length } => (0, vec![length]),
+            Shape::WideMerge { width } => (1, vec![width]),
+            Shape::DeepDag {
+                commits,
+                max_parents,
+            } => (2, vec![commits, max_parents]),
+            Shape::Adversarial { commits } => (3, vec![commits]),
+        };
+        let mut tag = vec![kind];
+        for param in params {
+            tag.extend_from_slice(&(param as u64).to_le_bytes());
+        }
+        tag
+    }
+
+    /// The parents of each commit, by index into the commits before it.
+    fn parents(self, rng: &mut Rng) -> Vec<Vec<usize>> {
+        match self {
+            Shape::Linear { length } => (0..length)
+                .map(|i| if i == 0 { vec![] } else { vec![i - 1] })
+                .collect(),
+            Shape::WideMerge { width } => {
+                let mut parents = vec![vec![]];
+                parents.extend((0..width).map(|_| vec![0]));
+                parents.push((1..=width).collect());
+                parents
+            }
+            Shape::DeepDag {
+                commits,
+                max_parents,
+            } => (0..commits)
+                .map(|i| {
+                    if i == 0 {
+                        return vec![];
+                    }
+                    let mut parents = vec![i - 1];
+                    let window = i.min(DEEP_DAG_WINDOW);
+                    for _ in 1..max_parents {
+                        let parent = i - 1 - rng.below(window);
+                        if !parents.contains(&parent) {
+                            parents.push(parent);
+                        }
+                    }
+                    parents
+                })
+                .collect(),
+            Shape::Adversarial { commits } => (0..commits)
+                .map(|i| {
+                    if i % 7 == 0 {
+                        return vec![];
+                    }
+                    let count = if i % 11 == 10 {
+                        i.min(ADVERSARIAL_MAX_PARENTS)
+                    } else {
+                        1
+                    };
+                    let mut parents = Vec::with_capacity(count);
+                    while parents.len() < count {
+                        let parent = rng.below(i);
+                        if !parents.contains(&parent) {
+                            parents.push(parent);
+                        }
+                    }
+                    parents
+                })
+                .collect(),
+        }
+    }
+
+    /// The contents of commit `index`.
+    fn contents(self, index: usize, rng: &mut Rng) -> Vec<u8> {
+        if let Shape::Adversarial { .. } = self {
+            return if index % 3 == 0 {
+                Vec::new()
+            } else {
+                b"identical contents".to_vec()
+            };
+        }
+        let length = 16 + rng.below(241);
+        (0..length).map(|_| rng.next().to_le_bytes()[0]).collect()
+    }
+}
+
+/// A generated document history.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fixture {
+    id: SedimentreeId,
+    commits: Vec<(LooseCommit, Blob)>,
+}
+
+impl Fixture {
+    /// Generate the history of shape `shape` for `seed`.
+    #[must_use]
+    pub fn generate(shape: Shape, seed: u64) -> Self {
+        let mut tag = shape.tag();
+        tag.extend_from_slice(&seed.to_le_bytes());
+        let id = SedimentreeId::new(*Digest::hash(&tag).as_bytes());
+
+        let mut rng = Rng(seed);
+        let mut commits: Vec<(LooseCommit, Blob)> = Vec::new();
+        for (index, parents) in shape.parents(&mut rng).into_iter().enumerate() {
+            let parents = parents
+                .into_iter()
+                .map(|parent| commits[parent].0.digest())
+                .collect::<Vec<_>>();
+            let contents = shape.contents(index, &mut rng);
+
+            let mut preimage = id.as_bytes().to_vec();
+            preimage.extend_from_slice(&(index as u64).to_le_bytes());
+            for parent in &parents {
+                preimage.extend_from_slice(parent.as_bytes());
+            }
+            preimage.extend_from_slice(&contents);
+
+            let commit =
+                LooseCommit::new(Digest::hash(&preimage), parents, BlobMeta::new(&contents));
+            commits.push((commit, Blob::new(contents)));
+        }
+        Self { id, commits }
+    }
+
+    /// The document's sedimentree ID.
+    #[must_use]
+    pub const fn id(&self) -> SedimentreeId {
+        self.id
+    }
+
+    /// The document's commits with their contents, in causal order.
+    #[must_use]
+    pub fn commits(&self) -> &[(LooseCommit, Blob)] {
+        &self.commits
+    }
+
+    /// A [`Sedimentree`] of the document's commits, all loose.
+    #[must_use]
+    pub fn sedimentree(&self) -> Sedimentree {
+        Sedimentree::new(
+            Vec::new(),
+            self.commits
+                .iter()
+                .map(|(commit, _)| commit.clone())
+                .collect(),
+        )
+    }
+
+    /// Encode the fixture as a snapshot file.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn to_snapshot(&self) -> Vec<u8> {
+        let mut bytes = StorageHeader::new(CREATED_BY.to_string()).to_bytes();
+        bytes.extend_from_slice(self.id.as_bytes());
+        bytes.extend_from_slice(&(self.commits.len() as u32).to_le_bytes());
+        for (commit, blob) in &self.commits {
+            bytes.extend_from_slice(commit.digest().as_bytes());
+            bytes.extend_from_slice(&(commit.parents().len() as u16).to_le_bytes());
+            for parent in commit.parents() {
+                bytes.extend_from_slice(parent.as_bytes());
+            }
+            bytes.extend_from_slice(&(blob.as_slice().len() as u32).to_le_bytes());
+            bytes.extend_from_slice(blob.as_slice());
+        }
+        bytes
+    }
+
+    /// Decode a fixture from a snapshot file.
+    ///
+    /// # Errors
+    ///
+    /// * [`SnapshotError`] if the bytes are not a snapshot this version can read.
+    pub fn from_snapshot(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let (_, header_len) = StorageHeader::from_bytes(bytes)?;
+        let mut reader = Reader(&bytes[header_len..]);
+
+        let id = SedimentreeId::new(reader.array()?);
+        let count = u32::from_le_bytes(reader.array()?);
+        let mut commits = Vec::new();
+        for _ in 0..count {
+            let digest = Digest::from(reader.array::<32>()?);
+            let parent_count = u16::from_le_bytes(reader.array()?);
+            let parents = (0..parent_count)
+                .map(|_| reader.array::<32>().map(Digest::from))
+                .collect::<Result<Vec<_>, _>>()?;
+            let length = u32::from_le_bytes(reader.array()?);
+            let contents = reader.take(length as usize)?.to_vec();
+            let commit = LooseCommit::new(digest, parents, BlobMeta::new(&contents));
+            commits.push((commit, Blob::new(contents)));
+        }
+        if !reader.0.is_empty() {
+            return Err(SnapshotError::TrailingBytes);
+        }
+        Ok(Self { id, commits })
+    }
+}
+
+/// The standard corpus for `seed`: one fixture of each [`Shape`], by name.
+#[must_use]
+pub fn corpus(seed: u64) -> Vec<(&'static str, Fixture)> {
+    [
+        ("linear", Shape::Linear { length: 1_000 }),
+        ("wide-merge", Shape::WideMerge { width: 256 }),
+        (
+            "deep-dag",
+            Shape::DeepDag {
+                commits: 2_000,
+                max_parents: 3,
+            },
+        ),
+        ("adversarial", Shape::Adversarial { commits: 500 }),
+    ]
+    .into_iter()
+    .map(|(name, shape)| (name, Fixture::generate(shape, seed)))
+    .collect()
+}
+
+/// A problem reading a snapshot file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum SnapshotError {
+    /// The file doesn't start with a storage header this version can read.
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+
+    /// The file ended partway through a commit.
+    #[error("snapshot is truncated")]
+    Truncated,
+
+    /// The file goes on after its last commit.
+    #[error("snapshot has trailing bytes")]
+    TrailingBytes,
+}
+
+/// Reads a snapshot file from the front.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    const fn take(&mut self, length: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.0.len() < length {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+/// `SplitMix64`, so that histories don't depend on a random number crate's algorithm.
+struct Rng(u64);
+
+impl Rng {
+    const fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number below `bound`, which must not be zero.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_same_history() {
+        let shape = Shape::DeepDag {
+            commits: 100,
+            max_parents: 3,
+        };
+        assert_eq!(Fixture::generate(shape, 7), Fixture::generate(shape, 7));
+        assert_ne!(
+            Fixture::generate(shape, 7).id(),
+            Fixture::generate(shape, 8).id()
+        );
+    }
+
+    #[test]
+    fn corpus_round_trips_through_snapshots() -> Result<(), SnapshotError> {
+        for (name, fixture) in corpus(0) {
+            let decoded = Fixture::from_snapshot(&fixture.to_snapshot())?;
+            assert_eq!(decoded, fixture, "{name}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn shapes_have_their_heads() {
+        let linear = Fixture::generate(Shape::Linear { length: 10 }, 0);
+        assert_eq!(linear.sedimentree().heads().len(), 1);
+
+        let merged = Fixture::generate(Shape::WideMerge { width: 50 }, 0);
+        assert_eq!(merged.sedimentree().heads().len(), 1);
+        assert_eq!(
+            merged.commits().last().map(|(c, _)| c.parents().len()),
+            Some(50)
+        );
+
+        let adversarial = Fixture::generate(Shape::Adversarial { commits: 100 }, 0);
+        assert!(adversarial.sedimentree().heads().len() > 1);
+    }
+
+    #[test]
+    fn truncated_snapshot_is_rejected() {
+        let snapshot = Fixture::generate(Shape::Linear { length: 3 }, 0).to_snapshot();
+        assert_eq!(
+            Fixture::from_snapshot(&snapshot[..snapshot.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+    }
+}
@@ -47,7 +47,11 @@ use std::{
 
 mod blob;
 mod commit_dag;
+#[cfg(any(test, feature = "fixtures"))]
+pub mod fixtures;
 pub mod future;
+#[cfg(feature = "perf-trace")]
+pub mod perf;
 pub mod storage;
 
 pub use blob::*;
@@ -67,6 +71,12 @@ impl SedimentreeId {
     pub const fn new(id: [u8; 32]) -> Self {
         Self(id)
     }
+
+    /// The bytes of this [`SedimentreeId`].
+    #[must_use]
+    pub const fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
 }
 
 /// An error indicating that a [`SedimentreeId`] could not be parsed from a string.
@@ -590,6 +600,18 @@ impl Sedimentree {
         self.loose_commits().any(|c| c.digest() == digest)
     }
 
+    /// Returns true if this [`Sedimentree`] holds `commit` as a loose commit.
+    #[must_use]
+    pub fn contains_commit(&self, commit: &LooseCommit) -> bool {
+        self.commits.contains(commit)
+    }
+
+    /// Returns true if this [`Sedimentree`] holds `chunk`.
+    #[must_use]
+    pub fn contains_chunk(&self, chunk: &Chunk) -> bool {
+        self.chunks.contains(chunk)
+    }
+
     /// Returns true if this [`Sedimentree`] has a chunk starting with the given digest.
     #[must_use]
     pub fn has_chunk_starting_with(&self, digest: Digest) -> bool {
@@ -606,8 +628,10 @@ impl Sedimentree {
         // First sort chunks by depth, then for each stratum below the lowest
         // level, discard that stratum if it is supported by any of the stratum
         // above it.
+        // Deepest first, and of those, the widest, so that each is seen after any
+        // that supports it.
         let mut chunks = self.chunks.iter().collect::<Vec<_>>();
-        chunks.sort_by_key(|a| a.depth());
+        chunks.sort_by_key(|a| std::cmp::Reverse((a.depth(), a.checkpoints().len())));
 
         let mut minimized_chunks = Vec::<Chunk>::new();
 
@@ -640,6 +664,8 @@ impl Sedimentree {
     /// It is useful for sending over the wire.
     #[must_use]
     pub fn summarize(&self) -> SedimentreeSummary {
+        #[cfg(feature = "perf-trace")]
+        let _span = perf::span(perf::Op::SummaryBuild);
         SedimentreeSummary {
             chunk_summaries: self
                 .chunks
@@ -650,26 +676,30 @@ impl Sedimentree {
         }
     }
 
-    /// The heads of a Sedimentree are the end hashes of all strata which are
-    /// not the start of any other strata or supported by any lower stratum
-    /// and which do not appear in the [`LooseCommit`] graph, plus the heads of
-    /// the loose commit graph.
+    /// The heads of a Sedimentree are the end hashes of all strata which no
+    /// other stratum starts from and no [`LooseCommit`] has as a parent, plus
+    /// the heads of the loose commit graph that no stratum starts from.
     #[must_use]
     pub fn heads(&self) -> Vec<Digest> {
         let minimized = self.minimize();
         let dag = commit_dag::CommitDag::from_commits(minimized.commits.iter());
         let mut heads = Vec::<Digest>::new();
         for chunk in &minimized.chunks {
-            if !minimized
-                .chunks
-                .iter()
-                .any(|s| s.boundary().contains(&chunk.head()))
-                && chunk.boundary().iter().all(|end| !dag.contains_commit(end))
-            {
-                heads.extend(chunk.boundary());
+            for end in chunk.boundary() {
+                let built_on = minimized.chunks.iter().any(|other| other.head() == *end)
+                    || minimized
+                        .commits
+                        .iter()
+                        .any(|commit| commit.parents().contains(end));
+                if !built_on && !dag.contains_commit(end) && !heads.contains(end) {
+                    heads.push(*end);
+                }
             }
         }
-        heads.extend(dag.heads());
+        heads.extend(
+            dag.heads()
+                .filter(|head| !minimized.chunks.iter().any(|chunk| chunk.head() == *head)),
+        );
         heads
     }
 
@@ -682,29 +712,52 @@ impl Sedimentree {
     }
 
     /// Given a [`SedimentreeId`], return the [`Chunk`]s that are missing to fill in the gaps.
+    ///
+    /// Each checkpoint ends a run at its own depth and at every shallower one, and is an
+    /// inner checkpoint of the deeper runs it falls in, so the strata nest: a chunk
+    /// supports those of the shallower runs inside it. A run is missing unless a chunk
+    /// at least as deep already covers its end.
     #[must_use]
     pub fn missing_chunks(&self, id: SedimentreeId) -> Vec<ChunkSpec> {
         let dag = commit_dag::CommitDag::from_commits(self.commits.iter());
-        let mut runs_by_level = BTreeMap::<Depth, (Digest, Vec<Digest>)>::new();
-        let mut all_bundles = Vec::new();
-        for commit_hash in dag.canonical_sequence(self.chunks.iter()) {
-            let level = Depth::from(commit_hash);
-            for (run_level, (_start, checkpoints)) in &mut runs_by_level {
-                if run_level < &level {
+        let mut all_bundles = Vec::<ChunkSpec>::new();
+        for sequence in dag.canonical_sequence(self.chunks.iter()) {
+            // The run at each depth: the checkpoint it ends with, and the shallower
+            // checkpoints passed since, newest first
+            let mut runs_by_level = BTreeMap::<Depth, (Digest, Vec<Digest>)>::new();
+            for commit_hash in sequence {
+                let level = Depth::from(commit_hash);
+                if level < crate::MAX_STRATA_DEPTH {
+                    continue;
+                }
+                for (_end, checkpoints) in runs_by_level
+                    .range_mut(Depth(level.0 + 1)..)
+                    .map(|(_, run)| run)
+                {
                     checkpoints.push(commit_hash);
                 }
-            }
-            if level >= crate::MAX_STRATA_DEPTH {
-                if let Some((head, checkpoints)) = runs_by_level.remove(&level) {
-                    if self.chunks.iter().any(|s| s.supports_block(commit_hash)) {
-                        runs_by_level.insert(level, (commit_hash, Vec::new()));
-                    } else {
-                        all_bundles.push(ChunkSpec {
-                            id,
-                            head,
-                            boundary: nonempty![commit_hash], // FIXME could be more than one, right?
-                            checkpoints: checkpoints.clone(),
-                        });
+                for run_level in (crate::MAX_STRATA_DEPTH.0..=level.0).map(Depth) {
+                    let Some((end, mut checkpoints)) =
+                        runs_by_level.insert(run_level, (commit_hash, Vec::new()))
+                    else {
+                        continue;
+                    };
+                    if self
+                        .chunks
+                        .iter()
+                        .any(|s| s.depth() >= run_level && s.supports_block(end))
+                    {
+                        continue;
+                    }
+                    checkpoints.reverse();
+                    let bundle = ChunkSpec {
+                        id,
+                        head: commit_hash,
+                        boundary: nonempty![end], // FIXME could be more than one, right?
+                        checkpoints,
+                    };
+                    if !all_bundles.contains(&bundle) {
+                        all_bundles.push(bundle);
                     }
                 }
             }
@@ -725,7 +778,7 @@ impl Sedimentree {
 }
 
 /// The barest information needed to identify a chunk.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ChunkSpec {
     id: SedimentreeId,
     head: Digest,
@@ -983,4 +1036,142 @@ mod tests {
                 assert_eq!(tree, minimized);
             });
     }
+
+    /// A digest whose depth is the number of trailing zeros of `n` in base 10.
+    fn digest(n: u64) -> Digest {
+        let mut bytes = [0; 32];
+        bytes[24..].copy_from_slice(&n.to_be_bytes());
+        Digest::from(bytes)
+    }
+
+    /// A chain of commits, each the parent of the next.
+    fn chain(hashes: &[u64], parent: Option<u64>) -> Vec<LooseCommit> {
+        let mut parent = parent.map(digest);
+        hashes
+            .iter()
+            .map(|&n| {
+                let commit =
+                    LooseCommit::new(digest(n), parent.into_iter().collect(), BlobMeta::new(&[]));
+                parent = Some(digest(n));
+                commit
+            })
+            .collect()
+    }
+
+    fn chunk(spec: &ChunkSpec) -> Chunk {
+        Chunk::new(
+            spec.head(),
+            spec.boundary().clone(),
+            spec.checkpoints().clone(),
+            BlobMeta::new(&[]),
+        )
+    }
+
+    #[test]
+    fn missing_chunks_cover_runs_between_checkpoints() {
+        let id = SedimentreeId::new([1; 32]);
+        let mut tree = Sedimentree::new(Vec::new(), chain(&[1, 200, 301, 402, 500, 601], None));
+
+        let missing = tree.missing_chunks(id);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].head(), digest(200));
+        assert_eq!(missing[0].boundary(), &nonempty![digest(500)]);
+
+        tree.add_chunk(chunk(&missing[0]));
+        let tree = tree.minimize();
+        let loose = tree
+            .loose_commits()
+            .map(LooseCommit::digest)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(loose, BTreeSet::from([digest(1), digest(200), digest(601)]));
+        assert_eq!(tree.heads(), vec![digest(601)]);
+        assert!(tree.missing_chunks(id).is_empty());
+    }
+
+    #[test]
+    fn missing_chunks_start_where_a_chunk_ends() {
+        let id = SedimentreeId::new([1; 32]);
+        let mut tree = Sedimentree::new(Vec::new(), chain(&[1, 200, 301, 500], None));
+        let first = tree.missing_chunks(id);
+        tree.add_chunk(chunk(&first[0]));
+        let mut tree = tree.minimize();
+        assert_eq!(tree.heads(), vec![digest(500)]);
+
+        for commit in chain(&[601, 700, 801], Some(500)) {
+            tree.add_commit(commit);
+        }
+        let missing = tree.missing_chunks(id);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(missing[0].head(), digest(500));
+        assert_eq!(missing[0].boundary(), &nonempty![digest(700)]);
+        assert_eq!(tree.heads(), vec![digest(801)]);
+    }
+
+    #[test]
+    fn strata_nest() {
+        let id = SedimentreeId::new([1; 32]);
+        let commits = chain(&[1, 1000, 1001, 1100, 1101, 1200, 1201, 2000, 2001], None);
+        let mut tree = Sedimentree::new(Vec::new(), commits.clone());
+
+        let missing = tree.missing_chunks(id);
+        let (deep, shallow): (Vec<_>, Vec<_>) = missing
+            .iter()
+            .map(chunk)
+            .partition(|chunk| !chunk.checkpoints().is_empty());
+        assert_eq!(shallow.len(), 3);
+        assert_eq!(deep.len(), 1);
+        let deep = &deep[0];
+        assert_eq!(deep.head(), digest(1000));
+        assert_eq!(deep.boundary(), &nonempty![digest(2000)]);
+        assert_eq!(deep.checkpoints(), &vec![digest(1100), digest(1200)]);
+        for chunk in &shallow {
+            assert!(deep.supports(chunk.summary()), "{chunk:?}");
+        }
+
+        // The shallow chunks alone still leave the deep one missing
+        let mut partial = Sedimentree::new(Vec::new(), commits);
+        for chunk in &shallow {
+            partial.add_chunk(chunk.clone());
+        }
+        let partial = partial.minimize();
+        let missing = partial.missing_chunks(id);
+        assert_eq!(missing.len(), 1);
+        assert_eq!(chunk(&missing[0]), *deep);
+
+        for chunk in missing.iter().map(chunk).chain(shallow) {
+            tree.add_chunk(chunk);
+        }
+        let tree = tree.minimize();
+        assert_eq!(tree.chunks().collect::<Vec<_>>(), vec![deep]);
+        let loose = tree
+            .loose_commits()
+            .map(LooseCommit::digest)
+            .collect::<BTreeSet<_>>();
+        assert_eq!(
+            loose,
+            BTreeSet::from([digest(1), digest(1000), digest(2001)])
+        );
+        assert_eq!(tree.heads(), vec![digest(2001)]);
+        assert!(tree.missing_chunks(id).is_empty());
+    }
+
+    #[test]
+    fn heads_are_the_commits_nothing_builds_on() {
+        for (name, fixture) in fixtures::corpus(0) {
+            let parents = fixture
+                .commits()
+                .iter()
+                .flat_map(|(commit, _)| commit.parents())
+                .collect::<HashSet<_>>();
+            let expected = fixture
+                .commits()
+                .iter()
+                .map(|(commit, _)| commit.digest())
+                .filter(|digest| !parents.contains(digest))
+                .collect::<HashSet<_>>();
+            let heads = fixture.sedimentree().heads();
+            assert_eq!(heads.len(), expected.len(), "{name}");
+            assert_eq!(heads.into_iter().collect::<HashSet<_>>(), expected, "{name}");
+        }
+    }
 }
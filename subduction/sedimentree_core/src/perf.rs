@@ -0,0 +1,284 @@
+//! Timing histograms for hot internal operations, for performance reports.
+//!
+//! With the `perf-trace` feature, each [`Op`] is timed wherever it happens, in
+//! this crate and the crates built on it, and counted into a histogram of
+//! power-of-two microsecond buckets. [`report`] returns the histograms so far,
+//! and its [`Display`](std::fmt::Display) is a plain-text dump to attach to a bug
+//! report. Without the feature, none of this is compiled in.
+//!
+//! `std::time::Instant` panics on `wasm32-unknown-unknown`, so hosts there
+//! install a clock with [`set_clock`]. Until they do, nothing is timed there.
+
+use std::{
+    fmt::{Display, Formatter},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+/// The number of buckets in each histogram. The last holds everything slower
+/// than the rest, from about 2 seconds on.
+const BUCKETS: usize = 23;
+
+static HISTOGRAMS: Mutex<[Histogram; Op::ALL.len()]> =
+    Mutex::new([Histogram::EMPTY; Op::ALL.len()]);
+
+static CLOCK: OnceLock<fn() -> f64> = OnceLock::new();
+
+/// An operation that is timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Parsing a hex-encoded [`Digest`](crate::Digest).
+    DigestParse,
+
+    /// Hashing a blob's contents for its [`BlobMeta`](crate::BlobMeta).
+    BlobHash,
+
+    /// Saving a commit or chunk and its blob to storage.
+    StoragePut,
+
+    /// Summarizing a [`Sedimentree`](crate::Sedimentree) for sync.
+    SummaryBuild,
+
+    /// Serializing results across a host boundary, such as into JS values.
+    BoundarySerialize,
+}
+
+impl Op {
+    /// Every operation, in the order [`report`] lists them.
+    pub const ALL: [Op; 5] = [
+        Op::DigestParse,
+        Op::BlobHash,
+        Op::StoragePut,
+        Op::SummaryBuild,
+        Op::BoundarySerialize,
+    ];
+
+    /// The operation's name in reports.
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Op::DigestParse => "digest_parse",
+            Op::BlobHash => "blob_hash",
+            Op::StoragePut => "storage_put",
+            Op::SummaryBuild => "summary_build",
+            Op::BoundarySerialize => "boundary_serialize",
+        }
+    }
+
+    const fn index(self) -> usize {
+        self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Histogram {
+    count: u64,
+    total_us: u64,
+    max_us: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl Histogram {
+    const EMPTY: Self = Self {
+        count: 0,
+        total_us: 0,
+        max_us: 0,
+        buckets: [0; BUCKETS],
+    };
+
+    fn record(&mut self, elapsed_us: u64) {
+        self.count += 1;
+        self.total_us = self.total_us.saturating_add(elapsed_us);
+        self.max_us = self.max_us.max(elapsed_us);
+        self.buckets[bucket_of(elapsed_us)] += 1;
+    }
+}
+
+/// The bucket for `elapsed_us`: bucket `i` holds times under `2^i` microseconds
+/// that don't fit an earlier one.
+fn bucket_of(elapsed_us: u64) -> usize {
+    let bits = (u64::BITS - elapsed_us.leading_zeros()) as usize;
+    bits.min(BUCKETS - 1)
+}
+
+/// Time operations with `clock`, which returns milliseconds since any fixed point.
+///
+/// The first clock installed is kept.
+pub fn set_clock(clock: fn() -> f64) {
+    let _ = CLOCK.set(clock);
+}
+
+#[allow(clippy::unnecessary_wraps)] // Only ever `Some` off `wasm32-unknown-unknown`
+fn now_ms() -> Option<f64> {
+    if let Some(clock) = CLOCK.get() {
+        return Some(clock());
+    }
+
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    {
+        static EPOCH: OnceLock<std::time::Instant> = OnceLock::new();
+        Some(
+            EPOCH
+                .get_or_init(std::time::Instant::now)
+                .elapsed()
+                .as_secs_f64()
+                * 1000.0,
+        )
+    }
+
+    #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+    None
+}
+
+/// An operation being timed, recorded when dropped.
+#[derive(Debug)]
+#[must_use = "the operation is timed until the span is dropped"]
+pub struct Span {
+    op: Op,
+    started_ms: Option<f64>,
+}
+
+/// Start timing `op`.
+pub fn span(op: Op) -> Span {
+    Span {
+        op,
+        started_ms: now_ms(),
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let (Some(started_ms), Some(finished_ms)) = (self.started_ms, now_ms()) else {
+            return;
+        };
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let elapsed_us = ((finished_ms - started_ms) * 1000.0).max(0.0) as u64;
+        HISTOGRAMS.lock().unwrap_or_else(PoisonError::into_inner)[self.op.index()]
+            .record(elapsed_us);
+    }
+}
+
+/// The timings of every [`Op`] so far, as [`report`] returns them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct Report {
+    /// One per [`Op`], in the order of [`Op::ALL`].
+    pub ops: Vec<OpReport>,
+}
+
+/// The timings of one [`Op`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct OpReport {
+    /// The [name](Op::name) of the operation.
+    pub op: &'static str,
+
+    /// How many times it was timed.
+    pub count: u64,
+
+    /// The time it took altogether, in microseconds.
+    pub total_us: u64,
+
+    /// The longest it took, in microseconds.
+    pub max_us: u64,
+
+    /// How many times took under each power of two microseconds, leaving out
+    /// empty buckets.
+    pub buckets: Vec<Bucket>,
+}
+
+/// A histogram bucket of an [`OpReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "camelCase"))]
+pub struct Bucket {
+    /// The times counted here are under this many microseconds, and at least
+    /// the previous bucket's. `None` for the last bucket, which has no bound.
+    pub under_us: Option<u64>,
+
+    /// How many times were.
+    pub count: u64,
+}
+
+/// The timings recorded so far.
+#[must_use]
+pub fn report() -> Report {
+    let histograms = *HISTOGRAMS.lock().unwrap_or_else(PoisonError::into_inner);
+    let ops = Op::ALL
+        .iter()
+        .zip(histograms)
+        .map(|(op, histogram)| OpReport {
+            op: op.name(),
+            count: histogram.count,
+            total_us: histogram.total_us,
+            max_us: histogram.max_us,
+            buckets: (0..BUCKETS)
+                .filter(|i| histogram.buckets[*i] > 0)
+                .map(|i| Bucket {
+                    under_us: (i < BUCKETS - 1).then(|| 1 << i),
+                    count: histogram.buckets[i],
+                })
+                .collect(),
+        })
+        .collect();
+    Report { ops }
+}
+
+/// Forget the timings recorded so far.
+pub fn reset() {
+    *HISTOGRAMS.lock().unwrap_or_else(PoisonError::into_inner) = [Histogram::EMPTY; Op::ALL.len()];
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for op in &self.ops {
+            write!(
+                f,
+                "{}: {} timed, {}µs total, {}µs max",
+                op.op, op.count, op.total_us, op.max_us
+            )?;
+            for bucket in &op.buckets {
+                match bucket.under_us {
+                    Some(under_us) => write!(f, "; <{under_us}µs: {}", bucket.count)?,
+                    None => write!(f, "; longer: {}", bucket.count)?,
+                }
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_are_powers_of_two() {
+        assert_eq!(bucket_of(0), 0);
+        assert_eq!(bucket_of(1), 1);
+        assert_eq!(bucket_of(3), 2);
+        assert_eq!(bucket_of(4), 3);
+        assert_eq!(bucket_of(u64::MAX), BUCKETS - 1);
+
+        let mut histogram = Histogram::EMPTY;
+        for elapsed_us in [0, 5, 7, 1_000] {
+            histogram.record(elapsed_us);
+        }
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.total_us, 1_012);
+        assert_eq!(histogram.max_us, 1_000);
+        assert_eq!(histogram.buckets[3], 2);
+        assert_eq!(histogram.buckets[10], 1);
+    }
+
+    #[test]
+    fn spans_are_reported() {
+        drop(span(Op::BoundarySerialize));
+        let report = report();
+        let op = &report.ops[Op::BoundarySerialize.index()];
+        assert_eq!(op.op, "boundary_serialize");
+        assert!(op.count >= 1);
+        assert!(report.to_string().contains("boundary_serialize: "));
+    }
+}
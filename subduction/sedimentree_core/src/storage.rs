@@ -1,5 +1,11 @@
 //! Storage abstraction for `Sedimentree` data.
 
+pub mod codec;
+pub mod fs;
+pub mod header;
+pub mod journal;
+pub mod record;
+
 use std::{collections::HashMap, sync::Arc};
 
 use futures::{
@@ -13,6 +19,7 @@ use crate::{
     Blob, Digest,
 };
 
+use self::header::{Codec, StorageHeader};
 use super::{Chunk, LooseCommit};
 
 /// Abstraction over storage for `Sedimentree` data.
@@ -20,6 +27,9 @@ pub trait Storage<K: FutureKind> {
     /// The error type for storage operations.
     type Error: core::error::Error;
 
+    /// The [`StorageHeader`] describing the format of this store.
+    fn header(&self) -> StorageHeader;
+
     /// Load all loose commits from storage.
     fn load_loose_commits(&self) -> K::Future<'_, Result<Vec<LooseCommit>, Self::Error>>;
 
@@ -40,6 +50,10 @@ pub trait Storage<K: FutureKind> {
 
     /// Load a blob from storage.
     fn load_blob(&self, blob_digest: Digest) -> K::Future<'_, Result<Option<Blob>, Self::Error>>;
+
+    /// Delete the blobs with digests `blob_digests` from storage, along with the loose
+    /// commits and chunks whose blobs they are. Digests that aren't stored are skipped.
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> K::Future<'_, Result<(), Self::Error>>;
 }
 
 /// Errors that can occur when loading tree data (commits or chunks)
@@ -55,16 +69,74 @@ pub enum LoadTreeData {
 }
 
 /// An in-memory storage backend.
+///
+/// See [`fs::FsStorage`] for one that persists to disk.
+///
+/// Blobs are held encoded with the header's [`Codec`].
 #[derive(Debug, Clone, Default)]
 pub struct MemoryStorage {
     chunks: Arc<Mutex<HashMap<Digest, Chunk>>>,
     commits: Arc<Mutex<HashMap<Digest, LooseCommit>>>,
-    blobs: Arc<Mutex<HashMap<Digest, Blob>>>,
+    blobs: Arc<Mutex<HashMap<Digest, Vec<u8>>>>,
+    header: StorageHeader,
+}
+
+impl MemoryStorage {
+    /// An empty store that encodes blobs with `codec`.
+    #[must_use]
+    pub fn with_codec(codec: Codec) -> Self {
+        Self {
+            header: StorageHeader::default().with_codec(codec),
+            ..Self::default()
+        }
+    }
+
+    /// The total size of the stored (encoded) blobs, in bytes.
+    pub async fn stored_blob_bytes(&self) -> u64 {
+        self.blobs
+            .lock()
+            .await
+            .values()
+            .map(|stored| stored.len() as u64)
+            .sum()
+    }
+
+    async fn insert_blob(&self, blob: &Blob) -> Digest {
+        let digest = Digest::hash(blob.contents());
+        let stored = codec::encode_blob(self.header.codec(), blob);
+        self.blobs.lock().await.insert(digest, stored);
+        digest
+    }
+
+    async fn get_blob(&self, digest: Digest) -> Option<Blob> {
+        let blobs = self.blobs.lock().await;
+        let stored = blobs.get(&digest)?;
+        codec::decode_blob(stored)
+            .inspect_err(|err| tracing::error!("failed to decode blob {digest}: {err}"))
+            .ok()
+    }
+
+    async fn remove(&self, digests: &[Digest]) {
+        let (mut blobs, mut commits, mut chunks) = (
+            self.blobs.lock().await,
+            self.commits.lock().await,
+            self.chunks.lock().await,
+        );
+        for digest in digests {
+            blobs.remove(digest);
+            commits.remove(digest);
+            chunks.remove(digest);
+        }
+    }
 }
 
 impl Storage<Sendable> for MemoryStorage {
     type Error = std::convert::Infallible;
 
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
     fn load_loose_commits(&self) -> BoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
         async move {
             let commits = self.commits.lock().await.values().cloned().collect();
@@ -103,18 +175,17 @@ impl Storage<Sendable> for MemoryStorage {
     }
 
     fn save_blob(&self, blob: Blob) -> BoxFuture<'_, Result<Digest, Self::Error>> {
-        async move {
-            let digest = Digest::hash(blob.contents());
-            self.blobs.lock().await.insert(digest, blob);
-            Ok(digest)
-        }
-        .boxed()
+        async move { Ok(self.insert_blob(&blob).await) }.boxed()
     }
 
     fn load_blob(&self, blob_digest: Digest) -> BoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { Ok(self.get_blob(blob_digest).await) }.boxed()
+    }
+
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> BoxFuture<'_, Result<(), Self::Error>> {
         async move {
-            let maybe_blob = self.blobs.lock().await.get(&blob_digest).cloned();
-            Ok(maybe_blob)
+            self.remove(&blob_digests).await;
+            Ok(())
         }
         .boxed()
     }
@@ -123,6 +194,10 @@ impl Storage<Sendable> for MemoryStorage {
 impl Storage<Local> for MemoryStorage {
     type Error = std::convert::Infallible;
 
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
     fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
         async move {
             let commits = self.commits.lock().await.values().cloned().collect();
@@ -161,21 +236,23 @@ impl Storage<Local> for MemoryStorage {
     }
 
     fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
-        async move {
-            let digest = Digest::hash(blob.contents());
-            self.blobs.lock().await.insert(digest, blob);
-            Ok(digest)
-        }
-        .boxed_local()
+        async move { Ok(self.insert_blob(&blob).await) }.boxed_local()
     }
 
     fn load_blob(
         &self,
         blob_digest: Digest,
     ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { Ok(self.get_blob(blob_digest).await) }.boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
         async move {
-            let maybe_blob = self.blobs.lock().await.get(&blob_digest).cloned();
-            Ok(maybe_blob)
+            self.remove(&blob_digests).await;
+            Ok(())
         }
         .boxed_local()
     }
@@ -0,0 +1,120 @@
+//! Encoding blob contents at rest.
+//!
+//! A store can compress the blobs it holds with a [`Codec`]. Each stored blob is
+//! prefixed with the tag of the codec it was encoded with, so a store whose codec
+//! is changed can still read everything it wrote before. Digests are always of
+//! the original contents, so compression is invisible to the sync protocol.
+
+use std::{io::Read, str::FromStr};
+
+use thiserror::Error;
+
+use super::header::Codec;
+use crate::Blob;
+
+/// The `miniz_oxide` compression level used for [`Codec::Deflate`].
+const DEFLATE_LEVEL: u8 = 6;
+
+impl Codec {
+    /// Encode `contents` with this codec.
+    #[must_use]
+    pub fn encode(self, contents: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::Raw => contents.to_vec(),
+            Codec::Deflate => miniz_oxide::deflate::compress_to_vec(contents, DEFLATE_LEVEL),
+            Codec::Zstd => ruzstd::encoding::compress_to_vec(
+                contents,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            ),
+        }
+    }
+
+    /// Decode `encoded`, which was produced by [`Codec::encode`] with this codec.
+    ///
+    /// # Errors
+    ///
+    /// * [`CodecError::Corrupt`] if `encoded` isn't valid for this codec.
+    pub fn decode(self, encoded: &[u8]) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Codec::Raw => Ok(encoded.to_vec()),
+            Codec::Deflate => miniz_oxide::inflate::decompress_to_vec(encoded)
+                .map_err(|err| CodecError::Corrupt(self, err.to_string())),
+            Codec::Zstd => {
+                let mut decoder = ruzstd::decoding::StreamingDecoder::new(encoded)
+                    .map_err(|err| CodecError::Corrupt(self, err.to_string()))?;
+                let mut contents = Vec::new();
+                decoder
+                    .read_to_end(&mut contents)
+                    .map_err(|err| CodecError::Corrupt(self, err.to_string()))?;
+                Ok(contents)
+            }
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = CodecError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" | "none" => Ok(Codec::Raw),
+            "deflate" => Ok(Codec::Deflate),
+            "zstd" => Ok(Codec::Zstd),
+            other => Err(CodecError::Unknown(other.to_string())),
+        }
+    }
+}
+
+/// Encode a blob for storage, recording the codec used.
+#[must_use]
+pub fn encode_blob(codec: Codec, blob: &Blob) -> Vec<u8> {
+    let mut stored = vec![codec.tag()];
+    stored.extend(codec.encode(blob.as_slice()));
+    stored
+}
+
+/// Decode a blob stored by [`encode_blob`], with whichever codec it records.
+///
+/// # Errors
+///
+/// * [`CodecError::UnknownTag`] if the recorded codec isn't supported.
+/// * [`CodecError::Corrupt`] if the contents can't be decoded.
+pub fn decode_blob(stored: &[u8]) -> Result<Blob, CodecError> {
+    let (&tag, encoded) = stored.split_first().ok_or(CodecError::UnknownTag(None))?;
+    let codec = Codec::from_tag(tag).ok_or(CodecError::UnknownTag(Some(tag)))?;
+    codec.decode(encoded).map(Blob::new)
+}
+
+/// Errors from encoding or decoding blob contents.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CodecError {
+    /// A codec name wasn't recognised.
+    #[error("unknown codec {0:?} (expected raw, none, deflate, or zstd)")]
+    Unknown(String),
+
+    /// A stored blob recorded an unsupported codec (or was empty).
+    #[error("stored blob has unknown codec tag {0:?}")]
+    UnknownTag(Option<u8>),
+
+    /// A stored blob couldn't be decoded with its codec.
+    #[error("stored blob is not valid {0}: {1}")]
+    Corrupt(Codec, String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_with_every_codec() -> Result<(), CodecError> {
+        let blob = Blob::new(b"the quick brown fox ".repeat(100));
+        for codec in [Codec::Raw, Codec::Deflate, Codec::Zstd] {
+            let stored = encode_blob(codec, &blob);
+            assert_eq!(decode_blob(&stored)?, blob);
+            if codec != Codec::Raw {
+                assert!(stored.len() < blob.as_slice().len() / 4);
+            }
+        }
+        Ok(())
+    }
+}
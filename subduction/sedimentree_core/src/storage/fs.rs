@@ -0,0 +1,459 @@
+//! A storage backend that persists to a directory on the local filesystem.
+//!
+//! The directory is laid out by content address:
+//!
+//! ```text
+//! <root>/header               the store's StorageHeader
+//! <root>/blobs/ab/abcd…       blobs, encoded with the header's codec, by their digest
+//! <root>/commits/ab/abcd…     loose commits, by their blob's digest
+//! <root>/chunks/ab/abcd…      chunks, by their blob's digest
+//! <root>/tmp/                 writes in progress
+//! ```
+//!
+//! Every file is written to `tmp/` first, synced, and renamed into place, after
+//! which its directory is synced too. A crash therefore leaves either the whole
+//! file or none of it, never a torn one, and the leftovers in `tmp/` are cleared
+//! the next time the store is opened. Since files are named by what they hold,
+//! writing one that already exists is skipped.
+//!
+//! The filesystem is accessed with blocking calls, which keeps this crate free of
+//! an async runtime. They are short, but a server that can't afford to stall its
+//! executor on disk should drive the store from a blocking-friendly task.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    FutureExt,
+};
+use thiserror::Error;
+
+use crate::{
+    future::{Local, Sendable},
+    Blob, Chunk, Digest, LooseCommit,
+};
+
+use super::{
+    codec::{self, CodecError},
+    header::{Codec, HeaderError, StorageHeader},
+    record::{decode_chunk, decode_commit, encode_chunk, encode_commit},
+    Storage,
+};
+
+/// Distinguishes the temporary files of concurrent writes.
+static NEXT_TMP: AtomicU64 = AtomicU64::new(0);
+
+/// A storage backend that keeps everything in files under a directory.
+///
+/// Blobs are held encoded with the header's [`Codec`].
+#[derive(Debug, Clone)]
+pub struct FsStorage {
+    root: PathBuf,
+    header: StorageHeader,
+}
+
+impl FsStorage {
+    /// Open the store at `root`, creating it if it doesn't exist yet.
+    ///
+    /// # Errors
+    ///
+    /// * [`FsStorageError::Header`] if `root` holds a store this version can't read.
+    /// * [`FsStorageError::Io`] if the directory can't be read or created.
+    pub fn open(root: impl Into<PathBuf>) -> Result<Self, FsStorageError> {
+        Self::open_with_codec(root, Codec::default())
+    }
+
+    /// Open the store at `root`, creating it with blobs encoded with `codec` if it
+    /// doesn't exist yet. An existing store keeps the codec it was created with.
+    ///
+    /// # Errors
+    ///
+    /// * [`FsStorageError::Header`] if `root` holds a store this version can't read.
+    /// * [`FsStorageError::Io`] if the directory can't be read or created.
+    pub fn open_with_codec(root: impl Into<PathBuf>, codec: Codec) -> Result<Self, FsStorageError> {
+        let root = root.into();
+        for dir in [BLOBS, COMMITS, CHUNKS] {
+            fs::create_dir_all(root.join(dir))?;
+        }
+
+        // Whatever a crash left half-written
+        let tmp = root.join(TMP);
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir_all(&tmp)?;
+
+        let header_path = root.join(HEADER);
+        let header = match fs::read(&header_path) {
+            Ok(bytes) => StorageHeader::from_bytes(&bytes)?.0,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let header = StorageHeader::default().with_codec(codec);
+                let store = Self {
+                    root,
+                    header: header.clone(),
+                };
+                store.write_atomically(&header_path, &header.to_bytes())?;
+                return Ok(store);
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Ok(Self { root, header })
+    }
+
+    /// The directory the store is kept in.
+    #[must_use]
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn path(&self, kind: &str, digest: Digest) -> PathBuf {
+        let name = digest.to_string();
+        self.root.join(kind).join(&name[..2]).join(name)
+    }
+
+    /// Write `bytes` to `path` so that a crash leaves all of them or none.
+    fn write_atomically(&self, path: &Path, bytes: &[u8]) -> Result<(), FsStorageError> {
+        let tmp = self.root.join(TMP).join(format!(
+            "{}-{}",
+            std::process::id(),
+            NEXT_TMP.fetch_add(1, Ordering::Relaxed)
+        ));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(&tmp)?;
+        file.write_all(bytes)?;
+        file.sync_all()?;
+        drop(file);
+
+        let dir = path
+            .parent()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no parent directory"))?;
+        fs::create_dir_all(dir)?;
+        fs::rename(&tmp, path)?;
+        sync_dir(dir)?;
+        Ok(())
+    }
+
+    /// Write `bytes` to the file for `digest`, unless it's already there.
+    fn put(&self, kind: &str, digest: Digest, bytes: &[u8]) -> Result<(), FsStorageError> {
+        let path = self.path(kind, digest);
+        if path.exists() {
+            return Ok(());
+        }
+        self.write_atomically(&path, bytes)
+    }
+
+    /// The contents of every file of `kind`.
+    fn list(&self, kind: &str) -> Result<Vec<(PathBuf, Vec<u8>)>, FsStorageError> {
+        let mut files = Vec::new();
+        for fan_out in fs::read_dir(self.root.join(kind))? {
+            let fan_out = fan_out?;
+            if !fan_out.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(fan_out.path())? {
+                let path = entry?.path();
+                let bytes = fs::read(&path)?;
+                files.push((path, bytes));
+            }
+        }
+        Ok(files)
+    }
+
+    fn load_commits(&self) -> Result<Vec<LooseCommit>, FsStorageError> {
+        self.list(COMMITS)?
+            .into_iter()
+            .map(|(path, bytes)| decode_commit(&bytes).ok_or(FsStorageError::Corrupt(path)))
+            .collect()
+    }
+
+    fn load_all_chunks(&self) -> Result<Vec<Chunk>, FsStorageError> {
+        self.list(CHUNKS)?
+            .into_iter()
+            .map(|(path, bytes)| decode_chunk(&bytes).ok_or(FsStorageError::Corrupt(path)))
+            .collect()
+    }
+
+    fn insert_commit(&self, commit: &LooseCommit) -> Result<(), FsStorageError> {
+        self.put(COMMITS, commit.blob().digest(), &encode_commit(commit))
+    }
+
+    fn insert_chunk(&self, chunk: &Chunk) -> Result<(), FsStorageError> {
+        let digest = chunk.summary().blob_meta().digest();
+        self.put(CHUNKS, digest, &encode_chunk(chunk))
+    }
+
+    fn insert_blob(&self, blob: &Blob) -> Result<Digest, FsStorageError> {
+        let digest = Digest::hash(blob.contents());
+        let stored = codec::encode_blob(self.header.codec(), blob);
+        self.put(BLOBS, digest, &stored)?;
+        Ok(digest)
+    }
+
+    fn get_blob(&self, digest: Digest) -> Result<Option<Blob>, FsStorageError> {
+        match fs::read(self.path(BLOBS, digest)) {
+            Ok(stored) => Ok(Some(codec::decode_blob(&stored)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove the blob, loose commit, and chunk files for each of `digests`.
+    fn remove(&self, digests: &[Digest]) -> Result<(), FsStorageError> {
+        for digest in digests {
+            for kind in [COMMITS, CHUNKS, BLOBS] {
+                match fs::remove_file(self.path(kind, *digest)) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+const HEADER: &str = "header";
+const BLOBS: &str = "blobs";
+const COMMITS: &str = "commits";
+const CHUNKS: &str = "chunks";
+const TMP: &str = "tmp";
+
+/// Make a rename into `dir` durable.
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened to sync them here; renames are durable once the
+/// filesystem flushes its metadata.
+#[cfg(not(unix))]
+#[allow(clippy::unnecessary_wraps)]
+const fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// A problem with a filesystem store.
+#[derive(Debug, Error)]
+pub enum FsStorageError {
+    /// Reading or writing the store failed.
+    #[error("filesystem storage: {0}")]
+    Io(#[from] io::Error),
+
+    /// The directory holds a store this version can't read.
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+
+    /// A stored blob couldn't be decoded.
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+
+    /// A commit's or chunk's file isn't one this version wrote.
+    #[error("corrupt file in store: {}", .0.display())]
+    Corrupt(PathBuf),
+}
+
+impl Storage<Sendable> for FsStorage {
+    type Error = FsStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> BoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move { self.load_commits() }.boxed()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.insert_commit(&loose_commit) }.boxed()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.insert_chunk(&chunk) }.boxed()
+    }
+
+    fn load_chunks(&self) -> BoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move { self.load_all_chunks() }.boxed()
+    }
+
+    fn save_blob(&self, blob: Blob) -> BoxFuture<'_, Result<Digest, Self::Error>> {
+        async move { self.insert_blob(&blob) }.boxed()
+    }
+
+    fn load_blob(&self, blob_digest: Digest) -> BoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { self.get_blob(blob_digest) }.boxed()
+    }
+
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.remove(&blob_digests) }.boxed()
+    }
+}
+
+impl Storage<Local> for FsStorage {
+    type Error = FsStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move { self.load_commits() }.boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.insert_commit(&loose_commit) }.boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.insert_chunk(&chunk) }.boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move { self.load_all_chunks() }.boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        async move { self.insert_blob(&blob) }.boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { self.get_blob(blob_digest) }.boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.remove(&blob_digests) }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nonempty::nonempty;
+
+    use super::*;
+
+    /// A fresh directory for a test's store.
+    fn scratch(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("sedimentree-fs-{name}-{}", std::process::id()));
+        fs::remove_dir_all(&dir).ok();
+        dir
+    }
+
+    #[test]
+    fn survives_reopening() -> Result<(), FsStorageError> {
+        let root = scratch("reopen");
+        let blob = Blob::new(b"hello".repeat(100));
+        let commit = LooseCommit::new(
+            Digest::hash(b"commit"),
+            vec![Digest::hash(b"parent")],
+            blob.meta(),
+        );
+        let chunk = Chunk::new(
+            Digest::hash(b"head"),
+            nonempty![Digest::hash(b"end")],
+            vec![Digest::hash(b"checkpoint")],
+            blob.meta(),
+        );
+
+        let store = FsStorage::open_with_codec(&root, Codec::Zstd)?;
+        futures::executor::block_on(async {
+            Storage::<Local>::save_blob(&store, blob.clone()).await?;
+            Storage::<Local>::save_loose_commit(&store, commit.clone()).await?;
+            Storage::<Local>::save_chunk(&store, chunk.clone()).await
+        })?;
+
+        // Opening it again keeps the codec it was created with
+        let reopened = FsStorage::open(&root)?;
+        assert_eq!(Storage::<Local>::header(&reopened).codec(), Codec::Zstd);
+        futures::executor::block_on(async {
+            assert_eq!(
+                Storage::<Local>::load_blob(&reopened, blob.meta().digest()).await?,
+                Some(blob)
+            );
+            assert_eq!(
+                Storage::<Local>::load_loose_commits(&reopened).await?,
+                vec![commit]
+            );
+            assert_eq!(Storage::<Local>::load_chunks(&reopened).await?, vec![chunk]);
+            assert_eq!(
+                Storage::<Local>::load_blob(&reopened, Digest::hash(b"missing")).await?,
+                None
+            );
+            Ok::<_, FsStorageError>(())
+        })?;
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn deletes_blobs_with_their_records() -> Result<(), FsStorageError> {
+        let root = scratch("delete");
+        let (gone, kept) = (Blob::new(b"gone".to_vec()), Blob::new(b"kept".to_vec()));
+        let store = FsStorage::open(&root)?;
+        futures::executor::block_on(async {
+            for (blob, name) in [(&gone, b"gone"), (&kept, b"kept")] {
+                Storage::<Local>::save_blob(&store, blob.clone()).await?;
+                let commit = LooseCommit::new(Digest::hash(name), vec![], blob.meta());
+                Storage::<Local>::save_loose_commit(&store, commit).await?;
+            }
+            Storage::<Local>::delete_blobs(
+                &store,
+                vec![gone.meta().digest(), Digest::hash(b"missing")],
+            )
+            .await?;
+
+            assert_eq!(
+                Storage::<Local>::load_blob(&store, gone.meta().digest()).await?,
+                None
+            );
+            assert_eq!(
+                Storage::<Local>::load_blob(&store, kept.meta().digest()).await?,
+                Some(kept.clone())
+            );
+            let commits = Storage::<Local>::load_loose_commits(&store).await?;
+            assert_eq!(
+                commits
+                    .iter()
+                    .map(|c| c.blob().digest())
+                    .collect::<Vec<_>>(),
+                vec![kept.meta().digest()]
+            );
+            Ok::<_, FsStorageError>(())
+        })?;
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+
+    #[test]
+    fn clears_interrupted_writes_and_refuses_foreign_stores() -> Result<(), FsStorageError> {
+        let root = scratch("crash");
+        FsStorage::open(&root)?;
+        let torn = root.join(TMP).join("torn");
+        fs::write(&torn, b"half a blo")?;
+
+        FsStorage::open(&root)?;
+        assert!(!torn.exists());
+
+        fs::write(root.join(HEADER), b"PK\x03\x04 not a store")?;
+        assert!(matches!(
+            FsStorage::open(&root),
+            Err(FsStorageError::Header(HeaderError::BadMagic))
+        ));
+        fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}
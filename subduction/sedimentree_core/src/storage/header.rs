@@ -0,0 +1,273 @@
+//! A self-describing header for persisted `Sedimentree` stores.
+//!
+//! Every persisted store starts with a [`StorageHeader`], so that tooling can
+//! recognise a store and refuse to open one written in an incompatible format
+//! instead of misparsing its bytes.
+
+use thiserror::Error;
+
+/// The magic bytes at the start of every persisted store.
+pub const MAGIC: [u8; 4] = *b"SDMT";
+
+/// The current storage format version.
+pub const FORMAT_VERSION: u16 = 1;
+
+/// The hash function used to compute [`Digest`][crate::Digest]s in a store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DigestAlgorithm {
+    /// BLAKE3 with a 32-byte output.
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    const fn tag(self) -> u8 {
+        match self {
+            DigestAlgorithm::Blake3 => 0,
+        }
+    }
+
+    const fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(DigestAlgorithm::Blake3),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DigestAlgorithm::Blake3 => write!(f, "blake3"),
+        }
+    }
+}
+
+/// The encoding applied to blob contents at rest.
+///
+/// See [`codec`][super::codec] for how blobs are encoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Codec {
+    /// Contents are stored as-is.
+    #[default]
+    Raw,
+
+    /// Contents are DEFLATE-compressed.
+    Deflate,
+
+    /// Contents are Zstandard-compressed.
+    Zstd,
+}
+
+impl Codec {
+    pub(crate) const fn tag(self) -> u8 {
+        match self {
+            Codec::Raw => 0,
+            Codec::Deflate => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub(crate) const fn from_tag(tag: u8) -> Option<Self> {
+        match tag {
+            0 => Some(Codec::Raw),
+            1 => Some(Codec::Deflate),
+            2 => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Codec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Codec::Raw => write!(f, "raw"),
+            Codec::Deflate => write!(f, "deflate"),
+            Codec::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// The header written at the start of a persisted store.
+///
+/// The binary layout is:
+///
+/// ```text
+/// magic (4) | format version (u16 LE) | digest algorithm (u8) | codec (u8) | created-by length (u16 LE) | created-by (UTF-8)
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StorageHeader {
+    format_version: u16,
+    digest_algorithm: DigestAlgorithm,
+    codec: Codec,
+    created_by: String,
+}
+
+impl StorageHeader {
+    /// A header for a store in the current format, written by `created_by`.
+    #[must_use]
+    pub const fn new(created_by: String) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            digest_algorithm: DigestAlgorithm::Blake3,
+            codec: Codec::Raw,
+            created_by,
+        }
+    }
+
+    /// The same header, for a store whose blobs are encoded with `codec`.
+    #[must_use]
+    pub fn with_codec(self, codec: Codec) -> Self {
+        Self { codec, ..self }
+    }
+
+    /// The format version of the store.
+    #[must_use]
+    pub const fn format_version(&self) -> u16 {
+        self.format_version
+    }
+
+    /// The hash function used for digests in the store.
+    #[must_use]
+    pub const fn digest_algorithm(&self) -> DigestAlgorithm {
+        self.digest_algorithm
+    }
+
+    /// The encoding applied to blob contents in the store.
+    #[must_use]
+    pub const fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    /// The software that created the store.
+    #[must_use]
+    #[allow(clippy::missing_const_for_fn)]
+    pub fn created_by(&self) -> &str {
+        &self.created_by
+    }
+
+    /// Encode this header to bytes.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let created_by = self.created_by.as_bytes();
+        #[allow(clippy::cast_possible_truncation)]
+        let created_by_len = created_by.len().min(usize::from(u16::MAX)) as u16;
+
+        let mut bytes = Vec::with_capacity(10 + usize::from(created_by_len));
+        bytes.extend_from_slice(&MAGIC);
+        bytes.extend_from_slice(&self.format_version.to_le_bytes());
+        bytes.push(self.digest_algorithm.tag());
+        bytes.push(self.codec.tag());
+        bytes.extend_from_slice(&created_by_len.to_le_bytes());
+        bytes.extend_from_slice(&created_by[..usize::from(created_by_len)]);
+        bytes
+    }
+
+    /// Decode a header from the start of `bytes`.
+    ///
+    /// # Returns
+    ///
+    /// The header and the number of bytes it occupied.
+    ///
+    /// # Errors
+    ///
+    /// * [`HeaderError`] if the bytes are not a header this version can read.
+    pub fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), HeaderError> {
+        let fixed = bytes.get(..10).ok_or(HeaderError::Truncated)?;
+        if fixed[..4] != MAGIC {
+            return Err(HeaderError::BadMagic);
+        }
+
+        let format_version = u16::from_le_bytes([fixed[4], fixed[5]]);
+        if format_version > FORMAT_VERSION {
+            return Err(HeaderError::UnsupportedVersion(format_version));
+        }
+
+        let digest_algorithm = DigestAlgorithm::from_tag(fixed[6])
+            .ok_or(HeaderError::UnknownDigestAlgorithm(fixed[6]))?;
+        let codec = Codec::from_tag(fixed[7]).ok_or(HeaderError::UnknownCodec(fixed[7]))?;
+
+        let created_by_len = usize::from(u16::from_le_bytes([fixed[8], fixed[9]]));
+        let created_by = bytes
+            .get(10..10 + created_by_len)
+            .ok_or(HeaderError::Truncated)?;
+        let created_by =
+            String::from_utf8(created_by.to_vec()).map_err(|_| HeaderError::InvalidCreatedBy)?;
+
+        Ok((
+            Self {
+                format_version,
+                digest_algorithm,
+                codec,
+                created_by,
+            },
+            10 + created_by_len,
+        ))
+    }
+}
+
+impl Default for StorageHeader {
+    fn default() -> Self {
+        Self::new(concat!("sedimentree_core/", env!("CARGO_PKG_VERSION")).to_string())
+    }
+}
+
+/// A problem reading a [`StorageHeader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum HeaderError {
+    /// The input ended before the header did.
+    #[error("storage header is truncated")]
+    Truncated,
+
+    /// The input does not start with the expected magic bytes.
+    #[error("not a sedimentree store (bad magic bytes)")]
+    BadMagic,
+
+    /// The store was written by a newer format version.
+    #[error("unsupported storage format version {0} (max supported is {FORMAT_VERSION})")]
+    UnsupportedVersion(u16),
+
+    /// The store uses a digest algorithm this version doesn't know.
+    #[error("unknown digest algorithm tag {0}")]
+    UnknownDigestAlgorithm(u8),
+
+    /// The store uses a codec this version doesn't know.
+    #[error("unknown codec tag {0}")]
+    UnknownCodec(u8),
+
+    /// The created-by field is not valid UTF-8.
+    #[error("created-by field is not valid UTF-8")]
+    InvalidCreatedBy,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = StorageHeader::new("test-suite".to_string());
+        let mut bytes = header.to_bytes();
+        let header_len = bytes.len();
+        bytes.extend_from_slice(b"payload");
+
+        assert_eq!(StorageHeader::from_bytes(&bytes), Ok((header, header_len)));
+    }
+
+    #[test]
+    fn rejects_foreign_and_future_stores() {
+        assert_eq!(
+            StorageHeader::from_bytes(b"PK\x03\x04 not a store"),
+            Err(HeaderError::BadMagic)
+        );
+
+        let mut future = StorageHeader::default().to_bytes();
+        future[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            StorageHeader::from_bytes(&future),
+            Err(HeaderError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+}
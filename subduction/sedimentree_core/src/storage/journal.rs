@@ -0,0 +1,674 @@
+//! A write-ahead log in front of another [`Storage`] backend.
+//!
+//! Storing a commit or chunk takes two writes, its record and its blob, and a crash
+//! between them would leave a backend holding one without the other. [`Journaled`]
+//! appends every write to a log file and flushes it before acknowledging the
+//! write, and only passes a record on to the backend once its blob is stored, so
+//! the backend never holds a record whose blob it lacks.
+//!
+//! Opening the journal recovers from a crash: the batches the log holds in full
+//! (a record and its blob) are replayed into the backend, and records whose blob
+//! never made it, along with a torn last entry, are discarded. The log is then
+//! emptied, as it is whenever it has grown past a threshold and no write is in
+//! progress.
+//!
+//! Deletions aren't logged. Instead, the log is emptied before blobs it holds are
+//! deleted, so recovery can't bring them back, unless a write is in progress.
+//!
+//! Like [`FsStorage`](super::fs::FsStorage), the log is written with blocking
+//! calls.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    FutureExt,
+};
+use thiserror::Error;
+
+use crate::{
+    future::{FutureKind, Local, Sendable},
+    Blob, Chunk, Digest, LooseCommit,
+};
+
+use super::{
+    header::StorageHeader,
+    record::{decode_chunk, decode_commit, encode_chunk, encode_commit},
+    Storage,
+};
+
+/// How large the log may grow before it's emptied, once no write is in progress.
+const CHECKPOINT_BYTES: u64 = 1 << 20;
+
+const BLOB: u8 = 0;
+const COMMIT: u8 = 1;
+const CHUNK: u8 = 2;
+
+/// A [`Storage`] backend that logs every write before passing it on to `S`.
+#[derive(Debug, Clone)]
+pub struct Journaled<S> {
+    inner: S,
+    path: PathBuf,
+    log: Arc<Mutex<Log>>,
+}
+
+/// The log file, and the writes it holds.
+#[derive(Debug)]
+struct Log {
+    file: File,
+    len: u64,
+    /// Writes logged but not yet passed on.
+    in_flight: usize,
+    /// The blobs logged since the log was last emptied.
+    blobs: HashSet<Digest>,
+    /// Records logged before their blob, by the blob's digest.
+    waiting: HashMap<Digest, Vec<Record>>,
+}
+
+/// A loose commit or chunk: the half of a batch that names a blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Record {
+    Commit(LooseCommit),
+    Chunk(Chunk),
+}
+
+/// An entry of the log.
+#[derive(Debug)]
+enum Entry {
+    Blob(Blob),
+    Record(Record),
+}
+
+impl<S> Journaled<S> {
+    /// Put a write-ahead log at `path` in front of `inner`, first recovering what
+    /// the log holds from an earlier run.
+    ///
+    /// # Errors
+    ///
+    /// * [`JournalError::Io`] if the log can't be read or created.
+    /// * [`JournalError::Storage`] if `inner` fails while it's recovered.
+    pub async fn open<K: FutureKind>(
+        inner: S,
+        path: impl Into<PathBuf>,
+    ) -> Result<Self, JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        let path = path.into();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+
+        let (blobs, records): (Vec<_>, Vec<_>) = entries(&bytes)
+            .into_iter()
+            .partition(|entry| matches!(entry, Entry::Blob(_)));
+        let mut logged = HashSet::new();
+        for entry in blobs {
+            if let Entry::Blob(blob) = entry {
+                logged.insert(inner.save_blob(blob).await.map_err(JournalError::Storage)?);
+            }
+        }
+        let (mut replayed, mut discarded) = (logged.len(), 0);
+        for entry in records {
+            if let Entry::Record(record) = entry {
+                let blob = record.blob();
+                let stored = logged.contains(&blob)
+                    || inner
+                        .load_blob(blob)
+                        .await
+                        .map_err(JournalError::Storage)?
+                        .is_some();
+                if stored {
+                    save_record(&inner, record).await?;
+                    replayed += 1;
+                } else {
+                    discarded += 1;
+                }
+            }
+        }
+        if replayed + discarded > 0 {
+            tracing::info!(
+                "Recovered write-ahead log {}: replayed {replayed} writes, discarded {discarded}",
+                path.display()
+            );
+        }
+
+        file.set_len(0)?;
+        file.sync_all()?;
+        Ok(Self {
+            inner,
+            path,
+            log: Arc::new(Mutex::new(Log {
+                file,
+                len: 0,
+                in_flight: 0,
+                blobs: HashSet::new(),
+                waiting: HashMap::new(),
+            })),
+        })
+    }
+
+    /// The backend writes are passed on to.
+    #[must_use]
+    pub const fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// The log file.
+    #[must_use]
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Log> {
+        // Every update of the log's state is made whole before it can panic
+        self.log.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn save_blob_logged<K: FutureKind>(
+        &self,
+        blob: Blob,
+    ) -> Result<Digest, JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        let ready = {
+            let mut log = self.lock();
+            log.append(BLOB, blob.contents())?;
+            let digest = Digest::hash(blob.contents());
+            log.blobs.insert(digest);
+            log.waiting.remove(&digest).unwrap_or_default()
+        };
+        let saved = self.pass_on_blob(blob, ready).await;
+        self.lock().finish()?;
+        saved
+    }
+
+    /// Save `blob` to the backend, then the records that were `ready` once it is.
+    async fn pass_on_blob<K: FutureKind>(
+        &self,
+        blob: Blob,
+        ready: Vec<Record>,
+    ) -> Result<Digest, JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        let digest = self
+            .inner
+            .save_blob(blob)
+            .await
+            .map_err(JournalError::Storage)?;
+        for record in ready {
+            save_record(&self.inner, record).await?;
+        }
+        Ok(digest)
+    }
+
+    async fn delete_blobs_logged<K: FutureKind>(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> Result<(), JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        self.lock().forget(&blob_digests)?;
+        self.inner
+            .delete_blobs(blob_digests)
+            .await
+            .map_err(JournalError::Storage)
+    }
+
+    async fn save_record_logged<K: FutureKind>(
+        &self,
+        record: Record,
+    ) -> Result<(), JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        let blob_logged = {
+            let mut log = self.lock();
+            let (tag, payload) = record.encode();
+            log.append(tag, &payload)?;
+            let blob_logged = log.blobs.contains(&record.blob());
+            if !blob_logged {
+                log.waiting
+                    .entry(record.blob())
+                    .or_default()
+                    .push(record.clone());
+            }
+            blob_logged
+        };
+        let saved = self.pass_on_record(record, blob_logged).await;
+        self.lock().finish()?;
+        saved
+    }
+
+    /// Save `record` to the backend once its blob is stored. A record whose blob
+    /// hasn't been saved yet waits for it.
+    async fn pass_on_record<K: FutureKind>(
+        &self,
+        record: Record,
+        blob_logged: bool,
+    ) -> Result<(), JournalError<<S as Storage<K>>::Error>>
+    where
+        S: Storage<K>,
+    {
+        if !blob_logged {
+            let blob = record.blob();
+            let stored = self
+                .inner
+                .load_blob(blob)
+                .await
+                .map_err(JournalError::Storage)?
+                .is_some();
+            if !stored {
+                return Ok(());
+            }
+
+            // Unless the blob's save has taken it in the meantime, it's ours to save
+            let mut log = self.lock();
+            let Some(waiting) = log.waiting.get_mut(&blob) else {
+                return Ok(());
+            };
+            let Some(position) = waiting.iter().position(|other| *other == record) else {
+                return Ok(());
+            };
+            waiting.swap_remove(position);
+            if waiting.is_empty() {
+                log.waiting.remove(&blob);
+            }
+        }
+        save_record(&self.inner, record).await
+    }
+}
+
+impl Log {
+    /// Append an entry and flush it to disk.
+    fn append(&mut self, tag: u8, payload: &[u8]) -> io::Result<()> {
+        let entry = frame(tag, payload);
+        if let Err(err) = self
+            .file
+            .write_all(&entry)
+            .and_then(|()| self.file.sync_data())
+        {
+            // Drop what was written of it, so entries after it can be read back
+            self.file.set_len(self.len)?;
+            return Err(err);
+        }
+        self.len += entry.len() as u64;
+        self.in_flight += 1;
+        Ok(())
+    }
+
+    /// Mark a logged write as passed on, and empty the log if it's due.
+    fn finish(&mut self) -> io::Result<()> {
+        self.in_flight -= 1;
+        if self.in_flight == 0 && self.len >= CHECKPOINT_BYTES {
+            self.empty()?;
+        }
+        Ok(())
+    }
+
+    /// Stop holding the blobs `digests` and the records waiting for them, emptying
+    /// the log if it holds one of the blobs and no write is in progress, so that
+    /// recovery doesn't bring them back.
+    fn forget(&mut self, digests: &[Digest]) -> io::Result<()> {
+        for digest in digests {
+            self.waiting.remove(digest);
+        }
+        if self.in_flight == 0 && digests.iter().any(|digest| self.blobs.contains(digest)) {
+            self.empty()?;
+        }
+        Ok(())
+    }
+
+    fn empty(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()?;
+        self.len = 0;
+        self.blobs.clear();
+        Ok(())
+    }
+}
+
+impl Record {
+    /// The digest of the blob the record names.
+    const fn blob(&self) -> Digest {
+        match self {
+            Record::Commit(commit) => commit.blob().digest(),
+            Record::Chunk(chunk) => chunk.summary().blob_meta().digest(),
+        }
+    }
+
+    fn encode(&self) -> (u8, Vec<u8>) {
+        match self {
+            Record::Commit(commit) => (COMMIT, encode_commit(commit)),
+            Record::Chunk(chunk) => (CHUNK, encode_chunk(chunk)),
+        }
+    }
+}
+
+async fn save_record<K: FutureKind, S: Storage<K>>(
+    inner: &S,
+    record: Record,
+) -> Result<(), JournalError<S::Error>> {
+    match record {
+        Record::Commit(commit) => inner.save_loose_commit(commit).await,
+        Record::Chunk(chunk) => inner.save_chunk(chunk).await,
+    }
+    .map_err(JournalError::Storage)
+}
+
+/// A log entry:
+///
+/// ```text
+/// length (u32 LE) | checksum (32) | tag (1) | payload
+/// ```
+///
+/// where the length and checksum cover the tag and payload.
+fn frame(tag: u8, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(1 + payload.len());
+    body.push(tag);
+    body.extend_from_slice(payload);
+
+    let mut entry = Vec::with_capacity(36 + body.len());
+    #[allow(clippy::cast_possible_truncation)]
+    entry.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    entry.extend_from_slice(Digest::hash(&body).as_bytes());
+    entry.extend_from_slice(&body);
+    entry
+}
+
+/// The entries of a log, up to the first that's torn or garbled.
+fn entries(mut bytes: &[u8]) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    while let Some((entry, rest)) = next_entry(bytes) {
+        entries.push(entry);
+        bytes = rest;
+    }
+    entries
+}
+
+fn next_entry(bytes: &[u8]) -> Option<(Entry, &[u8])> {
+    let (len, rest) = bytes.split_first_chunk::<4>()?;
+    let len = usize::try_from(u32::from_le_bytes(*len)).ok()?;
+    let (checksum, rest) = rest.split_first_chunk::<32>()?;
+    if rest.len() < len {
+        return None;
+    }
+    let (body, rest) = rest.split_at(len);
+    if Digest::hash(body) != Digest::from(*checksum) {
+        return None;
+    }
+    let entry = match body.split_first()? {
+        (&BLOB, payload) => Entry::Blob(Blob::new(payload.to_vec())),
+        (&COMMIT, payload) => Entry::Record(Record::Commit(decode_commit(payload)?)),
+        (&CHUNK, payload) => Entry::Record(Record::Chunk(decode_chunk(payload)?)),
+        _ => return None,
+    };
+    Some((entry, rest))
+}
+
+/// A problem with a journaled store.
+#[derive(Debug, Error)]
+pub enum JournalError<E> {
+    /// The backend failed.
+    #[error(transparent)]
+    Storage(E),
+
+    /// The log could not be read or written.
+    #[error("write-ahead log: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl<S> Storage<Sendable> for Journaled<S>
+where
+    S: Storage<Sendable> + Send + Sync,
+    S::Error: Send + 'static,
+{
+    type Error = JournalError<S::Error>;
+
+    fn header(&self) -> StorageHeader {
+        self.inner.header()
+    }
+
+    fn load_loose_commits(&self) -> BoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move {
+            self.inner
+                .load_loose_commits()
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.save_record_logged(Record::Commit(loose_commit))
+            .boxed()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.save_record_logged(Record::Chunk(chunk)).boxed()
+    }
+
+    fn load_chunks(&self) -> BoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move {
+            self.inner
+                .load_chunks()
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed()
+    }
+
+    fn save_blob(&self, blob: Blob) -> BoxFuture<'_, Result<Digest, Self::Error>> {
+        self.save_blob_logged(blob).boxed()
+    }
+
+    fn load_blob(&self, blob_digest: Digest) -> BoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move {
+            self.inner
+                .load_blob(blob_digest)
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed()
+    }
+
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.delete_blobs_logged(blob_digests).boxed()
+    }
+}
+
+impl<S> Storage<Local> for Journaled<S>
+where
+    S: Storage<Local>,
+    S::Error: 'static,
+{
+    type Error = JournalError<S::Error>;
+
+    fn header(&self) -> StorageHeader {
+        self.inner.header()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move {
+            self.inner
+                .load_loose_commits()
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.save_record_logged(Record::Commit(loose_commit))
+            .boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.save_record_logged(Record::Chunk(chunk)).boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move {
+            self.inner
+                .load_chunks()
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        self.save_blob_logged(blob).boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move {
+            self.inner
+                .load_blob(blob_digest)
+                .await
+                .map_err(JournalError::Storage)
+        }
+        .boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.delete_blobs_logged(blob_digests).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    type Error = JournalError<<MemoryStorage as Storage<Local>>::Error>;
+
+    /// A fresh path for a test's log.
+    fn scratch(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("sedimentree-wal-{name}-{}", std::process::id()));
+        fs::remove_file(&path).ok();
+        path
+    }
+
+    fn commit(blob: &Blob, name: &[u8]) -> LooseCommit {
+        LooseCommit::new(Digest::hash(name), vec![], blob.meta())
+    }
+
+    #[test]
+    fn recovery_replays_whole_batches_only() -> Result<(), Error> {
+        let path = scratch("recover");
+        let blob = Blob::new(b"hello".to_vec());
+        let whole = commit(&blob, b"whole");
+        let orphan = commit(&Blob::new(b"never saved".to_vec()), b"orphan");
+
+        // What a crash might leave: a whole batch, a record without its blob, and a
+        // torn entry
+        let mut log = frame(COMMIT, &encode_commit(&whole));
+        log.extend(frame(BLOB, blob.contents()));
+        log.extend(frame(COMMIT, &encode_commit(&orphan)));
+        log.extend(&frame(BLOB, b"torn")[..10]);
+        fs::write(&path, log)?;
+
+        let store = block_on(Journaled::open::<Local>(MemoryStorage::default(), &path))?;
+        block_on(async {
+            assert_eq!(
+                Storage::<Local>::load_loose_commits(&store).await?,
+                vec![whole]
+            );
+            assert_eq!(
+                Storage::<Local>::load_blob(&store, blob.meta().digest()).await?,
+                Some(blob)
+            );
+            Ok::<_, Error>(())
+        })?;
+        assert_eq!(fs::metadata(&path)?.len(), 0);
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn records_wait_for_their_blob() -> Result<(), Error> {
+        let path = scratch("wait");
+        let blob = Blob::new(b"hello".to_vec());
+        let commit = commit(&blob, b"commit");
+
+        let store = block_on(Journaled::open::<Local>(MemoryStorage::default(), &path))?;
+        block_on(async {
+            Storage::<Local>::save_loose_commit(&store, commit.clone()).await?;
+            assert!(Storage::<Local>::load_loose_commits(store.inner())
+                .await
+                .map_err(JournalError::Storage)?
+                .is_empty());
+
+            Storage::<Local>::save_blob(&store, blob).await?;
+            assert_eq!(
+                Storage::<Local>::load_loose_commits(store.inner())
+                    .await
+                    .map_err(JournalError::Storage)?,
+                vec![commit]
+            );
+            Ok::<_, Error>(())
+        })?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn deleted_blobs_are_not_recovered() -> Result<(), Error> {
+        let path = scratch("delete");
+        let blob = Blob::new(b"hello".to_vec());
+        let commit = commit(&blob, b"commit");
+
+        let store = block_on(Journaled::open::<Local>(MemoryStorage::default(), &path))?;
+        block_on(async {
+            Storage::<Local>::save_blob(&store, blob.clone()).await?;
+            Storage::<Local>::save_loose_commit(&store, commit).await?;
+            Storage::<Local>::delete_blobs(&store, vec![blob.meta().digest()]).await
+        })?;
+        assert_eq!(fs::metadata(&path)?.len(), 0);
+
+        let reopened = block_on(Journaled::open::<Local>(MemoryStorage::default(), &path))?;
+        block_on(async {
+            assert!(Storage::<Local>::load_loose_commits(&reopened)
+                .await?
+                .is_empty());
+            assert_eq!(
+                Storage::<Local>::load_blob(&reopened, blob.meta().digest()).await?,
+                None
+            );
+            Ok::<_, Error>(())
+        })?;
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+}
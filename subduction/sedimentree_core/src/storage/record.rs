@@ -0,0 +1,110 @@
+//! The bytes persistent stores keep loose commits and chunks as.
+//!
+//! Stores that hold each commit or chunk as a single value, such as a file or an
+//! object, share this encoding, so that tooling reads them alike. Digests are
+//! written as their 32 bytes, and integers little-endian.
+
+use nonempty::NonEmpty;
+
+use crate::{BlobMeta, Chunk, Digest, LooseCommit};
+
+/// Encode a loose commit:
+///
+/// ```text
+/// digest (32) | blob digest (32) | blob size (u64 LE) | parent count (u32 LE) | parents (32 each)
+/// ```
+#[must_use]
+pub fn encode_commit(commit: &LooseCommit) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(76 + 32 * commit.parents().len());
+    bytes.extend_from_slice(commit.digest().as_bytes());
+    encode_blob_meta(&mut bytes, *commit.blob());
+    encode_digests(&mut bytes, commit.parents());
+    bytes
+}
+
+/// Decode a loose commit [`encode_commit`] encoded, or `None` if `bytes` isn't one.
+#[must_use]
+pub fn decode_commit(bytes: &[u8]) -> Option<LooseCommit> {
+    let mut reader = Reader(bytes);
+    let digest = reader.digest()?;
+    let blob = reader.blob_meta()?;
+    let parents = reader.digests()?;
+    reader
+        .0
+        .is_empty()
+        .then(|| LooseCommit::new(digest, parents, blob))
+}
+
+/// Encode a chunk:
+///
+/// ```text
+/// head (32) | blob digest (32) | blob size (u64 LE)
+///   | boundary count (u32 LE) | boundary (32 each)
+///   | checkpoint count (u32 LE) | checkpoints (32 each)
+/// ```
+#[must_use]
+pub fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    let mut bytes =
+        Vec::with_capacity(80 + 32 * (chunk.boundary().len() + chunk.checkpoints().len()));
+    bytes.extend_from_slice(chunk.head().as_bytes());
+    encode_blob_meta(&mut bytes, chunk.summary().blob_meta());
+    encode_digests(
+        &mut bytes,
+        &chunk.boundary().iter().copied().collect::<Vec<_>>(),
+    );
+    encode_digests(&mut bytes, chunk.checkpoints());
+    bytes
+}
+
+/// Decode a chunk [`encode_chunk`] encoded, or `None` if `bytes` isn't one.
+#[must_use]
+pub fn decode_chunk(bytes: &[u8]) -> Option<Chunk> {
+    let mut reader = Reader(bytes);
+    let head = reader.digest()?;
+    let blob = reader.blob_meta()?;
+    let boundary = NonEmpty::from_vec(reader.digests()?)?;
+    let checkpoints = reader.digests()?;
+    reader
+        .0
+        .is_empty()
+        .then(|| Chunk::new(head, boundary, checkpoints, blob))
+}
+
+fn encode_blob_meta(bytes: &mut Vec<u8>, blob: BlobMeta) {
+    bytes.extend_from_slice(blob.digest().as_bytes());
+    bytes.extend_from_slice(&blob.size_bytes().to_le_bytes());
+}
+
+fn encode_digests(bytes: &mut Vec<u8>, digests: &[Digest]) {
+    #[allow(clippy::cast_possible_truncation)]
+    bytes.extend_from_slice(&(digests.len() as u32).to_le_bytes());
+    for digest in digests {
+        bytes.extend_from_slice(digest.as_bytes());
+    }
+}
+
+/// Reads the fields of an encoded commit or chunk in turn.
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let (taken, rest) = self.0.split_first_chunk::<N>()?;
+        self.0 = rest;
+        Some(*taken)
+    }
+
+    fn digest(&mut self) -> Option<Digest> {
+        self.take::<32>().map(Digest::from)
+    }
+
+    fn blob_meta(&mut self) -> Option<BlobMeta> {
+        let digest = self.digest()?;
+        let size_bytes = u64::from_le_bytes(self.take::<8>()?);
+        Some(BlobMeta::from_digest_size(digest, size_bytes))
+    }
+
+    fn digests(&mut self) -> Option<Vec<Digest>> {
+        let count = u32::from_le_bytes(self.take::<4>()?);
+        (0..count).map(|_| self.digest()).collect()
+    }
+}
@@ -0,0 +1,380 @@
+//! Sharing accepted writes with sibling relays over Redis or NATS pub/sub, for `start`.
+//!
+//! With `--backplane redis://host:6379` or `--backplane nats://host:4222`, each
+//! commit or chunk a peer pushes to this relay is published on a channel
+//! (`--backplane-channel`), and whatever sibling relays publish there is stored
+//! and forwarded to this relay's own peers. Credentials may be given in the URL.
+//! If the broker goes away, the relay keeps serving its peers and reconnects,
+//! then publishes what it accepted in the meantime.
+//!
+//! Each payload is the publishing relay's instance ID followed by the bincode
+//! encoding of a [`BackplaneMessage`], so that a relay can skip its own messages.
+
+use std::time::Duration;
+
+use sedimentree_core::{future::Sendable, storage::MemoryStorage};
+use subduction_core::{
+    connection::Connection,
+    sync::backplane::{Backplane, BackplaneMessage},
+    Subduction,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+};
+use url::Url;
+
+/// How long to wait before reconnecting to the broker.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The ID that marks a relay's own messages.
+type InstanceId = [u8; 16];
+
+/// A [`Backplane`] that queues messages for [`run_backplane`] to publish.
+#[derive(Debug)]
+pub(crate) struct BrokerBackplane {
+    instance: InstanceId,
+    outbox: UnboundedSender<Vec<u8>>,
+}
+
+impl Backplane for BrokerBackplane {
+    fn publish(&self, message: &BackplaneMessage) {
+        let encoded = match bincode::serde::encode_to_vec(message, bincode::config::standard()) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                tracing::error!("failed to encode backplane message: {e}");
+                return;
+            }
+        };
+        let mut payload = self.instance.to_vec();
+        payload.extend(encoded);
+        if self.outbox.send(payload).is_err() {
+            tracing::warn!("backplane stopped; not publishing write to {}", message.id);
+        }
+    }
+}
+
+/// The broker connection behind a [`BrokerBackplane`].
+#[derive(Debug)]
+pub(crate) struct Broker {
+    url: Url,
+    channel: String,
+    instance: InstanceId,
+    outbox: UnboundedReceiver<Vec<u8>>,
+}
+
+/// A backplane publishing to `channel` on the Redis or NATS broker at `url`, and the
+/// broker connection to pass to [`run_backplane`].
+pub(crate) fn backplane(url: &str, channel: String) -> anyhow::Result<(BrokerBackplane, Broker)> {
+    let url = Url::parse(url)?;
+    if !matches!(url.scheme(), "redis" | "nats") {
+        anyhow::bail!("--backplane must be a redis:// or nats:// URL");
+    }
+    let instance = rand::random();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    Ok((
+        BrokerBackplane {
+            instance,
+            outbox: sender,
+        },
+        Broker {
+            url,
+            channel,
+            instance,
+            outbox: receiver,
+        },
+    ))
+}
+
+/// Publish the writes `syncer` accepts to the broker, and hand it those its sibling
+/// relays publish, reconnecting whenever the connection drops.
+pub(crate) async fn run_backplane<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    mut broker: Broker,
+) -> anyhow::Result<()> {
+    loop {
+        let session = match broker.url.scheme() {
+            "redis" => redis_session(syncer, &mut broker).await,
+            _ => nats_session(syncer, &mut broker).await,
+        };
+        if let Err(e) = session {
+            tracing::warn!("backplane connection to {} failed: {e}", broker.url);
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+/// Hand `syncer` a payload published by a sibling relay.
+async fn deliver<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    instance: &InstanceId,
+    payload: &[u8],
+) {
+    let Some((origin, encoded)) = payload.split_at_checked(instance.len()) else {
+        tracing::warn!("ignoring truncated backplane message");
+        return;
+    };
+    if origin == instance {
+        return;
+    }
+    match bincode::serde::decode_from_slice::<BackplaneMessage, _>(
+        encoded,
+        bincode::config::standard(),
+    ) {
+        Ok((message, _)) => {
+            if let Err(e) = syncer.recv_backplane(message).await {
+                tracing::warn!("failed to apply backplane message: {e}");
+            }
+        }
+        Err(e) => tracing::warn!("ignoring malformed backplane message: {e}"),
+    }
+}
+
+/// The `host:port` of the broker at `url`.
+fn address(url: &Url, default_port: u16) -> anyhow::Result<String> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("--backplane needs a host"))?;
+    Ok(format!("{host}:{}", url.port().unwrap_or(default_port)))
+}
+
+/// Read a line without its terminator, failing if the broker hung up.
+async fn read_line<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> anyhow::Result<String> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        anyhow::bail!("the broker closed the connection");
+    }
+    line.truncate(line.trim_end_matches(['\r', '\n']).len());
+    Ok(line)
+}
+
+/// Read `len` bytes followed by a line terminator.
+async fn read_payload<R: AsyncRead + Unpin>(
+    reader: &mut BufReader<R>,
+    len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let mut payload = vec![0; len + 2];
+    reader.read_exact(&mut payload).await?;
+    payload.truncate(len);
+    Ok(payload)
+}
+
+/*********
+ * REDIS *
+ *********/
+
+/// Subscribe and publish over two connections to a Redis server, as a subscribed
+/// connection can't publish.
+async fn redis_session<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    broker: &mut Broker,
+) -> anyhow::Result<()> {
+    let Broker {
+        url,
+        channel,
+        instance,
+        outbox,
+    } = broker;
+    let address = address(url, 6379)?;
+    let mut subscriber = BufReader::new(TcpStream::connect(&address).await?);
+    let mut publisher = BufReader::new(TcpStream::connect(&address).await?);
+    redis_auth(&mut subscriber, url).await?;
+    redis_auth(&mut publisher, url).await?;
+    redis_command(&mut subscriber, &[b"SUBSCRIBE", channel.as_bytes()]).await?;
+    tracing::info!("Sharing writes over Redis channel {channel} at {address}");
+
+    tokio::try_join!(
+        redis_receive(syncer, instance, &mut subscriber),
+        redis_publish(channel, outbox, &mut publisher),
+    )?;
+    Ok(())
+}
+
+/// Publish what's queued in `outbox` on `channel`.
+async fn redis_publish(
+    channel: &str,
+    outbox: &mut UnboundedReceiver<Vec<u8>>,
+    publisher: &mut BufReader<TcpStream>,
+) -> anyhow::Result<()> {
+    while let Some(payload) = outbox.recv().await {
+        redis_command(publisher, &[b"PUBLISH", channel.as_bytes(), &payload]).await?;
+        redis_reply(publisher).await?;
+    }
+    Ok(())
+}
+
+/// Deliver the messages published on the channel `subscriber` is subscribed to.
+async fn redis_receive<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    instance: &InstanceId,
+    subscriber: &mut BufReader<TcpStream>,
+) -> anyhow::Result<()> {
+    loop {
+        if let [kind, _, payload] = redis_reply(subscriber).await?.as_slice() {
+            if kind == b"message" {
+                deliver(syncer, instance, payload).await;
+            }
+        }
+    }
+}
+
+/// Authenticate with the credentials in `url`, if it has any.
+async fn redis_auth(conn: &mut BufReader<TcpStream>, url: &Url) -> anyhow::Result<()> {
+    let Some(password) = url.password() else {
+        return Ok(());
+    };
+    if url.username().is_empty() {
+        redis_command(conn, &[b"AUTH", password.as_bytes()]).await?;
+    } else {
+        redis_command(conn, &[b"AUTH", url.username().as_bytes(), password.as_bytes()]).await?;
+    }
+    redis_reply(conn).await?;
+    Ok(())
+}
+
+/// Send a command as an array of bulk strings.
+async fn redis_command(conn: &mut BufReader<TcpStream>, args: &[&[u8]]) -> anyhow::Result<()> {
+    let mut frame = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        frame.extend(format!("${}\r\n", arg.len()).into_bytes());
+        frame.extend_from_slice(arg);
+        frame.extend_from_slice(b"\r\n");
+    }
+    conn.get_mut().write_all(&frame).await?;
+    Ok(())
+}
+
+/// Read a reply, flattening an array of simple values into its elements.
+async fn redis_reply(conn: &mut BufReader<TcpStream>) -> anyhow::Result<Vec<Vec<u8>>> {
+    let line = read_line(conn).await?;
+    if let Some(count) = line.strip_prefix('*') {
+        let mut elements = Vec::new();
+        for _ in 0..count.parse::<i64>()? {
+            let element = read_line(conn).await?;
+            elements.push(redis_value(conn, &element).await?);
+        }
+        Ok(elements)
+    } else {
+        Ok(vec![redis_value(conn, &line).await?])
+    }
+}
+
+/// Read the rest of the value that starts with `line`.
+async fn redis_value(conn: &mut BufReader<TcpStream>, line: &str) -> anyhow::Result<Vec<u8>> {
+    match line.split_at_checked(1) {
+        Some(("$", len)) => match len.parse::<i64>()? {
+            len if len < 0 => Ok(Vec::new()),
+            len => read_payload(conn, usize::try_from(len)?).await,
+        },
+        Some(("+" | ":", value)) => Ok(value.as_bytes().to_vec()),
+        Some(("-", error)) => anyhow::bail!("redis: {error}"),
+        _ => anyhow::bail!("unexpected reply from redis: {line:?}"),
+    }
+}
+
+/********
+ * NATS *
+ ********/
+
+/// Subscribe and publish over one connection to a NATS server, which doesn't echo
+/// this relay's own messages back.
+async fn nats_session<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    broker: &mut Broker,
+) -> anyhow::Result<()> {
+    let Broker {
+        url,
+        channel,
+        instance,
+        outbox,
+    } = broker;
+    let address = address(url, 4222)?;
+    let (reader, writer) = TcpStream::connect(&address).await?.into_split();
+    let mut reader = BufReader::new(reader);
+    let writer = Mutex::new(writer);
+
+    read_line(&mut reader).await?; // INFO
+    let mut connect = String::from(r#"{"verbose":false,"pedantic":false,"echo":false"#);
+    if !url.username().is_empty() {
+        connect.push_str(&format!(r#","user":{}"#, json_string(url.username())));
+    }
+    if let Some(password) = url.password() {
+        connect.push_str(&format!(r#","pass":{}"#, json_string(password)));
+    }
+    nats_send(&writer, format!("CONNECT {connect}}}\r\nSUB {channel} 1\r\n").as_bytes()).await?;
+    tracing::info!("Sharing writes over NATS subject {channel} at {address}");
+
+    tokio::try_join!(
+        nats_receive(syncer, instance, &mut reader, &writer),
+        nats_publish(channel, outbox, &writer),
+    )?;
+    Ok(())
+}
+
+/// Publish what's queued in `outbox` on subject `channel`.
+async fn nats_publish(
+    channel: &str,
+    outbox: &mut UnboundedReceiver<Vec<u8>>,
+    writer: &Mutex<OwnedWriteHalf>,
+) -> anyhow::Result<()> {
+    while let Some(payload) = outbox.recv().await {
+        let mut frame = format!("PUB {channel} {}\r\n", payload.len()).into_bytes();
+        frame.extend(payload);
+        frame.extend_from_slice(b"\r\n");
+        nats_send(writer, &frame).await?;
+    }
+    Ok(())
+}
+
+/// Deliver the messages on the subject subscribed to, and answer the server's pings.
+async fn nats_receive<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    instance: &InstanceId,
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &Mutex<OwnedWriteHalf>,
+) -> anyhow::Result<()> {
+    loop {
+        let line = read_line(reader).await?;
+        let mut words = line.split_ascii_whitespace();
+        match words.next() {
+            Some("MSG") => {
+                let len = words
+                    .next_back()
+                    .ok_or_else(|| anyhow::anyhow!("malformed MSG from nats"))?
+                    .parse()?;
+                let payload = read_payload(reader, len).await?;
+                deliver(syncer, instance, &payload).await;
+            }
+            Some("PING") => nats_send(writer, b"PONG\r\n").await?,
+            Some("-ERR") => anyhow::bail!("nats: {line}"),
+            _ => {}
+        }
+    }
+}
+
+/// Write `frame` to the server.
+async fn nats_send(writer: &Mutex<OwnedWriteHalf>, frame: &[u8]) -> anyhow::Result<()> {
+    writer.lock().await.write_all(frame).await?;
+    Ok(())
+}
+
+/// `s` as a JSON string literal.
+fn json_string(s: &str) -> String {
+    let mut quoted = String::from('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => quoted.extend(['\\', c]),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
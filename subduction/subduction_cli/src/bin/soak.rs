@@ -0,0 +1,451 @@
+//! A long-running soak test of the native engine.
+//!
+//! A relay and a set of peers sync thousands of documents over local WebSockets
+//! while every peer commits continuously, and peers are periodically torn down and
+//! replaced by fresh ones that bootstrap from the relay. Memory, open file
+//! descriptors, and storage are sampled throughout and checked against budgets,
+//! and at the end every peer must have converged with the relay.
+//!
+//! Run it before a release with `cargo run --release --bin soak`. The defaults run
+//! for 24 hours; `--duration-secs` and `--docs` can be lowered for a quick check.
+
+use async_tungstenite::tokio::accept_async;
+use clap::Parser;
+use rand::{seq::IndexedRandom, Rng};
+use sedimentree_core::{
+    future::Sendable, storage::MemoryStorage, Blob, BlobMeta, Digest, LooseCommit, SedimentreeId,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use subduction_core::{connection::handshake::SigningKey, peer::id::PeerId, Subduction};
+use subduction_websocket::tokio::{
+    client::TokioWebSocketClient, server::TokioWebSocketServer, start::Start,
+};
+use tokio::{net::TcpListener, task::JoinHandle, time::Instant};
+use tungstenite::http::Uri;
+
+type Relay = Subduction<Sendable, MemoryStorage, TokioWebSocketServer>;
+type PeerEngine = Subduction<Sendable, MemoryStorage, TokioWebSocketClient>;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args = Arguments::parse();
+    let docs = (0..args.docs)
+        .map(|_| SedimentreeId::new(rand::random()))
+        .collect::<Arc<[_]>>();
+
+    let relay = Arc::new(Subduction::new(
+        HashMap::new(),
+        MemoryStorage::default(),
+        HashMap::new(),
+    ));
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>()?).await?;
+    let bound = listener.local_addr()?;
+    let uri = Uri::try_from(format!("ws://{bound}"))?;
+    tokio::spawn(accept_peers(relay.clone(), listener));
+
+    let mut peers = Vec::with_capacity(args.peers);
+    for _ in 0..args.peers {
+        peers.push(Peer::connect(&uri).await?);
+    }
+    // Only start the relay once it has connections to listen to. The first peers
+    // have nothing to bootstrap, so they don't need it running yet.
+    let relay_run = tokio::spawn({
+        let relay = relay.clone();
+        async move { relay.run().await.map_err(|e| anyhow::anyhow!("{e}")) }
+    });
+    println!(
+        "soak: {} peer(s), {} document(s), for {}s",
+        args.peers, args.docs, args.duration_secs
+    );
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(args.duration_secs);
+    let warmed_up = started + Duration::from_secs(args.warmup_secs);
+    let mut commit_tick = tokio::time::interval(Duration::from_millis(args.commit_interval_ms));
+    let mut churn_tick = tokio::time::interval(Duration::from_secs(args.churn_every_secs));
+    let mut sample_tick = tokio::time::interval(Duration::from_secs(args.sample_every_secs));
+    churn_tick.tick().await;
+
+    let mut totals = Totals::default();
+    let mut baseline: Option<(Sample, Totals)> = None;
+    let mut failures = Vec::new();
+    // A replacement peer bootstrapping in the background, and the peer it will replace.
+    // The others keep committing meanwhile, which is also what wakes the relay up to
+    // listen to the new connection.
+    let mut joining: Option<(usize, JoinHandle<anyhow::Result<Peer>>)> = None;
+
+    loop {
+        tokio::select! {
+            () = tokio::time::sleep_until(deadline) => break,
+            _ = commit_tick.tick() => {
+                for peer in &mut peers {
+                    let Some(id) = docs.choose(&mut rand::rng()).copied() else {
+                        continue;
+                    };
+                    totals.payload_bytes += peer.commit(id, args.payload_bytes).await?;
+                    totals.commits += 1;
+                }
+            }
+            _ = churn_tick.tick(), if joining.is_none() => {
+                let index = rand::rng().random_range(0..peers.len());
+                joining = Some((index, tokio::spawn(Peer::join(uri.clone(), docs.clone()))));
+            }
+            Some((index, fresh)) = async {
+                match &mut joining {
+                    Some((index, handle)) => Some((*index, handle.await)),
+                    None => None,
+                }
+            }, if joining.is_some() => {
+                joining = None;
+                std::mem::replace(&mut peers[index], fresh??).leave();
+                totals.churned += 1;
+            }
+            _ = sample_tick.tick() => {
+                let sample = Sample::take(&relay).await;
+                println!(
+                    "soak: {:>6}s  commits={}  churned={}  rss={}  fds={}  relay storage={}",
+                    started.elapsed().as_secs(),
+                    totals.commits,
+                    totals.churned,
+                    sample.rss_bytes.map_or_else(|| "n/a".to_string(), mib),
+                    sample.fds.map_or_else(|| "n/a".to_string(), |fds| fds.to_string()),
+                    mib(sample.relay_stored_bytes),
+                );
+
+                match &baseline {
+                    None if Instant::now() >= warmed_up => baseline = Some((sample, totals)),
+                    None => {}
+                    Some((base, base_totals)) => {
+                        failures.extend(check(&args, base, base_totals, &sample, &totals));
+                        if !failures.is_empty() {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    if failures.is_empty() {
+        // Let in-flight commits settle before checking convergence
+        tokio::time::sleep(Duration::from_secs(args.settle_secs)).await;
+        failures.extend(converged(&relay, &peers, &docs).await?);
+    }
+
+    if let Some((_, handle)) = joining {
+        handle.abort();
+    }
+    for peer in peers {
+        peer.leave();
+    }
+    relay_run.abort();
+
+    if failures.is_empty() {
+        println!(
+            "soak: passed after {}s ({} commits, {} peers churned)",
+            started.elapsed().as_secs(),
+            totals.commits,
+            totals.churned
+        );
+        Ok(())
+    } else {
+        for failure in &failures {
+            eprintln!("soak: FAILED: {failure}");
+        }
+        anyhow::bail!("soak test failed with {} problem(s)", failures.len())
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    author = "Ink & Switch",
+    version,
+    about = "Soak test for the Subduction engine"
+)]
+struct Arguments {
+    /// How long to run the workload for.
+    #[arg(long, default_value_t = 24 * 60 * 60)]
+    duration_secs: u64,
+
+    /// How many documents to spread commits across.
+    #[arg(long, default_value_t = 2000)]
+    docs: usize,
+
+    /// How many peers to connect to the relay at once.
+    #[arg(long, default_value_t = 4)]
+    peers: usize,
+
+    /// How often each peer makes a commit.
+    #[arg(long, default_value_t = 1000)]
+    commit_interval_ms: u64,
+
+    /// The size of each commit's contents.
+    #[arg(long, default_value_t = 64)]
+    payload_bytes: usize,
+
+    /// How often to replace a peer with a fresh one.
+    #[arg(long, default_value_t = 300)]
+    churn_every_secs: u64,
+
+    /// How often to sample resource usage.
+    #[arg(long, default_value_t = 60)]
+    sample_every_secs: u64,
+
+    /// How long to run before taking the baseline that growth is measured from.
+    #[arg(long, default_value_t = 600)]
+    warmup_secs: u64,
+
+    /// How long to wait after the workload stops before checking convergence.
+    #[arg(long, default_value_t = 10)]
+    settle_secs: u64,
+
+    /// Memory growth allowed beyond the per-commit budget, in MiB.
+    #[arg(long, default_value_t = 128)]
+    max_rss_slack_mib: u64,
+
+    /// Memory growth allowed per commit made since the baseline, across all replicas.
+    #[arg(long, default_value_t = 2048)]
+    rss_bytes_per_commit: u64,
+
+    /// How many more file descriptors may be open than at the baseline.
+    #[arg(long, default_value_t = 32)]
+    max_fd_growth: usize,
+
+    /// How much relay storage may grow relative to the contents committed.
+    #[arg(long, default_value_t = 1.1)]
+    max_storage_ratio: f64,
+}
+
+/// A peer connected to the relay, committing to random documents.
+#[derive(Debug)]
+struct Peer {
+    engine: Arc<PeerEngine>,
+    run: JoinHandle<anyhow::Result<()>>,
+    /// The ID local commits are attributed to, distinct from the relay's.
+    author: PeerId,
+    /// This peer's latest commit to each document, used as the parent of its next one.
+    heads: HashMap<SedimentreeId, Digest>,
+}
+
+impl Peer {
+    /// Connect a fresh peer to the relay and bootstrap every document from it.
+    async fn join(uri: Uri, docs: Arc<[SedimentreeId]>) -> anyhow::Result<Self> {
+        let peer = Self::connect(&uri).await?;
+        for id in docs.iter() {
+            peer.engine.request_all_batch_sync(*id, None).await?;
+        }
+        Ok(peer)
+    }
+
+    /// Connect a fresh, empty peer to the relay.
+    async fn connect(uri: &Uri) -> anyhow::Result<Self> {
+        let engine = Arc::new(Subduction::new(
+            HashMap::new(),
+            MemoryStorage::default(),
+            HashMap::new(),
+        ));
+        let conn = TokioWebSocketClient::new(
+            uri.clone(),
+            Duration::from_secs(30),
+            &SigningKey::from_bytes(&rand::random()),
+        )
+        .await?
+        .start();
+        engine.register(conn).await?;
+        let run = tokio::spawn({
+            let engine = engine.clone();
+            async move { engine.run().await.map_err(|e| anyhow::anyhow!("{e}")) }
+        });
+
+        Ok(Self {
+            engine,
+            run,
+            author: PeerId::new(rand::random()),
+            heads: HashMap::new(),
+        })
+    }
+
+    /// Commit `payload_bytes` of random contents to `id`, returning the size committed.
+    async fn commit(&mut self, id: SedimentreeId, payload_bytes: usize) -> anyhow::Result<u64> {
+        let mut contents = vec![0; payload_bytes];
+        rand::rng().fill(contents.as_mut_slice());
+        let parents = self.heads.get(&id).copied().into_iter().collect();
+        let commit = LooseCommit::new(Digest::hash(&contents), parents, BlobMeta::new(&contents));
+
+        // `recv_commit` stores the commit and forwards it to the relay without
+        // needing exclusive access to the engine, which the run loop is sharing.
+        self.engine
+            .recv_commit(&self.author, id, &commit, Blob::new(contents))
+            .await?;
+        self.heads.insert(id, commit.digest());
+        Ok(payload_bytes as u64)
+    }
+
+    /// Disconnect and drop the peer.
+    fn leave(self) {
+        self.run.abort();
+    }
+}
+
+/// Accept peer connections to `relay`, disconnecting each once its socket closes.
+async fn accept_peers(relay: Arc<Relay>, listener: TcpListener) -> anyhow::Result<()> {
+    let bound = listener.local_addr()?;
+    let key = SigningKey::from_bytes(&rand::random());
+    loop {
+        let (tcp, _addr) = listener.accept().await?;
+        let ws_stream = accept_async(tcp).await?;
+
+        // Each peer proves a key of its own, so commits are forwarded between them
+        let conn = TokioWebSocketServer::new(bound, Duration::from_secs(30), &key, ws_stream)
+            .await?
+            .ignore();
+        let listening = conn.start();
+        let (_, conn_id) = relay.register(conn).await?;
+        tokio::spawn({
+            let relay = relay.clone();
+            async move {
+                let _closed = listening.await;
+                relay.disconnect(&conn_id).await
+            }
+        });
+    }
+}
+
+/// Running counts of the workload.
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    commits: u64,
+    payload_bytes: u64,
+    churned: u64,
+}
+
+/// Resource usage at a point in time.
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// Resident memory, where the platform reports it.
+    rss_bytes: Option<u64>,
+
+    /// Open file descriptors, where the platform reports them.
+    fds: Option<usize>,
+
+    relay_stored_bytes: u64,
+}
+
+impl Sample {
+    async fn take(relay: &Relay) -> Self {
+        Self {
+            rss_bytes: rss_bytes(),
+            fds: std::fs::read_dir("/proc/self/fd").ok().map(Iterator::count),
+            relay_stored_bytes: relay.storage().stored_blob_bytes().await,
+        }
+    }
+}
+
+/// The resident set size of this process, from `/proc/self/status`.
+fn rss_bytes() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let kib = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmRSS:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    Some(kib * 1024)
+}
+
+/// Check a sample against the budgets, relative to the baseline.
+fn check(
+    args: &Arguments,
+    base: &Sample,
+    base_totals: &Totals,
+    sample: &Sample,
+    totals: &Totals,
+) -> Vec<String> {
+    let mut failures = Vec::new();
+
+    if let (Some(base_rss), Some(rss)) = (base.rss_bytes, sample.rss_bytes) {
+        let commits = totals.commits - base_totals.commits;
+        let allowed = args.max_rss_slack_mib * 1024 * 1024 + commits * args.rss_bytes_per_commit;
+        let grown = rss.saturating_sub(base_rss);
+        if grown > allowed {
+            failures.push(format!(
+                "memory grew by {} over {commits} commits (allowed {})",
+                mib(grown),
+                mib(allowed)
+            ));
+        }
+    }
+
+    if let (Some(base_fds), Some(fds)) = (base.fds, sample.fds) {
+        if fds > base_fds + args.max_fd_growth {
+            failures.push(format!(
+                "open file descriptors grew from {base_fds} to {fds} (allowed +{})",
+                args.max_fd_growth
+            ));
+        }
+    }
+
+    let stored = sample
+        .relay_stored_bytes
+        .saturating_sub(base.relay_stored_bytes);
+    let committed = totals.payload_bytes - base_totals.payload_bytes;
+    #[allow(clippy::cast_precision_loss)]
+    if stored as f64 > committed as f64 * args.max_storage_ratio {
+        failures.push(format!(
+            "relay storage grew by {} for {} of commits (allowed x{})",
+            mib(stored),
+            mib(committed),
+            args.max_storage_ratio
+        ));
+    }
+
+    failures
+}
+
+/// Sync every document from the relay to every peer, and report any that differ.
+async fn converged(
+    relay: &Relay,
+    peers: &[Peer],
+    docs: &[SedimentreeId],
+) -> anyhow::Result<Vec<String>> {
+    let mut failures = Vec::new();
+    for id in docs {
+        let expected = commit_digests(relay.get_commits(*id).await);
+        for (index, peer) in peers.iter().enumerate() {
+            peer.engine.request_all_batch_sync(*id, None).await?;
+            let actual = commit_digests(peer.engine.get_commits(*id).await);
+            if actual != expected {
+                failures.push(format!(
+                    "peer {index} has {} commit(s) of {id}, the relay has {}",
+                    actual.len(),
+                    expected.len()
+                ));
+            }
+        }
+    }
+    Ok(failures)
+}
+
+fn commit_digests(commits: Option<Vec<LooseCommit>>) -> HashSet<Digest> {
+    commits
+        .unwrap_or_default()
+        .iter()
+        .map(LooseCommit::digest)
+        .collect()
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
@@ -0,0 +1,361 @@
+//! A standalone sync server for browser peers.
+//!
+//! It listens for WebSocket connections on a TCP address, and registers every peer
+//! that connects with one engine, which keeps the sedimentrees they sync in its
+//! storage. Peers sync with `BatchSyncRequest`s, and the commits and chunks one
+//! pushes are relayed to the others connected to the same documents. Unlike the
+//! relay `subduction_cli start` runs, which serves a single connection with the
+//! moderation, audit, and mirroring its configuration asks for, the server takes any
+//! number of peers, each for as long as it stays connected.
+//!
+//! `--storage` picks where the engine keeps what peers sync: the commits and chunks
+//! peers push, and the blobs it fetches. `fs` keeps them in the directory `--data`
+//! names, so they survive a restart. The engine's store isn't keyed by document, so
+//! a restarted server only serves a document again once a peer holding it syncs,
+//! but it serves the blobs it already holds without fetching them again. For the
+//! same reason, `sqlite` keeps everything in the database file `--data` names as
+//! one sedimentree's. It syncs every write to disk before acknowledging it, unless
+//! `--sqlite-durability normal` trades the last writes before a power failure for
+//! cheaper ones. `object` keeps it in the bucket and prefix `--bucket` names, such
+//! as `s3://bucket/prefix` or `gs://bucket/prefix`, with credentials from the
+//! environment (e.g. `AWS_ACCESS_KEY_ID`), so that several servers can share it.
+//!
+//! Storing a commit or chunk takes two writes, and a server that crashes between
+//! them can leave a backend holding a record without its blob. Given `--journal`,
+//! the server logs every write to that file before passing it on to the backend,
+//! and replays what the log holds when it starts again, as
+//! [`Journaled`](sedimentree_core::storage::journal::Journaled) describes.
+//!
+//! Each peer is known by the key it proves it holds when it connects. Given
+//! `--members`, the server only syncs each document with the peers listed as its
+//! members; otherwise any peer may sync any document.
+//!
+//! Run it with `cargo run --release --bin subduction-server -- --listen 0.0.0.0:8080`,
+//! and point the WASM client's `connect` at `ws://<host>:8080`.
+
+use async_tungstenite::tokio::accept_async;
+use clap::{Parser, ValueEnum};
+use futures::{stream::FuturesUnordered, FutureExt, StreamExt};
+use sedimentree_core::{
+    future::Sendable,
+    storage::{fs::FsStorage, journal::Journaled, MemoryStorage, Storage},
+    SedimentreeId,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subduction_core::{
+    connection::handshake::SigningKey,
+    peer::id::PeerId,
+    storage::{
+        object::ObjectStorage,
+        sqlite::{self, SqliteStorage},
+    },
+    sync::membership::Members,
+    Subduction,
+};
+use subduction_websocket::tokio::{server::TokioWebSocketServer, start::Start};
+use tokio::net::TcpListener;
+use url::Url;
+
+type Relay<S> = Subduction<Sendable, S, TokioWebSocketServer>;
+
+/// How long the sync loop waits before picking back up after a failure, at first.
+const MIN_RESTART_DELAY: Duration = Duration::from_millis(10);
+
+/// The longest the sync loop waits before picking back up, after failing repeatedly.
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
+        .init();
+
+    let args = Arguments::parse();
+    let listener = TcpListener::bind(args.listen).await?;
+    tracing::info!("Serving sync on ws://{}", listener.local_addr()?);
+
+    let members = args.members.as_deref().map(read_members).transpose()?;
+    match args.storage {
+        Backend::Memory => start(MemoryStorage::default(), members, listener, &args).await,
+        Backend::Fs => {
+            let storage = FsStorage::open(&args.data)?;
+            tracing::info!("Keeping documents in {}", storage.root().display());
+            start(storage, members, listener, &args).await
+        }
+        Backend::Sqlite => {
+            // The engine's store isn't keyed by document, so all of them share one
+            let storage = SqliteStorage::open(&args.data, SedimentreeId::new([0; 32]))?;
+            storage.set_durability(args.sqlite_durability.into())?;
+            tracing::info!("Keeping documents in {}", args.data.display());
+            start(storage, members, listener, &args).await
+        }
+        Backend::Object => {
+            let bucket = args
+                .bucket
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("--storage object needs --bucket"))?;
+            // Configured like the stores' own builders, e.g. by `AWS_REGION`
+            let options = std::env::vars().map(|(key, value)| (key.to_ascii_lowercase(), value));
+            let (store, prefix) = object_store::parse_url_opts(bucket, options)?;
+            let storage =
+                ObjectStorage::open(Arc::from(store), prefix, SedimentreeId::new([0; 32])).await?;
+            tracing::info!("Keeping documents in {bucket}");
+            start(storage, members, listener, &args).await
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(
+    author = "Ink & Switch",
+    version,
+    about = "Sync server for Subduction peers"
+)]
+struct Arguments {
+    /// The address to accept WebSocket connections on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: SocketAddr,
+
+    /// Where to keep the documents peers sync.
+    #[arg(long, value_enum, default_value_t = Backend::Memory)]
+    storage: Backend,
+
+    /// Where a backend on disk keeps documents: the directory for `fs`, the database
+    /// file for `sqlite`.
+    #[arg(long, default_value = "subduction-data")]
+    data: PathBuf,
+
+    /// Whether the `sqlite` backend syncs every write to disk before acknowledging it
+    /// (`full`), or only at checkpoints (`normal`), which is cheaper but can lose the
+    /// last writes to a power failure.
+    #[arg(long, value_enum, default_value_t = Durability::Full)]
+    sqlite_durability: Durability,
+
+    /// The bucket and prefix the `object` backend keeps documents under, as a URL
+    /// such as `s3://bucket/prefix`, `gs://bucket/prefix`, or `file:///path`.
+    #[arg(long)]
+    bucket: Option<Url>,
+
+    /// A write-ahead log file to put in front of the backend, so that a crash can't
+    /// leave it holding a commit or chunk without its blob.
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// How long to wait for a peer to answer a request.
+    #[arg(long, default_value_t = 30)]
+    timeout_secs: u64,
+
+    /// Have peers wait for credit once this many bytes of their messages are waiting to
+    /// be handled, rather than sending more until calls time out.
+    #[arg(long)]
+    receive_window: Option<u64>,
+
+    /// A file holding the server's Ed25519 secret key, hex encoded, whose public key
+    /// is the peer ID it proves to peers (defaults to a new key each run).
+    #[arg(long)]
+    key: Option<PathBuf>,
+
+    /// A file listing each document's members, one `<document ID> <peer ID>` pair of
+    /// hex IDs per line, so that each document only syncs with its members.
+    #[arg(long)]
+    members: Option<PathBuf>,
+}
+
+/// The storage backends the server can keep documents in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Backend {
+    /// In memory, for as long as the server runs.
+    Memory,
+
+    /// In files under the `--data` directory.
+    Fs,
+
+    /// In the `SQLite` database at `--data`.
+    Sqlite,
+
+    /// In the object store bucket at `--bucket`.
+    Object,
+}
+
+/// How far the `sqlite` backend syncs writes, as [`sqlite::Durability`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Durability {
+    /// Before acknowledging each.
+    Full,
+
+    /// Only at checkpoints.
+    Normal,
+}
+
+impl From<Durability> for sqlite::Durability {
+    fn from(durability: Durability) -> Self {
+        match durability {
+            Durability::Full => Self::Full,
+            Durability::Normal => Self::Normal,
+        }
+    }
+}
+
+/// The key in the file at `path`, hex encoded, or a new one for this run.
+fn signing_key(path: Option<&Path>) -> anyhow::Result<SigningKey> {
+    let Some(path) = path else {
+        return Ok(SigningKey::from_bytes(&rand::random()));
+    };
+    let secret = hex::decode(std::fs::read_to_string(path)?.trim())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| anyhow::anyhow!("{} must hold a 32 byte hex key", path.display()))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// The members listed in the file at `path`, skipping blank lines and `#` comments.
+fn read_members(path: &Path) -> anyhow::Result<Members> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let parsed = line
+                .split_once(char::is_whitespace)
+                .and_then(|(doc, peer)| {
+                    let doc = doc.parse::<SedimentreeId>().ok()?;
+                    let peer = <[u8; 32]>::try_from(hex::decode(peer.trim()).ok()?).ok()?;
+                    Some((doc, PeerId::new(peer)))
+                });
+            parsed.ok_or_else(|| anyhow::anyhow!("{}: bad member line {line:?}", path.display()))
+        })
+        .collect()
+}
+
+/// Serve with `storage`, behind the write-ahead log `--journal` names if any.
+async fn start<S>(
+    storage: S,
+    members: Option<Members>,
+    listener: TcpListener,
+    args: &Arguments,
+) -> anyhow::Result<()>
+where
+    S: Storage<Sendable> + Send + Sync,
+    S::Error: Send + Sync + 'static,
+{
+    match &args.journal {
+        Some(path) => {
+            let storage = Journaled::open::<Sendable>(storage, path).await?;
+            tracing::info!("Logging writes to {}", path.display());
+            serve(new_relay(storage, members), listener, args).await
+        }
+        None => serve(new_relay(storage, members), listener, args).await,
+    }
+}
+
+fn new_relay<S: Storage<Sendable>>(storage: S, members: Option<Members>) -> Relay<S> {
+    let relay = Subduction::new(HashMap::new(), storage, HashMap::new());
+    match members {
+        Some(members) => relay.with_membership(Arc::new(members)),
+        None => relay,
+    }
+}
+
+/// Accept peers on `listener` and sync with them until the listener fails.
+async fn serve<S: Storage<Sendable>>(
+    relay: Relay<S>,
+    listener: TcpListener,
+    args: &Arguments,
+) -> anyhow::Result<()> {
+    tokio::try_join!(run(&relay), accept_peers(&relay, listener, args))?;
+    Ok(())
+}
+
+/// Run `relay`'s sync loop, picking it back up whenever a peer's connection fails.
+///
+/// A connection that keeps failing until it's disconnected would otherwise spin the
+/// loop, so it waits before picking back up, twice as long each time it fails again
+/// soon after, up to [`MAX_RESTART_DELAY`].
+async fn run<S: Storage<Sendable>>(relay: &Relay<S>) -> anyhow::Result<()> {
+    let mut delay = MIN_RESTART_DELAY;
+    loop {
+        let started = Instant::now();
+        if let Err(e) = relay.run().await {
+            tracing::debug!("a peer's connection failed: {e}");
+        }
+        if started.elapsed() > MAX_RESTART_DELAY {
+            delay = MIN_RESTART_DELAY;
+        }
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_RESTART_DELAY);
+    }
+}
+
+/// Register everyone that connects to `listener` with `relay`, and disconnect them
+/// once their connection closes.
+async fn accept_peers<S: Storage<Sendable>>(
+    relay: &Relay<S>,
+    listener: TcpListener,
+    args: &Arguments,
+) -> anyhow::Result<()> {
+    let bound = listener.local_addr()?;
+    let timeout = Duration::from_secs(args.timeout_secs);
+    let key = signing_key(args.key.as_deref())?;
+    // Handshakes run alongside each other, so one slow peer doesn't hold up the rest
+    let mut greeting = FuturesUnordered::new();
+    let mut closing = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (tcp, address) = accepted?;
+                let key = &key;
+                greeting.push(async move {
+                    let ws_stream = accept_async(tcp)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("WebSocket handshake failed: {e}"))?;
+                    // Each peer is known by the key it proves it holds
+                    let conn = TokioWebSocketServer::new(bound, timeout, key, ws_stream)
+                        .await
+                        .map_err(|e| anyhow::anyhow!("handshake failed: {e}"))?;
+                    Ok::<_, anyhow::Error>(conn)
+                }.map(move |conn| (address, conn)));
+            }
+            Some((address, greeted)) = greeting.next() => {
+                // A failed handshake only loses that peer
+                let conn = match greeted {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        tracing::warn!("{address}: {e}");
+                        continue;
+                    }
+                };
+                let conn = match args.receive_window {
+                    Some(bytes) => conn.with_receive_window(bytes),
+                    None => conn,
+                }
+                .ignore();
+                let listening = conn.start();
+                match relay.register(conn).await {
+                    Ok((_, conn_id)) => {
+                        tracing::info!("{address} connected");
+                        closing.push(async move {
+                            let _closed = listening.await;
+                            (address, conn_id)
+                        });
+                    }
+                    Err(e) => {
+                        tracing::warn!("refused {address}: {e}");
+                        listening.abort();
+                    }
+                }
+            }
+            Some((address, conn_id)) = closing.next() => {
+                if let Err(e) = relay.disconnect(&conn_id).await {
+                    tracing::debug!("disconnecting {address}: {e}");
+                }
+                tracing::info!("{address} disconnected");
+            }
+        }
+    }
+}
@@ -0,0 +1,560 @@
+//! The relay's configuration for `start`, from a TOML file, the environment, and
+//! flags.
+//!
+//! Each layer overrides the one before it: the defaults, then the file given with
+//! `--config`, then environment variables, then flags. An environment variable
+//! `SUBDUCTION_<SECTION>_<KEY>` sets `key` in `[section]`, so
+//! `SUBDUCTION_LIMITS_RECEIVE_WINDOW=65536` sets `receive_window` in `[limits]`. Its
+//! value is read as a TOML value if it is one (`["a", "b"]`, `true`, `42`), and as a
+//! string otherwise.
+//!
+//! ```toml
+//! [listen]
+//! ws = "0.0.0.0:8080"
+//! admin = "127.0.0.1:8081"
+//!
+//! [storage]
+//! backend = "memory"
+//!
+//! [limits]
+//! receive_window = 1048576
+//!
+//! [lifecycle]
+//! maintenance_interval_secs = 600
+//! expire_after_days = 30
+//!
+//! [lifecycle.tenants.<name>]
+//! documents = ["<hex sedimentree ID>"]
+//! expire_after_days = 90
+//!
+//! [lifecycle.documents.<hex sedimentree ID>]
+//! max_history_days = 7
+//!
+//! [audit]
+//! log = "audit.log"
+//!
+//! [mirror]
+//! upstream = "ws://upstream:8080"
+//! docs = ["<hex sedimentree ID>"]
+//!
+//! [moderation]
+//! scan_command = "./scan"
+//!
+//! [backplane]
+//! url = "redis://redis:6379"
+//! ```
+//!
+//! Sending the relay `SIGHUP`, or `POST /reload` to its admin endpoint, reads the
+//! file and environment again. Only `[lifecycle]` takes effect without a restart;
+//! other changes are logged and wait for one. A configuration that doesn't validate
+//! is rejected, leaving the running one in place.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use anyhow::Context;
+use sedimentree_core::SedimentreeId;
+use serde::Deserialize;
+use subduction_core::{
+    lifecycle::{LifecyclePolicy, LifecycleRules, TenantPolicy},
+    sync::scan::ContentAccess,
+};
+use tokio::sync::watch;
+use toml::value::{Table, Value};
+
+use crate::{days, Arguments, Maintenance};
+
+/// The prefix of the environment variables that override the file.
+const ENV_PREFIX: &str = "SUBDUCTION_";
+
+/// The sections of the file, which environment variables can set keys in.
+const SECTIONS: [&str; 8] = [
+    "listen",
+    "storage",
+    "limits",
+    "lifecycle",
+    "audit",
+    "mirror",
+    "moderation",
+    "backplane",
+];
+
+/// Everything `start` can be configured with.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) listen: ListenSettings,
+    pub(crate) storage: StorageSettings,
+    pub(crate) limits: LimitSettings,
+    pub(crate) lifecycle: LifecycleSettings,
+    pub(crate) audit: AuditSettings,
+    pub(crate) mirror: MirrorSettings,
+    pub(crate) moderation: ModerationSettings,
+    pub(crate) backplane: BackplaneSettings,
+}
+
+/// Where the relay serves.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ListenSettings {
+    /// The WebSocket address.
+    pub(crate) ws: SocketAddr,
+    /// Serve server-sent events on this address instead of a WebSocket.
+    pub(crate) sse: Option<SocketAddr>,
+    /// The admin endpoint's address.
+    pub(crate) admin: Option<SocketAddr>,
+}
+
+impl Default for ListenSettings {
+    fn default() -> Self {
+        Self {
+            ws: SocketAddr::from(([127, 0, 0, 1], 8080)),
+            sse: None,
+            admin: None,
+        }
+    }
+}
+
+/// Where documents are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct StorageSettings {
+    pub(crate) backend: StorageBackend,
+}
+
+/// The storage backends the relay can keep documents in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum StorageBackend {
+    /// In memory, for as long as the relay runs.
+    #[default]
+    Memory,
+}
+
+/// Limits on what clients may do.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LimitSettings {
+    /// The bytes of a WebSocket client's messages that may wait to be handled before
+    /// it has to wait for credit.
+    pub(crate) receive_window: Option<u64>,
+}
+
+/// Maintenance, and the lifecycle policies it applies.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LifecycleSettings {
+    pub(crate) maintenance_interval_secs: u64,
+    pub(crate) expire_after_days: Option<u64>,
+    pub(crate) max_history_days: Option<u64>,
+    /// Policies for particular documents, by hex sedimentree ID.
+    pub(crate) documents: HashMap<String, PolicySettings>,
+    /// Policies for tenants' documents, by tenant name.
+    pub(crate) tenants: HashMap<String, TenantSettings>,
+}
+
+impl Default for LifecycleSettings {
+    fn default() -> Self {
+        Self {
+            maintenance_interval_secs: 3600,
+            expire_after_days: None,
+            max_history_days: None,
+            documents: HashMap::new(),
+            tenants: HashMap::new(),
+        }
+    }
+}
+
+/// One document's lifecycle policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct PolicySettings {
+    pub(crate) expire_after_days: Option<u64>,
+    pub(crate) max_history_days: Option<u64>,
+}
+
+/// One tenant's documents, and their lifecycle policy.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TenantSettings {
+    /// The tenant's documents, by hex sedimentree ID.
+    pub(crate) documents: Vec<String>,
+    pub(crate) expire_after_days: Option<u64>,
+    pub(crate) max_history_days: Option<u64>,
+}
+
+impl PolicySettings {
+    fn policy(&self) -> LifecyclePolicy {
+        LifecyclePolicy {
+            expire_after_idle: self.expire_after_days.map(days),
+            max_history_age: self.max_history_days.map(days),
+            archive_on_expiry: false,
+        }
+    }
+}
+
+impl LifecycleSettings {
+    /// The maintenance these settings describe.
+    pub(crate) fn maintenance(&self) -> anyhow::Result<Maintenance> {
+        if self.maintenance_interval_secs == 0 {
+            anyhow::bail!("lifecycle.maintenance_interval_secs must be at least 1");
+        }
+        let default = PolicySettings {
+            expire_after_days: self.expire_after_days,
+            max_history_days: self.max_history_days,
+        };
+        let documents = self
+            .documents
+            .iter()
+            .map(|(id, policy)| Ok((parse_id(id, "lifecycle.documents")?, policy.policy())))
+            .collect::<anyhow::Result<_>>()?;
+        let mut tenant_of = HashMap::new();
+        let mut tenants = BTreeMap::new();
+        for (name, tenant) in &self.tenants {
+            let setting = format!("lifecycle.tenants.{name}.documents");
+            let documents = tenant
+                .documents
+                .iter()
+                .map(|id| parse_id(id, &setting))
+                .collect::<anyhow::Result<HashSet<_>>>()?;
+            for id in &documents {
+                if let Some(other) = tenant_of.insert(*id, name) {
+                    anyhow::bail!("{id} belongs to both tenant {other} and tenant {name}");
+                }
+            }
+            let policy = PolicySettings {
+                expire_after_days: tenant.expire_after_days,
+                max_history_days: tenant.max_history_days,
+            };
+            tenants.insert(
+                name.clone(),
+                TenantPolicy {
+                    documents,
+                    policy: policy.policy(),
+                },
+            );
+        }
+        Ok(Maintenance {
+            rules: LifecycleRules {
+                default: default.policy(),
+                documents,
+                tenants,
+            },
+            every: Duration::from_secs(self.maintenance_interval_secs),
+        })
+    }
+}
+
+/// The audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AuditSettings {
+    pub(crate) log: Option<PathBuf>,
+    pub(crate) checkpoint_every: u64,
+}
+
+impl Default for AuditSettings {
+    fn default() -> Self {
+        Self {
+            log: None,
+            checkpoint_every: 1000,
+        }
+    }
+}
+
+/// Documents mirrored from an upstream relay.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MirrorSettings {
+    pub(crate) upstream: Option<String>,
+    /// Hex sedimentree IDs.
+    pub(crate) docs: Vec<String>,
+    pub(crate) interval_secs: u64,
+}
+
+impl Default for MirrorSettings {
+    fn default() -> Self {
+        Self {
+            upstream: None,
+            docs: Vec::new(),
+            interval_secs: 60,
+        }
+    }
+}
+
+impl MirrorSettings {
+    pub(crate) fn docs(&self) -> anyhow::Result<Vec<SedimentreeId>> {
+        self.docs
+            .iter()
+            .map(|doc| parse_id(doc, "mirror.docs"))
+            .collect()
+    }
+}
+
+/// Scanning pushed content.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ModerationSettings {
+    pub(crate) scan_command: Option<PathBuf>,
+    pub(crate) scan_all_content: bool,
+    /// Hex sedimentree IDs whose contents the scan command may read.
+    pub(crate) scan_content_docs: Vec<String>,
+}
+
+impl ModerationSettings {
+    /// Which documents' contents the scan command may read.
+    pub(crate) fn content_access(&self) -> anyhow::Result<ContentAccess> {
+        if self.scan_all_content {
+            return Ok(ContentAccess::All);
+        }
+        if self.scan_content_docs.is_empty() {
+            return Ok(ContentAccess::None);
+        }
+        let docs = self
+            .scan_content_docs
+            .iter()
+            .map(|doc| parse_id(doc, "moderation.scan_content_docs"))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ContentAccess::Documents(docs))
+    }
+}
+
+/// Sharing writes with other relays.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BackplaneSettings {
+    pub(crate) url: Option<String>,
+    pub(crate) channel: String,
+}
+
+impl Default for BackplaneSettings {
+    fn default() -> Self {
+        Self {
+            url: None,
+            channel: "subduction".to_string(),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from the file at `path`, if any, overridden by the
+    /// environment variables `vars` and then by `args`, and validate it.
+    pub(crate) fn load(
+        path: Option<&Path>,
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: &Arguments,
+    ) -> anyhow::Result<Self> {
+        let mut table = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?;
+                toml::from_str::<Table>(&text)
+                    .with_context(|| format!("failed to parse {}", path.display()))?
+            }
+            None => Table::new(),
+        };
+        apply_env(&mut table, vars)?;
+        let mut config = Value::Table(table)
+            .try_into::<Self>()
+            .context("invalid configuration")?;
+        config.apply_args(args)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, args: &Arguments) -> anyhow::Result<()> {
+        if let Some(ws) = &args.ws {
+            self.listen.ws = ws
+                .parse()
+                .map_err(|_| anyhow::anyhow!("--ws must be an address like 127.0.0.1:8080"))?;
+        }
+        if let Some(sse) = &args.sse {
+            self.listen.sse = Some(sse.parse()?);
+        }
+        if let Some(admin) = &args.admin {
+            self.listen.admin = Some(admin.parse()?);
+        }
+        if args.receive_window.is_some() {
+            self.limits.receive_window = args.receive_window;
+        }
+
+        let lifecycle = &mut self.lifecycle;
+        if let Some(secs) = args.maintenance_interval_secs {
+            lifecycle.maintenance_interval_secs = secs;
+        }
+        if args.expire_after_days.is_some() {
+            lifecycle.expire_after_days = args.expire_after_days;
+        }
+        if args.max_history_days.is_some() {
+            lifecycle.max_history_days = args.max_history_days;
+        }
+
+        if args.audit_log.is_some() {
+            self.audit.log.clone_from(&args.audit_log);
+        }
+        if let Some(every) = args.audit_checkpoint_every {
+            self.audit.checkpoint_every = every;
+        }
+
+        if args.mirror.is_some() {
+            self.mirror.upstream.clone_from(&args.mirror);
+        }
+        if !args.mirror_doc.is_empty() {
+            self.mirror.docs.clone_from(&args.mirror_doc);
+        }
+        if let Some(secs) = args.mirror_interval_secs {
+            self.mirror.interval_secs = secs;
+        }
+
+        if args.scan_command.is_some() {
+            self.moderation.scan_command.clone_from(&args.scan_command);
+        }
+        if args.scan_all_content {
+            self.moderation.scan_all_content = true;
+        }
+        if !args.scan_content_doc.is_empty() {
+            self.moderation.scan_content_docs.clone_from(&args.scan_content_doc);
+        }
+
+        if args.backplane.is_some() {
+            self.backplane.url.clone_from(&args.backplane);
+        }
+        if let Some(channel) = &args.backplane_channel {
+            self.backplane.channel.clone_from(channel);
+        }
+        Ok(())
+    }
+
+    /// Check what the types alone don't.
+    fn validate(&self) -> anyhow::Result<()> {
+        self.lifecycle.maintenance()?;
+        self.mirror.docs()?;
+        self.moderation.content_access()?;
+        if self.limits.receive_window == Some(0) {
+            anyhow::bail!("limits.receive_window must be at least 1");
+        }
+        if self.audit.checkpoint_every == 0 {
+            anyhow::bail!("audit.checkpoint_every must be at least 1");
+        }
+        if self.mirror.upstream.is_none() && !self.mirror.docs.is_empty() {
+            anyhow::bail!("mirror.docs needs mirror.upstream");
+        }
+        if self.mirror.interval_secs == 0 {
+            anyhow::bail!("mirror.interval_secs must be at least 1");
+        }
+        Ok(())
+    }
+
+    /// Whether `other` differs in anything that needs a restart to take effect.
+    fn needs_restart_for(&self, other: &Self) -> bool {
+        let unreloadable = |config: &Self| Self {
+            lifecycle: LifecycleSettings::default(),
+            ..config.clone()
+        };
+        unreloadable(self) != unreloadable(other)
+    }
+}
+
+/// Set the keys the environment variables in `vars` name in `table`.
+fn apply_env(
+    table: &mut Table,
+    vars: impl IntoIterator<Item = (String, String)>,
+) -> anyhow::Result<()> {
+    for (name, raw) in vars {
+        let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+            continue;
+        };
+        let rest = rest.to_ascii_lowercase();
+        let Some((section, key)) = SECTIONS.iter().find_map(|section| {
+            let key = rest.strip_prefix(section)?.strip_prefix('_')?;
+            Some((*section, key))
+        }) else {
+            continue;
+        };
+
+        let value = toml::from_str::<Table>(&format!("value = {raw}"))
+            .ok()
+            .and_then(|mut parsed| parsed.remove("value"))
+            .unwrap_or(Value::String(raw));
+        table
+            .entry(section)
+            .or_insert_with(|| Value::Table(Table::new()))
+            .as_table_mut()
+            .ok_or_else(|| anyhow::anyhow!("{section} must be a table"))?
+            .insert(key.to_string(), value);
+    }
+    Ok(())
+}
+
+fn parse_id(id: &str, setting: &str) -> anyhow::Result<SedimentreeId> {
+    id.parse()
+        .map_err(|_| anyhow::anyhow!("{setting} must hold 64 character hex IDs, not {id:?}"))
+}
+
+/// Reads the configuration again on request, and applies what can change without a
+/// restart.
+#[derive(Debug)]
+pub(crate) struct Reloader {
+    args: Arguments,
+    /// The configuration in effect.
+    current: Mutex<Config>,
+    maintenance: watch::Sender<Maintenance>,
+}
+
+impl Reloader {
+    /// A reloader for the relay started with `args` and `config`.
+    pub(crate) fn new(args: Arguments, config: Config) -> anyhow::Result<Self> {
+        let (maintenance, _) = watch::channel(config.lifecycle.maintenance()?);
+        Ok(Self {
+            args,
+            current: Mutex::new(config),
+            maintenance,
+        })
+    }
+
+    /// The maintenance to do, updated on each reload.
+    pub(crate) fn maintenance(&self) -> watch::Receiver<Maintenance> {
+        self.maintenance.subscribe()
+    }
+
+    /// Read the configuration again and apply its `[lifecycle]` settings.
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let config = Config::load(self.args.config.as_deref(), std::env::vars(), &self.args)?;
+        let maintenance = config.lifecycle.maintenance()?;
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        if current.needs_restart_for(&config) {
+            tracing::warn!("only [lifecycle] changes apply without a restart");
+        }
+        if current.lifecycle != config.lifecycle {
+            self.maintenance.send_replace(maintenance);
+            // The rest is what's running until a restart, so keep comparing with it
+            current.lifecycle = config.lifecycle;
+        }
+        tracing::info!("reloaded the configuration");
+        Ok(())
+    }
+}
+
+/// Reload the configuration whenever the relay is sent `SIGHUP`.
+pub(crate) async fn reload_on_hangup(reloader: &Reloader) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = signal(SignalKind::hangup())?;
+        while hangups.recv().await.is_some() {
+            if let Err(e) = reloader.reload() {
+                tracing::error!("failed to reload the configuration: {e:#}");
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = reloader;
+    Ok(())
+}
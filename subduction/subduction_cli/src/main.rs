@@ -1,8 +1,39 @@
+mod backplane;
+mod config;
+mod moderation;
+
+use backplane::{run_backplane, Broker, BrokerBackplane};
 use clap::Parser;
-use sedimentree_core::{storage::MemoryStorage, Sedimentree, SedimentreeId};
-use std::{collections::HashMap, time::Duration};
-use subduction_core::{peer::id::PeerId, Subduction};
-use subduction_websocket::tokio::{client::TokioWebSocketClient, server::TokioWebSocketServer};
+use config::{reload_on_hangup, Config, Reloader, StorageBackend};
+use moderation::{serve_admin, CommandScanner};
+use sedimentree_core::{
+    fixtures,
+    future::Sendable,
+    storage::{fs::FsStorage, header::StorageHeader, MemoryStorage, Storage},
+    Digest, Sedimentree, SedimentreeId,
+};
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use subduction_core::{
+    audit::{self, AuditLog, AuditRecord, AuditSink},
+    connection::{handshake::SigningKey, message::Message, Connection},
+    lifecycle::LifecycleRules,
+    peer::id::PeerId,
+    storage::sqlite::SqliteStorage,
+    sync::scan::ContentAccess,
+    Subduction,
+};
+use tokio::sync::watch;
+use subduction_websocket::tokio::{
+    client::TokioWebSocketClient, server::TokioWebSocketServer, sse::TokioSseServer,
+};
 use tungstenite::http::Uri;
 
 #[tokio::main]
@@ -12,27 +43,104 @@ async fn main() -> anyhow::Result<()> {
         .init();
 
     let args = Arguments::parse();
+    let key = signing_key(args.key.as_deref())?;
 
     let sed = Sedimentree::new(vec![], vec![]);
     let sed_id = SedimentreeId::new([0u8; 32]);
 
     match args.command.as_deref() {
         Some("start") => {
-            let syncer = Subduction::new(
-                HashMap::from_iter([(sed_id, sed)]),
-                MemoryStorage::default(),
-                HashMap::new(),
-            );
-
-            let ws: TokioWebSocketServer = {
-                let addr = args.ws.parse()?;
-                TokioWebSocketServer::setup(addr, Duration::from_secs(5), PeerId::new([0; 32]))
-                    .await?
-                    .start()
+            let config = Config::load(args.config.as_deref(), std::env::vars(), &args)?;
+            let audit = config
+                .audit
+                .log
+                .as_deref()
+                .map(|path| FileAuditSink::open(path, config.audit.checkpoint_every))
+                .transpose()?;
+            let mirror = config
+                .mirror
+                .upstream
+                .as_deref()
+                .map(|upstream| {
+                    Ok::<_, anyhow::Error>(Mirror {
+                        upstream: Uri::try_from(upstream)?,
+                        key: key.clone(),
+                        docs: config.mirror.docs()?,
+                        every: Duration::from_secs(config.mirror.interval_secs),
+                    })
+                })
+                .transpose()?;
+            let moderation = Moderation {
+                scanner: config
+                    .moderation
+                    .scan_command
+                    .clone()
+                    .map(|program| {
+                        Ok::<_, anyhow::Error>((
+                            CommandScanner::new(program),
+                            config.moderation.content_access()?,
+                        ))
+                    })
+                    .transpose()?,
+                admin: config.listen.admin,
             };
+            let backplane = config
+                .backplane
+                .url
+                .as_deref()
+                .map(|url| backplane::backplane(url, config.backplane.channel.clone()))
+                .transpose()?;
+            // The only backend so far
+            let StorageBackend::Memory = config.storage.backend;
+            let listen = config.listen.clone();
+            let receive_window = config.limits.receive_window;
+            let reloader = Reloader::new(args.clone(), config)?;
 
-            syncer.register(ws).await?;
-            syncer.run().await?;
+            if let Some(sse) = listen.sse {
+                let conn = TokioSseServer::setup(sse, Duration::from_secs(5), &key)
+                    .await?
+                    .start();
+                tracing::info!("Waiting for an SSE client to authenticate");
+                let peer = conn.authenticated().await;
+                tracing::info!("Syncing with SSE client {peer}");
+                serve(
+                    Subduction::new(
+                        HashMap::from_iter([(sed_id, sed)]),
+                        MemoryStorage::default(),
+                        HashMap::new(),
+                    ),
+                    conn,
+                    audit,
+                    mirror,
+                    moderation,
+                    backplane,
+                    &reloader,
+                )
+                .await?;
+            } else {
+                let ws: TokioWebSocketServer = {
+                    let ws = TokioWebSocketServer::setup(listen.ws, Duration::from_secs(5), &key)
+                        .await?;
+                    match receive_window {
+                        Some(bytes) => ws.with_receive_window(bytes).start(),
+                        None => ws.start(),
+                    }
+                };
+                serve(
+                    Subduction::new(
+                        HashMap::from_iter([(sed_id, sed)]),
+                        MemoryStorage::default(),
+                        HashMap::new(),
+                    ),
+                    ws,
+                    audit,
+                    mirror,
+                    moderation,
+                    backplane,
+                    &reloader,
+                )
+                .await?;
+            }
         }
         Some("connect") => {
             let syncer = Subduction::new(
@@ -42,9 +150,9 @@ async fn main() -> anyhow::Result<()> {
             );
 
             let ws = TokioWebSocketClient::new(
-                Uri::try_from(&args.ws)?,
+                Uri::try_from(args.ws.as_deref().unwrap_or(DEFAULT_RELAY))?,
                 Duration::from_secs(5),
-                PeerId::new([0; 32]),
+                &key,
             )
             .await?
             .start();
@@ -54,8 +162,91 @@ async fn main() -> anyhow::Result<()> {
             syncer.request_all_batch_sync_all(None).await?;
             listen.await?;
         }
+        Some("export-sqlite") => {
+            let ws = TokioWebSocketClient::new(
+                Uri::try_from(args.ws.as_deref().unwrap_or(DEFAULT_RELAY))?,
+                Duration::from_secs(5),
+                &key,
+            )
+            .await?
+            .start();
+
+            let mut docs = args
+                .doc
+                .iter()
+                .map(|doc| doc.parse::<SedimentreeId>())
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| anyhow::anyhow!("--doc must be a 64 character hex ID"))?;
+            if docs.is_empty() {
+                let admin = args.admin.as_deref().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "export-sqlite needs --doc, or --admin to list the relay's documents"
+                    )
+                })?;
+                docs = relay_documents(admin.parse()?).await?;
+            }
+
+            let syncer = Subduction::new(
+                docs.into_iter()
+                    .map(|id| (id, Sedimentree::new(vec![], vec![])))
+                    .collect(),
+                MemoryStorage::default(),
+                HashMap::new(),
+            );
+            syncer.register(ws).await?;
+            syncer.request_all_batch_sync_all(None).await?;
+
+            let summary = syncer.export_sqlite(&args.out, None).await?;
+            println!(
+                "exported {} document(s), {} commit(s), {} chunk(s) to {}",
+                summary.documents,
+                summary.commits,
+                summary.chunks,
+                args.out.display()
+            );
+        }
+        Some("info") => {
+            let path = args.path.as_deref().ok_or_else(|| {
+                anyhow::anyhow!("info needs the path of a storage directory or SQLite file")
+            })?;
+            describe_storage(path).await?;
+        }
+        Some("verify-audit") => {
+            let path = args
+                .audit_log
+                .as_deref()
+                .ok_or_else(|| anyhow::anyhow!("--audit-log is required"))?;
+            match read_audit_log(path).and_then(|records| Ok(audit::verify(records)?)) {
+                Ok(summary) => {
+                    println!("entries:     {}", summary.entries);
+                    println!("checkpoints: {}", summary.checkpoints);
+                    println!("head:        {}", summary.head);
+                }
+                Err(e) => {
+                    eprintln!("audit log {} failed verification: {e}", path.display());
+                    std::process::exit(1);
+                }
+            }
+        }
+        Some("fixtures") => {
+            std::fs::create_dir_all(&args.fixtures_dir)?;
+            for (name, fixture) in fixtures::corpus(args.seed) {
+                let path = args
+                    .fixtures_dir
+                    .join(format!("{name}-{}.snapshot", args.seed));
+                std::fs::write(&path, fixture.to_snapshot())?;
+                println!(
+                    "{}: {} commit(s) of document {}",
+                    path.display(),
+                    fixture.commits().len(),
+                    fixture.id()
+                );
+            }
+        }
         _ => {
-            eprintln!("Please specify either 'start' or 'connect' command");
+            eprintln!(
+                "Please specify either 'start', 'connect', 'export-sqlite', 'verify-audit', 'fixtures', or 'info' command"
+            );
             std::process::exit(1);
         }
     }
@@ -63,11 +254,531 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Parser)]
+/// The documents the relay whose admin endpoint is at `admin` holds.
+///
+/// The relay only serves the endpoint once a peer has connected, so this tries
+/// again for a few seconds while it can't connect.
+async fn relay_documents(admin: SocketAddr) -> anyhow::Result<Vec<SedimentreeId>> {
+    for _ in 0..50 {
+        match moderation::list_documents(admin).await {
+            Err(e) if is_refused(&e) => tokio::time::sleep(Duration::from_millis(100)).await,
+            listed => return listed,
+        }
+    }
+    moderation::list_documents(admin).await
+}
+
+/// Whether `err` is a refused connection.
+fn is_refused(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+}
+
+/// Print the header of the storage at `path`, an `FsStorage` directory or a `SQLite`
+/// file, and what it holds.
+async fn describe_storage(path: &Path) -> anyhow::Result<()> {
+    // Opening either creates what isn't there, so only open what is
+    let documents = if path.is_dir() {
+        let storage = FsStorage::open(path)?;
+        print_header(&Storage::<Sendable>::header(&storage));
+        vec![("all documents".to_string(), contents(&storage).await?)]
+    } else if path.is_file() {
+        let storage = SqliteStorage::open(path, SedimentreeId::new([0; 32]))?;
+        print_header(&Storage::<Sendable>::header(&storage));
+        let mut documents = Vec::new();
+        for id in storage.documents()? {
+            documents.push((id.to_string(), contents(&storage.document(id)).await?));
+        }
+        documents
+    } else {
+        anyhow::bail!("{} is neither a directory nor a file", path.display());
+    };
+
+    println!("documents:        {}", documents.len());
+    for (name, contents) in documents {
+        println!(
+            "{name}: {} commit(s), {} chunk(s), {} byte(s) of blobs, {} blob(s) missing",
+            contents.commits, contents.chunks, contents.blob_bytes, contents.missing_blobs
+        );
+    }
+    Ok(())
+}
+
+fn print_header(header: &StorageHeader) {
+    println!("format version:   {}", header.format_version());
+    println!("digest algorithm: {}", header.digest_algorithm());
+    println!("codec:            {}", header.codec());
+    println!("created by:       {}", header.created_by());
+}
+
+/// What a store holds, as `info` reports it.
+#[derive(Debug, Clone, Copy, Default)]
+struct Contents {
+    commits: usize,
+    chunks: usize,
+    /// The size of the blobs the commits and chunks name.
+    blob_bytes: u64,
+    /// The blobs named that the store doesn't hold.
+    missing_blobs: usize,
+}
+
+/// Count what `storage` holds, checking that it holds the blob of each commit and chunk.
+async fn contents<S>(storage: &S) -> anyhow::Result<Contents>
+where
+    S: Storage<Sendable>,
+    S::Error: Send + Sync + 'static,
+{
+    let commits = storage.load_loose_commits().await?;
+    let chunks = storage.load_chunks().await?;
+    let blobs = commits
+        .iter()
+        .map(|commit| *commit.blob())
+        .chain(chunks.iter().map(|chunk| chunk.summary().blob_meta()))
+        .collect::<Vec<_>>();
+
+    let mut contents = Contents {
+        commits: commits.len(),
+        chunks: chunks.len(),
+        ..Contents::default()
+    };
+    for blob in blobs {
+        contents.blob_bytes += blob.size_bytes();
+        if storage.load_blob(blob.digest()).await?.is_none() {
+            contents.missing_blobs += 1;
+        }
+    }
+    Ok(contents)
+}
+
+/// The relay `connect` and `export-sqlite` sync with if `--ws` isn't given.
+const DEFAULT_RELAY: &str = "localhost:8080";
+
+/// How long a mirror waits before reconnecting to upstream after losing it, at first.
+const MIN_MIRROR_RETRY: Duration = Duration::from_secs(1);
+
+/// The longest a mirror waits before reconnecting, after losing upstream repeatedly.
+const MAX_MIRROR_RETRY: Duration = Duration::from_secs(60);
+
+/// How long a mirror waits for upstream to answer a sync.
+const MIRROR_SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The key this process proves it holds when it connects or is connected to: the
+/// hex-encoded secret key in the file at `path`, or a new one for this run.
+fn signing_key(path: Option<&Path>) -> anyhow::Result<SigningKey> {
+    let Some(path) = path else {
+        return Ok(SigningKey::from_bytes(&rand::random()));
+    };
+    let secret = hex::decode(std::fs::read_to_string(path)?.trim())
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .ok_or_else(|| anyhow::anyhow!("{} must hold a 32 byte hex key", path.display()))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// The flags `start` takes override its configuration file (see [`config`]).
+#[derive(Debug, Clone, Parser)]
 #[command(author = "Ink & Switch", version, about = "CLI for Subduction")]
 struct Arguments {
     command: Option<String>,
 
-    #[arg(short, long, default_value = "localhost:8080")]
-    ws: String,
+    /// The storage `info` describes: an `FsStorage` directory, or a `SQLite` file.
+    path: Option<PathBuf>,
+
+    /// Read `start`'s settings from this TOML file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// The address to serve on (for `start`, default `127.0.0.1:8080`), or the relay to
+    /// sync with (default `localhost:8080`).
+    #[arg(short, long)]
+    ws: Option<String>,
+
+    /// Have WebSocket clients wait for credit once this many bytes of their messages
+    /// are waiting to be handled, rather than sending more until calls time out.
+    #[arg(long)]
+    receive_window: Option<u64>,
+
+    /// A file holding this peer's Ed25519 secret key, hex encoded, whose public key
+    /// is its peer ID (defaults to a new key each run).
+    #[arg(long)]
+    key: Option<PathBuf>,
+
+    /// The SQLite file written by `export-sqlite`.
+    #[arg(long, default_value = "subduction.sqlite")]
+    out: PathBuf,
+
+    /// A hex sedimentree ID to export (may be repeated; defaults to every document
+    /// the relay's `--admin` endpoint lists).
+    #[arg(long)]
+    doc: Vec<String>,
+
+    /// Expire documents that haven't been written to for this many days.
+    #[arg(long)]
+    expire_after_days: Option<u64>,
+
+    /// Prune loose history (other than heads) older than this many days.
+    #[arg(long)]
+    max_history_days: Option<u64>,
+
+    /// Serve server-sent events (`GET /events`, `POST /messages`) on this address
+    /// instead of a WebSocket, for clients that can't use WebSockets.
+    #[arg(long)]
+    sse: Option<String>,
+
+    /// How often to run maintenance, such as lifecycle policies (default 3600). This
+    /// also bounds how long commits of ephemeral documents outlive their TTL here.
+    #[arg(long)]
+    maintenance_interval_secs: Option<u64>,
+
+    /// Append every accepted protocol message to this audit log (for `start`),
+    /// or the audit log to check (for `verify-audit`).
+    #[arg(long)]
+    audit_log: Option<PathBuf>,
+
+    /// Write a checkpoint digest to the audit log every this many entries (default 1000).
+    #[arg(long)]
+    audit_checkpoint_every: Option<u64>,
+
+    /// Mirror documents from this upstream relay (e.g. `ws://upstream:8080`), serving
+    /// them to local clients read-only.
+    #[arg(long)]
+    mirror: Option<String>,
+
+    /// A hex sedimentree ID to mirror from `--mirror` (may be repeated).
+    #[arg(long)]
+    mirror_doc: Vec<String>,
+
+    /// How often to pull mirrored documents from upstream (default 60).
+    #[arg(long)]
+    mirror_interval_secs: Option<u64>,
+
+    /// Scan each commit and chunk peers push with this command before hosting it. It
+    /// exits with 0 to accept the item, 1 to quarantine it for review, or anything else
+    /// to reject it.
+    #[arg(long)]
+    scan_command: Option<PathBuf>,
+
+    /// Give the scan command every document's contents, not just their metadata.
+    #[arg(long)]
+    scan_all_content: bool,
+
+    /// A hex sedimentree ID whose contents the scan command may read (may be repeated).
+    #[arg(long)]
+    scan_content_doc: Vec<String>,
+
+    /// Serve the quarantine review endpoint on this address (e.g. `127.0.0.1:8081`),
+    /// or, for `export-sqlite`, the relay's endpoint to list its documents from.
+    #[arg(long)]
+    admin: Option<String>,
+
+    /// Share accepted writes with the other relays using this Redis or NATS broker
+    /// (e.g. `redis://redis:6379` or `nats://nats:4222`), so that peers connected to
+    /// any of them see each write straight away.
+    #[arg(long)]
+    backplane: Option<String>,
+
+    /// The pub/sub channel (or NATS subject) the relays share writes on (default
+    /// `subduction`).
+    #[arg(long)]
+    backplane_channel: Option<String>,
+
+    /// The directory `fixtures` writes its snapshot files to.
+    #[arg(long, default_value = "fixtures")]
+    fixtures_dir: PathBuf,
+
+    /// The seed `fixtures` generates its histories from.
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// When to run maintenance, and what it does.
+#[derive(Debug, Clone)]
+struct Maintenance {
+    rules: LifecycleRules,
+    every: Duration,
+}
+
+/// How pushed content is scanned, and where what gets flagged is reviewed.
+#[derive(Debug)]
+struct Moderation {
+    scanner: Option<(CommandScanner, ContentAccess)>,
+    admin: Option<SocketAddr>,
+}
+
+/// Documents to pull from an upstream relay.
+#[derive(Debug, Clone)]
+struct Mirror {
+    upstream: Uri,
+    /// The key to prove we hold to upstream.
+    key: SigningKey,
+    docs: Vec<SedimentreeId>,
+    every: Duration,
+}
+
+/// Register `conn` with `syncer` and run it, along with periodic maintenance.
+async fn serve<C: Connection<Sendable> + PartialEq>(
+    syncer: Subduction<Sendable, MemoryStorage, C>,
+    conn: C,
+    audit: Option<FileAuditSink>,
+    mirror: Option<Mirror>,
+    moderation: Moderation,
+    backplane: Option<(BrokerBackplane, Broker)>,
+    reloader: &Reloader,
+) -> anyhow::Result<()> {
+    let syncer = match audit {
+        Some(sink) => syncer.with_audit(Arc::new(sink)),
+        None => syncer,
+    };
+    let syncer = match moderation.scanner {
+        Some((scanner, access)) => syncer.with_scanner(Arc::new(scanner), access),
+        None => syncer,
+    };
+    let (syncer, broker) = match backplane {
+        Some((backplane, broker)) => (syncer.with_backplane(Arc::new(backplane)), Some(broker)),
+        None => (syncer, None),
+    };
+    let syncer =
+        syncer.with_read_only(mirror.iter().flat_map(|mirror| mirror.docs.iter().copied()));
+    syncer.register(conn).await?;
+    tokio::try_join!(
+        async { syncer.run().await.map_err(|e| anyhow::anyhow!("{e}")) },
+        run_maintenance(&syncer, reloader.maintenance()),
+        reload_on_hangup(reloader),
+        async {
+            match &mirror {
+                Some(mirror) => run_mirror(&syncer, mirror).await,
+                None => Ok(()),
+            }
+        },
+        async {
+            match moderation.admin {
+                Some(address) => serve_admin(&syncer, address, reloader).await,
+                None => Ok(()),
+            }
+        },
+        async {
+            match broker {
+                Some(broker) => run_backplane(&syncer, broker).await,
+                None => Ok(()),
+            }
+        },
+    )?;
+    Ok(())
+}
+
+/// Periodically pull the mirrored documents from upstream into `local`.
+///
+/// Nothing is ever pushed upstream: the mirrored documents are read-only here, so
+/// there is no local history to send it.
+///
+/// Losing upstream doesn't stop the local relay, which goes on serving what it has
+/// mirrored so far. The mirror reconnects, waiting twice as long each time upstream
+/// fails again soon after, up to [`MAX_MIRROR_RETRY`].
+async fn run_mirror<C: Connection<Sendable> + PartialEq>(
+    local: &Subduction<Sendable, MemoryStorage, C>,
+    mirror: &Mirror,
+) -> anyhow::Result<()> {
+    let mut delay = MIN_MIRROR_RETRY;
+    loop {
+        let started = Instant::now();
+        if let Err(e) = mirror_from_upstream(local, mirror).await {
+            tracing::warn!("mirror: lost upstream {}: {e:#}", mirror.upstream);
+        }
+        if started.elapsed() > MAX_MIRROR_RETRY {
+            delay = MIN_MIRROR_RETRY;
+        }
+        tracing::info!("mirror: reconnecting to {} in {delay:?}", mirror.upstream);
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(MAX_MIRROR_RETRY);
+    }
+}
+
+/// Connect to upstream and pull from it until the connection fails.
+async fn mirror_from_upstream<C: Connection<Sendable> + PartialEq>(
+    local: &Subduction<Sendable, MemoryStorage, C>,
+    mirror: &Mirror,
+) -> anyhow::Result<()> {
+    let upstream = Subduction::new(HashMap::new(), MemoryStorage::default(), HashMap::new());
+    let conn =
+        TokioWebSocketClient::new(mirror.upstream.clone(), Duration::from_secs(5), &mirror.key)
+            .await?
+            .start();
+    // Upstream's key, which no local client has, so they get the mirrored commits
+    // forwarded to them
+    let upstream_id = conn.peer_id();
+    upstream.register(conn).await?;
+    tracing::info!(
+        "Mirroring {} document(s) from {}",
+        mirror.docs.len(),
+        mirror.upstream
+    );
+
+    tokio::try_join!(
+        async { upstream.run().await.map_err(|e| anyhow::anyhow!("{e}")) },
+        pull_mirror(&upstream, local, &upstream_id, mirror),
+    )?;
+    Ok(())
+}
+
+/// Every `mirror.every`, sync the mirrored documents from upstream and copy them to
+/// `local`, until upstream stops answering.
+async fn pull_mirror<C: Connection<Sendable> + PartialEq>(
+    upstream: &Subduction<Sendable, MemoryStorage, TokioWebSocketClient>,
+    local: &Subduction<Sendable, MemoryStorage, C>,
+    upstream_id: &PeerId,
+    mirror: &Mirror,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(mirror.every);
+    loop {
+        interval.tick().await;
+        for id in &mirror.docs {
+            let answered = upstream
+                .request_all_batch_sync(*id, Some(MIRROR_SYNC_TIMEOUT))
+                .await
+                .map_err(|e| anyhow::anyhow!("{e}"))?
+                .values()
+                .any(|(success, _)| *success);
+            anyhow::ensure!(answered, "upstream didn't answer a sync of {id}");
+            match copy_sedimentree(upstream, local, upstream_id, *id).await {
+                Ok(0) => {}
+                Ok(copied) => tracing::info!("mirror: copied {copied} new item(s) of {id}"),
+                Err(e) => tracing::error!("mirror: failed to copy {id}: {e:#}"),
+            }
+        }
+    }
+}
+
+/// Copy the commits and chunks of sedimentree `id` that `to` doesn't have yet from
+/// `from`, returning how many were new.
+async fn copy_sedimentree<C: Connection<Sendable> + PartialEq>(
+    from: &Subduction<Sendable, MemoryStorage, TokioWebSocketClient>,
+    to: &Subduction<Sendable, MemoryStorage, C>,
+    source: &PeerId,
+    id: SedimentreeId,
+) -> anyhow::Result<usize> {
+    let mut copied = 0;
+    for commit in from.get_commits(id).await.unwrap_or_default() {
+        let Some(blob) = from.get_local_blob(commit.blob().digest()).await? else {
+            continue;
+        };
+        let was_new = to
+            .recv_commit(source, id, &commit, blob)
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        if was_new {
+            copied += 1;
+        }
+    }
+    for chunk in from.get_chunks(id).await.unwrap_or_default() {
+        let Some(blob) = from
+            .get_local_blob(chunk.summary().blob_meta().digest())
+            .await?
+        else {
+            continue;
+        };
+        let was_new = to
+            .recv_chunk(source, id, &chunk, blob)
+            .await
+            .map_err(|e| anyhow::anyhow!("{e}"))?;
+        if was_new {
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Periodically apply lifecycle rules, logging what they did.
+///
+/// When the configuration is reloaded, maintenance runs straight away under the new
+/// rules, and then at the new interval.
+async fn run_maintenance<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    mut maintenance: watch::Receiver<Maintenance>,
+) -> anyhow::Result<()> {
+    let mut interval = tokio::time::interval(maintenance.borrow().every);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            Ok(()) = maintenance.changed() => {
+                interval = tokio::time::interval(maintenance.borrow_and_update().every);
+                continue;
+            }
+        }
+        let rules = maintenance.borrow().rules.clone();
+        let report = syncer
+            .run_lifecycle::<MemoryStorage>(&rules, SystemTime::now(), None)
+            .await?;
+        for (id, action) in &report.actions {
+            tracing::info!("lifecycle: {id} {action:?}");
+        }
+    }
+}
+
+const fn days(n: u64) -> Duration {
+    Duration::from_secs(n * 24 * 60 * 60)
+}
+
+/// Read every record from an audit log file.
+fn read_audit_log(path: &Path) -> anyhow::Result<Vec<AuditRecord>> {
+    BufReader::new(File::open(path)?)
+        .lines()
+        .filter(|line| line.as_ref().map_or(true, |line| !line.trim().is_empty()))
+        .map(|line| Ok(line?.parse()?))
+        .collect()
+}
+
+/// An [`AuditSink`] that appends records to a file, one per line.
+#[derive(Debug)]
+struct FileAuditSink {
+    inner: Mutex<(File, AuditLog)>,
+}
+
+impl FileAuditSink {
+    /// Open (or create) the audit log at `path`, resuming its chain if it already
+    /// has entries.
+    fn open(path: &Path, checkpoint_every: u64) -> anyhow::Result<Self> {
+        let log = if path.exists() {
+            AuditLog::resume(read_audit_log(path)?, checkpoint_every)?
+        } else {
+            AuditLog::new(checkpoint_every)
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        tracing::info!("Writing audit log to {}", path.display());
+        Ok(Self {
+            inner: Mutex::new((file, log)),
+        })
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record(
+        &self,
+        sender: PeerId,
+        message: &Message,
+        sender_signature: Option<&[u8]>,
+        received_at: SystemTime,
+    ) {
+        let bytes = match bincode::serde::encode_to_vec(message, bincode::config::standard()) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                tracing::error!("failed to encode message for the audit log: {e}");
+                return;
+            }
+        };
+
+        let Ok(mut inner) = self.inner.lock() else {
+            tracing::error!("audit log lock poisoned");
+            return;
+        };
+        let (file, log) = &mut *inner;
+        let signature = sender_signature.map(<[u8]>::to_vec);
+        let records = log.append(sender, Digest::hash(&bytes), signature, received_at);
+        let written = records
+            .iter()
+            .try_for_each(|record| writeln!(file, "{record}"))
+            .and_then(|()| file.flush());
+        if let Err(e) = written {
+            tracing::error!("failed to write to the audit log: {e}");
+        }
+    }
 }
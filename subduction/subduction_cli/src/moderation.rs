@@ -0,0 +1,305 @@
+//! Content moderation for `start`: scanning pushed content with an external
+//! command, and an HTTP endpoint for reviewing what it flags.
+//!
+//! The scan command runs once for each commit or chunk a peer pushes, with the
+//! item's metadata in its environment (`SUBDUCTION_DOC`, `SUBDUCTION_PEER`,
+//! `SUBDUCTION_KIND`, `SUBDUCTION_DIGEST`, and `SUBDUCTION_SIZE`) and, if the
+//! document's contents may be read, the contents on stdin. It exits with 0 to
+//! accept the item, 1 to flag it for review, or anything else to reject it,
+//! and may print the reason. Items the command can't be run for are flagged.
+//!
+//! The admin endpoint serves:
+//!
+//! * `GET /quarantine`: the flagged items, one per line
+//! * `POST /quarantine/<digest>/release`: host a flagged item after all
+//! * `POST /quarantine/<digest>/discard`: drop a flagged item
+//! * `GET /dedup`: how many pushes were of content the relay already held
+//! * `GET /documents`: the IDs of the documents the relay holds, one per line
+//! * `GET /lifecycle`: what the last maintenance run did to documents
+//! * `GET /lifecycle/<sedimentree id>`: when a document was first seen and last
+//!   written to, as of the last maintenance run
+//! * `POST /reload`: reload the relay's configuration (see [`crate::config`])
+
+use std::{
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    process::Stdio,
+    time::{Duration, SystemTime},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use sedimentree_core::{future::Sendable, storage::MemoryStorage, Digest, SedimentreeId};
+use subduction_core::{
+    connection::Connection,
+    lifecycle::{LifecycleAction, LifecycleReport},
+    sync::scan::{ContentScanner, ItemKind, QuarantineEntry, ScannedItem, Verdict},
+    Subduction,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    process::Command,
+};
+
+use crate::config::Reloader;
+
+/// A [`ContentScanner`] that asks an external command about each item.
+#[derive(Debug)]
+pub(crate) struct CommandScanner {
+    program: PathBuf,
+}
+
+impl CommandScanner {
+    pub(crate) const fn new(program: PathBuf) -> Self {
+        Self { program }
+    }
+
+    async fn run(&self, item: &ScannedItem) -> io::Result<Verdict> {
+        let mut child = Command::new(&self.program)
+            .env("SUBDUCTION_DOC", item.id.to_string())
+            .env("SUBDUCTION_PEER", item.from.to_string())
+            .env("SUBDUCTION_KIND", kind_name(item.kind))
+            .env("SUBDUCTION_DIGEST", item.digest.to_string())
+            .env("SUBDUCTION_SIZE", item.size.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        if let (Some(mut stdin), Some(content)) = (child.stdin.take(), &item.content) {
+            // A command that decides without reading its input closes the pipe early
+            match stdin.write_all(content.as_slice()).await {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => return Err(e),
+                _ => {}
+            }
+        }
+
+        let output = child.wait_with_output().await?;
+        let reason = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        Ok(match output.status.code() {
+            Some(0) => Verdict::Accept,
+            Some(1) => Verdict::Flag { reason },
+            _ => Verdict::Reject { reason },
+        })
+    }
+}
+
+impl ContentScanner<Sendable> for CommandScanner {
+    fn scan<'a>(&'a self, item: &'a ScannedItem) -> BoxFuture<'a, Verdict> {
+        async move {
+            self.run(item).await.unwrap_or_else(|e| {
+                tracing::error!("failed to run scan command {}: {e}", self.program.display());
+                Verdict::Flag {
+                    reason: format!("scan command failed: {e}"),
+                }
+            })
+        }
+        .boxed()
+    }
+}
+
+/// Serve the admin endpoint for `syncer`'s quarantine, and for reloading with
+/// `reloader`, on `address`.
+pub(crate) async fn serve_admin<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    address: SocketAddr,
+    reloader: &Reloader,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving the admin endpoint on {address}");
+    loop {
+        let (tcp, peer) = listener.accept().await?;
+        if let Err(e) = handle_admin(syncer, reloader, tcp).await {
+            tracing::warn!("admin request from {peer} failed: {e}");
+        }
+    }
+}
+
+async fn handle_admin<C: Connection<Sendable> + PartialEq>(
+    syncer: &Subduction<Sendable, MemoryStorage, C>,
+    reloader: &Reloader,
+    tcp: TcpStream,
+) -> anyhow::Result<()> {
+    let (reader, mut writer) = tcp.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+    let segments = path.trim_matches('/').split('/').collect::<Vec<_>>();
+
+    let (status, body) = match (method, segments.as_slice()) {
+        ("GET", ["quarantine"]) => {
+            let lines = syncer
+                .quarantined()
+                .await
+                .iter()
+                .map(|entry| format!("{}\n", describe(entry)))
+                .collect::<String>();
+            ("200 OK", lines)
+        }
+        ("POST", ["quarantine", digest, action @ ("release" | "discard")]) => {
+            match digest.parse::<Digest>() {
+                Err(_) => ("400 Bad Request", "invalid digest\n".to_string()),
+                Ok(digest) => {
+                    let found = if *action == "release" {
+                        syncer
+                            .release_quarantined(digest)
+                            .await
+                            .map_err(|e| anyhow::anyhow!("{e}"))?
+                    } else {
+                        syncer.discard_quarantined(digest).await
+                    };
+                    if found {
+                        tracing::info!("admin: {action}d {digest}");
+                        ("200 OK", format!("{action}d {digest}\n"))
+                    } else {
+                        ("404 Not Found", "no such quarantined item\n".to_string())
+                    }
+                }
+            }
+        }
+        ("GET", ["dedup"]) => {
+            let stats = syncer.dedup_stats();
+            (
+                "200 OK",
+                format!(
+                    "duplicates {}\nduplicate_bytes {}\nuploads_cut_short {}\n",
+                    stats.duplicates, stats.duplicate_bytes, stats.uploads_cut_short
+                ),
+            )
+        }
+        ("GET", ["documents"]) => {
+            let lines = syncer
+                .sedimentree_ids()
+                .await
+                .iter()
+                .map(|id| format!("{id}\n"))
+                .collect::<String>();
+            ("200 OK", lines)
+        }
+        ("GET", ["lifecycle"]) => match syncer.last_lifecycle_report().await {
+            Some(report) => ("200 OK", describe_report(&report)),
+            None => ("404 Not Found", "maintenance hasn't run yet\n".to_string()),
+        },
+        ("GET", ["lifecycle", id]) => match id.parse::<SedimentreeId>() {
+            Err(_) => ("400 Bad Request", "invalid sedimentree ID\n".to_string()),
+            Ok(id) => match syncer.document_activity(id).await {
+                Some(activity) => (
+                    "200 OK",
+                    format!(
+                        "first_seen {}\nlast_touched {}\n",
+                        unix_secs(activity.first_seen),
+                        unix_secs(activity.last_touched)
+                    ),
+                ),
+                None => ("404 Not Found", "no such document\n".to_string()),
+            },
+        },
+        ("POST", ["reload"]) => match reloader.reload() {
+            Ok(()) => ("200 OK", "reloaded\n".to_string()),
+            Err(e) => {
+                tracing::error!("failed to reload the configuration: {e:#}");
+                ("400 Bad Request", format!("{e:#}\n"))
+            }
+        },
+        _ => ("404 Not Found", String::new()),
+    };
+
+    writer
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Ask the admin endpoint at `address` for the documents its relay holds.
+pub(crate) async fn list_documents(address: SocketAddr) -> anyhow::Result<Vec<SedimentreeId>> {
+    let mut tcp = TcpStream::connect(address).await?;
+    tcp.write_all(b"GET /documents HTTP/1.1\r\nConnection: close\r\n\r\n")
+        .await?;
+    let mut response = String::new();
+    tcp.read_to_string(&mut response).await?;
+
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| anyhow::anyhow!("malformed response from {address}"))?;
+    let status = head.lines().next().unwrap_or_default();
+    anyhow::ensure!(
+        status.split_whitespace().nth(1) == Some("200"),
+        "{address} answered {status}"
+    );
+    body.lines()
+        .map(|line| {
+            line.parse::<SedimentreeId>()
+                .map_err(|_| anyhow::anyhow!("{address} listed an invalid ID {line:?}"))
+        })
+        .collect()
+}
+
+/// One line of `GET /quarantine`: digest, document, peer, kind, size, flagged-at
+/// (seconds since the Unix epoch), and reason.
+fn describe(entry: &QuarantineEntry) -> String {
+    let item = &entry.item;
+    let flagged_at = unix_secs(entry.flagged_at);
+    format!(
+        "{} {} {} {} {} {flagged_at} {}",
+        item.digest,
+        item.id,
+        item.from,
+        kind_name(item.kind),
+        item.size,
+        entry.reason
+    )
+}
+
+/// The body of `GET /lifecycle`: when the run was (in seconds since the Unix epoch)
+/// and how many documents it evaluated, then a line per action: the document, what
+/// was done, and how long it had been idle or how many commits went.
+fn describe_report(report: &LifecycleReport) -> String {
+    let mut lines = format!(
+        "evaluated_at {}\ndocuments_evaluated {}\n",
+        unix_secs(report.evaluated_at),
+        report.documents_evaluated
+    );
+    for (id, action) in &report.actions {
+        let (name, amount) = match action {
+            LifecycleAction::Expired { idle_for } => ("expired", idle_for.as_secs()),
+            LifecycleAction::Archived { idle_for } => ("archived", idle_for.as_secs()),
+            LifecycleAction::HistoryPruned { commits } => ("history_pruned", commits.len() as u64),
+            LifecycleAction::CommitsExpired { commits } => {
+                ("commits_expired", commits.len() as u64)
+            }
+        };
+        lines.push_str(&format!("{id} {name} {amount}\n"));
+    }
+    lines
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+const fn kind_name(kind: ItemKind) -> &'static str {
+    match kind {
+        ItemKind::Commit => "commit",
+        ItemKind::Chunk => "chunk",
+    }
+}
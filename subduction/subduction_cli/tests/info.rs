@@ -0,0 +1,94 @@
+use sedimentree_core::{
+    future::Sendable,
+    storage::{fs::FsStorage, Storage},
+    Blob, BlobMeta, Digest, LooseCommit, SedimentreeId,
+};
+use subduction_core::storage::sqlite::SqliteStorage;
+
+#[tokio::test]
+async fn describes_a_directory() -> anyhow::Result<()> {
+    let data = std::env::temp_dir().join(format!("subduction-info-fs-{}", std::process::id()));
+    let storage = FsStorage::open(&data)?;
+    let (held, blob) = commit(1, b"held");
+    let (missing, _) = commit(2, b"missing");
+    <FsStorage as Storage<Sendable>>::save_loose_commit(&storage, held).await?;
+    <FsStorage as Storage<Sendable>>::save_blob(&storage, blob).await?;
+    <FsStorage as Storage<Sendable>>::save_loose_commit(&storage, missing).await?;
+
+    let output = info(&data)?;
+    std::fs::remove_dir_all(&data)?;
+    assert!(output.contains("format version:   1"), "{output}");
+    assert!(output.contains("documents:        1"), "{output}");
+    assert!(
+        output.contains(
+            "all documents: 2 commit(s), 0 chunk(s), 11 byte(s) of blobs, 1 blob(s) missing"
+        ),
+        "{output}"
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn describes_each_document_of_a_database() -> anyhow::Result<()> {
+    let data = std::env::temp_dir().join(format!("subduction-info-sqlite-{}", std::process::id()));
+    let (first, second) = (SedimentreeId::new([1; 32]), SedimentreeId::new([2; 32]));
+    let storage = SqliteStorage::open(&data, first)?;
+    for (id, byte) in [(first, 1), (second, 2)] {
+        let (commit, blob) = commit(byte, b"held");
+        let storage = storage.document(id);
+        <SqliteStorage as Storage<Sendable>>::save_loose_commit(&storage, commit).await?;
+        <SqliteStorage as Storage<Sendable>>::save_blob(&storage, blob).await?;
+    }
+    drop(storage);
+
+    let output = info(&data)?;
+    for suffix in ["", "-wal", "-shm"] {
+        std::fs::remove_file(format!("{}{suffix}", data.display())).ok();
+    }
+    assert!(output.contains("documents:        2"), "{output}");
+    for id in [first, second] {
+        assert!(
+            output.contains(&format!(
+                "{id}: 1 commit(s), 0 chunk(s), 4 byte(s) of blobs, 0 blob(s) missing"
+            )),
+            "{output}"
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn refuses_a_path_that_isnt_there() -> anyhow::Result<()> {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_subduction_cli"))
+        .arg("info")
+        .arg(std::env::temp_dir().join("subduction-info-nowhere"))
+        .output()?;
+    assert!(!output.status.success());
+    Ok(())
+}
+
+fn commit(byte: u8, contents: &[u8]) -> (LooseCommit, Blob) {
+    let blob = Blob::new(contents.to_vec());
+    let commit = LooseCommit::new(
+        Digest::from([byte; 32]),
+        vec![],
+        BlobMeta::new(blob.as_slice()),
+    );
+    (commit, blob)
+}
+
+/// Run `subduction_cli info` on `path`, returning what it prints.
+fn info(path: &std::path::Path) -> anyhow::Result<String> {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_subduction_cli"))
+        .arg("info")
+        .arg(path)
+        .output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(String::from_utf8(output.stdout)?)
+}
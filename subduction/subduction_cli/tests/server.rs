@@ -0,0 +1,298 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use object_store::{local::LocalFileSystem, path::Path};
+use sedimentree_core::{
+    future::Sendable,
+    storage::{fs::FsStorage, journal::Journaled, MemoryStorage, Storage},
+    Blob, BlobMeta, Digest, LooseCommit, Sedimentree, SedimentreeId,
+};
+use subduction_core::{
+    connection::handshake::SigningKey,
+    storage::{object::ObjectStorage, sqlite::SqliteStorage},
+    Subduction,
+};
+use subduction_websocket::tokio::client::TokioWebSocketClient;
+use tokio::{
+    net::TcpListener,
+    process::{Child, Command},
+};
+
+type Peer = Subduction<Sendable, MemoryStorage, TokioWebSocketClient>;
+
+#[tokio::test]
+async fn relays_commits_between_peers() -> anyhow::Result<()> {
+    let (_server, address) = spawn_server(&[]).await?;
+    let doc = SedimentreeId::new([7; 32]);
+
+    let blob = Blob::new(b"hello from alice".to_vec());
+    let commit = LooseCommit::new(
+        Digest::from([1; 32]),
+        vec![],
+        BlobMeta::new(blob.as_slice()),
+    );
+    let alice = connect(address, 1, doc, vec![(commit.clone(), blob)]).await?;
+    assert!(alice.request_all_batch_sync_all(None).await?);
+
+    let bob = connect(address, 2, doc, Vec::new()).await?;
+    assert_eq!(synced(&bob, doc).await?, vec![commit]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_what_peers_sync_on_disk() -> anyhow::Result<()> {
+    let data = std::env::temp_dir().join(format!("subduction-server-fs-{}", std::process::id()));
+    let blob = sync_through(&["--storage", "fs", "--data", &data.to_string_lossy()]).await?;
+
+    let stored = FsStorage::open(&data)?;
+    let kept = <FsStorage as Storage<Sendable>>::load_blob(&stored, blob.meta().digest()).await?;
+    std::fs::remove_dir_all(&data)?;
+    assert_eq!(kept, Some(blob));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_what_peers_sync_in_sqlite() -> anyhow::Result<()> {
+    let data =
+        std::env::temp_dir().join(format!("subduction-server-sqlite-{}", std::process::id()));
+    let blob = sync_through(&["--storage", "sqlite", "--data", &data.to_string_lossy()]).await?;
+
+    let stored = SqliteStorage::open(&data, SedimentreeId::new([0; 32]))?;
+    let kept =
+        <SqliteStorage as Storage<Sendable>>::load_blob(&stored, blob.meta().digest()).await?;
+    drop(stored);
+    for suffix in ["", "-wal", "-shm"] {
+        std::fs::remove_file(format!("{}{suffix}", data.display())).ok();
+    }
+    assert_eq!(kept, Some(blob));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_what_peers_sync_in_a_bucket() -> anyhow::Result<()> {
+    let data =
+        std::env::temp_dir().join(format!("subduction-server-object-{}", std::process::id()));
+    std::fs::create_dir_all(&data)?;
+    let bucket = format!("file://{}/documents", data.display());
+    let blob = sync_through(&["--storage", "object", "--bucket", &bucket]).await?;
+
+    let stored = ObjectStorage::open(
+        Arc::new(LocalFileSystem::new_with_prefix(&data)?),
+        Path::from("documents"),
+        SedimentreeId::new([0; 32]),
+    )
+    .await?;
+    let kept =
+        <ObjectStorage as Storage<Sendable>>::load_blob(&stored, blob.meta().digest()).await?;
+    std::fs::remove_dir_all(&data)?;
+    assert_eq!(kept, Some(blob));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn logs_writes_to_a_journal() -> anyhow::Result<()> {
+    let data =
+        std::env::temp_dir().join(format!("subduction-server-journal-{}", std::process::id()));
+    let journal = data.with_extension("log");
+    let blob = sync_through(&[
+        "--storage",
+        "fs",
+        "--data",
+        &data.to_string_lossy(),
+        "--journal",
+        &journal.to_string_lossy(),
+    ])
+    .await?;
+
+    let logged = journal.exists();
+    let stored = Journaled::open::<Sendable>(FsStorage::open(&data)?, &journal).await?;
+    let kept =
+        <Journaled<FsStorage> as Storage<Sendable>>::load_blob(&stored, blob.meta().digest())
+            .await?;
+    std::fs::remove_dir_all(&data)?;
+    std::fs::remove_file(&journal)?;
+    assert!(logged);
+    assert_eq!(kept, Some(blob));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirrors_on_through_upstream_going_down() -> anyhow::Result<()> {
+    let (upstream, upstream_address) = spawn_server(&[]).await?;
+    let doc = SedimentreeId::new([9; 32]);
+    let commit = |byte: u8, contents: &[u8]| {
+        let blob = Blob::new(contents.to_vec());
+        let commit = LooseCommit::new(
+            Digest::from([byte; 32]),
+            vec![],
+            BlobMeta::new(blob.as_slice()),
+        );
+        (commit, blob)
+    };
+    let before = commit(3, b"before the outage");
+    let alice = connect(upstream_address, 5, doc, vec![before.clone()]).await?;
+    assert!(alice.request_all_batch_sync_all(None).await?);
+
+    let mirror_address = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    let mut mirror = Command::new(env!("CARGO_BIN_EXE_subduction_cli"))
+        .arg("start")
+        .args(["--ws", &mirror_address.to_string()])
+        .args(["--mirror", &format!("ws://{upstream_address}")])
+        .args(["--mirror-doc", &doc.to_string()])
+        .args(["--mirror-interval-secs", "1"])
+        .kill_on_drop(true)
+        .spawn()?;
+    // The relay only takes one connection, so it can't be probed before connecting
+    let mut bob = None;
+    for _ in 0..100 {
+        match connect(mirror_address, 6, doc, Vec::new()).await {
+            Ok(peer) => {
+                bob = Some(peer);
+                break;
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+        }
+    }
+    let bob = bob.ok_or_else(|| anyhow::anyhow!("the mirror never listened"))?;
+    assert_eq!(synced(&bob, doc).await?, vec![before.0.clone()]);
+
+    drop(upstream);
+    drop(alice);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert!(
+        mirror.try_wait()?.is_none(),
+        "the mirror exited with upstream"
+    );
+    assert!(bob.request_all_batch_sync_all(None).await?);
+    assert_eq!(bob.get_commits(doc).await, Some(vec![before.0.clone()]));
+
+    // Once upstream is back, the mirror picks up where it left off
+    let (_upstream, _) = spawn_server_on(upstream_address, &[]).await?;
+    let after = commit(4, b"after the outage");
+    let alice = connect(upstream_address, 5, doc, vec![before, after.clone()]).await?;
+    assert!(alice.request_all_batch_sync_all(None).await?);
+    for _ in 0..200 {
+        bob.request_all_batch_sync_all(None).await?;
+        if bob
+            .get_commits(doc)
+            .await
+            .unwrap_or_default()
+            .contains(&after.0)
+        {
+            return Ok(());
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    anyhow::bail!("the mirror never pulled from upstream again")
+}
+
+/// Have one peer sync a commit through a server started with `args` to another,
+/// then stop the server, returning the commit's blob.
+///
+/// The commit comes in the first peer's summary rather than pushed, so the server
+/// only stores its blob, which it fetches from them once the second wants it.
+async fn sync_through(args: &[&str]) -> anyhow::Result<Blob> {
+    let (mut server, address) = spawn_server(args).await?;
+    let doc = SedimentreeId::new([8; 32]);
+
+    let blob = Blob::new(b"hello from carol".to_vec());
+    let commit = LooseCommit::new(
+        Digest::from([2; 32]),
+        vec![],
+        BlobMeta::new(blob.as_slice()),
+    );
+    let carol = connect(address, 3, doc, vec![(commit.clone(), blob.clone())]).await?;
+    assert!(carol.request_all_batch_sync_all(None).await?);
+    let dave = connect(address, 4, doc, Vec::new()).await?;
+    assert_eq!(synced(&dave, doc).await?, vec![commit]);
+
+    server.kill().await?;
+    Ok(blob)
+}
+
+/// Sync `peer` until it holds a commit of `doc`, returning its commits.
+///
+/// The server only asks the peer that pushed a commit for its blob once another
+/// wants it, so the commit may take a second round to arrive.
+async fn synced(peer: &Peer, doc: SedimentreeId) -> anyhow::Result<Vec<LooseCommit>> {
+    for _ in 0..50 {
+        peer.request_all_batch_sync_all(None).await?;
+        let commits = peer.get_commits(doc).await.unwrap_or_default();
+        if !commits.is_empty() {
+            return Ok(commits);
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    anyhow::bail!("no commits of {doc:?} arrived")
+}
+
+/// Start `subduction-server` on a free port with `args`, returning it with the address
+/// it serves on once it accepts connections. The server is killed when dropped.
+async fn spawn_server(args: &[&str]) -> anyhow::Result<(Child, SocketAddr)> {
+    let address = TcpListener::bind("127.0.0.1:0").await?.local_addr()?;
+    spawn_server_on(address, args).await
+}
+
+/// Start `subduction-server` on `address` with `args`, as [`spawn_server`] does.
+async fn spawn_server_on(
+    address: SocketAddr,
+    args: &[&str],
+) -> anyhow::Result<(Child, SocketAddr)> {
+    let server = Command::new(env!("CARGO_BIN_EXE_subduction-server"))
+        .arg("--listen")
+        .arg(address.to_string())
+        .args(args)
+        .kill_on_drop(true)
+        .spawn()?;
+
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            return Ok((server, address));
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    anyhow::bail!("subduction-server never listened on {address}")
+}
+
+/// Connect a peer holding key `[key; 32]` and `commits` of `doc` to the server at
+/// `address`, and start its sync loop.
+async fn connect(
+    address: SocketAddr,
+    key: u8,
+    doc: SedimentreeId,
+    commits: Vec<(LooseCommit, Blob)>,
+) -> anyhow::Result<Arc<Peer>> {
+    let storage = MemoryStorage::default();
+    for (commit, blob) in &commits {
+        <MemoryStorage as Storage<Sendable>>::save_loose_commit(&storage, commit.clone()).await?;
+        <MemoryStorage as Storage<Sendable>>::save_blob(&storage, blob.clone()).await?;
+    }
+    let tree = Sedimentree::new(
+        vec![],
+        commits.into_iter().map(|(commit, _)| commit).collect(),
+    );
+    let peer = Arc::new(Subduction::new(
+        HashMap::from_iter([(doc, tree)]),
+        storage,
+        HashMap::new(),
+    ));
+
+    let uri = format!("ws://{address}").parse()?;
+    let conn = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[key; 32]),
+    )
+    .await?
+    .start();
+    peer.register(conn).await?;
+    tokio::spawn({
+        let peer = peer.clone();
+        async move { peer.run().await }
+    });
+    Ok(peer)
+}
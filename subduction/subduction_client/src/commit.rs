@@ -0,0 +1,277 @@
+//! Commits: their hashes, their metadata, and how both are checked and stored.
+//!
+//! Commits and their parents are named by hex-encoded [`Digest`]s. A commit's
+//! hash is either the digest of its contents alone or, as [`commit_hash`] makes
+//! it, of its parents' hashes followed by its contents, which keeps two commits
+//! making the same change in different places apart.
+//!
+//! Metadata ([`CommitMeta`]) doesn't count towards a commit's hash. So that it
+//! survives the commit being stored and synced, a commit with any is stored with
+//! its metadata framed in front of its contents in its loose blob (see [`frame`]).
+
+use sedimentree_core::Digest;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The bytes a loose blob carrying metadata starts with.
+const FRAME: &[u8] = b"\0beelay-meta\x01";
+
+/// A problem with a commit.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum CommitError {
+    /// A digest that isn't 32 bytes, hex encoded.
+    #[error("digest must be {0}")]
+    InvalidDigest(&'static str),
+
+    /// A commit whose hash isn't the hash of its parents and contents.
+    #[error("commit {hash} doesn't match its contents, whose hash is {actual}")]
+    HashMismatch {
+        /// The hash the commit has.
+        hash: String,
+        /// The digest of its contents.
+        actual: String,
+    },
+
+    /// Metadata that couldn't be encoded.
+    #[error("{0}")]
+    Metadata(String),
+}
+
+/// What a commit says about itself, apart from its contents.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommitMeta {
+    /// The peer that made the commit, hex-encoded, if known.
+    #[serde(
+        default,
+        rename = "authorPeerId",
+        alias = "author",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub author: Option<String>,
+
+    /// When the commit was made, in milliseconds since the epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<f64>,
+
+    /// Whatever the app attached to the commit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tags: Option<ciborium::Value>,
+
+    /// The author's signature of the commit, hex-encoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
+
+impl CommitMeta {
+    /// This metadata, naming `local_author` as the author if it doesn't name one.
+    #[must_use]
+    pub fn attributed(&self, local_author: Option<&str>) -> Self {
+        Self {
+            author: self
+                .author
+                .clone()
+                .or_else(|| local_author.map(str::to_string)),
+            ..self.clone()
+        }
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.author.is_none()
+            && self.timestamp.is_none()
+            && self.tags.is_none()
+            && self.signature.is_none()
+    }
+}
+
+/// A commit to a document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Commit {
+    /// The hashes of the commits this one builds on.
+    pub parents: Vec<String>,
+
+    /// The commit's hash.
+    pub hash: String,
+
+    /// What the commit holds, which only the app knows how to read.
+    pub contents: Vec<u8>,
+
+    /// Who made the commit, when, and its tags.
+    pub meta: CommitMeta,
+}
+
+impl Commit {
+    /// A commit with `contents` on top of `parents`, hashed with [`commit_hash`].
+    #[must_use]
+    pub fn new(parents: Vec<String>, contents: Vec<u8>) -> Self {
+        Self {
+            hash: commit_hash(&parents, &contents),
+            parents,
+            contents,
+            meta: CommitMeta::default(),
+        }
+    }
+
+    /// This commit, with `meta`.
+    #[must_use]
+    pub fn with_meta(self, meta: CommitMeta) -> Self {
+        Self { meta, ..self }
+    }
+
+    /// Check that the commit's hash matches its contents (see [`check_hash`]).
+    ///
+    /// # Errors
+    ///
+    /// * [`CommitError`] if it doesn't.
+    pub fn check_hash(&self) -> Result<(), CommitError> {
+        check_hash(&self.hash, &self.parents, &self.contents)
+    }
+}
+
+/// The hash of a commit with `parents` and `contents`, hex-encoded.
+#[must_use]
+pub fn commit_hash(parents: &[String], contents: &[u8]) -> String {
+    let mut bytes = parents
+        .iter()
+        .flat_map(|parent| parent.bytes())
+        .collect::<Vec<_>>();
+    bytes.extend_from_slice(contents);
+    hex::encode(Digest::hash(&bytes).as_bytes())
+}
+
+/// A hex-encoded digest.
+///
+/// # Errors
+///
+/// * [`CommitError::InvalidDigest`] if it isn't 32 bytes, hex encoded.
+pub fn parse_digest(hex_str: &str) -> Result<Digest, CommitError> {
+    let bytes =
+        hex::decode(hex_str).map_err(|_| CommitError::InvalidDigest("64 hex characters"))?;
+    let bytes = <[u8; 32]>::try_from(bytes).map_err(|_| CommitError::InvalidDigest("32 bytes"))?;
+    Ok(Digest::from(bytes))
+}
+
+/// Check that `hash` is the digest of `contents`, either alone or after the hashes of
+/// `parents` as [`commit_hash`] makes it.
+///
+/// # Errors
+///
+/// * [`CommitError::InvalidDigest`] if `hash` isn't a digest.
+/// * [`CommitError::HashMismatch`] if it's the digest of something else.
+pub fn check_hash(hash: &str, parents: &[String], contents: &[u8]) -> Result<(), CommitError> {
+    let declared = parse_digest(hash)?;
+    let of_contents = Digest::hash(contents);
+    if declared == of_contents || hash.eq_ignore_ascii_case(&commit_hash(parents, contents)) {
+        return Ok(());
+    }
+    Err(CommitError::HashMismatch {
+        hash: hash.to_string(),
+        actual: of_contents.to_string(),
+    })
+}
+
+/// The loose blob for a commit with `contents` and `meta`.
+///
+/// Blobs of commits with metadata start with a frame: a marker, the length of the
+/// CBOR-encoded metadata as a big-endian `u32`, and the metadata, followed by the
+/// contents. Blobs of commits without metadata are their contents alone, as every
+/// commit's was before metadata.
+///
+/// # Errors
+///
+/// * [`CommitError::Metadata`] if the metadata can't be encoded.
+pub fn frame(contents: &[u8], meta: &CommitMeta) -> Result<Vec<u8>, CommitError> {
+    if meta.is_empty() {
+        return Ok(contents.to_vec());
+    }
+    let mut encoded = Vec::new();
+    ciborium::into_writer(meta, &mut encoded)
+        .map_err(|err| CommitError::Metadata(format!("failed to encode metadata: {err}")))?;
+    let len = u32::try_from(encoded.len())
+        .map_err(|_| CommitError::Metadata("commit metadata is too large".to_string()))?;
+
+    let mut blob = Vec::with_capacity(FRAME.len() + 4 + encoded.len() + contents.len());
+    blob.extend_from_slice(FRAME);
+    blob.extend_from_slice(&len.to_be_bytes());
+    blob.extend_from_slice(&encoded);
+    blob.extend_from_slice(contents);
+    Ok(blob)
+}
+
+/// The contents and metadata of a commit whose loose blob is `blob`.
+///
+/// A blob without a frame that can be read is taken for contents alone, as peers
+/// that predate metadata take a framed one.
+#[must_use]
+pub fn unframe(blob: Vec<u8>) -> (Vec<u8>, CommitMeta) {
+    let framed = blob.strip_prefix(FRAME).and_then(|rest| {
+        let (len, rest) = rest.split_first_chunk::<4>()?;
+        let len = usize::try_from(u32::from_be_bytes(*len)).ok()?;
+        let (encoded, contents) = rest.split_at_checked(len)?;
+        let meta = ciborium::from_reader::<CommitMeta, _>(encoded).ok()?;
+        Some((contents.to_vec(), meta))
+    });
+    framed.unwrap_or((blob, CommitMeta::default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use ciborium::Value;
+
+    use super::*;
+
+    #[test]
+    fn metadata_survives_the_loose_blob() -> Result<(), CommitError> {
+        let meta = CommitMeta {
+            author: Some("aa".to_string()),
+            timestamp: Some(1_700_000_000_000.0),
+            tags: Some(Value::Map(vec![(
+                Value::Text("label".to_string()),
+                Value::Text("draft".to_string()),
+            )])),
+            signature: Some("cc".to_string()),
+        };
+        let blob = frame(b"hello", &meta)?;
+        assert!(blob.starts_with(FRAME));
+        assert_eq!(unframe(blob), (b"hello".to_vec(), meta));
+        Ok(())
+    }
+
+    #[test]
+    fn blobs_without_metadata_are_contents() -> Result<(), CommitError> {
+        assert_eq!(frame(b"hello", &CommitMeta::default())?, b"hello");
+        assert_eq!(
+            unframe(b"hello".to_vec()),
+            (b"hello".to_vec(), CommitMeta::default())
+        );
+        // Contents that only look framed are still contents
+        let lookalike = [FRAME, &[0xff; 4]].concat();
+        assert_eq!(unframe(lookalike.clone()).0, lookalike);
+        Ok(())
+    }
+
+    #[test]
+    fn hashes_are_checked() {
+        let commit = Commit::new(vec!["11".repeat(32)], b"hello".to_vec());
+        assert!(commit.check_hash().is_ok());
+        let contents_only = hex::encode(Digest::hash(b"hello").as_bytes());
+        assert!(check_hash(&contents_only, &commit.parents, b"hello").is_ok());
+
+        let moved = Commit {
+            parents: vec!["22".repeat(32)],
+            ..commit
+        };
+        assert!(matches!(
+            moved.check_hash(),
+            Err(CommitError::HashMismatch { .. })
+        ));
+        assert_eq!(
+            parse_digest("zz"),
+            Err(CommitError::InvalidDigest("64 hex characters"))
+        );
+        assert_eq!(
+            parse_digest("00"),
+            Err(CommitError::InvalidDigest("32 bytes"))
+        );
+    }
+}
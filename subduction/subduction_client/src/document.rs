@@ -0,0 +1,209 @@
+//! A document: a sedimentree held by a Subduction engine, and the commits it has
+//! applied from it.
+//!
+//! The engine stores and syncs commits as loose commits and blobs, without
+//! knowing what they hold. A [`Document`] keeps the commits themselves, with
+//! their contents and metadata, in the order it applied them, and checks every
+//! commit's hash before storing it. Commits the engine gets by other means, from
+//! peers it syncs with or from storage it's hydrated from, are applied with
+//! [`Document::pull`].
+
+use std::collections::HashSet;
+
+use sedimentree_core::{future::FutureKind, storage::Storage, Blob, LooseCommit, SedimentreeId};
+use subduction_core::{connection::Connection, Subduction};
+use thiserror::Error;
+
+use crate::{
+    commit::{self, Commit, CommitError, CommitMeta},
+    order::{self, Order},
+};
+
+/// A problem applying commits to a document.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum DocumentError {
+    /// A commit that isn't valid.
+    #[error(transparent)]
+    Commit(#[from] CommitError),
+
+    /// The engine couldn't store a commit, or read one back.
+    #[error("storage failure: {0}")]
+    Storage(String),
+
+    /// A commit the engine holds without its blob.
+    #[error("commit {0} is missing its blob")]
+    MissingBlob(String),
+}
+
+/// A document, and the engine it's stored and synced with.
+#[derive(Debug)]
+pub struct Document<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> {
+    engine: Subduction<F, S, C>,
+    id: SedimentreeId,
+    /// In the order they were applied.
+    commits: Vec<Commit>,
+    seen: HashSet<String>,
+}
+
+impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Document<F, S, C> {
+    /// An empty document for sedimentree `id` of `engine`.
+    ///
+    /// Commits the engine already holds for it are applied by [`Document::pull`].
+    /// To pick up the commits of earlier runs from storage that persists, such as
+    /// [`FsStorage`](sedimentree_core::storage::fs::FsStorage), hydrate the engine
+    /// first (see [`Subduction::hydrate`]). Storage isn't keyed by document, so an
+    /// engine with persistent storage should only hold this one.
+    #[must_use]
+    pub fn new(engine: Subduction<F, S, C>, id: SedimentreeId) -> Self {
+        Self {
+            engine,
+            id,
+            commits: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// The ID of the document's sedimentree.
+    #[must_use]
+    pub const fn id(&self) -> SedimentreeId {
+        self.id
+    }
+
+    /// The engine the document is stored and synced with.
+    #[must_use]
+    pub const fn engine(&self) -> &Subduction<F, S, C> {
+        &self.engine
+    }
+
+    /// The commit with `hash`, if the document has applied it.
+    #[must_use]
+    pub fn get(&self, hash: &str) -> Option<&Commit> {
+        self.commits.iter().find(|commit| commit.hash == hash)
+    }
+
+    /// The document's commits, in `order`.
+    #[must_use]
+    pub fn commits(&self, order: Order) -> Vec<&Commit> {
+        order.apply(&self.commits)
+    }
+
+    /// The document's heads, the hashes of the commits no other commit names as a
+    /// parent, sorted. New commits should name them as their parents.
+    #[must_use]
+    pub fn heads(&self) -> Vec<String> {
+        let mut heads = order::heads(&self.commits);
+        heads.sort();
+        heads
+    }
+
+    /// Store and apply `commits`, once every one of their hashes checks out. Commits
+    /// the document has already applied are skipped.
+    ///
+    /// Returns the number of commits applied. Commits stored before one that fails
+    /// are kept.
+    ///
+    /// # Errors
+    ///
+    /// * [`DocumentError::Commit`] if a commit's hash, or one of its parents', isn't
+    ///   valid, in which case nothing is applied.
+    /// * [`DocumentError::Storage`] if the engine can't store a commit.
+    pub async fn add_commits(&mut self, commits: &[Commit]) -> Result<usize, DocumentError> {
+        commits.iter().try_for_each(Commit::check_hash)?;
+
+        let mut applied = 0;
+        for commit in commits {
+            if self.seen.contains(&commit.hash) {
+                continue;
+            }
+            self.store(commit).await?;
+            self.record(commit.clone());
+            applied += 1;
+        }
+        Ok(applied)
+    }
+
+    /// Add a commit with `contents` and `meta` on top of all of the document's heads,
+    /// and return its hash, which is the document's only head afterwards.
+    ///
+    /// # Errors
+    ///
+    /// * [`DocumentError::Storage`] if the engine can't store it.
+    pub async fn commit(
+        &mut self,
+        contents: Vec<u8>,
+        meta: CommitMeta,
+    ) -> Result<String, DocumentError> {
+        let commit = Commit::new(self.heads(), contents).with_meta(meta);
+        self.add_commits(std::slice::from_ref(&commit)).await?;
+        Ok(commit.hash)
+    }
+
+    /// Apply the commits the engine holds that the document hasn't applied yet, such as
+    /// ones synced from peers, parents first. Returns the number applied.
+    ///
+    /// # Errors
+    ///
+    /// * [`DocumentError::Storage`] if a commit's blob can't be read.
+    /// * [`DocumentError::MissingBlob`] if the engine doesn't have it.
+    pub async fn pull(&mut self) -> Result<usize, DocumentError> {
+        let held = self.engine.get_commits(self.id).await.unwrap_or_default();
+        let mut pulled = Vec::new();
+        for loose in held {
+            let hash = hex::encode(loose.digest().as_bytes());
+            if self.seen.contains(&hash) {
+                continue;
+            }
+            let blob = self
+                .engine
+                .get_local_blob(loose.blob().digest())
+                .await
+                .map_err(|err| DocumentError::Storage(err.to_string()))?
+                .ok_or_else(|| DocumentError::MissingBlob(hash.clone()))?;
+            let (contents, meta) = commit::unframe(blob.into_contents());
+            pulled.push(Commit {
+                parents: loose
+                    .parents()
+                    .iter()
+                    .map(|parent| hex::encode(parent.as_bytes()))
+                    .collect(),
+                hash,
+                contents,
+                meta,
+            });
+        }
+
+        let pulled = Order::Causal
+            .apply(&pulled)
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        let applied = pulled.len();
+        for commit in pulled {
+            self.record(commit);
+        }
+        Ok(applied)
+    }
+
+    /// Store `commit` with the engine, which syncs it to its peers.
+    async fn store(&mut self, commit: &Commit) -> Result<(), DocumentError> {
+        let parents = commit
+            .parents
+            .iter()
+            .map(|parent| commit::parse_digest(parent))
+            .collect::<Result<Vec<_>, _>>()?;
+        let digest = commit::parse_digest(&commit.hash)?;
+        let blob = Blob::new(commit::frame(&commit.contents, &commit.meta)?);
+        let loose = LooseCommit::new(digest, parents, blob.meta());
+
+        self.engine
+            .add_commit(self.id, &loose, blob)
+            .await
+            .map(drop)
+            .map_err(|err| DocumentError::Storage(err.to_string()))
+    }
+
+    fn record(&mut self, commit: Commit) {
+        self.seen.insert(commit.hash.clone());
+        self.commits.push(commit);
+    }
+}
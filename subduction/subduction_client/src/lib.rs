@@ -0,0 +1,44 @@
+//! # Subduction Client
+//!
+//! Documents and commits on top of a [`Subduction`](subduction_core::Subduction)
+//! engine, with a plain async Rust API.
+//!
+//! The engine syncs sedimentrees of opaque blobs. This crate gives them the shape
+//! apps work with: commits with parents, contents and metadata ([`commit`]), read
+//! in causal or insertion order ([`order`]), and documents that check, store and
+//! apply them ([`Document`]). Native clients use it directly, and the WASM
+//! bindings wrap it, so both hash, frame and order commits the same way.
+
+#![cfg_attr(docsrs, feature(doc_cfg))]
+#![warn(
+    clippy::dbg_macro,
+    clippy::expect_used,
+    clippy::missing_const_for_fn,
+    clippy::panic,
+    clippy::todo,
+    clippy::unwrap_used,
+    future_incompatible,
+    let_underscore,
+    missing_copy_implementations,
+    missing_debug_implementations,
+    missing_docs,
+    nonstandard_style,
+    rust_2021_compatibility
+)]
+#![deny(
+    clippy::all,
+    clippy::cargo,
+    clippy::pedantic,
+    rust_2018_idioms,
+    unreachable_pub,
+    unused_extern_crates
+)]
+#![forbid(unsafe_code)]
+
+pub mod commit;
+mod document;
+pub mod order;
+
+pub use commit::{Commit, CommitError, CommitMeta};
+pub use document::{Document, DocumentError};
+pub use order::Order;
@@ -0,0 +1,150 @@
+//! The order a document's commits are read in, and its heads.
+//!
+//! A document keeps its commits in the order they were added, but sync can add
+//! a child before its parents when batches arrive out of order. Causal order
+//! puts every commit after all of its parents, breaking ties by insertion
+//! order, so that consumers can replay the commits one by one.
+//!
+//! Both work on anything that names itself and its parents by hash ([`Node`]),
+//! so that wrappers can keep commits however suits them.
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
+
+use serde::Deserialize;
+
+use crate::commit::Commit;
+
+/// A commit in a document's history, as far as ordering is concerned.
+pub trait Node {
+    /// The commit's hash.
+    fn hash(&self) -> &str;
+
+    /// The hashes of its parents.
+    fn parents(&self) -> &[String];
+}
+
+impl Node for Commit {
+    fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn parents(&self) -> &[String] {
+        &self.parents
+    }
+}
+
+/// How to order a document's commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Order {
+    /// Parents before children.
+    #[default]
+    Causal,
+
+    /// The order the commits were added in.
+    Insertion,
+}
+
+impl Order {
+    /// `commits`, which are in insertion order, in this order.
+    #[must_use]
+    pub fn apply<T: Node>(self, commits: &[T]) -> Vec<&T> {
+        match self {
+            Self::Causal => causal(commits),
+            Self::Insertion => commits.iter().collect(),
+        }
+    }
+}
+
+/// The hashes of the commits in `commits` that no other commit in `commits` has as a
+/// parent, in the order of `commits`.
+#[must_use]
+pub fn heads<T: Node>(commits: &[T]) -> Vec<String> {
+    let parents = commits
+        .iter()
+        .flat_map(|commit| commit.parents().iter().map(String::as_str))
+        .collect::<HashSet<_>>();
+    commits
+        .iter()
+        .map(Node::hash)
+        .filter(|hash| !parents.contains(hash))
+        .map(str::to_string)
+        .collect()
+}
+
+/// `commits` with every commit after the parents it has among them, otherwise in
+/// insertion order.
+fn causal<T: Node>(commits: &[T]) -> Vec<&T> {
+    let index = commits
+        .iter()
+        .enumerate()
+        .map(|(i, commit)| (commit.hash(), i))
+        .collect::<HashMap<_, _>>();
+
+    let mut waiting_on = vec![0usize; commits.len()];
+    let mut children = vec![Vec::new(); commits.len()];
+    for (i, commit) in commits.iter().enumerate() {
+        let parents = commit
+            .parents()
+            .iter()
+            .filter_map(|parent| index.get(parent.as_str()).copied())
+            .filter(|parent| *parent != i)
+            .collect::<HashSet<_>>();
+        waiting_on[i] = parents.len();
+        for parent in parents {
+            children[parent].push(i);
+        }
+    }
+
+    let mut ready = (0..commits.len())
+        .filter(|i| waiting_on[*i] == 0)
+        .map(Reverse)
+        .collect::<BinaryHeap<_>>();
+    let mut ordered = Vec::with_capacity(commits.len());
+    let mut placed = vec![false; commits.len()];
+    while let Some(Reverse(i)) = ready.pop() {
+        ordered.push(&commits[i]);
+        placed[i] = true;
+        for child in &children[i] {
+            waiting_on[*child] -= 1;
+            if waiting_on[*child] == 0 {
+                ready.push(Reverse(*child));
+            }
+        }
+    }
+
+    // Only a hash collision could make a cycle, but don't lose commits over it
+    ordered.extend(
+        commits
+            .iter()
+            .zip(&placed)
+            .filter(|(_, placed)| !**placed)
+            .map(|(commit, _)| commit),
+    );
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parents_come_first() {
+        let root = Commit::new(vec![], b"root".to_vec());
+        let left = Commit::new(vec![root.hash.clone()], b"left".to_vec());
+        let right = Commit::new(vec![root.hash.clone()], b"right".to_vec());
+        let merge = Commit::new(vec![left.hash.clone(), right.hash.clone()], Vec::new());
+        let added = [merge.clone(), right.clone(), root.clone(), left.clone()];
+
+        let causal = Order::Causal.apply(&added);
+        assert_eq!(causal, [&root, &right, &left, &merge]);
+        assert_eq!(
+            Order::Insertion.apply(&added),
+            added.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(heads(&added), [merge.hash]);
+    }
+}
@@ -0,0 +1,463 @@
+//! Append-only audit logs of accepted protocol messages.
+//!
+//! In audit mode, a relay records every message it accepts: who sent it, when,
+//! a digest of its encoding, and, if the transport signs messages, the sender's
+//! signature over that digest (see [`sign_message`]), which proves the sender sent
+//! it. Each entry also carries a chain digest over the
+//! previous entry's chain digest and its own fields, so removing, reordering,
+//! or editing any entry breaks every chain digest after it. Every so often the
+//! log also records a [`AuditRecord::Checkpoint`] of the current chain digest,
+//! which can be published elsewhere to pin the log's history.
+//!
+//! [`verify`] replays a log and checks all of this, signatures included.
+//!
+//! [`sign_message`]: crate::connection::handshake::sign_message
+//!
+//! Records have a line-oriented text form (via [`Display`][std::fmt::Display] and
+//! [`FromStr`]) suitable for an append-only file.
+
+use std::{
+    fmt::{self, Debug},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use sedimentree_core::Digest;
+use thiserror::Error;
+
+use crate::{
+    connection::{handshake::verify_message, message::Message},
+    peer::id::PeerId,
+};
+
+/// Somewhere to record accepted protocol messages.
+pub trait AuditSink: Debug + Send + Sync {
+    /// Record that `message` from `sender`, signed with `sender_signature` if the
+    /// transport signs messages, was accepted at `received_at`.
+    fn record(
+        &self,
+        sender: PeerId,
+        message: &Message,
+        sender_signature: Option<&[u8]>,
+        received_at: SystemTime,
+    );
+}
+
+/// A single line of an audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum AuditRecord {
+    /// An accepted message.
+    Entry(AuditEntry),
+
+    /// The chain digest as of an entry.
+    Checkpoint {
+        /// The sequence number of the last entry covered.
+        seq: u64,
+
+        /// The chain digest of that entry.
+        chain: Digest,
+    },
+}
+
+/// An accepted message.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct AuditEntry {
+    /// The entry's position in the log, starting at 0.
+    pub seq: u64,
+
+    /// When the message was accepted, in milliseconds since the Unix epoch.
+    pub received_at_ms: u64,
+
+    /// The peer the message came from.
+    pub sender: PeerId,
+
+    /// The digest of the message's encoding.
+    pub message_digest: Digest,
+
+    /// The sender's signature over the message, if the transport provides one.
+    pub sender_signature: Option<Vec<u8>>,
+
+    /// The digest chaining this entry to the ones before it.
+    pub chain: Digest,
+}
+
+impl AuditEntry {
+    fn chain_digest(
+        previous: Digest,
+        seq: u64,
+        received_at_ms: u64,
+        sender: PeerId,
+        message_digest: Digest,
+        sender_signature: Option<&[u8]>,
+    ) -> Digest {
+        let mut bytes = b"subduction/audit/v1".to_vec();
+        bytes.extend_from_slice(previous.as_bytes());
+        bytes.extend_from_slice(&seq.to_be_bytes());
+        bytes.extend_from_slice(&received_at_ms.to_be_bytes());
+        bytes.extend_from_slice(sender.as_bytes());
+        bytes.extend_from_slice(message_digest.as_bytes());
+        if let Some(signature) = sender_signature {
+            bytes.extend_from_slice(&(signature.len() as u64).to_be_bytes());
+            bytes.extend_from_slice(signature);
+        }
+        Digest::hash(&bytes)
+    }
+}
+
+/// The chain digest that the first entry chains from.
+#[must_use]
+pub fn genesis() -> Digest {
+    Digest::from([0; 32])
+}
+
+/// The writing side of an audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditLog {
+    next_seq: u64,
+    chain: Digest,
+    checkpoint_every: u64,
+    since_checkpoint: u64,
+}
+
+impl AuditLog {
+    /// Start a new log, checkpointing every `checkpoint_every` entries.
+    #[must_use]
+    pub fn new(checkpoint_every: u64) -> Self {
+        Self {
+            next_seq: 0,
+            chain: genesis(),
+            checkpoint_every: checkpoint_every.max(1),
+            since_checkpoint: 0,
+        }
+    }
+
+    /// Continue an existing log, after checking it with [`verify`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the existing records don't verify.
+    pub fn resume(
+        records: impl IntoIterator<Item = AuditRecord>,
+        checkpoint_every: u64,
+    ) -> Result<Self, AuditError> {
+        let summary = verify(records)?;
+        Ok(Self {
+            next_seq: summary.entries,
+            chain: summary.head,
+            checkpoint_every: checkpoint_every.max(1),
+            since_checkpoint: summary.since_checkpoint,
+        })
+    }
+
+    /// Append an entry, returning the records to persist (in order).
+    pub fn append(
+        &mut self,
+        sender: PeerId,
+        message_digest: Digest,
+        sender_signature: Option<Vec<u8>>,
+        received_at: SystemTime,
+    ) -> Vec<AuditRecord> {
+        let seq = self.next_seq;
+        let received_at_ms = millis_since_epoch(received_at);
+        let chain = AuditEntry::chain_digest(
+            self.chain,
+            seq,
+            received_at_ms,
+            sender,
+            message_digest,
+            sender_signature.as_deref(),
+        );
+
+        self.next_seq += 1;
+        self.chain = chain;
+
+        let mut records = vec![AuditRecord::Entry(AuditEntry {
+            seq,
+            received_at_ms,
+            sender,
+            message_digest,
+            sender_signature,
+            chain,
+        })];
+        self.since_checkpoint += 1;
+        if self.since_checkpoint >= self.checkpoint_every {
+            self.since_checkpoint = 0;
+            records.push(AuditRecord::Checkpoint { seq, chain });
+        }
+        records
+    }
+}
+
+/// The outcome of a successful [`verify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AuditSummary {
+    /// The number of entries.
+    pub entries: u64,
+
+    /// The number of checkpoints.
+    pub checkpoints: u64,
+
+    /// The chain digest of the last entry (or [`genesis`] if there are none).
+    pub head: Digest,
+
+    /// The number of entries after the last checkpoint.
+    pub since_checkpoint: u64,
+}
+
+/// Replay an audit log, checking its sequence numbers, timestamps, chain
+/// digests, and checkpoints.
+///
+/// # Errors
+///
+/// Returns the first problem found.
+pub fn verify(records: impl IntoIterator<Item = AuditRecord>) -> Result<AuditSummary, AuditError> {
+    let mut summary = AuditSummary {
+        entries: 0,
+        checkpoints: 0,
+        head: genesis(),
+        since_checkpoint: 0,
+    };
+    let mut last_received_at = 0;
+
+    for record in records {
+        match record {
+            AuditRecord::Entry(entry) => {
+                if entry.seq != summary.entries {
+                    return Err(AuditError::OutOfSequence {
+                        expected: summary.entries,
+                        found: entry.seq,
+                    });
+                }
+                if entry.received_at_ms < last_received_at {
+                    return Err(AuditError::TimeWentBackwards { seq: entry.seq });
+                }
+
+                let expected = AuditEntry::chain_digest(
+                    summary.head,
+                    entry.seq,
+                    entry.received_at_ms,
+                    entry.sender,
+                    entry.message_digest,
+                    entry.sender_signature.as_deref(),
+                );
+                if expected != entry.chain {
+                    return Err(AuditError::BrokenChain { seq: entry.seq });
+                }
+                let forged = entry.sender_signature.as_ref().is_some_and(|signature| {
+                    !verify_message(entry.sender, entry.message_digest, signature)
+                });
+                if forged {
+                    return Err(AuditError::BadSignature { seq: entry.seq });
+                }
+
+                last_received_at = entry.received_at_ms;
+                summary.entries += 1;
+                summary.since_checkpoint += 1;
+                summary.head = entry.chain;
+            }
+            AuditRecord::Checkpoint { seq, chain } => {
+                if summary.entries == 0 || seq != summary.entries - 1 || chain != summary.head {
+                    return Err(AuditError::BadCheckpoint { seq });
+                }
+                summary.checkpoints += 1;
+                summary.since_checkpoint = 0;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// A problem found while verifying or parsing an audit log.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum AuditError {
+    /// An entry is missing, duplicated, or out of order.
+    #[error("expected entry {expected}, found entry {found}")]
+    OutOfSequence {
+        /// The sequence number that should have come next.
+        expected: u64,
+
+        /// The sequence number that did.
+        found: u64,
+    },
+
+    /// An entry was accepted before the one preceding it.
+    #[error("entry {seq} is timestamped before the entry preceding it")]
+    TimeWentBackwards {
+        /// The offending entry.
+        seq: u64,
+    },
+
+    /// An entry's chain digest doesn't match its contents and predecessor.
+    #[error("chain digest mismatch at entry {seq}")]
+    BrokenChain {
+        /// The offending entry.
+        seq: u64,
+    },
+
+    /// An entry's signature isn't its sender's over its message digest.
+    #[error("entry {seq} isn't signed by its sender")]
+    BadSignature {
+        /// The offending entry.
+        seq: u64,
+    },
+
+    /// A checkpoint doesn't match the entry it follows.
+    #[error("checkpoint for entry {seq} doesn't match the log")]
+    BadCheckpoint {
+        /// The entry the checkpoint claims to cover.
+        seq: u64,
+    },
+
+    /// A line couldn't be parsed as a record.
+    #[error("malformed audit record: {0}")]
+    Malformed(String),
+}
+
+impl fmt::Display for AuditRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditRecord::Entry(entry) => {
+                let signature = entry
+                    .sender_signature
+                    .as_deref()
+                    .map_or_else(|| "-".to_string(), hex::encode);
+                write!(
+                    f,
+                    "entry {} {} {} {} {} {}",
+                    entry.seq,
+                    entry.received_at_ms,
+                    entry.sender,
+                    entry.message_digest,
+                    signature,
+                    entry.chain
+                )
+            }
+            AuditRecord::Checkpoint { seq, chain } => write!(f, "checkpoint {seq} {chain}"),
+        }
+    }
+}
+
+impl FromStr for AuditRecord {
+    type Err = AuditError;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let malformed = || AuditError::Malformed(line.to_string());
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        let digest = |field: &str| Digest::from_str(field).map_err(|_| malformed());
+        let number = |field: &str| field.parse::<u64>().map_err(|_| malformed());
+
+        match fields.as_slice() {
+            ["entry", seq, received_at_ms, sender, message_digest, signature, chain] => {
+                let sender_signature = match *signature {
+                    "-" => None,
+                    hex_signature => Some(hex::decode(hex_signature).map_err(|_| malformed())?),
+                };
+                Ok(AuditRecord::Entry(AuditEntry {
+                    seq: number(seq)?,
+                    received_at_ms: number(received_at_ms)?,
+                    sender: PeerId::new(*digest(sender)?.as_bytes()),
+                    message_digest: digest(message_digest)?,
+                    sender_signature,
+                    chain: digest(chain)?,
+                }))
+            }
+            ["checkpoint", seq, chain] => Ok(AuditRecord::Checkpoint {
+                seq: number(seq)?,
+                chain: digest(chain)?,
+            }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+fn millis_since_epoch(at: SystemTime) -> u64 {
+    at.duration_since(SystemTime::UNIX_EPOCH)
+        .as_ref()
+        .map_or(0, Duration::as_millis)
+        .try_into()
+        .unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::connection::handshake::{sign_message, SigningKey};
+
+    use super::*;
+
+    fn write_log(n: u8) -> Vec<AuditRecord> {
+        let mut log = AuditLog::new(2);
+        (0..n)
+            .flat_map(|i| {
+                log.append(
+                    PeerId::new([i; 32]),
+                    Digest::hash(&[i]),
+                    None,
+                    SystemTime::UNIX_EPOCH,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_and_verifies() -> Result<(), AuditError> {
+        let records = write_log(5);
+        let parsed = records
+            .iter()
+            .map(|record| record.to_string().parse())
+            .collect::<Result<Vec<AuditRecord>, _>>()?;
+        assert_eq!(parsed, records);
+
+        let summary = verify(parsed)?;
+        assert_eq!(summary.entries, 5);
+        assert_eq!(summary.checkpoints, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn checks_senders_signatures() {
+        let key = SigningKey::from_bytes(&[1; 32]);
+        let sender = PeerId::new(key.verifying_key().to_bytes());
+        let (digest, other) = (Digest::hash(b"signed"), Digest::hash(b"other"));
+
+        let mut log = AuditLog::new(10);
+        let mut records = log.append(
+            sender,
+            digest,
+            Some(sign_message(&key, digest)),
+            SystemTime::UNIX_EPOCH,
+        );
+        assert_eq!(
+            verify(records.clone()).map(|summary| summary.entries),
+            Ok(1)
+        );
+
+        // A signature over another message, even with the chain made to match
+        records.extend(log.append(
+            sender,
+            digest,
+            Some(sign_message(&key, other)),
+            SystemTime::UNIX_EPOCH,
+        ));
+        assert_eq!(verify(records), Err(AuditError::BadSignature { seq: 1 }));
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let mut records = write_log(3);
+        if let Some(AuditRecord::Entry(entry)) = records.first_mut() {
+            entry.sender = PeerId::new([9; 32]);
+        }
+        assert_eq!(verify(records), Err(AuditError::BrokenChain { seq: 0 }));
+
+        let mut records = write_log(3);
+        records.remove(0);
+        assert_eq!(
+            verify(records),
+            Err(AuditError::OutOfSequence {
+                expected: 0,
+                found: 1
+            })
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! Scheduling compaction as documents' loose history grows.
+//!
+//! Bundling a document's loose commits into chunks (see
+//! [`Subduction::compact`][crate::Subduction::compact]) keeps it cheap to sync
+//! and load, but only the host knows how to encode a bundle. Rather than leave
+//! the host to decide when, the engine can be given a [`CompactionPolicy`] with
+//! [`Subduction::with_compaction`][crate::Subduction::with_compaction]. Whenever a
+//! new commit takes a document past one of the policy's thresholds, and some run
+//! of its commits could be bundled, the engine hands a [`CompactionTask`] to the
+//! host's [`CompactionSink`], which runs the compaction in the background.
+//!
+//! A document is scheduled once, and not again until it has been compacted, so
+//! the sink sees one task per document however many commits arrive meanwhile.
+
+use std::{
+    collections::HashSet,
+    fmt::Debug,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use sedimentree_core::{Sedimentree, SedimentreeId};
+
+/// When a document is due for compaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CompactionPolicy {
+    /// Compact a document once it holds this many loose commits.
+    pub loose_commits: Option<usize>,
+
+    /// Compact a document once the blobs of its loose commits add up to this many bytes.
+    pub loose_bytes: Option<u64>,
+}
+
+impl CompactionPolicy {
+    /// Whether a document with `loose_commits` loose commits, whose blobs add up to
+    /// `loose_bytes`, has crossed one of the thresholds.
+    #[must_use]
+    pub fn is_due(&self, loose_commits: usize, loose_bytes: u64) -> bool {
+        self.loose_commits.is_some_and(|max| loose_commits >= max)
+            || self.loose_bytes.is_some_and(|max| loose_bytes >= max)
+    }
+}
+
+/// A document the engine wants compacted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CompactionTask {
+    /// The document's sedimentree.
+    pub id: SedimentreeId,
+
+    /// The number of loose commits it held when it was scheduled.
+    pub loose_commits: usize,
+
+    /// The size of their blobs, in bytes.
+    pub loose_bytes: u64,
+}
+
+/// Somewhere to hand compaction tasks to the host.
+pub trait CompactionSink: Debug + Send + Sync {
+    /// Called when `task.id` is due for compaction. The host should call
+    /// [`Subduction::compact`][crate::Subduction::compact] for it off the sync path,
+    /// such as on a task of its own.
+    fn schedule(&self, task: CompactionTask);
+}
+
+/// The policy, the sink, and the documents scheduled and not compacted since.
+#[derive(Debug)]
+pub(crate) struct Compaction {
+    policy: CompactionPolicy,
+    sink: Arc<dyn CompactionSink>,
+    scheduled: Mutex<HashSet<SedimentreeId>>,
+}
+
+impl Compaction {
+    pub(crate) fn new(policy: CompactionPolicy, sink: Arc<dyn CompactionSink>) -> Self {
+        Self {
+            policy,
+            sink,
+            scheduled: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// The task for `tree` if it's now due, marking it scheduled.
+    pub(crate) fn check(&self, id: SedimentreeId, tree: &Sedimentree) -> Option<CompactionTask> {
+        let (loose_commits, loose_bytes) = tree
+            .loose_commits()
+            .fold((0, 0), |(commits, bytes), commit| {
+                (commits + 1, bytes + commit.blob().size_bytes())
+            });
+        if !self.policy.is_due(loose_commits, loose_bytes) {
+            return None;
+        }
+
+        let mut scheduled = self
+            .scheduled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Commits since the last checkpoint can't be bundled yet
+        if scheduled.contains(&id) || tree.missing_chunks(id).is_empty() {
+            return None;
+        }
+        scheduled.insert(id);
+        Some(CompactionTask {
+            id,
+            loose_commits,
+            loose_bytes,
+        })
+    }
+
+    pub(crate) fn schedule(&self, task: CompactionTask) {
+        tracing::debug!("Scheduling compaction of {:?}", task.id);
+        self.sink.schedule(task);
+    }
+
+    /// Note that `id` has been compacted, so that it can be scheduled again.
+    pub(crate) fn finished(&self, id: SedimentreeId) {
+        self.scheduled
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::{BlobMeta, Digest, LooseCommit};
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct Ignore;
+
+    impl CompactionSink for Ignore {
+        fn schedule(&self, _task: CompactionTask) {}
+    }
+
+    /// A commit whose digest is the number `n`, a checkpoint if it ends in two zeros.
+    fn commit(n: u16, parents: &[&LooseCommit]) -> LooseCommit {
+        let mut digest = [0; 32];
+        digest[30..].copy_from_slice(&n.to_be_bytes());
+        LooseCommit::new(
+            Digest::from(digest),
+            parents.iter().map(|parent| parent.digest()).collect(),
+            BlobMeta::new(&n.to_be_bytes()),
+        )
+    }
+
+    #[test]
+    fn schedules_once_until_compacted() {
+        let id = SedimentreeId::new([1; 32]);
+        let compaction = Compaction::new(
+            CompactionPolicy {
+                loose_commits: Some(3),
+                loose_bytes: None,
+            },
+            Arc::new(Ignore),
+        );
+        let first = commit(100, &[]);
+        let middle = commit(101, &[&first]);
+        let second = commit(200, &[&middle]);
+
+        let short = Sedimentree::new(vec![], vec![first.clone(), middle.clone()]);
+        assert_eq!(compaction.check(id, &short), None);
+
+        let due = Sedimentree::new(vec![], vec![first, middle, second]);
+        assert_eq!(
+            compaction.check(id, &due),
+            Some(CompactionTask {
+                id,
+                loose_commits: 3,
+                loose_bytes: 6,
+            })
+        );
+        assert_eq!(compaction.check(id, &due), None);
+
+        compaction.finished(id);
+        assert!(compaction.check(id, &due).is_some());
+    }
+
+    #[test]
+    fn waits_for_a_run_to_bundle() {
+        let id = SedimentreeId::new([1; 32]);
+        let compaction = Compaction::new(
+            CompactionPolicy {
+                loose_commits: Some(2),
+                loose_bytes: None,
+            },
+            Arc::new(Ignore),
+        );
+        let first = commit(1, &[]);
+        let second = commit(2, &[&first]);
+
+        // No checkpoints, so nothing to bundle
+        let tree = Sedimentree::new(vec![], vec![first, second]);
+        assert_eq!(compaction.check(id, &tree), None);
+    }
+}
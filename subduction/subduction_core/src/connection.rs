@@ -1,5 +1,6 @@
 //! Manage connections to peers in the network.
 
+pub mod handshake;
 pub mod id;
 pub mod message;
 
@@ -49,6 +50,14 @@ pub trait Connection<K: FutureKind>: Clone {
         req: BatchSyncRequest,
         timeout: Option<Duration>,
     ) -> K::Future<'_, Result<BatchSyncResponse, Self::CallError>>;
+
+    /// The peer's signature over the message [`recv`] last returned, if the transport
+    /// signs the messages it carries (see [`handshake::sign_message`]).
+    ///
+    /// [`recv`]: Connection::recv
+    fn sender_signature(&self, _message: &Message) -> Option<Vec<u8>> {
+        None
+    }
 }
 
 /// A trait for connections that can be re-established if they drop.
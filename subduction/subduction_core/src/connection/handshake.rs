@@ -0,0 +1,300 @@
+//! The frames exchanged when a connection opens, before any [`Message`].
+//!
+//! Each side sends a [`Hello`] as its first frame, naming the version of the wire
+//! format it speaks, the key it claims as its [`PeerId`], and a fresh nonce. Once
+//! it has the other side's [`Hello`], it sends a [`Proof`]: its signature over the
+//! other side's nonce and both keys. A side only sends messages once the other's
+//! [`Proof`] checks out, and then knows the other side by the key it proved it
+//! holds, never by anything the other side merely says.
+//!
+//! A side that gets a different version from its own closes the connection rather
+//! than misread what follows. The version is the first field of a [`Hello`] in
+//! every version, so a peer can always read it, whatever else a later version adds.
+//!
+//! Once connected, a transport may also sign each message it sends with the same
+//! key ([`sign_message`]), so that the receiver can show who sent it, as an
+//! [audit log](crate::audit) does.
+//!
+//! [`Message`]: crate::connection::message::Message
+
+use ed25519_dalek::{Signature, Signer, Verifier, VerifyingKey};
+use sedimentree_core::Digest;
+use thiserror::Error;
+
+use crate::peer::id::PeerId;
+
+pub use ed25519_dalek::SigningKey;
+
+/// The version of the wire format this crate speaks.
+///
+/// Bumped whenever the encoding of a [`Message`] or of the handshake changes.
+///
+/// [`Message`]: crate::connection::message::Message
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// What a [`Proof`] signs, ahead of the nonce and keys, so that the signature
+/// can't be passed off as one over anything else.
+const DOMAIN: &[u8] = b"subduction handshake";
+
+/// What a message signature signs, ahead of the message's digest.
+const MESSAGE_DOMAIN: &[u8] = b"subduction message";
+
+/// The first frame each side of a connection sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Hello {
+    /// The version of the wire format the sender speaks.
+    pub version: u32,
+
+    /// The sender's Ed25519 verifying key, which is its [`PeerId`].
+    pub peer_id: PeerId,
+
+    /// A value the other side signs in its [`Proof`], fresh for each connection.
+    pub nonce: [u8; 32],
+}
+
+impl Hello {
+    /// Check that the other side's [`Hello`] is for the same version.
+    ///
+    /// # Errors
+    ///
+    /// * [`VersionMismatch`] if it speaks another version of the wire format.
+    pub const fn check(&self, theirs: &Hello) -> Result<(), VersionMismatch> {
+        check_version(self.version, theirs.version)
+    }
+}
+
+/// Check the version a peer's [`Hello`] starts with against ours, before reading the
+/// rest of it, which may be laid out differently in its version.
+///
+/// # Errors
+///
+/// * [`VersionMismatch`] if it isn't [`PROTOCOL_VERSION`].
+pub const fn check_version(ours: u32, theirs: u32) -> Result<(), VersionMismatch> {
+    if ours == theirs {
+        Ok(())
+    } else {
+        Err(VersionMismatch { ours, theirs })
+    }
+}
+
+/// The second frame each side sends: its signature over the other side's [`Hello`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Proof {
+    /// The sender's Ed25519 signature over the handshake's challenge.
+    pub signature: Vec<u8>,
+}
+
+/// One side of a handshake, from its [`Hello`] to the other side's verified identity.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    key: SigningKey,
+    hello: Hello,
+}
+
+impl Handshake {
+    /// Start a handshake as the holder of `key`, with a `nonce` that must not be used
+    /// for another connection.
+    #[must_use]
+    pub fn new(key: &SigningKey, nonce: [u8; 32]) -> Self {
+        Self {
+            hello: Hello {
+                version: PROTOCOL_VERSION,
+                peer_id: PeerId::new(key.verifying_key().to_bytes()),
+                nonce,
+            },
+            key: key.clone(),
+        }
+    }
+
+    /// The [`Hello`] to send.
+    #[must_use]
+    pub const fn hello(&self) -> &Hello {
+        &self.hello
+    }
+
+    /// The [`Proof`] to send in answer to the other side's [`Hello`].
+    ///
+    /// # Errors
+    ///
+    /// * [`HandshakeError::Version`] if it speaks another version of the wire format.
+    pub fn answer(&self, theirs: &Hello) -> Result<Proof, HandshakeError> {
+        self.hello.check(theirs)?;
+        let signature = self.key.sign(&challenge(&self.hello.peer_id, theirs));
+        Ok(Proof {
+            signature: signature.to_bytes().to_vec(),
+        })
+    }
+
+    /// Check the other side's [`Proof`], returning the [`PeerId`] it proved it holds.
+    ///
+    /// # Errors
+    ///
+    /// * [`HandshakeError::Unproven`] if the proof isn't a signature over our
+    ///   nonce by the key their [`Hello`] names.
+    pub fn verify(&self, theirs: &Hello, proof: &Proof) -> Result<PeerId, HandshakeError> {
+        let unproven = HandshakeError::Unproven(theirs.peer_id);
+        let key = VerifyingKey::from_bytes(theirs.peer_id.as_bytes()).map_err(|_| unproven)?;
+        let signature = Signature::from_slice(&proof.signature).map_err(|_| unproven)?;
+        key.verify(&challenge(&theirs.peer_id, &self.hello), &signature)
+            .map_err(|_| unproven)?;
+        Ok(theirs.peer_id)
+    }
+}
+
+/// What `signer` signs to answer `verifier`'s [`Hello`]: both keys, in that order, so
+/// that the signature can't be reflected back to its signer, and the verifier's nonce.
+fn challenge(signer: &PeerId, verifier: &Hello) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(DOMAIN.len() + 4 + 32 * 3);
+    bytes.extend_from_slice(DOMAIN);
+    bytes.extend_from_slice(&verifier.version.to_be_bytes());
+    bytes.extend_from_slice(signer.as_bytes());
+    bytes.extend_from_slice(verifier.peer_id.as_bytes());
+    bytes.extend_from_slice(&verifier.nonce);
+    bytes
+}
+
+/// Sign the [`Message`] whose encoding has digest `digest` as its sender, the holder
+/// of `key`.
+///
+/// [`Message`]: crate::connection::message::Message
+#[must_use]
+pub fn sign_message(key: &SigningKey, digest: Digest) -> Vec<u8> {
+    key.sign(&message_challenge(digest)).to_bytes().to_vec()
+}
+
+/// Whether `signature` is `sender`'s over the [`Message`] whose encoding has digest
+/// `digest`.
+///
+/// [`Message`]: crate::connection::message::Message
+#[must_use]
+pub fn verify_message(sender: PeerId, digest: Digest, signature: &[u8]) -> bool {
+    let (Ok(key), Ok(signature)) = (
+        VerifyingKey::from_bytes(sender.as_bytes()),
+        Signature::from_slice(signature),
+    ) else {
+        return false;
+    };
+    key.verify(&message_challenge(digest), &signature).is_ok()
+}
+
+fn message_challenge(digest: Digest) -> Vec<u8> {
+    let mut bytes = MESSAGE_DOMAIN.to_vec();
+    bytes.extend_from_slice(digest.as_bytes());
+    bytes
+}
+
+/// The other side of a connection speaks another version of the wire format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+#[error("peer speaks protocol version {theirs}, not {ours}")]
+pub struct VersionMismatch {
+    /// The version we speak.
+    pub ours: u32,
+
+    /// The version they speak.
+    pub theirs: u32,
+}
+
+/// A handshake that failed, so the connection must be closed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Error)]
+pub enum HandshakeError {
+    /// The other side speaks another version of the wire format.
+    #[error(transparent)]
+    Version(#[from] VersionMismatch),
+
+    /// The other side didn't prove it holds the key it claimed.
+    #[error("peer {0} didn't prove it holds its key")]
+    Unproven(PeerId),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(byte: u8) -> SigningKey {
+        SigningKey::from_bytes(&[byte; 32])
+    }
+
+    #[test]
+    fn each_side_learns_the_others_key() -> Result<(), HandshakeError> {
+        let (alice, bob) = (
+            Handshake::new(&key(1), [1; 32]),
+            Handshake::new(&key(2), [2; 32]),
+        );
+        let from_alice = alice.answer(bob.hello())?;
+        let from_bob = bob.answer(alice.hello())?;
+
+        assert_eq!(
+            bob.verify(alice.hello(), &from_alice),
+            Ok(PeerId::new(key(1).verifying_key().to_bytes()))
+        );
+        assert_eq!(
+            alice.verify(bob.hello(), &from_bob),
+            Ok(PeerId::new(key(2).verifying_key().to_bytes()))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn refuses_a_claimed_key_without_its_signature() -> Result<(), HandshakeError> {
+        let (alice, bob) = (
+            Handshake::new(&key(1), [1; 32]),
+            Handshake::new(&key(2), [2; 32]),
+        );
+        let mallory = Handshake::new(&key(3), [3; 32]);
+
+        // Mallory claims to be Alice, but can only sign with their own key
+        let claimed = Hello {
+            peer_id: alice.hello().peer_id,
+            ..*mallory.hello()
+        };
+        let forged = mallory.answer(bob.hello())?;
+        assert_eq!(
+            bob.verify(&claimed, &forged),
+            Err(HandshakeError::Unproven(alice.hello().peer_id))
+        );
+
+        // Nor can they replay Alice's answer to another nonce
+        let replayed = alice.answer(bob.hello())?;
+        let carol = Handshake::new(&key(4), [4; 32]);
+        assert_eq!(
+            carol.verify(alice.hello(), &replayed),
+            Err(HandshakeError::Unproven(alice.hello().peer_id))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn only_the_sender_signs_its_messages() {
+        let alice = PeerId::new(key(1).verifying_key().to_bytes());
+        let bob = PeerId::new(key(2).verifying_key().to_bytes());
+        let digest = Digest::hash(b"message");
+        let signature = sign_message(&key(1), digest);
+
+        assert!(verify_message(alice, digest, &signature));
+        assert!(!verify_message(bob, digest, &signature));
+        assert!(!verify_message(alice, Digest::hash(b"another"), &signature));
+    }
+
+    #[test]
+    fn refuses_another_version() {
+        let (alice, bob) = (
+            Handshake::new(&key(1), [1; 32]),
+            Handshake::new(&key(2), [2; 32]),
+        );
+        let newer = Hello {
+            version: PROTOCOL_VERSION + 1,
+            ..*bob.hello()
+        };
+        assert_eq!(
+            alice.answer(&newer),
+            Err(HandshakeError::Version(VersionMismatch {
+                ours: PROTOCOL_VERSION,
+                theirs: PROTOCOL_VERSION + 1,
+            }))
+        );
+    }
+}
@@ -1,8 +1,16 @@
 //! The API contact messages to be sent over a [`Connection`].
 
+use std::time::Duration;
+
 use sedimentree_core::{Blob, Chunk, Digest, LooseCommit, SedimentreeId, SedimentreeSummary};
 
-use crate::peer::id::PeerId;
+use crate::{
+    peer::id::PeerId,
+    sync::{
+        filter::CommitFilter,
+        subscription::{DocumentFilter, SubscriptionDenied},
+    },
+};
 
 /// The API contact messages to be sent over a [`Connection`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -39,11 +47,159 @@ pub enum Message {
     /// A response to a [`BlobRequest`].
     BlobsResponse(Vec<Blob>),
 
+    /// A request for a byte range of a single blob.
+    BlobRangeRequest {
+        /// The [`Digest`] of the whole blob.
+        digest: Digest,
+
+        /// The offset of the first requested byte.
+        offset: u64,
+
+        /// The number of bytes requested.
+        length: u64,
+    },
+
+    /// A response to a [`Message::BlobRangeRequest`].
+    BlobRangeResponse {
+        /// The [`Digest`] of the whole blob.
+        digest: Digest,
+
+        /// The offset of the first byte in `data`.
+        offset: u64,
+
+        /// The requested bytes.
+        data: Vec<u8>,
+
+        /// The chaining values of the subtrees beside `data` in the blob's BLAKE3
+        /// tree, from the root down, for verifying the range against `digest` on
+        /// receipt (see [`range_proof`](crate::sync::download::range_proof)).
+        proof: Vec<Digest>,
+    },
+
     /// A request to "batch sync" an entire [`Sedimentree`].
     BatchSyncRequest(BatchSyncRequest),
 
     /// A response to a [`BatchSyncRequest`].
     BatchSyncResponse(BatchSyncResponse),
+
+    /// A WebRTC signaling message for a relay to pass on to another peer of the
+    /// same [`Sedimentree`].
+    RelaySignal {
+        /// The ID of the [`Sedimentree`] that both peers sync.
+        id: SedimentreeId,
+
+        /// The peer to pass the signal on to.
+        to: PeerId,
+
+        /// The signal itself.
+        signal: Signal,
+    },
+
+    /// A WebRTC signaling message passed on by a relay.
+    Signal {
+        /// The ID of the [`Sedimentree`] that both peers sync.
+        id: SedimentreeId,
+
+        /// The peer that sent the signal.
+        from: PeerId,
+
+        /// The signal itself.
+        signal: Signal,
+    },
+
+    /// A piece of a large commit's blob, pushed in order so that an interrupted push
+    /// can resume where it left off.
+    CommitUpload {
+        /// The ID of the [`Sedimentree`] that this commit belongs to.
+        id: SedimentreeId,
+
+        /// The [`LooseCommit`] being sent.
+        commit: LooseCommit,
+
+        /// The offset of the first byte in `data`.
+        offset: u64,
+
+        /// The piece of the commit's blob.
+        data: Vec<u8>,
+
+        /// The [`Digest`] of `data`, for verifying the piece on receipt.
+        chunk_digest: Digest,
+    },
+
+    /// An acknowledgement of the pieces of a [`Message::CommitUpload`] received so far.
+    CommitUploadAck {
+        /// The [`Digest`] of the whole blob.
+        digest: Digest,
+
+        /// The number of bytes held from the start of the blob, without a gap.
+        received: u64,
+    },
+
+    /// Permission to send `bytes` more bytes over this connection, granted by the
+    /// receiver as it works through what it has been sent.
+    ///
+    /// A receiver that sends one is flow controlled: its first credit is the window
+    /// of unacknowledged bytes it will hold, and later ones give back bytes it has
+    /// finished with. Connections that never receive one are not limited.
+    Credit {
+        /// The number of bytes granted.
+        bytes: u64,
+    },
+
+    /// The TTL of an ephemeral [`Sedimentree`], whose commits expire once they're
+    /// older than it, or `None` if it isn't ephemeral.
+    DocumentTtl {
+        /// The ID of the [`Sedimentree`].
+        id: SedimentreeId,
+
+        /// The TTL in seconds.
+        ttl_secs: Option<u64>,
+    },
+
+    /// The documents the sender wants pushed to it over this connection from now on,
+    /// in place of any it subscribed to before (see
+    /// [`subscription`](crate::sync::subscription)).
+    Subscribe {
+        /// The documents wanted.
+        filters: Vec<DocumentFilter>,
+    },
+
+    /// The answer to a [`Message::Subscribe`].
+    Subscribed {
+        /// The filters refused, which are left out of the subscription.
+        denied: Vec<SubscriptionDenied>,
+    },
+
+    /// The answer to a [`Message::BlobRangeRequest`] for a blob the sender doesn't
+    /// hold, so that the requester asks another peer for the range.
+    BlobRangeNotFound {
+        /// The [`Digest`] of the whole blob.
+        digest: Digest,
+    },
+
+    /// A request for access to a document, for relays to pass on to the peers that
+    /// may grant it (see [`access`](crate::sync::access)).
+    AccessRequest {
+        /// The document the request is for, by the ID its requester and admins know
+        /// it by.
+        id: SedimentreeId,
+
+        /// The request itself, signed by the requester, which relays pass on as it is.
+        request: Vec<u8>,
+    },
+
+    /// One of the chunks a [`BatchSyncResponse`] left to follow it (see
+    /// [`bootstrap`](crate::sync::bootstrap)).
+    Backfill {
+        /// The ID of the [`Sedimentree`] that this chunk belongs to.
+        id: SedimentreeId,
+
+        /// The [`Chunk`] being sent.
+        chunk: Chunk,
+
+        /// The [`Blob`] containing the chunk data.
+        blob: Blob,
+    },
 }
 
 impl Message {
@@ -56,6 +212,32 @@ impl Message {
             _ => None,
         }
     }
+
+    /// The sedimentree this message is about, if it's about one.
+    #[must_use]
+    pub const fn sedimentree_id(&self) -> Option<SedimentreeId> {
+        match self {
+            Message::LooseCommit { id, .. }
+            | Message::Chunk { id, .. }
+            | Message::Backfill { id, .. }
+            | Message::CommitUpload { id, .. }
+            | Message::BatchSyncRequest(BatchSyncRequest { id, .. })
+            | Message::BatchSyncResponse(BatchSyncResponse { id, .. })
+            | Message::RelaySignal { id, .. }
+            | Message::Signal { id, .. }
+            | Message::DocumentTtl { id, .. } => Some(*id),
+            _ => None,
+        }
+    }
+
+    /// A [`Message::DocumentTtl`] giving `ttl` for the sedimentree `id`, to the second.
+    #[must_use]
+    pub fn document_ttl(id: SedimentreeId, ttl: Option<Duration>) -> Self {
+        Message::DocumentTtl {
+            id,
+            ttl_secs: ttl.map(|ttl| ttl.as_secs()),
+        }
+    }
 }
 
 /// A request to sync a sedimentree in batch.
@@ -71,6 +253,20 @@ pub struct BatchSyncRequest {
 
     /// The summary of the sedimentree that the requester has.
     pub sedimentree_summary: SedimentreeSummary,
+
+    /// The token from the requester's last round with this peer, if the requester's
+    /// sedimentree hasn't changed since. A responder whose summary still has that
+    /// token answers with an empty diff without comparing summaries.
+    pub summary_token: Option<SummaryToken>,
+
+    /// What the requester has of its loose commits, in place of listing them in
+    /// its summary (see [`filter`](crate::sync::filter)).
+    pub have: Option<Have>,
+
+    /// The heads the requester's last round with this peer left it holding, if any.
+    /// The summary leaves out the loose commits they were built on (see
+    /// [`session`](crate::sync::session)).
+    pub since: Vec<Digest>,
 }
 
 impl From<BatchSyncRequest> for Message {
@@ -92,6 +288,22 @@ pub struct BatchSyncResponse {
 
     /// The diff for the remote peer.
     pub diff: SyncDiff,
+
+    /// The token for the responder's summary once the round is done, for the
+    /// requester to send back next time.
+    pub summary_token: SummaryToken,
+
+    /// What the responder has of its loose commits, for the requester to push what
+    /// it lacks, if the request carried a [`Have`].
+    pub have: Option<Have>,
+
+    /// Whether the responder held all of the request's `since`, and so left out the
+    /// same commits. If not, the requester pushes the ones it left out.
+    pub resumed: bool,
+
+    /// How many more of the requester's missing chunks follow the response, each in a
+    /// [`Message::Backfill`].
+    pub backfill: u64,
 }
 
 impl From<BatchSyncResponse> for Message {
@@ -100,6 +312,66 @@ impl From<BatchSyncResponse> for Message {
     }
 }
 
+/// What a peer has of a sedimentree's loose commits, summed up rather than listed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Have {
+    /// The loose commits no other loose commit names as a parent.
+    pub heads: Vec<Digest>,
+
+    /// A Bloom filter of the digests of all of its loose commits.
+    pub filter: CommitFilter,
+
+    /// Commits it knows it lacks, such as missing parents of ones it holds.
+    pub need: Vec<Digest>,
+}
+
+/// Identifies a responder's summary of a sedimentree, like an HTTP `ETag`, so that a
+/// requester can ask it to sync only if it has changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SummaryToken(Digest);
+
+impl SummaryToken {
+    /// A token from its digest.
+    #[must_use]
+    pub const fn new(digest: Digest) -> Self {
+        Self(digest)
+    }
+
+    /// The token's digest.
+    #[must_use]
+    pub const fn digest(&self) -> Digest {
+        self.0
+    }
+}
+
+/// The parts of a WebRTC session negotiation exchanged between two peers.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Signal {
+    /// An SDP offer.
+    Offer(String),
+
+    /// An SDP answer to an offer.
+    Answer(String),
+
+    /// An ICE candidate.
+    IceCandidate {
+        /// The candidate line.
+        candidate: String,
+
+        /// The media stream identification tag the candidate is for.
+        sdp_mid: Option<String>,
+
+        /// The index of the media description the candidate is for.
+        sdp_m_line_index: Option<u16>,
+    },
+}
+
 /// A unique identifier for a particular request.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
@@ -0,0 +1,229 @@
+//! Export [`Sedimentree`] history to a `SQLite` database for analysis.
+//!
+//! The exported database has the following tables:
+//!
+//! | Table            | Contents                                               |
+//! |------------------|--------------------------------------------------------|
+//! | `documents`      | One row per sedimentree, with its head commits         |
+//! | `commits`        | Loose commits with their blob digest, size, and depth  |
+//! | `commit_parents` | One row per (commit, parent) edge                      |
+//! | `chunks`         | Chunks with their blob digest, size, and depth         |
+//! | `chunk_boundary` | One row per (chunk, boundary commit)                   |
+//!
+//! All digests and IDs are stored as lowercase hex strings.
+//! Exporting into an existing database replaces the rows for the exported documents.
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, Transaction};
+use sedimentree_core::{Depth, Sedimentree, SedimentreeId};
+use thiserror::Error;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS documents (
+        id          TEXT PRIMARY KEY,
+        heads       TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS commits (
+        document_id TEXT NOT NULL REFERENCES documents(id),
+        digest      TEXT NOT NULL,
+        blob_digest TEXT NOT NULL,
+        size_bytes  INTEGER NOT NULL,
+        depth       INTEGER NOT NULL,
+        PRIMARY KEY (document_id, digest)
+    );
+    CREATE TABLE IF NOT EXISTS commit_parents (
+        document_id TEXT NOT NULL REFERENCES documents(id),
+        commit_digest TEXT NOT NULL,
+        parent_digest TEXT NOT NULL,
+        position    INTEGER NOT NULL,
+        PRIMARY KEY (document_id, commit_digest, position)
+    );
+    CREATE TABLE IF NOT EXISTS chunks (
+        document_id TEXT NOT NULL REFERENCES documents(id),
+        head        TEXT NOT NULL,
+        digest      TEXT NOT NULL,
+        blob_digest TEXT NOT NULL,
+        size_bytes  INTEGER NOT NULL,
+        depth       INTEGER NOT NULL,
+        checkpoint_count INTEGER NOT NULL,
+        PRIMARY KEY (document_id, digest)
+    );
+    CREATE TABLE IF NOT EXISTS chunk_boundary (
+        document_id TEXT NOT NULL REFERENCES documents(id),
+        chunk_digest TEXT NOT NULL,
+        boundary_digest TEXT NOT NULL,
+        PRIMARY KEY (document_id, chunk_digest, boundary_digest)
+    );
+";
+
+/// The number of rows written by an export.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExportSummary {
+    /// The number of documents exported.
+    pub documents: usize,
+
+    /// The number of loose commits exported.
+    pub commits: usize,
+
+    /// The number of chunks exported.
+    pub chunks: usize,
+}
+
+/// Write the given sedimentrees to the `SQLite` database at `path`, creating it if needed.
+///
+/// # Errors
+///
+/// * [`ExportError`] if the database can't be opened or written.
+pub fn export_sqlite<'a>(
+    path: impl AsRef<Path>,
+    trees: impl IntoIterator<Item = (SedimentreeId, &'a Sedimentree)>,
+) -> Result<ExportSummary, ExportError> {
+    let mut conn = Connection::open(path)?;
+    conn.execute_batch(SCHEMA)?;
+
+    let tx = conn.transaction()?;
+    let mut summary = ExportSummary::default();
+    for (id, tree) in trees {
+        write_tree(&tx, id, tree, &mut summary)?;
+    }
+    tx.commit()?;
+
+    Ok(summary)
+}
+
+fn write_tree(
+    tx: &Transaction<'_>,
+    id: SedimentreeId,
+    tree: &Sedimentree,
+    summary: &mut ExportSummary,
+) -> Result<(), ExportError> {
+    let doc_id = id.to_string();
+    for table in ["commit_parents", "commits", "chunk_boundary", "chunks"] {
+        tx.execute(
+            &format!("DELETE FROM {table} WHERE document_id = ?1"),
+            params![doc_id],
+        )?;
+    }
+
+    let heads = tree
+        .heads()
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    tx.execute(
+        "INSERT OR REPLACE INTO documents (id, heads) VALUES (?1, ?2)",
+        params![doc_id, heads],
+    )?;
+
+    for commit in tree.loose_commits() {
+        let digest = commit.digest().to_string();
+        tx.execute(
+            "INSERT OR REPLACE INTO commits (document_id, digest, blob_digest, size_bytes, depth)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                doc_id,
+                digest,
+                commit.blob().digest().to_string(),
+                commit.blob().size_bytes(),
+                Depth::from(commit.digest()).0,
+            ],
+        )?;
+
+        for (position, parent) in commit.parents().iter().enumerate() {
+            tx.execute(
+                "INSERT OR REPLACE INTO commit_parents (document_id, commit_digest, parent_digest, position)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![doc_id, digest, parent.to_string(), position],
+            )?;
+        }
+
+        summary.commits += 1;
+    }
+
+    for chunk in tree.chunks() {
+        let digest = chunk.digest().to_string();
+        let blob_meta = chunk.summary().blob_meta();
+        tx.execute(
+            "INSERT OR REPLACE INTO chunks
+                (document_id, head, digest, blob_digest, size_bytes, depth, checkpoint_count)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                doc_id,
+                chunk.head().to_string(),
+                digest,
+                blob_meta.digest().to_string(),
+                blob_meta.size_bytes(),
+                chunk.depth().0,
+                chunk.checkpoints().len(),
+            ],
+        )?;
+
+        for boundary in chunk.boundary() {
+            tx.execute(
+                "INSERT OR REPLACE INTO chunk_boundary (document_id, chunk_digest, boundary_digest)
+                 VALUES (?1, ?2, ?3)",
+                params![doc_id, digest, boundary.to_string()],
+            )?;
+        }
+
+        summary.chunks += 1;
+    }
+
+    summary.documents += 1;
+    Ok(())
+}
+
+/// A problem exporting to `SQLite`.
+#[derive(Debug, Error)]
+pub enum ExportError {
+    /// The database could not be opened or written.
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::{BlobMeta, Digest, LooseCommit};
+
+    use super::*;
+
+    #[test]
+    fn exports_commits_and_parents() -> Result<(), ExportError> {
+        let parent = LooseCommit::new(Digest::hash(b"parent"), vec![], BlobMeta::new(b"a"));
+        let child = LooseCommit::new(
+            Digest::hash(b"child"),
+            vec![parent.digest()],
+            BlobMeta::new(b"bc"),
+        );
+        let tree = Sedimentree::new(vec![], vec![parent, child]);
+        let id = SedimentreeId::new([1; 32]);
+
+        let path = std::env::temp_dir().join(format!("export-{}.sqlite", std::process::id()));
+        let summary = export_sqlite(&path, [(id, &tree)])?;
+        // Exporting again replaces rather than duplicates.
+        export_sqlite(&path, [(id, &tree)])?;
+
+        let conn = Connection::open(&path)?;
+        let (commits, bytes): (usize, u64) =
+            conn.query_row("SELECT COUNT(*), SUM(size_bytes) FROM commits", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?;
+        let edges: usize =
+            conn.query_row("SELECT COUNT(*) FROM commit_parents", [], |row| row.get(0))?;
+        drop(conn);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(
+            summary,
+            ExportSummary {
+                documents: 1,
+                commits: 2,
+                chunks: 0
+            }
+        );
+        assert_eq!((commits, bytes, edges), (2, 3, 1));
+        Ok(())
+    }
+}
@@ -26,7 +26,12 @@
 )]
 #![forbid(unsafe_code)]
 
+pub mod audit;
+pub mod compaction;
 pub mod connection;
+#[cfg(feature = "sqlite")]
+pub mod export;
+pub mod lifecycle;
 pub mod peer;
 pub mod storage;
 pub mod sync;
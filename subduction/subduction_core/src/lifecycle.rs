@@ -0,0 +1,426 @@
+//! Lifecycle policies for documents held by a relay.
+//!
+//! A public relay can't keep every document forever. [`LifecycleRules`] say
+//! when a document expires (or is archived) after going untouched, and how
+//! much loose history it keeps, by default, per tenant, and per document. The rules are evaluated periodically by
+//! [`Subduction::run_lifecycle`][crate::Subduction::run_lifecycle], which
+//! returns a [`LifecycleReport`] of what it did.
+//!
+//! Activity is stamped when the rules are evaluated rather than on every
+//! write, so that the sync path never needs a clock. As a result, times are
+//! only as precise as the interval between evaluations.
+//!
+//! Separately from the rules, a document may be ephemeral: its metadata gives
+//! a TTL, set with [`Subduction::set_ttl`][crate::Subduction::set_ttl] and
+//! passed between peers as they sync, after which its commits expire. Every
+//! replica that evaluates its lifecycle drops commits it first saw longer ago
+//! than the TTL, heads included, and refuses them if a peer that saw them
+//! later offers them back.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    time::{Duration, SystemTime},
+};
+
+use sedimentree_core::{Digest, Sedimentree, SedimentreeId};
+
+/// The lifecycle policy for a single document.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct LifecyclePolicy {
+    /// Expire documents that haven't been touched for this long.
+    pub expire_after_idle: Option<Duration>,
+
+    /// Prune loose commits (other than heads) first seen longer ago than this.
+    pub max_history_age: Option<Duration>,
+
+    /// Copy expiring documents to the archive storage before dropping them.
+    pub archive_on_expiry: bool,
+}
+
+/// The lifecycle policy for a tenant of a relay, and the documents it holds there.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TenantPolicy {
+    /// The tenant's documents.
+    pub documents: HashSet<SedimentreeId>,
+
+    /// The policy for the tenant's documents.
+    pub policy: LifecyclePolicy,
+}
+
+/// The lifecycle policies for all documents on a relay.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LifecycleRules {
+    /// The policy for documents without an override.
+    pub default: LifecyclePolicy,
+
+    /// Per-document overrides.
+    pub documents: HashMap<SedimentreeId, LifecyclePolicy>,
+
+    /// Per-tenant overrides, by the tenant's name. A document's own override takes
+    /// precedence over its tenant's.
+    pub tenants: BTreeMap<String, TenantPolicy>,
+}
+
+impl LifecycleRules {
+    /// The policy that applies to the document `id`: its own override, or else that
+    /// of the first tenant (by name) holding it, or else the default.
+    #[must_use]
+    pub fn policy_for(&self, id: SedimentreeId) -> &LifecyclePolicy {
+        self.documents
+            .get(&id)
+            .or_else(|| {
+                self.tenants
+                    .values()
+                    .find(|tenant| tenant.documents.contains(&id))
+                    .map(|tenant| &tenant.policy)
+            })
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Something done to a document by a lifecycle run.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LifecycleAction {
+    /// The document was dropped after going idle.
+    Expired {
+        /// How long the document had been idle.
+        idle_for: Duration,
+    },
+
+    /// The document was copied to archive storage and dropped after going idle.
+    Archived {
+        /// How long the document had been idle.
+        idle_for: Duration,
+    },
+
+    /// Loose commits older than the maximum history age were dropped.
+    HistoryPruned {
+        /// The digests of the pruned commits.
+        commits: Vec<Digest>,
+    },
+
+    /// Loose commits older than the document's TTL were dropped, heads included.
+    CommitsExpired {
+        /// The digests of the expired commits.
+        commits: Vec<Digest>,
+    },
+}
+
+/// The outcome of a lifecycle run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LifecycleReport {
+    /// When the rules were evaluated.
+    pub evaluated_at: SystemTime,
+
+    /// The number of documents the rules were evaluated against.
+    pub documents_evaluated: usize,
+
+    /// The actions taken, per document.
+    pub actions: Vec<(SedimentreeId, LifecycleAction)>,
+}
+
+/// What we know about when a document was last used.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocumentActivity {
+    /// When the document was first seen.
+    pub first_seen: SystemTime,
+
+    /// When the document was last written to.
+    pub last_touched: SystemTime,
+
+    commits_seen: HashMap<Digest, SystemTime>,
+}
+
+/// Bookkeeping for lifecycle evaluation.
+#[derive(Debug, Default)]
+pub(crate) struct LifecycleState {
+    touched: HashSet<SedimentreeId>,
+    activity: HashMap<SedimentreeId, DocumentActivity>,
+    /// The TTLs of ephemeral documents.
+    ttls: HashMap<SedimentreeId, Duration>,
+    /// Commits dropped for being older than their document's TTL, and when, so they
+    /// aren't taken back from peers that still have them.
+    expired: HashMap<SedimentreeId, HashMap<Digest, SystemTime>>,
+    last_report: Option<LifecycleReport>,
+}
+
+impl LifecycleState {
+    /// Note that `id` was written to since the last evaluation.
+    pub(crate) fn touch(&mut self, id: SedimentreeId) {
+        self.touched.insert(id);
+    }
+
+    /// Stamp activity as of `now`, and decide what `rules` call for.
+    ///
+    /// Expiry is planned as [`LifecycleAction::Expired`] even when the policy asks for
+    /// archiving; the caller upgrades it once the archive copy has succeeded.
+    pub(crate) fn plan(
+        &mut self,
+        trees: &HashMap<SedimentreeId, Sedimentree>,
+        rules: &LifecycleRules,
+        now: SystemTime,
+    ) -> Vec<(SedimentreeId, LifecycleAction)> {
+        self.activity.retain(|id, _| trees.contains_key(id));
+        // A peer that kept an expired commit drops it within a TTL of us, so by
+        // then nobody should offer it back
+        let ttls = &self.ttls;
+        self.expired.retain(|id, expired| {
+            let ttl = ttls.get(id).copied().unwrap_or_default();
+            expired.retain(|_, at| now.duration_since(*at).unwrap_or_default() < ttl);
+            !expired.is_empty()
+        });
+
+        let mut planned = Vec::new();
+        for (id, tree) in trees {
+            let activity = self
+                .activity
+                .entry(*id)
+                .or_insert_with(|| DocumentActivity {
+                    first_seen: now,
+                    last_touched: now,
+                    commits_seen: HashMap::new(),
+                });
+            if self.touched.contains(id) {
+                activity.last_touched = now;
+            }
+            for commit in tree.loose_commits() {
+                activity.commits_seen.entry(commit.digest()).or_insert(now);
+            }
+
+            let policy = rules.policy_for(*id);
+            let idle_for = now
+                .duration_since(activity.last_touched)
+                .unwrap_or_default();
+            if policy
+                .expire_after_idle
+                .is_some_and(|expire_after| idle_for >= expire_after)
+            {
+                planned.push((*id, LifecycleAction::Expired { idle_for }));
+                continue;
+            }
+
+            let seen_for = |digest: &Digest| {
+                activity
+                    .commits_seen
+                    .get(digest)
+                    .map(|seen| now.duration_since(*seen).unwrap_or_default())
+            };
+            let mut expired = Vec::new();
+            if let Some(ttl) = self.ttls.get(id) {
+                expired = tree
+                    .loose_commits()
+                    .map(sedimentree_core::LooseCommit::digest)
+                    .filter(|digest| seen_for(digest).is_some_and(|age| age >= *ttl))
+                    .collect::<Vec<_>>();
+                if !expired.is_empty() {
+                    expired.sort();
+                    planned.push((
+                        *id,
+                        LifecycleAction::CommitsExpired {
+                            commits: expired.clone(),
+                        },
+                    ));
+                }
+            }
+
+            if let Some(max_age) = policy.max_history_age {
+                let heads = tree.heads();
+                let mut commits = tree
+                    .loose_commits()
+                    .map(sedimentree_core::LooseCommit::digest)
+                    .filter(|digest| !heads.contains(digest) && !expired.contains(digest))
+                    .filter(|digest| seen_for(digest).is_some_and(|age| age >= max_age))
+                    .collect::<Vec<_>>();
+                if !commits.is_empty() {
+                    commits.sort();
+                    planned.push((*id, LifecycleAction::HistoryPruned { commits }));
+                }
+            }
+        }
+
+        self.touched.clear();
+        planned
+    }
+
+    /// Drop all bookkeeping for a document that is no longer held.
+    pub(crate) fn forget(&mut self, id: SedimentreeId) {
+        self.activity.remove(&id);
+        self.ttls.remove(&id);
+        self.expired.remove(&id);
+    }
+
+    /// Make `id` ephemeral with `ttl`, or not with `None`, returning whether that changed.
+    pub(crate) fn set_ttl(&mut self, id: SedimentreeId, ttl: Option<Duration>) -> bool {
+        match ttl {
+            Some(ttl) => self.ttls.insert(id, ttl) != Some(ttl),
+            None => self.ttls.remove(&id).is_some(),
+        }
+    }
+
+    pub(crate) fn ttl(&self, id: SedimentreeId) -> Option<Duration> {
+        self.ttls.get(&id).copied()
+    }
+
+    /// Note that `commits` of `id` expired at `now`.
+    pub(crate) fn record_expired(
+        &mut self,
+        id: SedimentreeId,
+        commits: &[Digest],
+        now: SystemTime,
+    ) {
+        let expired = self.expired.entry(id).or_default();
+        for digest in commits {
+            expired.insert(*digest, now);
+            if let Some(activity) = self.activity.get_mut(&id) {
+                activity.commits_seen.remove(digest);
+            }
+        }
+    }
+
+    /// Whether commit `digest` of `id` expired recently enough to be refused.
+    pub(crate) fn is_expired(&self, id: SedimentreeId, digest: Digest) -> bool {
+        self.expired
+            .get(&id)
+            .is_some_and(|expired| expired.contains_key(&digest))
+    }
+
+    pub(crate) fn activity(&self, id: SedimentreeId) -> Option<&DocumentActivity> {
+        self.activity.get(&id)
+    }
+
+    pub(crate) const fn last_report(&self) -> Option<&LifecycleReport> {
+        self.last_report.as_ref()
+    }
+
+    pub(crate) fn set_last_report(&mut self, report: LifecycleReport) {
+        self.last_report = Some(report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::{BlobMeta, LooseCommit};
+
+    use super::*;
+
+    fn days(n: u64) -> Duration {
+        Duration::from_secs(n * 24 * 60 * 60)
+    }
+
+    #[test]
+    fn expires_idle_documents_and_prunes_old_history() {
+        let parent = LooseCommit::new(Digest::hash(b"parent"), vec![], BlobMeta::new(b"p"));
+        let head = LooseCommit::new(
+            Digest::hash(b"head"),
+            vec![parent.digest()],
+            BlobMeta::new(b"h"),
+        );
+        let idle = SedimentreeId::new([1; 32]);
+        let busy = SedimentreeId::new([2; 32]);
+        let trees = HashMap::from([
+            (idle, Sedimentree::default()),
+            (busy, Sedimentree::new(vec![], vec![parent.clone(), head])),
+        ]);
+        let rules = LifecycleRules {
+            default: LifecyclePolicy {
+                expire_after_idle: Some(days(30)),
+                max_history_age: Some(days(7)),
+                archive_on_expiry: false,
+            },
+            ..LifecycleRules::default()
+        };
+
+        let mut state = LifecycleState::default();
+        let start = SystemTime::UNIX_EPOCH;
+        assert!(state.plan(&trees, &rules, start).is_empty());
+
+        state.touch(busy);
+        let later = start + days(31);
+        let mut planned = state.plan(&trees, &rules, later);
+        planned.sort_by_key(|(id, _)| *id);
+
+        assert_eq!(
+            planned,
+            vec![
+                (idle, LifecycleAction::Expired { idle_for: days(31) }),
+                (
+                    busy,
+                    LifecycleAction::HistoryPruned {
+                        commits: vec![parent.digest()]
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn expires_commits_older_than_the_ttl_heads_included() {
+        let parent = LooseCommit::new(Digest::hash(b"parent"), vec![], BlobMeta::new(b"p"));
+        let head = LooseCommit::new(
+            Digest::hash(b"head"),
+            vec![parent.digest()],
+            BlobMeta::new(b"h"),
+        );
+        let id = SedimentreeId::new([1; 32]);
+        let trees = HashMap::from([(
+            id,
+            Sedimentree::new(vec![], vec![parent.clone(), head.clone()]),
+        )]);
+        let ttl = days(1);
+
+        let mut state = LifecycleState::default();
+        assert!(state.set_ttl(id, Some(ttl)));
+        assert!(!state.set_ttl(id, Some(ttl)));
+        let start = SystemTime::UNIX_EPOCH;
+        let rules = LifecycleRules::default();
+        assert!(state.plan(&trees, &rules, start).is_empty());
+
+        let mut commits = vec![parent.digest(), head.digest()];
+        commits.sort();
+        let planned = state.plan(&trees, &rules, start + ttl);
+        assert_eq!(
+            planned,
+            vec![(
+                id,
+                LifecycleAction::CommitsExpired {
+                    commits: commits.clone()
+                }
+            )]
+        );
+
+        state.record_expired(id, &commits, start + ttl);
+        assert!(commits.iter().all(|digest| state.is_expired(id, *digest)));
+
+        // Refused for one more TTL, then forgotten
+        state.plan(&HashMap::new(), &rules, start + ttl * 2);
+        assert!(!state.is_expired(id, commits[0]));
+    }
+
+    #[test]
+    fn documents_override_their_tenant_and_tenants_the_default() {
+        let (own, tenants, other) = (
+            SedimentreeId::new([1; 32]),
+            SedimentreeId::new([2; 32]),
+            SedimentreeId::new([3; 32]),
+        );
+        let policy = |days_idle| LifecyclePolicy {
+            expire_after_idle: Some(days(days_idle)),
+            ..LifecyclePolicy::default()
+        };
+        let rules = LifecycleRules {
+            default: policy(30),
+            documents: HashMap::from([(own, policy(1))]),
+            tenants: BTreeMap::from([(
+                "acme".to_string(),
+                TenantPolicy {
+                    documents: HashSet::from([own, tenants]),
+                    policy: policy(7),
+                },
+            )]),
+        };
+
+        assert_eq!(rules.policy_for(own), &policy(1));
+        assert_eq!(rules.policy_for(tenants), &policy(7));
+        assert_eq!(rules.policy_for(other), &policy(30));
+    }
+}
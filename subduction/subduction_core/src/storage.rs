@@ -2,3 +2,7 @@
 
 pub mod id;
 pub mod key;
+#[cfg(feature = "object-store")]
+pub mod object;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
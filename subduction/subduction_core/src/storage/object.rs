@@ -0,0 +1,515 @@
+//! A [`Storage`] backend that keeps sedimentrees in an object store, such as S3,
+//! GCS, or `MinIO`, via the [`object_store`] crate.
+//!
+//! Objects are laid out under a root prefix:
+//!
+//! ```text
+//! <root>/header                                 the store's StorageHeader
+//! <root>/blobs/abcd…                            blobs, encoded with the header's codec, by their digest
+//! <root>/documents/<id>/commits/abcd…           loose commits, by their blob's digest
+//! <root>/documents/<id>/chunks/abcd…            chunks, by their blob's digest
+//! ```
+//!
+//! Commits and chunks are encoded as [`record`] lays out, like
+//! [`FsStorage`](sedimentree_core::storage::fs::FsStorage)'s files. Every object is
+//! named by what it holds and never changes once written, so servers sharing a
+//! bucket don't need to coordinate: writing an object twice writes the same bytes,
+//! and what one server reads is never stale. That lets sync servers scale
+//! horizontally without state of their own. Objects are only removed when expired
+//! data is deleted (see [`Storage::delete_blobs`]): a blob goes once no sedimentree
+//! in the store has a commit or chunk stored by it.
+//!
+//! Each [`ObjectStorage`] is scoped to one sedimentree, and keeps the commits and
+//! chunks it has read in a local cache. Loading them lists the sedimentree's
+//! prefix and only fetches the objects the cache doesn't hold yet, which keeps
+//! repeated loads to a single listing. Blobs aren't cached.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    FutureExt, StreamExt, TryStreamExt,
+};
+use object_store::{path::Path, ObjectStore, PutMode, PutOptions, PutPayload};
+use sedimentree_core::{
+    future::{Local, Sendable},
+    storage::{
+        codec::{self, CodecError},
+        header::{Codec, HeaderError, StorageHeader},
+        record, Storage,
+    },
+    Blob, Chunk, Digest, LooseCommit, SedimentreeId,
+};
+use thiserror::Error;
+
+const HEADER: &str = "header";
+const BLOBS: &str = "blobs";
+const DOCUMENTS: &str = "documents";
+const COMMITS: &str = "commits";
+const CHUNKS: &str = "chunks";
+
+/// How many objects a load fetches at once.
+const CONCURRENT_FETCHES: usize = 16;
+
+/// A [`Storage`] backend for one sedimentree in an object store.
+///
+/// Blobs are held encoded with the header's [`Codec`].
+#[derive(Debug, Clone)]
+pub struct ObjectStorage {
+    store: Arc<dyn ObjectStore>,
+    root: Path,
+    header: StorageHeader,
+    document: SedimentreeId,
+    cache: Arc<Mutex<Cache>>,
+}
+
+/// The commits and chunks a handle has read or written, by their object's path.
+#[derive(Debug, Default)]
+struct Cache {
+    commits: HashMap<Path, LooseCommit>,
+    chunks: HashMap<Path, Chunk>,
+}
+
+impl ObjectStorage {
+    /// Open the store under `root` in `store` for sedimentree `document`, creating it
+    /// if it doesn't exist yet.
+    ///
+    /// # Errors
+    ///
+    /// * [`ObjectStorageError::Header`] if `root` holds a store this version can't
+    ///   read.
+    /// * [`ObjectStorageError::ObjectStore`] if its header can't be read or written.
+    pub async fn open(
+        store: Arc<dyn ObjectStore>,
+        root: Path,
+        document: SedimentreeId,
+    ) -> Result<Self, ObjectStorageError> {
+        Self::open_with_codec(store, root, document, Codec::default()).await
+    }
+
+    /// Open the store under `root` in `store` for sedimentree `document`, creating it
+    /// with blobs encoded with `codec` if it doesn't exist yet. An existing store
+    /// keeps the codec it was created with.
+    ///
+    /// # Errors
+    ///
+    /// * [`ObjectStorageError::Header`] if `root` holds a store this version can't
+    ///   read.
+    /// * [`ObjectStorageError::ObjectStore`] if its header can't be read or written.
+    pub async fn open_with_codec(
+        store: Arc<dyn ObjectStore>,
+        root: Path,
+        document: SedimentreeId,
+        codec: Codec,
+    ) -> Result<Self, ObjectStorageError> {
+        let location = root.child(HEADER);
+        let header = if let Some(bytes) = get(store.as_ref(), &location).await? {
+            StorageHeader::from_bytes(&bytes)?.0
+        } else {
+            let header = StorageHeader::default().with_codec(codec);
+            let create = PutOptions::from(PutMode::Create);
+            match store
+                .put_opts(&location, PutPayload::from(header.to_bytes()), create)
+                .await
+            {
+                Ok(_) => header,
+                // Another server created it first; theirs is the one in effect
+                Err(object_store::Error::AlreadyExists { .. }) => {
+                    let bytes = store.get(&location).await?.bytes().await?;
+                    StorageHeader::from_bytes(&bytes)?.0
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+
+        Ok(Self {
+            store,
+            root,
+            header,
+            document,
+            cache: Arc::default(),
+        })
+    }
+
+    /// The sedimentree this handle stores.
+    #[must_use]
+    pub const fn id(&self) -> SedimentreeId {
+        self.document
+    }
+
+    /// The prefix the store is kept under.
+    #[must_use]
+    pub const fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// A handle for sedimentree `document` in the same store, with a cache of its own.
+    #[must_use]
+    pub fn document(&self, document: SedimentreeId) -> Self {
+        Self {
+            store: self.store.clone(),
+            root: self.root.clone(),
+            header: self.header.clone(),
+            document,
+            cache: Arc::default(),
+        }
+    }
+
+    fn blob_path(&self, digest: Digest) -> Path {
+        self.root.child(BLOBS).child(digest.to_string())
+    }
+
+    /// The prefix the sedimentree's objects of `kind` are kept under.
+    fn prefix(&self, kind: &str) -> Path {
+        self.root
+            .child(DOCUMENTS)
+            .child(self.document.to_string())
+            .child(kind)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Cache> {
+        // The cache only ever holds whole entries, so a panic can't leave it torn
+        self.cache.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    async fn put(&self, location: &Path, bytes: Vec<u8>) -> Result<(), ObjectStorageError> {
+        self.store.put(location, PutPayload::from(bytes)).await?;
+        Ok(())
+    }
+
+    async fn put_commit(&self, commit: LooseCommit) -> Result<(), ObjectStorageError> {
+        let location = self
+            .prefix(COMMITS)
+            .child(commit.blob().digest().to_string());
+        self.put(&location, record::encode_commit(&commit)).await?;
+        self.lock().commits.insert(location, commit);
+        Ok(())
+    }
+
+    async fn put_chunk(&self, chunk: Chunk) -> Result<(), ObjectStorageError> {
+        let location = self
+            .prefix(CHUNKS)
+            .child(chunk.summary().blob_meta().digest().to_string());
+        self.put(&location, record::encode_chunk(&chunk)).await?;
+        self.lock().chunks.insert(location, chunk);
+        Ok(())
+    }
+
+    async fn put_blob(&self, blob: Blob) -> Result<Digest, ObjectStorageError> {
+        let digest = Digest::hash(blob.contents());
+        let stored = codec::encode_blob(self.header.codec(), &blob);
+        self.put(&self.blob_path(digest), stored).await?;
+        Ok(digest)
+    }
+
+    async fn get_blob(&self, digest: Digest) -> Result<Option<Blob>, ObjectStorageError> {
+        let stored = get(self.store.as_ref(), &self.blob_path(digest)).await?;
+        Ok(stored
+            .map(|stored| codec::decode_blob(&stored))
+            .transpose()?)
+    }
+
+    /// Delete the sedimentree's commits and chunks stored by `digests`, then the
+    /// blobs no sedimentree in the store has a commit or chunk stored by any more.
+    ///
+    /// Another server may store a commit by one of the blobs between the listing and
+    /// the deletion, in which case it has to fetch the blob again.
+    async fn remove(&self, digests: Vec<Digest>) -> Result<(), ObjectStorageError> {
+        for digest in &digests {
+            for kind in [COMMITS, CHUNKS] {
+                let location = self.prefix(kind).child(digest.to_string());
+                delete(self.store.as_ref(), &location).await?;
+                let mut cache = self.lock();
+                cache.commits.remove(&location);
+                cache.chunks.remove(&location);
+            }
+        }
+
+        let referenced = self
+            .store
+            .list(Some(&self.root.child(DOCUMENTS)))
+            .map_ok(|meta| meta.location.filename().map(str::to_string))
+            .try_collect::<HashSet<_>>()
+            .await?;
+        for digest in digests {
+            if !referenced.contains(&Some(digest.to_string())) {
+                delete(self.store.as_ref(), &self.blob_path(digest)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The values of every object under the sedimentree's `kind` prefix, fetching
+    /// and decoding the ones `cached` doesn't hold yet.
+    async fn load<T: Clone>(
+        &self,
+        kind: &str,
+        decode: fn(&[u8]) -> Option<T>,
+        cached: fn(&mut Cache) -> &mut HashMap<Path, T>,
+    ) -> Result<Vec<T>, ObjectStorageError> {
+        let listed = self
+            .store
+            .list(Some(&self.prefix(kind)))
+            .map_ok(|meta| meta.location)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let (mut values, missing) = {
+            let mut cache = self.lock();
+            let cache = cached(&mut cache);
+            let mut values = Vec::with_capacity(listed.len());
+            let mut missing = Vec::new();
+            for location in listed {
+                match cache.get(&location) {
+                    Some(value) => values.push(value.clone()),
+                    None => missing.push(location),
+                }
+            }
+            (values, missing)
+        };
+
+        let fetched = futures::stream::iter(missing)
+            .map(|location| async move {
+                let bytes = self.store.get(&location).await?.bytes().await?;
+                let value =
+                    decode(&bytes).ok_or_else(|| ObjectStorageError::Corrupt(location.clone()))?;
+                Ok::<_, ObjectStorageError>((location, value))
+            })
+            .buffer_unordered(CONCURRENT_FETCHES)
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        let mut cache = self.lock();
+        let cache = cached(&mut cache);
+        for (location, value) in fetched {
+            values.push(value.clone());
+            cache.insert(location, value);
+        }
+        Ok(values)
+    }
+
+    async fn load_commits(&self) -> Result<Vec<LooseCommit>, ObjectStorageError> {
+        self.load(COMMITS, record::decode_commit, |cache| &mut cache.commits)
+            .await
+    }
+
+    async fn load_all_chunks(&self) -> Result<Vec<Chunk>, ObjectStorageError> {
+        self.load(CHUNKS, record::decode_chunk, |cache| &mut cache.chunks)
+            .await
+    }
+}
+
+/// The bytes of the object at `location`, or `None` if there isn't one.
+async fn get(
+    store: &dyn ObjectStore,
+    location: &Path,
+) -> Result<Option<Vec<u8>>, ObjectStorageError> {
+    match store.get(location).await {
+        Ok(found) => Ok(Some(found.bytes().await?.to_vec())),
+        Err(object_store::Error::NotFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Delete the object at `location`, if there is one.
+async fn delete(store: &dyn ObjectStore, location: &Path) -> Result<(), ObjectStorageError> {
+    match store.delete(location).await {
+        Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A problem with an object store.
+#[derive(Debug, Error)]
+pub enum ObjectStorageError {
+    /// The object store could not be read or written.
+    #[error(transparent)]
+    ObjectStore(#[from] object_store::Error),
+
+    /// The store holds a header this version can't read.
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+
+    /// A stored blob couldn't be decoded.
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+
+    /// An object isn't one this version wrote.
+    #[error("corrupt object in object store: {0}")]
+    Corrupt(Path),
+}
+
+impl Storage<Sendable> for ObjectStorage {
+    type Error = ObjectStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> BoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        self.load_commits().boxed()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.put_commit(loose_commit).boxed()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.put_chunk(chunk).boxed()
+    }
+
+    fn load_chunks(&self) -> BoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        self.load_all_chunks().boxed()
+    }
+
+    fn save_blob(&self, blob: Blob) -> BoxFuture<'_, Result<Digest, Self::Error>> {
+        self.put_blob(blob).boxed()
+    }
+
+    fn load_blob(&self, blob_digest: Digest) -> BoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        self.get_blob(blob_digest).boxed()
+    }
+
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> BoxFuture<'_, Result<(), Self::Error>> {
+        self.remove(blob_digests).boxed()
+    }
+}
+
+impl Storage<Local> for ObjectStorage {
+    type Error = ObjectStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        self.load_commits().boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.put_commit(loose_commit).boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.put_chunk(chunk).boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        self.load_all_chunks().boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        self.put_blob(blob).boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        self.get_blob(blob_digest).boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        self.remove(blob_digests).boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use nonempty::nonempty;
+    use object_store::memory::InMemory;
+    use sedimentree_core::BlobMeta;
+
+    use super::*;
+
+    #[test]
+    fn shares_a_bucket_between_servers() -> Result<(), ObjectStorageError> {
+        block_on(async {
+            let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+            let root = Path::from("sync");
+            let doc = SedimentreeId::new([1; 32]);
+            let other = SedimentreeId::new([2; 32]);
+
+            let blob = Blob::new(b"hello".to_vec());
+            let commit = LooseCommit::new(Digest::hash(b"commit"), vec![], blob.meta());
+            let chunk = Chunk::new(
+                commit.digest(),
+                nonempty![Digest::hash(b"end")],
+                vec![],
+                BlobMeta::new(b"bundle"),
+            );
+
+            let first =
+                ObjectStorage::open_with_codec(bucket.clone(), root.clone(), doc, Codec::Deflate)
+                    .await?;
+            Storage::<Sendable>::save_blob(&first, blob.clone()).await?;
+            Storage::<Sendable>::save_loose_commit(&first, commit.clone()).await?;
+            Storage::<Sendable>::save_chunk(&first.document(other), chunk.clone()).await?;
+
+            // A second server on the same bucket sees the first one's writes
+            let second = ObjectStorage::open(bucket, root, doc).await?;
+            assert_eq!(Storage::<Local>::header(&second).codec(), Codec::Deflate);
+            assert_eq!(
+                Storage::<Local>::load_loose_commits(&second).await?,
+                vec![commit]
+            );
+            assert!(Storage::<Local>::load_chunks(&second).await?.is_empty());
+            assert_eq!(
+                Storage::<Local>::load_chunks(&second.document(other)).await?,
+                vec![chunk]
+            );
+            assert_eq!(
+                Storage::<Local>::load_blob(&second, blob.meta().digest()).await?,
+                Some(blob)
+            );
+            assert_eq!(
+                Storage::<Local>::load_blob(&second, Digest::hash(b"missing")).await?,
+                None
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn keeps_blobs_another_sedimentree_still_stores_by() -> Result<(), ObjectStorageError> {
+        block_on(async {
+            let bucket: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+            let (doc, other) = (SedimentreeId::new([1; 32]), SedimentreeId::new([2; 32]));
+            let blob = Blob::new(b"shared".to_vec());
+            let commit = LooseCommit::new(Digest::hash(b"commit"), vec![], blob.meta());
+            let digest = blob.meta().digest();
+
+            let store = ObjectStorage::open(bucket, Path::from("sync"), doc).await?;
+            for handle in [store.clone(), store.document(other)] {
+                Storage::<Sendable>::save_blob(&handle, blob.clone()).await?;
+                Storage::<Sendable>::save_loose_commit(&handle, commit.clone()).await?;
+            }
+
+            Storage::<Sendable>::delete_blobs(&store, vec![digest]).await?;
+            assert!(Storage::<Sendable>::load_loose_commits(&store)
+                .await?
+                .is_empty());
+            assert_eq!(
+                Storage::<Sendable>::load_blob(&store, digest).await?,
+                Some(blob)
+            );
+
+            Storage::<Sendable>::delete_blobs(&store.document(other), vec![digest]).await?;
+            assert_eq!(Storage::<Sendable>::load_blob(&store, digest).await?, None);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,660 @@
+//! A [`Storage`] backend that keeps sedimentrees in a `SQLite` database.
+//!
+//! One database holds any number of sedimentrees, which suits servers hosting many
+//! documents: listing them, or counting a document's commits, is an indexed query
+//! rather than a walk over directories. Commits and chunks are rows keyed by the
+//! sedimentree they belong to, and blobs are shared between sedimentrees by
+//! digest. Each [`SqliteStorage`] is scoped to one sedimentree, and handles for
+//! others share its connection (see [`SqliteStorage::document`]).
+//!
+//! The database runs in WAL mode, so readers don't wait for writers, and every
+//! write is a transaction. [`SqliteStorage::save_commits`] writes a batch of
+//! commits and their blobs in a single one, for imports and other bulk writes.
+//!
+//! By default every transaction is synced to disk before it commits, so a write
+//! that was acknowledged survives a crash or power failure ([`Durability::Full`]).
+//! [`Durability::Normal`] only syncs the WAL when it's checkpointed, which makes
+//! writes cheaper, but a power failure can then lose the last ones acknowledged. The
+//! database is consistent either way; what's at stake is whether a peer that was
+//! told a commit is stored may have to push it again.
+//!
+//! `SQLite` is called synchronously, which keeps this crate free of an async
+//! runtime. A server that can't afford to stall its executor on disk should drive
+//! the store from a blocking-friendly task.
+
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use futures::{
+    future::{BoxFuture, LocalBoxFuture},
+    FutureExt,
+};
+use nonempty::NonEmpty;
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+use sedimentree_core::{
+    future::{Local, Sendable},
+    storage::{
+        codec::{self, CodecError},
+        header::{Codec, HeaderError, StorageHeader},
+        Storage,
+    },
+    Blob, BlobMeta, Chunk, Digest, LooseCommit, SedimentreeId,
+};
+use thiserror::Error;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS header (
+        id          INTEGER PRIMARY KEY CHECK (id = 0),
+        bytes       BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS documents (
+        id          BLOB PRIMARY KEY
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS blobs (
+        digest      BLOB PRIMARY KEY,
+        stored      BLOB NOT NULL
+    ) WITHOUT ROWID;
+    CREATE TABLE IF NOT EXISTS commits (
+        document_id BLOB NOT NULL REFERENCES documents(id),
+        blob_digest BLOB NOT NULL,
+        digest      BLOB NOT NULL,
+        blob_size   INTEGER NOT NULL,
+        parents     BLOB NOT NULL,
+        PRIMARY KEY (document_id, blob_digest)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS commits_by_digest ON commits (document_id, digest);
+    CREATE TABLE IF NOT EXISTS chunks (
+        document_id BLOB NOT NULL REFERENCES documents(id),
+        blob_digest BLOB NOT NULL,
+        head        BLOB NOT NULL,
+        blob_size   INTEGER NOT NULL,
+        boundary    BLOB NOT NULL,
+        checkpoints BLOB NOT NULL,
+        PRIMARY KEY (document_id, blob_digest)
+    ) WITHOUT ROWID;
+    CREATE INDEX IF NOT EXISTS chunks_by_head ON chunks (document_id, head);
+";
+
+/// How far `SQLite` syncs a transaction to disk before it commits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub enum Durability {
+    /// Sync every transaction, so none that committed is lost to a crash or power
+    /// failure.
+    #[default]
+    Full,
+
+    /// Only sync the WAL when it's checkpointed. Writes are cheaper, but a power
+    /// failure can lose the transactions committed since the last checkpoint.
+    Normal,
+}
+
+impl Durability {
+    /// The value of the `synchronous` pragma for this durability.
+    const fn pragma(self) -> &'static str {
+        match self {
+            Self::Full => "FULL",
+            Self::Normal => "NORMAL",
+        }
+    }
+}
+
+/// A [`Storage`] backend for one sedimentree in a `SQLite` database.
+///
+/// Blobs are held encoded with the header's [`Codec`].
+#[derive(Debug, Clone)]
+pub struct SqliteStorage {
+    db: Arc<Mutex<Connection>>,
+    header: StorageHeader,
+    document: SedimentreeId,
+}
+
+impl SqliteStorage {
+    /// Open the database at `path` for sedimentree `document`, creating it if it
+    /// doesn't exist yet.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Header`] if the database holds a store this version
+    ///   can't read.
+    /// * [`SqliteStorageError::Sqlite`] if it can't be opened or set up.
+    pub fn open(
+        path: impl AsRef<Path>,
+        document: SedimentreeId,
+    ) -> Result<Self, SqliteStorageError> {
+        Self::open_with_codec(path, document, Codec::default())
+    }
+
+    /// Open the database at `path` for sedimentree `document`, creating it with blobs
+    /// encoded with `codec` if it doesn't exist yet. An existing database keeps the
+    /// codec it was created with.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Header`] if the database holds a store this version
+    ///   can't read.
+    /// * [`SqliteStorageError::Sqlite`] if it can't be opened or set up.
+    pub fn open_with_codec(
+        path: impl AsRef<Path>,
+        document: SedimentreeId,
+        codec: Codec,
+    ) -> Result<Self, SqliteStorageError> {
+        let mut conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.pragma_update(None, "synchronous", Durability::default().pragma())?;
+        conn.pragma_update(None, "foreign_keys", "ON")?;
+
+        let tx = conn.transaction()?;
+        tx.execute_batch(SCHEMA)?;
+        let stored = tx
+            .query_row("SELECT bytes FROM header WHERE id = 0", [], |row| {
+                row.get::<_, Vec<u8>>(0)
+            })
+            .optional()?;
+        let header = if let Some(bytes) = stored {
+            StorageHeader::from_bytes(&bytes)?.0
+        } else {
+            let header = StorageHeader::default().with_codec(codec);
+            tx.execute(
+                "INSERT INTO header (id, bytes) VALUES (0, ?1)",
+                params![header.to_bytes()],
+            )?;
+            header
+        };
+        tx.commit()?;
+
+        Ok(Self {
+            db: Arc::new(Mutex::new(conn)),
+            header,
+            document,
+        })
+    }
+
+    /// Set how far transactions are synced to disk before they commit, for every
+    /// handle on the database.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Sqlite`] if it can't be set.
+    pub fn set_durability(&self, durability: Durability) -> Result<(), SqliteStorageError> {
+        self.lock()?
+            .pragma_update(None, "synchronous", durability.pragma())?;
+        Ok(())
+    }
+
+    /// The sedimentree this handle stores.
+    #[must_use]
+    pub const fn id(&self) -> SedimentreeId {
+        self.document
+    }
+
+    /// A handle for sedimentree `document` in the same database.
+    #[must_use]
+    pub fn document(&self, document: SedimentreeId) -> Self {
+        Self {
+            db: self.db.clone(),
+            header: self.header.clone(),
+            document,
+        }
+    }
+
+    /// Every sedimentree the database holds commits or chunks of.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Sqlite`] if the query fails.
+    pub fn documents(&self) -> Result<Vec<SedimentreeId>, SqliteStorageError> {
+        let db = self.lock()?;
+        let mut query = db.prepare_cached("SELECT id FROM documents ORDER BY id")?;
+        let ids = query
+            .query_map([], |row| row.get::<_, [u8; 32]>(0))?
+            .map(|id| id.map(SedimentreeId::new))
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    /// Whether the sedimentree has a loose commit with `digest`.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Sqlite`] if the query fails.
+    pub fn has_commit(&self, digest: Digest) -> Result<bool, SqliteStorageError> {
+        let db = self.lock()?;
+        let found = db
+            .prepare_cached("SELECT 1 FROM commits WHERE document_id = ?1 AND digest = ?2")?
+            .exists(params![self.document.as_bytes(), digest.as_bytes()])?;
+        Ok(found)
+    }
+
+    /// Save `commits` and their blobs in a single transaction, so that either all of
+    /// them are stored or none are.
+    ///
+    /// # Errors
+    ///
+    /// * [`SqliteStorageError::Sqlite`] if the transaction fails.
+    pub fn save_commits(
+        &self,
+        commits: impl IntoIterator<Item = (LooseCommit, Blob)>,
+    ) -> Result<(), SqliteStorageError> {
+        self.write(|tx, this| {
+            for (commit, blob) in commits {
+                this.put_blob(tx, &blob)?;
+                this.put_commit(tx, &commit)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Connection>, SqliteStorageError> {
+        self.db.lock().map_err(|_| SqliteStorageError::Poisoned)
+    }
+
+    /// Run `f` in a transaction, committing it if `f` succeeds.
+    fn write<T>(
+        &self,
+        f: impl FnOnce(&Transaction<'_>, &Self) -> Result<T, SqliteStorageError>,
+    ) -> Result<T, SqliteStorageError> {
+        let mut db = self.lock()?;
+        let tx = db.transaction()?;
+        let value = f(&tx, self)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    fn put_document(&self, tx: &Transaction<'_>) -> Result<(), SqliteStorageError> {
+        tx.prepare_cached("INSERT OR IGNORE INTO documents (id) VALUES (?1)")?
+            .execute(params![self.document.as_bytes()])?;
+        Ok(())
+    }
+
+    fn put_blob(&self, tx: &Transaction<'_>, blob: &Blob) -> Result<Digest, SqliteStorageError> {
+        let digest = Digest::hash(blob.contents());
+        let stored = codec::encode_blob(self.header.codec(), blob);
+        tx.prepare_cached("INSERT OR IGNORE INTO blobs (digest, stored) VALUES (?1, ?2)")?
+            .execute(params![digest.as_bytes(), stored])?;
+        Ok(digest)
+    }
+
+    fn put_commit(
+        &self,
+        tx: &Transaction<'_>,
+        commit: &LooseCommit,
+    ) -> Result<(), SqliteStorageError> {
+        self.put_document(tx)?;
+        tx.prepare_cached(
+            "INSERT OR IGNORE INTO commits (document_id, blob_digest, digest, blob_size, parents)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+        )?
+        .execute(params![
+            self.document.as_bytes(),
+            commit.blob().digest().as_bytes(),
+            commit.digest().as_bytes(),
+            commit.blob().size_bytes(),
+            join(commit.parents()),
+        ])?;
+        Ok(())
+    }
+
+    fn put_chunk(&self, tx: &Transaction<'_>, chunk: &Chunk) -> Result<(), SqliteStorageError> {
+        self.put_document(tx)?;
+        let blob_meta = chunk.summary().blob_meta();
+        tx.prepare_cached(
+            "INSERT OR IGNORE INTO chunks
+                (document_id, blob_digest, head, blob_size, boundary, checkpoints)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?
+        .execute(params![
+            self.document.as_bytes(),
+            blob_meta.digest().as_bytes(),
+            chunk.head().as_bytes(),
+            blob_meta.size_bytes(),
+            join(&chunk.boundary().iter().copied().collect::<Vec<_>>()),
+            join(chunk.checkpoints()),
+        ])?;
+        Ok(())
+    }
+
+    /// Delete the sedimentree's commits and chunks stored by `digests`, and the blobs
+    /// no sedimentree's commit or chunk is stored by any more.
+    fn remove(&self, tx: &Transaction<'_>, digests: &[Digest]) -> Result<(), SqliteStorageError> {
+        for digest in digests {
+            for table in ["commits", "chunks"] {
+                tx.prepare_cached(&format!(
+                    "DELETE FROM {table} WHERE document_id = ?1 AND blob_digest = ?2"
+                ))?
+                .execute(params![self.document.as_bytes(), digest.as_bytes()])?;
+            }
+            tx.prepare_cached(
+                "DELETE FROM blobs WHERE digest = ?1
+                 AND NOT EXISTS (SELECT 1 FROM commits WHERE blob_digest = ?1)
+                 AND NOT EXISTS (SELECT 1 FROM chunks WHERE blob_digest = ?1)",
+            )?
+            .execute(params![digest.as_bytes()])?;
+        }
+        Ok(())
+    }
+
+    fn load_commits(&self) -> Result<Vec<LooseCommit>, SqliteStorageError> {
+        let db = self.lock()?;
+        let mut query = db.prepare_cached(
+            "SELECT digest, blob_digest, blob_size, parents FROM commits WHERE document_id = ?1",
+        )?;
+        let rows = query.query_map(params![self.document.as_bytes()], |row| {
+            Ok((
+                row.get::<_, [u8; 32]>(0)?,
+                row.get::<_, [u8; 32]>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (digest, blob_digest, blob_size, parents) = row?;
+            Ok(LooseCommit::new(
+                Digest::from(digest),
+                split(&parents)?,
+                BlobMeta::from_digest_size(Digest::from(blob_digest), blob_size),
+            ))
+        })
+        .collect()
+    }
+
+    fn load_all_chunks(&self) -> Result<Vec<Chunk>, SqliteStorageError> {
+        let db = self.lock()?;
+        let mut query = db.prepare_cached(
+            "SELECT head, blob_digest, blob_size, boundary, checkpoints
+             FROM chunks WHERE document_id = ?1",
+        )?;
+        let rows = query.query_map(params![self.document.as_bytes()], |row| {
+            Ok((
+                row.get::<_, [u8; 32]>(0)?,
+                row.get::<_, [u8; 32]>(1)?,
+                row.get::<_, u64>(2)?,
+                row.get::<_, Vec<u8>>(3)?,
+                row.get::<_, Vec<u8>>(4)?,
+            ))
+        })?;
+        rows.map(|row| {
+            let (head, blob_digest, blob_size, boundary, checkpoints) = row?;
+            let boundary =
+                NonEmpty::from_vec(split(&boundary)?).ok_or(SqliteStorageError::Corrupt)?;
+            Ok(Chunk::new(
+                Digest::from(head),
+                boundary,
+                split(&checkpoints)?,
+                BlobMeta::from_digest_size(Digest::from(blob_digest), blob_size),
+            ))
+        })
+        .collect()
+    }
+
+    fn get_blob(&self, digest: Digest) -> Result<Option<Blob>, SqliteStorageError> {
+        let db = self.lock()?;
+        let stored = db
+            .prepare_cached("SELECT stored FROM blobs WHERE digest = ?1")?
+            .query_row(params![digest.as_bytes()], |row| row.get::<_, Vec<u8>>(0))
+            .optional()?;
+        Ok(stored
+            .map(|stored| codec::decode_blob(&stored))
+            .transpose()?)
+    }
+}
+
+/// `digests`, one after another.
+fn join(digests: &[Digest]) -> Vec<u8> {
+    digests
+        .iter()
+        .flat_map(|digest| *digest.as_bytes())
+        .collect()
+}
+
+/// The digests [`join`] put one after another.
+fn split(bytes: &[u8]) -> Result<Vec<Digest>, SqliteStorageError> {
+    let digests = bytes.chunks_exact(32);
+    if !digests.remainder().is_empty() {
+        return Err(SqliteStorageError::Corrupt);
+    }
+    digests
+        .map(|digest| {
+            <[u8; 32]>::try_from(digest)
+                .map(Digest::from)
+                .map_err(|_| SqliteStorageError::Corrupt)
+        })
+        .collect()
+}
+
+/// A problem with a `SQLite` store.
+#[derive(Debug, Error)]
+pub enum SqliteStorageError {
+    /// The database could not be read or written.
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    /// The database holds a store this version can't read.
+    #[error(transparent)]
+    Header(#[from] HeaderError),
+
+    /// A stored blob couldn't be decoded.
+    #[error(transparent)]
+    Codec(#[from] CodecError),
+
+    /// A row isn't one this version wrote.
+    #[error("corrupt row in SQLite store")]
+    Corrupt,
+
+    /// A thread panicked while holding the connection.
+    #[error("SQLite connection lock poisoned")]
+    Poisoned,
+}
+
+impl Storage<Sendable> for SqliteStorage {
+    type Error = SqliteStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> BoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move { self.load_commits() }.boxed()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.put_commit(tx, &loose_commit)) }.boxed()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.put_chunk(tx, &chunk)) }.boxed()
+    }
+
+    fn load_chunks(&self) -> BoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move { self.load_all_chunks() }.boxed()
+    }
+
+    fn save_blob(&self, blob: Blob) -> BoxFuture<'_, Result<Digest, Self::Error>> {
+        async move { self.write(|tx, this| this.put_blob(tx, &blob)) }.boxed()
+    }
+
+    fn load_blob(&self, blob_digest: Digest) -> BoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { self.get_blob(blob_digest) }.boxed()
+    }
+
+    fn delete_blobs(&self, blob_digests: Vec<Digest>) -> BoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.remove(tx, &blob_digests)) }.boxed()
+    }
+}
+
+impl Storage<Local> for SqliteStorage {
+    type Error = SqliteStorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        async move { self.load_commits() }.boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.put_commit(tx, &loose_commit)) }.boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.put_chunk(tx, &chunk)) }.boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        async move { self.load_all_chunks() }.boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        async move { self.write(|tx, this| this.put_blob(tx, &blob)) }.boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move { self.get_blob(blob_digest) }.boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move { self.write(|tx, this| this.remove(tx, &blob_digests)) }.boxed_local()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nonempty::nonempty;
+
+    use super::*;
+
+    #[test]
+    fn keeps_sedimentrees_apart() -> Result<(), SqliteStorageError> {
+        let path = std::env::temp_dir().join(format!("subduction-sqlite-{}", std::process::id()));
+        let doc = SedimentreeId::new([1; 32]);
+        let other = SedimentreeId::new([2; 32]);
+
+        let blob = Blob::new(b"hello".to_vec());
+        let parent = LooseCommit::new(Digest::hash(b"parent"), vec![], blob.meta());
+        let child = LooseCommit::new(
+            Digest::hash(b"child"),
+            vec![parent.digest()],
+            BlobMeta::new(b"world"),
+        );
+        let chunk = Chunk::new(
+            parent.digest(),
+            nonempty![child.digest()],
+            vec![],
+            BlobMeta::new(b"bundle"),
+        );
+
+        let store = SqliteStorage::open_with_codec(&path, doc, Codec::Deflate)?;
+        store.save_commits([
+            (parent.clone(), blob.clone()),
+            (child.clone(), Blob::new(b"world".to_vec())),
+        ])?;
+        futures::executor::block_on(Storage::<Local>::save_chunk(&store, chunk.clone()))?;
+        futures::executor::block_on(Storage::<Local>::save_loose_commit(
+            &store.document(other),
+            parent.clone(),
+        ))?;
+        drop(store);
+
+        let reopened = SqliteStorage::open(&path, doc)?;
+        assert_eq!(Storage::<Local>::header(&reopened).codec(), Codec::Deflate);
+        assert_eq!(reopened.documents()?, [doc, other]);
+        assert!(reopened.has_commit(child.digest())?);
+        assert!(!reopened.document(other).has_commit(child.digest())?);
+        futures::executor::block_on(async {
+            let mut commits = Storage::<Local>::load_loose_commits(&reopened).await?;
+            commits.sort();
+            let mut expected = vec![parent, child];
+            expected.sort();
+            assert_eq!(commits, expected);
+            assert_eq!(Storage::<Local>::load_chunks(&reopened).await?, [chunk]);
+            assert_eq!(
+                Storage::<Local>::load_blob(&reopened, blob.meta().digest()).await?,
+                Some(blob)
+            );
+            Ok::<_, SqliteStorageError>(())
+        })?;
+
+        drop(reopened);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn keeps_blobs_another_sedimentree_still_stores_by() -> Result<(), SqliteStorageError> {
+        let path =
+            std::env::temp_dir().join(format!("subduction-sqlite-delete-{}", std::process::id()));
+        let (doc, other) = (SedimentreeId::new([1; 32]), SedimentreeId::new([2; 32]));
+        let blob = Blob::new(b"shared".to_vec());
+        let commit = LooseCommit::new(Digest::hash(b"commit"), vec![], blob.meta());
+        let digest = blob.meta().digest();
+
+        let store = SqliteStorage::open(&path, doc)?;
+        store.save_commits([(commit.clone(), blob.clone())])?;
+        store
+            .document(other)
+            .save_commits([(commit, blob.clone())])?;
+        futures::executor::block_on(async {
+            Storage::<Local>::delete_blobs(&store, vec![digest]).await?;
+            assert!(Storage::<Local>::load_loose_commits(&store)
+                .await?
+                .is_empty());
+            assert_eq!(
+                Storage::<Local>::load_blob(&store, digest).await?,
+                Some(blob)
+            );
+
+            Storage::<Local>::delete_blobs(&store.document(other), vec![digest]).await?;
+            assert_eq!(Storage::<Local>::load_blob(&store, digest).await?, None);
+            Ok::<_, SqliteStorageError>(())
+        })?;
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn syncs_every_transaction_unless_told_otherwise() -> Result<(), SqliteStorageError> {
+        let path = std::env::temp_dir().join(format!(
+            "subduction-sqlite-durability-{}",
+            std::process::id()
+        ));
+        let synchronous = |store: &SqliteStorage| -> Result<i64, SqliteStorageError> {
+            Ok(store
+                .lock()?
+                .pragma_query_value(None, "synchronous", |row| row.get(0))?)
+        };
+
+        let store = SqliteStorage::open(&path, SedimentreeId::new([1; 32]))?;
+        assert_eq!(synchronous(&store)?, 2);
+        store.set_durability(Durability::Normal)?;
+        assert_eq!(
+            synchronous(&store.document(SedimentreeId::new([2; 32])))?,
+            1
+        );
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            std::fs::remove_file(format!("{}{suffix}", path.display())).ok();
+        }
+        Ok(())
+    }
+}
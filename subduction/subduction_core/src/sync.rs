@@ -1,35 +1,127 @@
 //! The main synchronization logic and bookkeeping for [`Sedimentree`].
 
+pub mod access;
+pub mod backplane;
+pub mod bootstrap;
+pub mod dedup;
+pub mod download;
 pub mod error;
+pub mod filter;
+pub mod membership;
+pub mod peer_sync;
+pub mod report;
 pub mod request;
-
-use self::request::ChunkRequested;
+pub mod scan;
+pub mod session;
+pub mod signal;
+pub mod subscription;
+pub mod summary_cache;
+pub mod upload;
+
+mod in_flight;
+
+use self::{
+    access::Forwarded,
+    backplane::{Backplane, BackplaneItem, BackplaneMessage},
+    bootstrap::{Backfills, BootstrapPhase, BootstrapProgress, ProgressSink},
+    dedup::{Dedup, DedupStats},
+    download::{range_proof, BlobDownload, BlobFetch, BlobRange, DEFAULT_CHUNK_SIZE},
+    filter::FILTER_MIN_COMMITS,
+    in_flight::{InFlight, Joined},
+    membership::Membership,
+    peer_sync::{PeerSync, PeerSyncStatus},
+    report::{Reconciliation, ReportSink, SyncReport, SyncRole},
+    request::ChunkRequested,
+    scan::{
+        ContentAccess, ContentScanner, Held, ItemKind, Quarantine, QuarantineEntry, ScannedItem,
+        Verdict,
+    },
+    session::SessionStore,
+    signal::{Participants, SignalSink},
+    subscription::{DocumentFilter, SubscriptionDenied, SubscriptionPolicy, Subscriptions},
+    summary_cache::SummaryCache,
+    upload::BlobUpload,
+};
 use crate::{
+    audit::AuditSink,
+    compaction::{Compaction, CompactionPolicy, CompactionSink},
     connection::{
         id::ConnectionId,
-        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId, SyncDiff},
+        message::{
+            BatchSyncRequest, BatchSyncResponse, Have, Message, RequestId, Signal, SyncDiff,
+        },
         Connection, ConnectionDisallowed, ConnectionPolicy,
     },
+    lifecycle::{
+        DocumentActivity, LifecycleAction, LifecycleReport, LifecycleRules, LifecycleState,
+    },
     peer::id::PeerId,
 };
-use error::{BlobRequestErr, IoError, ListenError};
-use futures::{lock::Mutex, stream::FuturesUnordered, StreamExt};
+use error::{BlobRequestErr, IoError, LifecycleError, ListenError};
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    lock::Mutex,
+    stream::FuturesUnordered,
+    StreamExt,
+};
 use sedimentree_core::{
-    future::FutureKind, storage::Storage, Blob, Chunk, Depth, Digest, LooseCommit, RemoteDiff,
-    Sedimentree, SedimentreeId, SedimentreeSummary,
+    future::FutureKind, storage::Storage, Blob, BlobMeta, Chunk, ChunkSpec, Depth, Digest,
+    LooseCommit, RemoteDiff, Sedimentree, SedimentreeId, SedimentreeSummary,
 };
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     fmt::Debug,
     sync::Arc,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
+use tracing::{Instrument, Span};
 
 /// The main synchronization manager for sedimentrees.
 #[derive(Debug, Clone)]
 pub struct Subduction<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> {
     sedimentrees: Arc<Mutex<HashMap<SedimentreeId, Sedimentree>>>,
     conn_manager: Arc<Mutex<ConnectionManager<C>>>,
+    /// Wakes the run loop when a connection is registered, so it starts listening to it.
+    registered: mpsc::UnboundedSender<()>,
+    woken: Arc<Mutex<mpsc::UnboundedReceiver<()>>>,
+    /// Blobs being fetched in ranges, by digest (see [`download`]).
+    downloads: Arc<Mutex<HashMap<Digest, BlobFetch>>>,
+    /// Commits pushed in chunks, received so far, by blob digest.
+    receiving: Arc<Mutex<HashMap<Digest, BlobDownload>>>,
+    /// Commits being pushed in chunks, by recipient and blob digest.
+    uploads: Arc<Mutex<HashMap<(PeerId, Digest), BlobUpload>>>,
+    /// Push commits with blobs larger than this in chunks of this size.
+    upload_chunk_size: Option<u64>,
+    in_flight: InFlight<(PeerId, SedimentreeId)>,
+    peer_sync: PeerSync,
+    /// Pushes of commits and chunks already held.
+    dedup: Arc<Dedup>,
+    lifecycle: Arc<Mutex<LifecycleState>>,
+    audit: Option<Arc<dyn AuditSink>>,
+    progress: Option<Arc<dyn ProgressSink>>,
+    /// Chunks peers said would follow their batch sync responses (see [`bootstrap`]).
+    backfills: Arc<Mutex<Backfills>>,
+    read_only: Arc<HashSet<SedimentreeId>>,
+    /// Sedimentrees frozen with [`Subduction::freeze`].
+    frozen: Arc<std::sync::Mutex<HashSet<SedimentreeId>>>,
+    participants: Arc<Mutex<Participants>>,
+    signals: Option<Arc<dyn SignalSink>>,
+    /// Access requests passed on to other peers.
+    forwarded: Arc<Mutex<Forwarded>>,
+    reports: Option<Arc<dyn ReportSink>>,
+    scanner: Option<Arc<dyn ContentScanner<F>>>,
+    content_access: Arc<ContentAccess>,
+    quarantine: Arc<Mutex<Quarantine>>,
+    summaries: Arc<SummaryCache>,
+    backplane: Option<Arc<dyn Backplane>>,
+    compaction: Option<Arc<Compaction>>,
+    subscription_policy: Option<Arc<dyn SubscriptionPolicy>>,
+    membership: Option<Arc<dyn Membership>>,
+    /// The filters each peer refused in its answer to our last subscription.
+    subscription_denials: Arc<Mutex<HashMap<PeerId, Vec<SubscriptionDenied>>>>,
+    /// The filters of our last subscription, sent over each new connection too.
+    subscription: Arc<Mutex<Option<Vec<DocumentFilter>>>>,
     storage: S,
     _phantom: std::marker::PhantomData<F>,
 }
@@ -53,40 +145,53 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
     async fn listen(&self) -> Result<(), ListenError<F, S, C>> {
         tracing::info!("Listening for messages from connections");
 
+        let mut woken = self.woken.lock().await;
         let mut pump = FuturesUnordered::new();
-        {
-            let mut locked = self.conn_manager.lock().await;
-            let mut unstarted = locked.unstarted.drain().collect::<Vec<_>>();
-            for conn_id in unstarted.drain(..) {
-                #[allow(clippy::expect_used)]
-                let conn = locked
-                    .connections
-                    .get(&conn_id)
-                    .expect("connections with IDs should be present")
-                    .clone();
-
-                tracing::info!("Spawning listener for connection {:?}", conn_id);
-                pump.push(self.fire_once(conn_id, conn));
-            }
-        }
-
-        while let Some((conn_id, conn, res)) = pump.next().await {
-            let () = res?;
-            let mut locked = self.conn_manager.lock().await;
-            if locked.connections.contains_key(&conn_id) {
-                // Re-enque if that connection is still active at the top-level
-                pump.push(self.fire_once(conn_id, conn));
+        let mut listening = HashSet::new();
+        loop {
+            {
+                let mut locked = self.conn_manager.lock().await;
+                let unstarted = locked.unstarted.drain().collect::<Vec<_>>();
+                for conn_id in unstarted {
+                    if let Some(conn) = locked.connections.get(&conn_id) {
+                        tracing::info!("Spawning listener for connection {:?}", conn_id);
+                        listening.insert(conn_id);
+                        pump.push(self.fire_once(conn_id, conn.clone()));
+                    }
+                }
             }
 
-            let unstarted_ids = locked.unstarted.drain().collect::<Vec<_>>();
-            for conn_id in unstarted_ids {
-                if let Some(unstarted) = locked.connections.get(&conn_id) {
-                    pump.push(self.fire_once(conn_id, unstarted.clone()));
+            let fired = match future::select(pump.next(), woken.next()).await {
+                Either::Left((fired, _)) => Some(fired),
+                Either::Right(_) => None,
+            };
+            match fired {
+                Some(Some((conn_id, conn, res))) => {
+                    let mut locked = self.conn_manager.lock().await;
+                    if let Err(e) = res {
+                        // Leave the other connections for the next call to pick up
+                        listening.remove(&conn_id);
+                        let still_active = listening
+                            .into_iter()
+                            .filter(|id| locked.connections.contains_key(id))
+                            .collect::<Vec<_>>();
+                        locked.unstarted.extend(still_active);
+                        return Err(e);
+                    }
+                    if locked.connections.contains_key(&conn_id) {
+                        // Re-enque if that connection is still active at the top-level
+                        pump.push(self.fire_once(conn_id, conn));
+                    } else {
+                        listening.remove(&conn_id);
+                    }
+                }
+                // Nothing to listen to until a connection is registered
+                Some(None) => {
+                    woken.next().await;
                 }
+                None => {}
             }
         }
-
-        Ok(())
     }
 
     async fn fire_once(
@@ -111,30 +216,47 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         let from = conn.peer_id();
 
         tracing::info!("Received message from peer {:?}: {:?}", from, message);
+        if let Some(id) = message
+            .sedimentree_id()
+            .filter(|&id| !self.admits(from, id))
+        {
+            tracing::warn!("Dropping message from peer {from:?}, which isn't a member of {id:?}");
+            return Ok(());
+        }
+        let audited = self.audit.as_ref().map(|sink| {
+            let signature = conn.sender_signature(&message);
+            (sink, message.clone(), signature)
+        });
+        self.note_participant(from, &message).await;
 
         match message {
             Message::LooseCommit { id, commit, blob } => {
-                self.recv_commit(&from, id, &commit, blob).await?;
+                let held = Held::Commit(commit, blob);
+                if !self.recv_pushed(from, id, held).await? {
+                    return Ok(());
+                }
             }
             Message::Chunk { id, chunk, blob } => {
-                self.recv_chunk(&from, id, &chunk, blob).await?;
+                let held = Held::Chunk(chunk, blob);
+                if !self.recv_pushed(from, id, held).await? {
+                    return Ok(());
+                }
             }
-            Message::BatchSyncRequest(BatchSyncRequest {
-                id,
-                sedimentree_summary,
-                req_id,
-            }) => {
-                if let Err(ListenError::MissingBlobs(missing)) = self
-                    .recv_batch_sync_request(id, &sedimentree_summary, req_id, conn)
-                    .await
+            Message::BatchSyncRequest(request) => {
+                if let Err(ListenError::MissingBlobs(missing)) =
+                    self.recv_batch_sync_request(&request, conn).await
                 {
                     self.request_blobs(missing).await;
                 }
             }
-            Message::BatchSyncResponse(BatchSyncResponse { id, diff, .. }) => {
-                self.recv_batch_sync_response(&from, id, &diff).await?;
+            Message::BatchSyncResponse(response) => {
+                self.recv_batch_sync_response(&from, response).await?;
+            }
+            Message::Backfill { id, chunk, blob } => {
+                self.recv_backfill(from, id, chunk, blob).await?;
             }
             Message::BlobsRequest(digests) => {
+                let digests = self.admitted_blobs(from, digests).await;
                 if self
                     .conn_manager
                     .lock()
@@ -143,12 +265,7 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                     .contains_key(&conn_id)
                 {
                     match self.recv_blob_request(conn, &digests).await {
-                        Ok(()) => {
-                            tracing::info!(
-                                "Successfully handled blob request from peer {:?}",
-                                from
-                            );
-                        }
+                        Ok(()) => tracing::info!("Handled blob request from peer {from:?}"),
                         Err(BlobRequestErr::IoError(e)) => Err(e)?,
                         Err(BlobRequestErr::MissingBlobs(missing)) => {
                             tracing::warn!(
@@ -162,14 +279,29 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                     tracing::warn!("No open connection for request ({:?})", conn_id);
                 }
             }
-            Message::BlobsResponse(blobs) => {
-                for blob in blobs {
-                    self.storage
-                        .save_blob(blob)
-                        .await
-                        .map_err(IoError::Storage)?;
+            Message::BlobsResponse(blobs) => self.recv_blobs(blobs).await?,
+            range @ (Message::BlobRangeRequest { .. }
+            | Message::BlobRangeResponse { .. }
+            | Message::BlobRangeNotFound { .. }) => self.recv_range(conn, range).await?,
+            Message::RelaySignal { id, to, signal } => self.relay_signal(from, id, to, signal).await?,
+            Message::Signal { id, from, signal } => self.recv_signal(id, from, signal),
+            upload @ (Message::CommitUpload { .. } | Message::CommitUploadAck { .. }) => {
+                if !self.recv_upload(conn, upload).await? {
+                    return Ok(());
                 }
             }
+            // Flow control is up to the transport, which sees credit before we do
+            Message::Credit { .. } => return Ok(()),
+            Message::DocumentTtl { id, ttl_secs } => self.recv_ttl(from, id, ttl_secs).await?,
+            Message::Subscribe { filters } => self.recv_subscribe(conn_id, conn, filters).await?,
+            Message::Subscribed { denied } => self.recv_subscribed(from, denied).await,
+            Message::AccessRequest { id, request } => {
+                self.relay_access_request(from, id, request).await?;
+            }
+        }
+
+        if let Some((sink, message, signature)) = audited {
+            sink.record(from, &message, signature.as_deref(), SystemTime::now());
         }
         Ok(())
     }
@@ -180,32 +312,235 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         storage: S,
         connections: HashMap<ConnectionId, C>,
     ) -> Self {
+        let (registered, woken) = mpsc::unbounded();
         Self {
             sedimentrees: Arc::new(Mutex::new(sedimentrees)),
             conn_manager: Arc::new(Mutex::new(ConnectionManager {
                 next_id: ConnectionId::default(),
                 connections,
                 unstarted: HashSet::new(),
+                subscriptions: Subscriptions::default(),
             })),
+            registered,
+            woken: Arc::new(Mutex::new(woken)),
+            downloads: Arc::new(Mutex::new(HashMap::new())),
+            receiving: Arc::new(Mutex::new(HashMap::new())),
+            uploads: Arc::new(Mutex::new(HashMap::new())),
+            upload_chunk_size: None,
+            in_flight: InFlight::default(),
+            peer_sync: PeerSync::default(),
+            dedup: Arc::new(Dedup::default()),
+            lifecycle: Arc::new(Mutex::new(LifecycleState::default())),
+            audit: None,
+            progress: None,
+            backfills: Arc::new(Mutex::new(Backfills::default())),
+            read_only: Arc::new(HashSet::new()),
+            frozen: Arc::new(std::sync::Mutex::new(HashSet::new())),
+            participants: Arc::new(Mutex::new(Participants::default())),
+            signals: None,
+            forwarded: Arc::new(Mutex::new(Forwarded::default())),
+            reports: None,
+            scanner: None,
+            content_access: Arc::new(ContentAccess::None),
+            quarantine: Arc::new(Mutex::new(Quarantine::default())),
+            summaries: Arc::new(SummaryCache::new()),
+            backplane: None,
+            compaction: None,
+            subscription_policy: None,
+            membership: None,
+            subscription_denials: Arc::new(Mutex::new(HashMap::new())),
+            subscription: Arc::new(Mutex::new(None)),
             storage,
             _phantom: std::marker::PhantomData,
         }
     }
 
+    /// Record every protocol message accepted from a peer to `sink`.
+    #[must_use]
+    pub fn with_audit(mut self, sink: Arc<dyn AuditSink>) -> Self {
+        self.audit = Some(sink);
+        self
+    }
+
+    /// Refuse commits and chunks from peers for the sedimentrees in `ids`.
+    ///
+    /// Peers can still sync these sedimentrees down, but only local calls (such as
+    /// mirroring them from elsewhere with [`Subduction::recv_commit`]) can change them.
+    #[must_use]
+    pub fn with_read_only(mut self, ids: impl IntoIterator<Item = SedimentreeId>) -> Self {
+        self.read_only = Arc::new(ids.into_iter().collect());
+        self
+    }
+
+    /// Whether peers are refused writes to the sedimentree `id`.
+    #[must_use]
+    pub fn is_read_only(&self, id: SedimentreeId) -> bool {
+        self.read_only.contains(&id) || self.is_frozen(id)
+    }
+
+    /// Stop the sedimentree `id` from taking anything more from peers, whether pushed
+    /// to us or in response to our own sync requests.
+    ///
+    /// Unlike [`Subduction::with_read_only`], which is for mirrors, this can be done
+    /// at any time, and also covers what we sync down. Peers can still sync the
+    /// sedimentree from us.
+    pub fn freeze(&self, id: SedimentreeId) {
+        self.frozen
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .insert(id);
+    }
+
+    /// Whether the sedimentree `id` has been frozen.
+    #[must_use]
+    pub fn is_frozen(&self, id: SedimentreeId) -> bool {
+        self.frozen
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner)
+            .contains(&id)
+    }
+
+    /// Report the progress of applying synced history to `sink`.
+    ///
+    /// See [`bootstrap`] for the order history is applied in.
+    #[must_use]
+    pub fn with_progress(mut self, sink: Arc<dyn ProgressSink>) -> Self {
+        self.progress = Some(sink);
+        self
+    }
+
+    /// Deliver WebRTC signals passed on by a relay to `sink`.
+    ///
+    /// See [`signal`] for how signals are relayed.
+    #[must_use]
+    pub fn with_signals(mut self, sink: Arc<dyn SignalSink>) -> Self {
+        self.signals = Some(sink);
+        self
+    }
+
+    /// Report what each batch sync round with a peer took to `sink`.
+    ///
+    /// See [`report`] for what a report covers.
+    #[must_use]
+    pub fn with_reports(mut self, sink: Arc<dyn ReportSink>) -> Self {
+        self.reports = Some(sink);
+        self
+    }
+
+    /// Scan every commit and chunk received from a peer with `scanner` before storing
+    /// or forwarding it, showing it the contents of the documents `access` permits.
+    ///
+    /// See [`scan`] for what happens to flagged and rejected items.
+    #[must_use]
+    pub fn with_scanner(
+        mut self,
+        scanner: Arc<dyn ContentScanner<F>>,
+        access: ContentAccess,
+    ) -> Self {
+        self.scanner = Some(scanner);
+        self.content_access = Arc::new(access);
+        self
+    }
+
+    /// Keep sedimentree summaries in `cache`, which other instances holding different
+    /// sedimentrees may share.
+    ///
+    /// See [`summary_cache`] for how the cache is kept up to date.
+    #[must_use]
+    pub fn with_summary_cache(mut self, cache: Arc<SummaryCache>) -> Self {
+        self.summaries = cache;
+        self
+    }
+
+    /// Publish the commits and chunks accepted from peers to `backplane`, for other
+    /// relay instances to forward to their own peers.
+    ///
+    /// See [`backplane`] for how instances share writes.
+    #[must_use]
+    pub fn with_backplane(mut self, backplane: Arc<dyn Backplane>) -> Self {
+        self.backplane = Some(backplane);
+        self
+    }
+
+    /// Hand sedimentrees to `sink` for compaction once their loose history crosses one
+    /// of `policy`'s thresholds.
+    ///
+    /// See [`compaction`](crate::compaction) for when a sedimentree is scheduled.
+    #[must_use]
+    pub fn with_compaction(
+        mut self,
+        policy: CompactionPolicy,
+        sink: Arc<dyn CompactionSink>,
+    ) -> Self {
+        self.compaction = Some(Arc::new(Compaction::new(policy, sink)));
+        self
+    }
+
+    /// Keep the frontier each batch sync with a peer leaves off at in `store`, so that
+    /// the next one can resume from it after a restart.
+    ///
+    /// See [`session`] for how a batch sync resumes.
+    #[must_use]
+    pub fn with_sessions(mut self, store: Arc<dyn SessionStore>) -> Self {
+        self.peer_sync.set_store(store);
+        self
+    }
+
+    /// Check each filter a peer subscribes with against `policy`, refusing those it
+    /// doesn't allow.
+    ///
+    /// See [`subscription`] for how subscriptions limit what's pushed.
+    #[must_use]
+    pub fn with_subscription_policy(mut self, policy: Arc<dyn SubscriptionPolicy>) -> Self {
+        self.subscription_policy = Some(policy);
+        self
+    }
+
+    /// Only sync each document with the peers `membership` says are its members.
+    ///
+    /// See [`membership`] for what's refused to everyone else.
+    #[must_use]
+    pub fn with_membership(mut self, membership: Arc<dyn Membership>) -> Self {
+        self.membership = Some(membership);
+        self
+    }
+
+    /// Push commits whose blobs are larger than `chunk_size` in pieces of that size, so
+    /// that a push interrupted by a dropped connection resumes where it left off.
+    ///
+    /// See [`upload`] for how uploads are acknowledged and resumed. Peers receiving
+    /// them must understand [`Message::CommitUpload`].
+    #[must_use]
+    pub fn with_chunked_uploads(mut self, chunk_size: u64) -> Self {
+        self.upload_chunk_size = Some(chunk_size.max(1));
+        self
+    }
+
+    /// The cache of sedimentree summaries, for its [stats](SummaryCache::stats).
+    pub fn summary_cache(&self) -> &SummaryCache {
+        &self.summaries
+    }
+
     /// The storage backend used for persisting sedimentree data.
+    pub const fn storage(&self) -> &S {
+        &self.storage
+    }
+
+    /// Load all commits and chunks from storage into the known sedimentrees.
     ///
     /// # Errors
     ///
     /// * Returns `S::Error` if the storage backend encounters an error.
     pub async fn hydrate(&self) -> Result<(), S::Error> {
-        for tree_id in self
+        // Collected first, as the lock would otherwise be held through the loop
+        let tree_ids = self
             .sedimentrees
             .lock()
             .await
             .keys()
             .copied()
-            .collect::<Vec<_>>()
-        {
+            .collect::<Vec<_>>();
+        for tree_id in tree_ids {
             if let Some(sedimentree) = self.sedimentrees.lock().await.get_mut(&tree_id) {
                 for commit in self.storage.load_loose_commits().await? {
                     tracing::trace!("Loaded commit {:?}", commit.digest());
@@ -216,6 +551,7 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                     tracing::trace!("Loaded chunk {:?}", chunk.digest());
                     sedimentree.add_chunk(chunk);
                 }
+                self.summaries.invalidate(tree_id);
             }
         }
 
@@ -259,12 +595,15 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
     /// * Returns `C::DisconnectionError` if disconnect fails or it occurs ungracefully.
     pub async fn disconnect(&self, conn_id: &ConnectionId) -> Result<bool, C::DisconnectionError> {
         let mut locked = self.conn_manager.lock().await;
-        locked.unstarted.remove(conn_id);
-        if let Some(mut conn) = locked.connections.remove(conn_id) {
-            conn.disconnect().await.map(|()| true)
-        } else {
-            Ok(false)
+        let Some(mut conn) = locked.remove(*conn_id) else {
+            return Ok(false);
+        };
+        let gone = self.forget_if_gone(&locked, conn.peer_id()).await;
+        drop(locked);
+        if gone {
+            self.abandon_ranges(conn.peer_id()).await;
         }
+        conn.disconnect().await.map(|()| true)
     }
 
     /// Gracefully disconnect from all connections to a given peer ID.
@@ -292,12 +631,16 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         for (id, conn_peer_id) in &conn_meta {
             if *conn_peer_id == *peer_id {
                 touched = true;
-                locked.unstarted.remove(id);
-                if let Some(mut conn) = locked.connections.remove(id) {
+                if let Some(mut conn) = locked.remove(*id) {
                     conn.disconnect().await?;
                 }
             }
         }
+        if touched {
+            self.forget_if_gone(&locked, *peer_id).await;
+            drop(locked);
+            self.abandon_ranges(*peer_id).await;
+        }
 
         Ok(touched)
     }
@@ -327,7 +670,12 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         } else {
             let conn_id = locked.next_connection_id();
             locked.unstarted.insert(conn_id);
-            locked.connections.insert(conn_id, conn);
+            locked.connections.insert(conn_id, conn.clone());
+            drop(locked);
+            // Only fails once the instance, and with it the run loop, is gone
+            self.registered.unbounded_send(()).ok();
+            self.resume_uploads(&conn).await;
+            self.resend_subscription(&conn).await;
             Ok((true, conn_id))
         }
     }
@@ -335,8 +683,28 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
     /// Low-level unregistration of a connection.
     pub async fn unregister(&mut self, conn_id: &ConnectionId) -> bool {
         let mut locked = self.conn_manager.lock().await;
-        locked.unstarted.remove(conn_id);
-        locked.connections.remove(conn_id).is_some()
+        let Some(conn) = locked.remove(*conn_id) else {
+            return false;
+        };
+        if self.forget_if_gone(&locked, conn.peer_id()).await {
+            drop(locked);
+            self.abandon_ranges(conn.peer_id()).await;
+        }
+        true
+    }
+
+    /// Forget which sedimentrees `peer` syncs, for relaying signals, once the last of
+    /// its connections has been removed, returning whether it has.
+    async fn forget_if_gone(&self, locked: &ConnectionManager<C>, peer: PeerId) -> bool {
+        let gone = locked
+            .connections
+            .values()
+            .all(|conn| conn.peer_id() != peer);
+        if gone {
+            self.participants.lock().await.leave(&peer);
+            self.backfills.lock().await.forget(peer);
+        }
+        gone
     }
 
     /*********
@@ -421,29 +789,35 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
             Ok(Some(blobs))
         } else {
             if let Some(tree) = self.sedimentrees.lock().await.get(&id) {
-                let summary = tree.summarize();
+                let summary = self.summaries.summarize(id, tree);
                 let locked = self.conn_manager.lock().await;
                 for conn in locked.connections.values() {
                     let req_id = conn.next_request_id().await;
-                    let BatchSyncResponse {
-                        id,
-                        diff,
-                        req_id: resp_batch_id,
-                    } = conn
+                    self.backfills.lock().await.requested(conn.peer_id(), id);
+                    let response = conn
                         .call(
                             BatchSyncRequest {
                                 id,
                                 req_id,
-                                sedimentree_summary: summary.clone(),
+                                sedimentree_summary: (*summary).clone(),
+                                summary_token: None,
+                                have: None,
+                                since: Vec::new(),
                             },
                             timeout,
                         )
-                        .await
-                        .map_err(IoError::ConnCall)?;
+                        .await;
+                    let response = match response {
+                        Ok(response) => response,
+                        Err(e) => {
+                            self.backfills.lock().await.cancelled(conn.peer_id(), id);
+                            return Err(IoError::ConnCall(e));
+                        }
+                    };
 
-                    debug_assert_eq!(req_id, resp_batch_id);
+                    debug_assert_eq!(req_id, response.req_id);
 
-                    self.recv_batch_sync_response(&conn.peer_id(), id, &diff)
+                    self.recv_batch_sync_response(&conn.peer_id(), response)
                         .await?;
                 }
             }
@@ -490,176 +864,1297 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         }
     }
 
-    /***********************
-     * INCREMENTAL CHANGES *
-     ***********************/
-
-    /// Add a new (incremental) commit locally and propagate it to all connected peers.
+    /// Fetch a single blob by splitting it into ranges and requesting them from
+    /// all connected peers in parallel.
+    ///
+    /// Ranges are assigned to peers round-robin. Each range is checked against the
+    /// blob's digest with the chaining values sent alongside it, and the assembled
+    /// blob against `meta` before it is saved to storage. The ranges of a peer that
+    /// doesn't hold the blob, sends data that doesn't check out, or goes away, are
+    /// asked of the others, and the fetch is dropped once
+    /// no peer is left to ask (see [`download`]).
     ///
     /// # Returns
     ///
-    /// * `Ok(None)` if the commit is not on a chunk boundary.
-    /// * `Ok(Some(ChunkRequested))` if the commit is on a [`Chunk`] boundary.
-    ///   In this case, please call `add_chunk` after creating the requested chunk.
+    /// * `Ok(true)` if range requests were sent.
+    /// * `Ok(false)` if the blob is already local, already downloading, empty (and
+    ///   saved at once), or no peers are connected.
     ///
     /// # Errors
     ///
-    /// * [`IoError`] if a storage or network error occurs.
-    pub async fn add_commit(
-        &mut self,
-        id: SedimentreeId,
-        commit: &LooseCommit,
-        blob: Blob,
-    ) -> Result<Option<ChunkRequested>, IoError<F, S, C>> {
-        self.insert_commit_locally(id, commit.clone(), blob.clone()) // TODO lots of cloning
+    /// * [`IoError`] if a storage or network error occurs, in which case the fetch
+    ///   is dropped.
+    pub async fn fetch_blob_from_peers(
+        &self,
+        meta: BlobMeta,
+        chunk_size: u64,
+    ) -> Result<bool, IoError<F, S, C>> {
+        let digest = meta.digest();
+        if self
+            .get_local_blob(digest)
             .await
-            .map_err(IoError::Storage)?;
-
+            .map_err(IoError::Storage)?
+            .is_some()
         {
-            let locked = self.conn_manager.lock().await;
-            let conns = locked.connections.values().collect::<Vec<_>>();
-            for conn in conns {
-                conn.send(Message::LooseCommit {
-                    id,
-                    commit: commit.clone(),
-                    blob: blob.clone(),
-                })
-                .await
-                .map_err(IoError::ConnSend)?;
-            }
+            return Ok(false);
         }
 
-        let mut maybe_requested_chunk = None;
+        // There's nothing to ask for, so no range would ever complete it
+        if meta.size_bytes() == 0 {
+            if let Ok(blob) = BlobDownload::new(meta).assemble() {
+                self.storage
+                    .save_blob(blob)
+                    .await
+                    .map_err(IoError::Storage)?;
+            }
+            return Ok(false);
+        }
 
-        let depth = Depth::from(commit.digest());
-        if depth != Depth(0) {
-            maybe_requested_chunk = Some(ChunkRequested {
-                head: commit.digest(),
-                depth,
-            });
+        let peers = self.range_peers().await;
+        if peers.is_empty() {
+            return Ok(false);
         }
 
-        Ok(maybe_requested_chunk)
+        let requests = {
+            let mut downloads = self.downloads.lock().await;
+            if downloads.contains_key(&digest) {
+                return Ok(false);
+            }
+            let ids = peers
+                .iter()
+                .map(|(peer_id, _)| *peer_id)
+                .collect::<Vec<_>>();
+            downloads
+                .entry(digest)
+                .or_insert_with(|| BlobFetch::new(meta, chunk_size))
+                .assign(&ids)
+        };
+
+        tracing::info!(
+            "Fetching blob {:?} in {} ranges from {} peers",
+            digest,
+            requests.len(),
+            peers.len()
+        );
+        self.send_range_requests(digest, &peers, requests)
+            .await
+            .map_err(IoError::ConnSend)?;
+        Ok(true)
     }
 
-    /// Add a new (incremental) chunk locally and propagate it to all connected peers.
-    ///
-    /// NOTE this performs no integrity checks;
-    /// we assume this is a good chunk at the right depth
-    ///
-    /// # Errors
-    ///
-    /// * [`IoError`] if a storage or network error occurs.
-    pub async fn add_chunk(
-        &self,
-        id: SedimentreeId,
-        chunk: &Chunk,
-        blob: Blob,
-    ) -> Result<(), IoError<F, S, C>> {
+    /// One connection to each connected peer, ordered by peer, to ask for ranges.
+    async fn range_peers(&self) -> Vec<(PeerId, C)> {
+        let mut peers: HashMap<PeerId, C> = HashMap::new();
         {
-            let mut sed = self.sedimentrees.lock().await;
-            let tree = sed.entry(id).or_default();
-            tree.add_chunk(chunk.clone());
+            let locked = self.conn_manager.lock().await;
+            for conn in locked.connections.values() {
+                peers.entry(conn.peer_id()).or_insert_with(|| conn.clone());
+            }
         }
+        let mut peers = peers.into_iter().collect::<Vec<_>>();
+        peers.sort_by_key(|(peer_id, _)| *peer_id);
+        peers
+    }
 
-        self.storage
-            .save_blob(blob.clone()) // TODO lots of cloning
-            .await
-            .map_err(IoError::Storage)?;
+    /// Ask each peer in `requests` for its range of the blob `digest`, over its
+    /// connection in `peers`, dropping the fetch if a request can't be sent.
+    async fn send_range_requests(
+        &self,
+        digest: Digest,
+        peers: &[(PeerId, C)],
+        requests: Vec<(PeerId, BlobRange)>,
+    ) -> Result<(), C::SendError> {
+        let mut sends = requests
+            .into_iter()
+            .filter_map(|(peer_id, BlobRange { offset, length })| {
+                let (_, conn) = peers.iter().find(|(id, _)| *id == peer_id)?;
+                Some(conn.send(Message::BlobRangeRequest {
+                    digest,
+                    offset,
+                    length,
+                }))
+            })
+            .collect::<FuturesUnordered<_>>();
 
+        while let Some(result) = sends.next().await {
+            if let Err(e) = result {
+                self.downloads.lock().await.remove(&digest);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Ask the peers still connected for the ranges no peer is being asked for any
+    /// more, and drop the fetches with no peer left to ask.
+    async fn reassign_ranges(&self) {
+        let peers = self.range_peers().await;
+        let ids = peers
+            .iter()
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        let mut requests: HashMap<Digest, Vec<(PeerId, BlobRange)>> = HashMap::new();
+        self.downloads.lock().await.retain(|digest, fetch| {
+            let assigned = fetch.assign(&ids);
+            if !assigned.is_empty() {
+                requests.insert(*digest, assigned);
+            }
+            if fetch.is_stalled() {
+                tracing::warn!("No peer left to fetch blob {:?} from", digest);
+            }
+            !fetch.is_stalled()
+        });
+
+        for (digest, requests) in requests {
+            if let Err(e) = self.send_range_requests(digest, &peers, requests).await {
+                tracing::warn!("Dropping the fetch of blob {:?}: {}", digest, e);
+            }
+        }
+    }
+
+    /// Ask others for the ranges `peer` was asked for, now that it's gone.
+    async fn abandon_ranges(&self, peer: PeerId) {
         {
-            let locked = self.conn_manager.lock().await;
-            let conns = locked.connections.values().collect::<Vec<_>>();
-            for conn in conns {
-                conn.send(Message::Chunk {
-                    id,
-                    chunk: chunk.clone(),
-                    blob: blob.clone(),
-                })
-                .await
-                .map_err(IoError::ConnSend)?;
+            let mut downloads = self.downloads.lock().await;
+            if downloads.is_empty() {
+                return;
+            }
+            for fetch in downloads.values_mut() {
+                fetch.abandon(peer);
             }
         }
+        self.reassign_ranges().await;
+    }
 
+    /// Store the blobs a peer sent in answer to our request.
+    async fn recv_blobs(&self, blobs: Vec<Blob>) -> Result<(), IoError<F, S, C>> {
+        for blob in blobs {
+            self.storage
+                .save_blob(blob)
+                .await
+                .map_err(IoError::Storage)?;
+        }
         Ok(())
     }
 
-    /****************************
-     * RECEIVE UPDATE FROM PEER *
-     ****************************/
+    /// Handle a message about a blob fetched in ranges: a peer's request for a range,
+    /// or its answer to ours.
+    async fn recv_range(&self, conn: &C, message: Message) -> Result<(), IoError<F, S, C>> {
+        match message {
+            Message::BlobRangeRequest {
+                digest,
+                offset,
+                length,
+            } => {
+                if self
+                    .admitted_blobs(conn.peer_id(), vec![digest])
+                    .await
+                    .is_empty()
+                {
+                    return conn
+                        .send(Message::BlobRangeNotFound { digest })
+                        .await
+                        .map_err(IoError::ConnSend);
+                }
+                self.recv_blob_range_request(conn, digest, BlobRange { offset, length })
+                    .await
+            }
+            Message::BlobRangeResponse {
+                digest,
+                offset,
+                data,
+                proof,
+            } => self
+                .recv_blob_range_response(&conn.peer_id(), digest, offset, data, &proof)
+                .await
+                .map(|_| ()),
+            Message::BlobRangeNotFound { digest } => {
+                self.recv_range_not_found(conn.peer_id(), digest).await;
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
 
-    /// Handle receiving a new commit from a peer.
-    ///
-    /// Also propagates it to all other connected peers.
+    /// Handle a request for a byte range of a blob from a peer, answering with the
+    /// range and its proof (see [`range_proof`]), or that we can't serve it.
     ///
     /// # Errors
     ///
     /// * [`IoError`] if a storage or network error occurs.
-    pub async fn recv_commit(
+    pub async fn recv_blob_range_request(
         &self,
-        from: &PeerId,
-        id: SedimentreeId,
-        commit: &LooseCommit,
-        blob: Blob,
-    ) -> Result<bool, IoError<F, S, C>> {
-        let was_new = self
-            .insert_commit_locally(id, commit.clone(), blob.clone())
+        conn: &C,
+        digest: Digest,
+        range: BlobRange,
+    ) -> Result<(), IoError<F, S, C>> {
+        let Some(blob) = self
+            .get_local_blob(digest)
             .await
-            .map_err(IoError::Storage)?;
-
-        if was_new {
-            let locked = self.conn_manager.lock().await;
-            for conn in locked.connections.values() {
-                if conn.peer_id() != *from {
-                    conn.send(Message::LooseCommit {
-                        id,
-                        commit: commit.clone(),
-                        blob: blob.clone(),
-                    })
-                    .await
-                    .map_err(IoError::ConnSend)?;
-                }
-            }
-        }
-
-        Ok(was_new)
+            .map_err(IoError::Storage)?
+        else {
+            tracing::warn!("Missing blob {:?} for range request", digest);
+            return conn
+                .send(Message::BlobRangeNotFound { digest })
+                .await
+                .map_err(IoError::ConnSend);
+        };
+
+        let contents = blob.as_slice();
+        let len = contents.len() as u64;
+        let start = range.offset.min(len);
+        let end = range.offset.saturating_add(range.length).min(len);
+        let range = BlobRange {
+            offset: start,
+            length: end - start,
+        };
+        let Some(proof) = range_proof(contents, range) else {
+            tracing::warn!("Range {:?} of blob {:?} isn't a subtree", range, digest);
+            return conn
+                .send(Message::BlobRangeNotFound { digest })
+                .await
+                .map_err(IoError::ConnSend);
+        };
+        #[allow(clippy::cast_possible_truncation)]
+        let data = contents[start as usize..end as usize].to_vec();
+
+        conn.send(Message::BlobRangeResponse {
+            digest,
+            offset: start,
+            data,
+            proof,
+        })
+        .await
+        .map_err(IoError::ConnSend)
     }
 
-    /// Handle receiving a new chunk from a peer.
+    /// Handle a byte range of a blob that we requested from a peer.
     ///
-    /// Also propagates it to all other connected peers.
+    /// A range that doesn't lead to the blob's digest is asked of another peer,
+    /// and its sender isn't asked again. Once all ranges have arrived, the blob is
+    /// assembled, verified, and saved.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(true)` if this range completed the blob and it was saved.
+    /// * `Ok(false)` otherwise.
     ///
     /// # Errors
     ///
-    /// * [`IoError`] if a storage or network error occurs.
+    /// * [`IoError`] if a storage error occurs.
+    pub async fn recv_blob_range_response(
+        &self,
+        from: &PeerId,
+        digest: Digest,
+        offset: u64,
+        data: Vec<u8>,
+        proof: &[Digest],
+    ) -> Result<bool, IoError<F, S, C>> {
+        let inserted = {
+            let mut downloads = self.downloads.lock().await;
+            let Some(fetch) = downloads.get_mut(&digest) else {
+                tracing::warn!(
+                    "Unsolicited blob range for {:?} from peer {:?}",
+                    digest,
+                    from
+                );
+                return Ok(false);
+            };
+
+            match fetch.insert(*from, offset, data, proof) {
+                Ok(()) if fetch.download().is_complete() => Ok(downloads.remove(&digest)),
+                Ok(()) => Ok(None),
+                Err(e) => Err(e),
+            }
+        };
+
+        let finished = match inserted {
+            Ok(finished) => finished,
+            Err(e) => {
+                // Someone else is asked for the range instead
+                tracing::warn!("Rejected blob range from peer {:?}: {}", from, e);
+                self.reassign_ranges().await;
+                return Ok(false);
+            }
+        };
+        let Some(fetch) = finished else {
+            return Ok(false);
+        };
+
+        match fetch.into_download().assemble() {
+            Ok(blob) => {
+                self.storage
+                    .save_blob(blob)
+                    .await
+                    .map_err(IoError::Storage)?;
+                Ok(true)
+            }
+            Err(e) => {
+                tracing::error!("Discarding downloaded blob: {}", e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Handle a peer's answer that it doesn't hold a blob we asked it for ranges of,
+    /// by asking the others for them.
+    async fn recv_range_not_found(&self, from: PeerId, digest: Digest) {
+        {
+            let mut downloads = self.downloads.lock().await;
+            let Some(fetch) = downloads.get_mut(&digest) else {
+                return;
+            };
+            tracing::debug!("Peer {:?} doesn't hold blob {:?}", from, digest);
+            fetch.decline(from);
+        }
+        self.reassign_ranges().await;
+    }
+
+    /// The digests of blobs currently being downloaded in ranges, with bytes received so far.
+    pub async fn pending_blob_downloads(&self) -> HashMap<Digest, u64> {
+        self.downloads
+            .lock()
+            .await
+            .iter()
+            .map(|(digest, fetch)| (*digest, fetch.download().received_bytes()))
+            .collect()
+    }
+
+    /// The commits being pushed in chunks, by recipient and blob digest, with the bytes
+    /// acknowledged so far.
+    pub async fn pending_blob_uploads(&self) -> HashMap<(PeerId, Digest), u64> {
+        self.uploads
+            .lock()
+            .await
+            .iter()
+            .map(|(key, upload)| (*key, upload.acked()))
+            .collect()
+    }
+
+    /// The pushes of commits and chunks this instance already held (see [`dedup`]).
+    #[must_use]
+    pub fn dedup_stats(&self) -> DedupStats {
+        self.dedup.stats()
+    }
+
+    /// Push a commit to `conn`, in chunks if its blob is large enough (see [`upload`]).
+    async fn push_commit(
+        &self,
+        conn: &C,
+        id: SedimentreeId,
+        commit: &LooseCommit,
+        blob: &Blob,
+    ) -> Result<(), C::SendError> {
+        let chunk_size = self
+            .upload_chunk_size
+            .filter(|chunk_size| blob.as_slice().len() as u64 > *chunk_size);
+        let Some(chunk_size) = chunk_size else {
+            return conn
+                .send(Message::LooseCommit {
+                    id,
+                    commit: commit.clone(),
+                    blob: blob.clone(),
+                })
+                .await;
+        };
+
+        let key = (conn.peer_id(), commit.blob().digest());
+        let messages = {
+            let mut uploads = self.uploads.lock().await;
+            let upload = uploads
+                .entry(key)
+                .or_insert_with(|| BlobUpload::new(id, commit.clone(), blob.clone(), chunk_size));
+            upload.messages(upload.acked())
+        };
+        for message in messages {
+            // The peer may have acknowledged the whole blob already, if it held the commit
+            if !self.uploads.lock().await.contains_key(&key) {
+                tracing::debug!("Peer {:?} already holds blob {:?}", key.0, key.1);
+                break;
+            }
+            conn.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Push the rest of every chunked upload to `conn`'s peer that an earlier
+    /// connection left unfinished.
+    async fn resume_uploads(&self, conn: &C) {
+        let peer_id = conn.peer_id();
+        let messages = {
+            let uploads = self.uploads.lock().await;
+            uploads
+                .iter()
+                .filter(|((recipient, _), _)| *recipient == peer_id)
+                .flat_map(|(_, upload)| upload.messages(upload.acked()))
+                .collect::<Vec<_>>()
+        };
+        if messages.is_empty() {
+            return;
+        }
+
+        tracing::info!("Resuming uploads to peer {:?}", peer_id);
+        for message in messages {
+            if let Err(e) = conn.send(message).await {
+                tracing::warn!("Failed to resume uploads to peer {:?}: {}", peer_id, e);
+                return;
+            }
+        }
+    }
+
+    /// Handle a chunked upload message: a piece of a commit a peer is pushing, or a
+    /// peer's acknowledgement of one this instance is pushing.
+    ///
+    /// Returns `false` if a completed commit was ignored, quarantined, or rejected.
+    async fn recv_upload(&self, conn: &C, message: Message) -> Result<bool, IoError<F, S, C>> {
+        match message {
+            Message::CommitUpload {
+                id,
+                commit,
+                offset,
+                data,
+                chunk_digest,
+            } => {
+                let received = self
+                    .recv_commit_upload(conn, id, &commit, offset, data, chunk_digest)
+                    .await?;
+                match received {
+                    Some(blob) => {
+                        let held = Held::Commit(commit, blob);
+                        self.recv_pushed(conn.peer_id(), id, held).await
+                    }
+                    None => Ok(true),
+                }
+            }
+            Message::CommitUploadAck { digest, received } => {
+                self.recv_upload_ack(conn, digest, received)
+                    .await
+                    .map_err(IoError::ConnSend)?;
+                Ok(true)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Handle a peer's acknowledgement of a chunked upload, pushing again from where it
+    /// is if it has fallen behind.
+    async fn recv_upload_ack(
+        &self,
+        conn: &C,
+        digest: Digest,
+        received: u64,
+    ) -> Result<(), C::SendError> {
+        let key = (conn.peer_id(), digest);
+        let messages = {
+            let mut uploads = self.uploads.lock().await;
+            let Some(upload) = uploads.get_mut(&key) else {
+                return Ok(());
+            };
+            let behind = upload.ack(received);
+            if upload.is_done() {
+                uploads.remove(&key);
+                tracing::debug!("Finished uploading blob {:?} to peer {:?}", digest, key.0);
+                return Ok(());
+            }
+            behind.map(|from| upload.messages(from)).unwrap_or_default()
+        };
+        for message in messages {
+            conn.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a piece of a commit pushed in chunks, acknowledging what has arrived so far.
+    ///
+    /// Returns the commit's blob once all of it has arrived. If the commit is already
+    /// held, the whole blob is acknowledged at once instead (see [`dedup`]).
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    async fn recv_commit_upload(
+        &self,
+        conn: &C,
+        id: SedimentreeId,
+        commit: &LooseCommit,
+        offset: u64,
+        data: Vec<u8>,
+        chunk_digest: Digest,
+    ) -> Result<Option<Blob>, IoError<F, S, C>> {
+        let meta = *commit.blob();
+        let digest = meta.digest();
+
+        if self.holds_commit(id, commit).await {
+            let partial = self.receiving.lock().await.remove(&digest);
+            // Count the upload once, on its first piece, or whichever arrived first
+            if offset == 0 || partial.is_some() {
+                self.dedup.upload_cut_short(meta.size_bytes());
+            }
+            self.peer_sync.pushed(conn.peer_id(), id, commit);
+            conn.send(Message::CommitUploadAck {
+                digest,
+                received: meta.size_bytes(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+            return Ok(None);
+        }
+
+        // An earlier attempt may have finished without its last acknowledgement arriving
+        if let Some(blob) = self.get_local_blob(digest).await.map_err(IoError::Storage)? {
+            conn.send(Message::CommitUploadAck {
+                digest,
+                received: meta.size_bytes(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+            return Ok(Some(blob));
+        }
+
+        let (received, finished) = {
+            let mut receiving = self.receiving.lock().await;
+            let download = receiving
+                .entry(digest)
+                .or_insert_with(|| BlobDownload::new(meta));
+            if let Err(e) = download.insert(offset, data, chunk_digest) {
+                tracing::warn!("Rejected upload chunk from peer {:?}: {}", conn.peer_id(), e);
+            }
+            let received = download.received_prefix();
+            let finished = if download.is_complete() {
+                receiving.remove(&digest)
+            } else {
+                None
+            };
+            (received, finished)
+        };
+
+        conn.send(Message::CommitUploadAck { digest, received })
+            .await
+            .map_err(IoError::ConnSend)?;
+
+        let Some(download) = finished else {
+            return Ok(None);
+        };
+        match download.assemble() {
+            Ok(blob) => Ok(Some(blob)),
+            Err(e) => {
+                tracing::error!("Discarding uploaded blob: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /***********************
+     * INCREMENTAL CHANGES *
+     ***********************/
+
+    /// Add a new (incremental) commit locally and propagate it to all connected peers.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(None)` if the commit is not on a chunk boundary.
+    /// * `Ok(Some(ChunkRequested))` if the commit is on a [`Chunk`] boundary.
+    ///   In this case, please call `add_chunk` after creating the requested chunk.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn add_commit(
+        &mut self,
+        id: SedimentreeId,
+        commit: &LooseCommit,
+        blob: Blob,
+    ) -> Result<Option<ChunkRequested>, IoError<F, S, C>> {
+        self.insert_commit_locally(id, commit.clone(), blob.clone()) // TODO lots of cloning
+            .await
+            .map_err(IoError::Storage)?;
+
+        {
+            let locked = self.conn_manager.lock().await;
+            let conns = locked
+                .subscribed(id, self.subscription_policy.as_deref())
+                .filter(|conn| self.admits(conn.peer_id(), id))
+                .collect::<Vec<_>>();
+            for conn in conns {
+                self.push_commit(conn, id, commit, &blob)
+                    .await
+                    .map_err(IoError::ConnSend)?;
+            }
+        }
+
+        let mut maybe_requested_chunk = None;
+
+        let depth = Depth::from(commit.digest());
+        if depth != Depth(0) {
+            maybe_requested_chunk = Some(ChunkRequested {
+                head: commit.digest(),
+                depth,
+            });
+        }
+
+        Ok(maybe_requested_chunk)
+    }
+
+    /// Add a new (incremental) chunk locally and propagate it to all connected peers.
+    ///
+    /// NOTE this performs no integrity checks;
+    /// we assume this is a good chunk at the right depth
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn add_chunk(
+        &self,
+        id: SedimentreeId,
+        chunk: &Chunk,
+        blob: Blob,
+    ) -> Result<(), IoError<F, S, C>> {
+        let was_new = self
+            .insert_chunk_locally(id, chunk.clone(), blob.clone()) // TODO lots of cloning
+            .await
+            .map_err(IoError::Storage)?;
+
+        if was_new {
+            let locked = self.conn_manager.lock().await;
+            let conns = locked
+                .subscribed(id, self.subscription_policy.as_deref())
+                .filter(|conn| self.admits(conn.peer_id(), id))
+                .collect::<Vec<_>>();
+            for conn in conns {
+                conn.send(Message::Chunk {
+                    id,
+                    chunk: chunk.clone(),
+                    blob: blob.clone(),
+                })
+                .await
+                .map_err(IoError::ConnSend)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Bundle a sedimentree's loose commits into chunks, and drop the loose commits
+    /// they cover.
+    ///
+    /// Every run of commits between two checkpoints that no chunk covers yet, at each
+    /// depth they reach (see [`Sedimentree::missing_chunks`]), is handed to `bundle`,
+    /// which returns the chunk's blob, or `None` to leave the run loose. The chunks are added as with
+    /// [`Subduction::add_chunk`].
+    ///
+    /// Returns the chunks made. Covered commits stay in storage, so they're back after
+    /// a restart until the next compaction. A sedimentree scheduled for compaction (see
+    /// [`Subduction::with_compaction`]) can be scheduled again once this returns.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn compact(
+        &self,
+        id: SedimentreeId,
+        mut bundle: impl FnMut(&ChunkSpec) -> Option<Blob>,
+    ) -> Result<Vec<Chunk>, IoError<F, S, C>> {
+        let missing = self
+            .sedimentrees
+            .lock()
+            .await
+            .get(&id)
+            .map(|tree| tree.missing_chunks(id))
+            .unwrap_or_default();
+
+        let mut chunks = Vec::new();
+        for spec in missing {
+            let Some(blob) = bundle(&spec) else {
+                continue;
+            };
+            let chunk = Chunk::new(
+                spec.head(),
+                spec.boundary().clone(),
+                spec.checkpoints().clone(),
+                BlobMeta::new(blob.as_slice()),
+            );
+            if let Err(e) = self.add_chunk(id, &chunk, blob).await {
+                // Otherwise it would never be scheduled again
+                if let Some(compaction) = &self.compaction {
+                    compaction.finished(id);
+                }
+                return Err(e);
+            }
+            chunks.push(chunk);
+        }
+
+        if !chunks.is_empty() {
+            if let Some(tree) = self.sedimentrees.lock().await.get_mut(&id) {
+                *tree = tree.minimize();
+            }
+            self.summaries.invalidate(id);
+        }
+        if let Some(compaction) = &self.compaction {
+            compaction.finished(id);
+        }
+        Ok(chunks)
+    }
+
+    /****************************
+     * RECEIVE UPDATE FROM PEER *
+     ****************************/
+
+    /// Handle receiving a new commit from a peer.
+    ///
+    /// Also propagates it to all other connected peers.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn recv_commit(
+        &self,
+        from: &PeerId,
+        id: SedimentreeId,
+        commit: &LooseCommit,
+        blob: Blob,
+    ) -> Result<bool, IoError<F, S, C>> {
+        let was_new = self
+            .insert_commit_locally(id, commit.clone(), blob.clone())
+            .await
+            .map_err(IoError::Storage)?;
+        self.peer_sync.pushed(*from, id, commit);
+
+        if was_new {
+            let locked = self.conn_manager.lock().await;
+            for conn in locked.subscribed(id, self.subscription_policy.as_deref()) {
+                if conn.peer_id() != *from && self.admits(conn.peer_id(), id) {
+                    self.push_commit(conn, id, commit, &blob)
+                        .await
+                        .map_err(IoError::ConnSend)?;
+                }
+            }
+        }
+
+        Ok(was_new)
+    }
+
+    /// Handle receiving a new chunk from a peer.
+    ///
+    /// Also propagates it to all other connected peers.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
     pub async fn recv_chunk(
         &self,
-        from: &PeerId,
+        from: &PeerId,
+        id: SedimentreeId,
+        chunk: &Chunk,
+        blob: Blob,
+    ) -> Result<bool, IoError<F, S, C>> {
+        let was_new = self
+            .insert_chunk_locally(id, chunk.clone(), blob.clone()) // TODO lots of cloning
+            .await
+            .map_err(IoError::Storage)?;
+
+        if was_new {
+            let locked = self.conn_manager.lock().await;
+            for conn in locked.subscribed(id, self.subscription_policy.as_deref()) {
+                if conn.peer_id() != *from && self.admits(conn.peer_id(), id) {
+                    conn.send(Message::Chunk {
+                        id,
+                        chunk: chunk.clone(),
+                        blob: blob.clone(),
+                    })
+                    .await
+                    .map_err(IoError::ConnSend)?;
+                }
+            }
+        }
+
+        Ok(was_new)
+    }
+
+    /**************
+     * QUARANTINE *
+     **************/
+
+    /// The items flagged by the content scanner and awaiting review, oldest first.
+    pub async fn quarantined(&self) -> Vec<QuarantineEntry> {
+        self.quarantine.lock().await.entries()
+    }
+
+    /// Release the quarantined item with `digest`, storing and forwarding it as though
+    /// the scanner had accepted it.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no item with that digest is quarantined.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn release_quarantined(&self, digest: Digest) -> Result<bool, IoError<F, S, C>> {
+        let Some((entry, held)) = self.quarantine.lock().await.take(&digest) else {
+            return Ok(false);
+        };
+        tracing::info!("Releasing quarantined item {:?}", digest);
+        self.admit(&entry.item.from, entry.item.id, held).await?;
+        Ok(true)
+    }
+
+    /// Drop the quarantined item with `digest`.
+    ///
+    /// # Returns
+    ///
+    /// `false` if no item with that digest is quarantined.
+    pub async fn discard_quarantined(&self, digest: Digest) -> bool {
+        let discarded = self.quarantine.lock().await.take(&digest).is_some();
+        if discarded {
+            tracing::info!("Discarded quarantined item {:?}", digest);
+        }
+        discarded
+    }
+
+    /// Handle a commit or chunk pushed by a peer, unless the sedimentree is read-only,
+    /// once the scanner (if any) accepts it.
+    ///
+    /// Returns `false` if the item was already held, ignored, quarantined, or rejected.
+    async fn recv_pushed(
+        &self,
+        from: PeerId,
+        id: SedimentreeId,
+        held: Held,
+    ) -> Result<bool, IoError<F, S, C>> {
+        if self.is_read_only(id) {
+            tracing::warn!(
+                "Ignoring write from peer {:?} to read-only sedimentree {:?}",
+                from,
+                id
+            );
+            return Ok(false);
+        }
+        if self.holds(id, &held).await {
+            let size = match &held {
+                Held::Commit(commit, blob) => {
+                    self.peer_sync.pushed(from, id, commit);
+                    blob.meta().size_bytes()
+                }
+                Held::Chunk(_, blob) => blob.meta().size_bytes(),
+            };
+            self.dedup.duplicate(size);
+            return Ok(false);
+        }
+        let Some(held) = self.screen(from, id, held).await else {
+            return Ok(false);
+        };
+        self.admit(&from, id, held).await?;
+        Ok(true)
+    }
+
+    /// Store and forward a commit or chunk received from `from`, publishing it to the
+    /// backplane (if any) if it was new.
+    async fn admit(
+        &self,
+        from: &PeerId,
+        id: SedimentreeId,
+        held: Held,
+    ) -> Result<bool, IoError<F, S, C>> {
+        let Some(backplane) = &self.backplane else {
+            return match held {
+                Held::Commit(commit, blob) => self.recv_commit(from, id, &commit, blob).await,
+                Held::Chunk(chunk, blob) => self.recv_chunk(from, id, &chunk, blob).await,
+            };
+        };
+
+        let (was_new, item) = match held {
+            Held::Commit(commit, blob) => (
+                self.recv_commit(from, id, &commit, blob.clone()).await?,
+                BackplaneItem::Commit(commit, blob),
+            ),
+            Held::Chunk(chunk, blob) => (
+                self.recv_chunk(from, id, &chunk, blob.clone()).await?,
+                BackplaneItem::Chunk(chunk, blob),
+            ),
+        };
+        if was_new {
+            backplane.publish(&BackplaneMessage {
+                id,
+                from: *from,
+                item,
+            });
+        }
+        Ok(was_new)
+    }
+
+    /// Handle a write published to the backplane by another relay instance, storing
+    /// it and forwarding it to every connected peer except the one that pushed it.
+    ///
+    /// Read-only sedimentrees are left alone, and the write isn't published again.
+    ///
+    /// # Returns
+    ///
+    /// Whether the item was new.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage or network error occurs.
+    pub async fn recv_backplane(
+        &self,
+        message: BackplaneMessage,
+    ) -> Result<bool, IoError<F, S, C>> {
+        let BackplaneMessage { id, from, item } = message;
+        if self.is_read_only(id) {
+            return Ok(false);
+        }
+        match item {
+            BackplaneItem::Commit(commit, blob) => self.recv_commit(&from, id, &commit, blob).await,
+            BackplaneItem::Chunk(chunk, blob) => self.recv_chunk(&from, id, &chunk, blob).await,
+        }
+    }
+
+    /// Check that an item received from `from` is intact, and ask the scanner, if there
+    /// is one, whether it may be hosted, returning it if so.
+    ///
+    /// Items whose blob isn't the one they name are dropped, as are rejected ones, and
+    /// flagged ones are quarantined.
+    async fn screen(&self, from: PeerId, id: SedimentreeId, held: Held) -> Option<Held> {
+        if !held.is_intact() {
+            tracing::warn!(
+                "Dropping an item from peer {:?} for {:?} whose blob doesn't match it",
+                from,
+                id
+            );
+            return None;
+        }
+        let Some(scanner) = &self.scanner else {
+            return Some(held);
+        };
+        let (kind, digest, blob) = match &held {
+            Held::Commit(commit, blob) => (ItemKind::Commit, commit.digest(), blob),
+            Held::Chunk(chunk, blob) => (ItemKind::Chunk, chunk.digest(), blob),
+        };
+        let item = ScannedItem {
+            id,
+            from,
+            kind,
+            digest,
+            size: blob.meta().size_bytes(),
+            content: self.content_access.permits(id).then(|| blob.clone()),
+        };
+
+        match scanner.scan(&item).await {
+            Verdict::Accept => Some(held),
+            Verdict::Flag { reason } => {
+                tracing::warn!(
+                    "Quarantining {:?} {:?} from peer {:?}: {}",
+                    kind,
+                    digest,
+                    from,
+                    reason
+                );
+                let entry = QuarantineEntry {
+                    item,
+                    reason,
+                    flagged_at: SystemTime::now(),
+                };
+                self.quarantine.lock().await.hold(entry, held);
+                None
+            }
+            Verdict::Reject { reason } => {
+                tracing::warn!(
+                    "Rejecting {:?} {:?} from peer {:?}: {}",
+                    kind,
+                    digest,
+                    from,
+                    reason
+                );
+                None
+            }
+        }
+    }
+
+    /*****************
+     * SUBSCRIPTIONS *
+     *****************/
+
+    /// Ask every connected peer to push us only writes to the documents `filters`
+    /// match, in place of any earlier subscription, and every peer connected later.
+    ///
+    /// Filters a peer refuses are listed by [`Subduction::denied_subscriptions`] once
+    /// it answers.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a network error occurs.
+    pub async fn subscribe(&self, filters: Vec<DocumentFilter>) -> Result<(), IoError<F, S, C>> {
+        *self.subscription.lock().await = Some(filters.clone());
+        let locked = self.conn_manager.lock().await;
+        for conn in locked.connections.values() {
+            conn.send(Message::Subscribe {
+                filters: filters.clone(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+        }
+        Ok(())
+    }
+
+    /// Send our subscription, if we've made one, over the new connection `conn`.
+    async fn resend_subscription(&self, conn: &C) {
+        let Some(filters) = self.subscription.lock().await.clone() else {
+            return;
+        };
+        if let Err(e) = conn.send(Message::Subscribe { filters }).await {
+            tracing::warn!(
+                "Failed to send our subscription to peer {:?}: {}",
+                conn.peer_id(),
+                e
+            );
+        }
+    }
+
+    /// The filters `peer` refused in its answer to our last subscription.
+    pub async fn denied_subscriptions(&self, peer: &PeerId) -> Vec<SubscriptionDenied> {
+        self.subscription_denials
+            .lock()
+            .await
+            .get(peer)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Push the connection `conn_id` only what `filters` match, of those the policy
+    /// allows, and tell the peer which it doesn't.
+    async fn recv_subscribe(
+        &self,
+        conn_id: ConnectionId,
+        conn: &C,
+        filters: Vec<DocumentFilter>,
+    ) -> Result<(), IoError<F, S, C>> {
+        let from = conn.peer_id();
+        let (allowed, denied) =
+            subscription::screen(self.subscription_policy.as_deref(), from, filters);
+        for SubscriptionDenied { filter, reason } in &denied {
+            tracing::info!("Refused subscription of peer {from:?} to {filter:?}: {reason}");
+        }
+
+        self.conn_manager
+            .lock()
+            .await
+            .subscriptions
+            .set(conn_id, allowed);
+        conn.send(Message::Subscribed { denied })
+            .await
+            .map_err(IoError::ConnSend)
+    }
+
+    /// Keep the filters `from` refused of our subscription.
+    async fn recv_subscribed(&self, from: PeerId, denied: Vec<SubscriptionDenied>) {
+        for SubscriptionDenied { filter, reason } in &denied {
+            tracing::warn!("Peer {from:?} refused our subscription to {filter:?}: {reason}");
+        }
+        self.subscription_denials.lock().await.insert(from, denied);
+    }
+
+    /*************
+     * SIGNALING *
+     *************/
+
+    /// Send a WebRTC signal about sedimentree `id` to peer `to`, by way of every
+    /// connected relay.
+    ///
+    /// # Returns
+    ///
+    /// The number of connections the signal was sent over.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a network error occurs.
+    pub async fn send_signal(
+        &self,
         id: SedimentreeId,
-        chunk: &Chunk,
-        blob: Blob,
-    ) -> Result<bool, IoError<F, S, C>> {
-        let was_new = self
-            .insert_chunk_locally(id, chunk.clone(), blob.clone()) // TODO lots of cloning
+        to: PeerId,
+        signal: Signal,
+    ) -> Result<usize, IoError<F, S, C>> {
+        let locked = self.conn_manager.lock().await;
+        for conn in locked.connections.values() {
+            conn.send(Message::RelaySignal {
+                id,
+                to,
+                signal: signal.clone(),
+            })
             .await
-            .map_err(IoError::Storage)?;
+            .map_err(IoError::ConnSend)?;
+        }
+        Ok(locked.connections.len())
+    }
 
-        if was_new {
-            let locked = self.conn_manager.lock().await;
-            for conn in locked.connections.values() {
-                if conn.peer_id() != *from {
-                    conn.send(Message::Chunk {
-                        id,
-                        chunk: chunk.clone(),
-                        blob: blob.clone(),
-                    })
-                    .await
-                    .map_err(IoError::ConnSend)?;
-                }
-            }
+    /// Deliver a signal passed on by a relay.
+    fn recv_signal(&self, id: SedimentreeId, from: PeerId, signal: Signal) {
+        if let Some(sink) = &self.signals {
+            sink.signal(id, from, signal);
+        } else {
+            tracing::debug!("Ignoring signal from peer {:?} about {:?}", from, id);
         }
+    }
 
-        Ok(was_new)
+    /*******************
+     * ACCESS REQUESTS *
+     *******************/
+
+    /// Send a request for access to the document `id` to every connected relay, to
+    /// pass on to the peers that may grant it (see [`access`]).
+    ///
+    /// # Returns
+    ///
+    /// The number of connections the request was sent over.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a network error occurs.
+    pub async fn send_access_request(
+        &self,
+        id: SedimentreeId,
+        request: Vec<u8>,
+    ) -> Result<usize, IoError<F, S, C>> {
+        self.forwarded.lock().await.first_sight(&request);
+        let conns = self
+            .conn_manager
+            .lock()
+            .await
+            .connections
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for conn in &conns {
+            conn.send(Message::AccessRequest {
+                id,
+                request: request.clone(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+        }
+        Ok(conns.len())
+    }
+
+    /// Pass an access request from `from` on to the other connections subscribed to
+    /// the document `id`, unless it has been passed on already.
+    async fn relay_access_request(
+        &self,
+        from: PeerId,
+        id: SedimentreeId,
+        request: Vec<u8>,
+    ) -> Result<(), IoError<F, S, C>> {
+        if !self.forwarded.lock().await.first_sight(&request) {
+            tracing::debug!("Not passing on an access request to {id:?} again");
+            return Ok(());
+        }
+
+        // Not sent under the lock, so a slow peer doesn't hold up every other connection
+        let targets = self
+            .conn_manager
+            .lock()
+            .await
+            .subscribed(id, self.subscription_policy.as_deref())
+            .filter(|conn| conn.peer_id() != from)
+            .cloned()
+            .collect::<Vec<_>>();
+        for conn in targets {
+            conn.send(Message::AccessRequest {
+                id,
+                request: request.clone(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+        }
+        Ok(())
+    }
+
+    /// Whether `peer` may sync the sedimentree `id`, by the [`Membership`] if there is one.
+    fn admits(&self, peer: PeerId, id: SedimentreeId) -> bool {
+        self.membership
+            .as_ref()
+            .is_none_or(|membership| membership.is_member(peer, id))
+    }
+
+    /// The blobs of `digests` that `peer` may fetch, by the [`Membership`] if there
+    /// is one: those of the commits and chunks of sedimentrees it's a member of.
+    async fn admitted_blobs(&self, peer: PeerId, digests: Vec<Digest>) -> Vec<Digest> {
+        let Some(membership) = self.membership.as_ref() else {
+            return digests;
+        };
+        let sedimentrees = self.sedimentrees.lock().await;
+        let (admitted, refused) = digests.into_iter().partition::<Vec<_>, _>(|digest| {
+            sedimentrees.iter().any(|(id, sedimentree)| {
+                membership.is_member(peer, *id)
+                    && (sedimentree
+                        .loose_commits()
+                        .any(|commit| commit.blob().digest() == *digest)
+                        || sedimentree
+                            .chunks()
+                            .any(|chunk| chunk.summary().blob_meta().digest() == *digest))
+            })
+        });
+        if !refused.is_empty() {
+            tracing::warn!(
+                "Refusing blobs {:?} to peer {:?}, which isn't a member of their sedimentrees",
+                refused,
+                peer
+            );
+        }
+        admitted
+    }
+
+    /// Record that `from` syncs the sedimentree `message` is about, if it's sync
+    /// traffic. A signal doesn't count, or a peer could make itself a participant of
+    /// any sedimentree just by signaling about it.
+    async fn note_participant(&self, from: PeerId, message: &Message) {
+        match message {
+            Message::LooseCommit { id, .. }
+            | Message::Chunk { id, .. }
+            | Message::CommitUpload { id, .. }
+            | Message::BatchSyncRequest(BatchSyncRequest { id, .. })
+            | Message::BatchSyncResponse(BatchSyncResponse { id, .. })
+            | Message::Backfill { id, .. }
+            | Message::DocumentTtl { id, .. } => self.participants.lock().await.join(*id, from),
+            _ => {}
+        }
+    }
+
+    /// Pass a signal from `from` on to `to`, if they both sync sedimentree `id`.
+    async fn relay_signal(
+        &self,
+        from: PeerId,
+        id: SedimentreeId,
+        to: PeerId,
+        signal: Signal,
+    ) -> Result<(), IoError<F, S, C>> {
+        if !self.participants.lock().await.share(id, &from, &to) {
+            tracing::warn!(
+                "Not relaying signal from peer {:?} to {:?}, which don't both sync {:?}",
+                from,
+                to,
+                id
+            );
+            return Ok(());
+        }
+
+        // Not sent under the lock, so a slow peer doesn't hold up every other connection
+        let targets = self
+            .conn_manager
+            .lock()
+            .await
+            .connections
+            .values()
+            .filter(|conn| conn.peer_id() == to)
+            .cloned()
+            .collect::<Vec<_>>();
+        for conn in targets {
+            conn.send(Message::Signal {
+                id,
+                from,
+                signal: signal.clone(),
+            })
+            .await
+            .map_err(IoError::ConnSend)?;
+        }
+        Ok(())
     }
 
     /*********************
@@ -668,22 +2163,38 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
 
     /// Handle receiving a batch sync request from a peer.
     ///
+    /// If the request's token is the token of our summary, neither side has changed
+    /// since the peer's last round with us, so the request is answered with an empty
+    /// diff without comparing summaries. If the peer sent a [`Have`] in place of
+    /// listing its loose commits, the commits its filter lacks are sent, and the
+    /// response carries our own for the peer to do the same (see [`filter`]). If it
+    /// resumed from a frontier we hold, what came before it isn't compared (see
+    /// [`session`]).
+    ///
     /// # Errors
     ///
     /// * [`IoError`] if a storage or network error occurs.
     pub async fn recv_batch_sync_request(
         &self,
-        id: SedimentreeId,
-        their_summary: &SedimentreeSummary,
-        req_id: RequestId,
+        request: &BatchSyncRequest,
         conn: &C,
     ) -> Result<(), ListenError<F, S, C>> {
-        let mut their_missing_commits = Vec::new();
-        let mut their_missing_chunks = Vec::new();
-        let mut our_missing_blobs = Vec::new();
+        let BatchSyncRequest {
+            id,
+            req_id,
+            sedimentree_summary: ref their_summary,
+            summary_token: their_token,
+            have: ref their_have,
+            ref since,
+        } = *request;
+        let mut our_have = None;
+
+        let started = self.reports.as_ref().map(|sink| sink.now());
+        let mut report = SyncReport::new(conn.peer_id(), id, SyncRole::Responder);
+        let span = SyncReport::span(conn.peer_id(), id, SyncRole::Responder);
 
         tracing::info!("recv_batch_sync_request for sedimentree {:?}", id);
-        {
+        let (sent, summary_token, resumed) = {
             let mut guard = self.sedimentrees.lock().await;
             let sedimentree = guard.entry(id).or_default();
             tracing::info!(
@@ -693,69 +2204,153 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                 their_summary.chunk_summaries().len()
             );
 
-            let local_sedimentree = sedimentree.clone();
-            let diff: RemoteDiff<'_> = local_sedimentree.diff_remote(their_summary);
-
-            for commit in diff.remote_commits {
-                sedimentree.add_commit(commit.clone());
-            }
-
-            for commit in diff.local_commits {
-                if let Some(blob) = self
-                    .storage
-                    .load_blob(commit.blob().digest())
-                    .await
-                    .map_err(IoError::Storage)?
-                {
-                    their_missing_commits.push((commit.clone(), blob)); // TODO lots of cloning
-                } else {
-                    tracing::warn!("Missing blob for commit {:?}", commit.digest(),);
-                    our_missing_blobs.push(commit.blob().digest());
-                }
-            }
-
-            for chunk in diff.local_chunks {
-                if let Some(blob) = self
-                    .storage
-                    .load_blob(chunk.summary().blob_meta().digest())
-                    .await
-                    .map_err(IoError::Storage)?
-                {
-                    their_missing_chunks.push((chunk.clone(), blob)); // TODO lots of cloning
-                } else {
-                    tracing::warn!("Missing blob for chunk {:?} ", chunk.digest(),);
-                    our_missing_blobs.push(chunk.summary().blob_meta().digest());
-                }
-            }
-        }
-
+            let settled = session::settled(sedimentree, since);
+            let resumed = settled.is_some();
+            let sent = if their_token
+                .is_some_and(|token| token == self.summaries.token(id, sedimentree))
+            {
+                tracing::debug!(
+                    "Sedimentree {:?} hasn't changed since the requester's last round",
+                    id
+                );
+                report.strategy = Reconciliation::NotModified;
+                Default::default()
+            } else if let Some(their_have) = their_have {
+                report.strategy = Reconciliation::Filter;
+                our_have = Some(filter::have(sedimentree));
+                // Their summary only lists chunks, so only its chunks are worth diffing
+                let diff = sedimentree.diff_remote(their_summary);
+                let commits = filter::missing(sedimentree, their_have);
+                self.load_with_blobs(commits, diff.local_chunks).await?
+            // A requester that's up to date sends back the summary we'd send it
+            } else if resumed && *self.summaries.summarize(id, sedimentree) == *their_summary {
+                tracing::debug!("Sedimentree {:?} is already in sync with the requester", id);
+                Default::default()
+            } else {
+                report.strategy = Reconciliation::SummaryDiff;
+                let local_sedimentree = sedimentree.clone();
+                let diff: RemoteDiff<'_> = local_sedimentree.diff_remote(their_summary);
+                report.commits_received += self
+                    .add_remote_commits(id, sedimentree, diff.remote_commits)
+                    .await;
+
+                // The requester left out what it held before the frontier
+                let settled = settled.unwrap_or_default();
+                let mut local_commits = diff.local_commits;
+                local_commits.retain(|commit| !settled.contains(&commit.digest()));
+                self.load_with_blobs(local_commits, diff.local_chunks)
+                    .await?
+            };
+
+            // What we couldn't send for want of its blob isn't covered by the token
+            let token = self.summaries.token_without(id, sedimentree, &sent.2);
+            (sent, token, resumed)
+        };
+        let (their_missing_commits, mut their_missing_chunks, our_missing_blobs) = sent;
+
+        let backfill = bootstrap::split_backfill(&mut their_missing_chunks);
         tracing::info!(
-            "Sending batch sync response for sedimentree {:?} with {} missing commits and {} missing chunks",
+            "Sending batch sync response for sedimentree {:?} with {} missing commits and {} missing chunks, and {} to backfill",
             id,
             their_missing_commits.len(),
-            their_missing_chunks.len()
+            their_missing_chunks.len(),
+            backfill.len()
         );
-        conn.send(
-            BatchSyncResponse {
-                id,
-                req_id,
-                diff: SyncDiff {
-                    missing_commits: their_missing_commits,
-                    missing_chunks: their_missing_chunks,
-                },
-            }
-            .into(),
-        )
-        .await
-        .map_err(IoError::ConnSend)?;
+        let diff = SyncDiff {
+            missing_commits: their_missing_commits,
+            missing_chunks: their_missing_chunks,
+        };
+        let response = BatchSyncResponse {
+            req_id,
+            id,
+            diff,
+            summary_token,
+            have: our_have,
+            resumed,
+            backfill: backfill.len() as u64,
+        };
+        self.send_batch_sync_response(conn, response, backfill, &mut report)
+            .await?;
+        self.finish_round(report, started, &span);
 
         if our_missing_blobs.is_empty() {
+            // Between the request and this response, the requester has everything we do
+            if let Some(heads) = self.get_heads(id).await {
+                self.peer_sync.synced(conn.peer_id(), id, heads);
+            }
             Ok(())
         } else {
             Err(ListenError::MissingBlobs(our_missing_blobs))
         }
     }
 
+    /// Add the `commits` a peer has that `sedimentree` lacks, unless it's read only
+    /// or they've expired, and return how many were added.
+    async fn add_remote_commits(
+        &self,
+        id: SedimentreeId,
+        sedimentree: &mut Sedimentree,
+        commits: Vec<&LooseCommit>,
+    ) -> usize {
+        if self.is_read_only(id) || commits.is_empty() {
+            return 0;
+        }
+        let lifecycle = self.lifecycle.lock().await;
+        let mut added = 0;
+        for commit in commits {
+            if !lifecycle.is_expired(id, commit.digest()) && sedimentree.add_commit(commit.clone())
+            {
+                added += 1;
+            }
+        }
+        self.summaries.invalidate(id);
+        added
+    }
+
+    /// Pair `commits` and `chunks` with their blobs, and list the digests of any blobs
+    /// we're missing.
+    #[allow(clippy::type_complexity)]
+    async fn load_with_blobs(
+        &self,
+        commits: Vec<&LooseCommit>,
+        chunks: Vec<&Chunk>,
+    ) -> Result<(Vec<(LooseCommit, Blob)>, Vec<(Chunk, Blob)>, Vec<BlobMeta>), IoError<F, S, C>>
+    {
+        let mut with_commits = Vec::new();
+        let mut with_chunks = Vec::new();
+        let mut missing = Vec::new();
+
+        for commit in commits {
+            if let Some(blob) = self
+                .storage
+                .load_blob(commit.blob().digest())
+                .await
+                .map_err(IoError::Storage)?
+            {
+                with_commits.push((commit.clone(), blob)); // TODO lots of cloning
+            } else {
+                tracing::warn!("Missing blob for commit {:?}", commit.digest(),);
+                missing.push(*commit.blob());
+            }
+        }
+
+        for chunk in chunks {
+            if let Some(blob) = self
+                .storage
+                .load_blob(chunk.summary().blob_meta().digest())
+                .await
+                .map_err(IoError::Storage)?
+            {
+                with_chunks.push((chunk.clone(), blob)); // TODO lots of cloning
+            } else {
+                tracing::warn!("Missing blob for chunk {:?} ", chunk.digest(),);
+                missing.push(chunk.summary().blob_meta());
+            }
+        }
+
+        Ok((with_commits, with_chunks, missing))
+    }
+
     /// Handle receiving a batch sync response from a peer.
     ///
     /// # Errors
@@ -764,34 +2359,104 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
     pub async fn recv_batch_sync_response(
         &self,
         from: &PeerId,
-        id: SedimentreeId,
-        diff: &SyncDiff,
+        response: BatchSyncResponse,
     ) -> Result<(), IoError<F, S, C>> {
+        let BatchSyncResponse {
+            id, diff, backfill, ..
+        } = response;
         tracing::info!(
-            "Received batch sync response for sedimentree {:?} from peer {:?} with {} missing commits and {} missing chunks",
+            "Received batch sync response for sedimentree {:?} from peer {:?} with {} missing commits and {} missing chunks, and {} to backfill",
             id,
             from,
             diff.missing_commits.len(),
-            diff.missing_chunks.len()
+            diff.missing_chunks.len(),
+            backfill
         );
 
-        for (commit, blob) in &diff.missing_commits {
-            self.insert_commit_locally(id, commit.clone(), blob.clone()) // TODO potentially a LOT of cloning
+        self.apply_diff(from, id, diff, backfill).await
+    }
+
+    /// Send `response` to `conn`, then stream the chunks it left out as `backfill`,
+    /// counting them all in `report`.
+    async fn send_batch_sync_response(
+        &self,
+        conn: &C,
+        response: BatchSyncResponse,
+        backfill: Vec<(Chunk, Blob)>,
+        report: &mut SyncReport,
+    ) -> Result<(), IoError<F, S, C>> {
+        let id = response.id;
+        // The requester may not know the sedimentree is ephemeral yet
+        if let Some(ttl) = self.ttl(id).await {
+            conn.send(Message::document_ttl(id, Some(ttl)))
                 .await
-                .map_err(IoError::Storage)?;
+                .map_err(IoError::ConnSend)?;
+        }
+        report.sent(&response.diff);
+        conn.send(response.into())
+            .await
+            .map_err(IoError::ConnSend)?;
+        for (chunk, blob) in backfill {
+            report.sent_chunk(&blob);
+            conn.send(Message::Backfill { id, chunk, blob })
+                .await
+                .map_err(IoError::ConnSend)?;
         }
+        Ok(())
+    }
 
-        for (chunk, blob) in &diff.missing_chunks {
-            self.insert_chunk_locally(id, chunk.clone(), blob.clone())
+    /// Handle a chunk `from` backfilled after its batch sync response (see
+    /// [`bootstrap`]), unless it didn't announce any.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if a storage error occurs.
+    pub async fn recv_backfill(
+        &self,
+        from: PeerId,
+        id: SedimentreeId,
+        chunk: Chunk,
+        blob: Blob,
+    ) -> Result<(), IoError<F, S, C>> {
+        if !self.backfills.lock().await.expects(from, id) {
+            tracing::warn!(
+                "Ignoring backfill of {:?} that wasn't asked of peer {:?}",
+                id,
+                from
+            );
+            return Ok(());
+        }
+        if self.is_frozen(id) {
+            tracing::warn!("Ignoring backfill for frozen sedimentree {:?}", id);
+        } else if let Some(Held::Chunk(chunk, blob)) =
+            self.screen(from, id, Held::Chunk(chunk, blob)).await
+        {
+            self.insert_chunk_locally(id, chunk, blob)
                 .await
                 .map_err(IoError::Storage)?;
         }
-
+        if let Some(progress) = self.backfills.lock().await.received(from, id) {
+            self.report_progress(progress);
+        }
         Ok(())
     }
 
-    /// Find blobs from connected peers.
-    pub async fn request_blobs(&self, digests: Vec<Digest>) {
+    /// Find blobs from connected peers: those up to [`DEFAULT_CHUNK_SIZE`] whole,
+    /// and larger ones in ranges (see [`Self::fetch_blob_from_peers`]).
+    pub async fn request_blobs(&self, metas: Vec<BlobMeta>) {
+        let (large, small): (Vec<_>, Vec<_>) = metas
+            .into_iter()
+            .partition(|meta| meta.size_bytes() > DEFAULT_CHUNK_SIZE);
+        for meta in large {
+            if let Err(e) = self.fetch_blob_from_peers(meta, DEFAULT_CHUNK_SIZE).await {
+                tracing::error!("Error fetching blob {:?}: {}", meta.digest(), e);
+            }
+        }
+        if small.is_empty() {
+            return;
+        }
+
+        let digests = small.iter().map(BlobMeta::digest).collect::<Vec<_>>();
         let locked = self.conn_manager.lock().await;
         for conn in locked.connections.values() {
             if let Err(e) = conn.send(Message::BlobsRequest(digests.clone())).await {
@@ -819,79 +2484,26 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         &self,
         to_ask: &PeerId,
         id: SedimentreeId,
-        timeout: Option<Duration>,
-    ) -> Result<(bool, Vec<(C, C::CallError)>), IoError<F, S, C>> {
-        tracing::info!(
-            "Requesting batch sync for sedimentree {:?} from peer {:?}",
-            id,
-            to_ask
-        );
-
-        let mut had_success = false;
-        let mut peer_conns = Vec::new();
-        {
-            let locked = self.conn_manager.lock().await;
-            for (conn_id, conn) in &locked.connections {
-                if conn.peer_id() == *to_ask {
-                    peer_conns.push((*conn_id, conn.clone()));
-                }
-            }
-        }
-
-        let mut conn_errs = Vec::new();
-
-        for (conn_id, conn) in peer_conns {
-            tracing::info!("Using connection {:?} to peer {:?}", conn_id, to_ask);
-            let summary = self
-                .sedimentrees
-                .lock()
-                .await
-                .get(&id)
-                .map(Sedimentree::summarize)
-                .unwrap_or_default();
-
-            let req_id = conn.next_request_id().await;
-
-            let result = conn
-                .call(
-                    BatchSyncRequest {
-                        id,
-                        req_id,
-                        sedimentree_summary: summary,
-                    },
-                    timeout,
-                )
-                .await;
-
-            match result {
-                Err(e) => conn_errs.push((conn, e)),
-                Ok(BatchSyncResponse {
-                    diff:
-                        SyncDiff {
-                            missing_commits,
-                            missing_chunks,
-                        },
-                    ..
-                }) => {
-                    for (commit, blob) in missing_commits {
-                        self.insert_commit_locally(id, commit.clone(), blob.clone()) // TODO potentially a LOT of cloning
-                            .await
-                            .map_err(IoError::Storage)?;
-                    }
-
-                    for (chunk, blob) in missing_chunks {
-                        self.insert_chunk_locally(id, chunk.clone(), blob.clone())
-                            .await
-                            .map_err(IoError::Storage)?;
-                    }
+        timeout: Option<Duration>,
+    ) -> Result<(bool, Vec<(C, C::CallError)>), IoError<F, S, C>> {
+        tracing::info!(
+            "Requesting batch sync for sedimentree {:?} from peer {:?}",
+            id,
+            to_ask
+        );
 
-                    had_success = true;
-                    break;
+        let mut peer_conns = Vec::new();
+        {
+            let locked = self.conn_manager.lock().await;
+            for (conn_id, conn) in &locked.connections {
+                if conn.peer_id() == *to_ask {
+                    peer_conns.push((*conn_id, conn.clone()));
                 }
             }
         }
 
-        Ok((had_success, conn_errs))
+        self.batch_sync_with_peer(*to_ask, id, &peer_conns, timeout)
+            .await
     }
 
     /// Request a batch sync from all connected peers for a given sedimentree ID.
@@ -935,64 +2547,9 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                     peer_conns.len(),
                 );
 
-                let mut had_success = false;
-                let mut conn_errs = Vec::new();
-
-                for (conn_id, conn) in peer_conns {
-                    tracing::debug!(
-                        "Using connection {:?} to peer {:?}",
-                        conn_id,
-                        conn.peer_id()
-                    );
-                    let summary = self
-                        .sedimentrees
-                        .lock()
-                        .await
-                        .get(&id)
-                        .map(Sedimentree::summarize)
-                        .unwrap_or_default();
-
-                    let req_id = conn.next_request_id().await;
-
-                    let result = conn
-                        .call(
-                            BatchSyncRequest {
-                                id,
-                                req_id,
-                                sedimentree_summary: summary,
-                            },
-                            timeout,
-                        )
-                        .await;
-
-                    match result {
-                        Err(e) => conn_errs.push((conn.clone(), e)),
-                        Ok(BatchSyncResponse {
-                            diff:
-                                SyncDiff {
-                                    missing_commits,
-                                    missing_chunks,
-                                },
-                            ..
-                        }) => {
-                            for (commit, blob) in missing_commits {
-                                self.insert_commit_locally(id, commit.clone(), blob.clone()) // TODO potentially a LOT of cloning
-                                    .await
-                                    .map_err(IoError::<F, S, C>::Storage)?;
-                            }
-
-                            for (chunk, blob) in missing_chunks {
-                                self.insert_chunk_locally(id, chunk.clone(), blob.clone())
-                                    .await
-                                    .map_err(IoError::<F, S, C>::Storage)?;
-                            }
-
-                            had_success = true;
-                            break;
-                        }
-                    }
-                }
-
+                let (had_success, conn_errs) = self
+                    .batch_sync_with_peer(*peer_id, id, peer_conns, timeout)
+                    .await?;
                 Ok::<(PeerId, bool, Vec<(C, _)>), IoError<F, S, C>>((
                     *peer_id,
                     had_success,
@@ -1009,6 +2566,219 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         Ok(out)
     }
 
+    /// Batch sync a sedimentree with one peer, trying each of its connections in turn.
+    ///
+    /// If a sync for the same peer and sedimentree is already in flight, this waits for
+    /// that sync to finish and shares its outcome instead of sending a duplicate request.
+    /// Connection errors are only reported to the caller that issued the request.
+    async fn batch_sync_with_peer(
+        &self,
+        peer_id: PeerId,
+        id: SedimentreeId,
+        peer_conns: &[(ConnectionId, C)],
+        timeout: Option<Duration>,
+    ) -> Result<(bool, Vec<(C, C::CallError)>), IoError<F, S, C>> {
+        let lead = match self.in_flight.join((peer_id, id)) {
+            Joined::Leader(lead) => lead,
+            Joined::Follower(outcome) => {
+                tracing::debug!(
+                    "Batch sync for sedimentree {:?} with peer {:?} already in flight",
+                    id,
+                    peer_id
+                );
+                // A cancelled or failed leader counts as an unsuccessful sync.
+                return Ok((outcome.await.unwrap_or(false), Vec::new()));
+            }
+        };
+
+        let mut had_success = false;
+        let mut conn_errs = Vec::new();
+
+        for (conn_id, conn) in peer_conns {
+            tracing::debug!("Using connection {:?} to peer {:?}", conn_id, peer_id);
+            // So that the peer expires the same commits
+            if let Some(ttl) = self.ttl(id).await {
+                let sent = conn.send(Message::document_ttl(id, Some(ttl))).await;
+                if let Err(e) = sent {
+                    tracing::warn!("Couldn't send TTL of {:?} to peer {:?}: {}", id, peer_id, e);
+                }
+            }
+            let req_id = conn.next_request_id().await;
+            let (request, version) = self.batch_sync_request(peer_id, id, req_id).await;
+            let sent_token = request.summary_token;
+            let since = request.since.clone();
+
+            let _outstanding = self.peer_sync.begin(peer_id, id);
+            let started = self.reports.as_ref().map(|sink| sink.now());
+            let span = SyncReport::span(peer_id, id, SyncRole::Requester);
+            self.backfills.lock().await.requested(peer_id, id);
+            let result = conn.call(request, timeout).instrument(span.clone()).await;
+
+            match result {
+                Err(e) => {
+                    self.backfills.lock().await.cancelled(peer_id, id);
+                    conn_errs.push((conn.clone(), e));
+                }
+                Ok(BatchSyncResponse {
+                    diff,
+                    summary_token,
+                    have: their_have,
+                    resumed,
+                    backfill,
+                    ..
+                }) => {
+                    let mut report = SyncReport::new(peer_id, id, SyncRole::Requester);
+                    report.received(&diff);
+                    // An in-sync responder sends back an empty diff
+                    let mut unchanged = diff.missing_commits.is_empty()
+                        && diff.missing_chunks.is_empty()
+                        && backfill == 0;
+                    if !unchanged {
+                        report.strategy = Reconciliation::SummaryDiff;
+                    } else if sent_token == Some(summary_token) {
+                        report.strategy = Reconciliation::NotModified;
+                    }
+                    self.apply_diff(&peer_id, id, diff, backfill)
+                        .instrument(span.clone())
+                        .await?;
+                    if their_have.is_some() {
+                        report.strategy = Reconciliation::Filter;
+                    }
+                    // A responder that couldn't resume lacks what we left out
+                    let left_out = if resumed { &[][..] } else { &since[..] };
+                    let pushed = self
+                        .push_missing(conn, id, their_have.as_ref(), left_out)
+                        .instrument(span.clone())
+                        .await?;
+                    report.commits_sent += pushed.0;
+                    report.bytes_sent += pushed.1;
+                    unchanged = unchanged && pushed.0 == 0;
+                    // Applying a diff moves our sedimentree on from the summary the
+                    // token covers, so it's only good after a round that didn't
+                    let token = unchanged.then_some(summary_token);
+                    self.peer_sync.got_token(peer_id, id, token, version);
+                    if let Some(heads) = self.get_heads(id).await {
+                        self.peer_sync.synced(peer_id, id, heads);
+                    }
+                    self.finish_round(report, started, &span);
+                    had_success = true;
+                    break;
+                }
+            }
+        }
+
+        lead.finish(had_success);
+        Ok((had_success, conn_errs))
+    }
+
+    /// A batch sync request for sedimentree `id` to `peer_id`, and the version of our
+    /// sedimentree it summarizes.
+    async fn batch_sync_request(
+        &self,
+        peer_id: PeerId,
+        id: SedimentreeId,
+        req_id: RequestId,
+    ) -> (BatchSyncRequest, u64) {
+        let trees = self.sedimentrees.lock().await;
+        let tree = trees.get(&id);
+        let mut summary = tree
+            .map(|tree| (*self.summaries.summarize(id, tree)).clone())
+            .unwrap_or_default();
+        let version = self.summaries.version(id);
+
+        // A long loose history is cheaper to send as a filter than to list
+        let have = tree
+            .filter(|tree| tree.loose_commits().count() >= FILTER_MIN_COMMITS)
+            .map(filter::have);
+        // Otherwise, what the peer held after our last round needn't be listed
+        let frontier = self.peer_sync.frontier(peer_id, id);
+        let settled = tree
+            .filter(|_| have.is_none())
+            .and_then(|tree| session::settled(tree, &frontier));
+        if have.is_some() {
+            summary = SedimentreeSummary::new(summary.chunk_summaries().clone(), BTreeSet::new());
+        } else if let Some(settled) = &settled {
+            let commits = summary
+                .loose_commits()
+                .iter()
+                .filter(|commit| !settled.contains(&commit.digest()))
+                .cloned()
+                .collect();
+            summary = SedimentreeSummary::new(summary.chunk_summaries().clone(), commits);
+        }
+
+        let request = BatchSyncRequest {
+            id,
+            req_id,
+            sedimentree_summary: summary,
+            summary_token: self.peer_sync.token(peer_id, id, version),
+            have,
+            since: if settled.is_some() {
+                frontier
+            } else {
+                Vec::new()
+            },
+        };
+        (request, version)
+    }
+
+    /// Push `conn` the loose commits of `id` that a peer lacks: those the filter of
+    /// `their_have` doesn't hold, and those at or before `left_out`, which it didn't
+    /// hold all of. Returns how many were pushed and the size of their blobs.
+    async fn push_missing(
+        &self,
+        conn: &C,
+        id: SedimentreeId,
+        their_have: Option<&Have>,
+        left_out: &[Digest],
+    ) -> Result<(usize, u64), IoError<F, S, C>> {
+        if their_have.is_none() && left_out.is_empty() {
+            return Ok((0, 0));
+        }
+        let missing = {
+            let trees = self.sedimentrees.lock().await;
+            let Some(tree) = trees.get(&id) else {
+                return Ok((0, 0));
+            };
+            let left_out = session::settled(tree, left_out).unwrap_or_default();
+            let mut missing = their_have
+                .map(|their_have| filter::missing(tree, their_have))
+                .unwrap_or_default();
+            missing.extend(
+                tree.loose_commits()
+                    .filter(|commit| left_out.contains(&commit.digest())),
+            );
+            missing.into_iter().cloned().collect::<Vec<_>>()
+        };
+        let (with_blobs, _, missing_blobs) = self
+            .load_with_blobs(missing.iter().collect(), Vec::new())
+            .await?;
+        if !missing_blobs.is_empty() {
+            self.request_blobs(missing_blobs).await;
+        }
+
+        let mut bytes = 0;
+        for (commit, blob) in &with_blobs {
+            self.push_commit(conn, id, commit, blob)
+                .await
+                .map_err(IoError::ConnSend)?;
+            bytes += commit.blob().size_bytes();
+        }
+        Ok((with_blobs.len(), bytes))
+    }
+
+    /// Time a finished round from `started`, trace it on `span`, and hand it to the
+    /// report sink, if any.
+    fn finish_round(&self, mut report: SyncReport, started: Option<Duration>, span: &Span) {
+        if let (Some(sink), Some(started)) = (&self.reports, started) {
+            report.duration = sink.now().saturating_sub(started);
+        }
+        report.trace(span);
+        if let Some(sink) = &self.reports {
+            sink.report(report);
+        }
+    }
+
     /// Request a batch sync from all connected peers for all known sedimentree IDs.
     ///
     /// # Returns
@@ -1065,6 +2835,13 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
             .map(|tree| tree.loose_commits().cloned().collect())
     }
 
+    /// Get the heads of a given sedimentree: the commits nothing else builds on.
+    ///
+    /// See [`Sedimentree::heads`] for how chunks count towards them.
+    pub async fn get_heads(&self, id: SedimentreeId) -> Option<Vec<Digest>> {
+        self.sedimentrees.lock().await.get(&id).map(Sedimentree::heads)
+    }
+
     /// Get all chunks for a given sedimentree ID.
     pub async fn get_chunks(&self, id: SedimentreeId) -> Option<Vec<Chunk>> {
         self.sedimentrees
@@ -1074,6 +2851,293 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
             .map(|tree| tree.chunks().cloned().collect())
     }
 
+    /// Drop the loose commits of sedimentree `id` whose digests are in `commits`, such as
+    /// ones its chunks already hold. Returns the digests of the commits it held.
+    ///
+    /// Dropped commits are no longer served to peers, but, unlike those
+    /// [`Subduction::run_lifecycle`] drops, are kept in local storage.
+    pub async fn drop_loose_commits(
+        &self,
+        id: SedimentreeId,
+        commits: &HashSet<Digest>,
+    ) -> Vec<Digest> {
+        let mut trees = self.sedimentrees.lock().await;
+        let Some(tree) = trees.get_mut(&id) else {
+            return Vec::new();
+        };
+        let (dropped, kept): (Vec<_>, Vec<_>) = tree
+            .loose_commits()
+            .cloned()
+            .partition(|commit| commits.contains(&commit.digest()));
+        if dropped.is_empty() {
+            return Vec::new();
+        }
+        *tree = Sedimentree::new(tree.chunks().cloned().collect(), kept);
+        self.summaries.invalidate(id);
+        dropped.iter().map(LooseCommit::digest).collect()
+    }
+
+    /// Export one sedimentree, or all of them, to a `SQLite` database at `path`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ExportError`][crate::export::ExportError] if the database can't be written.
+    #[cfg(feature = "sqlite")]
+    pub async fn export_sqlite(
+        &self,
+        path: impl AsRef<std::path::Path>,
+        id: Option<SedimentreeId>,
+    ) -> Result<crate::export::ExportSummary, crate::export::ExportError> {
+        let trees = self.sedimentrees.lock().await;
+        crate::export::export_sqlite(
+            path,
+            trees
+                .iter()
+                .filter(|(tree_id, _)| id.is_none_or(|wanted| wanted == **tree_id))
+                .map(|(tree_id, tree)| (*tree_id, tree)),
+        )
+    }
+
+    /// Apply lifecycle `rules` to every held sedimentree, as of `now`.
+    ///
+    /// Idle sedimentrees are dropped, after being copied to `archive` if their policy asks
+    /// for it. Sedimentrees whose policy asks for archiving are kept if no `archive` is given.
+    /// Loose commits past the maximum history age are dropped, except for heads. So are
+    /// loose commits of ephemeral sedimentrees past their TTL (see [`Subduction::set_ttl`]),
+    /// heads included.
+    ///
+    /// Dropped data is no longer served to peers, and is deleted from local storage,
+    /// except for blobs a sedimentree still held has a commit or chunk of.
+    ///
+    /// # Errors
+    ///
+    /// * [`LifecycleError`] if data can't be read from or deleted in local storage, or
+    ///   written to the archive.
+    pub async fn run_lifecycle<A: Storage<F>>(
+        &self,
+        rules: &LifecycleRules,
+        now: SystemTime,
+        archive: Option<&A>,
+    ) -> Result<LifecycleReport, LifecycleError<F, S, A>> {
+        let (documents_evaluated, planned) = {
+            let trees = self.sedimentrees.lock().await;
+            let planned = self.lifecycle.lock().await.plan(&trees, rules, now);
+            (trees.len(), planned)
+        };
+
+        let mut actions = Vec::new();
+        let mut dropped = HashSet::new();
+        for (id, action) in planned {
+            match action {
+                LifecycleAction::Expired { idle_for } => {
+                    let action = if rules.policy_for(id).archive_on_expiry {
+                        let Some(archive) = archive else {
+                            tracing::warn!(
+                                "Not expiring sedimentree {:?}: no archive to copy it to",
+                                id
+                            );
+                            continue;
+                        };
+                        self.archive_sedimentree(id, archive).await?;
+                        LifecycleAction::Archived { idle_for }
+                    } else {
+                        LifecycleAction::Expired { idle_for }
+                    };
+
+                    if let Some(tree) = self.sedimentrees.lock().await.remove(&id) {
+                        dropped.extend(blob_digests(&tree));
+                    }
+                    self.summaries.forget(id);
+                    self.lifecycle.lock().await.forget(id);
+                    actions.push((id, action));
+                }
+                LifecycleAction::HistoryPruned { ref commits }
+                | LifecycleAction::CommitsExpired { ref commits } => {
+                    let mut trees = self.sedimentrees.lock().await;
+                    if let Some(tree) = trees.get_mut(&id) {
+                        let pruned = commits.iter().copied().collect::<HashSet<_>>();
+                        let (gone, kept): (Vec<_>, Vec<_>) = tree
+                            .loose_commits()
+                            .cloned()
+                            .partition(|commit| pruned.contains(&commit.digest()));
+                        dropped.extend(gone.iter().map(|commit| commit.blob().digest()));
+                        *tree = Sedimentree::new(tree.chunks().cloned().collect(), kept);
+                        self.summaries.invalidate(id);
+                        if matches!(action, LifecycleAction::CommitsExpired { .. }) {
+                            self.lifecycle.lock().await.record_expired(id, commits, now);
+                        }
+                        actions.push((id, action));
+                    }
+                }
+                LifecycleAction::Archived { .. } => {}
+            }
+        }
+
+        if !dropped.is_empty() {
+            for tree in self.sedimentrees.lock().await.values() {
+                for digest in blob_digests(tree) {
+                    dropped.remove(&digest);
+                }
+            }
+            self.storage
+                .delete_blobs(dropped.into_iter().collect())
+                .await
+                .map_err(LifecycleError::Storage)?;
+        }
+
+        let report = LifecycleReport {
+            evaluated_at: now,
+            documents_evaluated,
+            actions,
+        };
+        tracing::info!(
+            "Lifecycle run evaluated {} sedimentree(s) and took {} action(s)",
+            report.documents_evaluated,
+            report.actions.len()
+        );
+        self.lifecycle.lock().await.set_last_report(report.clone());
+        Ok(report)
+    }
+
+    /// The report from the most recent [`Subduction::run_lifecycle`], if any.
+    pub async fn last_lifecycle_report(&self) -> Option<LifecycleReport> {
+        self.lifecycle.lock().await.last_report().cloned()
+    }
+
+    /// Activity bookkeeping for a sedimentree, as of the most recent lifecycle run.
+    pub async fn document_activity(&self, id: SedimentreeId) -> Option<DocumentActivity> {
+        self.lifecycle.lock().await.activity(id).cloned()
+    }
+
+    /// Make the sedimentree `id` ephemeral, so that its commits expire once they're older
+    /// than `ttl`, or not with `None`, and tell the connected peers subscribed to it.
+    ///
+    /// Peers pass the TTL on as they sync, and each expires commits as it runs its own
+    /// lifecycle, so the maintenance interval bounds how long past the TTL a commit lives.
+    ///
+    /// # Errors
+    ///
+    /// * [`IoError`] if the TTL can't be sent to a peer.
+    pub async fn set_ttl(
+        &self,
+        id: SedimentreeId,
+        ttl: Option<Duration>,
+    ) -> Result<(), IoError<F, S, C>> {
+        if !self.lifecycle.lock().await.set_ttl(id, ttl) {
+            return Ok(());
+        }
+        let locked = self.conn_manager.lock().await;
+        for conn in locked
+            .subscribed(id, self.subscription_policy.as_deref())
+            .filter(|conn| self.admits(conn.peer_id(), id))
+        {
+            conn.send(Message::document_ttl(id, ttl))
+                .await
+                .map_err(IoError::ConnSend)?;
+        }
+        Ok(())
+    }
+
+    /// The TTL of the sedimentree `id`, if it's ephemeral.
+    pub async fn ttl(&self, id: SedimentreeId) -> Option<Duration> {
+        self.lifecycle.lock().await.ttl(id)
+    }
+
+    /// Take the TTL of sedimentree `id` from `from`, passing it on to other peers if it
+    /// changed.
+    async fn recv_ttl(
+        &self,
+        from: PeerId,
+        id: SedimentreeId,
+        ttl_secs: Option<u64>,
+    ) -> Result<(), IoError<F, S, C>> {
+        if self.is_read_only(id) {
+            tracing::warn!("Ignoring TTL from peer {:?} for read-only {:?}", from, id);
+            return Ok(());
+        }
+        let ttl = ttl_secs.map(Duration::from_secs);
+        if !self.lifecycle.lock().await.set_ttl(id, ttl) {
+            return Ok(());
+        }
+        let locked = self.conn_manager.lock().await;
+        for conn in locked.subscribed(id, self.subscription_policy.as_deref()) {
+            if conn.peer_id() != from && self.admits(conn.peer_id(), id) {
+                conn.send(Message::document_ttl(id, ttl)).await.map_err(IoError::ConnSend)?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn archive_sedimentree<A: Storage<F>>(
+        &self,
+        id: SedimentreeId,
+        archive: &A,
+    ) -> Result<(), LifecycleError<F, S, A>> {
+        let (commits, chunks) = {
+            let trees = self.sedimentrees.lock().await;
+            let Some(tree) = trees.get(&id) else {
+                return Ok(());
+            };
+            (
+                tree.loose_commits().cloned().collect::<Vec<_>>(),
+                tree.chunks().cloned().collect::<Vec<_>>(),
+            )
+        };
+
+        for commit in commits {
+            self.archive_blob(commit.blob().digest(), archive).await?;
+            archive
+                .save_loose_commit(commit)
+                .await
+                .map_err(LifecycleError::Archive)?;
+        }
+
+        for chunk in chunks {
+            self.archive_blob(chunk.summary().blob_meta().digest(), archive)
+                .await?;
+            archive
+                .save_chunk(chunk)
+                .await
+                .map_err(LifecycleError::Archive)?;
+        }
+
+        Ok(())
+    }
+
+    async fn archive_blob<A: Storage<F>>(
+        &self,
+        digest: Digest,
+        archive: &A,
+    ) -> Result<(), LifecycleError<F, S, A>> {
+        let blob = self
+            .storage
+            .load_blob(digest)
+            .await
+            .map_err(LifecycleError::Storage)?
+            .ok_or(LifecycleError::MissingBlob(digest))?;
+        archive
+            .save_blob(blob)
+            .await
+            .map_err(LifecycleError::Archive)?;
+        Ok(())
+    }
+
+    /// How the sedimentree `id` stands with `peer`: the batch syncs outstanding with it,
+    /// the heads it's known to have, and how many batch syncs with it have finished.
+    ///
+    /// Its heads are learned from batch syncs, and moved along by commits it pushes.
+    #[must_use]
+    pub fn peer_sync_status(&self, peer: PeerId, id: SedimentreeId) -> PeerSyncStatus {
+        self.peer_sync.status(peer, id)
+    }
+
+    /// Whether `peer` is known to have exactly the heads we have of the sedimentree `id`,
+    /// with no batch sync with it outstanding.
+    pub async fn is_synced_with(&self, peer: PeerId, id: SedimentreeId) -> bool {
+        let heads = self.get_heads(id).await.unwrap_or_default();
+        self.peer_sync.status(peer, id).matches(&heads)
+    }
+
     /// Get the set of all connected peer IDs.
     pub async fn peer_ids(&self) -> HashSet<PeerId> {
         self.conn_manager
@@ -1089,6 +3153,27 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
      * PRIVATE METHODS *
      *******************/
 
+    /// Whether sedimentree `id` already holds the pushed `held`.
+    async fn holds(&self, id: SedimentreeId, held: &Held) -> bool {
+        match held {
+            Held::Commit(commit, _) => self.holds_commit(id, commit).await,
+            Held::Chunk(chunk, _) => self
+                .sedimentrees
+                .lock()
+                .await
+                .get(&id)
+                .is_some_and(|tree| tree.contains_chunk(chunk)),
+        }
+    }
+
+    async fn holds_commit(&self, id: SedimentreeId, commit: &LooseCommit) -> bool {
+        self.sedimentrees
+            .lock()
+            .await
+            .get(&id)
+            .is_some_and(|tree| tree.contains_commit(commit))
+    }
+
     async fn insert_commit_locally(
         &self,
         id: SedimentreeId,
@@ -1096,20 +3181,108 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
         blob: Blob,
     ) -> Result<bool, S::Error> {
         tracing::debug!("Inserting commit {:?} locally", commit.digest());
-        {
+        if self.lifecycle.lock().await.is_expired(id, commit.digest()) {
+            tracing::debug!("Not taking back expired commit {:?}", commit.digest());
+            return Ok(false);
+        }
+        let due = {
             let mut sed = self.sedimentrees.lock().await;
             let tree = sed.entry(id).or_default();
             if !tree.add_commit(commit.clone()) {
                 return Ok(false);
             }
+            self.compaction
+                .as_ref()
+                .and_then(|compaction| compaction.check(id, tree))
+        };
+        self.summaries.invalidate(id);
+        self.lifecycle.lock().await.touch(id);
+        if let (Some(compaction), Some(task)) = (&self.compaction, due) {
+            compaction.schedule(task);
         }
 
-        self.storage.save_loose_commit(commit).await?;
+        #[cfg(feature = "perf-trace")]
+        let _span = sedimentree_core::perf::span(sedimentree_core::perf::Op::StoragePut);
+        // The blob first, so that storage never holds a commit without it
         self.storage.save_blob(blob).await?;
+        self.storage.save_loose_commit(commit).await?;
 
         Ok(true)
     }
 
+    /// Apply a sync diff from `from` newest first: the loose commits, then the chunks
+    /// from the shallowest strata to the deepest, reporting progress along the way,
+    /// and expect `backfill` more chunks to follow it (see [`bootstrap`]).
+    ///
+    /// Items the scanner (if any) doesn't accept are left out.
+    async fn apply_diff(
+        &self,
+        from: &PeerId,
+        id: SedimentreeId,
+        diff: SyncDiff,
+        backfill: u64,
+    ) -> Result<(), IoError<F, S, C>> {
+        if self.is_frozen(id) {
+            tracing::warn!("Ignoring synced history for frozen sedimentree {:?}", id);
+            return Ok(());
+        }
+        let mut missing_commits = Vec::with_capacity(diff.missing_commits.len());
+        for (commit, blob) in diff.missing_commits {
+            if let Some(Held::Commit(commit, blob)) =
+                self.screen(*from, id, Held::Commit(commit, blob)).await
+            {
+                missing_commits.push((commit, blob));
+            }
+        }
+        let mut missing_chunks = Vec::with_capacity(diff.missing_chunks.len());
+        for (chunk, blob) in diff.missing_chunks {
+            if let Some(Held::Chunk(chunk, blob)) =
+                self.screen(*from, id, Held::Chunk(chunk, blob)).await
+            {
+                missing_chunks.push((chunk, blob));
+            }
+        }
+        bootstrap::newest_first(&mut missing_chunks);
+
+        let mut progress = BootstrapProgress {
+            id,
+            phase: BootstrapPhase::HeadsReady,
+            commits: missing_commits.len(),
+            chunks_applied: 0,
+            chunks_total: missing_chunks.len(),
+        };
+
+        for (commit, blob) in missing_commits {
+            self.insert_commit_locally(id, commit, blob)
+                .await
+                .map_err(IoError::Storage)?;
+        }
+        self.report_progress(progress);
+
+        progress.phase = BootstrapPhase::Backfilling;
+        for (chunk, blob) in missing_chunks {
+            self.insert_chunk_locally(id, chunk, blob)
+                .await
+                .map_err(IoError::Storage)?;
+            progress.chunks_applied += 1;
+            self.report_progress(progress);
+        }
+
+        let progress = self
+            .backfills
+            .lock()
+            .await
+            .expect(*from, progress, backfill);
+        self.report_progress(progress);
+        Ok(())
+    }
+
+    fn report_progress(&self, progress: BootstrapProgress) {
+        if let Some(sink) = &self.progress {
+            sink.progress(progress);
+        }
+    }
+
     // NOTE no integrity checking, we assume that they made a good chunk at the right depth
     async fn insert_chunk_locally(
         &self,
@@ -1124,9 +3297,13 @@ impl<F: FutureKind, S: Storage<F>, C: Connection<F> + PartialEq> Subduction<F, S
                 return Ok(false);
             }
         }
+        self.summaries.invalidate(id);
+        self.lifecycle.lock().await.touch(id);
 
-        self.storage.save_chunk(chunk).await?;
+        #[cfg(feature = "perf-trace")]
+        let _span = sedimentree_core::perf::span(sedimentree_core::perf::Op::StoragePut);
         self.storage.save_blob(blob).await?;
+        self.storage.save_chunk(chunk).await?;
         Ok(true)
     }
 }
@@ -1144,6 +3321,7 @@ struct ConnectionManager<C> {
     next_id: ConnectionId,
     connections: HashMap<ConnectionId, C>,
     unstarted: HashSet<ConnectionId>,
+    subscriptions: Subscriptions,
 }
 
 impl<C> ConnectionManager<C> {
@@ -1155,4 +3333,39 @@ impl<C> ConnectionManager<C> {
         self.next_id = id.wrapping_add(1).into();
         id.into()
     }
+
+    fn remove(&mut self, conn_id: ConnectionId) -> Option<C> {
+        self.unstarted.remove(&conn_id);
+        self.subscriptions.remove(conn_id);
+        self.connections.remove(&conn_id)
+    }
+
+    /// The connections that want writes to the sedimentree `id` pushed to them, and
+    /// that `policy` allows them if they haven't subscribed.
+    fn subscribed<'a, F: FutureKind>(
+        &'a self,
+        id: SedimentreeId,
+        policy: Option<&'a dyn SubscriptionPolicy>,
+    ) -> impl Iterator<Item = &'a C>
+    where
+        C: Connection<F>,
+    {
+        self.connections
+            .iter()
+            .filter(move |(conn_id, conn)| {
+                self.subscriptions
+                    .wants(**conn_id, conn.peer_id(), id, policy)
+            })
+            .map(|(_, conn)| conn)
+    }
+}
+
+/// The digests of the blobs `tree`'s commits and chunks are stored by.
+fn blob_digests(tree: &Sedimentree) -> impl Iterator<Item = Digest> + '_ {
+    tree.loose_commits()
+        .map(|commit| commit.blob().digest())
+        .chain(
+            tree.chunks()
+                .map(|chunk| chunk.summary().blob_meta().digest()),
+        )
 }
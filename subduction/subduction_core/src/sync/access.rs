@@ -0,0 +1,65 @@
+//! Passing requests for access to a document on to the peers that may grant it.
+//!
+//! A peer that isn't a member of a document can't sync it, so it asks for access
+//! with a [`Message::AccessRequest`], which it sends to its relays like any other
+//! message. A relay can't tell who administers a document, and doesn't read the
+//! request, which its requester signs for the document's admins to check. So it
+//! passes the request on over every connection subscribed to the document (see
+//! [`subscription`](super::subscription)) other than the one it came in on, and the
+//! peers it reaches keep the requests for documents they administer.
+//!
+//! Relays connected to each other pass requests on as well, so each relay only
+//! passes a request on the first time it sees it.
+//!
+//! [`Message::AccessRequest`]: crate::connection::message::Message::AccessRequest
+
+use std::collections::{HashSet, VecDeque};
+
+use sedimentree_core::Digest;
+
+/// How many requests a relay remembers having passed on.
+const REMEMBERED: usize = 1024;
+
+/// The access requests passed on most recently, by digest.
+#[derive(Debug, Default)]
+pub(crate) struct Forwarded {
+    seen: HashSet<Digest>,
+    order: VecDeque<Digest>,
+}
+
+impl Forwarded {
+    /// Remember `request`, returning whether it's the first time it was seen.
+    pub(crate) fn first_sight(&mut self, request: &[u8]) -> bool {
+        let digest = Digest::hash(request);
+        if !self.seen.insert(digest) {
+            return false;
+        }
+        self.order.push_back(digest);
+        while self.order.len() > REMEMBERED {
+            let Some(oldest) = self.order.pop_front() else {
+                break;
+            };
+            self.seen.remove(&oldest);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_each_request_on_once() {
+        let mut forwarded = Forwarded::default();
+        assert!(forwarded.first_sight(b"alice"));
+        assert!(!forwarded.first_sight(b"alice"));
+        assert!(forwarded.first_sight(b"bob"));
+
+        // Only the most recent are remembered
+        for n in 0..REMEMBERED {
+            forwarded.first_sight(&n.to_le_bytes());
+        }
+        assert!(forwarded.first_sight(b"alice"));
+    }
+}
@@ -0,0 +1,52 @@
+//! Sharing accepted writes between relay instances.
+//!
+//! Several relays behind a load balancer each hold their own connections. When
+//! one of them accepts a commit or chunk pushed by a peer, it publishes a
+//! [`BackplaneMessage`] to a [`Backplane`] (such as a Redis or NATS channel), and
+//! the others pass what they receive to [`Subduction::recv_backplane`], which
+//! stores the item and forwards it to their own peers. Peers connected to any
+//! instance then see the write straight away, without waiting for a sync round.
+//!
+//! Items received over the backplane are never published again, so instances
+//! don't echo each other's messages back and forth.
+//!
+//! [`Subduction::recv_backplane`]: crate::Subduction::recv_backplane
+
+use std::fmt::Debug;
+
+use sedimentree_core::{Blob, Chunk, LooseCommit, SedimentreeId};
+
+use crate::peer::id::PeerId;
+
+/// Somewhere to publish the writes a relay accepts, for its sibling instances.
+pub trait Backplane: Debug + Send + Sync {
+    /// Called with each commit or chunk accepted from a connected peer.
+    ///
+    /// This must not block; implementations should queue the message for delivery.
+    fn publish(&self, message: &BackplaneMessage);
+}
+
+/// A write accepted by one relay instance.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BackplaneMessage {
+    /// The sedimentree written to.
+    pub id: SedimentreeId,
+
+    /// The peer that pushed the write, which needn't be sent it back.
+    pub from: PeerId,
+
+    /// What was written.
+    pub item: BackplaneItem,
+}
+
+/// The item carried by a [`BackplaneMessage`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum BackplaneItem {
+    /// A loose commit and its blob.
+    Commit(LooseCommit, Blob),
+
+    /// A chunk and its blob.
+    Chunk(Chunk, Blob),
+}
@@ -0,0 +1,298 @@
+//! Syncing history newest first.
+//!
+//! A peer joining a large document is missing most of its history. Rather than
+//! sending it all in one [`BatchSyncResponse`], the responder answers with the
+//! loose commits (the most recent history, including the heads) and the chunks
+//! of the newest stratum it has to send, then streams the chunks of the older
+//! strata, from the shallowest to the deepest, in a [`Message::Backfill`] each.
+//! The response says how many follow.
+//!
+//! The requester applies the response as soon as it arrives, so an app listening
+//! for [`BootstrapPhase::HeadsReady`] can render the current state while the
+//! older strata are backfilled in the background, and is told as each arrives.
+//! It only takes backfill from a peer whose response announced it.
+//!
+//! [`BatchSyncResponse`]: crate::connection::message::BatchSyncResponse
+//! [`Message::Backfill`]: crate::connection::message::Message
+
+use std::{collections::HashMap, fmt::Debug};
+
+use sedimentree_core::{Blob, Chunk, SedimentreeId};
+
+use crate::peer::id::PeerId;
+
+/// Somewhere to report the progress of applying synced history.
+pub trait ProgressSink: Debug + Send + Sync {
+    /// Called once the heads are in place, and after each chunk is backfilled.
+    fn progress(&self, progress: BootstrapProgress);
+}
+
+/// How far along applying a sync for one sedimentree is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BootstrapProgress {
+    /// The sedimentree being synced.
+    pub id: SedimentreeId,
+
+    /// Which part of the history is being applied.
+    pub phase: BootstrapPhase,
+
+    /// The number of loose commits applied.
+    pub commits: usize,
+
+    /// The number of chunks backfilled so far.
+    pub chunks_applied: usize,
+
+    /// The number of chunks to backfill in total, counting those announced but yet
+    /// to arrive.
+    pub chunks_total: usize,
+}
+
+/// The stages of applying synced history.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BootstrapPhase {
+    /// The loose commits have been applied, so the latest state can be shown.
+    HeadsReady,
+
+    /// Older strata are being backfilled.
+    Backfilling,
+
+    /// Everything has been applied.
+    Complete,
+}
+
+/// Order chunks from the shallowest (most recent) strata to the deepest (oldest).
+pub fn newest_first(chunks: &mut [(Chunk, Blob)]) {
+    chunks.sort_by_key(|(chunk, _)| chunk.depth());
+}
+
+/// Order `chunks` newest first, and split off those after the newest stratum, to
+/// follow the response as backfill.
+pub fn split_backfill(chunks: &mut Vec<(Chunk, Blob)>) -> Vec<(Chunk, Blob)> {
+    newest_first(chunks);
+    let newest = chunks.first().map(|(chunk, _)| chunk.depth());
+    let older = chunks
+        .iter()
+        .position(|(chunk, _)| Some(chunk.depth()) != newest)
+        .unwrap_or(chunks.len());
+    chunks.split_off(older)
+}
+
+/// The backfills in progress, by the peer sending them and the sedimentree.
+///
+/// Backfill can overtake the response announcing it, since the response goes to
+/// the caller waiting on it and the backfill to the run loop, so chunks that
+/// arrive while a request is waiting for its response are counted when it comes.
+#[derive(Debug, Default)]
+pub(crate) struct Backfills {
+    expected: HashMap<(PeerId, SedimentreeId), Backfill>,
+}
+
+#[derive(Debug, Default)]
+struct Backfill {
+    /// How far along the announced backfill is, once some has been announced.
+    progress: Option<BootstrapProgress>,
+    remaining: u64,
+    /// Chunks that arrived before the response announcing them.
+    early: u64,
+    /// Requests still waiting for their response.
+    waiting: usize,
+}
+
+impl Backfills {
+    /// Note a request to `from` for sedimentree `id`, whose backfill may arrive before
+    /// its response.
+    pub(crate) fn requested(&mut self, from: PeerId, id: SedimentreeId) {
+        self.expected.entry((from, id)).or_default().waiting += 1;
+    }
+
+    /// Note that a request to `from` got no response.
+    pub(crate) fn cancelled(&mut self, from: PeerId, id: SedimentreeId) {
+        if let Some(backfill) = self.expected.get_mut(&(from, id)) {
+            backfill.waiting = backfill.waiting.saturating_sub(1);
+            self.remove_if_done(from, id);
+        }
+    }
+
+    /// Expect `remaining` more chunks from `from` after a response applied as far as
+    /// `progress`, returning the progress to report.
+    pub(crate) fn expect(
+        &mut self,
+        from: PeerId,
+        mut progress: BootstrapProgress,
+        remaining: u64,
+    ) -> BootstrapProgress {
+        let key = (from, progress.id);
+        let backfill = self.expected.entry(key).or_default();
+        backfill.waiting = backfill.waiting.saturating_sub(1);
+        // Another round's backfill may still be on its way
+        if let Some(ongoing) = backfill.progress.filter(|_| backfill.remaining > 0) {
+            progress.commits += ongoing.commits;
+            progress.chunks_applied += ongoing.chunks_applied;
+            progress.chunks_total += ongoing.chunks_total;
+        }
+        let arrived = backfill.early.min(remaining);
+        backfill.early -= arrived;
+        backfill.remaining += remaining - arrived;
+        progress.chunks_applied += usize::try_from(arrived).unwrap_or(usize::MAX);
+        progress.chunks_total += usize::try_from(remaining).unwrap_or(usize::MAX);
+        progress.phase = if backfill.remaining == 0 {
+            BootstrapPhase::Complete
+        } else {
+            BootstrapPhase::Backfilling
+        };
+        backfill.progress = Some(progress);
+        self.remove_if_done(from, progress.id);
+        progress
+    }
+
+    /// Whether a chunk `from` backfilled of sedimentree `id` was asked for.
+    pub(crate) fn expects(&self, from: PeerId, id: SedimentreeId) -> bool {
+        self.expected.contains_key(&(from, id))
+    }
+
+    /// Count a chunk `from` backfilled, returning the progress to report, or `None` if
+    /// its response hasn't arrived yet or it wasn't asked for.
+    pub(crate) fn received(
+        &mut self,
+        from: PeerId,
+        id: SedimentreeId,
+    ) -> Option<BootstrapProgress> {
+        let backfill = self.expected.get_mut(&(from, id))?;
+        if backfill.remaining == 0 {
+            backfill.early += 1;
+            return None;
+        }
+        backfill.remaining -= 1;
+        let progress = backfill.progress.as_mut()?;
+        progress.chunks_applied += 1;
+        if backfill.remaining == 0 {
+            progress.phase = BootstrapPhase::Complete;
+        }
+        let progress = *progress;
+        self.remove_if_done(from, id);
+        Some(progress)
+    }
+
+    /// Stop expecting anything from `from`, whose connection went away.
+    pub(crate) fn forget(&mut self, from: PeerId) {
+        self.expected.retain(|(peer, _), _| *peer != from);
+    }
+
+    fn remove_if_done(&mut self, from: PeerId, id: SedimentreeId) {
+        let done = self
+            .expected
+            .get(&(from, id))
+            .is_some_and(|backfill| backfill.remaining == 0 && backfill.waiting == 0);
+        if done {
+            self.expected.remove(&(from, id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nonempty::nonempty;
+    use sedimentree_core::{Depth, Digest};
+
+    use super::*;
+
+    /// A chunk whose head lands at `depth`.
+    fn chunk_at(depth: u32) -> Option<(Chunk, Blob)> {
+        let head = (0..=u16::MAX)
+            .map(|n| Digest::hash(&n.to_le_bytes()))
+            .find(|digest| Depth::from(digest) == Depth(depth))?;
+        let blob = Blob::new(vec![0; 4]);
+        Some((
+            Chunk::new(head, nonempty![Digest::hash(&[0])], Vec::new(), blob.meta()),
+            blob,
+        ))
+    }
+
+    #[test]
+    fn orders_shallow_strata_first() -> Result<(), &'static str> {
+        let mut chunks = [0, 1, 0]
+            .into_iter()
+            .map(chunk_at)
+            .collect::<Option<Vec<_>>>()
+            .ok_or("no digest at the wanted depth")?;
+        chunks.reverse();
+        newest_first(&mut chunks);
+
+        let depths = chunks
+            .iter()
+            .map(|(chunk, _)| chunk.depth())
+            .collect::<Vec<_>>();
+        assert_eq!(depths, vec![Depth(0), Depth(0), Depth(1)]);
+        Ok(())
+    }
+
+    #[test]
+    fn backfills_strata_after_the_newest() -> Result<(), &'static str> {
+        let mut chunks = [2, 0, 1, 0]
+            .into_iter()
+            .map(chunk_at)
+            .collect::<Option<Vec<_>>>()
+            .ok_or("no digest at the wanted depth")?;
+        let backfill = split_backfill(&mut chunks);
+
+        let depths = |chunks: &[(Chunk, Blob)]| {
+            chunks
+                .iter()
+                .map(|(chunk, _)| chunk.depth())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(depths(&chunks), vec![Depth(0), Depth(0)]);
+        assert_eq!(depths(&backfill), vec![Depth(1), Depth(2)]);
+        Ok(())
+    }
+
+    #[test]
+    fn tracks_backfill_until_it_has_all_arrived() {
+        let (peer, id) = (PeerId::new([1; 32]), SedimentreeId::new([2; 32]));
+        let mut backfills = Backfills::default();
+        let progress = BootstrapProgress {
+            id,
+            phase: BootstrapPhase::HeadsReady,
+            commits: 3,
+            chunks_applied: 1,
+            chunks_total: 1,
+        };
+
+        // Nothing asked for, nothing taken
+        assert!(!backfills.expects(peer, id));
+        backfills.requested(peer, id);
+        assert!(backfills.expects(peer, id));
+        assert!(!backfills.expects(PeerId::new([9; 32]), id));
+
+        // One chunk overtakes the response announcing three
+        assert_eq!(backfills.received(peer, id), None);
+        let started = backfills.expect(peer, progress, 3);
+        assert_eq!(
+            (started.phase, started.chunks_applied, started.chunks_total),
+            (BootstrapPhase::Backfilling, 2, 4)
+        );
+
+        let next = backfills
+            .received(peer, id)
+            .map(|p| (p.phase, p.chunks_applied));
+        assert_eq!(next, Some((BootstrapPhase::Backfilling, 3)));
+        let last = backfills
+            .received(peer, id)
+            .map(|p| (p.phase, p.chunks_applied));
+        assert_eq!(last, Some((BootstrapPhase::Complete, 4)));
+        assert!(!backfills.expects(peer, id));
+
+        // Nothing announced, nothing to wait for
+        backfills.requested(peer, id);
+        assert_eq!(
+            backfills.expect(peer, progress, 0).phase,
+            BootstrapPhase::Complete
+        );
+        assert!(!backfills.expects(peer, id));
+
+        // Nor after a request that got no response
+        backfills.requested(peer, id);
+        backfills.cancelled(peer, id);
+        assert!(!backfills.expects(peer, id));
+    }
+}
@@ -0,0 +1,76 @@
+//! Recognizing commits and chunks pushed again, by digest.
+//!
+//! Clients often push what a relay already holds, such as commits they re-send
+//! after reconnecting. A pushed item the sedimentree already holds is dropped as
+//! soon as it's recognized, before it's scanned, stored, forwarded, or audited.
+//! For a commit pushed in chunks (see [`upload`]), that's on its first piece: the
+//! receiver acknowledges the whole blob straight away, and the sender stops
+//! pushing the rest.
+//!
+//! [`upload`]: super::upload
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// The pushes recognized as duplicates since the instance was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DedupStats {
+    /// Commits and chunks pushed that were already held.
+    pub duplicates: u64,
+
+    /// The blob bytes of those pushes, which weren't scanned or stored again.
+    pub duplicate_bytes: u64,
+
+    /// Chunked uploads cut short because the commit was already held.
+    pub uploads_cut_short: u64,
+}
+
+/// Counters behind [`DedupStats`].
+#[derive(Debug, Default)]
+pub(crate) struct Dedup {
+    duplicates: AtomicU64,
+    duplicate_bytes: AtomicU64,
+    uploads_cut_short: AtomicU64,
+}
+
+impl Dedup {
+    /// Count a duplicate push of an item with a blob of `bytes`.
+    pub(crate) fn duplicate(&self, bytes: u64) {
+        self.duplicates.fetch_add(1, Ordering::Relaxed);
+        self.duplicate_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a chunked upload of a commit with a blob of `bytes` that was cut short.
+    pub(crate) fn upload_cut_short(&self, bytes: u64) {
+        self.duplicate(bytes);
+        self.uploads_cut_short.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn stats(&self) -> DedupStats {
+        DedupStats {
+            duplicates: self.duplicates.load(Ordering::Relaxed),
+            duplicate_bytes: self.duplicate_bytes.load(Ordering::Relaxed),
+            uploads_cut_short: self.uploads_cut_short.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cut_short_uploads_count_as_duplicates() {
+        let dedup = Dedup::default();
+        dedup.duplicate(10);
+        dedup.upload_cut_short(100);
+        assert_eq!(
+            dedup.stats(),
+            DedupStats {
+                duplicates: 2,
+                duplicate_bytes: 110,
+                uploads_cut_short: 1,
+            }
+        );
+    }
+}
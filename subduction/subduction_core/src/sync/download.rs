@@ -0,0 +1,613 @@
+//! Bookkeeping for blobs fetched in ranges from several peers at once.
+//!
+//! A [`BlobFetch`] remembers which peer it asked for each range it still waits
+//! for. When a peer answers that it doesn't hold the blob, sends a range that
+//! doesn't check out, or goes away, the ranges it was asked for go to the peers
+//! left, and the ranges already received are kept, so the download resumes where
+//! it was interrupted. A fetch with no peer left to ask is stalled, and dropped.
+//!
+//! Each range is a subtree of the blob's BLAKE3 tree, and comes with the chaining
+//! values of the subtrees beside it on the way up to the root (see
+//! [`range_proof`]). The range is checked against the blob's digest with them, so a
+//! peer can't pass off data of its own, whatever digest it names.
+
+use std::collections::{BTreeMap, HashSet};
+
+use blake3::{
+    hazmat::{self, HasherExt, Mode},
+    Hasher, CHUNK_LEN,
+};
+use sedimentree_core::{Blob, BlobMeta, Digest};
+
+use crate::peer::id::PeerId;
+
+/// The default size of each range requested from a peer.
+pub const DEFAULT_CHUNK_SIZE: u64 = 256 * 1024;
+
+/// A contiguous byte range of a blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BlobRange {
+    /// The offset of the first byte in the range.
+    pub offset: u64,
+
+    /// The number of bytes in the range.
+    pub length: u64,
+}
+
+/// A partially downloaded blob, assembled from ranges as they arrive.
+#[derive(Debug, Clone)]
+pub struct BlobDownload {
+    meta: BlobMeta,
+    received: BTreeMap<u64, Vec<u8>>,
+}
+
+impl BlobDownload {
+    /// Start tracking the download of the blob described by `meta`.
+    #[must_use]
+    pub const fn new(meta: BlobMeta) -> Self {
+        Self {
+            meta,
+            received: BTreeMap::new(),
+        }
+    }
+
+    /// Metadata for the blob being downloaded.
+    #[must_use]
+    pub const fn meta(&self) -> BlobMeta {
+        self.meta
+    }
+
+    /// Split the blob into ranges of at most `chunk_size` bytes.
+    #[must_use]
+    pub fn ranges(&self, chunk_size: u64) -> Vec<BlobRange> {
+        let chunk_size = chunk_size.max(1);
+        let size = self.meta.size_bytes();
+        let mut ranges = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let length = chunk_size.min(size - offset);
+            ranges.push(BlobRange { offset, length });
+            offset += length;
+        }
+        ranges
+    }
+
+    /// The ranges of at most `chunk_size` bytes that haven't been received in full.
+    #[must_use]
+    pub fn missing(&self, chunk_size: u64) -> Vec<BlobRange> {
+        self.ranges(chunk_size)
+            .into_iter()
+            .filter(|range| !self.covers(*range))
+            .collect()
+    }
+
+    /// Whether every byte of `range` has been received.
+    fn covers(&self, range: BlobRange) -> bool {
+        let end = range.offset.saturating_add(range.length);
+        let mut cursor = range.offset;
+        for (offset, data) in self.received.range(..end) {
+            if *offset > cursor {
+                return false;
+            }
+            cursor = cursor.max(offset + data.len() as u64);
+        }
+        cursor >= end
+    }
+
+    /// Record a range pushed by a peer, with the digest it claims for it.
+    ///
+    /// # Errors
+    ///
+    /// * [`ChunkRejected`] if the data doesn't match the digest the peer claimed for it,
+    ///   or if it falls outside of the blob.
+    pub fn insert(
+        &mut self,
+        offset: u64,
+        data: Vec<u8>,
+        chunk_digest: Digest,
+    ) -> Result<(), ChunkRejected> {
+        if Digest::hash(&data) != chunk_digest {
+            return Err(ChunkRejected::DigestMismatch(chunk_digest));
+        }
+
+        let end = offset.saturating_add(data.len() as u64);
+        if end > self.meta.size_bytes() {
+            return Err(ChunkRejected::OutOfBounds { offset, end });
+        }
+
+        self.received.insert(offset, data);
+        Ok(())
+    }
+
+    /// Record a range received from a peer, with the chaining values that lead from
+    /// it to the blob's digest (see [`range_proof`]).
+    ///
+    /// # Errors
+    ///
+    /// * [`ChunkRejected`] if it falls outside of the blob, or the proof doesn't lead
+    ///   from the data to the blob's digest.
+    pub fn insert_proven(
+        &mut self,
+        offset: u64,
+        data: Vec<u8>,
+        proof: &[Digest],
+    ) -> Result<(), ChunkRejected> {
+        let range = BlobRange {
+            offset,
+            length: data.len() as u64,
+        };
+        let end = offset.saturating_add(range.length);
+        if end > self.meta.size_bytes() {
+            return Err(ChunkRejected::OutOfBounds { offset, end });
+        }
+        if !verify_range(self.meta, range, &data, proof) {
+            return Err(ChunkRejected::Unproven { offset, end });
+        }
+
+        self.received.insert(offset, data);
+        Ok(())
+    }
+
+    /// Returns true once every byte of the blob has been received.
+    #[must_use]
+    pub fn is_complete(&self) -> bool {
+        self.received_bytes() == self.meta.size_bytes()
+    }
+
+    /// The number of bytes received from the start of the blob, up to the first gap.
+    #[must_use]
+    pub fn received_prefix(&self) -> u64 {
+        let mut cursor = 0;
+        for (offset, data) in &self.received {
+            if *offset > cursor {
+                break;
+            }
+            cursor = cursor.max(offset + data.len() as u64);
+        }
+        cursor
+    }
+
+    /// The number of distinct bytes received so far.
+    #[must_use]
+    pub fn received_bytes(&self) -> u64 {
+        let mut covered = 0;
+        let mut cursor = 0;
+        for (offset, data) in &self.received {
+            let end = offset + data.len() as u64;
+            if end > cursor {
+                covered += end - cursor.max(*offset);
+                cursor = end;
+            }
+        }
+        covered
+    }
+
+    /// Assemble the received ranges into a [`Blob`].
+    ///
+    /// # Errors
+    ///
+    /// * [`AssemblyError::Incomplete`] if some ranges are still missing.
+    /// * [`AssemblyError::DigestMismatch`] if the assembled bytes don't hash to the expected digest.
+    pub fn assemble(self) -> Result<Blob, AssemblyError> {
+        if !self.is_complete() {
+            return Err(AssemblyError::Incomplete(self.meta.digest()));
+        }
+
+        #[allow(clippy::cast_possible_truncation)]
+        let mut contents = Vec::with_capacity(self.meta.size_bytes() as usize);
+        for (offset, data) in self.received {
+            #[allow(clippy::cast_possible_truncation)]
+            let skip = (contents.len() as u64).saturating_sub(offset) as usize;
+            if skip < data.len() {
+                contents.extend_from_slice(&data[skip..]);
+            }
+        }
+
+        let blob = Blob::new(contents);
+        if blob.meta() == self.meta {
+            Ok(blob)
+        } else {
+            Err(AssemblyError::DigestMismatch(self.meta.digest()))
+        }
+    }
+}
+
+/// A blob being fetched in ranges, with the peer asked for each range still awaited.
+#[derive(Debug, Clone)]
+pub struct BlobFetch {
+    download: BlobDownload,
+    chunk_size: u64,
+    /// The ranges asked for and not yet received, by offset.
+    asked: BTreeMap<u64, PeerId>,
+    /// The peers that don't hold the blob, or sent a range that didn't check out.
+    declined: HashSet<PeerId>,
+}
+
+impl BlobFetch {
+    /// Start fetching the blob described by `meta`, `chunk_size` bytes at a time,
+    /// rounded up to a power of two [`CHUNK_LEN`]s so that each range is a subtree.
+    #[must_use]
+    pub fn new(meta: BlobMeta, chunk_size: u64) -> Self {
+        Self {
+            download: BlobDownload::new(meta),
+            chunk_size: chunk_size.max(CHUNK_LEN as u64).next_power_of_two(),
+            asked: BTreeMap::new(),
+            declined: HashSet::new(),
+        }
+    }
+
+    /// What has been received so far.
+    #[must_use]
+    pub const fn download(&self) -> &BlobDownload {
+        &self.download
+    }
+
+    /// Stop tracking the fetch, keeping what has been received.
+    #[must_use]
+    pub fn into_download(self) -> BlobDownload {
+        self.download
+    }
+
+    /// Share out the ranges no one is being asked for among `peers`, round-robin,
+    /// leaving out those that declined, and return which peer to ask for each.
+    pub fn assign(&mut self, peers: &[PeerId]) -> Vec<(PeerId, BlobRange)> {
+        let peers = peers
+            .iter()
+            .filter(|peer| !self.declined.contains(peer))
+            .collect::<Vec<_>>();
+        if peers.is_empty() {
+            return Vec::new();
+        }
+        let unasked = self
+            .download
+            .missing(self.chunk_size)
+            .into_iter()
+            .filter(|range| !self.asked.contains_key(&range.offset));
+        let assigned = unasked
+            .zip(peers.into_iter().cycle())
+            .map(|(range, peer)| (*peer, range))
+            .collect::<Vec<_>>();
+        for (peer, range) in &assigned {
+            self.asked.insert(range.offset, *peer);
+        }
+        assigned
+    }
+
+    /// Record a range received from `from`.
+    ///
+    /// # Errors
+    ///
+    /// * [`ChunkRejected`] if it doesn't check out (see [`BlobDownload::insert_proven`]),
+    ///   in which case `from` isn't asked again.
+    pub fn insert(
+        &mut self,
+        from: PeerId,
+        offset: u64,
+        data: Vec<u8>,
+        proof: &[Digest],
+    ) -> Result<(), ChunkRejected> {
+        if let Err(e) = self.download.insert_proven(offset, data, proof) {
+            self.decline(from);
+            return Err(e);
+        }
+        self.asked.remove(&offset);
+        Ok(())
+    }
+
+    /// `peer` doesn't hold the blob, or sent data that isn't part of it: forget what it was asked for, and don't ask it again.
+    pub fn decline(&mut self, peer: PeerId) {
+        self.abandon(peer);
+        self.declined.insert(peer);
+    }
+
+    /// `peer` went away: forget what it was asked for, so it can be asked of another.
+    pub fn abandon(&mut self, peer: PeerId) {
+        self.asked.retain(|_, asked| *asked != peer);
+    }
+
+    /// Whether the blob is incomplete, yet no one is being asked for any of it.
+    #[must_use]
+    pub fn is_stalled(&self) -> bool {
+        self.asked.is_empty() && !self.download.is_complete()
+    }
+}
+
+/// The chaining values of the subtrees beside `range` in the BLAKE3 tree of
+/// `contents`, from the root down, which lead from the range to the blob's digest.
+///
+/// Returns `None` unless `range` is a subtree of that tree, as the ranges a
+/// [`BlobFetch`] asks for are.
+#[must_use]
+pub fn range_proof(contents: &[u8], range: BlobRange) -> Option<Vec<Digest>> {
+    let path = subtree_path(contents.len() as u64, range)?;
+    let proof = path
+        .into_iter()
+        .map(|(_, beside)| {
+            #[allow(clippy::cast_possible_truncation)]
+            let data = &contents[beside.offset as usize..(beside.offset + beside.length) as usize];
+            Digest::from(chaining_value(beside.offset, data))
+        })
+        .collect();
+    Some(proof)
+}
+
+/// Whether `data`, found at `range` of the blob `meta`, leads through `proof` to
+/// the blob's digest.
+fn verify_range(meta: BlobMeta, range: BlobRange, data: &[u8], proof: &[Digest]) -> bool {
+    let Some(path) = subtree_path(meta.size_bytes(), range) else {
+        return false;
+    };
+    if path.len() != proof.len() {
+        return false;
+    }
+    if path.is_empty() {
+        return Digest::hash(data) == meta.digest();
+    }
+
+    let mut cv = chaining_value(range.offset, data);
+    for (depth, ((on_left, _), beside)) in path.iter().zip(proof).enumerate().rev() {
+        let (left, right) = if *on_left {
+            (&cv, beside.as_bytes())
+        } else {
+            (beside.as_bytes(), &cv)
+        };
+        if depth == 0 {
+            let root = hazmat::merge_subtrees_root(left, right, Mode::Hash);
+            return Digest::from(*root.as_bytes()) == meta.digest();
+        }
+        cv = hazmat::merge_subtrees_non_root(left, right, Mode::Hash);
+    }
+    false
+}
+
+/// The way from the root of the BLAKE3 tree of a `size`-byte blob down to the
+/// subtree `range`: at each parent, whether `range` is under its left child, and
+/// the other child. Returns `None` if `range` isn't a subtree.
+fn subtree_path(size: u64, range: BlobRange) -> Option<Vec<(bool, BlobRange)>> {
+    let end = range.offset.checked_add(range.length)?;
+    let mut node = BlobRange {
+        offset: 0,
+        length: size,
+    };
+    let mut path = Vec::new();
+    while node != range {
+        if node.length <= CHUNK_LEN as u64 {
+            return None;
+        }
+        let left_length = hazmat::left_subtree_len(node.length);
+        let left = BlobRange {
+            offset: node.offset,
+            length: left_length,
+        };
+        let right = BlobRange {
+            offset: node.offset + left_length,
+            length: node.length - left_length,
+        };
+        if end <= right.offset {
+            path.push((true, right));
+            node = left;
+        } else if range.offset >= right.offset {
+            path.push((false, left));
+            node = right;
+        } else {
+            return None;
+        }
+    }
+    Some(path)
+}
+
+/// The chaining value of the subtree holding `data`, which starts at `offset`.
+fn chaining_value(offset: u64, data: &[u8]) -> [u8; 32] {
+    Hasher::new()
+        .set_input_offset(offset)
+        .update(data)
+        .finalize_non_root()
+}
+
+/// A range received from a peer that could not be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ChunkRejected {
+    /// The data did not hash to the digest sent alongside it.
+    #[error("chunk data does not match digest {0}")]
+    DigestMismatch(Digest),
+
+    /// The data isn't part of the blob, by the proof sent alongside it.
+    #[error("chunk range {offset}..{end} does not lead to the blob's digest")]
+    Unproven {
+        /// The start of the rejected range.
+        offset: u64,
+
+        /// The end of the rejected range.
+        end: u64,
+    },
+
+    /// The range extends past the end of the blob.
+    #[error("chunk range {offset}..{end} is out of bounds")]
+    OutOfBounds {
+        /// The start of the rejected range.
+        offset: u64,
+
+        /// The end of the rejected range.
+        end: u64,
+    },
+}
+
+/// A problem assembling a downloaded blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum AssemblyError {
+    /// Not all ranges have been received yet.
+    #[error("blob {0} is incomplete")]
+    Incomplete(Digest),
+
+    /// The assembled blob does not hash to the expected digest.
+    #[error("assembled blob does not match digest {0}")]
+    DigestMismatch(Digest),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_out_of_order_ranges() {
+        let contents = (0..=255u8).cycle().take(1000).collect::<Vec<_>>();
+        let meta = BlobMeta::new(&contents);
+        let mut download = BlobDownload::new(meta);
+
+        let ranges = download.ranges(300);
+        assert_eq!(ranges.len(), 4);
+
+        for range in ranges.iter().rev() {
+            #[allow(clippy::cast_possible_truncation)]
+            let data =
+                contents[range.offset as usize..(range.offset + range.length) as usize].to_vec();
+            let digest = Digest::hash(&data);
+            assert!(download.insert(range.offset, data, digest).is_ok());
+        }
+
+        assert!(download.is_complete());
+        assert_eq!(download.assemble().map(Blob::into_contents), Ok(contents));
+    }
+
+    #[test]
+    fn received_prefix_stops_at_the_first_gap() {
+        let contents = vec![7u8; 300];
+        let mut download = BlobDownload::new(BlobMeta::new(&contents));
+        for offset in [0, 200] {
+            let data = contents[offset..offset + 100].to_vec();
+            let digest = Digest::hash(&data);
+            assert!(download.insert(offset as u64, data, digest).is_ok());
+        }
+        assert_eq!(download.received_prefix(), 100);
+        assert_eq!(download.received_bytes(), 200);
+    }
+
+    /// The bytes of `contents` in `range`, with their proof.
+    fn proven(contents: &[u8], range: BlobRange) -> (Vec<u8>, Vec<Digest>) {
+        #[allow(clippy::cast_possible_truncation)]
+        let data = contents[range.offset as usize..(range.offset + range.length) as usize].to_vec();
+        let proof = range_proof(contents, range).unwrap_or_default();
+        (data, proof)
+    }
+
+    #[test]
+    fn range_proofs_lead_to_the_digest() {
+        for size in [1, 1024, 1025, 3000, 4096, 5000, 9000] {
+            let contents = (0..=255u8).cycle().take(size).collect::<Vec<_>>();
+            let meta = BlobMeta::new(&contents);
+            for chunk_size in [1024, 2048] {
+                let mut download = BlobDownload::new(meta);
+                for range in download.ranges(chunk_size) {
+                    let (mut data, proof) = proven(&contents, range);
+                    data[0] ^= 1;
+                    assert_eq!(
+                        download.insert_proven(range.offset, data.clone(), &proof),
+                        Err(ChunkRejected::Unproven {
+                            offset: range.offset,
+                            end: range.offset + range.length
+                        })
+                    );
+                    data[0] ^= 1;
+                    assert!(download.insert_proven(range.offset, data, &proof).is_ok());
+                }
+                assert_eq!(
+                    download.assemble().map(Blob::into_contents).as_ref(),
+                    Ok(&contents)
+                );
+            }
+        }
+
+        // Only subtrees have a proof
+        let contents = vec![7u8; 4096];
+        let straddling = BlobRange {
+            offset: 512,
+            length: 1024,
+        };
+        assert_eq!(range_proof(&contents, straddling), None);
+    }
+
+    #[test]
+    fn declines_a_peer_sending_data_of_its_own() {
+        let contents = vec![7u8; 4096];
+        let forged = vec![8u8; 4096];
+        let (alice, bob) = (PeerId::new([1; 32]), PeerId::new([2; 32]));
+        let mut fetch = BlobFetch::new(BlobMeta::new(&contents), 1024);
+
+        // Alice's data checks out against her own proof and digests, but not against
+        // the blob's digest
+        let asked = fetch.assign(&[alice]);
+        assert_eq!(asked.len(), 4);
+        let (_, range) = asked[0];
+        let (data, proof) = proven(&forged, range);
+        assert!(fetch.insert(alice, range.offset, data, &proof).is_err());
+        assert!(fetch.is_stalled());
+
+        let resumed = fetch.assign(&[alice, bob]);
+        assert_eq!(resumed.len(), 4);
+        assert!(resumed.iter().all(|(peer, _)| *peer == bob));
+        for (peer, range) in resumed {
+            let (data, proof) = proven(&contents, range);
+            assert!(fetch.insert(peer, range.offset, data, &proof).is_ok());
+        }
+        assert_eq!(
+            fetch.into_download().assemble().map(Blob::into_contents),
+            Ok(contents)
+        );
+    }
+
+    #[test]
+    fn resumes_from_another_peer_after_an_interruption() {
+        let contents = (0..=255u8).cycle().take(4000).collect::<Vec<_>>();
+        let (alice, bob) = (PeerId::new([1; 32]), PeerId::new([2; 32]));
+        let mut fetch = BlobFetch::new(BlobMeta::new(&contents), 1000);
+        let send = |fetch: &mut BlobFetch, peer: PeerId, range: BlobRange| {
+            let (data, proof) = proven(&contents, range);
+            fetch.insert(peer, range.offset, data, &proof)
+        };
+
+        let asked = fetch.assign(&[alice, bob]);
+        assert_eq!(asked.len(), 4);
+        assert!(fetch.assign(&[alice, bob]).is_empty());
+
+        // Alice answers before going away, while Bob doesn't hold the blob
+        for (peer, range) in asked.iter().filter(|(peer, _)| *peer == alice) {
+            assert!(send(&mut fetch, *peer, *range).is_ok());
+        }
+        fetch.decline(bob);
+        assert!(fetch.is_stalled());
+        assert!(fetch.assign(&[bob]).is_empty());
+
+        // Carol picks up only what's missing
+        let carol = PeerId::new([3; 32]);
+        let resumed = fetch.assign(&[bob, carol]);
+        assert_eq!(
+            resumed,
+            asked
+                .iter()
+                .filter(|(peer, _)| *peer == bob)
+                .map(|(_, range)| (carol, *range))
+                .collect::<Vec<_>>()
+        );
+        for (peer, range) in resumed {
+            assert!(send(&mut fetch, peer, range).is_ok());
+        }
+        assert!(!fetch.is_stalled());
+        assert_eq!(
+            fetch.into_download().assemble().map(Blob::into_contents),
+            Ok(contents)
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_chunk() {
+        let contents = vec![7u8; 64];
+        let mut download = BlobDownload::new(BlobMeta::new(&contents));
+        let digest = Digest::hash(&contents);
+
+        assert_eq!(
+            download.insert(0, vec![8u8; 64], digest),
+            Err(ChunkRejected::DigestMismatch(digest))
+        );
+        assert!(!download.is_complete());
+    }
+}
@@ -1,6 +1,6 @@
 //! Error types for the top-level `Subduction`.
 
-use sedimentree_core::{future::FutureKind, storage::Storage, Digest};
+use sedimentree_core::{future::FutureKind, storage::Storage, BlobMeta, Digest};
 use thiserror::Error;
 
 use crate::connection::{Connection, ConnectionDisallowed};
@@ -52,5 +52,21 @@ pub enum ListenError<F: FutureKind, S: Storage<F>, C: Connection<F>> {
 
     /// Missing blobs associated with local chunks or commits.
     #[error("Missing blobs associated to local chunks & commits: {0:?}")]
-    MissingBlobs(Vec<Digest>),
+    MissingBlobs(Vec<BlobMeta>),
+}
+
+/// An error that can occur while applying lifecycle rules.
+#[derive(Debug, Error)]
+pub enum LifecycleError<F: FutureKind, S: Storage<F>, A: Storage<F>> {
+    /// An error occurred while reading from or deleting in local storage.
+    #[error("storage error: {0}")]
+    Storage(S::Error),
+
+    /// An error occurred while writing to archive storage.
+    #[error("archive error: {0}")]
+    Archive(A::Error),
+
+    /// A blob needed for archiving was missing locally.
+    #[error("missing blob: {0}")]
+    MissingBlob(Digest),
 }
@@ -0,0 +1,304 @@
+//! Have/want negotiation for batch syncs of sedimentrees with long loose histories.
+//!
+//! A [`SedimentreeSummary`](sedimentree_core::SedimentreeSummary) lists every
+//! loose commit, parents and all, so every round of a batch sync re-sends the
+//! whole loose history even when the two sides differ by a single commit. Once a
+//! requester holds [`FILTER_MIN_COMMITS`] loose commits, it sends a [`Have`]
+//! instead: a Bloom filter of its loose commits' digests, its heads, and the
+//! commits it knows it lacks. Its summary then only lists chunks.
+//!
+//! The responder sends back the loose commits the filter doesn't hold, along
+//! with every commit built on them, those of its heads that aren't the
+//! requester's, and the commits the requester needs. Its response carries a
+//! [`Have`] of its own, from which the requester picks the commits the responder
+//! lacks in the same way and pushes them.
+//!
+//! A Bloom filter claims to hold about one commit in a hundred that it doesn't.
+//! Sending everything built on a missing commit, and the heads, covers most of
+//! these. A commit that's still missed leaves its children with a parent the
+//! requester lacks, which it names as needed in its next request.
+//!
+//! A filter comes from the peer, so one that no peer following this scheme would
+//! make (with no bits, or too many, or setting no bits per commit or more than
+//! [`MAX_HASHES`]) isn't trusted to hold anything: every loose commit is sent, as
+//! if the peer had none.
+
+use std::collections::{HashMap, HashSet};
+
+use sedimentree_core::{Digest, LooseCommit, Sedimentree};
+
+use crate::connection::message::Have;
+
+/// The number of loose commits from which a requester sends a [`Have`] rather than
+/// listing its loose commits.
+pub const FILTER_MIN_COMMITS: usize = 64;
+
+/// Bits per commit, for about one false positive in a hundred.
+const BITS_PER_COMMIT: usize = 10;
+
+/// The number of bits each digest sets.
+const HASHES: u8 = 7;
+
+/// The most bits a filter from a peer may set for each digest.
+pub const MAX_HASHES: u8 = 16;
+
+/// The largest filter accepted from a peer, in bytes: enough for about 13 million
+/// loose commits.
+pub const MAX_FILTER_BYTES: usize = 1 << 24;
+
+/// A Bloom filter of commit digests.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CommitFilter {
+    bits: Vec<u8>,
+    hashes: u8,
+}
+
+impl CommitFilter {
+    /// A filter holding `digests`.
+    #[must_use]
+    pub fn new<'a>(digests: impl ExactSizeIterator<Item = &'a Digest>) -> Self {
+        let bytes = (digests.len() * BITS_PER_COMMIT).div_ceil(8).max(8);
+        let mut filter = Self {
+            bits: vec![0; bytes],
+            hashes: HASHES,
+        };
+        for digest in digests {
+            for bit in bits_of(digest, filter.hashes, bytes) {
+                filter.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        filter
+    }
+
+    /// Whether the filter may hold `digest`. It certainly doesn't if this is `false`.
+    #[must_use]
+    pub fn contains(&self, digest: &Digest) -> bool {
+        !self.bits.is_empty()
+            && bits_of(digest, self.hashes, self.bits.len())
+                .all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// Whether the filter is one a peer could have made with [`CommitFilter::new`],
+    /// which sets between 1 and [`MAX_HASHES`] bits per digest in at most
+    /// [`MAX_FILTER_BYTES`] bytes.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_HASHES).contains(&self.hashes) && (1..=MAX_FILTER_BYTES).contains(&self.bits.len())
+    }
+
+    /// The size of the filter, in bytes.
+    #[must_use]
+    pub const fn len_bytes(&self) -> usize {
+        self.bits.len()
+    }
+}
+
+/// The bits `digest` sets in a filter of `len` bytes.
+fn bits_of(digest: &Digest, hashes: u8, len: usize) -> impl Iterator<Item = usize> + use<> {
+    // Digests are BLAKE3 hashes, so their first two words make independent probes
+    let bytes = digest.as_bytes();
+    let mut first = [0; 8];
+    let mut second = [0; 8];
+    first.copy_from_slice(&bytes[..8]);
+    second.copy_from_slice(&bytes[8..16]);
+    let first = u64::from_le_bytes(first);
+    let second = u64::from_le_bytes(second) | 1;
+    let len = len as u64 * 8;
+
+    #[allow(clippy::cast_possible_truncation)]
+    (0..u64::from(hashes)).map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % len) as usize)
+}
+
+/// What `tree` holds of its loose history, for a peer to pick what it lacks from.
+#[must_use]
+pub fn have(tree: &Sedimentree) -> Have {
+    let commits = tree.loose_commits().collect::<Vec<_>>();
+    let digests = commits
+        .iter()
+        .map(|commit| commit.digest())
+        .collect::<Vec<_>>();
+
+    let known = digests
+        .iter()
+        .copied()
+        .chain(tree.chunks().flat_map(|chunk| {
+            chunk
+                .boundary()
+                .iter()
+                .chain(chunk.checkpoints())
+                .copied()
+                .chain([chunk.head()])
+        }))
+        .collect::<HashSet<_>>();
+    let mut need = commits
+        .iter()
+        .flat_map(|commit| commit.parents())
+        .filter(|parent| !known.contains(parent))
+        .copied()
+        .collect::<Vec<_>>();
+    need.sort();
+    need.dedup();
+
+    Have {
+        heads: heads(&commits),
+        filter: CommitFilter::new(digests.iter()),
+        need,
+    }
+}
+
+/// The loose commits of `tree` that a peer with `their` lacks, or may.
+///
+/// All of them if `their` filter isn't [valid](CommitFilter::is_valid).
+#[must_use]
+pub fn missing<'a>(tree: &'a Sedimentree, their: &Have) -> Vec<&'a LooseCommit> {
+    let commits = tree.loose_commits().collect::<Vec<_>>();
+    if !their.filter.is_valid() {
+        tracing::warn!(
+            "Ignoring a commit filter of {} bytes setting {} bits per commit",
+            their.filter.len_bytes(),
+            their.filter.hashes
+        );
+        return commits;
+    }
+    let mut wanted = commits
+        .iter()
+        .map(|commit| commit.digest())
+        .filter(|digest| !their.filter.contains(digest) || their.need.contains(digest))
+        .collect::<HashSet<_>>();
+    wanted.extend(
+        heads(&commits)
+            .into_iter()
+            .filter(|head| !their.heads.contains(head)),
+    );
+
+    // And everything built on them, in case the filter hid one of those too
+    let mut children = HashMap::<Digest, Vec<Digest>>::new();
+    for commit in &commits {
+        for parent in commit.parents() {
+            children.entry(*parent).or_default().push(commit.digest());
+        }
+    }
+    let mut unvisited = wanted.iter().copied().collect::<Vec<_>>();
+    while let Some(digest) = unvisited.pop() {
+        for child in children.get(&digest).into_iter().flatten() {
+            if wanted.insert(*child) {
+                unvisited.push(*child);
+            }
+        }
+    }
+
+    commits
+        .into_iter()
+        .filter(|commit| wanted.contains(&commit.digest()))
+        .collect()
+}
+
+/// The loose commits no other loose commit names as a parent, sorted.
+fn heads(commits: &[&LooseCommit]) -> Vec<Digest> {
+    let parents = commits
+        .iter()
+        .flat_map(|commit| commit.parents())
+        .collect::<HashSet<_>>();
+    let mut heads = commits
+        .iter()
+        .map(|commit| commit.digest())
+        .filter(|digest| !parents.contains(digest))
+        .collect::<Vec<_>>();
+    heads.sort();
+    heads
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::BlobMeta;
+
+    use super::*;
+
+    fn chain(len: u8) -> Vec<LooseCommit> {
+        let mut commits = Vec::<LooseCommit>::new();
+        for n in 0..len {
+            let parents = commits
+                .last()
+                .map(LooseCommit::digest)
+                .into_iter()
+                .collect();
+            commits.push(LooseCommit::new(
+                Digest::hash(&[n]),
+                parents,
+                BlobMeta::new(&[n]),
+            ));
+        }
+        commits
+    }
+
+    #[test]
+    fn filter_holds_what_it_was_made_from() {
+        let digests = (0..1000_u16)
+            .map(|n| Digest::hash(&n.to_le_bytes()))
+            .collect::<Vec<_>>();
+        let filter = CommitFilter::new(digests.iter());
+        assert!(digests.iter().all(|digest| filter.contains(digest)));
+        assert_eq!(filter.len_bytes(), 1250);
+
+        let false_positives = (1000..11_000_u16)
+            .filter(|n| filter.contains(&Digest::hash(&n.to_le_bytes())))
+            .count();
+        assert!(false_positives < 200, "{false_positives} false positives");
+    }
+
+    #[test]
+    fn picks_the_commits_the_peer_lacks() {
+        let commits = chain(10);
+        let ours = Sedimentree::new(vec![], commits.clone());
+        let theirs = Sedimentree::new(vec![], commits[..7].to_vec());
+
+        let mut lacking = missing(&ours, &have(&theirs))
+            .into_iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        lacking.sort();
+        let mut expected = commits[7..].to_vec();
+        expected.sort();
+        assert_eq!(lacking, expected);
+        // Only their head, in case our filter just seemed to hold it
+        assert_eq!(missing(&theirs, &have(&ours)), vec![&commits[6]]);
+    }
+
+    #[test]
+    fn sends_everything_past_a_filter_it_cant_trust() {
+        let commits = chain(5);
+        let ours = Sedimentree::new(vec![], commits.clone());
+        let mut their_have = have(&ours);
+        assert!(their_have.filter.is_valid());
+
+        // Holds every digest, which would leave them with nothing
+        their_have.filter.hashes = 0;
+        assert!(!their_have.filter.is_valid());
+        assert_eq!(missing(&ours, &their_have).len(), commits.len());
+
+        their_have.filter.hashes = MAX_HASHES + 1;
+        assert!(!their_have.filter.is_valid());
+        assert_eq!(missing(&ours, &their_have).len(), commits.len());
+
+        their_have.filter.hashes = HASHES;
+        their_have.filter.bits = Vec::new();
+        assert!(!their_have.filter.is_valid());
+        their_have.filter.bits = vec![0xff; MAX_FILTER_BYTES + 1];
+        assert!(!their_have.filter.is_valid());
+        assert_eq!(missing(&ours, &their_have).len(), commits.len());
+    }
+
+    #[test]
+    fn sends_what_a_peer_needs() {
+        let commits = chain(3);
+        let ours = Sedimentree::new(vec![], commits.clone());
+        // A peer that got the last commit but not its parent
+        let theirs = Sedimentree::new(vec![], vec![commits[0].clone(), commits[2].clone()]);
+
+        let their_have = have(&theirs);
+        assert_eq!(their_have.need, vec![commits[1].digest()]);
+        assert!(missing(&ours, &their_have).contains(&&commits[1]));
+    }
+}
@@ -0,0 +1,134 @@
+//! Deduplication of concurrent identical requests.
+//!
+//! The first caller for a key becomes the leader and performs the request.
+//! Callers that arrive while it is in flight wait for the leader's outcome
+//! instead of issuing their own round trip.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    hash::Hash,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use futures::channel::oneshot;
+
+/// A registry of requests currently in flight, keyed by `K`.
+#[derive(Debug, Clone)]
+pub(crate) struct InFlight<K> {
+    waiting: Arc<Mutex<HashMap<K, Vec<oneshot::Sender<bool>>>>>,
+}
+
+impl<K> Default for InFlight<K> {
+    fn default() -> Self {
+        Self {
+            waiting: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> InFlight<K> {
+    /// Register interest in the request for `key`.
+    pub(crate) fn join(&self, key: K) -> Joined<K> {
+        let mut waiting = self.waiting.lock().unwrap_or_else(PoisonError::into_inner);
+        match waiting.entry(key.clone()) {
+            Entry::Occupied(mut entry) => {
+                let (tx, rx) = oneshot::channel();
+                entry.get_mut().push(tx);
+                Joined::Follower(rx)
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(Vec::new());
+                Joined::Leader(Lead {
+                    registry: self.clone(),
+                    key,
+                    outcome: None,
+                })
+            }
+        }
+    }
+}
+
+/// The result of [`InFlight::join`].
+#[derive(Debug)]
+pub(crate) enum Joined<K: Hash + Eq> {
+    /// No identical request was in flight; the caller must perform it.
+    Leader(Lead<K>),
+
+    /// An identical request is in flight; its outcome arrives on this channel.
+    /// The channel is cancelled if the leader fails or is dropped.
+    Follower(oneshot::Receiver<bool>),
+}
+
+/// The right (and obligation) to perform an in-flight request.
+///
+/// Dropping this without calling [`Lead::finish`] releases the key and
+/// cancels any followers.
+#[derive(Debug)]
+pub(crate) struct Lead<K: Hash + Eq> {
+    registry: InFlight<K>,
+    key: K,
+    outcome: Option<bool>,
+}
+
+impl<K: Hash + Eq> Lead<K> {
+    /// Complete the request, sharing `outcome` with every follower.
+    pub(crate) fn finish(mut self, outcome: bool) {
+        self.outcome = Some(outcome);
+    }
+}
+
+impl<K: Hash + Eq> Drop for Lead<K> {
+    fn drop(&mut self) {
+        let followers = self
+            .registry
+            .waiting
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.key)
+            .unwrap_or_default();
+
+        if let Some(outcome) = self.outcome {
+            for follower in followers {
+                // The follower may have given up waiting; that's fine.
+                follower.send(outcome).ok();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn followers_share_the_leaders_outcome() -> Result<(), &'static str> {
+        let registry = InFlight::default();
+
+        let Joined::Leader(lead) = registry.join("doc") else {
+            return Err("first caller should lead");
+        };
+        let Joined::Follower(mut follower) = registry.join("doc") else {
+            return Err("second caller should follow");
+        };
+        assert!(matches!(registry.join("other"), Joined::Leader(_)));
+
+        lead.finish(true);
+        assert_eq!(follower.try_recv(), Ok(Some(true)));
+        assert!(matches!(registry.join("doc"), Joined::Leader(_)));
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_leader_cancels_followers() -> Result<(), &'static str> {
+        let registry = InFlight::default();
+
+        let lead = registry.join(1);
+        let Joined::Follower(mut follower) = registry.join(1) else {
+            return Err("second caller should follow");
+        };
+
+        drop(lead);
+        assert!(follower.try_recv().is_err());
+        Ok(())
+    }
+}
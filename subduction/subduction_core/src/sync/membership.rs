@@ -0,0 +1,95 @@
+//! Limiting each document to its members.
+//!
+//! By default, any peer that connects may sync any document. An engine with a
+//! [`Membership`] (see [`Subduction::with_membership`]) drops every message a peer
+//! sends about a document it isn't a member of, unanswered, and only pushes a
+//! document's commits, chunks, and TTL to the connections of its members.
+//!
+//! Peers are checked by the [`PeerId`] their connection proved in its
+//! [handshake](crate::connection::handshake), so a peer can't claim another's
+//! membership. A blob is only served to the members of a document whose commits
+//! or chunks hold it; a blob request from anyone else is answered as if the blob
+//! weren't held. Other messages that aren't about any document are left to the
+//! transport.
+//!
+//! [`Subduction::with_membership`]: crate::Subduction::with_membership
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
+
+use sedimentree_core::SedimentreeId;
+
+use crate::peer::id::PeerId;
+
+/// Decides who may sync each document.
+pub trait Membership: Debug + Send + Sync {
+    /// Whether `peer` may sync the document `id`.
+    fn is_member(&self, peer: PeerId, id: SedimentreeId) -> bool;
+}
+
+/// A fixed list of each document's members, such as a relay is configured with.
+///
+/// A document that isn't listed has no members.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Members(HashMap<SedimentreeId, HashSet<PeerId>>);
+
+impl Members {
+    /// Make `peer` a member of the document `id`, returning whether it wasn't already.
+    pub fn insert(&mut self, id: SedimentreeId, peer: PeerId) -> bool {
+        self.0.entry(id).or_default().insert(peer)
+    }
+
+    /// Stop `peer` being a member of the document `id`, returning whether it was.
+    pub fn remove(&mut self, id: SedimentreeId, peer: PeerId) -> bool {
+        let Some(members) = self.0.get_mut(&id) else {
+            return false;
+        };
+        let removed = members.remove(&peer);
+        if members.is_empty() {
+            self.0.remove(&id);
+        }
+        removed
+    }
+}
+
+impl FromIterator<(SedimentreeId, PeerId)> for Members {
+    fn from_iter<I: IntoIterator<Item = (SedimentreeId, PeerId)>>(iter: I) -> Self {
+        let mut members = Self::default();
+        for (id, peer) in iter {
+            members.insert(id, peer);
+        }
+        members
+    }
+}
+
+impl Membership for Members {
+    fn is_member(&self, peer: PeerId, id: SedimentreeId) -> bool {
+        self.0
+            .get(&id)
+            .is_some_and(|members| members.contains(&peer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_listed_peers_are_members() {
+        let (doc, other_doc) = (SedimentreeId::new([1; 32]), SedimentreeId::new([2; 32]));
+        let (alice, bob) = (PeerId::new([3; 32]), PeerId::new([4; 32]));
+
+        let mut members = Members::from_iter([(doc, alice)]);
+        assert!(members.is_member(alice, doc));
+        assert!(!members.is_member(bob, doc));
+        assert!(!members.is_member(alice, other_doc));
+
+        assert!(members.insert(doc, bob));
+        assert!(members.is_member(bob, doc));
+        assert!(members.remove(doc, alice));
+        assert!(!members.remove(doc, alice));
+        assert!(!members.is_member(alice, doc));
+    }
+}
@@ -0,0 +1,241 @@
+//! What each peer is known to hold of each sedimentree, for telling when it's in sync.
+//!
+//! A peer's heads are learned from batch syncs with it, after which it holds
+//! everything we do, and are moved along by the commits it pushes, each of which
+//! it built on what it held. Commits pushed to the peer don't count until a batch
+//! sync confirms them, since nothing says they arrived.
+//!
+//! The same heads are the frontier the next batch sync with the peer resumes from
+//! (see [`session`](super::session)), kept in a [`SessionStore`] if there is one.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+use sedimentree_core::{Digest, LooseCommit, SedimentreeId};
+
+use super::session::SessionStore;
+use crate::{connection::message::SummaryToken, peer::id::PeerId};
+
+/// How a sedimentree stands with one peer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerSyncStatus {
+    /// Batch syncs with the peer that haven't finished yet.
+    pub outstanding: usize,
+
+    /// The heads the peer is known to have, sorted, or `None` before any batch sync.
+    pub heads: Option<Vec<Digest>>,
+
+    /// Batch syncs with the peer that have finished, so that callers with a clock can
+    /// tell when a new one has.
+    pub syncs: u64,
+}
+
+impl PeerSyncStatus {
+    /// Whether no batch syncs are outstanding and the peer has `heads`, ours.
+    #[must_use]
+    pub fn matches(&self, heads: &[Digest]) -> bool {
+        let mut heads = heads.to_vec();
+        heads.sort();
+        heads.dedup();
+        self.outstanding == 0 && self.heads.as_deref() == Some(heads.as_slice())
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    outstanding: usize,
+    heads: Option<BTreeSet<Digest>>,
+    syncs: u64,
+    /// The peer's token from the last round, and the version our sedimentree was at.
+    token: Option<(SummaryToken, u64)>,
+}
+
+/// The [`PeerSyncStatus`] of every sedimentree with every peer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerSync {
+    peers: Arc<Mutex<HashMap<(PeerId, SedimentreeId), PeerState>>>,
+    sessions: Option<Arc<dyn SessionStore>>,
+}
+
+impl PeerSync {
+    /// Keep each peer's heads after a batch sync in `store`, and fall back on it for
+    /// peers not synced with since starting.
+    pub(crate) fn set_store(&mut self, store: Arc<dyn SessionStore>) {
+        self.sessions = Some(store);
+    }
+
+    /// Count a batch sync with `peer` as outstanding until the guard is dropped.
+    pub(crate) fn begin(&self, peer: PeerId, id: SedimentreeId) -> Outstanding {
+        self.with(peer, id, |state| state.outstanding += 1);
+        Outstanding {
+            registry: self.clone(),
+            key: (peer, id),
+        }
+    }
+
+    /// Note that a batch sync left `peer` with `heads`.
+    pub(crate) fn synced(&self, peer: PeerId, id: SedimentreeId, heads: Vec<Digest>) {
+        if let Some(store) = &self.sessions {
+            store.save(peer, id, &heads);
+        }
+        self.with(peer, id, |state| {
+            state.heads = Some(heads.into_iter().collect());
+            state.syncs += 1;
+        });
+    }
+
+    /// The heads `peer` is known to hold, from this run or a stored session, to
+    /// resume the next batch sync from.
+    pub(crate) fn frontier(&self, peer: PeerId, id: SedimentreeId) -> Vec<Digest> {
+        let heads = {
+            let peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+            peers
+                .get(&(peer, id))
+                .and_then(|state| state.heads.as_ref())
+                .map(|heads| heads.iter().copied().collect())
+        };
+        heads
+            .or_else(|| self.sessions.as_ref()?.load(peer, id))
+            .unwrap_or_default()
+    }
+
+    /// Note that `peer` pushed `commit`, building on the heads it had.
+    pub(crate) fn pushed(&self, peer: PeerId, id: SedimentreeId, commit: &LooseCommit) {
+        self.with(peer, id, |state| {
+            if let Some(heads) = &mut state.heads {
+                for parent in commit.parents() {
+                    heads.remove(parent);
+                }
+                heads.insert(commit.digest());
+            }
+        });
+    }
+
+    /// Note that a round with `peer`, after which our sedimentree was at `version`,
+    /// ended with the peer's `token`, or forget the peer's token with `None`.
+    pub(crate) fn got_token(
+        &self,
+        peer: PeerId,
+        id: SedimentreeId,
+        token: Option<SummaryToken>,
+        version: u64,
+    ) {
+        self.with(peer, id, |state| {
+            state.token = token.map(|token| (token, version));
+        });
+    }
+
+    /// The token to send `peer` with a request, if our sedimentree is still at the
+    /// version it was when the peer gave it.
+    pub(crate) fn token(
+        &self,
+        peer: PeerId,
+        id: SedimentreeId,
+        version: u64,
+    ) -> Option<SummaryToken> {
+        let peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        peers
+            .get(&(peer, id))
+            .and_then(|state| state.token)
+            .filter(|&(_, at)| at == version)
+            .map(|(token, _)| token)
+    }
+
+    pub(crate) fn status(&self, peer: PeerId, id: SedimentreeId) -> PeerSyncStatus {
+        let peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        peers
+            .get(&(peer, id))
+            .map(|state| PeerSyncStatus {
+                outstanding: state.outstanding,
+                heads: state.heads.as_ref().map(|heads| heads.iter().copied().collect()),
+                syncs: state.syncs,
+            })
+            .unwrap_or_default()
+    }
+
+    fn with(&self, peer: PeerId, id: SedimentreeId, f: impl FnOnce(&mut PeerState)) {
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        f(peers.entry((peer, id)).or_default());
+    }
+}
+
+/// A batch sync in progress, from [`PeerSync::begin`].
+#[derive(Debug)]
+pub(crate) struct Outstanding {
+    registry: PeerSync,
+    key: (PeerId, SedimentreeId),
+}
+
+impl Drop for Outstanding {
+    fn drop(&mut self) {
+        let (peer, id) = self.key;
+        self.registry.with(peer, id, |state| {
+            state.outstanding = state.outstanding.saturating_sub(1);
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sedimentree_core::BlobMeta;
+
+    #[test]
+    fn tracks_heads_through_syncs_and_pushes() {
+        let peers = PeerSync::default();
+        let peer = PeerId::new([1; 32]);
+        let id = SedimentreeId::new([2; 32]);
+        let base = Digest::hash(b"base");
+        let commit = LooseCommit::new(Digest::hash(b"next"), vec![base], BlobMeta::new(b"n"));
+
+        // Pushes before the first sync say nothing about the rest of the peer's history
+        peers.pushed(peer, id, &commit);
+        assert!(!peers.status(peer, id).matches(&[commit.digest()]));
+
+        let outstanding = peers.begin(peer, id);
+        peers.synced(peer, id, vec![base]);
+        assert!(!peers.status(peer, id).matches(&[base]));
+        drop(outstanding);
+        assert!(peers.status(peer, id).matches(&[base]));
+        assert_eq!(peers.status(peer, id).syncs, 1);
+
+        peers.pushed(peer, id, &commit);
+        assert!(peers.status(peer, id).matches(&[commit.digest()]));
+        assert_eq!(peers.status(peer, id).syncs, 1);
+    }
+
+    #[derive(Debug, Default)]
+    struct Sessions(Mutex<HashMap<(PeerId, SedimentreeId), Vec<Digest>>>);
+
+    impl SessionStore for Sessions {
+        fn load(&self, peer: PeerId, id: SedimentreeId) -> Option<Vec<Digest>> {
+            let sessions = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            sessions.get(&(peer, id)).cloned()
+        }
+
+        fn save(&self, peer: PeerId, id: SedimentreeId, frontier: &[Digest]) {
+            let mut sessions = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            sessions.insert((peer, id), frontier.to_vec());
+        }
+    }
+
+    #[test]
+    fn resumes_from_a_stored_frontier() {
+        let sessions = Arc::new(Sessions::default());
+        let peer = PeerId::new([1; 32]);
+        let id = SedimentreeId::new([2; 32]);
+        let heads = vec![Digest::hash(b"head")];
+
+        let mut before = PeerSync::default();
+        before.set_store(sessions.clone());
+        assert_eq!(before.frontier(peer, id), Vec::new());
+        before.synced(peer, id, heads.clone());
+
+        // A restart forgets everything but the store
+        let mut after = PeerSync::default();
+        after.set_store(sessions);
+        assert_eq!(after.frontier(peer, id), heads);
+    }
+}
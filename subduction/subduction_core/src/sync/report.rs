@@ -0,0 +1,224 @@
+//! Reports of each batch sync round with a peer.
+//!
+//! Once a batch sync of one sedimentree with a peer finishes, on either side, a
+//! [`SyncReport`] says what it took: how the two sides reconciled, what each sent
+//! the other, and how long it took. Reports go to the [`ReportSink`] the instance
+//! was built with, and each round also runs in a `sync_round` tracing span that
+//! ends with the same details as an event.
+//!
+//! The sync path doesn't read a clock itself, so that it runs where there isn't
+//! one, so rounds are timed by the sink's [`ReportSink::now`].
+
+use std::{fmt::Debug, time::Duration};
+
+use sedimentree_core::{Blob, SedimentreeId};
+
+use crate::{connection::message::SyncDiff, peer::id::PeerId};
+
+/// Somewhere to deliver [`SyncReport`]s.
+pub trait ReportSink: Debug + Send + Sync {
+    /// The time since some fixed point, used to time rounds.
+    fn now(&self) -> Duration;
+
+    /// Called once each round finishes.
+    fn report(&self, report: SyncReport);
+}
+
+/// What a batch sync round with a peer took.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SyncReport {
+    /// The peer synced with.
+    pub peer: PeerId,
+
+    /// The sedimentree compared.
+    pub id: SedimentreeId,
+
+    /// Which side of the round this instance was on.
+    pub role: SyncRole,
+
+    /// How the two sides reconciled.
+    pub strategy: Reconciliation,
+
+    /// The commits sent to the peer.
+    pub commits_sent: usize,
+
+    /// The chunks sent to the peer.
+    pub chunks_sent: usize,
+
+    /// The commits received from the peer, including ones only known by their
+    /// metadata so far.
+    pub commits_received: usize,
+
+    /// The chunks received from the peer.
+    pub chunks_received: usize,
+
+    /// The blob bytes sent to the peer.
+    pub bytes_sent: u64,
+
+    /// The blob bytes received from the peer.
+    pub bytes_received: u64,
+
+    /// How long the round took.
+    pub duration: Duration,
+}
+
+/// Which side of a round an instance was on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum SyncRole {
+    /// This instance asked the peer to sync.
+    Requester,
+
+    /// The peer asked this instance to sync.
+    Responder,
+}
+
+/// How the two sides of a round reconciled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum Reconciliation {
+    /// The summaries matched, so nothing had to be compared or sent.
+    InSync,
+
+    /// The responder diffed its sedimentree against the requester's summary, and
+    /// sent back what the requester lacked.
+    SummaryDiff,
+
+    /// The requester's summary token showed that neither side had changed since
+    /// their last round, so nothing was compared or sent.
+    NotModified,
+
+    /// The two sides swapped Bloom filters of their loose commits rather than
+    /// listing them, and each sent what the other's filter lacked.
+    Filter,
+}
+
+impl SyncReport {
+    /// A report of a round with `peer` over sedimentree `id` in which nothing has been
+    /// sent or received yet.
+    pub(crate) const fn new(peer: PeerId, id: SedimentreeId, role: SyncRole) -> Self {
+        Self {
+            peer,
+            id,
+            role,
+            strategy: Reconciliation::InSync,
+            commits_sent: 0,
+            chunks_sent: 0,
+            commits_received: 0,
+            chunks_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            duration: Duration::ZERO,
+        }
+    }
+
+    /// Count `diff` as sent to the peer.
+    pub(crate) fn sent(&mut self, diff: &SyncDiff) {
+        let (commits, chunks, bytes) = measure(diff);
+        self.commits_sent += commits;
+        self.chunks_sent += chunks;
+        self.bytes_sent += bytes;
+    }
+
+    /// Count a chunk with `blob` as sent to the peer after the diff.
+    pub(crate) fn sent_chunk(&mut self, blob: &Blob) {
+        self.chunks_sent += 1;
+        self.bytes_sent += blob.meta().size_bytes();
+    }
+
+    /// Count `diff` as received from the peer.
+    pub(crate) fn received(&mut self, diff: &SyncDiff) {
+        let (commits, chunks, bytes) = measure(diff);
+        self.commits_received += commits;
+        self.chunks_received += chunks;
+        self.bytes_received += bytes;
+    }
+
+    /// The span a round with `peer` over sedimentree `id` runs in, whose details
+    /// [`SyncReport::trace`] fills in once it finishes.
+    pub(crate) fn span(peer: PeerId, id: SedimentreeId, role: SyncRole) -> tracing::Span {
+        tracing::info_span!(
+            "sync_round",
+            peer = ?peer,
+            id = ?id,
+            role = ?role,
+            strategy = tracing::field::Empty,
+            commits_sent = tracing::field::Empty,
+            commits_received = tracing::field::Empty,
+            bytes_sent = tracing::field::Empty,
+            bytes_received = tracing::field::Empty,
+        )
+    }
+
+    /// Record the round's details on its `span`, and as an event within it.
+    pub(crate) fn trace(&self, span: &tracing::Span) {
+        span.record("strategy", tracing::field::debug(self.strategy));
+        span.record("commits_sent", self.commits_sent);
+        span.record("commits_received", self.commits_received);
+        span.record("bytes_sent", self.bytes_sent);
+        span.record("bytes_received", self.bytes_received);
+        tracing::info!(
+            parent: span,
+            peer = ?self.peer,
+            id = ?self.id,
+            role = ?self.role,
+            strategy = ?self.strategy,
+            commits_sent = self.commits_sent,
+            chunks_sent = self.chunks_sent,
+            commits_received = self.commits_received,
+            chunks_received = self.chunks_received,
+            bytes_sent = self.bytes_sent,
+            bytes_received = self.bytes_received,
+            duration_ms = self.duration.as_secs_f64() * 1000.0,
+            "Sync round finished"
+        );
+    }
+}
+
+/// The commits, chunks, and blob bytes in `diff`.
+fn measure(diff: &SyncDiff) -> (usize, usize, u64) {
+    let bytes = diff
+        .missing_commits
+        .iter()
+        .map(|(_, blob)| blob.meta().size_bytes())
+        .chain(
+            diff.missing_chunks
+                .iter()
+                .map(|(_, blob)| blob.meta().size_bytes()),
+        )
+        .sum();
+    (diff.missing_commits.len(), diff.missing_chunks.len(), bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::{Digest, LooseCommit};
+
+    use super::*;
+
+    #[test]
+    fn counts_what_each_side_sent() {
+        let blob = Blob::new(vec![0; 10]);
+        let commit = LooseCommit::new(Digest::hash(b"c"), Vec::new(), blob.meta());
+        let diff = SyncDiff {
+            missing_commits: vec![(commit, blob)],
+            missing_chunks: Vec::new(),
+        };
+
+        let mut report = SyncReport::new(
+            PeerId::new([1; 32]),
+            SedimentreeId::new([2; 32]),
+            SyncRole::Requester,
+        );
+        report.received(&diff);
+        report.received(&diff);
+        report.sent(&diff);
+
+        assert_eq!(report.commits_received, 2);
+        assert_eq!(report.bytes_received, 20);
+        assert_eq!(report.commits_sent, 1);
+        assert_eq!(report.bytes_sent, 10);
+        assert_eq!(report.chunks_sent + report.chunks_received, 0);
+    }
+}
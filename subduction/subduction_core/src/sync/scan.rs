@@ -0,0 +1,233 @@
+//! Scanning the content peers push to a relay.
+//!
+//! Operators of public relays may be obliged to keep malware or abusive
+//! content from being hosted. A relay with a [`ContentScanner`] asks it about
+//! every commit and chunk it receives from a peer, before storing or
+//! forwarding it, and acts on the [`Verdict`]: accepted items are handled as
+//! usual, rejected ones are dropped, and flagged ones are held in quarantine
+//! until an admin releases or discards them (see
+//! [`Subduction::quarantined`][crate::Subduction::quarantined]).
+//!
+//! The scanner always sees an item's metadata and size, but only sees its
+//! contents for the documents that [`ContentAccess`] permits.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    time::SystemTime,
+};
+
+use sedimentree_core::{future::FutureKind, Blob, Chunk, Digest, LooseCommit, SedimentreeId};
+
+use crate::peer::id::PeerId;
+
+/// Something that decides whether pushed content may be hosted.
+pub trait ContentScanner<F: FutureKind>: Debug + Send + Sync {
+    /// Decide what to do with `item`.
+    fn scan<'a>(&'a self, item: &'a ScannedItem) -> F::Future<'a, Verdict>;
+}
+
+/// What a [`ContentScanner`] decided about an item.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Verdict {
+    /// Store and forward the item as usual.
+    Accept,
+
+    /// Hold the item in quarantine for an admin to review.
+    Flag {
+        /// Why the item was flagged, for the reviewer.
+        reason: String,
+    },
+
+    /// Drop the item.
+    Reject {
+        /// Why the item was rejected, for the relay's logs.
+        reason: String,
+    },
+}
+
+/// Whether the item is a loose commit or a chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ItemKind {
+    /// A loose commit.
+    Commit,
+
+    /// A chunk of history.
+    Chunk,
+}
+
+/// A commit or chunk received from a peer, as shown to a [`ContentScanner`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ScannedItem {
+    /// The document the item belongs to.
+    pub id: SedimentreeId,
+
+    /// The peer the item came from.
+    pub from: PeerId,
+
+    /// Whether the item is a commit or a chunk.
+    pub kind: ItemKind,
+
+    /// The commit's or chunk's digest.
+    pub digest: Digest,
+
+    /// The size of the item's contents in bytes.
+    pub size: u64,
+
+    /// The item's contents, if the scanner is permitted to read this document.
+    pub content: Option<Blob>,
+}
+
+/// Which documents' contents a [`ContentScanner`] may read.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ContentAccess {
+    /// Metadata only.
+    #[default]
+    None,
+
+    /// Every document's contents.
+    All,
+
+    /// The contents of these documents only, such as those whose owners consented.
+    Documents(HashSet<SedimentreeId>),
+}
+
+impl ContentAccess {
+    /// Whether the contents of document `id` may be read.
+    #[must_use]
+    pub fn permits(&self, id: SedimentreeId) -> bool {
+        match self {
+            Self::None => false,
+            Self::All => true,
+            Self::Documents(ids) => ids.contains(&id),
+        }
+    }
+}
+
+/// An item held in quarantine.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct QuarantineEntry {
+    /// The item, as it was shown to the scanner.
+    pub item: ScannedItem,
+
+    /// Why it was flagged.
+    pub reason: String,
+
+    /// When it was flagged.
+    pub flagged_at: SystemTime,
+}
+
+/// A commit or chunk as received, with its blob.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Held {
+    Commit(LooseCommit, Blob),
+    Chunk(Chunk, Blob),
+}
+
+impl Held {
+    /// Whether the blob is the one the commit or chunk names, by digest and size.
+    pub(crate) fn is_intact(&self) -> bool {
+        match self {
+            Self::Commit(commit, blob) => blob.meta() == *commit.blob(),
+            Self::Chunk(chunk, blob) => blob.meta() == chunk.summary().blob_meta(),
+        }
+    }
+}
+
+/// Flagged items awaiting review, by digest.
+#[derive(Debug, Default)]
+pub(crate) struct Quarantine(HashMap<Digest, (QuarantineEntry, Held)>);
+
+impl Quarantine {
+    /// Hold `held` until it's released or discarded.
+    pub(crate) fn hold(&mut self, entry: QuarantineEntry, held: Held) {
+        self.0.insert(entry.item.digest, (entry, held));
+    }
+
+    /// Every held item, oldest first.
+    pub(crate) fn entries(&self) -> Vec<QuarantineEntry> {
+        let mut entries = self
+            .0
+            .values()
+            .map(|(entry, _)| entry.clone())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.flagged_at);
+        entries
+    }
+
+    /// Stop holding the item with `digest`, returning it if it was held.
+    pub(crate) fn take(&mut self, digest: &Digest) -> Option<(QuarantineEntry, Held)> {
+        self.0.remove(digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sedimentree_core::BlobMeta;
+
+    use super::*;
+
+    fn flagged(n: u8, flagged_at: SystemTime) -> (QuarantineEntry, Held) {
+        let blob = Blob::new(vec![n]);
+        let commit = LooseCommit::new(Digest::hash(&[n]), vec![], BlobMeta::new(&[n]));
+        let entry = QuarantineEntry {
+            item: ScannedItem {
+                id: SedimentreeId::new([1; 32]),
+                from: PeerId::new([2; 32]),
+                kind: ItemKind::Commit,
+                digest: commit.digest(),
+                size: 1,
+                content: None,
+            },
+            reason: "suspicious".to_string(),
+            flagged_at,
+        };
+        (entry, Held::Commit(commit, blob))
+    }
+
+    #[test]
+    fn lists_oldest_first_until_taken() {
+        let start = SystemTime::UNIX_EPOCH;
+        let (newer, newer_held) = flagged(1, start + Duration::from_secs(10));
+        let (older, older_held) = flagged(2, start);
+
+        let mut quarantine = Quarantine::default();
+        quarantine.hold(newer.clone(), newer_held.clone());
+        quarantine.hold(older.clone(), older_held);
+        assert_eq!(quarantine.entries(), vec![older, newer.clone()]);
+
+        assert_eq!(
+            quarantine.take(&newer.item.digest),
+            Some((newer.clone(), newer_held))
+        );
+        assert_eq!(quarantine.take(&newer.item.digest), None);
+        assert_eq!(quarantine.entries().len(), 1);
+    }
+
+    #[test]
+    fn blobs_must_be_the_ones_named() {
+        let (_, held) = flagged(1, SystemTime::UNIX_EPOCH);
+        assert!(held.is_intact());
+
+        let Held::Commit(commit, _) = held else {
+            unreachable!("flagged items are commits");
+        };
+        // Same size, different contents
+        assert!(!Held::Commit(commit.clone(), Blob::new(vec![2])).is_intact());
+        assert!(!Held::Commit(commit, Blob::new(vec![1, 1])).is_intact());
+    }
+
+    #[test]
+    fn content_access_is_per_document() {
+        let consented = SedimentreeId::new([1; 32]);
+        let other = SedimentreeId::new([2; 32]);
+        let access = ContentAccess::Documents(HashSet::from([consented]));
+
+        assert!(access.permits(consented));
+        assert!(!access.permits(other));
+        assert!(ContentAccess::All.permits(other));
+        assert!(!ContentAccess::None.permits(consented));
+    }
+}
@@ -0,0 +1,91 @@
+//! Picking a batch sync up from where the last one with a peer left off.
+//!
+//! Once a batch sync with a peer finishes, both sides hold the same history up to
+//! the peer's heads: the frontier of the session. The next request for the
+//! sedimentree names that frontier as `since` and leaves every loose commit it was
+//! built on out of its summary, so a peer that reconnects after a dropped
+//! connection only compares what came after. The responder leaves the same
+//! commits out of its diff.
+//!
+//! A responder that doesn't hold the whole frontier, such as one that has lost
+//! its storage since, diffs the summary as it stands and says it didn't resume.
+//! The requester then pushes the commits it left out.
+//!
+//! Frontiers live in memory for as long as the [`Subduction`](crate::Subduction)
+//! does. To keep them across restarts, give it a [`SessionStore`] with
+//! [`Subduction::with_sessions`](crate::Subduction::with_sessions).
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
+
+use sedimentree_core::{Digest, Sedimentree, SedimentreeId};
+
+use crate::peer::id::PeerId;
+
+/// Somewhere to keep the frontier of each peer's last batch sync of each sedimentree.
+pub trait SessionStore: Debug + Send + Sync {
+    /// The frontier saved for `peer` and sedimentree `id`, if any.
+    fn load(&self, peer: PeerId, id: SedimentreeId) -> Option<Vec<Digest>>;
+
+    /// Called each time a batch sync with `peer` leaves it holding `frontier`.
+    ///
+    /// This must not block; implementations should queue the write.
+    fn save(&self, peer: PeerId, id: SedimentreeId, frontier: &[Digest]);
+}
+
+/// The loose commits of `tree` at or before `since`, or `None` if it doesn't hold
+/// every commit of `since` as a loose commit.
+#[must_use]
+pub fn settled(tree: &Sedimentree, since: &[Digest]) -> Option<HashSet<Digest>> {
+    let commits = tree
+        .loose_commits()
+        .map(|commit| (commit.digest(), commit))
+        .collect::<HashMap<_, _>>();
+    if !since.iter().all(|digest| commits.contains_key(digest)) {
+        return None;
+    }
+
+    let mut settled = since.iter().copied().collect::<HashSet<_>>();
+    let mut unvisited = since.to_vec();
+    while let Some(digest) = unvisited.pop() {
+        let parents = commits.get(&digest).map(|commit| commit.parents());
+        for parent in parents.into_iter().flatten() {
+            if commits.contains_key(parent) && settled.insert(*parent) {
+                unvisited.push(*parent);
+            }
+        }
+    }
+    Some(settled)
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::{BlobMeta, LooseCommit};
+
+    use super::*;
+
+    #[test]
+    fn settles_what_the_frontier_was_built_on() {
+        let root = LooseCommit::new(Digest::hash(b"root"), vec![], BlobMeta::new(b"r"));
+        let left = LooseCommit::new(
+            Digest::hash(b"left"),
+            vec![root.digest()],
+            BlobMeta::new(b"l"),
+        );
+        let right = LooseCommit::new(
+            Digest::hash(b"right"),
+            vec![root.digest()],
+            BlobMeta::new(b"r"),
+        );
+        let tree = Sedimentree::new(vec![], vec![root.clone(), left.clone(), right]);
+
+        assert_eq!(
+            settled(&tree, &[left.digest()]),
+            Some([root.digest(), left.digest()].into())
+        );
+        assert_eq!(settled(&tree, &[]), Some(HashSet::new()));
+        assert_eq!(settled(&tree, &[Digest::hash(b"elsewhere")]), None);
+    }
+}
@@ -0,0 +1,81 @@
+//! Relaying WebRTC signaling between peers of the same sedimentree.
+//!
+//! Two peers that sync through a relay can upgrade to a direct WebRTC
+//! connection without any other signaling infrastructure. Each sends its
+//! offer, answer, and ICE candidates to the relay as a
+//! [`Message::RelaySignal`], and the relay passes them on to the other peer as a
+//! [`Message::Signal`]. A relay only passes signals between peers that are both
+//! connected and have both synced the sedimentree the signal is for, so a
+//! connection can't be used to probe for arbitrary peers. Signals themselves
+//! don't count as syncing.
+//!
+//! [`Message::RelaySignal`]: crate::connection::message::Message::RelaySignal
+//! [`Message::Signal`]: crate::connection::message::Message::Signal
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+};
+
+use sedimentree_core::SedimentreeId;
+
+use crate::{connection::message::Signal, peer::id::PeerId};
+
+/// Somewhere to deliver signals passed on by a relay.
+pub trait SignalSink: Debug + Send + Sync {
+    /// Called with each signal that `from` sent to this peer about sedimentree `id`.
+    fn signal(&self, id: SedimentreeId, from: PeerId, signal: Signal);
+}
+
+/// The peers that have synced each sedimentree over a connection.
+#[derive(Debug, Default)]
+pub(crate) struct Participants(HashMap<SedimentreeId, HashSet<PeerId>>);
+
+impl Participants {
+    /// Record that `peer` syncs `id`.
+    pub(crate) fn join(&mut self, id: SedimentreeId, peer: PeerId) {
+        self.0.entry(id).or_default().insert(peer);
+    }
+
+    /// Forget every sedimentree `peer` syncs, once it has no connections left.
+    pub(crate) fn leave(&mut self, peer: &PeerId) {
+        self.0.retain(|_, peers| {
+            peers.remove(peer);
+            !peers.is_empty()
+        });
+    }
+
+    /// Whether `a` and `b` both sync `id`.
+    pub(crate) fn share(&self, id: SedimentreeId, a: &PeerId, b: &PeerId) -> bool {
+        self.0
+            .get(&id)
+            .is_some_and(|peers| peers.contains(a) && peers.contains(b))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_peers_of_the_same_sedimentree_share_it() {
+        let doc = SedimentreeId::new([1; 32]);
+        let other_doc = SedimentreeId::new([2; 32]);
+        let alice = PeerId::new([1; 32]);
+        let bob = PeerId::new([2; 32]);
+        let carol = PeerId::new([3; 32]);
+
+        let mut participants = Participants::default();
+        participants.join(doc, alice);
+        participants.join(doc, bob);
+        participants.join(other_doc, carol);
+
+        assert!(participants.share(doc, &alice, &bob));
+        assert!(!participants.share(doc, &alice, &carol));
+        assert!(!participants.share(other_doc, &alice, &carol));
+
+        participants.leave(&bob);
+        assert!(!participants.share(doc, &alice, &bob));
+        assert!(participants.share(doc, &alice, &alice));
+    }
+}
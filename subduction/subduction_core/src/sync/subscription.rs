@@ -0,0 +1,204 @@
+//! Choosing which documents a connection is pushed.
+//!
+//! By default, every commit and chunk accepted is pushed over every connection. A
+//! peer that only follows some documents can send a [`Message::Subscribe`] naming
+//! them, by ID or by a prefix of their IDs, after which only writes to those
+//! documents are pushed to it over that connection. Each subscription replaces the
+//! last, and lasts until the connection closes. [`Subduction::subscribe`] sends it
+//! again over every connection made since.
+//!
+//! The receiver checks each filter against its [`SubscriptionPolicy`], if it has
+//! one (see [`Subduction::with_subscription_policy`]), and answers with a
+//! [`Message::Subscribed`] giving the filters it refused and why. The rest take
+//! effect. A connection that hasn't subscribed is only pushed the documents the
+//! policy would let it subscribe to, each by its ID.
+//!
+//! Subscriptions only limit what is pushed. A peer can still batch sync any
+//! document it could before.
+//!
+//! [`Message::Subscribe`]: crate::connection::message::Message::Subscribe
+//! [`Message::Subscribed`]: crate::connection::message::Message::Subscribed
+//! [`Subduction::subscribe`]: crate::Subduction::subscribe
+//! [`Subduction::with_subscription_policy`]: crate::Subduction::with_subscription_policy
+
+use std::{collections::HashMap, fmt::Debug};
+
+use sedimentree_core::SedimentreeId;
+
+use crate::{connection::id::ConnectionId, peer::id::PeerId};
+
+/// Decides which documents a peer may subscribe to.
+pub trait SubscriptionPolicy: Debug + Send + Sync {
+    /// Check whether `peer` may be pushed the documents `filter` matches.
+    ///
+    /// # Errors
+    ///
+    /// * The reason to give the peer, if it may not.
+    fn check(&self, peer: PeerId, filter: &DocumentFilter) -> Result<(), String>;
+}
+
+/// A set of documents to subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DocumentFilter {
+    /// The document with this ID.
+    Id(SedimentreeId),
+
+    /// Every document whose ID starts with these bytes.
+    Prefix(Vec<u8>),
+}
+
+impl DocumentFilter {
+    /// Whether the document `id` is in the set.
+    #[must_use]
+    pub fn matches(&self, id: SedimentreeId) -> bool {
+        match self {
+            DocumentFilter::Id(wanted) => *wanted == id,
+            DocumentFilter::Prefix(prefix) => id.as_bytes().starts_with(prefix),
+        }
+    }
+}
+
+/// A filter a peer may not subscribe to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SubscriptionDenied {
+    /// The filter refused.
+    pub filter: DocumentFilter,
+
+    /// Why, from the [`SubscriptionPolicy`].
+    pub reason: String,
+}
+
+/// Split `filters` into those `policy` allows `peer` and those it refuses.
+pub(crate) fn screen(
+    policy: Option<&dyn SubscriptionPolicy>,
+    peer: PeerId,
+    filters: Vec<DocumentFilter>,
+) -> (Vec<DocumentFilter>, Vec<SubscriptionDenied>) {
+    let Some(policy) = policy else {
+        return (filters, Vec::new());
+    };
+    let mut allowed = Vec::new();
+    let mut denied = Vec::new();
+    for filter in filters {
+        match policy.check(peer, &filter) {
+            Ok(()) => allowed.push(filter),
+            Err(reason) => denied.push(SubscriptionDenied { filter, reason }),
+        }
+    }
+    (allowed, denied)
+}
+
+/// The filters each connection has subscribed with, if it has.
+#[derive(Debug, Default)]
+pub(crate) struct Subscriptions(HashMap<ConnectionId, Vec<DocumentFilter>>);
+
+impl Subscriptions {
+    /// Push `conn_id` only the documents `filters` match from now on.
+    pub(crate) fn set(&mut self, conn_id: ConnectionId, filters: Vec<DocumentFilter>) {
+        self.0.insert(conn_id, filters);
+    }
+
+    /// Forget the subscription of a closed connection.
+    pub(crate) fn remove(&mut self, conn_id: ConnectionId) {
+        self.0.remove(&conn_id);
+    }
+
+    /// Whether `conn_id`, a connection to `peer`, wants writes to the document `id`
+    /// pushed to it. Until it subscribes, it's pushed every document `policy` allows.
+    pub(crate) fn wants(
+        &self,
+        conn_id: ConnectionId,
+        peer: PeerId,
+        id: SedimentreeId,
+        policy: Option<&dyn SubscriptionPolicy>,
+    ) -> bool {
+        match self.0.get(&conn_id) {
+            Some(filters) => filters.iter().any(|filter| filter.matches(id)),
+            None => policy.is_none_or(|policy| policy.check(peer, &DocumentFilter::Id(id)).is_ok()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug)]
+    struct OnlyIds;
+
+    impl SubscriptionPolicy for OnlyIds {
+        fn check(&self, _peer: PeerId, filter: &DocumentFilter) -> Result<(), String> {
+            match filter {
+                DocumentFilter::Id(_) => Ok(()),
+                DocumentFilter::Prefix(_) => Err("prefixes aren't allowed".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn pushes_only_what_was_subscribed_to() {
+        let conn_id = ConnectionId::new(1);
+        let peer = PeerId::new([1; 32]);
+        let doc = SedimentreeId::new([0xab; 32]);
+        let other_doc = SedimentreeId::new([0xcd; 32]);
+
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.wants(conn_id, peer, doc, None));
+
+        subscriptions.set(conn_id, vec![DocumentFilter::Prefix(vec![0xab, 0xab])]);
+        assert!(subscriptions.wants(conn_id, peer, doc, None));
+        assert!(!subscriptions.wants(conn_id, peer, other_doc, None));
+
+        subscriptions.set(conn_id, Vec::new());
+        assert!(!subscriptions.wants(conn_id, peer, doc, None));
+
+        subscriptions.remove(conn_id);
+        assert!(subscriptions.wants(conn_id, peer, other_doc, None));
+    }
+
+    #[derive(Debug)]
+    struct Only(SedimentreeId);
+
+    impl SubscriptionPolicy for Only {
+        fn check(&self, _peer: PeerId, filter: &DocumentFilter) -> Result<(), String> {
+            match filter {
+                DocumentFilter::Id(id) if *id == self.0 => Ok(()),
+                _ => Err("not this one".to_string()),
+            }
+        }
+    }
+
+    #[test]
+    fn pushes_only_what_the_policy_allows_until_subscribed() {
+        let conn_id = ConnectionId::new(1);
+        let peer = PeerId::new([1; 32]);
+        let doc = SedimentreeId::new([0xab; 32]);
+        let other_doc = SedimentreeId::new([0xcd; 32]);
+        let policy = Only(doc);
+
+        let subscriptions = Subscriptions::default();
+        assert!(subscriptions.wants(conn_id, peer, doc, Some(&policy)));
+        assert!(!subscriptions.wants(conn_id, peer, other_doc, Some(&policy)));
+    }
+
+    #[test]
+    fn reports_each_refused_filter() {
+        let peer = PeerId::new([1; 32]);
+        let id = DocumentFilter::Id(SedimentreeId::new([2; 32]));
+        let prefix = DocumentFilter::Prefix(vec![3]);
+
+        let (allowed, denied) = screen(Some(&OnlyIds), peer, vec![id.clone(), prefix.clone()]);
+        assert_eq!(allowed, vec![id]);
+        assert_eq!(
+            denied,
+            vec![SubscriptionDenied {
+                filter: prefix,
+                reason: "prefixes aren't allowed".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Reusing sedimentree summaries between sync rounds.
+//!
+//! Every batch sync summarizes the local sedimentree, which copies all of its
+//! chunk summaries and loose commits. A [`SummaryCache`] keeps the summary of
+//! each sedimentree at its current version, so that repeated rounds (and
+//! peers asking about a document that hasn't changed) reuse it instead. The
+//! version of a sedimentree is bumped whenever it changes, which drops its
+//! cached summary.
+//!
+//! The cache also keeps the [`SummaryToken`] of each summary, which peers send
+//! back to ask whether the sedimentree has changed since, so that answering them
+//! only takes a lookup.
+//!
+//! A cache can be shared by several [`Subduction`][crate::Subduction]
+//! instances, as long as they hold different sedimentrees.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+};
+
+use sedimentree_core::{BlobMeta, Digest, Sedimentree, SedimentreeId, SedimentreeSummary};
+
+use crate::connection::message::SummaryToken;
+
+/// Summaries of sedimentrees, by ID and version.
+#[derive(Debug, Default)]
+pub struct SummaryCache {
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    versions: HashMap<SedimentreeId, u64>,
+    summaries: HashMap<(SedimentreeId, u64), Arc<SedimentreeSummary>>,
+    tokens: HashMap<(SedimentreeId, u64), SummaryToken>,
+}
+
+/// How well a [`SummaryCache`] is doing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SummaryCacheStats {
+    /// Lookups answered from the cache.
+    pub hits: u64,
+
+    /// Lookups that had to summarize the sedimentree.
+    pub misses: u64,
+
+    /// Summaries currently cached.
+    pub entries: usize,
+}
+
+impl SummaryCache {
+    /// An empty cache.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The current version of sedimentree `id`.
+    #[must_use]
+    pub fn version(&self, id: SedimentreeId) -> u64 {
+        self.lock().versions.get(&id).copied().unwrap_or_default()
+    }
+
+    /// Note that sedimentree `id` changed, dropping its cached summary.
+    pub fn invalidate(&self, id: SedimentreeId) {
+        let mut entries = self.lock();
+        let version = entries.versions.entry(id).or_default();
+        let stale = *version;
+        *version += 1;
+        entries.summaries.remove(&(id, stale));
+        entries.tokens.remove(&(id, stale));
+    }
+
+    /// Forget sedimentree `id` altogether, as when it's dropped.
+    pub fn forget(&self, id: SedimentreeId) {
+        let mut entries = self.lock();
+        if let Some(version) = entries.versions.remove(&id) {
+            entries.summaries.remove(&(id, version));
+            entries.tokens.remove(&(id, version));
+        }
+    }
+
+    /// The summary of `tree`, the current version of sedimentree `id`, from the cache
+    /// if it's there.
+    pub fn summarize(&self, id: SedimentreeId, tree: &Sedimentree) -> Arc<SedimentreeSummary> {
+        let mut entries = self.lock();
+        let key = (id, entries.versions.get(&id).copied().unwrap_or_default());
+        if let Some(summary) = entries.summaries.get(&key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return summary.clone();
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let summary = Arc::new(tree.summarize());
+        entries.summaries.insert(key, summary.clone());
+        summary
+    }
+
+    /// The token of the summary of `tree`, the current version of sedimentree `id`,
+    /// from the cache if it's there.
+    ///
+    /// Tokens only depend on what the summary holds, so they stay the same across
+    /// restarts and between instances holding the same sedimentree.
+    pub fn token(&self, id: SedimentreeId, tree: &Sedimentree) -> SummaryToken {
+        let key = (id, self.version(id));
+        if let Some(token) = self.lock().tokens.get(&key) {
+            return *token;
+        }
+
+        let token = token_of(&self.summarize(id, tree));
+        self.lock().tokens.insert(key, token);
+        token
+    }
+
+    /// The token of the summary of `tree`, the current version of sedimentree `id`,
+    /// as if it lacked the loose commits and chunks whose blobs are `missing`.
+    ///
+    /// A responder that left those out of its diff, for want of their blobs, answers
+    /// with this token, so that the requester's next round isn't taken for one with
+    /// nothing new once the blobs arrive.
+    pub fn token_without(
+        &self,
+        id: SedimentreeId,
+        tree: &Sedimentree,
+        missing: &[BlobMeta],
+    ) -> SummaryToken {
+        if missing.is_empty() {
+            return self.token(id, tree);
+        }
+        let summary = self.summarize(id, tree);
+        let lacks = |blob: &BlobMeta| missing.contains(blob);
+        token_of(&SedimentreeSummary::new(
+            summary
+                .chunk_summaries()
+                .iter()
+                .filter(|chunk| !lacks(&chunk.blob_meta()))
+                .cloned()
+                .collect(),
+            summary
+                .loose_commits()
+                .iter()
+                .filter(|commit| !lacks(commit.blob()))
+                .cloned()
+                .collect(),
+        ))
+    }
+
+    /// Hit and miss counts since the cache was created, and its size.
+    #[must_use]
+    pub fn stats(&self) -> SummaryCacheStats {
+        SummaryCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.lock().summaries.len(),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Hash everything `summary` holds, in its (sorted) order.
+fn token_of(summary: &SedimentreeSummary) -> SummaryToken {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(&(summary.chunk_summaries().len() as u64).to_le_bytes());
+    for chunk in summary.chunk_summaries() {
+        bytes.extend_from_slice(chunk.head().as_bytes());
+        bytes.extend_from_slice(&(chunk.boundary().len() as u64).to_le_bytes());
+        for digest in chunk.boundary() {
+            bytes.extend_from_slice(digest.as_bytes());
+        }
+        bytes.extend_from_slice(chunk.blob_meta().digest().as_bytes());
+    }
+    for commit in summary.loose_commits() {
+        bytes.extend_from_slice(commit.digest().as_bytes());
+    }
+    SummaryToken::new(Digest::hash(&bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::LooseCommit;
+
+    use super::*;
+
+    #[test]
+    fn reuses_summaries_until_invalidated() {
+        let id = SedimentreeId::new([1; 32]);
+        let mut tree = Sedimentree::default();
+        let cache = SummaryCache::new();
+
+        let first = cache.summarize(id, &tree);
+        let second = cache.summarize(id, &tree);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        tree.add_commit(LooseCommit::new(
+            Digest::hash(&[1]),
+            vec![],
+            BlobMeta::new(&[1]),
+        ));
+        cache.invalidate(id);
+        let third = cache.summarize(id, &tree);
+        assert_eq!(*third, tree.summarize());
+        assert_eq!(cache.version(id), 1);
+
+        assert_eq!(
+            cache.stats(),
+            SummaryCacheStats {
+                hits: 1,
+                misses: 2,
+                entries: 1
+            }
+        );
+    }
+
+    #[test]
+    fn tokens_follow_what_summaries_hold() {
+        let id = SedimentreeId::new([1; 32]);
+        let mut tree = Sedimentree::default();
+        let cache = SummaryCache::new();
+        let empty = cache.token(id, &tree);
+        assert_eq!(cache.token(id, &tree), empty);
+
+        tree.add_commit(LooseCommit::new(
+            Digest::hash(&[1]),
+            vec![],
+            BlobMeta::new(&[1]),
+        ));
+        cache.invalidate(id);
+        let one = cache.token(id, &tree);
+        assert_ne!(one, empty);
+
+        // Another instance with the same sedimentree hands out the same token
+        assert_eq!(SummaryCache::new().token(id, &tree), one);
+    }
+
+    #[test]
+    fn tokens_without_missing_blobs_leave_out_their_commits() {
+        let id = SedimentreeId::new([1; 32]);
+        let mut tree = Sedimentree::default();
+        let cache = SummaryCache::new();
+        let empty = cache.token(id, &tree);
+
+        let blob = BlobMeta::new(&[1]);
+        tree.add_commit(LooseCommit::new(Digest::hash(&[1]), vec![], blob));
+        cache.invalidate(id);
+        assert_eq!(cache.token_without(id, &tree, &[blob]), empty);
+        assert_eq!(cache.token_without(id, &tree, &[]), cache.token(id, &tree));
+    }
+}
@@ -0,0 +1,140 @@
+//! Bookkeeping for large commits pushed to a peer in chunks.
+//!
+//! With [`Subduction::with_chunked_uploads`], a commit whose blob is larger than
+//! the chunk size is pushed as a run of [`Message::CommitUpload`]s instead of a
+//! single [`Message::LooseCommit`]. After each one, the receiver acknowledges how
+//! many bytes from the start of the blob it holds, and the sender keeps the
+//! upload in its outbox until the whole blob is acknowledged. If the connection
+//! drops part way through, the upload resumes from the last acknowledged byte
+//! once the peer connects again, rather than from zero.
+//!
+//! Receivers keep partial uploads in memory. One that restarted in between
+//! acknowledges less than the sender expects, and the sender goes back to
+//! where it left off.
+//!
+//! [`Subduction::with_chunked_uploads`]: crate::Subduction::with_chunked_uploads
+
+use sedimentree_core::{Blob, Digest, LooseCommit, SedimentreeId};
+
+use crate::connection::message::Message;
+
+/// A commit being pushed to one peer in chunks.
+#[derive(Debug, Clone)]
+pub struct BlobUpload {
+    id: SedimentreeId,
+    commit: LooseCommit,
+    blob: Blob,
+    chunk_size: u64,
+    acked: u64,
+}
+
+impl BlobUpload {
+    /// Start pushing `commit` to sedimentree `id`, `chunk_size` bytes of `blob` at a time.
+    #[must_use]
+    pub fn new(id: SedimentreeId, commit: LooseCommit, blob: Blob, chunk_size: u64) -> Self {
+        Self {
+            id,
+            commit,
+            blob,
+            chunk_size: chunk_size.max(1),
+            acked: 0,
+        }
+    }
+
+    /// The digest of the blob being pushed.
+    #[must_use]
+    pub const fn digest(&self) -> Digest {
+        self.commit.blob().digest()
+    }
+
+    /// The number of bytes the peer has acknowledged, from the start of the blob.
+    #[must_use]
+    pub const fn acked(&self) -> u64 {
+        self.acked
+    }
+
+    /// Returns true once the peer has acknowledged the whole blob.
+    #[must_use]
+    pub fn is_done(&self) -> bool {
+        self.acked >= self.blob.as_slice().len() as u64
+    }
+
+    /// Record that the peer holds the first `received` bytes of the blob.
+    ///
+    /// Returns where to push from again if the peer has fewer than it did before,
+    /// as when it lost a partial upload to a restart.
+    pub fn ack(&mut self, received: u64) -> Option<u64> {
+        let behind = received < self.acked;
+        self.acked = received.min(self.blob.as_slice().len() as u64);
+        behind.then_some(self.acked)
+    }
+
+    /// The messages pushing the blob from byte `from` to the end.
+    #[must_use]
+    pub fn messages(&self, from: u64) -> Vec<Message> {
+        let contents = self.blob.as_slice();
+        #[allow(clippy::cast_possible_truncation)]
+        let (from, chunk_size) = (from as usize, self.chunk_size as usize);
+        contents
+            .get(from..)
+            .unwrap_or_default()
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(i, data)| Message::CommitUpload {
+                id: self.id,
+                commit: self.commit.clone(),
+                offset: (from + i * chunk_size) as u64,
+                data: data.to_vec(),
+                chunk_digest: Digest::hash(data),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sedimentree_core::BlobMeta;
+
+    use super::*;
+
+    fn upload(contents: &[u8], chunk_size: u64) -> BlobUpload {
+        let commit = LooseCommit::new(Digest::hash(contents), Vec::new(), BlobMeta::new(contents));
+        BlobUpload::new(
+            SedimentreeId::new([1; 32]),
+            commit,
+            Blob::new(contents.to_vec()),
+            chunk_size,
+        )
+    }
+
+    fn offsets(messages: &[Message]) -> Vec<u64> {
+        messages
+            .iter()
+            .filter_map(|message| match message {
+                Message::CommitUpload { offset, .. } => Some(*offset),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resumes_from_the_last_acknowledged_byte() {
+        let mut upload = upload(&[7; 1000], 300);
+        assert_eq!(offsets(&upload.messages(0)), vec![0, 300, 600, 900]);
+
+        assert_eq!(upload.ack(600), None);
+        assert_eq!(offsets(&upload.messages(upload.acked())), vec![600, 900]);
+        assert!(!upload.is_done());
+
+        assert_eq!(upload.ack(1000), None);
+        assert!(upload.is_done());
+    }
+
+    #[test]
+    fn starts_over_when_the_peer_lost_its_progress() {
+        let mut upload = upload(&[7; 1000], 300);
+        assert_eq!(upload.ack(600), None);
+        assert_eq!(upload.ack(0), Some(0));
+        assert_eq!(upload.acked(), 0);
+    }
+}
@@ -0,0 +1,405 @@
+//! Randomly crashing peers mid-sync and restarting them from their storage, to
+//! catch durability bugs in the load and recovery path.
+//!
+//! Peers are connected to each other over in-memory channels. A crashed peer loses
+//! everything that isn't in its storage: its sedimentrees, its run loop, its syncs
+//! in flight, and its connections, along with the messages on them. It is then
+//! reloaded from its storage with [`Subduction::hydrate`] and reconnected.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    lock::Mutex,
+    FutureExt, StreamExt,
+};
+use sedimentree_core::{
+    future::Sendable, storage::MemoryStorage, Blob, BlobMeta, Digest, LooseCommit, Sedimentree,
+    SedimentreeId,
+};
+use subduction_core::{
+    connection::{
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+    Subduction,
+};
+use tokio::task::JoinHandle;
+
+type Peer = Subduction<Sendable, MemoryStorage, MemConnection>;
+
+const DOC: SedimentreeId = SedimentreeId::new([7; 32]);
+const SYNC_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[tokio::test]
+async fn peers_converge_despite_crash_restarts() {
+    for seed in 0..8 {
+        let mut network = Network::new(3).await;
+        let mut rng = Rng(seed * 2 + 1);
+        let mut acknowledged = BTreeSet::new();
+
+        // These are acknowledged before any crash, so must survive every restart
+        for peer in 0..network.peers.len() {
+            for n in 0..3 {
+                let label = format!("seed {seed} peer {peer} durable {n}");
+                acknowledged.insert(network.add_commit(peer, &label).await);
+            }
+        }
+
+        let mut restarts = 0;
+        for round in 0..60 {
+            let peer = rng.below(network.peers.len());
+            match rng.below(10) {
+                0 | 1 => {
+                    network.restart(peer).await;
+                    restarts += 1;
+                }
+                2..=4 => {
+                    let label = format!("seed {seed} peer {peer} round {round}");
+                    acknowledged.insert(network.add_commit(peer, &label).await);
+                }
+                _ => network.start_sync(peer),
+            }
+            for _ in 0..rng.below(4) {
+                tokio::task::yield_now().await;
+            }
+        }
+        assert!(restarts > 0, "seed {seed} never crashed a peer");
+
+        network.settle().await;
+        for (n, node) in network.peers.iter().enumerate() {
+            let held = node
+                .peer
+                .get_commits(DOC)
+                .await
+                .unwrap_or_default()
+                .into_iter()
+                .map(|commit| commit.digest())
+                .collect::<BTreeSet<_>>();
+            assert_eq!(held, acknowledged, "seed {seed}: peer {n} didn't converge");
+            for commit in node.peer.get_commits(DOC).await.unwrap_or_default() {
+                let blob = node.peer.get_local_blob(commit.blob().digest()).await;
+                assert!(
+                    matches!(blob, Ok(Some(_))),
+                    "seed {seed}: peer {n} lost the blob of {:?}",
+                    commit.digest()
+                );
+            }
+        }
+    }
+}
+
+/// Peers connected to each other, which may be crashed and restarted.
+struct Network {
+    peers: Vec<Node>,
+    next_connection: Arc<AtomicU64>,
+}
+
+/// A running peer: what it persists, and what it loses when it crashes.
+struct Node {
+    id: PeerId,
+    storage: MemoryStorage,
+    peer: Peer,
+    run: JoinHandle<()>,
+    syncs: Vec<JoinHandle<()>>,
+}
+
+impl Network {
+    async fn new(size: u8) -> Self {
+        let mut network = Self {
+            peers: Vec::new(),
+            next_connection: Arc::new(AtomicU64::new(0)),
+        };
+        for n in 0..size {
+            let id = PeerId::new([n + 1; 32]);
+            let node = Node::load(id, MemoryStorage::default()).await;
+            network.peers.push(node);
+        }
+        for a in 0..network.peers.len() {
+            for b in a + 1..network.peers.len() {
+                network.connect(a, b).await;
+            }
+        }
+        network
+    }
+
+    /// Add a commit with `label` as its contents on peer `n`, returning its digest
+    /// once it's acknowledged.
+    async fn add_commit(&mut self, n: usize, label: &str) -> Digest {
+        let blob = Blob::new(label.as_bytes().to_vec());
+        let commit = LooseCommit::new(
+            Digest::hash(label.as_bytes()),
+            vec![],
+            BlobMeta::new(blob.as_slice()),
+        );
+        self.peers[n]
+            .peer
+            .add_commit(DOC, &commit, blob)
+            .await
+            .expect("in-memory storage doesn't fail");
+        commit.digest()
+    }
+
+    /// Start peer `n` syncing with every peer, without waiting for it to finish.
+    fn start_sync(&mut self, n: usize) {
+        let node = &mut self.peers[n];
+        let peer = node.peer.clone();
+        node.syncs.push(tokio::spawn(async move {
+            peer.request_all_batch_sync_all(Some(SYNC_TIMEOUT))
+                .await
+                .ok();
+        }));
+    }
+
+    /// Crash peer `n` and reload it from its storage.
+    async fn restart(&mut self, n: usize) {
+        let crashed = self.peers[n].id;
+        let node = &mut self.peers[n];
+        node.run.abort();
+        for sync in node.syncs.drain(..) {
+            sync.abort();
+        }
+        for node in &mut self.peers {
+            if node.id != crashed {
+                node.peer
+                    .disconnect_from_peer(&crashed)
+                    .await
+                    .expect("in-memory connections disconnect");
+            }
+        }
+
+        let storage = self.peers[n].storage.clone();
+        self.peers[n] = Node::load(crashed, storage).await;
+        for other in 0..self.peers.len() {
+            if other != n {
+                self.connect(n, other).await;
+            }
+        }
+    }
+
+    /// Sync every peer with every other until none learns anything new.
+    async fn settle(&mut self) {
+        for node in &mut self.peers {
+            for sync in node.syncs.drain(..) {
+                sync.await.ok();
+            }
+        }
+        let mut last = Vec::new();
+        for _ in 0..10 {
+            for node in &self.peers {
+                node.peer
+                    .request_all_batch_sync_all(Some(SYNC_TIMEOUT))
+                    .await
+                    .expect("in-memory storage doesn't fail");
+            }
+            // Let the blobs the syncs asked for arrive
+            tokio::time::sleep(Duration::from_millis(10)).await;
+
+            let mut held = Vec::new();
+            for node in &self.peers {
+                held.push(node.peer.get_commits(DOC).await.unwrap_or_default().len());
+            }
+            if held == last && held.windows(2).all(|pair| pair[0] == pair[1]) {
+                return;
+            }
+            last = held;
+        }
+    }
+
+    async fn connect(&self, a: usize, b: usize) {
+        let (to_b, from_a) = mpsc::unbounded();
+        let (to_a, from_b) = mpsc::unbounded();
+        let (a_id, b_id) = (self.peers[a].id, self.peers[b].id);
+        let a_end = MemConnection::new(a_id, b_id, to_b, from_b, &self.next_connection);
+        let b_end = MemConnection::new(b_id, a_id, to_a, from_a, &self.next_connection);
+        self.peers[a]
+            .peer
+            .register(a_end)
+            .await
+            .expect("every peer is allowed");
+        self.peers[b]
+            .peer
+            .register(b_end)
+            .await
+            .expect("every peer is allowed");
+    }
+}
+
+impl Node {
+    /// Start peer `id` from what `storage` holds.
+    async fn load(id: PeerId, storage: MemoryStorage) -> Self {
+        let peer = Subduction::new(
+            HashMap::from_iter([(DOC, Sedimentree::default())]),
+            storage.clone(),
+            HashMap::new(),
+        );
+        peer.hydrate()
+            .await
+            .expect("in-memory storage doesn't fail");
+        let run = tokio::spawn({
+            let peer = peer.clone();
+            async move {
+                peer.run().await.ok();
+            }
+        });
+        Self {
+            id,
+            storage,
+            peer,
+            run,
+            syncs: Vec::new(),
+        }
+    }
+}
+
+/// One end of an in-memory connection between two peers.
+///
+/// Like a real network, messages sent to a peer that has gone away are lost, and
+/// a connection whose other end has gone away goes quiet.
+#[derive(Debug, Clone)]
+struct MemConnection {
+    id: u64,
+    local: PeerId,
+    remote: PeerId,
+    outbound: mpsc::UnboundedSender<Message>,
+    inbound: Arc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+    pending: Arc<Mutex<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>>,
+    nonce: Arc<AtomicU64>,
+}
+
+impl MemConnection {
+    fn new(
+        local: PeerId,
+        remote: PeerId,
+        outbound: mpsc::UnboundedSender<Message>,
+        inbound: mpsc::UnboundedReceiver<Message>,
+        next_id: &AtomicU64,
+    ) -> Self {
+        Self {
+            id: next_id.fetch_add(1, Ordering::Relaxed),
+            local,
+            remote,
+            outbound,
+            inbound: Arc::new(Mutex::new(inbound)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            nonce: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl PartialEq for MemConnection {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+/// A call that got no response.
+#[derive(Debug, thiserror::Error)]
+enum CallError {
+    #[error("the call timed out")]
+    TimedOut,
+
+    #[error("the connection closed before the call was answered")]
+    Closed,
+}
+
+impl Connection<Sendable> for MemConnection {
+    type DisconnectionError = Infallible;
+    type SendError = Infallible;
+    type RecvError = Infallible;
+    type CallError = CallError;
+
+    fn peer_id(&self) -> PeerId {
+        self.remote
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        async {
+            self.outbound.close_channel();
+            self.pending.lock().await.clear();
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), Self::SendError>> {
+        self.outbound.unbounded_send(message).ok();
+        async { Ok(()) }.boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Message, Self::RecvError>> {
+        async {
+            let mut inbound = self.inbound.lock().await;
+            loop {
+                let Some(message) = inbound.next().await else {
+                    return futures::future::pending().await;
+                };
+                if let Message::BatchSyncResponse(response) = message {
+                    match self.pending.lock().await.remove(&response.req_id) {
+                        Some(waiting) => {
+                            waiting.send(response).ok();
+                        }
+                        None => return Ok(Message::BatchSyncResponse(response)),
+                    }
+                } else {
+                    return Ok(message);
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn next_request_id(&self) -> BoxFuture<'_, RequestId> {
+        let nonce = self.nonce.fetch_add(1, Ordering::Relaxed);
+        async move {
+            RequestId {
+                requestor: self.local,
+                nonce: u128::from(nonce),
+            }
+        }
+        .boxed()
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        timeout: Option<Duration>,
+    ) -> BoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        async move {
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().await.insert(req.req_id, tx);
+            self.outbound
+                .unbounded_send(Message::BatchSyncRequest(req))
+                .ok();
+            match tokio::time::timeout(timeout.unwrap_or(SYNC_TIMEOUT), rx).await {
+                Ok(Ok(response)) => Ok(response),
+                Ok(Err(_)) => Err(CallError::Closed),
+                Err(_) => Err(CallError::TimedOut),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// A small seeded generator, so that a failing seed can be rerun.
+struct Rng(u64);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % n as u64) as usize
+    }
+}
@@ -0,0 +1,546 @@
+//! Serving sync clients from a JS host, such as a Node or WASI server.
+//!
+//! The host accepts connections however it likes (a `net` server, a `ws` server,
+//! a `MessagePort` from a worker) and hands each one to `Beelay.acceptConnection`
+//! as a port: an object with a `send` or `postMessage` method taking a
+//! `Uint8Array`, whose `onmessage` and `onclose` properties the handle sets. A
+//! message is delivered by calling `onmessage` with it, or with an event whose
+//! `data` is it, as an `ArrayBuffer` or `Uint8Array` (a Node `Buffer` will do).
+//! Hosts whose ports have no close event call `onclose` themselves.
+//!
+//! Frames are single bincode-encoded messages after the [handshake](crate::handshake),
+//! as over the sync server's socket (see [`socket`]), so a client connects to the
+//! handle as it would to a sync server. Every document the handle has, or creates later, syncs with each
+//! accepted client that's a member of it; messages about other documents are
+//! dropped.
+//!
+//! [`socket`]: crate::socket
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either, LocalBoxFuture},
+    lock::Mutex,
+    FutureExt, StreamExt,
+};
+use js_sys::{Function, Reflect, Uint8Array};
+use sedimentree_core::{future::Local, SedimentreeId};
+use subduction_core::{
+    connection::{
+        handshake::Handshake,
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+    Subduction,
+};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::{
+    connection::PeerConnection,
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+    handshake::{self, Greeting},
+    random_source,
+    socket::{self, SocketError, DEFAULT_CALL_TIMEOUT},
+    storage::DocStorage,
+    Beelay, HANDLES,
+};
+
+/// A document's connection to a client, its engine, and whether the engine has to
+/// start listening.
+type Started = (
+    AcceptedConnection,
+    Subduction<Local, DocStorage, PeerConnection>,
+    bool,
+);
+
+/// Where a document's messages go, as in [`socket`](crate::socket).
+struct Route {
+    /// The connection the route belongs to, so a stale one can't remove its successor.
+    serial: u64,
+    handle_id: u32,
+    doc_id: String,
+    inbox: mpsc::UnboundedSender<Message>,
+}
+
+/// A port accepted from the host, shared by all of a handle's documents.
+pub(crate) struct AcceptedPort {
+    port: JsValue,
+    handle_id: u32,
+    /// The port's ID among the resources tracked (see [`diagnostics`]).
+    diagnostics_id: u32,
+    /// The port's `send` or `postMessage` method.
+    sender: Function,
+    /// The client at the other end.
+    peer: PeerId,
+    /// This handle's identity, used to tag its requests.
+    requestor: PeerId,
+    next_nonce: Cell<u128>,
+    next_serial: Cell<u64>,
+    routes: RefCell<HashMap<SedimentreeId, Route>>,
+    /// Batch sync calls waiting for their response.
+    pending: RefCell<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>,
+    open: Cell<bool>,
+    /// Told when the port closes.
+    on_closed: RefCell<Option<oneshot::Sender<()>>>,
+    _on_message: Closure<dyn FnMut(JsValue)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+impl fmt::Debug for AcceptedPort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptedPort")
+            .field("peer", &self.peer)
+            .field("documents", &self.routes.borrow().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AcceptedPort {
+    /// Serve the client at the other end of `port` for handle `handle_id`, once it has
+    /// proved its key in the handshake we run as `ours`.
+    ///
+    /// Also returns a receiver told when the port closes.
+    async fn new(
+        port: JsValue,
+        handle_id: u32,
+        ours: &Handshake,
+    ) -> Result<(Rc<Self>, oneshot::Receiver<()>), JsValue> {
+        if !port.is_object() {
+            return Err(BeelayError::invalid_argument("port must be an object").into());
+        }
+        let sender = ["send", "postMessage"]
+            .into_iter()
+            .find_map(|name| {
+                Reflect::get(&port, &name.into())
+                    .ok()
+                    .and_then(|method| method.dyn_into::<Function>().ok())
+            })
+            .ok_or_else(|| {
+                BeelayError::invalid_argument("port must have a send or postMessage method")
+            })?;
+        let mut greeting = Greeting::listen(&port, handshake::sender(&port, &sender));
+        let peer = greeting.hello(ours).await?;
+        drop(greeting);
+        let requestor = ours.hello().peer_id;
+
+        let (on_closed, closed) = oneshot::channel();
+        let diagnostics_id = diagnostics::next_id();
+        diagnostics::track(
+            ResourceKind::Connection,
+            handle_id,
+            diagnostics_id.to_string(),
+        );
+        let accepted = Rc::new_cyclic(|weak: &Weak<Self>| {
+            let on_message = {
+                let weak = weak.clone();
+                Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+                    if let Some(accepted) = weak.upgrade() {
+                        accepted.receive(&event);
+                    }
+                })
+            };
+            let on_close = {
+                let weak = weak.clone();
+                Closure::<dyn FnMut()>::new(move || {
+                    if let Some(accepted) = weak.upgrade() {
+                        accepted.close();
+                    }
+                })
+            };
+            let _ = Reflect::set(&port, &"onmessage".into(), on_message.as_ref());
+            let _ = Reflect::set(&port, &"onclose".into(), on_close.as_ref());
+
+            Self {
+                port,
+                handle_id,
+                diagnostics_id,
+                sender,
+                peer,
+                requestor,
+                next_nonce: Cell::new(0),
+                next_serial: Cell::new(0),
+                routes: RefCell::new(HashMap::new()),
+                pending: RefCell::new(HashMap::new()),
+                open: Cell::new(true),
+                on_closed: RefCell::new(Some(on_closed)),
+                _on_message: on_message,
+                _on_close: on_close,
+            }
+        });
+        Ok((accepted, closed))
+    }
+
+    /// Open a connection for one document, replacing any earlier one for the same document.
+    ///
+    /// Commits that arrive for it are pulled into document `doc_id` of handle `handle_id`.
+    pub(crate) fn connection(
+        self: &Rc<Self>,
+        id: SedimentreeId,
+        handle_id: u32,
+        doc_id: String,
+    ) -> AcceptedConnection {
+        let (inbox, inbound) = mpsc::unbounded();
+        let serial = self.next_serial.get();
+        self.next_serial.set(serial + 1);
+        self.routes.borrow_mut().insert(
+            id,
+            Route {
+                serial,
+                handle_id,
+                doc_id,
+                inbox,
+            },
+        );
+        AcceptedConnection {
+            port: self.clone(),
+            id,
+            serial,
+            inbound: Rc::new(Mutex::new(inbound)),
+        }
+    }
+
+    /// Whether a document's connection is open on the port.
+    fn serves(&self, id: SedimentreeId) -> bool {
+        self.routes.borrow().contains_key(&id)
+    }
+
+    /// Stop serving the port, and close it if it has a `close` method.
+    pub(crate) fn shutdown(&self) {
+        let _ = Reflect::set(&self.port, &"onmessage".into(), &JsValue::NULL);
+        let _ = Reflect::set(&self.port, &"onclose".into(), &JsValue::NULL);
+        if let Some(close) = Reflect::get(&self.port, &"close".into())
+            .ok()
+            .and_then(|close| close.dyn_into::<Function>().ok())
+        {
+            let _ = close.call0(&self.port);
+        }
+        self.close();
+    }
+
+    fn send(&self, message: &Message) -> Result<(), SocketError> {
+        if !self.open.get() {
+            return Err(SocketError::Closed);
+        }
+        let bytes = bincode::serde::encode_to_vec(message, bincode::config::standard())
+            .map_err(|err| SocketError::Encode(err.to_string()))?;
+        self.sender
+            .call1(&self.port, &Uint8Array::from(bytes.as_slice()))
+            .map(|_| ())
+            .map_err(|err| SocketError::Send(format!("{err:?}")))
+    }
+
+    /// Route an incoming frame to the call or document waiting for it.
+    fn receive(&self, event: &JsValue) {
+        let Some(bytes) = handshake::frame_of(event) else {
+            return;
+        };
+        let Ok((message, _)) =
+            bincode::serde::decode_from_slice::<Message, _>(&bytes, bincode::config::standard())
+        else {
+            return;
+        };
+
+        if let Message::BatchSyncResponse(response) = &message {
+            if let Some(waiting) = self.pending.borrow_mut().remove(&response.req_id) {
+                let _ = waiting.send(response.clone());
+                return;
+            }
+        }
+
+        let routes = self.routes.borrow();
+        let Some(id) = socket::document_of(&message) else {
+            for route in routes.values() {
+                let _ = route.inbox.unbounded_send(message.clone());
+            }
+            return;
+        };
+        let Some(route) = routes.get(&id) else {
+            return;
+        };
+        let pull = socket::brings_commits(&message);
+        let _ = route.inbox.unbounded_send(message);
+        if pull {
+            socket::pull_soon(route.handle_id, route.doc_id.clone());
+        }
+    }
+
+    /// Stop delivering messages, fail pending calls, and say the port has closed.
+    fn close(&self) {
+        diagnostics::untrack(
+            ResourceKind::Connection,
+            self.handle_id,
+            &self.diagnostics_id.to_string(),
+        );
+        self.open.set(false);
+        self.routes.borrow_mut().clear();
+        self.pending.borrow_mut().clear();
+        if let Some(closed) = self.on_closed.borrow_mut().take() {
+            let _ = closed.send(());
+        }
+    }
+}
+
+/// One document's view of an [`AcceptedPort`].
+#[derive(Clone)]
+pub(crate) struct AcceptedConnection {
+    port: Rc<AcceptedPort>,
+    id: SedimentreeId,
+    serial: u64,
+    inbound: Rc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+}
+
+impl fmt::Debug for AcceptedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptedConnection")
+            .field("port", &self.port)
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for AcceptedConnection {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.port, &other.port) && self.serial == other.serial
+    }
+}
+
+impl Connection<Local> for AcceptedConnection {
+    type DisconnectionError = SocketError;
+    type SendError = SocketError;
+    type RecvError = SocketError;
+    type CallError = SocketError;
+
+    fn peer_id(&self) -> PeerId {
+        self.port.peer
+    }
+
+    fn disconnect(&mut self) -> LocalBoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        let mut routes = self.port.routes.borrow_mut();
+        if routes
+            .get(&self.id)
+            .is_some_and(|route| route.serial == self.serial)
+        {
+            routes.remove(&self.id);
+        }
+        drop(routes);
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn send(&self, message: Message) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
+        let result = self.port.send(&message);
+        async { result }.boxed_local()
+    }
+
+    /// Once the port closes, this never resolves: the connection is disconnected
+    /// instead, so that a client going away doesn't stop the document's other
+    /// connections.
+    fn recv(&self) -> LocalBoxFuture<'_, Result<Message, Self::RecvError>> {
+        async {
+            match self.inbound.lock().await.next().await {
+                Some(message) => Ok(message),
+                None => future::pending().await,
+            }
+        }
+        .boxed_local()
+    }
+
+    fn next_request_id(&self) -> LocalBoxFuture<'_, RequestId> {
+        let nonce = self.port.next_nonce.get();
+        self.port.next_nonce.set(nonce.wrapping_add(1));
+        let requestor = self.port.requestor;
+        async move { RequestId { requestor, nonce } }.boxed_local()
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        async move {
+            let (respond, response) = oneshot::channel();
+            let req_id = req.req_id;
+            self.port.pending.borrow_mut().insert(req_id, respond);
+            if let Err(err) = self.port.send(&Message::BatchSyncRequest(req)) {
+                self.port.pending.borrow_mut().remove(&req_id);
+                return Err(err);
+            }
+
+            let timeout = socket::sleep(timeout.unwrap_or(DEFAULT_CALL_TIMEOUT));
+            match future::select(response, Box::pin(timeout)).await {
+                Either::Left((response, _)) => response.map_err(|_| SocketError::Closed),
+                Either::Right(((), _)) => {
+                    self.port.pending.borrow_mut().remove(&req_id);
+                    Err(SocketError::Timeout)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+/// Sync every document of handle `handle_id` that the client at `accepted` is a
+/// member of with it.
+fn serve(handle_id: u32, accepted: &Rc<AcceptedPort>) -> Result<(), JsValue> {
+    let member = hex::encode(accepted.peer.as_bytes());
+    let started = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        let started = ctx
+            .documents
+            .iter_mut()
+            .filter(|(_, doc)| doc.members.access(&member, &ctx.groups).is_some())
+            .map(|(doc_id, doc)| {
+                let conn = accepted.connection(doc.sed_id, handle_id, doc_id.clone());
+                let start_listening = !std::mem::replace(&mut doc.listening, true);
+                (conn, doc.subduction.clone(), start_listening)
+            })
+            .collect::<Vec<_>>();
+        Ok::<_, JsValue>(started)
+    })?;
+    register(started);
+    Ok(())
+}
+
+/// Sync document `doc_id` of handle `handle_id` with every client the handle serves
+/// that's a member of it and isn't syncing it yet, and stop syncing it with those
+/// that aren't members (any more).
+pub(crate) fn serve_document(handle_id: u32, doc_id: &str) -> Result<(), JsValue> {
+    let (started, engine, dropped) = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        let accepted = ctx.accepted.values().cloned().collect::<Vec<_>>();
+        let doc = ctx
+            .documents
+            .get_mut(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        let (members, dropped): (Vec<_>, Vec<_>) = accepted.into_iter().partition(|accepted| {
+            let member = hex::encode(accepted.peer.as_bytes());
+            doc.members.access(&member, &ctx.groups).is_some()
+        });
+        let started = members
+            .iter()
+            .filter(|accepted| !accepted.serves(doc.sed_id))
+            .map(|accepted| {
+                let conn = accepted.connection(doc.sed_id, handle_id, doc_id.to_string());
+                let start_listening = !std::mem::replace(&mut doc.listening, true);
+                (conn, doc.subduction.clone(), start_listening)
+            })
+            .collect::<Vec<_>>();
+        let dropped = dropped
+            .into_iter()
+            .filter(|accepted| accepted.serves(doc.sed_id))
+            .map(|accepted| accepted.peer)
+            .collect::<Vec<_>>();
+        Ok::<_, JsValue>((started, doc.subduction.clone(), dropped))
+    })?;
+    register(started);
+    if !dropped.is_empty() {
+        wasm_bindgen_futures::spawn_local(async move {
+            let mut engine = engine;
+            for peer in dropped {
+                let _ = engine.disconnect_from_peer(&peer).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Register each connection with its document's engine, starting the engines that
+/// aren't listening yet.
+fn register(started: Vec<Started>) {
+    for (conn, engine, start_listening) in started {
+        wasm_bindgen_futures::spawn_local(async move {
+            if engine
+                .register(PeerConnection::Accepted(conn))
+                .await
+                .is_err()
+            {
+                return;
+            }
+            if start_listening {
+                let _ = engine.run().await;
+            }
+        });
+    }
+}
+
+/// Forget a client whose port has closed, disconnecting every document from it.
+async fn forget_client(handle_id: u32, peer: PeerId) {
+    let engines = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let Some(ctx) = handles.get_mut(&handle_id) else {
+            return Vec::new();
+        };
+        ctx.accepted.remove(&peer);
+        ctx.documents
+            .values()
+            .map(|doc| doc.subduction.clone())
+            .collect::<Vec<_>>()
+    });
+    for mut engine in engines {
+        let _ = engine.disconnect_from_peer(&peer).await;
+    }
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Serve a sync client over `port`, a connection the JS host has accepted: an
+    /// object with a `send` or `postMessage` method, whose `onmessage` and `onclose`
+    /// properties the handle sets.
+    ///
+    /// Every document the handle has, or creates later, that the client is a member of
+    /// syncs with it until the port closes, `disconnect` is called with the client's
+    /// peer ID, or the client stops being a member. The peer ID is the key the client
+    /// proves it holds in the handshake, which it starts as soon as it connects, so the
+    /// host should hand the port over in the task it accepted it in. Resolves to the
+    /// peer ID once the handshake is done.
+    #[wasm_bindgen(js_name = acceptConnection)]
+    pub async fn accept_connection(&self, port: JsValue) -> Result<String, JsValue> {
+        let nonce = random_source(self.id)?.bytes()?;
+        let handshake = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            Ok::<_, JsValue>(Handshake::new(&ctx.signing_key, nonce))
+        })?;
+
+        let (accepted, closed) = AcceptedPort::new(port, self.id, &handshake).await?;
+        let peer = accepted.peer;
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let serving = ctx.sync.as_ref().is_some_and(|sync| sync.server() == peer);
+            if serving || ctx.accepted.contains_key(&peer) {
+                accepted.shutdown();
+                return Err(JsValue::from(BeelayError::AlreadyConnected(
+                    "peer is already connected",
+                )));
+            }
+            ctx.accepted.insert(peer, accepted.clone());
+            Ok::<_, JsValue>(())
+        })?;
+        serve(self.id, &accepted)?;
+
+        let handle_id = self.id;
+        wasm_bindgen_futures::spawn_local(async move {
+            if closed.await.is_ok() {
+                forget_client(handle_id, peer).await;
+            }
+        });
+        Ok(hex::encode(peer.as_bytes()))
+    }
+}
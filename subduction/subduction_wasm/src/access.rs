@@ -0,0 +1,480 @@
+//! Document membership and the access request / approval workflow.
+//!
+//! A peer that isn't a member of a document can ask for access with a signed
+//! [`AccessRequest`]. Requests go over the handle's connection to its sync server,
+//! which passes them on to the other peers connected to it, as described in
+//! [`subduction_core::sync::access`]. A request names the document by its
+//! [`document_key`], since the requester doesn't know the sedimentree behind the
+//! document ID. The handles of the document's admins keep the requests whose
+//! signature checks out, and approve or deny them. Approving a request adds the
+//! requester to the document's members.
+
+use std::collections::{HashMap, HashSet};
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use sedimentree_core::{Digest, SedimentreeId};
+use serde::{Deserialize, Serialize};
+
+/// The level of access a member has to a document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Admin,
+}
+
+/// The members of a document.
+///
+/// Individuals are keyed by hex-encoded verifying key. Groups are keyed by
+/// group ID, and grant their access to every member of the group, so changes
+/// to a group's membership apply to the document straight away.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct Membership {
+    members: HashMap<String, Access>,
+    groups: HashMap<String, Access>,
+    /// The peer that created the document. Empty in backups made before it was recorded.
+    #[serde(default)]
+    creator: String,
+}
+
+impl Membership {
+    /// A membership with `owner` as its creator and only (admin) member.
+    pub(crate) fn with_owner(owner: String) -> Self {
+        Self {
+            members: HashMap::from([(owner.clone(), Access::Admin)]),
+            groups: HashMap::new(),
+            creator: owner,
+        }
+    }
+
+    pub(crate) fn creator(&self) -> &str {
+        &self.creator
+    }
+
+    /// The highest access `peer` has, either directly or through a group.
+    pub(crate) fn access(&self, peer: &str, groups: &HashMap<String, Group>) -> Option<Access> {
+        let via_groups = self
+            .groups
+            .iter()
+            .filter(|(group_id, _)| {
+                groups
+                    .get(*group_id)
+                    .is_some_and(|group| group.contains(peer))
+            })
+            .map(|(_, access)| *access);
+
+        self.members
+            .get(peer)
+            .copied()
+            .into_iter()
+            .chain(via_groups)
+            .max()
+    }
+
+    pub(crate) fn is_admin(&self, peer: &str, groups: &HashMap<String, Group>) -> bool {
+        self.access(peer, groups) == Some(Access::Admin)
+    }
+
+    pub(crate) fn insert(&mut self, peer: String, access: Access) {
+        self.members.insert(peer, access);
+    }
+
+    /// Remove `peer` as a member. Any access it has through groups is kept.
+    pub(crate) fn remove(&mut self, peer: &str) -> bool {
+        self.members.remove(peer).is_some()
+    }
+
+    /// Whether `peer` is the only direct admin, so that the document would have none
+    /// left if it were removed or given less access.
+    pub(crate) fn is_last_admin(&self, peer: &str) -> bool {
+        self.members.get(peer) == Some(&Access::Admin)
+            && self
+                .members
+                .values()
+                .filter(|access| **access == Access::Admin)
+                .count()
+                == 1
+    }
+
+    /// The peers that are members in their own right, not through a group.
+    pub(crate) fn members(&self) -> impl Iterator<Item = (&String, Access)> {
+        self.members.iter().map(|(peer, access)| (peer, *access))
+    }
+
+    pub(crate) fn insert_group(&mut self, group_id: String, access: Access) {
+        self.groups.insert(group_id, access);
+    }
+
+    pub(crate) fn remove_group(&mut self, group_id: &str) -> bool {
+        self.groups.remove(group_id).is_some()
+    }
+}
+
+/// Filters accepted by `Beelay.listDocuments`. Every filter that is set must match.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DocumentFilter {
+    /// The least access the listing handle must have.
+    pub(crate) access: Option<Access>,
+    /// A peer, other than the document's creator, that must have access.
+    pub(crate) shared_with: Option<String>,
+    /// The peer that must have created the document.
+    pub(crate) created_by: Option<String>,
+}
+
+impl DocumentFilter {
+    /// Whether a document with `membership` matches, given the listing handle's `access` to it.
+    pub(crate) fn matches(
+        &self,
+        membership: &Membership,
+        access: Option<Access>,
+        groups: &HashMap<String, Group>,
+    ) -> bool {
+        let access_ok = self.access.is_none_or(|least| access >= Some(least));
+        let shared_ok = self.shared_with.as_deref().is_none_or(|peer| {
+            peer != membership.creator && membership.access(peer, groups).is_some()
+        });
+        let creator_ok = self
+            .created_by
+            .as_deref()
+            .is_none_or(|peer| peer == membership.creator);
+        access_ok && shared_ok && creator_ok
+    }
+}
+
+/// A named set of peers that can be made a member of documents as a unit.
+///
+/// A group is signed by its owner, and re-signed whenever its members change, so
+/// only the owner can say who is in it: a group read back from storage, a snapshot,
+/// or a backup is only kept if its signature checks out (see [`verified_groups`]).
+///
+/// These aren't Keyhive groups. Keyhive is only built with the `encryption`
+/// feature, and the document membership groups are members of is the handle's own,
+/// so a group is checked the same way as an [`AccessRequest`]: by the signature of
+/// the peer it names.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Group {
+    name: String,
+    owner: String,
+    members: HashSet<String>,
+    /// The owner's signature over the rest and the group ID, hex encoded. Empty in
+    /// backups made before groups were signed.
+    #[serde(default)]
+    signature: String,
+}
+
+impl Group {
+    /// A group called `name` with ID `group_id`, owned by `owner`, who is its first member.
+    pub(crate) fn new(group_id: &str, name: String, owner: &SigningKey) -> Self {
+        let owner_id = hex::encode(owner.verifying_key().as_bytes());
+        let mut group = Self {
+            name,
+            members: HashSet::from([owner_id.clone()]),
+            owner: owner_id,
+            signature: String::new(),
+        };
+        group.sign(group_id, owner);
+        group
+    }
+
+    pub(crate) fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn owner(&self) -> &str {
+        &self.owner
+    }
+
+    pub(crate) fn contains(&self, peer: &str) -> bool {
+        self.members.contains(peer)
+    }
+
+    /// Add `peer`, re-signing the group with `owner`'s key.
+    pub(crate) fn insert(&mut self, group_id: &str, peer: String, owner: &SigningKey) -> bool {
+        let inserted = self.members.insert(peer);
+        self.sign(group_id, owner);
+        inserted
+    }
+
+    /// Remove `peer`, re-signing the group with `owner`'s key.
+    pub(crate) fn remove(&mut self, group_id: &str, peer: &str, owner: &SigningKey) -> bool {
+        let removed = self.members.remove(peer);
+        self.sign(group_id, owner);
+        removed
+    }
+
+    pub(crate) fn members(&self) -> impl Iterator<Item = &String> {
+        self.members.iter()
+    }
+
+    fn sign(&mut self, group_id: &str, owner: &SigningKey) {
+        self.signature = hex::encode(owner.sign(&self.signing_payload(group_id)).to_bytes());
+    }
+
+    /// Whether the group, as the group `group_id`, was signed by its owner.
+    pub(crate) fn verify(&self, group_id: &str) -> bool {
+        verify(
+            &self.owner,
+            &self.signature,
+            &self.signing_payload(group_id),
+        )
+    }
+
+    fn signing_payload(&self, group_id: &str) -> Vec<u8> {
+        let mut members = self.members.iter().map(String::as_str).collect::<Vec<_>>();
+        members.sort_unstable();
+        let mut payload = b"subduction/group/v1".to_vec();
+        for field in [group_id, &self.name, &self.owner]
+            .into_iter()
+            .chain(members)
+        {
+            payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+            payload.extend_from_slice(field.as_bytes());
+        }
+        payload
+    }
+}
+
+/// The groups in `groups` signed by their owners, once those owned by `signer` from
+/// before groups were signed have been signed.
+pub(crate) fn verified_groups(
+    groups: HashMap<String, Group>,
+    signer: &SigningKey,
+) -> HashMap<String, Group> {
+    let signer_id = hex::encode(signer.verifying_key().as_bytes());
+    groups
+        .into_iter()
+        .filter_map(|(group_id, mut group)| {
+            if group.signature.is_empty() && group.owner == signer_id {
+                group.sign(&group_id, signer);
+            }
+            group.verify(&group_id).then_some((group_id, group))
+        })
+        .collect()
+}
+
+/// A request from a non-member for access to a document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct AccessRequest {
+    pub(crate) request_id: String,
+    pub(crate) doc_id: String,
+    pub(crate) requester: String,
+    pub(crate) message: String,
+    pub(crate) created_at: f64,
+    /// The requester's signature over the rest, hex encoded.
+    signature: String,
+}
+
+impl AccessRequest {
+    /// Create a request for `doc_id` signed by `signer`.
+    pub(crate) fn new(
+        request_id: String,
+        doc_id: String,
+        message: String,
+        created_at: f64,
+        signer: &SigningKey,
+    ) -> Self {
+        let requester = hex::encode(signer.verifying_key().as_bytes());
+        let payload = signing_payload(&request_id, &doc_id, &requester, &message, created_at);
+        Self {
+            signature: hex::encode(signer.sign(&payload).to_bytes()),
+            request_id,
+            doc_id,
+            requester,
+            message,
+            created_at,
+        }
+    }
+
+    /// Check that the request was signed by the requester it names.
+    pub(crate) fn verify(&self) -> bool {
+        let payload = signing_payload(
+            &self.request_id,
+            &self.doc_id,
+            &self.requester,
+            &self.message,
+            self.created_at,
+        );
+        verify(&self.requester, &self.signature, &payload)
+    }
+
+    /// The request as it goes over the wire.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        bincode::serde::encode_to_vec(self, bincode::config::standard())
+    }
+
+    /// Read a request off the wire, if it's well formed and its signature checks out.
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        let (request, _): (Self, usize) =
+            bincode::serde::decode_from_slice(bytes, bincode::config::standard()).ok()?;
+        request.verify().then_some(request)
+    }
+}
+
+/// Whether `signature` (hex encoded) is the signature over `payload` of the peer
+/// whose hex-encoded verifying key is `signer`.
+fn verify(signer: &str, signature: &str, payload: &[u8]) -> bool {
+    let Ok(bytes) = hex::decode(signer) else {
+        return false;
+    };
+    let Ok(bytes) = <[u8; 32]>::try_from(bytes) else {
+        return false;
+    };
+    let Ok(key) = VerifyingKey::from_bytes(&bytes) else {
+        return false;
+    };
+    let Some(signature) = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+    else {
+        return false;
+    };
+    key.verify(payload, &signature).is_ok()
+}
+
+fn signing_payload(
+    request_id: &str,
+    doc_id: &str,
+    requester: &str,
+    message: &str,
+    created_at: f64,
+) -> Vec<u8> {
+    let mut payload = b"subduction/access-request/v1".to_vec();
+    for field in [request_id, doc_id, requester, message] {
+        payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        payload.extend_from_slice(field.as_bytes());
+    }
+    payload.extend_from_slice(&created_at.to_le_bytes());
+    payload
+}
+
+/// The ID access requests for the document `doc_id` are sent under, which the
+/// requester and the document's admins both know.
+pub(crate) fn document_key(doc_id: &str) -> SedimentreeId {
+    let digest = Digest::hash(format!("subduction/access-request/{doc_id}").as_bytes());
+    SedimentreeId::new(*digest.as_bytes())
+}
+
+/// The access requests a handle has received and not yet decided, by request ID.
+#[derive(Debug, Default)]
+pub(crate) struct Inbox(HashMap<String, AccessRequest>);
+
+impl Inbox {
+    /// Keep `request` until it's decided.
+    pub(crate) fn insert(&mut self, request: AccessRequest) {
+        self.0.insert(request.request_id.clone(), request);
+    }
+
+    /// The requests for which `is_admin_of(doc_id)` holds, oldest first.
+    pub(crate) fn pending(&self, is_admin_of: impl Fn(&str) -> bool) -> Vec<AccessRequest> {
+        let mut pending = self
+            .0
+            .values()
+            .filter(|request| is_admin_of(&request.doc_id))
+            .cloned()
+            .collect::<Vec<_>>();
+        pending.sort_by(|a, b| a.created_at.total_cmp(&b.created_at));
+        pending
+    }
+
+    /// Take a request out so it can be decided, provided `is_admin_of(doc_id)` holds
+    /// for the document it targets.
+    pub(crate) fn take(
+        &mut self,
+        request_id: &str,
+        is_admin_of: impl Fn(&str) -> bool,
+    ) -> Option<AccessRequest> {
+        let doc_id = &self.0.get(request_id)?.doc_id;
+        if is_admin_of(doc_id) {
+            self.0.remove(request_id)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_last_admin_is_kept() {
+        let mut members = Membership::with_owner("owner".to_string());
+        assert!(members.is_last_admin("owner"));
+
+        members.insert("other".to_string(), Access::Admin);
+        assert!(!members.is_last_admin("owner"));
+        assert!(members.remove("owner"));
+        assert!(members.is_last_admin("other"));
+        assert!(!members.remove("owner"));
+    }
+
+    #[test]
+    fn only_requests_signed_by_their_requester_are_read() {
+        let signer = SigningKey::from_bytes(&[1; 32]);
+        let request = AccessRequest::new(
+            "request".to_string(),
+            "doc".to_string(),
+            "let me in".to_string(),
+            1.0,
+            &signer,
+        );
+        let bytes = request.encode().unwrap();
+        let read = AccessRequest::decode(&bytes).unwrap();
+        assert_eq!(read.requester, request.requester);
+        assert!(read.verify());
+
+        let forged = AccessRequest {
+            message: "make me an admin".to_string(),
+            ..request
+        };
+        assert!(AccessRequest::decode(&forged.encode().unwrap()).is_none());
+    }
+
+    #[test]
+    fn removing_a_member_keeps_group_access() {
+        let owner = SigningKey::from_bytes(&[1; 32]);
+        let mut group = Group::new("team", "Team".to_string(), &owner);
+        group.insert("team", "peer".to_string(), &owner);
+        let groups = HashMap::from([("team".to_string(), group)]);
+
+        let owner_id = hex::encode(owner.verifying_key().as_bytes());
+        let mut members = Membership::with_owner(owner_id);
+        members.insert("peer".to_string(), Access::Admin);
+        members.insert_group("team".to_string(), Access::Read);
+        assert!(members.remove("peer"));
+        assert_eq!(members.access("peer", &groups), Some(Access::Read));
+    }
+
+    #[test]
+    fn only_groups_signed_by_their_owner_are_kept() {
+        let (owner, other) = (
+            SigningKey::from_bytes(&[1; 32]),
+            SigningKey::from_bytes(&[2; 32]),
+        );
+        let mut group = Group::new("team", "Team".to_string(), &owner);
+        group.insert("team", "peer".to_string(), &owner);
+        assert!(group.verify("team"));
+        assert!(!group.verify("other team"));
+
+        let mut forged = group.clone();
+        forged.members.insert("intruder".to_string());
+        let mut unsigned = Group::new("old", "Old".to_string(), &owner);
+        unsigned.signature.clear();
+        let groups = HashMap::from([
+            ("team".to_string(), group),
+            ("forged".to_string(), forged),
+            ("old".to_string(), unsigned.clone()),
+        ]);
+
+        // Groups from before signing are only signed by their owner's handle
+        let mut kept = verified_groups(groups.clone(), &owner)
+            .into_keys()
+            .collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, ["old", "team"]);
+        assert_eq!(verified_groups(groups, &other).len(), 1);
+    }
+}
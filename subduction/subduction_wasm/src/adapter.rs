@@ -0,0 +1,286 @@
+//! Persisting documents through a storage adapter supplied by the app.
+//!
+//! An adapter is any JS object with the methods of the original Beelay storage
+//! interface, so apps can keep documents in OPFS, localStorage, a Durable
+//! Object, or anywhere else:
+//!
+//! ```text
+//! load(key: string[]): Promise<Uint8Array | undefined>
+//! save(key: string[], data: Uint8Array): Promise<void>
+//! remove(key: string[]): Promise<void>
+//! listOneLevel(prefix: string[]): Promise<string[][]>
+//! ```
+//!
+//! The handle is saved under `["handle"]`, its changes feed under `["feed"]`,
+//! and each document's data under
+//! `[<sedimentree id>, "commits" | "chunks" | "blobs", <digest>]`. A handle
+//! loaded with a `namespace` puts `["ns", <namespace>]` in front of all of
+//! these, so handles sharing one adapter keep out of each other's way.
+
+use futures::{future::LocalBoxFuture, FutureExt};
+use js_sys::{Array, Function, Promise, Uint8Array};
+use sedimentree_core::{
+    future::Local,
+    storage::{
+        codec,
+        header::{Codec, StorageHeader},
+        Storage,
+    },
+    Blob, Chunk, Digest, LooseCommit, SedimentreeId,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+
+use crate::storage::{from_cbor, to_cbor, StorageError};
+
+/// The methods an adapter must have.
+const METHODS: [&str; 4] = ["load", "save", "remove", "listOneLevel"];
+
+/// The key the handle is saved under.
+pub(crate) const HANDLE_KEY: &str = "handle";
+
+/// The key the handle's changes feed is saved under.
+pub(crate) const FEED_KEY: &str = "feed";
+
+/// The key namespaces are kept under.
+pub(crate) const NAMESPACES_KEY: &str = "ns";
+
+const COMMITS: &str = "commits";
+const CHUNKS: &str = "chunks";
+const BLOBS: &str = "blobs";
+
+/// A JS storage adapter, seen through the handle's namespace.
+///
+/// Keys passed in and handed back are relative to the namespace.
+#[derive(Debug, Clone)]
+pub(crate) struct JsStorageAdapter {
+    adapter: JsValue,
+    /// `["ns", <namespace>]`, or nothing outside any namespace.
+    root: Vec<String>,
+}
+
+impl JsStorageAdapter {
+    /// Wrap `adapter`, checking that it has every method of the interface, with keys in
+    /// `namespace` if there is one.
+    pub(crate) fn new(adapter: JsValue, namespace: Option<&str>) -> Result<Self, StorageError> {
+        for method in METHODS {
+            if !js_sys::Reflect::get(&adapter, &method.into())?.is_function() {
+                return Err(StorageError::new(format!(
+                    "storage adapter has no {method} method"
+                )));
+            }
+        }
+        let root = namespace
+            .map(|namespace| vec![NAMESPACES_KEY.to_string(), namespace.to_string()])
+            .unwrap_or_default();
+        Ok(Self { adapter, root })
+    }
+
+    /// The namespaces with anything saved through the adapter.
+    pub(crate) async fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        let keys = self.start("listOneLevel", &[path(&[NAMESPACES_KEY])])?.await?;
+        let keys: Vec<Vec<String>> = serde_wasm_bindgen::from_value(keys)
+            .map_err(|err| StorageError::new(format!("invalid listOneLevel result: {err}")))?;
+        Ok(keys.into_iter().filter_map(|key| key.get(1).cloned()).collect())
+    }
+
+    pub(crate) async fn load(&self, key: &[&str]) -> Result<Option<Vec<u8>>, StorageError> {
+        let value = self.start("load", &[self.path(key)])?.await?;
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+        Ok(Some(Uint8Array::new(&value).to_vec()))
+    }
+
+    /// Save `data` under `key`.
+    ///
+    /// The adapter is called straight away, so saves reach it in the order they're made,
+    /// even if the returned future is never awaited.
+    pub(crate) fn save(
+        &self,
+        key: &[&str],
+        data: &[u8],
+    ) -> Result<impl Future<Output = Result<(), StorageError>> + use<>, StorageError> {
+        let saved = self.start("save", &[self.path(key), Uint8Array::from(data).into()])?;
+        Ok(async move { saved.await.map(drop).map_err(StorageError::from) })
+    }
+
+    pub(crate) async fn list_one_level(
+        &self,
+        prefix: &[&str],
+    ) -> Result<Vec<Vec<String>>, StorageError> {
+        let keys = self.start("listOneLevel", &[self.path(prefix)])?.await?;
+        let keys: Vec<Vec<String>> = serde_wasm_bindgen::from_value(keys)
+            .map_err(|err| StorageError::new(format!("invalid listOneLevel result: {err}")))?;
+        Ok(keys
+            .into_iter()
+            .map(|key| key.get(self.root.len()..).unwrap_or_default().to_vec())
+            .collect())
+    }
+
+    pub(crate) async fn remove(&self, key: &[&str]) -> Result<(), StorageError> {
+        self.start("remove", &[self.path(key)])?.await?;
+        Ok(())
+    }
+
+    /// Remove every key under `prefix`, and `prefix` itself.
+    pub(crate) async fn remove_all(&self, prefix: Vec<String>) -> Result<(), StorageError> {
+        let mut pending = vec![prefix];
+        while let Some(prefix) = pending.pop() {
+            let prefix_ref = prefix.iter().map(String::as_str).collect::<Vec<_>>();
+            let children = self.list_one_level(&prefix_ref).await?;
+            if children.is_empty() && !prefix.is_empty() {
+                self.remove(&prefix_ref).await?;
+            }
+            pending.extend(children);
+        }
+        Ok(())
+    }
+
+    /// Call `method`, returning a future for the promise it returns.
+    fn start(&self, method: &str, args: &[JsValue]) -> Result<JsFuture, StorageError> {
+        let function =
+            js_sys::Reflect::get(&self.adapter, &method.into())?.dyn_into::<Function>()?;
+        let result = function.apply(&self.adapter, &args.iter().collect::<Array>())?;
+        Ok(JsFuture::from(Promise::resolve(&result)))
+    }
+
+    /// `key` in the namespace, as the JS array of strings adapters expect.
+    fn path(&self, key: &[&str]) -> JsValue {
+        let root = self.root.iter().map(String::as_str);
+        path(&root.chain(key.iter().copied()).collect::<Vec<_>>())
+    }
+}
+
+/// One document's commits, chunks, and blobs in a [`JsStorageAdapter`].
+#[derive(Debug, Clone)]
+pub(crate) struct AdapterStorage {
+    adapter: JsStorageAdapter,
+    /// The document's sedimentree ID, in hex.
+    prefix: String,
+    header: StorageHeader,
+}
+
+impl AdapterStorage {
+    /// Storage for sedimentree `id`, encoding blobs with `codec`.
+    pub(crate) fn new(adapter: JsStorageAdapter, id: SedimentreeId, codec: Codec) -> Self {
+        Self {
+            adapter,
+            prefix: id.to_string(),
+            header: StorageHeader::default().with_codec(codec),
+        }
+    }
+
+    async fn put<T: Serialize>(
+        &self,
+        kind: &str,
+        digest: Digest,
+        value: &T,
+    ) -> Result<(), StorageError> {
+        let digest = digest.to_string();
+        self.adapter
+            .save(&[&self.prefix, kind, &digest], &to_cbor(value)?)?
+            .await
+    }
+
+    /// Every record of one kind that belongs to this document.
+    async fn get_all<T: DeserializeOwned>(&self, kind: &str) -> Result<Vec<T>, StorageError> {
+        let keys = self.adapter.list_one_level(&[&self.prefix, kind]).await?;
+        let mut records = Vec::with_capacity(keys.len());
+        for key in keys {
+            let key = key.iter().map(String::as_str).collect::<Vec<_>>();
+            if let Some(bytes) = self.adapter.load(&key).await? {
+                records.push(from_cbor(&bytes)?);
+            }
+        }
+        Ok(records)
+    }
+}
+
+impl Storage<Local> for AdapterStorage {
+    type Error = StorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        self.get_all(COMMITS).boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            self.put(COMMITS, loose_commit.blob().digest(), &loose_commit)
+                .await
+        }
+        .boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            self.put(CHUNKS, chunk.summary().blob_meta().digest(), &chunk)
+                .await
+        }
+        .boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        self.get_all(CHUNKS).boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        async move {
+            let digest = Digest::hash(blob.contents());
+            let stored = codec::encode_blob(self.header.codec(), &blob);
+            self.adapter
+                .save(&[&self.prefix, BLOBS, &digest.to_string()], &stored)?
+                .await?;
+            Ok(digest)
+        }
+        .boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move {
+            let digest = blob_digest.to_string();
+            let Some(stored) = self.adapter.load(&[&self.prefix, BLOBS, &digest]).await? else {
+                return Ok(None);
+            };
+            codec::decode_blob(&stored).map(Some).map_err(|err| {
+                StorageError::new(format!("failed to decode blob {blob_digest}: {err}"))
+            })
+        }
+        .boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            for digest in blob_digests {
+                let digest = digest.to_string();
+                for kind in [COMMITS, CHUNKS, BLOBS] {
+                    self.adapter.remove(&[&self.prefix, kind, &digest]).await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// A key as the JS array of strings adapters expect.
+fn path(key: &[&str]) -> JsValue {
+    key.iter()
+        .map(|part| JsValue::from_str(part))
+        .collect::<Array>()
+        .into()
+}
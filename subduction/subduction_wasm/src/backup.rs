@@ -0,0 +1,199 @@
+//! Passphrase-encrypted backups of an entire handle.
+//!
+//! A backup holds the handle's identity, every document's commit history and
+//! membership, the groups it knows about, and the keys of its encrypted
+//! documents. It is CBOR-encoded and then encrypted with [age]'s scrypt
+//! passphrase recipient, so it can be stored anywhere and restored without any
+//! relay.
+//!
+//! The same snapshot, without commits or encryption, is how a handle saves itself to
+//! persistent storage, and with commits but without encryption, it's what `save`
+//! returns for embedders to persist however they can. Encrypted backups need the
+//! `backup` feature.
+//!
+//! [age]: https://age-encryption.org
+
+use std::collections::HashMap;
+#[cfg(feature = "backup")]
+use std::{
+    io::{Read, Write},
+    iter,
+};
+
+#[cfg(feature = "backup")]
+use age::secrecy::SecretString;
+#[cfg(feature = "backup")]
+use js_sys::Uint8Array;
+use sedimentree_core::{storage::header::Codec, SedimentreeId};
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "backup")]
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    access::{Group, Membership},
+    freeze::FreezeProof,
+    CommitInput,
+};
+#[cfg(feature = "backup")]
+use crate::{error::BeelayError, restore_snapshot, Beelay, HANDLES};
+
+/// The current backup format version.
+const FORMAT_VERSION: u32 = 1;
+
+/// Everything needed to recreate a handle.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) version: u32,
+    pub(crate) signing_key: [u8; 32],
+    pub(crate) documents: Vec<DocumentSnapshot>,
+    pub(crate) groups: HashMap<String, Group>,
+    /// The handle's Keyhive agent, if it has encrypted documents (see
+    /// [`crate::encryption`]).
+    #[serde(default)]
+    pub(crate) keyhive: Option<Vec<u8>>,
+}
+
+/// A single document in a [`Snapshot`].
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DocumentSnapshot {
+    pub(crate) doc_id: String,
+    pub(crate) sed_id: SedimentreeId,
+    pub(crate) commits: Vec<CommitInput>,
+    pub(crate) members: Membership,
+    /// Missing from backups made before documents had codecs.
+    #[serde(default)]
+    pub(crate) codec: Codec,
+    /// Missing from backups made before documents could be ephemeral.
+    #[serde(default)]
+    pub(crate) ttl_secs: Option<u64>,
+    /// The hashes of every commit the document has seen, which can include ones it no
+    /// longer has, such as expired commits. Only kept along with the commits.
+    #[serde(default)]
+    pub(crate) seen: Vec<String>,
+    /// The document's heads, sorted, to check the commits against. Only kept along
+    /// with the commits.
+    #[serde(default)]
+    pub(crate) heads: Vec<String>,
+    /// The proof the document was frozen with, if it's frozen.
+    #[serde(default)]
+    pub(crate) frozen: Option<FreezeProof>,
+    /// The ID of the Keyhive document the document's commits are encrypted with, if
+    /// they are.
+    #[serde(default)]
+    pub(crate) encryption: Option<[u8; 32]>,
+}
+
+impl Snapshot {
+    pub(crate) const fn new(
+        signing_key: [u8; 32],
+        documents: Vec<DocumentSnapshot>,
+        groups: HashMap<String, Group>,
+        keyhive: Option<Vec<u8>>,
+    ) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            signing_key,
+            documents,
+            groups,
+            keyhive,
+        }
+    }
+
+    /// Encode the snapshot, as `save` returns it.
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes)
+            .map_err(|err| format!("failed to encode backup: {err}"))?;
+        Ok(bytes)
+    }
+
+    /// Decode a snapshot produced by [`Snapshot::encode`].
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let snapshot: Self =
+            ciborium::from_reader(bytes).map_err(|err| format!("invalid backup: {err}"))?;
+        if snapshot.version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported backup version {} (expected {FORMAT_VERSION})",
+                snapshot.version
+            ));
+        }
+        Ok(snapshot)
+    }
+
+    /// Encode and encrypt the snapshot under `passphrase`.
+    #[cfg(feature = "backup")]
+    pub(crate) fn encrypt(&self, passphrase: String) -> Result<Vec<u8>, String> {
+        let plaintext = self.encode()?;
+
+        let encryptor = age::Encryptor::with_user_passphrase(SecretString::from(passphrase));
+        let mut ciphertext = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(&mut ciphertext)
+            .map_err(|err| format!("failed to encrypt backup: {err}"))?;
+        writer
+            .write_all(&plaintext)
+            .and_then(|()| writer.finish())
+            .map_err(|err| format!("failed to encrypt backup: {err}"))?;
+
+        Ok(ciphertext)
+    }
+
+    /// Decrypt a backup produced by [`Snapshot::encrypt`].
+    #[cfg(feature = "backup")]
+    pub(crate) fn decrypt(ciphertext: &[u8], passphrase: String) -> Result<Self, String> {
+        let decryptor =
+            age::Decryptor::new(ciphertext).map_err(|err| format!("invalid backup: {err}"))?;
+        let identity = age::scrypt::Identity::new(SecretString::from(passphrase));
+        let mut reader = decryptor
+            .decrypt(iter::once(&identity as &dyn age::Identity))
+            .map_err(|err| format!("failed to decrypt backup: {err}"))?;
+
+        let mut plaintext = Vec::new();
+        reader
+            .read_to_end(&mut plaintext)
+            .map_err(|err| format!("failed to decrypt backup: {err}"))?;
+
+        Self::decode(&plaintext)
+    }
+}
+
+#[cfg(feature = "backup")]
+#[wasm_bindgen]
+impl Beelay {
+    /// Export this handle (identity, documents, and membership) as an archive encrypted
+    /// with `passphrase`.
+    ///
+    /// The archive can be restored with `restoreEncryptedBackup` without involving any relay.
+    #[wasm_bindgen(js_name = exportEncryptedBackup)]
+    pub fn export_encrypted_backup(&self, passphrase: String) -> Result<Uint8Array, JsValue> {
+        let snapshot = HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(|ctx| ctx.snapshot(true))
+                .ok_or_else(|| JsValue::from(BeelayError::InvalidHandle))
+        })?;
+
+        let bytes = snapshot
+            .encrypt(passphrase)
+            .map_err(BeelayError::InvalidSnapshot)?;
+        Ok(Uint8Array::from(bytes.as_slice()))
+    }
+
+    /// Replace this handle's identity, documents, and membership with those in an archive
+    /// produced by `exportEncryptedBackup`.
+    ///
+    /// Returns the IDs of the restored documents. Subscriptions are kept.
+    #[wasm_bindgen(js_name = restoreEncryptedBackup)]
+    pub async fn restore_encrypted_backup(
+        &self,
+        bytes: Uint8Array,
+        passphrase: String,
+    ) -> Result<JsValue, JsValue> {
+        let snapshot =
+            Snapshot::decrypt(&bytes.to_vec(), passphrase).map_err(BeelayError::InvalidSnapshot)?;
+
+        let doc_ids = restore_snapshot(self.id, snapshot).await?;
+        serde_wasm_bindgen::to_value(&doc_ids).map_err(JsValue::from)
+    }
+}
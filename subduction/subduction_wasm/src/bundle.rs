@@ -0,0 +1,223 @@
+//! Bundles: runs of a document's history stored and synced as one unit.
+//!
+//! A checkpoint is a commit whose hash, read as a number, ends in at least two
+//! zeros in base 10 (about one commit in a hundred). `compact` hands every run
+//! of commits between two checkpoints to the document's engine as a chunk,
+//! whose blob is the run's commits, CBOR-encoded parents first. Peers that
+//! receive the chunk apply the commits inside it, and `loadDocument` lists a
+//! bundle in place of the commits it covers.
+
+use std::{
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
+
+use sedimentree_core::{Chunk, Depth, Digest, MAX_STRATA_DEPTH};
+use serde::Serialize;
+
+use crate::{
+    storage::{from_cbor, to_cbor, StorageError},
+    CommitInput, CommitOutput, CommitRecord,
+};
+
+/// A run of commits stored as one chunk.
+#[derive(Debug, Clone)]
+pub(crate) struct Bundle {
+    /// The digest of the chunk holding the bundle.
+    chunk: Digest,
+    /// The checkpoint the bundle builds on.
+    start: String,
+    /// The checkpoint the bundle ends with.
+    end: String,
+    checkpoints: Vec<String>,
+    /// The encoded commits, shared so that loads don't copy them.
+    contents: Rc<[u8]>,
+    /// The hashes of the commits in the bundle.
+    covered: HashSet<String>,
+}
+
+/// A bundle as `loadDocument` lists it.
+#[derive(Debug, Serialize)]
+pub(crate) struct BundleOutput {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    start: String,
+    end: String,
+    checkpoints: Vec<String>,
+    /// A `Uint8Array`.
+    #[serde(serialize_with = "crate::envelope::contents::serialize")]
+    contents: Rc<[u8]>,
+}
+
+/// One entry of `loadDocument`'s result.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Entry {
+    Commit(CommitOutput),
+    Bundle(BundleOutput),
+}
+
+impl Bundle {
+    /// The bundle held by `chunk`, whose blob is `contents`, and the commits inside it.
+    pub(crate) fn from_chunk(
+        chunk: &Chunk,
+        contents: &[u8],
+    ) -> Result<(Self, Vec<CommitInput>), StorageError> {
+        let commits = decode(contents)?;
+        let bundle = Self {
+            chunk: chunk.digest(),
+            start: hex::encode(chunk.head().as_bytes()),
+            end: hex::encode(chunk.boundary().first().as_bytes()),
+            checkpoints: chunk
+                .checkpoints()
+                .iter()
+                .map(|checkpoint| hex::encode(checkpoint.as_bytes()))
+                .collect(),
+            contents: Rc::from(contents),
+            covered: commits.iter().map(|commit| commit.hash.clone()).collect(),
+        };
+        Ok((bundle, commits))
+    }
+
+    /// The digest of the chunk holding the bundle.
+    pub(crate) const fn chunk(&self) -> Digest {
+        self.chunk
+    }
+
+    /// Whether the commit `hash` is in the bundle.
+    pub(crate) fn covers(&self, hash: &str) -> bool {
+        self.covered.contains(hash)
+    }
+
+    /// The commits in the bundle, parents first.
+    pub(crate) fn commits(&self) -> Result<Vec<CommitInput>, StorageError> {
+        decode(&self.contents)
+    }
+
+    fn to_output(&self) -> BundleOutput {
+        BundleOutput {
+            kind: "bundle",
+            start: self.start.clone(),
+            end: self.end.clone(),
+            checkpoints: self.checkpoints.clone(),
+            contents: Rc::clone(&self.contents),
+        }
+    }
+}
+
+/// The commits a bundle ending at checkpoint `end` covers, in the order of `commits`:
+/// `end`, and its ancestors back to (but not including) the previous checkpoints.
+pub(crate) fn covered<'a>(commits: &'a [CommitRecord], end: &str) -> Vec<&'a CommitRecord> {
+    let by_hash = commits
+        .iter()
+        .map(|commit| (commit.hash.as_str(), commit))
+        .collect::<HashMap<_, _>>();
+    let mut inside = HashSet::from([end.to_string()]);
+    let mut pending = vec![end.to_string()];
+    while let Some(hash) = pending.pop() {
+        let Some(commit) = by_hash.get(hash.as_str()) else {
+            continue;
+        };
+        for parent in &commit.parents {
+            if !is_checkpoint(parent) && inside.insert(parent.clone()) {
+                pending.push(parent.clone());
+            }
+        }
+    }
+    commits
+        .iter()
+        .filter(|commit| inside.contains(&commit.hash))
+        .collect()
+}
+
+/// Encode a bundle's commits, parents first, as its chunk's blob.
+pub(crate) fn encode(commits: &[&CommitRecord]) -> Result<Vec<u8>, StorageError> {
+    to_cbor(&commits.iter().map(|commit| commit.to_input()).collect::<Vec<_>>())
+}
+
+/// Decode the commits in a bundle's contents.
+pub(crate) fn decode(contents: &[u8]) -> Result<Vec<CommitInput>, StorageError> {
+    from_cbor(contents)
+}
+
+/// `ordered`, with each bundle listed in place of the commits it covers.
+///
+/// A bundle goes where the last of its commits would have, and any commit building on
+/// one of them is held back until after it. Bundles none of whose commits are left,
+/// once `gc` has collected them, go first, each after the one it builds on.
+pub(crate) fn with_bundles(ordered: Vec<&CommitRecord>, bundles: &[Bundle]) -> Vec<Entry> {
+    let bundle_of = |hash: &str| bundles.iter().position(|bundle| bundle.covered.contains(hash));
+    let mut remaining = bundles
+        .iter()
+        .map(|bundle| {
+            ordered
+                .iter()
+                .filter(|commit| bundle.covered.contains(&commit.hash))
+                .count()
+        })
+        .collect::<Vec<_>>();
+    let mut listed = vec![false; bundles.len()];
+    let mut held = Vec::<&CommitRecord>::new();
+    let mut entries = Vec::with_capacity(ordered.len());
+
+    let mut collected = (0..bundles.len())
+        .filter(|index| remaining[*index] == 0)
+        .collect::<Vec<_>>();
+    while !collected.is_empty() {
+        let position = collected
+            .iter()
+            .position(|index| {
+                !collected
+                    .iter()
+                    .any(|other| bundles[*other].end == bundles[*index].start)
+            })
+            .unwrap_or(0);
+        let index = collected.remove(position);
+        entries.push(Entry::Bundle(bundles[index].to_output()));
+        listed[index] = true;
+    }
+
+    for commit in ordered {
+        if let Some(index) = bundle_of(&commit.hash) {
+            remaining[index] -= 1;
+            if remaining[index] == 0 {
+                entries.push(Entry::Bundle(bundles[index].to_output()));
+                listed[index] = true;
+                // Whatever was held back for this bundle may be ready now
+                let mut waiting = std::mem::take(&mut held);
+                while let Some(position) = waiting.iter().position(|held_back| {
+                    !blocked(held_back, &bundle_of, &listed, &waiting)
+                }) {
+                    entries.push(Entry::Commit(waiting.remove(position).to_output()));
+                }
+                held = waiting;
+            }
+        } else if blocked(commit, &bundle_of, &listed, &held) {
+            held.push(commit);
+        } else {
+            entries.push(Entry::Commit(commit.to_output()));
+        }
+    }
+    entries.extend(held.into_iter().map(|commit| Entry::Commit(commit.to_output())));
+    entries
+}
+
+/// Whether `commit` builds on a bundle not listed yet, or on a commit held back.
+fn blocked(
+    commit: &CommitRecord,
+    bundle_of: &impl Fn(&str) -> Option<usize>,
+    listed: &[bool],
+    held: &[&CommitRecord],
+) -> bool {
+    commit.parents.iter().any(|parent| {
+        bundle_of(parent).is_some_and(|index| !listed[index])
+            || held.iter().any(|held_back| held_back.hash == *parent)
+    })
+}
+
+fn is_checkpoint(hash: &str) -> bool {
+    let Ok(bytes) = <[u8; 32]>::try_from(hex::decode(hash).unwrap_or_default()) else {
+        return false;
+    };
+    Depth::from(Digest::from(bytes)) >= MAX_STRATA_DEPTH
+}
@@ -0,0 +1,60 @@
+//! A small least-recently-used cache.
+
+use std::{collections::HashMap, hash::Hash};
+
+/// The default number of entries kept by the `loadDocument` cache.
+pub(crate) const DEFAULT_LOAD_CACHE_SIZE: usize = 16;
+
+/// A fixed-capacity map that evicts its least recently used entry when full.
+///
+/// Capacities are expected to be small, so eviction is a linear scan.
+#[derive(Debug)]
+pub(crate) struct LruCache<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (u64, V)>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Look up `key`, marking it as recently used.
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let (used, value) = self.entries.get_mut(key)?;
+        *used = self.tick;
+        Some(value.clone())
+    }
+
+    /// Insert `value`, evicting the least recently used entry if the cache is full.
+    pub(crate) fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (used, _))| *used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.tick += 1;
+        self.entries.insert(key, (self.tick, value));
+    }
+
+    /// Drop every entry whose key matches `stale`.
+    pub(crate) fn invalidate(&mut self, stale: impl Fn(&K) -> bool) {
+        self.entries.retain(|key, _| !stale(key));
+    }
+}
@@ -0,0 +1,66 @@
+//! Documents taken out of their handle while commits are applied to them.
+//!
+//! Applying commits takes a document out of its handle for as long as it awaits
+//! its engine, and puts it back afterwards. A `deleteDoc` in the meantime can't
+//! remove it from the handle, so it leaves a tombstone instead, and the document
+//! is dropped rather than put back.
+
+use std::collections::{HashMap, HashSet};
+
+use sedimentree_core::SedimentreeId;
+
+/// The documents taken out of a handle, by ID.
+#[derive(Debug, Default)]
+pub(crate) struct Checkouts {
+    out: HashMap<String, SedimentreeId>,
+    /// Those deleted while out.
+    deleted: HashSet<String>,
+}
+
+impl Checkouts {
+    /// Note that `doc_id`, whose sedimentree is `sed_id`, was taken out.
+    pub(crate) fn take(&mut self, doc_id: &str, sed_id: SedimentreeId) {
+        self.out.insert(doc_id.to_string(), sed_id);
+    }
+
+    /// Delete `doc_id` if it's out, returning its sedimentree.
+    pub(crate) fn delete(&mut self, doc_id: &str) -> Option<SedimentreeId> {
+        let sed_id = *self.out.get(doc_id)?;
+        self.deleted.insert(doc_id.to_string());
+        Some(sed_id)
+    }
+
+    /// Note that `doc_id` is done with, returning whether it may be put back: it
+    /// may not if it was deleted while out.
+    pub(crate) fn give_back(&mut self, doc_id: &str) -> bool {
+        self.out.remove(doc_id);
+        !self.deleted.remove(doc_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn documents_deleted_while_out_are_not_put_back() {
+        let mut checkouts = Checkouts::default();
+        let sed_id = SedimentreeId::new([1; 32]);
+        checkouts.take("doc", sed_id);
+        assert_eq!(checkouts.delete("doc"), Some(sed_id));
+        assert!(!checkouts.give_back("doc"));
+
+        // The tombstone doesn't outlive the checkout
+        checkouts.take("doc", sed_id);
+        assert!(checkouts.give_back("doc"));
+    }
+
+    #[test]
+    fn only_documents_out_are_deleted_here() {
+        let mut checkouts = Checkouts::default();
+        assert_eq!(checkouts.delete("doc"), None);
+        checkouts.take("doc", SedimentreeId::new([1; 32]));
+        assert!(checkouts.give_back("doc"));
+        assert_eq!(checkouts.delete("doc"), None);
+    }
+}
@@ -0,0 +1,157 @@
+//! Helpers for apps ported from the original Beelay API: an in-memory signer, an
+//! in-memory storage adapter, and the other odds and ends that API's callers expect.
+//!
+//! These used to live in a separate, synchronous `subduction-wasm` crate with its own
+//! document store. They're here now so that ported apps get the same documents, and
+//! the same behavior, as everything else.
+
+use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+use ed25519_dalek::{Signer, SigningKey};
+use js_sys::Uint8Array;
+use wasm_bindgen::prelude::*;
+
+use crate::{random::RandomSource, signature::signing_key, Beelay};
+
+#[wasm_bindgen]
+impl Beelay {
+    /// The name and version of the crate the handle was built from.
+    #[wasm_bindgen(js_name = version)]
+    pub fn version(&self) -> String {
+        concat!("subduction-wasm-", env!("CARGO_PKG_VERSION")).to_string()
+    }
+}
+
+/// A document ID as the original API parsed it. Document IDs are opaque strings here,
+/// so it's returned as it is.
+#[wasm_bindgen]
+pub fn parse_beelay_doc_id(val: String) -> String {
+    val
+}
+
+/// An ed25519 signing key held in memory.
+///
+/// `new MemorySigner()` generates a key with the platform's secure random source.
+/// Save `export()` somewhere safe to sign as the same identity later, with
+/// `MemorySigner.import(bytes)`.
+#[wasm_bindgen]
+pub struct MemorySigner {
+    signing_key: SigningKey,
+}
+
+#[wasm_bindgen]
+impl MemorySigner {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<MemorySigner, JsValue> {
+        let secret = RandomSource::Crypto.bytes()?;
+        Ok(MemorySigner {
+            signing_key: SigningKey::from_bytes(&secret),
+        })
+    }
+
+    /// Recreate a signer from the 32-byte secret key returned by `export()`.
+    #[wasm_bindgen(js_name = import)]
+    pub fn import_key(secret: Uint8Array) -> Result<MemorySigner, JsValue> {
+        Ok(MemorySigner {
+            signing_key: signing_key(secret.to_vec())?,
+        })
+    }
+
+    /// The 32-byte secret key. Anyone holding it can sign as this signer.
+    #[wasm_bindgen(js_name = export)]
+    pub fn export_key(&self) -> Uint8Array {
+        Uint8Array::from(self.signing_key.to_bytes().as_slice())
+    }
+
+    /// The 32-byte ed25519 public key that verifies this signer's signatures.
+    #[wasm_bindgen(js_name = verifyingKey)]
+    pub fn verifying_key(&self) -> Uint8Array {
+        Uint8Array::from(self.signing_key.verifying_key().as_bytes().as_slice())
+    }
+
+    /// The 64-byte ed25519 signature of `message`.
+    #[wasm_bindgen(js_name = sign)]
+    pub async fn sign(&self, message: Uint8Array) -> Uint8Array {
+        let signature = self.signing_key.sign(&message.to_vec());
+        Uint8Array::from(signature.to_bytes().as_slice())
+    }
+}
+
+/// A storage adapter that keeps everything in memory, for passing as `storage` to
+/// `Beelay.load`.
+///
+/// Data lasts as long as the adapter, so a handle can be stopped and loaded again
+/// from the same adapter.
+#[wasm_bindgen]
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStorageAdapter {
+    entries: Rc<RefCell<BTreeMap<Vec<String>, Vec<u8>>>>,
+}
+
+#[wasm_bindgen]
+impl MemoryStorageAdapter {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> MemoryStorageAdapter {
+        MemoryStorageAdapter::default()
+    }
+
+    /// The data saved under `key`, or `undefined`.
+    pub async fn load(&self, key: Vec<String>) -> JsValue {
+        self.entries
+            .borrow()
+            .get(&key)
+            .map_or(JsValue::UNDEFINED, |data| {
+                Uint8Array::from(data.as_slice()).into()
+            })
+    }
+
+    /// Every key under `prefix` that has data, mapped to the data.
+    #[wasm_bindgen(js_name = loadRange)]
+    pub async fn load_range(&self, prefix: Vec<String>) -> js_sys::Map {
+        let range = js_sys::Map::new();
+        for (key, data) in self.entries.borrow().iter() {
+            if key.starts_with(&prefix) {
+                let key = key
+                    .iter()
+                    .map(|part| JsValue::from_str(part))
+                    .collect::<js_sys::Array>();
+                range.set(&key, &Uint8Array::from(data.as_slice()));
+            }
+        }
+        range
+    }
+
+    pub async fn save(&self, key: Vec<String>, data: Uint8Array) {
+        self.entries.borrow_mut().insert(key, data.to_vec());
+    }
+
+    pub async fn remove(&self, key: Vec<String>) {
+        self.entries.borrow_mut().remove(&key);
+    }
+
+    /// The distinct keys one segment longer than `prefix` that have data under them.
+    #[wasm_bindgen(js_name = listOneLevel)]
+    pub async fn list_one_level(&self, prefix: Vec<String>) -> js_sys::Array {
+        let entries = self.entries.borrow();
+        let mut children = entries
+            .keys()
+            .filter(|key| key.len() > prefix.len() && key.starts_with(&prefix))
+            .map(|key| &key[..=prefix.len()])
+            .collect::<Vec<_>>();
+        children.dedup();
+        children
+            .into_iter()
+            .map(|child| {
+                child
+                    .iter()
+                    .map(|part| JsValue::from_str(part))
+                    .collect::<js_sys::Array>()
+            })
+            .collect()
+    }
+}
+
+#[wasm_bindgen(js_name = createMemoryStorageAdapter)]
+pub fn create_memory_storage_adapter() -> MemoryStorageAdapter {
+    MemoryStorageAdapter::new()
+}
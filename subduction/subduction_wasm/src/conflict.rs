@@ -0,0 +1,208 @@
+//! Detecting when synced commits diverge from local work.
+//!
+//! Commits added locally stay "unsynced" until a commit from another peer
+//! descends from them. If a sync brings in heads that don't cover some unsynced
+//! local head, the two lines of history have diverged, and listeners get a
+//! `conflict-detected` event with enough context to open a merge UI straight
+//! away.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    graph::{ancestors, newest},
+    CommitRecord,
+};
+
+/// Where a batch of commits came from.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum CommitOrigin {
+    /// Edits made by this peer.
+    #[default]
+    Local,
+
+    /// Commits received from another peer.
+    Sync,
+
+    /// Commits already stored in the document's engine, by sync or by a previous load.
+    #[serde(skip)]
+    Remote,
+
+    /// Commits read from an export, which may well be this peer's own history, so
+    /// they neither count as synced nor as diverging from local work.
+    #[cfg(feature = "import")]
+    #[serde(skip)]
+    Import,
+}
+
+/// The payload of a `conflict-detected` event.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Conflict {
+    #[serde(rename = "type")]
+    pub(crate) kind: &'static str,
+    pub(crate) doc_id: String,
+    /// The document's heads before the sync.
+    pub(crate) local_heads: Vec<String>,
+    /// The heads introduced by the sync.
+    pub(crate) remote_heads: Vec<String>,
+    /// The most recent commits that both sides share.
+    pub(crate) common_ancestors: Vec<String>,
+    /// Commits reachable from the local heads but not the remote ones.
+    pub(crate) local_only: Vec<String>,
+    /// Commits reachable from the remote heads but not the local ones.
+    pub(crate) remote_only: Vec<String>,
+}
+
+/// Track the commits `commits[applied_from..]` just applied from `origin`,
+/// returning the conflict they cause, if any (see [`detect`]).
+///
+/// Local commits are added to `unsynced`, and imported ones are left out of it.
+pub(crate) fn track(
+    doc_id: &str,
+    origin: CommitOrigin,
+    commits: &[CommitRecord],
+    applied_from: usize,
+    unsynced: &mut HashSet<String>,
+) -> Option<Conflict> {
+    match origin {
+        CommitOrigin::Local => {
+            unsynced.extend(
+                commits[applied_from..]
+                    .iter()
+                    .map(|record| record.hash.clone()),
+            );
+            None
+        }
+        #[cfg(feature = "import")]
+        CommitOrigin::Import => None,
+        CommitOrigin::Sync | CommitOrigin::Remote => {
+            detect(doc_id, commits, applied_from, unsynced)
+        }
+    }
+}
+
+/// Check whether the synced commits `commits[synced_from..]` diverge from `unsynced` local work.
+///
+/// Local commits that the sync builds on are removed from `unsynced`.
+fn detect(
+    doc_id: &str,
+    commits: &[CommitRecord],
+    synced_from: usize,
+    unsynced: &mut HashSet<String>,
+) -> Option<Conflict> {
+    let (before, synced) = commits.split_at(synced_from);
+    if synced.is_empty() {
+        return None;
+    }
+
+    let local_heads = heads(before);
+    let remote_heads = heads(synced);
+    let local = ancestors(commits, &local_heads);
+    let remote = ancestors(commits, &remote_heads);
+
+    unsynced.retain(|hash| !remote.contains(hash));
+    if !local_heads.iter().any(|head| unsynced.contains(head)) {
+        return None;
+    }
+
+    let common = local.intersection(&remote).cloned().collect::<HashSet<_>>();
+    let in_order = |keep: &dyn Fn(&String) -> bool| {
+        commits
+            .iter()
+            .map(|record| &record.hash)
+            .filter(|hash| keep(hash))
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    Some(Conflict {
+        kind: "conflict-detected",
+        doc_id: doc_id.to_string(),
+        common_ancestors: newest(commits, &common),
+        local_only: in_order(&|hash| local.contains(hash) && !remote.contains(hash)),
+        remote_only: in_order(&|hash| remote.contains(hash) && !local.contains(hash)),
+        local_heads,
+        remote_heads,
+    })
+}
+
+/// The commits in `commits` that no other commit in `commits` has as a parent.
+pub(crate) fn heads(commits: &[CommitRecord]) -> Vec<String> {
+    subduction_client::order::heads(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::meta::CommitMeta;
+
+    fn commit(hash: &str, parents: &[&str]) -> CommitRecord {
+        CommitRecord {
+            parents: parents.iter().map(ToString::to_string).collect(),
+            hash: hash.to_string(),
+            contents: Rc::from(Vec::new()),
+            meta: CommitMeta::default(),
+            extensions: crate::envelope::Extensions::new(),
+        }
+    }
+
+    /// `a` ← `b` made locally, then `origin`'s `commits` applied on top.
+    fn apply(
+        origin: CommitOrigin,
+        commits: &[CommitRecord],
+    ) -> (Option<Conflict>, HashSet<String>) {
+        let mut history = vec![commit("a", &[])];
+        let mut unsynced = HashSet::new();
+        track("doc", CommitOrigin::Local, &history, 0, &mut unsynced);
+        history.push(commit("b", &["a"]));
+        track("doc", CommitOrigin::Local, &history, 1, &mut unsynced);
+
+        let applied_from = history.len();
+        history.extend_from_slice(commits);
+        let conflict = track("doc", origin, &history, applied_from, &mut unsynced);
+        (conflict, unsynced)
+    }
+
+    #[test]
+    fn diverging_syncs_are_conflicts() {
+        let (conflict, unsynced) = apply(CommitOrigin::Sync, &[commit("c", &["a"])]);
+        let conflict = conflict.expect("c doesn't build on b");
+        assert_eq!(conflict.local_heads, ["b"]);
+        assert_eq!(conflict.remote_heads, ["c"]);
+        assert_eq!(conflict.common_ancestors, ["a"]);
+        assert_eq!(conflict.local_only, ["b"]);
+        assert_eq!(conflict.remote_only, ["c"]);
+        // Only `a` has been seen by the other side
+        assert_eq!(unsynced, HashSet::from(["b".to_string()]));
+    }
+
+    #[test]
+    fn syncs_building_on_local_work_are_not() {
+        let (conflict, unsynced) = apply(CommitOrigin::Sync, &[commit("c", &["b"])]);
+        assert!(conflict.is_none());
+        assert!(unsynced.is_empty());
+    }
+
+    #[test]
+    fn synced_history_isnt_in_conflict_with_itself() {
+        // Diverging from commits that came from sync rather than local edits
+        let history = [commit("a", &[]), commit("b", &["a"]), commit("c", &["a"])];
+        let mut unsynced = HashSet::new();
+        assert!(track("doc", CommitOrigin::Sync, &history[..2], 0, &mut unsynced).is_none());
+        assert!(track("doc", CommitOrigin::Sync, &history, 2, &mut unsynced).is_none());
+    }
+
+    #[cfg(feature = "import")]
+    #[test]
+    fn imports_are_never_conflicts() {
+        // An export of another line of this peer's own history
+        let (conflict, unsynced) = apply(CommitOrigin::Import, &[commit("c", &["a"])]);
+        assert!(conflict.is_none());
+        assert_eq!(unsynced, HashSet::from(["a".to_string(), "b".to_string()]));
+    }
+}
@@ -0,0 +1,104 @@
+//! The connections a document's engine syncs over: the handle's sync server, any
+//! peers the app has connected it to directly, and any clients it serves.
+
+use std::time::Duration;
+
+use futures::future::LocalBoxFuture;
+use sedimentree_core::future::Local;
+use subduction_core::{
+    connection::{
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+};
+
+#[cfg(feature = "accept")]
+use crate::accept::AcceptedConnection;
+#[cfg(feature = "rtc")]
+use crate::rtc::RtcDataChannelConnection;
+use crate::socket::{SocketError, WebSocketConnection};
+
+/// One of a document's connections.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum PeerConnection {
+    /// The handle's sync server.
+    Server(WebSocketConnection),
+    /// Another browser, over a WebRTC data channel.
+    #[cfg(feature = "rtc")]
+    Direct(RtcDataChannelConnection),
+    /// A client, over a port the JS host accepted.
+    #[cfg(feature = "accept")]
+    Accepted(AcceptedConnection),
+}
+
+impl Connection<Local> for PeerConnection {
+    type DisconnectionError = SocketError;
+    type SendError = SocketError;
+    type RecvError = SocketError;
+    type CallError = SocketError;
+
+    fn peer_id(&self) -> PeerId {
+        match self {
+            Self::Server(conn) => conn.peer_id(),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.peer_id(),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.peer_id(),
+        }
+    }
+
+    fn disconnect(&mut self) -> LocalBoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        match self {
+            Self::Server(conn) => conn.disconnect(),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.disconnect(),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.disconnect(),
+        }
+    }
+
+    fn send(&self, message: Message) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
+        match self {
+            Self::Server(conn) => conn.send(message),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.send(message),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.send(message),
+        }
+    }
+
+    fn recv(&self) -> LocalBoxFuture<'_, Result<Message, Self::RecvError>> {
+        match self {
+            Self::Server(conn) => conn.recv(),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.recv(),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.recv(),
+        }
+    }
+
+    fn next_request_id(&self) -> LocalBoxFuture<'_, RequestId> {
+        match self {
+            Self::Server(conn) => conn.next_request_id(),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.next_request_id(),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.next_request_id(),
+        }
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        match self {
+            Self::Server(conn) => conn.call(req, timeout),
+            #[cfg(feature = "rtc")]
+            Self::Direct(conn) => conn.call(req, timeout),
+            #[cfg(feature = "accept")]
+            Self::Accepted(conn) => conn.call(req, timeout),
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Contact cards: what a peer hands out so that others can add it to documents.
+//!
+//! A card is a Keyhive contact card, as beelay-core's are: a fresh prekey for the
+//! handle's Keyhive agent, signed with the handle's signing key, so the card names
+//! the handle's verifying key and can't be forged for anyone else's. It's
+//! bincode-encoded as Keyhive encodes it, with bincode 1's defaults, and then
+//! hex-encoded, with or without a `0x` prefix. `parseContactCard` checks the
+//! signature and reads back the peer the card belongs to, whose peer ID is the
+//! same as that handle's `peerId()`.
+//!
+//! Contact cards need the `encryption` feature, which brings in Keyhive.
+
+use keyhive_core::contact_card::ContactCard;
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{encryption, envelope, error::BeelayError, Beelay, HANDLES};
+
+/// `card`, hex-encoded.
+pub(crate) fn encode(card: &ContactCard) -> Result<String, BeelayError> {
+    let bytes = bincode::serde::encode_to_vec(card, bincode::config::legacy())
+        .map_err(BeelayError::encryption)?;
+    Ok(hex::encode(bytes))
+}
+
+/// The contact card `card` encodes, once its signature is checked.
+pub(crate) fn decode(card: &str) -> Result<ContactCard, BeelayError> {
+    let invalid =
+        |reason: &str| BeelayError::InvalidArgument(format!("invalid contact card: {reason}"));
+    let hex_str = card.strip_prefix("0x").unwrap_or(card);
+    let bytes = hex::decode(hex_str).map_err(|_| invalid("not hex"))?;
+    let (decoded, read): (ContactCard, _) =
+        bincode::serde::decode_from_slice(&bytes, bincode::config::legacy())
+            .map_err(|err| invalid(&err.to_string()))?;
+    if read != bytes.len() {
+        return Err(invalid("trailing bytes"));
+    }
+    decoded
+        .op()
+        .try_verify()
+        .map_err(|_| invalid("bad signature"))?;
+    Ok(decoded)
+}
+
+/// The peer a contact card belongs to, as `parseContactCard` returns it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ParsedCard {
+    /// The hex-encoded verifying key, as the peer's `peerId()` gives it.
+    peer_id: String,
+    /// A `Uint8Array`.
+    #[serde(serialize_with = "envelope::contents::serialize")]
+    verifying_key: [u8; 32],
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// A new contact card for this handle, to give to peers who'll add it to their
+    /// documents. Each call makes a new card, with a fresh prekey; any of them
+    /// identifies the handle.
+    #[wasm_bindgen(js_name = createContactCard)]
+    pub async fn create_contact_card(&self) -> Result<String, JsValue> {
+        let keys = encryption::handle_keys(self.id).await?;
+        let card = encode(&keys.contact_card().await?)?;
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            ctx.persist()
+        })?;
+        Ok(card)
+    }
+}
+
+/// The peer a contact card made by `createContactCard` belongs to, as
+/// `{ peerId, verifyingKey }`. Throws `InvalidArgument` if the card can't be read or
+/// its signature doesn't check out.
+#[wasm_bindgen(js_name = parseContactCard, unchecked_return_type = "ContactCardInfo")]
+pub fn parse_contact_card(card: String) -> Result<JsValue, JsValue> {
+    let issuer = *decode(&card)?.op().issuer();
+    let parsed = ParsedCard {
+        peer_id: hex::encode(issuer.as_bytes()),
+        verifying_key: issuer.to_bytes(),
+    };
+    serde_wasm_bindgen::to_value(&parsed).map_err(JsValue::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::SigningKey;
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::{encryption::Keys, random::RandomSource};
+
+    #[test]
+    fn cards_round_trip() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let keys = block_on(Keys::generate(&signing_key, RandomSource::Crypto)).unwrap();
+        let card = block_on(keys.contact_card()).unwrap();
+
+        let encoded = encode(&card).unwrap();
+        let decoded = decode(&encoded).unwrap();
+        assert_eq!(decoded.op().issuer(), &signing_key.verifying_key());
+        assert_eq!(encode(&decoded).unwrap(), encoded);
+        assert!(decode(&format!("0x{encoded}")).is_ok());
+    }
+
+    #[test]
+    fn tampered_cards_are_rejected() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let keys = block_on(Keys::generate(&signing_key, RandomSource::Crypto)).unwrap();
+        let mut bytes =
+            hex::decode(encode(&block_on(keys.contact_card()).unwrap()).unwrap()).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 1;
+
+        assert!(decode(&hex::encode(bytes)).is_err());
+        assert!(decode("not a card").is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! Opaque cursors for the paged APIs: `changesFeed`, `listDocuments`, `history`,
+//! and `loadDocumentPage`.
+//!
+//! A cursor records the stable key of the last item a page handed out (a
+//! document ID, or a commit hash), not its offset, so it keeps working after a
+//! restart reorders the handle's in-memory logs. The item's position is kept as
+//! a hint, which saves a search when nothing has moved. If the item is gone
+//! altogether, as after the document it belongs to is deleted, resuming fails
+//! with a `CursorExpired` error saying how to start over.
+//!
+//! The changes feed is the exception: its cursors resume by sequence number, and
+//! belong to the epoch of the feed that handed them out (see [`feed`]).
+//!
+//! [`feed`]: crate::feed
+//!
+//! Tokens are `v1.` followed by the hex-encoded CBOR of a [`Cursor`]. Tokens from
+//! another format version are reported as expired rather than misread.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::error::BeelayError;
+
+/// The prefix of tokens in the current format.
+const TOKEN_PREFIX: &str = "v1.";
+
+/// The API a cursor pages through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum CursorKind {
+    Changes,
+    Documents,
+    History,
+    Load,
+}
+
+impl CursorKind {
+    /// What to do once a cursor for this API has expired.
+    const fn guidance(self) -> &'static str {
+        match self {
+            Self::Changes => {
+                "call changesFeed without a token to replay the feed, skipping hashes already seen"
+            }
+            Self::Documents => "call listDocuments without a cursor to start over",
+            Self::History => {
+                "call history without a cursor to start over, skipping hashes already seen"
+            }
+            Self::Load => {
+                "call loadDocumentPage without a cursor to start over, skipping hashes already seen"
+            }
+        }
+    }
+}
+
+/// Why a cursor couldn't be used.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum CursorError {
+    /// The token isn't a cursor, or is a cursor for another API or document.
+    #[error("invalid cursor: {0}")]
+    Invalid(String),
+
+    /// The cursor can't be resumed any more.
+    #[error("cursor expired: {reason}; {}", kind.guidance())]
+    Expired {
+        kind: CursorKind,
+        reason: &'static str,
+    },
+}
+
+impl From<CursorError> for JsValue {
+    fn from(err: CursorError) -> Self {
+        let error = js_sys::Error::new(&err.to_string());
+        error.set_name(match err {
+            CursorError::Invalid(_) => "InvalidCursor",
+            CursorError::Expired { .. } => "CursorExpired",
+        });
+        error.into()
+    }
+}
+
+/// A position in one of the paged APIs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Cursor {
+    kind: CursorKind,
+    /// The document a history or load cursor belongs to; empty for the others.
+    scope: String,
+    /// The key of the last item handed out, or `None` before the first.
+    key: Option<String>,
+    /// Where that item was, as a hint.
+    index: usize,
+}
+
+impl Cursor {
+    /// A cursor before the first item.
+    pub(crate) fn start(kind: CursorKind, scope: &str) -> Self {
+        Self {
+            kind,
+            scope: scope.to_string(),
+            key: None,
+            index: 0,
+        }
+    }
+
+    /// A cursor after the item with `key`, found at `index`.
+    pub(crate) fn after(kind: CursorKind, scope: &str, key: String, index: usize) -> Self {
+        Self {
+            kind,
+            scope: scope.to_string(),
+            key: Some(key),
+            index,
+        }
+    }
+
+    /// Read a token handed out by [`Cursor::encode`] for `kind` and `scope`.
+    ///
+    /// # Errors
+    ///
+    /// [`CursorError::Expired`] if the token is from another format version, and
+    /// [`CursorError::Invalid`] if it isn't a cursor for `kind` and `scope` at all.
+    pub(crate) fn decode(token: &str, kind: CursorKind, scope: &str) -> Result<Self, CursorError> {
+        let cursor = Self::decode_unscoped(token, kind)?;
+        if cursor.scope != scope {
+            return Err(CursorError::Invalid(format!(
+                "{token} belongs to another API or document"
+            )));
+        }
+        Ok(cursor)
+    }
+
+    /// Read a token handed out by [`Cursor::encode`] for `kind`, whatever its scope.
+    ///
+    /// # Errors
+    ///
+    /// As [`Cursor::decode`].
+    pub(crate) fn decode_unscoped(token: &str, kind: CursorKind) -> Result<Self, CursorError> {
+        let Some(encoded) = token.strip_prefix(TOKEN_PREFIX) else {
+            return Err(CursorError::Expired {
+                kind,
+                reason: "the token is from an older format",
+            });
+        };
+        let cursor = hex::decode(encoded)
+            .ok()
+            .and_then(|bytes| ciborium::from_reader::<Self, _>(bytes.as_slice()).ok())
+            .ok_or_else(|| CursorError::Invalid(token.to_string()))?;
+        if cursor.kind != kind {
+            return Err(CursorError::Invalid(format!(
+                "{token} belongs to another API"
+            )));
+        }
+        Ok(cursor)
+    }
+
+    /// The opaque token for this cursor.
+    pub(crate) fn encode(&self) -> String {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes).expect("writing to a Vec can't fail");
+        format!("{TOKEN_PREFIX}{}", hex::encode(bytes))
+    }
+
+    /// The key of the last item handed out, or `None` before the first.
+    pub(crate) fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    /// The document, or the changes feed's epoch, the cursor belongs to.
+    pub(crate) fn scope(&self) -> &str {
+        &self.scope
+    }
+
+    /// Where the last item handed out was, or its sequence number in the changes feed.
+    pub(crate) const fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Where to resume in a log of `len` items, where `is_key(i, key)` says whether
+    /// item `i` has the key `key`.
+    ///
+    /// # Errors
+    ///
+    /// [`CursorError::Expired`] if the last item handed out is no longer in the log.
+    pub(crate) fn resume(
+        &self,
+        len: usize,
+        is_key: impl Fn(usize, &str) -> bool,
+    ) -> Result<usize, CursorError> {
+        let Some(key) = &self.key else {
+            return Ok(0);
+        };
+        if self.index < len && is_key(self.index, key) {
+            return Ok(self.index + 1);
+        }
+        (0..len)
+            .find(|i| is_key(*i, key))
+            .map(|i| i + 1)
+            .ok_or(CursorError::Expired {
+                kind: self.kind,
+                reason: "the last item it handed out is gone",
+            })
+    }
+}
+
+/// Options accepted by the paged APIs.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PageOptions {
+    /// Where the previous page left off.
+    pub(crate) cursor: Option<String>,
+    /// The most items to return, at least one. Unlimited if unset.
+    pub(crate) limit: Option<usize>,
+}
+
+impl PageOptions {
+    /// Parse options passed from JS, where `undefined` or `null` mean the defaults.
+    pub(crate) fn from_js(options: JsValue) -> Result<Self, JsValue> {
+        if options.is_undefined() || options.is_null() {
+            Ok(Self::default())
+        } else {
+            serde_wasm_bindgen::from_value(options)
+                .map_err(|err| BeelayError::invalid_argument(err).into())
+        }
+    }
+
+    /// The end of a page starting at `from` in a list of `len` items.
+    pub(crate) fn end(&self, from: usize, len: usize) -> usize {
+        self.limit
+            .map_or(len, |limit| from.saturating_add(limit.max(1)).min(len))
+    }
+}
@@ -0,0 +1,356 @@
+//! Bookkeeping of live engine resources, to help apps spot leaks.
+//!
+//! Every handle, document, subscription, listener, connection, and blob view is
+//! registered when it is created and unregistered when it is stopped, removed,
+//! closed, or released, as is every `Beelay` object while it is alive. What counts
+//! as a likely leak is decided by what still refers to a resource, not by its age:
+//!
+//! * a handle stopped while some of its `Beelay` objects are still referenced,
+//!   which keep it from being collected and fail every call;
+//! * a subscription or listener made through a `Beelay` object that was freed
+//!   without `close()`, which nothing can remove until the handle stops;
+//! * a connection left open after its handle stopped.
+//!
+//! With the `debug` feature, each registration also records the JS stack it was
+//! created from, so a leaked resource can be traced back to the code that made it.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use serde::Serialize;
+
+thread_local! {
+    static REGISTRY: RefCell<Registry> = RefCell::new(Registry::default());
+}
+
+/// The kinds of resource that are tracked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum ResourceKind {
+    Handle,
+    Document,
+    /// Made with `subscribe`.
+    Subscription,
+    /// Made with `on`, `onConflict`, `watchAll`, or `watchSyncReports`.
+    Listener,
+    /// A socket to a sync server, a data channel to a peer, or an accepted client.
+    Connection,
+    BlobView,
+}
+
+/// Why a resource looks leaked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+enum LeakReason {
+    /// The handle was stopped, but this `Beelay` object for it is still referenced.
+    StoppedButReferenced,
+    /// The `Beelay` object it was made through was freed without `close()`.
+    OwnerFreed,
+    /// Still open, though its handle was stopped.
+    OutlivedHandle,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Key {
+    kind: ResourceKind,
+    handle: u32,
+    id: String,
+}
+
+impl Key {
+    fn handle(handle: u32) -> Self {
+        Self {
+            kind: ResourceKind::Handle,
+            handle,
+            id: String::new(),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Origin {
+    /// The `Beelay` object the resource was made through, if it belongs to one.
+    owner: Option<u32>,
+    label: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    live: HashMap<Key, Origin>,
+    /// The `Beelay` objects alive, by handle and facade, including detached ones.
+    objects: HashMap<(u32, u32), Origin>,
+    next_id: u32,
+}
+
+/// A snapshot of the live resources.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Diagnostics {
+    handles: usize,
+    documents: usize,
+    subscriptions: usize,
+    listeners: usize,
+    connections: usize,
+    blob_views: usize,
+    likely_leaks: Vec<LikelyLeak>,
+}
+
+/// A resource that nothing should still be holding on to.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct LikelyLeak {
+    kind: ResourceKind,
+    handle: u32,
+    id: String,
+    reason: LeakReason,
+    /// Where the resource was created, if built with the `debug` feature.
+    label: Option<String>,
+}
+
+/// Register a newly created resource.
+pub(crate) fn track(kind: ResourceKind, handle: u32, id: impl Into<String>) {
+    register(kind, handle, id.into(), None);
+}
+
+/// Register a subscription or listener made through facade `owner` of the handle.
+pub(crate) fn track_owned(kind: ResourceKind, handle: u32, owner: u32, id: impl Into<String>) {
+    register(kind, handle, id.into(), Some(owner));
+}
+
+fn register(kind: ResourceKind, handle: u32, id: String, owner: Option<u32>) {
+    let key = Key { kind, handle, id };
+    let origin = Origin {
+        owner,
+        label: creation_label(),
+    };
+    REGISTRY.with(|registry| registry.borrow_mut().live.insert(key, origin));
+}
+
+/// Unregister a resource that has been stopped, removed, closed, or released.
+pub(crate) fn untrack(kind: ResourceKind, handle: u32, id: &str) {
+    let key = Key {
+        kind,
+        handle,
+        id: id.to_string(),
+    };
+    REGISTRY.with(|registry| registry.borrow_mut().live.remove(&key));
+}
+
+/// Unregister a subscription or listener that was removed.
+pub(crate) fn untrack_subscription(handle: u32, id: &str) {
+    untrack(ResourceKind::Subscription, handle, id);
+    untrack(ResourceKind::Listener, handle, id);
+}
+
+/// Unregister a stopped handle, along with its documents, subscriptions, and
+/// listeners.
+///
+/// Connections are left to unregister as they close, and blob views are left
+/// alone, since they outlive the handle that created them.
+pub(crate) fn untrack_handle(handle: u32) {
+    untrack_where(|key| {
+        key.handle == handle
+            && !matches!(key.kind, ResourceKind::Connection | ResourceKind::BlobView)
+    });
+}
+
+/// Unregister all of a handle's documents, e.g. before they are replaced wholesale.
+pub(crate) fn untrack_documents(handle: u32) {
+    untrack_where(|key| key.handle == handle && key.kind == ResourceKind::Document);
+}
+
+fn untrack_where(matches: impl Fn(&Key) -> bool) {
+    REGISTRY.with(|registry| registry.borrow_mut().live.retain(|key, _| !matches(key)));
+}
+
+/// Register a new `Beelay` object for facade `facade` of the handle.
+pub(crate) fn track_object(handle: u32, facade: u32) {
+    let origin = Origin {
+        owner: None,
+        label: creation_label(),
+    };
+    REGISTRY.with(|registry| {
+        registry
+            .borrow_mut()
+            .objects
+            .insert((handle, facade), origin)
+    });
+}
+
+/// Unregister a `Beelay` object that was freed.
+pub(crate) fn untrack_object(handle: u32, facade: u32) {
+    // The registry is already gone if the thread is exiting
+    let _ = REGISTRY.try_with(|registry| registry.borrow_mut().objects.remove(&(handle, facade)));
+}
+
+/// An ID for a new blob view or connection.
+pub(crate) fn next_id() -> u32 {
+    REGISTRY.with(|registry| {
+        let mut registry = registry.borrow_mut();
+        registry.next_id += 1;
+        registry.next_id
+    })
+}
+
+/// Count the live resources, and list the ones that look leaked.
+pub(crate) fn report() -> Diagnostics {
+    REGISTRY.with(|registry| {
+        let registry = registry.borrow();
+        let count = |kind| registry.live.keys().filter(|key| key.kind == kind).count();
+        let running = |handle| registry.live.contains_key(&Key::handle(handle));
+
+        let stopped_objects = registry
+            .objects
+            .iter()
+            .filter(|((handle, _), _)| !running(*handle))
+            .map(|(&(handle, facade), origin)| LikelyLeak {
+                kind: ResourceKind::Handle,
+                handle,
+                id: facade.to_string(),
+                reason: LeakReason::StoppedButReferenced,
+                label: origin.label.clone(),
+            });
+        let left_behind = registry.live.iter().filter_map(|(key, origin)| {
+            let reason = match key.kind {
+                ResourceKind::Subscription | ResourceKind::Listener => {
+                    let owner = origin.owner?;
+                    if registry.objects.contains_key(&(key.handle, owner)) {
+                        return None;
+                    }
+                    LeakReason::OwnerFreed
+                }
+                ResourceKind::Connection if !running(key.handle) => LeakReason::OutlivedHandle,
+                _ => return None,
+            };
+            Some(LikelyLeak {
+                kind: key.kind,
+                handle: key.handle,
+                id: key.id.clone(),
+                reason,
+                label: origin.label.clone(),
+            })
+        });
+
+        let mut likely_leaks = stopped_objects.chain(left_behind).collect::<Vec<_>>();
+        likely_leaks.sort_by(|a, b| (a.handle, &a.id).cmp(&(b.handle, &b.id)));
+        Diagnostics {
+            handles: count(ResourceKind::Handle),
+            documents: count(ResourceKind::Document),
+            subscriptions: count(ResourceKind::Subscription),
+            listeners: count(ResourceKind::Listener),
+            connections: count(ResourceKind::Connection),
+            blob_views: count(ResourceKind::BlobView),
+            likely_leaks,
+        }
+    })
+}
+
+/// The JS stack at the point a resource is created.
+#[cfg(feature = "debug")]
+fn creation_label() -> Option<String> {
+    let error = js_sys::Error::new("created here");
+    js_sys::Reflect::get(&error, &"stack".into())
+        .ok()?
+        .as_string()
+}
+
+#[cfg(not(feature = "debug"))]
+const fn creation_label() -> Option<String> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaks() -> Vec<(ResourceKind, u32, String, LeakReason)> {
+        report()
+            .likely_leaks
+            .into_iter()
+            .map(|leak| (leak.kind, leak.handle, leak.id, leak.reason))
+            .collect()
+    }
+
+    /// Handle 1, with its root object and a subscription made through it.
+    fn load() {
+        track(ResourceKind::Handle, 1, "");
+        track_object(1, 0);
+        track(ResourceKind::Document, 1, "doc");
+        track_owned(ResourceKind::Subscription, 1, 0, "1");
+    }
+
+    #[test]
+    fn long_lived_resources_are_not_leaks() {
+        load();
+        track_owned(ResourceKind::Listener, 1, 0, "2");
+        track(ResourceKind::Connection, 1, "3");
+        let report = report();
+        assert_eq!(
+            (
+                report.handles,
+                report.subscriptions,
+                report.listeners,
+                report.connections
+            ),
+            (1, 1, 1, 1)
+        );
+        assert!(report.likely_leaks.is_empty());
+    }
+
+    #[test]
+    fn stopped_handles_still_referenced_are_leaks() {
+        load();
+        untrack_handle(1);
+        assert_eq!(
+            leaks(),
+            [(
+                ResourceKind::Handle,
+                1,
+                "0".to_string(),
+                LeakReason::StoppedButReferenced
+            )]
+        );
+
+        untrack_object(1, 0);
+        assert!(leaks().is_empty());
+    }
+
+    #[test]
+    fn subscriptions_of_freed_objects_are_leaks() {
+        load();
+        track_object(1, 1);
+        track_owned(ResourceKind::Listener, 1, 1, "2");
+        untrack_object(1, 1);
+        assert_eq!(
+            leaks(),
+            [(
+                ResourceKind::Listener,
+                1,
+                "2".to_string(),
+                LeakReason::OwnerFreed
+            )]
+        );
+
+        untrack_subscription(1, "2");
+        assert!(leaks().is_empty());
+    }
+
+    #[test]
+    fn connections_left_open_after_stopping_are_leaks() {
+        load();
+        track(ResourceKind::Connection, 1, "3");
+        untrack_handle(1);
+        untrack_object(1, 0);
+        assert_eq!(
+            leaks(),
+            [(
+                ResourceKind::Connection,
+                1,
+                "3".to_string(),
+                LeakReason::OutlivedHandle
+            )]
+        );
+
+        untrack(ResourceKind::Connection, 1, "3");
+        assert!(leaks().is_empty());
+    }
+}
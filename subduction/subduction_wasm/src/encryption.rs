@@ -0,0 +1,415 @@
+//! End-to-end encrypted documents, with [Keyhive].
+//!
+//! A document created with `encrypted: true` has each commit encrypted by Keyhive
+//! before it reaches storage or any peer, the sync server included. The commit's
+//! loose blob is [`SEALED`] followed by the bincode-encoded Keyhive ciphertext of
+//! the blob it would otherwise have had, so its metadata is encrypted along with
+//! its contents. Hashes and parents stay in the clear, since sync runs on them.
+//! Commits are decrypted as they're pulled from the document's engine, so
+//! `loadDocument` and the rest of the API see them as they were added.
+//!
+//! The keys belong to the handle: its Keyhive agent signs with the handle's signing
+//! key, is made the first time the handle creates an encrypted document, and is
+//! saved along with the handle, in its storage, snapshots, and backups. Until
+//! documents can be shared through Keyhive, only the handle that created one, or a
+//! handle restored from it, can read it. Other peers store and relay its commits
+//! without being able to open them, and commits the handle can't decrypt, or that
+//! aren't encrypted, are left out of the document.
+//!
+//! Encrypted documents can't be compacted or handed over with `exportDocState`,
+//! both of which would store their commits in the clear. `exportDoc`, `save`, and
+//! backups hold their commits decrypted, like any document's.
+//!
+//! Encryption needs the `encryption` feature. Without it, creating an encrypted
+//! document, or reopening a handle that has them, fails as `Unsupported`.
+//!
+//! [Keyhive]: https://github.com/inkandswitch/keyhive
+
+#[cfg(not(feature = "encryption"))]
+use std::convert::Infallible;
+#[cfg(feature = "encryption")]
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    sync::Arc,
+};
+
+use ed25519_dalek::SigningKey;
+#[cfg(feature = "encryption")]
+use ed25519_dalek::VerifyingKey;
+#[cfg(feature = "encryption")]
+use futures::lock::Mutex;
+#[cfg(feature = "encryption")]
+use keyhive_core::{
+    archive::Archive,
+    contact_card::ContactCard,
+    crypto::{encrypted::EncryptedContent, signer::memory::MemorySigner},
+    keyhive::Keyhive,
+    listener::no_listener::NoListener,
+    principal::{document::Document, identifier::Identifier},
+    store::ciphertext::memory::MemoryCiphertextStore,
+};
+#[cfg(feature = "encryption")]
+use nonempty::NonEmpty;
+use sedimentree_core::Digest;
+use wasm_bindgen::JsValue;
+
+use crate::{error::BeelayError, random::RandomSource, HANDLES};
+#[cfg(feature = "encryption")]
+use crate::{
+    random::KeyRng,
+    storage::{from_cbor, to_cbor},
+};
+
+/// The bytes an encrypted loose blob starts with.
+#[cfg(feature = "encryption")]
+const SEALED: &[u8] = b"\0beelay-keyhive\x01";
+
+#[cfg(feature = "encryption")]
+type Hive = Keyhive<
+    MemorySigner,
+    [u8; 32],
+    Vec<u8>,
+    MemoryCiphertextStore<[u8; 32], Vec<u8>>,
+    NoListener,
+    KeyRng,
+>;
+
+/// A handle's Keyhive agent, shared by all of its encrypted documents.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Keys(Rc<Agent>);
+
+#[cfg(feature = "encryption")]
+struct Agent {
+    hive: Hive,
+    /// The agent's archive as last taken, CBOR-encoded, for the handle to save.
+    archive: RefCell<Vec<u8>>,
+    /// Whether the archive has changed since the handle last saved it.
+    changed: Cell<bool>,
+}
+
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub(crate) struct Keys(Infallible);
+
+/// How one document's commits are encrypted.
+#[cfg(feature = "encryption")]
+#[derive(Clone)]
+pub(crate) struct Encryption {
+    keys: Keys,
+    doc: Arc<Mutex<Document<MemorySigner>>>,
+    /// The ID of the document's Keyhive document, as saved with it.
+    id: [u8; 32],
+}
+
+#[cfg(not(feature = "encryption"))]
+#[derive(Clone)]
+pub(crate) struct Encryption(Infallible);
+
+#[cfg(feature = "encryption")]
+impl Keys {
+    /// A new agent, signing with `signing_key` and making its keys from `random`.
+    pub(crate) async fn generate(
+        signing_key: &SigningKey,
+        random: RandomSource,
+    ) -> Result<Self, BeelayError> {
+        let hive = Hive::generate(
+            MemorySigner::from(signing_key.clone()),
+            MemoryCiphertextStore::new(),
+            NoListener,
+            KeyRng(random),
+        )
+        .await
+        .map_err(BeelayError::encryption)?;
+        let keys = Self(Rc::new(Agent {
+            hive,
+            archive: RefCell::new(Vec::new()),
+            changed: Cell::new(false),
+        }));
+        keys.save().await?;
+        Ok(keys)
+    }
+
+    /// The agent saved as `archive`, signing with `signing_key` and making new keys
+    /// from `random`.
+    pub(crate) async fn restore(
+        archive: &[u8],
+        signing_key: &SigningKey,
+        random: RandomSource,
+    ) -> Result<Self, BeelayError> {
+        let decoded: Archive<[u8; 32]> = from_cbor(archive)?;
+        let hive = Hive::try_from_archive(
+            &decoded,
+            MemorySigner::from(signing_key.clone()),
+            MemoryCiphertextStore::new(),
+            NoListener,
+            Arc::new(Mutex::new(KeyRng(random))),
+        )
+        .await
+        .map_err(BeelayError::encryption)?;
+        Ok(Self(Rc::new(Agent {
+            hive,
+            archive: RefCell::new(archive.to_vec()),
+            changed: Cell::new(false),
+        })))
+    }
+
+    /// Take a new archive of the agent, after its keys have changed.
+    async fn save(&self) -> Result<(), BeelayError> {
+        let archive = to_cbor(&self.0.hive.into_archive().await)?;
+        *self.0.archive.borrow_mut() = archive;
+        self.0.changed.set(true);
+        Ok(())
+    }
+
+    /// A new contact card for the agent (see [`crate::contact`]).
+    pub(crate) async fn contact_card(&self) -> Result<ContactCard, BeelayError> {
+        let card = self
+            .0
+            .hive
+            .contact_card()
+            .await
+            .map_err(BeelayError::encryption)?;
+        self.save().await?;
+        Ok(card)
+    }
+
+    /// The agent's archive, for [`Keys::restore`].
+    pub(crate) fn archive(&self) -> Vec<u8> {
+        self.0.archive.borrow().clone()
+    }
+
+    /// Whether the archive has changed since this was last called.
+    pub(crate) fn take_changed(&self) -> bool {
+        self.0.changed.replace(false)
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Keys {
+    pub(crate) async fn generate(
+        _signing_key: &SigningKey,
+        _random: RandomSource,
+    ) -> Result<Self, BeelayError> {
+        Err(unsupported())
+    }
+
+    pub(crate) async fn restore(
+        _archive: &[u8],
+        _signing_key: &SigningKey,
+        _random: RandomSource,
+    ) -> Result<Self, BeelayError> {
+        Err(unsupported())
+    }
+
+    pub(crate) fn archive(&self) -> Vec<u8> {
+        match self.0 {}
+    }
+
+    pub(crate) fn take_changed(&self) -> bool {
+        match self.0 {}
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl Encryption {
+    /// A new Keyhive document, starting at the commit `initial`.
+    pub(crate) async fn create(keys: &Keys, initial: Digest) -> Result<Self, BeelayError> {
+        let doc = keys
+            .0
+            .hive
+            .generate_doc(Vec::new(), NonEmpty::new(*initial.as_bytes()))
+            .await
+            .map_err(BeelayError::encryption)?;
+        let id = doc.lock().await.doc_id().to_bytes();
+        keys.save().await?;
+        Ok(Self {
+            keys: keys.clone(),
+            doc,
+            id,
+        })
+    }
+
+    /// The Keyhive document `id`, as saved by [`Encryption::id`].
+    pub(crate) async fn open(keys: &Keys, id: [u8; 32]) -> Result<Self, BeelayError> {
+        let key = VerifyingKey::from_bytes(&id).map_err(BeelayError::encryption)?;
+        let doc = keys
+            .0
+            .hive
+            .get_document(Identifier(key).into())
+            .await
+            .ok_or_else(|| BeelayError::encryption("the handle has no keys for the document"))?;
+        Ok(Self {
+            keys: keys.clone(),
+            doc,
+            id,
+        })
+    }
+
+    pub(crate) fn id(&self) -> [u8; 32] {
+        self.id
+    }
+
+    /// The encrypted loose blob of the commit `hash`, with `parents`, whose blob in the
+    /// clear is `blob`.
+    pub(crate) async fn seal(
+        &self,
+        hash: Digest,
+        parents: &[Digest],
+        blob: &[u8],
+    ) -> Result<Vec<u8>, BeelayError> {
+        let parents = parents
+            .iter()
+            .map(|parent| *parent.as_bytes())
+            .collect::<Vec<_>>();
+        let sealed = self
+            .keys
+            .0
+            .hive
+            .try_encrypt_content(self.doc.clone(), hash.as_bytes(), &parents, blob)
+            .await
+            .map_err(BeelayError::encryption)?;
+        // Encrypting under a new key changes what the agent must save to decrypt later
+        if sealed.update_op().is_some() {
+            self.keys.save().await?;
+        }
+
+        let encoded =
+            bincode::serde::encode_to_vec(sealed.encrypted_content(), bincode::config::standard())
+                .map_err(BeelayError::encryption)?;
+        Ok([SEALED, &encoded].concat())
+    }
+
+    /// The blob in the clear of the encrypted loose blob `sealed`, or `None` if it isn't
+    /// encrypted or can't be decrypted with the handle's keys.
+    pub(crate) async fn open_blob(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let encoded = sealed.strip_prefix(SEALED)?;
+        let (encrypted, _): (EncryptedContent<Vec<u8>, [u8; 32]>, _) =
+            bincode::serde::decode_from_slice(encoded, bincode::config::standard()).ok()?;
+        self.keys
+            .0
+            .hive
+            .try_decrypt_content(self.doc.clone(), &encrypted)
+            .await
+            .ok()
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+impl Encryption {
+    pub(crate) async fn create(_keys: &Keys, _initial: Digest) -> Result<Self, BeelayError> {
+        Err(unsupported())
+    }
+
+    pub(crate) async fn open(_keys: &Keys, _id: [u8; 32]) -> Result<Self, BeelayError> {
+        Err(unsupported())
+    }
+
+    pub(crate) fn id(&self) -> [u8; 32] {
+        match self.0 {}
+    }
+
+    pub(crate) async fn seal(
+        &self,
+        _hash: Digest,
+        _parents: &[Digest],
+        _blob: &[u8],
+    ) -> Result<Vec<u8>, BeelayError> {
+        match self.0 {}
+    }
+
+    pub(crate) async fn open_blob(&self, _sealed: &[u8]) -> Option<Vec<u8>> {
+        match self.0 {}
+    }
+}
+
+/// The Keyhive agent of handle `handle_id`, made now if it has none yet.
+pub(crate) async fn handle_keys(handle_id: u32) -> Result<Keys, JsValue> {
+    let (keys, signing_key, random) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        Ok::<_, JsValue>((
+            ctx.keys.clone(),
+            ctx.signing_key.clone(),
+            ctx.random.clone(),
+        ))
+    })?;
+    if let Some(keys) = keys {
+        return Ok(keys);
+    }
+
+    let generated = Keys::generate(&signing_key, random).await?;
+    HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        // Another call may have made them while this one was
+        Ok(ctx.keys.get_or_insert(generated).clone())
+    })
+}
+
+/// The agent a handle saved as `archive`, if it had one, making new keys from the
+/// handle's `random` source.
+pub(crate) async fn restore_keys(
+    archive: Option<&[u8]>,
+    signing_key: &SigningKey,
+    random: RandomSource,
+) -> Result<Option<Keys>, BeelayError> {
+    match archive {
+        Some(archive) => Keys::restore(archive, signing_key, random).await.map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The encryption of a document saved with the Keyhive document `id`, if it was
+/// encrypted, with the handle's `keys`.
+pub(crate) async fn restore(
+    keys: Option<&Keys>,
+    id: Option<[u8; 32]>,
+) -> Result<Option<Encryption>, BeelayError> {
+    let Some(id) = id else {
+        return Ok(None);
+    };
+    let keys = keys.ok_or_else(|| {
+        BeelayError::encryption("an encrypted document was saved without the handle's keys")
+    })?;
+    Encryption::open(keys, id).await.map(Some)
+}
+
+#[cfg(not(feature = "encryption"))]
+fn unsupported() -> BeelayError {
+    BeelayError::Unsupported("encrypted documents need the encryption feature".to_string())
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn sealed_blobs_open_after_a_restore() {
+        block_on(async {
+            let signing_key = SigningKey::from_bytes(&[7; 32]);
+            let keys = Keys::generate(&signing_key, RandomSource::Crypto)
+                .await
+                .unwrap();
+            let initial = Digest::hash(b"initial");
+            let encryption = Encryption::create(&keys, initial).await.unwrap();
+
+            let sealed = encryption.seal(initial, &[], b"contents").await.unwrap();
+            assert!(sealed.starts_with(SEALED));
+            assert!(!sealed.windows(8).any(|window| window == b"contents"));
+            assert_eq!(encryption.open_blob(&sealed).await.unwrap(), b"contents");
+            assert_eq!(encryption.open_blob(b"contents").await, None);
+
+            let restored = Keys::restore(&keys.archive(), &signing_key, RandomSource::Crypto)
+                .await
+                .unwrap();
+            let reopened = Encryption::open(&restored, encryption.id()).await.unwrap();
+            assert_eq!(reopened.open_blob(&sealed).await.unwrap(), b"contents");
+        });
+    }
+}
@@ -0,0 +1,229 @@
+//! The versioned shape of the commits the JS API takes and returns.
+//!
+//! Every commit a method returns carries `v`, the version of its shape, which is
+//! currently [`COMMIT_VERSION`]. Commits passed in may leave it out, and are then
+//! read as version 1, the shape from before it was versioned.
+//!
+//! Fields of a commit that this version doesn't know, such as ones a later release
+//! adds, are kept as the commit's extensions rather than dropped, and written back
+//! out wherever the commit goes: `loadDocument` results, subscriptions, other tabs,
+//! backups, handed-over document state, and bundles. Code that only knows this
+//! version can pass newer commits through without losing anything. A commit's loose
+//! blob in storage only holds its contents and metadata (see [`crate::meta`]),
+//! though, so its extensions don't survive the document being reopened from loose
+//! commits.
+//!
+//! Commit contents are returned as `Uint8Array`s, copied out of WASM memory in one
+//! go rather than byte by byte into an array of numbers, and commits passed in may
+//! give theirs as a `Uint8Array` or an array of numbers. Contents written as CBOR,
+//! in backups, bundles, and handed-over document state, are still arrays, so that
+//! earlier releases can read them. `getBlobView` reads a commit's contents in place,
+//! without even the one copy.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+
+/// The version of the commit shape this release reads and writes.
+pub(crate) const COMMIT_VERSION: u32 = 1;
+
+/// The fields of a commit this version doesn't know, by name.
+pub(crate) type Extensions = BTreeMap<String, ciborium::Value>;
+
+/// The version of a commit that doesn't say, which predates versioning.
+pub(crate) const fn unversioned() -> u32 {
+    1
+}
+
+/// The extensions a commit passed in brings to the document, which leave out the
+/// `type` that commit outputs add of their own, in case an output was passed back in.
+pub(crate) fn carried(extensions: &Extensions) -> Extensions {
+    extensions
+        .iter()
+        .filter(|(name, _)| name.as_str() != "type")
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Convert `value`, which holds commits, to JS.
+///
+/// Commits serialize as maps, so that their extensions sit alongside their other
+/// fields, and maps would otherwise become JS `Map`s rather than plain objects.
+pub(crate) fn to_js<T: Serialize + ?Sized>(
+    value: &T,
+) -> Result<JsValue, serde_wasm_bindgen::Error> {
+    #[cfg(feature = "perf-trace")]
+    let _span = sedimentree_core::perf::span(sedimentree_core::perf::Op::BoundarySerialize);
+    value.serialize(&serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true))
+}
+
+/// Commit contents as bytes, which `serde_wasm_bindgen` converts to and from
+/// `Uint8Array`s.
+pub(crate) mod contents {
+    use std::fmt;
+
+    use serde::{
+        de::{SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+
+    pub(crate) fn serialize<S: Serializer>(
+        contents: &[u8],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(contents)
+    }
+
+    /// Contents given as bytes, or as an array of numbers.
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<u8>, D::Error> {
+        deserializer.deserialize_byte_buf(ContentsVisitor)
+    }
+
+    struct ContentsVisitor;
+
+    impl<'de> Visitor<'de> for ContentsVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a Uint8Array or an array of bytes")
+        }
+
+        fn visit_bytes<E>(self, bytes: &[u8]) -> Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E>(self, bytes: Vec<u8>) -> Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use ciborium::Value;
+
+    use super::*;
+    use crate::{meta::CommitMeta, CommitInput, CommitRecord};
+
+    fn commit_json(extra: &str) -> String {
+        format!(r#"{{"parents":["aa"],"hash":"bb","contents":[1,2,3]{extra}}}"#)
+    }
+
+    #[test]
+    fn unversioned_commits_read_as_version_one() {
+        let commit: CommitInput = serde_json::from_str(&commit_json("")).unwrap();
+        assert_eq!(commit.v, 1);
+        assert!(commit.extensions.is_empty());
+    }
+
+    #[test]
+    fn unknown_fields_survive_a_json_round_trip() {
+        let json = commit_json(r#","v":2,"attestation":"ff","meta":{"tags":["a"],"at":7}"#);
+        let commit: CommitInput = serde_json::from_str(&json).unwrap();
+        assert_eq!(commit.v, 2);
+        assert_eq!(commit.contents, [1, 2, 3]);
+        assert_eq!(
+            commit.extensions["attestation"],
+            Value::Text("ff".to_string())
+        );
+
+        let written = serde_json::to_value(&commit).unwrap();
+        let reread: CommitInput = serde_json::from_value(written.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&reread).unwrap(), written);
+        assert_eq!(written["meta"]["tags"][0], "a");
+        assert_eq!(written["meta"]["at"], 7);
+    }
+
+    #[test]
+    fn unknown_fields_survive_a_cbor_round_trip() {
+        let mut commit: CommitInput = serde_json::from_str(&commit_json("")).unwrap();
+        commit
+            .extensions
+            .insert("attestation".to_string(), Value::Bytes(vec![9; 64]));
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&commit, &mut bytes).unwrap();
+        let reread: CommitInput = ciborium::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(reread.v, COMMIT_VERSION);
+        assert_eq!(reread.hash, commit.hash);
+        assert_eq!(reread.extensions, commit.extensions);
+    }
+
+    #[test]
+    fn outputs_carry_the_version_and_extensions_but_one_type() {
+        let json = commit_json(r#","type":"commit","revision":12"#);
+        let commit: CommitInput = serde_json::from_str(&json).unwrap();
+        let record = CommitRecord {
+            parents: commit.parents,
+            hash: commit.hash,
+            contents: Rc::from(commit.contents),
+            meta: CommitMeta::default(),
+            extensions: carried(&commit.extensions),
+        };
+
+        let written = serde_json::to_string(&record.to_output()).unwrap();
+        assert_eq!(written.matches(r#""type""#).count(), 1);
+        let written: serde_json::Value = serde_json::from_str(&written).unwrap();
+        assert_eq!(written["v"], COMMIT_VERSION);
+        assert_eq!(written["revision"], 12);
+    }
+
+    #[test]
+    fn contents_read_as_bytes_or_numbers() {
+        let as_bytes = Value::Map(vec![
+            (Value::Text("parents".to_string()), Value::Array(vec![])),
+            (
+                Value::Text("hash".to_string()),
+                Value::Text("bb".to_string()),
+            ),
+            (
+                Value::Text("contents".to_string()),
+                Value::Bytes(vec![1, 2, 3]),
+            ),
+        ]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&as_bytes, &mut bytes).unwrap();
+        let commit: CommitInput = ciborium::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(commit.contents, [1, 2, 3]);
+        assert!(commit.extensions.is_empty());
+
+        let commit: CommitInput = serde_json::from_str(&commit_json("")).unwrap();
+        assert_eq!(commit.contents, [1, 2, 3]);
+    }
+
+    #[test]
+    fn outputs_write_contents_as_bytes() {
+        let commit: CommitInput = serde_json::from_str(&commit_json("")).unwrap();
+        let record = CommitRecord {
+            parents: commit.parents,
+            hash: commit.hash,
+            contents: Rc::from(commit.contents),
+            meta: CommitMeta::default(),
+            extensions: Extensions::new(),
+        };
+
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&record.to_output(), &mut bytes).unwrap();
+        let written: Value = ciborium::from_reader(bytes.as_slice()).unwrap();
+        let contents = written
+            .as_map()
+            .unwrap()
+            .iter()
+            .find(|(key, _)| key.as_text() == Some("contents"))
+            .map(|(_, value)| value);
+        assert_eq!(contents, Some(&Value::Bytes(vec![1, 2, 3])));
+    }
+}
@@ -0,0 +1,224 @@
+//! The errors `Beelay` methods throw.
+//!
+//! Apart from the `MisuseError`s of [strict mode](crate::strict), every error a
+//! method throws is a JS `Error` named `BeelayError`, with a stable `code` (the
+//! name of the [`BeelayError`] variant, such as `"UnknownDocument"`) and a
+//! `details` object holding anything the code comes with. Callers can branch on
+//! `code`; messages are for people and may change.
+
+use js_sys::{Object, Reflect};
+use subduction_client::CommitError;
+use wasm_bindgen::JsValue;
+
+use crate::{freeze::FreezeProof, storage::StorageError};
+
+/// An error thrown by a `Beelay` method.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum BeelayError {
+    /// The handle was stopped, or never loaded.
+    #[error("invalid handle")]
+    InvalidHandle,
+
+    #[error("unknown document")]
+    UnknownDocument,
+
+    #[error("unknown group")]
+    UnknownGroup,
+
+    #[error("unknown commit")]
+    UnknownCommit,
+
+    #[error("unknown access request")]
+    UnknownAccessRequest,
+
+    #[error("document already exists")]
+    DocumentExists,
+
+    #[error("invalid peer ID")]
+    InvalidPeerId,
+
+    /// A digest that isn't 32 bytes, hex encoded.
+    #[error("digest must be {0}")]
+    InvalidDigest(&'static str),
+
+    /// A commit whose hash isn't the hash of its parents and contents.
+    #[error("commit {hash} doesn't match its contents, whose hash is {actual}")]
+    HashMismatch { hash: String, actual: String },
+
+    /// A signed commit whose signature isn't its author's (see [`crate::signature`]).
+    #[error("commit {hash} isn't signed by its author")]
+    InvalidSignature { hash: String },
+
+    /// `addCommits` was given an `ifHeadsVersion` the document has moved on from.
+    #[error("heads version conflict: expected {expected}, document is at {actual}")]
+    HeadsVersionConflict { expected: u64, actual: u64 },
+
+    /// An argument or option of the wrong shape or out of range.
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    /// A backup, import, or handed-over document state that couldn't be read or written.
+    #[error("{0}")]
+    InvalidSnapshot(String),
+
+    /// The handle isn't allowed to do this to the document.
+    #[error("{0}")]
+    AccessDenied(&'static str),
+
+    #[error("{0}")]
+    AlreadyConnected(&'static str),
+
+    /// A connection that couldn't be opened, or isn't open.
+    #[error("{0}")]
+    ConnectionFailure(String),
+
+    /// A storage backend failed, or something expected in storage is missing.
+    #[error("{0}")]
+    StorageFailure(String),
+
+    /// Syncing with a peer failed.
+    #[error("{0}")]
+    SyncFailure(String),
+
+    /// The environment lacks something the handle needs.
+    #[error("{0}")]
+    Unsupported(String),
+
+    /// Keyhive couldn't encrypt a commit, or set up or restore the keys of an
+    /// encrypted document (see [`crate::encryption`]).
+    #[error("{0}")]
+    EncryptionFailure(String),
+
+    /// A view used after it was released.
+    #[error("{0}")]
+    Released(&'static str),
+
+    /// A commit to a document frozen with `freezeDoc`, whose proof this is.
+    #[error("document {} is frozen", .0.doc_id)]
+    DocumentFrozen(Box<FreezeProof>),
+
+    /// A call that would take the handle past one of its limits (see [`crate::quota`]).
+    #[error("{limit} is {max}, and this would reach {requested}")]
+    QuotaExceeded {
+        limit: &'static str,
+        max: u64,
+        requested: u64,
+    },
+}
+
+impl BeelayError {
+    /// An [`InvalidArgument`](Self::InvalidArgument) describing `err`.
+    pub(crate) fn invalid_argument(err: impl ToString) -> Self {
+        Self::InvalidArgument(err.to_string())
+    }
+
+    /// A [`StorageFailure`](Self::StorageFailure) describing `err`.
+    pub(crate) fn storage(err: impl ToString) -> Self {
+        Self::StorageFailure(err.to_string())
+    }
+
+    /// An [`EncryptionFailure`](Self::EncryptionFailure) describing `err`.
+    pub(crate) fn encryption(err: impl ToString) -> Self {
+        Self::EncryptionFailure(err.to_string())
+    }
+
+    /// A [`SyncFailure`](Self::SyncFailure) describing `err`.
+    pub(crate) fn sync(err: impl std::fmt::Debug) -> Self {
+        Self::SyncFailure(format!("{err:?}"))
+    }
+
+    /// The stable code JS callers can branch on.
+    pub(crate) const fn code(&self) -> &'static str {
+        match self {
+            Self::InvalidHandle => "InvalidHandle",
+            Self::UnknownDocument => "UnknownDocument",
+            Self::UnknownGroup => "UnknownGroup",
+            Self::UnknownCommit => "UnknownCommit",
+            Self::UnknownAccessRequest => "UnknownAccessRequest",
+            Self::DocumentExists => "DocumentExists",
+            Self::InvalidPeerId => "InvalidPeerId",
+            Self::InvalidDigest(_) => "InvalidDigest",
+            Self::HashMismatch { .. } => "HashMismatch",
+            Self::InvalidSignature { .. } => "InvalidSignature",
+            Self::HeadsVersionConflict { .. } => "HeadsVersionConflict",
+            Self::InvalidArgument(_) => "InvalidArgument",
+            Self::InvalidSnapshot(_) => "InvalidSnapshot",
+            Self::AccessDenied(_) => "AccessDenied",
+            Self::AlreadyConnected(_) => "AlreadyConnected",
+            Self::ConnectionFailure(_) => "ConnectionFailure",
+            Self::StorageFailure(_) => "StorageFailure",
+            Self::SyncFailure(_) => "SyncFailure",
+            Self::Unsupported(_) => "Unsupported",
+            Self::EncryptionFailure(_) => "EncryptionFailure",
+            Self::Released(_) => "Released",
+            Self::DocumentFrozen(_) => "DocumentFrozen",
+            Self::QuotaExceeded { .. } => "QuotaExceeded",
+        }
+    }
+
+    /// What the error comes with, as a plain object.
+    fn details(&self) -> Object {
+        let details = Object::new();
+        let set = |key: &str, value: JsValue| {
+            let _ = Reflect::set(&details, &JsValue::from_str(key), &value);
+        };
+        match self {
+            Self::HashMismatch { hash, actual } => {
+                set("hash", JsValue::from_str(hash));
+                set("actual", JsValue::from_str(actual));
+            }
+            Self::InvalidSignature { hash } => set("hash", JsValue::from_str(hash)),
+            Self::HeadsVersionConflict { expected, actual } => {
+                set("expected", JsValue::from_f64(*expected as f64));
+                set("actual", JsValue::from_f64(*actual as f64));
+            }
+            Self::QuotaExceeded {
+                limit,
+                max,
+                requested,
+            } => {
+                set("limit", JsValue::from_str(limit));
+                set("max", JsValue::from_f64(*max as f64));
+                set("requested", JsValue::from_f64(*requested as f64));
+            }
+            Self::DocumentFrozen(proof) => {
+                set(
+                    "proof",
+                    serde_wasm_bindgen::to_value(proof).unwrap_or(JsValue::NULL),
+                );
+            }
+            _ => {}
+        }
+        details
+    }
+}
+
+impl From<StorageError> for BeelayError {
+    fn from(err: StorageError) -> Self {
+        Self::storage(err)
+    }
+}
+
+impl From<CommitError> for BeelayError {
+    fn from(err: CommitError) -> Self {
+        match err {
+            CommitError::InvalidDigest(expected) => Self::InvalidDigest(expected),
+            CommitError::HashMismatch { hash, actual } => Self::HashMismatch { hash, actual },
+            CommitError::Metadata(_) => Self::storage(err),
+        }
+    }
+}
+
+impl From<BeelayError> for JsValue {
+    fn from(err: BeelayError) -> Self {
+        let error = js_sys::Error::new(&err.to_string());
+        error.set_name("BeelayError");
+        let _ = Reflect::set(
+            &error,
+            &JsValue::from_str("code"),
+            &JsValue::from_str(err.code()),
+        );
+        let _ = Reflect::set(&error, &JsValue::from_str("details"), &err.details());
+        error.into()
+    }
+}
@@ -0,0 +1,195 @@
+//! Exporting a single document as a file, and importing it on any handle.
+//!
+//! `exportDoc` packs a document into a CBOR-encoded [`DocExport`]: its commits, the
+//! bundles `compact` made of them along with the chunks they're stored as, and its
+//! codec and TTL. Unlike `exportDocState`, which hands a document between handles
+//! of the same app, an export doesn't carry any handle's indexes or membership, so
+//! it can be backed up, mailed around, or used to seed a new peer. `importDoc`
+//! rebuilds the document from it as if its commits had been synced, owned by the
+//! importing handle.
+
+use std::collections::HashSet;
+
+use js_sys::Uint8Array;
+use sedimentree_core::{
+    future::Local,
+    storage::{header::Codec, Storage},
+    Blob, Chunk, SedimentreeId,
+};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    bundle::Bundle,
+    check_hash,
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+    notify_heads, sorted_heads, start_syncing,
+    storage::DocStorage,
+    sync_status::RoundReports,
+    Beelay, CommitInput, CommitRecord, DocumentCtx, HANDLES,
+};
+
+/// The current export format version.
+const FORMAT_VERSION: u32 = 1;
+
+/// One document, as exported.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DocExport {
+    version: u32,
+    doc_id: String,
+    sed_id: SedimentreeId,
+    codec: Codec,
+    ttl_secs: Option<u64>,
+    /// In the order they were applied.
+    commits: Vec<CommitInput>,
+    bundles: Vec<ExportedBundle>,
+}
+
+/// A bundle, with the chunk it's stored as.
+#[derive(Debug, Serialize, Deserialize)]
+struct ExportedBundle {
+    chunk: Chunk,
+    contents: Vec<u8>,
+}
+
+impl DocExport {
+    /// The export of `doc`, known to this handle as `doc_id`, before its bundles are
+    /// added.
+    fn of(doc_id: String, doc: &DocumentCtx) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            doc_id,
+            sed_id: doc.sed_id,
+            codec: Storage::<Local>::header(doc.subduction.storage()).codec(),
+            ttl_secs: doc.ttl.map(|ttl| ttl.as_secs()),
+            commits: doc.commits.iter().map(CommitRecord::to_input).collect(),
+            bundles: Vec::new(),
+        }
+    }
+
+    fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes)
+            .map_err(|err| format!("failed to encode document export: {err}"))?;
+        Ok(bytes)
+    }
+
+    /// Decode an export produced by [`DocExport::encode`].
+    fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let export: Self = ciborium::from_reader(bytes)
+            .map_err(|err| format!("invalid document export: {err}"))?;
+        if export.version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported document export version {} (expected {FORMAT_VERSION})",
+                export.version
+            ));
+        }
+        Ok(export)
+    }
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Export a document as a single file that `importDoc` can rebuild it from on any
+    /// handle: its commits, its bundles, and its codec and TTL.
+    ///
+    /// Membership isn't included; whoever imports the document owns their copy.
+    #[wasm_bindgen(js_name = exportDoc)]
+    pub async fn export_doc(&self, doc_id: String) -> Result<Uint8Array, JsValue> {
+        let (engine, bundled, mut export) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            let bundled = doc
+                .bundles
+                .iter()
+                .map(Bundle::chunk)
+                .collect::<HashSet<_>>();
+            Ok::<_, JsValue>((
+                doc.subduction.clone(),
+                bundled,
+                DocExport::of(doc_id.clone(), doc),
+            ))
+        })?;
+
+        for chunk in engine.get_chunks(export.sed_id).await.unwrap_or_default() {
+            if !bundled.contains(&chunk.digest()) {
+                continue;
+            }
+            let contents = engine
+                .get_local_blob(chunk.summary().blob_meta().digest())
+                .await
+                .map_err(BeelayError::storage)?
+                .ok_or_else(|| BeelayError::storage("bundle is missing its blob"))?
+                .into_contents();
+            export.bundles.push(ExportedBundle { chunk, contents });
+        }
+
+        let bytes = export.encode().map_err(BeelayError::InvalidSnapshot)?;
+        Ok(Uint8Array::from(bytes.as_slice()))
+    }
+
+    /// Rebuild a document exported with `exportDoc`, owned by this handle, and start
+    /// syncing it. Returns the document's ID, which is the same as on the exporting
+    /// handle.
+    #[wasm_bindgen(js_name = importDoc)]
+    pub async fn import_doc(&self, bytes: Uint8Array) -> Result<String, JsValue> {
+        let export = DocExport::decode(&bytes.to_vec()).map_err(BeelayError::InvalidSnapshot)?;
+        let doc_id = export.doc_id.clone();
+        let (owner, storage, summaries) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            if ctx.documents.contains_key(&doc_id) {
+                return Err(JsValue::from(BeelayError::DocumentExists));
+            }
+            if ctx.strict_hashes {
+                export.commits.iter().try_for_each(check_hash)?;
+            }
+            ctx.limits.check_documents(ctx.documents.len(), 1)?;
+            ctx.limits.check_commits(0, &export.commits)?;
+            let storage = DocStorage::new(ctx.backend.as_ref(), export.sed_id, export.codec);
+            Ok((ctx.peer_id(), storage, ctx.summaries.clone()))
+        })?;
+
+        let reports = RoundReports::new(self.id, doc_id.clone());
+        let mut doc_ctx = DocumentCtx::new(export.sed_id, owner, storage, summaries, reports);
+        for commit in &export.commits {
+            doc_ctx.apply_commit(commit, None).await?;
+        }
+        for bundle in export.bundles {
+            let (recorded, _) = Bundle::from_chunk(&bundle.chunk, &bundle.contents)?;
+            doc_ctx
+                .subduction
+                .add_chunk(export.sed_id, &bundle.chunk, Blob::new(bundle.contents))
+                .await
+                .map_err(BeelayError::storage)?;
+            doc_ctx.record_bundle(recorded);
+        }
+        doc_ctx.restore_ttl(export.ttl_secs).await?;
+        let heads = sorted_heads(&doc_ctx.commits);
+
+        let watchers = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if ctx.documents.contains_key(&doc_id) {
+                return Err(JsValue::from(BeelayError::DocumentExists));
+            }
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            ctx.changes.append(&doc_id, &doc_ctx.commits);
+            ctx.documents.insert(doc_id.clone(), doc_ctx);
+            ctx.persist()?;
+            Ok(ctx.heads_watchers.values().cloned().collect::<Vec<_>>())
+        })?;
+        diagnostics::track(ResourceKind::Document, self.id, doc_id.clone());
+        start_syncing(self.id, &doc_id)?;
+        notify_heads(&doc_id, &heads, &watchers)?;
+
+        Ok(doc_id)
+    }
+}
@@ -0,0 +1,60 @@
+//! Facades: extra `Beelay` objects for the same handle, one per app module.
+//!
+//! `handle()` returns a facade sharing everything the handle holds, its
+//! documents, connections, and storage, so that separate parts of an app can
+//! each be given their own. What a facade subscribes to belongs to it, and
+//! `close()` removes just those subscriptions and frees the facade, leaving the
+//! handle and its other facades running. `stop()`, on any of them, stops the
+//! handle itself, as does freeing the last of them (see [`crate::ownership`]).
+
+use wasm_bindgen::prelude::*;
+
+use crate::{diagnostics, error::BeelayError, Beelay, HANDLES};
+
+/// The facade `load` returns.
+pub(crate) const ROOT: u32 = 0;
+
+#[wasm_bindgen]
+impl Beelay {
+    /// A new facade for this handle, with its own subscriptions and `close()`.
+    #[wasm_bindgen(js_name = handle)]
+    pub fn handle(&self) -> Result<Beelay, JsValue> {
+        let facade = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let facade = ctx.next_facade_id;
+            ctx.next_facade_id += 1;
+            ctx.owners.retain();
+            Ok::<_, JsValue>(facade)
+        })?;
+        diagnostics::track_object(self.id, facade);
+        Ok(Self {
+            id: self.id,
+            facade,
+        })
+    }
+
+    /// Remove every subscription made through this object and free it. The handle
+    /// keeps running for its other facades until one of them calls `stop()`, or the
+    /// last of them is freed.
+    pub fn close(self) {
+        let owned = HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(|ctx| {
+                    ctx.subscription_owners
+                        .iter()
+                        .filter(|(_, owner)| **owner == self.facade)
+                        .map(|(id, _)| *id)
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        });
+        for subscription_id in owned {
+            self.unsubscribe(subscription_id);
+        }
+    }
+}
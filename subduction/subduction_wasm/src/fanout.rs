@@ -0,0 +1,361 @@
+//! Sharing a socket's messages that aren't about any document among the engines
+//! of the documents it syncs.
+//!
+//! Each engine keeps only its own document's data, so a request from the server
+//! that isn't about a document (for blobs, a blob range, or a subscription) goes to
+//! every engine, and their answers are gathered into one: the blobs any of them
+//! had, the range if any of them had it, or the filters any of them refused. A
+//! gathered answer is sent once every engine has answered or gone, or after
+//! [`GATHER_TIMEOUT_MS`] with whatever has been gathered by then.
+//!
+//! Answers to requests the engines make go back to the engines that asked: blobs and
+//! ranges and upload acknowledgements by digest, and subscription answers in the
+//! order the subscriptions were sent.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use sedimentree_core::{Blob, Digest};
+use subduction_core::{connection::message::Message, sync::subscription::SubscriptionDenied};
+
+/// How long to wait for every engine to answer a request before answering with
+/// what they have so far.
+pub(crate) const GATHER_TIMEOUT_MS: u32 = 5_000;
+
+/// The requests being answered, and the answers the engines are waiting for, on one
+/// socket. Engines are known by the serial of their route.
+#[derive(Debug, Default)]
+pub(crate) struct Fanouts {
+    next_id: u64,
+    gathering: Vec<Gathering>,
+    /// The engines waiting for each blob, or for ranges or upload acknowledgements
+    /// of it.
+    asked: HashMap<Digest, HashSet<u64>>,
+    /// The engines that sent a subscription the server has yet to answer, oldest
+    /// first.
+    subscribing: VecDeque<u64>,
+}
+
+/// A request from the server being answered by every engine.
+#[derive(Debug)]
+struct Gathering {
+    id: u64,
+    /// The engines yet to answer.
+    awaiting: HashSet<u64>,
+    answer: Answer,
+}
+
+/// What has been gathered for a request so far.
+#[derive(Debug)]
+enum Answer {
+    Blobs(Vec<Blob>),
+    Range {
+        digest: Digest,
+        /// Whether one engine has sent the range already.
+        sent: bool,
+    },
+    Subscribed(Vec<SubscriptionDenied>),
+}
+
+impl Answer {
+    /// The answer to send once gathering is done, unless it was sent already.
+    fn finish(self) -> Option<Message> {
+        match self {
+            Self::Blobs(blobs) => Some(Message::BlobsResponse(blobs)),
+            Self::Range { sent: true, .. } => None,
+            Self::Range {
+                digest,
+                sent: false,
+            } => Some(Message::BlobRangeNotFound { digest }),
+            Self::Subscribed(denied) => Some(Message::Subscribed { denied }),
+        }
+    }
+}
+
+impl Fanouts {
+    /// Start gathering the answers of `routes` to `message`, if it is a request from
+    /// the server that isn't about a document, returning an ID to [`expire`] it by.
+    ///
+    /// [`expire`]: Self::expire
+    pub(crate) fn request(
+        &mut self,
+        message: &Message,
+        routes: impl IntoIterator<Item = u64>,
+    ) -> Option<u64> {
+        let answer = match message {
+            Message::BlobsRequest(_) => Answer::Blobs(Vec::new()),
+            Message::BlobRangeRequest { digest, .. } => Answer::Range {
+                digest: *digest,
+                sent: false,
+            },
+            Message::Subscribe { .. } => Answer::Subscribed(Vec::new()),
+            _ => return None,
+        };
+        let awaiting = routes.into_iter().collect::<HashSet<_>>();
+        if awaiting.is_empty() {
+            return None;
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.gathering.push(Gathering {
+            id,
+            awaiting,
+            answer,
+        });
+        Some(id)
+    }
+
+    /// Take `message`, which engine `serial` is sending, as its answer to the oldest
+    /// request it hasn't answered that it fits, returning what is now ready to send,
+    /// or `None` if it doesn't answer a request being gathered and should be sent as
+    /// it is.
+    pub(crate) fn answer(&mut self, serial: u64, message: &Message) -> Option<Vec<Message>> {
+        let at = self.gathering.iter().position(|gathering| {
+            gathering.awaiting.contains(&serial) && answers(&gathering.answer, message)
+        })?;
+        let gathering = &mut self.gathering[at];
+        gathering.awaiting.remove(&serial);
+        let mut ready = Vec::new();
+        match (&mut gathering.answer, message) {
+            (Answer::Blobs(gathered), Message::BlobsResponse(blobs)) => {
+                for blob in blobs {
+                    let digest = blob.meta().digest();
+                    if !gathered.iter().any(|kept| kept.meta().digest() == digest) {
+                        gathered.push(blob.clone());
+                    }
+                }
+            }
+            (Answer::Range { sent, .. }, Message::BlobRangeResponse { .. }) if !*sent => {
+                *sent = true;
+                ready.push(message.clone());
+            }
+            (Answer::Subscribed(gathered), Message::Subscribed { denied }) => {
+                for refused in denied {
+                    if !gathered.contains(refused) {
+                        gathered.push(refused.clone());
+                    }
+                }
+            }
+            // Another engine may have the range, or one sent it already
+            _ => {}
+        }
+        ready.extend(self.take_finished());
+        Some(ready)
+    }
+
+    /// Stop waiting for engine `serial`, whose route has gone, returning what is now
+    /// ready to send.
+    pub(crate) fn route_gone(&mut self, serial: u64) -> Vec<Message> {
+        for gathering in &mut self.gathering {
+            gathering.awaiting.remove(&serial);
+        }
+        self.asked.retain(|_, askers| {
+            askers.remove(&serial);
+            !askers.is_empty()
+        });
+        self.subscribing.retain(|&asker| asker != serial);
+        self.take_finished()
+    }
+
+    /// Give up waiting for the rest of the engines to answer request `id`, returning
+    /// the answer gathered so far, if it wasn't sent already.
+    pub(crate) fn expire(&mut self, id: u64) -> Option<Message> {
+        let at = self
+            .gathering
+            .iter()
+            .position(|gathering| gathering.id == id)?;
+        self.gathering.remove(at).answer.finish()
+    }
+
+    /// Note what engine `serial` asks for in `message`, which it is sending.
+    pub(crate) fn asking(&mut self, serial: u64, message: &Message) {
+        let digests = match message {
+            Message::BlobsRequest(digests) => digests.clone(),
+            Message::BlobRangeRequest { digest, .. } => vec![*digest],
+            Message::CommitUpload { commit, .. } => vec![commit.blob().digest()],
+            Message::Subscribe { .. } => {
+                self.subscribing.push_back(serial);
+                return;
+            }
+            _ => return,
+        };
+        for digest in digests {
+            self.asked.entry(digest).or_default().insert(serial);
+        }
+    }
+
+    /// The engines to pass `message` from the server on to, which isn't about a
+    /// document or a request to gather answers to, with what each should get.
+    pub(crate) fn deliver(&mut self, message: Message) -> Vec<(u64, Message)> {
+        match message {
+            Message::BlobsResponse(blobs) => {
+                let mut by_asker = HashMap::<u64, Vec<Blob>>::new();
+                for blob in blobs {
+                    // Having the whole blob, nobody is waiting for it any more
+                    for asker in self.asked.remove(&blob.meta().digest()).unwrap_or_default() {
+                        by_asker.entry(asker).or_default().push(blob.clone());
+                    }
+                }
+                by_asker
+                    .into_iter()
+                    .map(|(asker, blobs)| (asker, Message::BlobsResponse(blobs)))
+                    .collect()
+            }
+            Message::BlobRangeResponse { digest, .. }
+            | Message::BlobRangeNotFound { digest }
+            | Message::CommitUploadAck { digest, .. } => self
+                .asked
+                .get(&digest)
+                .into_iter()
+                .flatten()
+                .map(|&asker| (asker, message.clone()))
+                .collect(),
+            Message::Subscribed { .. } => self
+                .subscribing
+                .pop_front()
+                .map(|asker| (asker, message))
+                .into_iter()
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Forget everything, as the socket has closed.
+    pub(crate) fn clear(&mut self) {
+        *self = Self {
+            next_id: self.next_id,
+            ..Self::default()
+        };
+    }
+
+    /// Take the requests every engine has answered, returning their answers.
+    fn take_finished(&mut self) -> Vec<Message> {
+        let (finished, gathering) = std::mem::take(&mut self.gathering)
+            .into_iter()
+            .partition::<Vec<_>, _>(|gathering| gathering.awaiting.is_empty());
+        self.gathering = gathering;
+        finished
+            .into_iter()
+            .filter_map(|gathering| gathering.answer.finish())
+            .collect()
+    }
+}
+
+/// Whether `message` answers a request whose answer is being gathered into `answer`.
+fn answers(answer: &Answer, message: &Message) -> bool {
+    match (answer, message) {
+        (Answer::Blobs(_), Message::BlobsResponse(_))
+        | (Answer::Subscribed(_), Message::Subscribed { .. }) => true,
+        (
+            Answer::Range { digest, .. },
+            Message::BlobRangeResponse {
+                digest: answered, ..
+            }
+            | Message::BlobRangeNotFound { digest: answered },
+        ) => digest == answered,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digests(message: &Message) -> Vec<Digest> {
+        match message {
+            Message::BlobsResponse(blobs) => {
+                blobs.iter().map(|blob| blob.meta().digest()).collect()
+            }
+            _ => panic!("not a blobs response: {message:?}"),
+        }
+    }
+
+    #[test]
+    fn blob_requests_get_one_answer_from_every_engine() {
+        let (a, b) = (Blob::new(b"a".to_vec()), Blob::new(b"b".to_vec()));
+        let request = Message::BlobsRequest(vec![a.meta().digest(), b.meta().digest()]);
+        let mut fanouts = Fanouts::default();
+        assert!(fanouts.request(&request, [1, 2, 3]).is_some());
+
+        let answer = |blobs: &[&Blob]| {
+            Message::BlobsResponse(blobs.iter().map(|blob| (*blob).clone()).collect())
+        };
+        assert!(fanouts.answer(1, &answer(&[&a])).unwrap().is_empty());
+        assert!(fanouts.answer(2, &answer(&[&a, &b])).unwrap().is_empty());
+        // Engine 3 goes instead of answering
+        let ready = fanouts.route_gone(3);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(digests(&ready[0]), [a.meta().digest(), b.meta().digest()]);
+
+        // Later responses are the engines' own
+        assert!(fanouts.answer(1, &answer(&[&a])).is_none());
+    }
+
+    #[test]
+    fn ranges_are_sent_by_the_first_engine_that_has_them() {
+        let digest = Digest::hash(b"blob");
+        let request = Message::BlobRangeRequest {
+            digest,
+            offset: 0,
+            length: 4,
+        };
+        let range = Message::BlobRangeResponse {
+            digest,
+            offset: 0,
+            data: b"blob".to_vec(),
+            proof: Vec::new(),
+        };
+        let mut fanouts = Fanouts::default();
+
+        fanouts.request(&request, [1, 2]);
+        let not_found = Message::BlobRangeNotFound { digest };
+        assert!(fanouts.answer(1, &not_found).unwrap().is_empty());
+        let ready = fanouts.answer(2, &range).unwrap();
+        assert!(matches!(ready[..], [Message::BlobRangeResponse { .. }]));
+
+        // Nobody has it
+        fanouts.request(&request, [1, 2]);
+        assert!(fanouts.answer(1, &not_found).unwrap().is_empty());
+        let ready = fanouts.answer(2, &not_found).unwrap();
+        assert!(matches!(ready[..], [Message::BlobRangeNotFound { .. }]));
+    }
+
+    #[test]
+    fn expired_requests_are_answered_with_what_was_gathered() {
+        let mut fanouts = Fanouts::default();
+        let id = fanouts.request(&Message::BlobsRequest(Vec::new()), [1, 2]);
+        let blob = Blob::new(b"a".to_vec());
+        let response = Message::BlobsResponse(vec![blob.clone()]);
+        assert!(fanouts.answer(1, &response).unwrap().is_empty());
+
+        let answer = fanouts.expire(id.unwrap()).unwrap();
+        assert_eq!(digests(&answer), [blob.meta().digest()]);
+        assert!(fanouts
+            .answer(2, &Message::BlobsResponse(Vec::new()))
+            .is_none());
+    }
+
+    #[test]
+    fn responses_go_back_to_the_engines_that_asked() {
+        let (a, b) = (Blob::new(b"a".to_vec()), Blob::new(b"b".to_vec()));
+        let mut fanouts = Fanouts::default();
+        fanouts.asking(1, &Message::BlobsRequest(vec![a.meta().digest()]));
+        fanouts.asking(2, &Message::BlobsRequest(vec![b.meta().digest()]));
+        fanouts.asking(
+            2,
+            &Message::Subscribe {
+                filters: Vec::new(),
+            },
+        );
+
+        let mut delivered = fanouts.deliver(Message::BlobsResponse(vec![a.clone(), b.clone()]));
+        delivered.sort_by_key(|(asker, _)| *asker);
+        assert_eq!(delivered.len(), 2);
+        assert_eq!(digests(&delivered[0].1), [a.meta().digest()]);
+        assert_eq!(digests(&delivered[1].1), [b.meta().digest()]);
+
+        // Nobody asked for it again
+        assert!(fanouts.deliver(Message::BlobsResponse(vec![a])).is_empty());
+        let subscribed = fanouts.deliver(Message::Subscribed { denied: Vec::new() });
+        assert!(matches!(subscribed[..], [(2, Message::Subscribed { .. })]));
+    }
+}
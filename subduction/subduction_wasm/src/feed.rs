@@ -0,0 +1,257 @@
+//! An append-only log of the commits applied to a handle's documents.
+//!
+//! `changesFeed` hands out the log a page at a time, with a [`Cursor`] to
+//! resume from, so external indexes, backups, and ETL jobs can follow a handle
+//! incrementally without reloading whole documents. Entries only describe
+//! commits; their contents are a `loadDocument` or `getBlobView` away.
+//!
+//! Each entry gets the next sequence number as it is appended, and a cursor
+//! resumes after the sequence number of the last entry it handed out, so pages
+//! neither skip nor repeat entries. Deleting a document drops its entries, which
+//! cursors then pass over. The log keeps the most recent [`MAX_ENTRIES`]; a cursor
+//! that fell behind the oldest it keeps has expired.
+//!
+//! A handle that persists saves its log whole, next to the saved handle, each time
+//! the log changes, and picks it up again when it's reloaded, so cursors resume
+//! across reloads. Restoring a snapshot or backup replaces the restored documents'
+//! entries with new ones at the end of the log. Each log has an epoch of its own,
+//! which tells its cursors from those of another handle's log, or of a handle that
+//! kept its log in memory only.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cursor::{Cursor, CursorError, CursorKind, PageOptions},
+    CommitRecord,
+};
+
+/// The most entries a feed keeps, dropping the oldest beyond it.
+pub(crate) const MAX_ENTRIES: usize = 10_000;
+
+/// One commit in the feed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChangeEntry {
+    doc_id: String,
+    hash: String,
+    parents: Vec<String>,
+    /// The size of the commit's contents in bytes.
+    size: usize,
+}
+
+/// The result of `Beelay.changesFeed`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ChangesPage<'a> {
+    entries: Vec<&'a ChangeEntry>,
+    /// Pass this to the next call to get only the entries after these.
+    next_token: String,
+}
+
+/// The log itself.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ChangeFeed {
+    /// Tells this log's cursors from those of any other.
+    epoch: String,
+    /// The entries kept, with their sequence numbers, oldest first.
+    entries: VecDeque<(usize, ChangeEntry)>,
+    next_seq: usize,
+    /// The sequence number of the oldest entry that hasn't been dropped for space.
+    kept_from: usize,
+    #[serde(skip, default = "max_entries")]
+    limit: usize,
+}
+
+const fn max_entries() -> usize {
+    MAX_ENTRIES
+}
+
+impl ChangeFeed {
+    /// An empty log for the epoch `epoch`, which should differ from every earlier one.
+    pub(crate) const fn new(epoch: String) -> Self {
+        Self {
+            epoch,
+            entries: VecDeque::new(),
+            next_seq: 0,
+            kept_from: 0,
+            limit: MAX_ENTRIES,
+        }
+    }
+
+    /// Append newly applied commits to `doc_id`.
+    pub(crate) fn append(&mut self, doc_id: &str, commits: &[CommitRecord]) {
+        for commit in commits {
+            let entry = ChangeEntry {
+                doc_id: doc_id.to_string(),
+                hash: commit.hash.clone(),
+                parents: commit.parents.clone(),
+                size: commit.contents.len(),
+            };
+            self.entries.push_back((self.next_seq, entry));
+            self.next_seq += 1;
+        }
+        while self.entries.len() > self.limit {
+            if let Some((seq, _)) = self.entries.pop_front() {
+                self.kept_from = seq + 1;
+            }
+        }
+    }
+
+    /// Drop the entries of `doc_id`, which was deleted.
+    pub(crate) fn remove(&mut self, doc_id: &str) {
+        self.entries.retain(|(_, entry)| entry.doc_id != doc_id);
+    }
+
+    /// The entries after `page.cursor`, or from the oldest kept if there is none, up
+    /// to `page.limit` of them.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the cursor wasn't handed out by this log, or expired because the log
+    /// dropped entries it hadn't handed out yet.
+    pub(crate) fn since(&self, page: &PageOptions) -> Result<ChangesPage<'_>, CursorError> {
+        let (cursor, after) = match &page.cursor {
+            Some(token) => {
+                let cursor = Cursor::decode_unscoped(token, CursorKind::Changes)?;
+                if cursor.scope() != self.epoch {
+                    return Err(CursorError::Invalid(
+                        "it's from another handle's changes feed, or one that wasn't saved"
+                            .to_string(),
+                    ));
+                }
+                let after = cursor.key().map_or(0, |_| cursor.index() + 1);
+                if after < self.kept_from {
+                    return Err(CursorError::Expired {
+                        kind: CursorKind::Changes,
+                        reason: "entries it hadn't handed out were dropped to bound the feed",
+                    });
+                }
+                (cursor, after)
+            }
+            None => (Cursor::start(CursorKind::Changes, &self.epoch), 0),
+        };
+        let from = self.entries.partition_point(|(seq, _)| *seq < after);
+        let to = page.end(from, self.entries.len());
+
+        let next = match to.checked_sub(1).filter(|_| to > from) {
+            Some(last) => {
+                let (seq, entry) = &self.entries[last];
+                let key = format!("{}/{}", entry.doc_id, entry.hash);
+                Cursor::after(CursorKind::Changes, &self.epoch, key, *seq)
+            }
+            None => cursor,
+        };
+        Ok(ChangesPage {
+            entries: self
+                .entries
+                .range(from..to)
+                .map(|(_, entry)| entry)
+                .collect(),
+            next_token: next.encode(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        envelope::Extensions,
+        meta::CommitMeta,
+        storage::{from_cbor, to_cbor},
+    };
+
+    fn commits(hashes: &[&str]) -> Vec<CommitRecord> {
+        hashes
+            .iter()
+            .map(|hash| CommitRecord {
+                parents: Vec::new(),
+                hash: (*hash).to_string(),
+                contents: Rc::from(Vec::new()),
+                meta: CommitMeta::default(),
+                extensions: Extensions::new(),
+            })
+            .collect()
+    }
+
+    /// The hashes on the page after `cursor`, and the token to resume from.
+    fn page(
+        feed: &ChangeFeed,
+        cursor: Option<String>,
+        limit: usize,
+    ) -> Result<(Vec<String>, String), CursorError> {
+        let page = feed.since(&PageOptions {
+            cursor,
+            limit: Some(limit),
+        })?;
+        let hashes = page
+            .entries
+            .iter()
+            .map(|entry| entry.hash.clone())
+            .collect();
+        Ok((hashes, page.next_token))
+    }
+
+    #[test]
+    fn resumes_where_it_left_off_across_deletes() -> Result<(), CursorError> {
+        let mut feed = ChangeFeed::new("a".to_string());
+        feed.append("doc1", &commits(&["a1", "a2"]));
+        feed.append("doc2", &commits(&["b1"]));
+
+        let (first, token) = page(&feed, None, 2)?;
+        assert_eq!(first, ["a1", "a2"]);
+
+        // The cursor's last entry goes, and one more arrives
+        feed.remove("doc1");
+        feed.append("doc1", &commits(&["c1"]));
+        let (rest, _) = page(&feed, Some(token), 10)?;
+        assert_eq!(rest, ["b1", "c1"]);
+        Ok(())
+    }
+
+    #[test]
+    fn cursors_resume_once_the_feed_is_saved_and_loaded() -> Result<(), CursorError> {
+        let mut feed = ChangeFeed::new("a".to_string());
+        feed.append("doc2", &commits(&["b1"]));
+        feed.append("doc1", &commits(&["a1"]));
+        feed.remove("doc2");
+        let (_, token) = page(&feed, None, 1)?;
+
+        let saved = to_cbor(&feed).map_err(|err| CursorError::Invalid(err.to_string()))?;
+        let mut loaded: ChangeFeed =
+            from_cbor(&saved).map_err(|err| CursorError::Invalid(err.to_string()))?;
+        loaded.append("doc1", &commits(&["a2"]));
+        assert_eq!(page(&loaded, Some(token), 10)?.0, ["a2"]);
+        assert_eq!(loaded.limit, MAX_ENTRIES);
+
+        // Another feed's cursors are no use here
+        let (_, other) = page(&ChangeFeed::new("b".to_string()), None, 1)?;
+        let resumed = page(&loaded, Some(other), 10);
+        assert!(matches!(resumed, Err(CursorError::Invalid(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn drops_the_oldest_entries_past_its_limit() -> Result<(), CursorError> {
+        let mut feed = ChangeFeed {
+            limit: 2,
+            ..ChangeFeed::new("a".to_string())
+        };
+        let (_, fresh) = page(&feed, None, 1)?;
+        feed.append("doc", &commits(&["1"]));
+        let (_, behind) = page(&feed, Some(fresh.clone()), 1)?;
+        feed.append("doc", &commits(&["2", "3", "4"]));
+
+        assert_eq!(page(&feed, None, 10)?.0, ["3", "4"]);
+        // It had yet to see 2
+        let resumed = page(&feed, Some(behind), 10);
+        assert!(matches!(resumed, Err(CursorError::Expired { .. })));
+        let resumed = page(&feed, Some(fresh), 10);
+        assert!(matches!(resumed, Err(CursorError::Expired { .. })));
+        Ok(())
+    }
+}
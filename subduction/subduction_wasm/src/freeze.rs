@@ -0,0 +1,210 @@
+//! Freezing documents at the end of their life.
+//!
+//! An admin of a document can freeze it, for finalized contracts, published
+//! versions, and the like. Freezing signs a [`FreezeProof`] naming the document's
+//! heads at the time, and from then on the handle rejects new commits to it, local
+//! edits and synced commits alike, with a `DocumentFrozen` error carrying the
+//! proof. The document's engine takes nothing more from peers either, though peers
+//! can still sync it down. Anyone can check the proof with `verifyFreezeProof` and
+//! compare its heads with the document's.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::{error::BeelayError, sorted_heads, Beelay, DocumentCtx, HANDLES};
+
+/// An admin's signed statement that a document was frozen at some heads.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FreezeProof {
+    pub(crate) doc_id: String,
+    /// The document's heads when it was frozen, sorted.
+    pub(crate) heads: Vec<String>,
+    /// The hex-encoded verifying key of the admin who froze it.
+    pub(crate) frozen_by: String,
+    /// When it was frozen, in milliseconds since the epoch.
+    pub(crate) frozen_at: u64,
+    /// The admin's hex-encoded signature over everything else.
+    signature: String,
+}
+
+impl FreezeProof {
+    /// A proof that `doc_id` was frozen at `heads`, signed by `signer`.
+    fn new(doc_id: String, heads: Vec<String>, frozen_at: u64, signer: &SigningKey) -> Self {
+        let frozen_by = hex::encode(signer.verifying_key().as_bytes());
+        let payload = signing_payload(&doc_id, &heads, &frozen_by, frozen_at);
+        Self {
+            signature: hex::encode(signer.sign(&payload).to_bytes()),
+            doc_id,
+            heads,
+            frozen_by,
+            frozen_at,
+        }
+    }
+
+    /// Check that the proof was signed by the admin it names.
+    pub(crate) fn verify(&self) -> bool {
+        let Some(key) = hex::decode(&self.frozen_by)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        else {
+            return false;
+        };
+        let Some(signature) = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        else {
+            return false;
+        };
+
+        let payload = signing_payload(&self.doc_id, &self.heads, &self.frozen_by, self.frozen_at);
+        key.verify(&payload, &signature).is_ok()
+    }
+}
+
+fn signing_payload(doc_id: &str, heads: &[String], frozen_by: &str, frozen_at: u64) -> Vec<u8> {
+    let mut payload = b"subduction/freeze/v1".to_vec();
+    let fields = [doc_id, frozen_by]
+        .into_iter()
+        .chain(heads.iter().map(String::as_str));
+    payload.extend_from_slice(&(heads.len() as u64).to_le_bytes());
+    for field in fields {
+        payload.extend_from_slice(&(field.len() as u64).to_le_bytes());
+        payload.extend_from_slice(field.as_bytes());
+    }
+    payload.extend_from_slice(&frozen_at.to_le_bytes());
+    payload
+}
+
+impl DocumentCtx {
+    /// Freeze the document with `proof`, as it was saved or just made.
+    pub(crate) fn restore_freeze(&mut self, proof: Option<FreezeProof>) {
+        if let Some(proof) = proof {
+            self.subduction.freeze(self.sed_id);
+            self.frozen = Some(proof);
+        }
+    }
+
+    /// Fail with the document's freeze proof if it's frozen and any of `hashes` are new
+    /// to it.
+    pub(crate) fn check_unfrozen<'a>(
+        &self,
+        mut hashes: impl Iterator<Item = &'a String>,
+    ) -> Result<(), BeelayError> {
+        match &self.frozen {
+            Some(proof) if hashes.any(|hash| !self.seen.contains(hash)) => {
+                Err(BeelayError::DocumentFrozen(Box::new(proof.clone())))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Freeze a document: sign a proof of its current heads as an admin of it, and
+    /// reject any further commits to it, local or synced, with a `DocumentFrozen`
+    /// error whose `details.proof` is that proof.
+    ///
+    /// Returns the proof, `{ docId, heads, frozenBy, frozenAt, signature }`. Freezing
+    /// a frozen document returns the proof it was frozen with. Documents can't be
+    /// unfrozen.
+    #[wasm_bindgen(js_name = freezeDoc)]
+    pub fn freeze_doc(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        let proof = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let peer_id = ctx.peer_id();
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            if let Some(proof) = &doc.frozen {
+                return Ok::<_, JsValue>(proof.clone());
+            }
+            if !doc.members.is_admin(&peer_id, &ctx.groups) {
+                return Err(BeelayError::AccessDenied("only admins can freeze a document").into());
+            }
+
+            let proof = FreezeProof::new(
+                doc_id.clone(),
+                sorted_heads(&doc.commits),
+                js_sys::Date::now() as u64,
+                &ctx.signing_key,
+            );
+            if let Some(doc) = ctx.documents.get_mut(&doc_id) {
+                doc.restore_freeze(Some(proof.clone()));
+            }
+            ctx.persist()?;
+            Ok(proof)
+        })?;
+        serde_wasm_bindgen::to_value(&proof).map_err(JsValue::from)
+    }
+
+    /// The proof a document was frozen with, or `null` if it isn't frozen.
+    #[wasm_bindgen(js_name = freezeProof)]
+    pub fn freeze_proof(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            match &doc.frozen {
+                Some(proof) => serde_wasm_bindgen::to_value(proof).map_err(JsValue::from),
+                None => Ok(JsValue::NULL),
+            }
+        })
+    }
+}
+
+/// Whether `proof`, as `freezeDoc` returns it, was signed by the admin it names.
+///
+/// That the admin was an admin of the document, and that its heads are the
+/// document's, are for the caller to check against what they know of it.
+#[wasm_bindgen(js_name = verifyFreezeProof)]
+pub fn verify_freeze_proof(proof: JsValue) -> Result<bool, JsValue> {
+    let proof: FreezeProof =
+        serde_wasm_bindgen::from_value(proof).map_err(BeelayError::invalid_argument)?;
+    Ok(proof.verify())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proof() -> FreezeProof {
+        FreezeProof::new(
+            "doc".to_string(),
+            vec!["aa".to_string(), "bb".to_string()],
+            1_700_000_000_000,
+            &SigningKey::from_bytes(&[7; 32]),
+        )
+    }
+
+    #[test]
+    fn proofs_verify() {
+        assert!(proof().verify());
+    }
+
+    #[test]
+    fn altered_proofs_dont_verify() {
+        let mut moved = proof();
+        moved.heads.push("cc".to_string());
+        assert!(!moved.verify());
+
+        let mut backdated = proof();
+        backdated.frozen_at -= 1;
+        assert!(!backdated.verify());
+
+        let mut reassigned = proof();
+        reassigned.frozen_by =
+            hex::encode(SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes());
+        assert!(!reassigned.verify());
+    }
+}
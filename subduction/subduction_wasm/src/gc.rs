@@ -0,0 +1,163 @@
+//! Garbage collection of commits that bundles have superseded.
+//!
+//! Once `compact` has bundled a run of commits, the bundle holds everything they
+//! do, so the commits themselves only take up room. `gc` drops them from the
+//! document, its engine, and storage, blobs included, leaving them in their
+//! bundles. Heads are always kept, and so are the commits up to `keepDepth`
+//! parents back from them, so that peers a few commits behind can still sync
+//! them one by one rather than take a whole bundle.
+//!
+//! A collected commit is still one the document has seen, so it doesn't come back
+//! when peers offer it again. `loadDocument` lists its bundle as before, but
+//! `loadDocumentPage`, `loadCommitsSince`, and the commit graph leave it out. `save`
+//! reads collected commits back out of their bundles, and so does reopening the
+//! handle, without storing them again.
+
+use std::collections::{HashMap, HashSet};
+
+use sedimentree_core::Digest;
+use serde::Deserialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{error::BeelayError, rewrite_storage, strict, Beelay, CommitRecord, HANDLES};
+
+/// Options accepted by `Beelay.gc`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcOptions {
+    /// How many parents back from the heads to keep commits. Defaults to 0, keeping
+    /// only the heads.
+    keep_depth: Option<u32>,
+}
+
+/// The heads of `commits`, and their ancestors up to `keep_depth` parents back.
+fn recent(commits: &[CommitRecord], keep_depth: u32) -> HashSet<String> {
+    let parents = commits
+        .iter()
+        .map(|record| (record.hash.as_str(), &record.parents))
+        .collect::<HashMap<_, _>>();
+
+    let mut kept = crate::conflict::heads(commits)
+        .into_iter()
+        .collect::<HashSet<_>>();
+    let mut frontier = kept.iter().cloned().collect::<Vec<_>>();
+    for _ in 0..keep_depth {
+        frontier = frontier
+            .iter()
+            .filter_map(|hash| parents.get(hash.as_str()))
+            .flat_map(|next| next.iter())
+            .filter(|parent| kept.insert((*parent).clone()))
+            .cloned()
+            .collect();
+    }
+    kept
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Drop the commits of a document that bundles made by `compact` already hold, from
+    /// the document and from storage, keeping its heads and the commits up to
+    /// `keepDepth` parents back from them (0 by default). Resolves to the number of
+    /// commits dropped.
+    ///
+    /// `loadDocument` lists their bundles as before, but `loadDocumentPage` and
+    /// `loadCommitsSince` no longer include them.
+    #[wasm_bindgen(js_name = gc)]
+    pub async fn gc(
+        &self,
+        doc_id: String,
+        #[wasm_bindgen(unchecked_param_type = "GcOptions | undefined")] options: JsValue,
+    ) -> Result<usize, JsValue> {
+        strict::check_running(self.id, "gc")?;
+        let options: GcOptions = if options.is_undefined() || options.is_null() {
+            GcOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options).map_err(BeelayError::invalid_argument)?
+        };
+
+        let (engine, sed_id, superseded) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            let kept = recent(&doc.commits, options.keep_depth.unwrap_or(0));
+            let superseded = doc
+                .commits
+                .iter()
+                .map(|record| &record.hash)
+                .filter(|hash| !kept.contains(*hash))
+                .filter(|hash| doc.bundles.iter().any(|bundle| bundle.covers(hash)))
+                .filter_map(|hash| <[u8; 32]>::try_from(hex::decode(hash).ok()?).ok())
+                .map(Digest::from)
+                .collect::<HashSet<_>>();
+            Ok::<_, JsValue>((doc.subduction.clone(), doc.sed_id, superseded))
+        })?;
+        if superseded.is_empty() {
+            return Ok(0);
+        }
+
+        let dropped = engine
+            .drop_loose_commits(sed_id, &superseded)
+            .await
+            .iter()
+            .map(|digest| hex::encode(digest.as_bytes()))
+            .collect::<HashSet<_>>();
+        let backend = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get_mut(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            doc.collect_commits(&dropped);
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            Ok::<_, JsValue>(ctx.backend.clone())
+        })?;
+        if let Some(backend) = backend {
+            rewrite_storage(&backend, &engine, sed_id).await?;
+        }
+        Ok(dropped.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{envelope::Extensions, meta::CommitMeta};
+
+    fn commit(hash: &str, parents: &[&str]) -> CommitRecord {
+        CommitRecord {
+            parents: parents.iter().map(ToString::to_string).collect(),
+            hash: hash.to_string(),
+            contents: Rc::from(Vec::new()),
+            meta: CommitMeta::default(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    #[test]
+    fn heads_and_their_recent_ancestors_are_kept() {
+        // `a` ← `b` ← `c` ← `d`, and `b` ← `e`
+        let commits = vec![
+            commit("a", &[]),
+            commit("b", &["a"]),
+            commit("c", &["b"]),
+            commit("d", &["c"]),
+            commit("e", &["b"]),
+        ];
+        let sorted = |kept: HashSet<String>| {
+            let mut kept = kept.into_iter().collect::<Vec<_>>();
+            kept.sort();
+            kept
+        };
+        assert_eq!(sorted(recent(&commits, 0)), ["d", "e"]);
+        assert_eq!(sorted(recent(&commits, 1)), ["b", "c", "d", "e"]);
+        assert_eq!(sorted(recent(&commits, 9)), ["a", "b", "c", "d", "e"]);
+    }
+}
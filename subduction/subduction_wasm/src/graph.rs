@@ -0,0 +1,240 @@
+//! A document's commit graph, for history views and merge tooling.
+//!
+//! `getCommitGraph` lists every commit as a node with its parents and depth,
+//! and every parent link as an edge, so apps don't have to rebuild the graph
+//! from `loadDocument`. `isAncestor` and `commonAncestors` answer the questions
+//! merge tooling asks of it without pulling it into JS at all.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Serialize;
+use wasm_bindgen::prelude::*;
+
+use crate::{error::BeelayError, order::Order, Beelay, CommitRecord, DocumentCtx, HANDLES};
+
+/// A document's commit graph, as `getCommitGraph` returns it.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CommitGraph {
+    /// Parents first.
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+    heads: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Node {
+    hash: String,
+    parents: Vec<String>,
+    /// The length of the longest path from the commit back to a commit without
+    /// parents in the document, which is 0.
+    depth: usize,
+}
+
+/// A link from a commit to one of its parents.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Edge {
+    from: String,
+    to: String,
+}
+
+impl CommitGraph {
+    fn of(commits: &[CommitRecord]) -> Self {
+        let mut depths = HashMap::<&str, usize>::with_capacity(commits.len());
+        let mut nodes = Vec::with_capacity(commits.len());
+        let mut edges = Vec::new();
+        for record in Order::Causal.apply(commits) {
+            let depth = record
+                .parents
+                .iter()
+                .filter_map(|parent| depths.get(parent.as_str()))
+                .max()
+                .map_or(0, |deepest| deepest + 1);
+            depths.insert(&record.hash, depth);
+            edges.extend(record.parents.iter().map(|parent| Edge {
+                from: record.hash.clone(),
+                to: parent.clone(),
+            }));
+            nodes.push(Node {
+                hash: record.hash.clone(),
+                parents: record.parents.clone(),
+                depth,
+            });
+        }
+        let mut heads = crate::conflict::heads(commits);
+        heads.sort();
+        Self {
+            nodes,
+            edges,
+            heads,
+        }
+    }
+}
+
+/// `from` and every commit reachable from it through parent links.
+pub(crate) fn ancestors(commits: &[CommitRecord], from: &[String]) -> HashSet<String> {
+    let parents = commits
+        .iter()
+        .map(|record| (&record.hash, &record.parents))
+        .collect::<HashMap<_, _>>();
+
+    let mut reached = HashSet::new();
+    let mut stack = from.to_vec();
+    while let Some(hash) = stack.pop() {
+        if let Some(next) = parents.get(&hash) {
+            stack.extend(
+                next.iter()
+                    .filter(|parent| !reached.contains(*parent))
+                    .cloned(),
+            );
+        }
+        reached.insert(hash);
+    }
+    reached
+}
+
+/// The commits of `among` that aren't an ancestor of another of them, in the order of
+/// `commits`.
+pub(crate) fn newest(commits: &[CommitRecord], among: &HashSet<String>) -> Vec<String> {
+    let parents = commits
+        .iter()
+        .filter(|record| among.contains(&record.hash))
+        .flat_map(|record| record.parents.iter().cloned())
+        .collect::<Vec<_>>();
+    let covered = ancestors(commits, &parents);
+    commits
+        .iter()
+        .map(|record| &record.hash)
+        .filter(|hash| among.contains(*hash) && !covered.contains(*hash))
+        .cloned()
+        .collect()
+}
+
+/// The most recent commits that both `a` and `b` descend from, themselves included.
+fn common_ancestors(commits: &[CommitRecord], a: &str, b: &str) -> Vec<String> {
+    let of_a = ancestors(commits, &[a.to_string()]);
+    let of_b = ancestors(commits, &[b.to_string()]);
+    let common = of_a.intersection(&of_b).cloned().collect::<HashSet<_>>();
+    newest(commits, &common)
+}
+
+/// Run `f` on document `doc_id` of handle `handle_id`, once `hashes` are known to be
+/// its commits.
+fn with_commits<T>(
+    handle_id: u32,
+    doc_id: &str,
+    hashes: &[&str],
+    f: impl FnOnce(&DocumentCtx) -> T,
+) -> Result<T, JsValue> {
+    HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        let doc = ctx
+            .documents
+            .get(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        if !hashes.iter().all(|hash| doc.seen.contains(*hash)) {
+            return Err(BeelayError::UnknownCommit.into());
+        }
+        Ok(f(doc))
+    })
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// A document's commit graph, as `{ nodes, edges, heads }`.
+    ///
+    /// `nodes` holds each commit as `{ hash, parents, depth }`, parents first, where
+    /// `depth` is the length of the longest path back to a commit without parents.
+    /// `edges` holds each parent link as `{ from, to }`, from a commit to its parent,
+    /// and `heads` the document's heads, sorted.
+    #[wasm_bindgen(js_name = getCommitGraph)]
+    pub fn get_commit_graph(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        let graph = with_commits(self.id, &doc_id, &[], |doc| CommitGraph::of(&doc.commits))?;
+        serde_wasm_bindgen::to_value(&graph).map_err(JsValue::from)
+    }
+
+    /// Whether commit `a` is an ancestor of commit `b` in a document. As with git, a
+    /// commit counts as its own ancestor.
+    #[wasm_bindgen(js_name = isAncestor)]
+    pub fn is_ancestor(&self, doc_id: String, a: String, b: String) -> Result<bool, JsValue> {
+        with_commits(self.id, &doc_id, &[&a, &b], |doc| {
+            ancestors(&doc.commits, std::slice::from_ref(&b)).contains(&a)
+        })
+    }
+
+    /// The most recent commits that both `a` and `b` descend from in a document (their
+    /// merge bases), in the order they were applied. Either may be one of them, if it's
+    /// an ancestor of the other.
+    #[wasm_bindgen(js_name = commonAncestors)]
+    pub fn common_ancestors(
+        &self,
+        doc_id: String,
+        a: String,
+        b: String,
+    ) -> Result<Vec<String>, JsValue> {
+        with_commits(self.id, &doc_id, &[&a, &b], |doc| {
+            common_ancestors(&doc.commits, &a, &b)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::meta::CommitMeta;
+
+    fn commit(hash: &str, parents: &[&str]) -> CommitRecord {
+        CommitRecord {
+            parents: parents.iter().map(ToString::to_string).collect(),
+            hash: hash.to_string(),
+            contents: Rc::from(Vec::new()),
+            meta: CommitMeta::default(),
+            extensions: crate::envelope::Extensions::new(),
+        }
+    }
+
+    /// `a` ← `b` ← `c`, and `b` ← `d` ← `e`, merged by `f` (with `c`) and `g` (with `d`).
+    fn history() -> Vec<CommitRecord> {
+        vec![
+            commit("a", &[]),
+            commit("b", &["a"]),
+            commit("c", &["b"]),
+            commit("d", &["b"]),
+            commit("e", &["d"]),
+            commit("f", &["c", "e"]),
+            commit("g", &["c", "d"]),
+        ]
+    }
+
+    #[test]
+    fn depths_follow_the_longest_path() {
+        let graph = CommitGraph::of(&history());
+        let depth = |hash: &str| {
+            graph
+                .nodes
+                .iter()
+                .find(|node| node.hash == hash)
+                .unwrap()
+                .depth
+        };
+        assert_eq!(depth("a"), 0);
+        assert_eq!(depth("c"), 2);
+        assert_eq!(depth("f"), 4);
+        assert_eq!(depth("g"), 3);
+        assert_eq!(graph.edges.len(), 8);
+        assert_eq!(graph.heads, ["f", "g"]);
+    }
+
+    #[test]
+    fn common_ancestors_are_the_newest_shared() {
+        let commits = history();
+        assert_eq!(common_ancestors(&commits, "f", "g"), ["c", "d"]);
+        assert_eq!(common_ancestors(&commits, "c", "e"), ["b"]);
+        assert_eq!(common_ancestors(&commits, "b", "e"), ["b"]);
+    }
+}
@@ -0,0 +1,74 @@
+//! Handing a document over from one handle to another.
+//!
+//! `exportDocState` packs a document into a CBOR-encoded [`DocState`]: its
+//! commits, plus the indexes built up as they were applied (statistics, heads
+//! version, and unsynced local work). `adoptDocState` on another handle,
+//! typically in another worker, stores the commits and takes the indexes as
+//! they are, so the document carries on where it left off instead of being
+//! rebuilt from its history.
+
+use std::collections::HashSet;
+
+use sedimentree_core::{
+    future::Local,
+    storage::{header::Codec, Storage},
+    SedimentreeId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{access::Membership, stats::DocStats, CommitInput, CommitRecord, DocumentCtx};
+
+/// The current handoff format version.
+const FORMAT_VERSION: u32 = 1;
+
+/// One document, as handed from one handle to another.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct DocState {
+    pub(crate) version: u32,
+    pub(crate) doc_id: String,
+    pub(crate) sed_id: SedimentreeId,
+    pub(crate) codec: Codec,
+    pub(crate) members: Membership,
+    /// In the order they were applied.
+    pub(crate) commits: Vec<CommitInput>,
+    pub(crate) heads_version: u64,
+    pub(crate) unsynced: HashSet<String>,
+    pub(crate) stats: DocStats,
+}
+
+impl DocState {
+    /// The state of `doc`, known to this handle as `doc_id`.
+    pub(crate) fn of(doc_id: String, doc: &DocumentCtx) -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            doc_id,
+            sed_id: doc.sed_id,
+            codec: Storage::<Local>::header(doc.subduction.storage()).codec(),
+            members: doc.members.clone(),
+            commits: doc.commits.iter().map(CommitRecord::to_input).collect(),
+            heads_version: doc.version,
+            unsynced: doc.unsynced.clone(),
+            stats: doc.stats.clone(),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::new();
+        ciborium::into_writer(self, &mut bytes)
+            .map_err(|err| format!("failed to encode document state: {err}"))?;
+        Ok(bytes)
+    }
+
+    /// Decode a state produced by [`DocState::encode`].
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, String> {
+        let state: Self =
+            ciborium::from_reader(bytes).map_err(|err| format!("invalid document state: {err}"))?;
+        if state.version != FORMAT_VERSION {
+            return Err(format!(
+                "unsupported document state version {} (expected {FORMAT_VERSION})",
+                state.version
+            ));
+        }
+        Ok(state)
+    }
+}
@@ -0,0 +1,204 @@
+//! The handshake each of the handle's connections opens with.
+//!
+//! Each side sends a [`Hello`], reads the other's, and then trades [`Proof`]s of
+//! the keys they named, before any message, as described in
+//! [`subduction_core::connection::handshake`]. A connection knows the other side
+//! by the key it proved, not by anything the app said about it. While it runs, a
+//! [`Greeting`] holds the transport's `onmessage` and `onclose`; the connection
+//! sets its own once the handshake is done, in the same task, so no frame falls
+//! between the two.
+//!
+//! The other end of a data channel may have sent its [`Hello`] before the app
+//! handed the channel over, when nothing was listening. So over a data channel a
+//! side sends its [`Hello`] again once it gets the other's, and frames carrying a
+//! [`Hello`] or a [`Proof`] have headers of their own, which the connection ignores
+//! later.
+
+use futures::{
+    channel::mpsc,
+    future::{self, Either},
+    StreamExt,
+};
+#[cfg(feature = "accept")]
+use js_sys::Function;
+use js_sys::{ArrayBuffer, Reflect, Uint8Array};
+use subduction_core::{
+    connection::handshake::{check_version, Handshake, Hello, Proof, PROTOCOL_VERSION},
+    peer::id::PeerId,
+};
+use wasm_bindgen::{prelude::*, JsCast};
+#[cfg(feature = "rtc")]
+use web_sys::RtcDataChannel;
+
+use crate::{
+    error::BeelayError,
+    socket::{self, DEFAULT_CALL_TIMEOUT},
+};
+
+/// The header of a data channel frame carrying a [`Hello`], after the ones in
+/// [`rtc`](crate::rtc).
+pub(crate) const HELLO: u8 = 2;
+
+/// The header of a data channel frame carrying a [`Proof`].
+pub(crate) const PROOF: u8 = 3;
+
+/// Sends a frame over a transport.
+pub(crate) type SendFrame = Box<dyn Fn(&[u8]) -> Result<(), JsValue>>;
+
+/// A transport whose handshake is under way.
+pub(crate) struct Greeting {
+    target: JsValue,
+    send: SendFrame,
+    /// Whether this is a data channel's, whose frames have headers.
+    framed: bool,
+    frames: mpsc::UnboundedReceiver<Vec<u8>>,
+    _on_message: Closure<dyn FnMut(JsValue)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+impl Greeting {
+    /// Take over the `onmessage` and `onclose` of `target`, which sends frames with `send`.
+    pub(crate) fn listen(target: &JsValue, send: SendFrame) -> Self {
+        Self::new(target, send, false)
+    }
+
+    /// Take over the `onmessage` and `onclose` of a data channel.
+    #[cfg(feature = "rtc")]
+    pub(crate) fn listen_channel(channel: &RtcDataChannel) -> Self {
+        let sender = channel.clone();
+        let send = Box::new(move |bytes: &[u8]| sender.send_with_u8_array(bytes));
+        Self::new(channel, send, true)
+    }
+
+    fn new(target: &JsValue, send: SendFrame, framed: bool) -> Self {
+        let (inbox, frames) = mpsc::unbounded();
+        let on_close = {
+            let inbox = inbox.clone();
+            Closure::<dyn FnMut()>::new(move || inbox.close_channel())
+        };
+        let on_message = Closure::<dyn FnMut(JsValue)>::new(move |event: JsValue| {
+            if let Some(frame) = frame_of(&event) {
+                let _ = inbox.unbounded_send(frame);
+            }
+        });
+        let _ = Reflect::set(target, &"onmessage".into(), on_message.as_ref());
+        let _ = Reflect::set(target, &"onclose".into(), on_close.as_ref());
+        Self {
+            target: target.clone(),
+            send,
+            framed,
+            frames,
+            _on_message: on_message,
+            _on_close: on_close,
+        }
+    }
+
+    /// Run the handshake as `ours`, returning the key the other side proved it holds.
+    ///
+    /// # Errors
+    ///
+    /// * [`BeelayError::ConnectionFailure`] if the other side speaks another version,
+    ///   doesn't prove the key it names, sends something else, closes, or doesn't
+    ///   answer in time.
+    pub(crate) async fn hello(&mut self, ours: &Handshake) -> Result<PeerId, BeelayError> {
+        self.send(HELLO, ours.hello())?;
+        let theirs = read_hello(&self.receive(HELLO).await?)?;
+        if self.framed {
+            self.send(HELLO, ours.hello())?;
+        }
+        self.send(PROOF, &ours.answer(&theirs).map_err(failure)?)?;
+        let (proof, _): (Proof, usize) = bincode::serde::decode_from_slice(
+            &self.receive(PROOF).await?,
+            bincode::config::standard(),
+        )
+        .map_err(|err| failure(format!("malformed handshake proof: {err}")))?;
+        ours.verify(&theirs, &proof).map_err(failure)
+    }
+
+    /// Send one handshake frame, under `header` over a data channel.
+    fn send<T: serde::Serialize>(&self, header: u8, frame: &T) -> Result<(), BeelayError> {
+        let mut bytes = Vec::new();
+        if self.framed {
+            bytes.push(header);
+        }
+        bincode::serde::encode_into_std_write(frame, &mut bytes, bincode::config::standard())
+            .map_err(failure)?;
+        (self.send)(&bytes).map_err(|err| {
+            BeelayError::ConnectionFailure(format!("could not send the handshake: {err:?}"))
+        })
+    }
+
+    /// Wait for the next handshake frame, which has `header` over a data channel.
+    async fn receive(&mut self, header: u8) -> Result<Vec<u8>, BeelayError> {
+        let timeout = Box::pin(socket::sleep(DEFAULT_CALL_TIMEOUT));
+        let next = async {
+            loop {
+                let frame = self.frames.next().await?;
+                if !self.framed {
+                    return Some(frame);
+                }
+                // Anything else is a repeated frame, or what's left of an earlier
+                // connection over the channel
+                if let Some((&found, frame)) = frame.split_first() {
+                    if found == header {
+                        return Some(frame.to_vec());
+                    }
+                }
+            }
+        };
+        match future::select(Box::pin(next), timeout).await {
+            Either::Left((Some(frame), _)) => Ok(frame),
+            Either::Left((None, _)) => Err(BeelayError::ConnectionFailure(
+                "connection closed during the handshake".into(),
+            )),
+            Either::Right(((), _)) => Err(BeelayError::ConnectionFailure(
+                "timed out waiting for the handshake".into(),
+            )),
+        }
+    }
+}
+
+/// Read the other side's [`Hello`], checking its version before the rest, which may
+/// be laid out differently in another version.
+fn read_hello(frame: &[u8]) -> Result<Hello, BeelayError> {
+    let malformed = |err| failure(format!("malformed handshake hello: {err}"));
+    let (version, _): (u32, usize) =
+        bincode::serde::decode_from_slice(frame, bincode::config::standard()).map_err(malformed)?;
+    check_version(PROTOCOL_VERSION, version).map_err(failure)?;
+    bincode::serde::decode_from_slice(frame, bincode::config::standard())
+        .map(|(hello, _)| hello)
+        .map_err(malformed)
+}
+
+/// A failed handshake.
+fn failure(err: impl ToString) -> BeelayError {
+    BeelayError::ConnectionFailure(err.to_string())
+}
+
+impl Drop for Greeting {
+    fn drop(&mut self) {
+        let _ = Reflect::set(&self.target, &"onmessage".into(), &JsValue::NULL);
+        let _ = Reflect::set(&self.target, &"onclose".into(), &JsValue::NULL);
+    }
+}
+
+/// The bytes of a frame, given to `onmessage` as an event whose `data` is the
+/// frame, or as the frame itself, in an `ArrayBuffer` or `Uint8Array`.
+pub(crate) fn frame_of(event: &JsValue) -> Option<Vec<u8>> {
+    let data = Reflect::get(event, &"data".into())
+        .ok()
+        .filter(|data| !data.is_undefined())
+        .unwrap_or_else(|| event.clone());
+    if let Some(buffer) = data.dyn_ref::<ArrayBuffer>() {
+        Some(Uint8Array::new(buffer).to_vec())
+    } else {
+        data.dyn_ref::<Uint8Array>().map(Uint8Array::to_vec)
+    }
+}
+
+/// A `send` for [`Greeting::listen`] calling `method` on `target`.
+#[cfg(feature = "accept")]
+pub(crate) fn sender(target: &JsValue, method: &Function) -> SendFrame {
+    let (target, method) = (target.clone(), method.clone());
+    Box::new(move |bytes: &[u8]| method.call1(&target, &Uint8Array::from(bytes)).map(|_| ()))
+}
@@ -0,0 +1,452 @@
+//! Persisting documents in the browser's IndexedDB.
+//!
+//! A handle loaded with `storage: "indexeddb"` keeps everything in one
+//! database, so its documents survive a page reload. Each document's commits,
+//! chunks, and blobs go through an [`IndexedDbStorage`], keyed by the
+//! document's sedimentree ID and then the item's digest. The saved handle and its
+//! changes feed are a record each in a store of their own.
+//!
+//! Handles loaded with a `namespace` share the database without seeing each
+//! other's data: their keys start with `ns/<namespace>/`, which no sedimentree
+//! ID in hex does.
+//!
+//! Documents created together are written through a [batched](Database::batched)
+//! database, which queues their records and then puts them all in a single
+//! transaction.
+
+use std::{cell::RefCell, rc::Rc};
+
+use futures::{future::LocalBoxFuture, FutureExt};
+use js_sys::Uint8Array;
+use sedimentree_core::{
+    future::Local,
+    storage::{
+        codec,
+        header::{Codec, StorageHeader},
+        Storage,
+    },
+    Blob, Chunk, Digest, LooseCommit, SedimentreeId,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    IdbDatabase, IdbFactory, IdbKeyRange, IdbObjectStore, IdbRequest, IdbTransaction,
+    IdbTransactionMode,
+};
+
+use crate::storage::{from_cbor, to_cbor, StorageError};
+
+/// The database used unless `Beelay.load` names another.
+pub(crate) const DEFAULT_DATABASE_NAME: &str = "subduction";
+
+/// Bump this, and migrate in [`Database::open`], whenever the stores change.
+const SCHEMA_VERSION: u32 = 1;
+
+const COMMITS: &str = "commits";
+const CHUNKS: &str = "chunks";
+const BLOBS: &str = "blobs";
+const HANDLE: &str = "handle";
+
+/// The handle store holds the saved handle per namespace under this key.
+const HANDLE_KEY: &str = "handle";
+
+/// The handle store holds the handle's changes feed per namespace under this key.
+const FEED_KEY: &str = "feed";
+
+/// Keys in a namespace start with this, then the namespace and a `/`.
+const NAMESPACE_PREFIX: &str = "ns/";
+
+/// A record waiting to be put: its store, key, and value.
+type Put = (&'static str, JsValue, JsValue);
+
+/// An open database.
+#[derive(Debug, Clone)]
+pub(crate) struct Database {
+    db: IdbDatabase,
+    /// The namespace the handle's keys are in, if any.
+    namespace: Option<Rc<str>>,
+    /// Records queued by a batched database, or `None` to put them straight away.
+    batch: Rc<RefCell<Option<Vec<Put>>>>,
+}
+
+impl Database {
+    /// The database's name, followed by `/<namespace>` if the handle has one.
+    pub(crate) fn name(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}/{namespace}", self.db.name()),
+            None => self.db.name(),
+        }
+    }
+
+    /// Open the database called `name`, creating it if need be, with keys in
+    /// `namespace` if there is one.
+    pub(crate) async fn open(name: &str, namespace: Option<&str>) -> Result<Self, StorageError> {
+        let factory = js_sys::Reflect::get(&js_sys::global(), &"indexedDB".into())?
+            .dyn_into::<IdbFactory>()
+            .map_err(|_| StorageError::new("IndexedDB is not available here"))?;
+        let request = factory.open_with_u32(name, SCHEMA_VERSION)?;
+
+        let upgrade = {
+            let request = request.clone();
+            Closure::<dyn FnMut()>::new(move || {
+                let Ok(db) = request.result().and_then(JsCast::dyn_into::<IdbDatabase>) else {
+                    return;
+                };
+                for store in [COMMITS, CHUNKS, BLOBS, HANDLE] {
+                    if !db.object_store_names().contains(store) {
+                        let _ = db.create_object_store(store);
+                    }
+                }
+            })
+        };
+        request.set_onupgradeneeded(Some(upgrade.as_ref().unchecked_ref()));
+        let opened = settle(&request).await;
+        request.set_onupgradeneeded(None);
+
+        Ok(Self {
+            db: opened?.dyn_into::<IdbDatabase>()?,
+            namespace: namespace.map(Rc::from),
+            batch: Rc::new(RefCell::new(None)),
+        })
+    }
+
+    /// The same database, but queueing the documents' records until
+    /// [`Database::commit`] puts them in one transaction.
+    ///
+    /// Records queued but not yet committed can't be read back.
+    pub(crate) fn batched(&self) -> Self {
+        Self {
+            db: self.db.clone(),
+            namespace: self.namespace.clone(),
+            batch: Rc::new(RefCell::new(Some(Vec::new()))),
+        }
+    }
+
+    /// Put every record a batched database queued in one transaction, waiting for it to
+    /// complete. Records are put straight away from then on.
+    pub(crate) async fn commit(&self) -> Result<(), StorageError> {
+        let Some(puts) = self.batch.borrow_mut().take() else {
+            return Ok(());
+        };
+        if puts.is_empty() {
+            return Ok(());
+        }
+
+        let names = js_sys::Array::of3(&COMMITS.into(), &CHUNKS.into(), &BLOBS.into());
+        let transaction = self
+            .db
+            .transaction_with_str_sequence_and_mode(&names, IdbTransactionMode::Readwrite)?;
+        for (store, key, value) in &puts {
+            transaction.object_store(store)?.put_with_key(value, key)?;
+        }
+        complete(&transaction).await
+    }
+
+    /// Queue a record if the database is batched, or put it and wait for it otherwise.
+    async fn put(
+        &self,
+        store: &'static str,
+        key: JsValue,
+        value: JsValue,
+    ) -> Result<(), StorageError> {
+        if let Some(puts) = self.batch.borrow_mut().as_mut() {
+            puts.push((store, key, value));
+            return Ok(());
+        }
+        let request = self
+            .store(store, IdbTransactionMode::Readwrite)?
+            .put_with_key(&value, &key)?;
+        settle(&request).await?;
+        Ok(())
+    }
+
+    /// The encoded handle last saved with [`Database::save_handle`], if any.
+    pub(crate) async fn load_handle(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        self.load_record(HANDLE_KEY).await
+    }
+
+    /// Replace the saved handle.
+    ///
+    /// The write is queued rather than awaited, so this can be called from
+    /// synchronous methods. Writes are applied in the order they're queued.
+    pub(crate) fn save_handle(&self, bytes: &[u8]) -> Result<(), StorageError> {
+        self.save_record(HANDLE_KEY, bytes)
+    }
+
+    /// The encoded changes feed last saved with [`Database::save_feed`], if any.
+    pub(crate) async fn load_feed(&self) -> Result<Option<Vec<u8>>, StorageError> {
+        self.load_record(FEED_KEY).await
+    }
+
+    /// Replace the saved changes feed, queued like [`Database::save_handle`].
+    pub(crate) fn save_feed(&self, bytes: &[u8]) -> Result<(), StorageError> {
+        self.save_record(FEED_KEY, bytes)
+    }
+
+    /// The record under `key` in the handle store, in the handle's namespace.
+    async fn load_record(&self, key: &str) -> Result<Option<Vec<u8>>, StorageError> {
+        let request = self
+            .store(HANDLE, IdbTransactionMode::Readonly)?
+            .get(&self.prefixed(key).into())?;
+        let value = settle(&request).await?;
+        if value.is_undefined() {
+            return Ok(None);
+        }
+        Ok(Some(Uint8Array::new(&value).to_vec()))
+    }
+
+    /// Queue replacing the record under `key` in the handle store.
+    fn save_record(&self, key: &str, bytes: &[u8]) -> Result<(), StorageError> {
+        self.store(HANDLE, IdbTransactionMode::Readwrite)?
+            .put_with_key(&Uint8Array::from(bytes), &self.prefixed(key).into())?;
+        Ok(())
+    }
+
+    /// Delete every document's commits, chunks, and blobs in the handle's namespace,
+    /// queued like [`Database::save_handle`].
+    pub(crate) fn clear_documents(&self) -> Result<(), StorageError> {
+        let range = match &self.namespace {
+            Some(namespace) => {
+                // `0` is the character after `/`
+                let prefix = format!("{NAMESPACE_PREFIX}{namespace}");
+                IdbKeyRange::bound(&format!("{prefix}/").into(), &format!("{prefix}0").into())?
+            }
+            // Keys outside any namespace start with a hex digit, and `g` comes after them all
+            None => IdbKeyRange::bound(&"0".into(), &"g".into())?,
+        };
+        for name in [COMMITS, CHUNKS, BLOBS] {
+            self.store(name, IdbTransactionMode::Readwrite)?
+                .delete(&range)?;
+        }
+        Ok(())
+    }
+
+    /// Delete one document's commits, chunks, and blobs, queued like
+    /// [`Database::save_handle`].
+    pub(crate) fn delete_document(&self, id: SedimentreeId) -> Result<(), StorageError> {
+        let range = document_range(&self.prefixed(&id.to_string()))?;
+        for name in [COMMITS, CHUNKS, BLOBS] {
+            self.store(name, IdbTransactionMode::Readwrite)?
+                .delete(&range)?;
+        }
+        Ok(())
+    }
+
+    /// The namespaces with a handle saved in the database.
+    pub(crate) async fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        let request = self
+            .store(HANDLE, IdbTransactionMode::Readonly)?
+            .get_all_keys()?;
+        let keys = settle(&request).await?.dyn_into::<js_sys::Array>()?;
+        Ok(keys
+            .iter()
+            .filter_map(|key| key.as_string())
+            .filter_map(|key| {
+                key.strip_prefix(NAMESPACE_PREFIX)?
+                    .strip_suffix(HANDLE_KEY)?
+                    .strip_suffix('/')
+                    .map(str::to_string)
+            })
+            .collect())
+    }
+
+    /// `key`, in the handle's namespace.
+    fn prefixed(&self, key: &str) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{NAMESPACE_PREFIX}{namespace}/{key}"),
+            None => key.to_string(),
+        }
+    }
+
+    fn store(&self, name: &str, mode: IdbTransactionMode) -> Result<IdbObjectStore, StorageError> {
+        Ok(self
+            .db
+            .transaction_with_str_and_mode(name, mode)?
+            .object_store(name)?)
+    }
+}
+
+/// One document's commits, chunks, and blobs in a [`Database`].
+#[derive(Debug, Clone)]
+pub(crate) struct IndexedDbStorage {
+    db: Database,
+    /// The document's sedimentree ID in hex, in the database's namespace.
+    prefix: String,
+    header: StorageHeader,
+}
+
+impl IndexedDbStorage {
+    /// Storage for sedimentree `id`, encoding blobs with `codec`.
+    pub(crate) fn new(db: Database, id: SedimentreeId, codec: Codec) -> Self {
+        Self {
+            prefix: db.prefixed(&id.to_string()),
+            db,
+            header: StorageHeader::default().with_codec(codec),
+        }
+    }
+
+    fn key(&self, digest: Digest) -> JsValue {
+        format!("{}:{digest}", self.prefix).into()
+    }
+
+    async fn put(
+        &self,
+        store: &'static str,
+        digest: Digest,
+        value: JsValue,
+    ) -> Result<(), StorageError> {
+        self.db.put(store, self.key(digest), value).await
+    }
+
+    /// Every record in `store` that belongs to this document.
+    async fn get_all<T: DeserializeOwned>(&self, store: &str) -> Result<Vec<T>, StorageError> {
+        let range = document_range(&self.prefix)?;
+        let request = self
+            .db
+            .store(store, IdbTransactionMode::Readonly)?
+            .get_all_with_key(&range)?;
+        let values = settle(&request).await?.dyn_into::<js_sys::Array>()?;
+        values.iter().map(|value| decode(&value)).collect()
+    }
+}
+
+impl Storage<Local> for IndexedDbStorage {
+    type Error = StorageError;
+
+    fn header(&self) -> StorageHeader {
+        self.header.clone()
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        self.get_all(COMMITS).boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            let digest = loose_commit.blob().digest();
+            self.put(COMMITS, digest, encode(&loose_commit)?).await
+        }
+        .boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            let digest = chunk.summary().blob_meta().digest();
+            self.put(CHUNKS, digest, encode(&chunk)?).await
+        }
+        .boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        self.get_all(CHUNKS).boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        async move {
+            let digest = Digest::hash(blob.contents());
+            let stored = codec::encode_blob(self.header.codec(), &blob);
+            self.put(BLOBS, digest, Uint8Array::from(stored.as_slice()).into())
+                .await?;
+            Ok(digest)
+        }
+        .boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        async move {
+            let request = self
+                .db
+                .store(BLOBS, IdbTransactionMode::Readonly)?
+                .get(&self.key(blob_digest))?;
+            let value = settle(&request).await?;
+            if value.is_undefined() {
+                return Ok(None);
+            }
+            codec::decode_blob(&Uint8Array::new(&value).to_vec())
+                .map(Some)
+                .map_err(|err| {
+                    StorageError::new(format!("failed to decode blob {blob_digest}: {err}"))
+                })
+        }
+        .boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        async move {
+            for digest in blob_digests {
+                for name in [COMMITS, CHUNKS, BLOBS] {
+                    let request = self
+                        .db
+                        .store(name, IdbTransactionMode::Readwrite)?
+                        .delete(&self.key(digest))?;
+                    settle(&request).await?;
+                }
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+}
+
+/// The range of keys holding the document whose sedimentree ID is `prefix`, in hex
+/// and in its namespace.
+fn document_range(prefix: &str) -> Result<IdbKeyRange, StorageError> {
+    // Keys are `<prefix>:<digest>`, and `;` is the character after `:`.
+    Ok(IdbKeyRange::bound(
+        &format!("{prefix}:").into(),
+        &format!("{prefix};").into(),
+    )?)
+}
+
+/// Wait for a request to succeed, and return its result.
+async fn settle(request: &IdbRequest) -> Result<JsValue, StorageError> {
+    let done = js_sys::Promise::new(&mut |resolve, reject| {
+        request.set_onsuccess(Some(&resolve));
+        request.set_onerror(Some(&reject));
+    });
+    if JsFuture::from(done).await.is_err() {
+        return Err(StorageError::new(
+            request
+                .error()
+                .ok()
+                .flatten()
+                .map_or_else(|| "request failed".to_string(), |err| err.message()),
+        ));
+    }
+    Ok(request.result()?)
+}
+
+/// Wait for a transaction to complete.
+async fn complete(transaction: &IdbTransaction) -> Result<(), StorageError> {
+    let done = js_sys::Promise::new(&mut |resolve, reject| {
+        transaction.set_oncomplete(Some(&resolve));
+        transaction.set_onerror(Some(&reject));
+        transaction.set_onabort(Some(&reject));
+    });
+    if JsFuture::from(done).await.is_err() {
+        return Err(StorageError::new(
+            transaction
+                .error()
+                .map_or_else(|| "transaction failed".to_string(), |err| err.message()),
+        ));
+    }
+    Ok(())
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<JsValue, StorageError> {
+    Ok(Uint8Array::from(to_cbor(value)?.as_slice()).into())
+}
+
+fn decode<T: DeserializeOwned>(value: &JsValue) -> Result<T, StorageError> {
+    from_cbor(&Uint8Array::new(value).to_vec())
+}
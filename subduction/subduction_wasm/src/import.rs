@@ -0,0 +1,203 @@
+//! Incremental import of commit history from a JS `ReadableStream`.
+//!
+//! Commits are decoded as bytes arrive, so large histories never need to be
+//! materialised as one giant array on either side of the boundary.
+
+use js_sys::Uint8Array;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::{apply_commits, conflict::CommitOrigin, error::BeelayError, Beelay, CommitInput};
+
+/// How commits are framed in an import stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) enum Format {
+    /// One JSON-encoded commit per line.
+    #[default]
+    Ndjson,
+
+    /// A CBOR sequence (RFC 8742) of commits.
+    Cbor,
+}
+
+/// Splits a byte stream into commits, buffering any partial frame.
+#[derive(Debug)]
+pub(crate) struct FrameDecoder {
+    format: Format,
+    buffer: Vec<u8>,
+    line: usize,
+}
+
+impl FrameDecoder {
+    pub(crate) const fn new(format: Format) -> Self {
+        Self {
+            format,
+            buffer: Vec::new(),
+            line: 0,
+        }
+    }
+
+    /// Feed the next bytes from the stream, returning every commit they complete.
+    pub(crate) fn push(&mut self, bytes: &[u8]) -> Result<Vec<CommitInput>, String> {
+        self.buffer.extend_from_slice(bytes);
+        match self.format {
+            Format::Ndjson => self.drain_lines(),
+            Format::Cbor => self.drain_cbor(),
+        }
+    }
+
+    /// Signal the end of the stream, returning any final commit.
+    pub(crate) fn finish(mut self) -> Result<Vec<CommitInput>, String> {
+        match self.format {
+            Format::Ndjson => {
+                self.buffer.push(b'\n');
+                self.drain_lines()
+            }
+            Format::Cbor if self.buffer.is_empty() => Ok(Vec::new()),
+            Format::Cbor => Err("stream ended in the middle of a CBOR commit".to_string()),
+        }
+    }
+
+    fn drain_lines(&mut self) -> Result<Vec<CommitInput>, String> {
+        let mut commits = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line = self.buffer.drain(..=end).collect::<Vec<_>>();
+            self.line += 1;
+            if line.trim_ascii().is_empty() {
+                continue;
+            }
+
+            let commit = serde_json::from_slice(&line)
+                .map_err(|err| format!("invalid commit on line {}: {err}", self.line))?;
+            commits.push(commit);
+        }
+        Ok(commits)
+    }
+
+    fn drain_cbor(&mut self) -> Result<Vec<CommitInput>, String> {
+        let mut commits = Vec::new();
+        while !self.buffer.is_empty() {
+            let mut rest = self.buffer.as_slice();
+            match ciborium::from_reader::<CommitInput, _>(&mut rest) {
+                Ok(commit) => {
+                    let consumed = self.buffer.len() - rest.len();
+                    self.buffer.drain(..consumed);
+                    commits.push(commit);
+                }
+                Err(ciborium::de::Error::Io(err))
+                    if err.kind() == std::io::ErrorKind::UnexpectedEof =>
+                {
+                    break;
+                }
+                Err(err) => return Err(format!("invalid CBOR commit: {err}")),
+            }
+        }
+        Ok(commits)
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportOptions {
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ImportProgress {
+    commits_read: usize,
+    commits_applied: usize,
+    bytes_read: usize,
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Import commits into a document from a `ReadableStream`, applying them as they arrive.
+    ///
+    /// The stream may yield `Uint8Array`s or strings. `options.format` selects the framing:
+    /// `"ndjson"` (the default, one commit object per line) or `"cbor"` (a CBOR sequence).
+    /// If `options.onProgress` is a function it is called after each chunk with
+    /// `{ commitsRead, commitsApplied, bytesRead }`, which is also the final result.
+    #[wasm_bindgen(js_name = importHistoryStream)]
+    pub async fn import_history_stream(
+        &self,
+        doc_id: String,
+        stream: JsValue,
+        options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let (import_options, on_progress) = if options.is_undefined() || options.is_null() {
+            (ImportOptions::default(), None)
+        } else {
+            let on_progress = js_sys::Reflect::get(&options, &"onProgress".into())?
+                .dyn_into::<js_sys::Function>()
+                .ok();
+            let import_options =
+                serde_wasm_bindgen::from_value(options).map_err(BeelayError::invalid_argument)?;
+            (import_options, on_progress)
+        };
+
+        let reader = call_method(&stream, "getReader")?;
+        let mut decoder = FrameDecoder::new(import_options.format);
+        let mut progress = ImportProgress {
+            commits_read: 0,
+            commits_applied: 0,
+            bytes_read: 0,
+        };
+
+        let result = async {
+            loop {
+                let next = call_method(&reader, "read")?;
+                let next =
+                    wasm_bindgen_futures::JsFuture::from(js_sys::Promise::from(next)).await?;
+
+                let done = js_sys::Reflect::get(&next, &"done".into())?.as_bool() == Some(true);
+                let commits = if done {
+                    std::mem::replace(&mut decoder, FrameDecoder::new(import_options.format))
+                        .finish()
+                } else {
+                    let value = js_sys::Reflect::get(&next, &"value".into())?;
+                    let bytes = match value.as_string() {
+                        Some(text) => text.into_bytes(),
+                        None => Uint8Array::new(&value).to_vec(),
+                    };
+                    progress.bytes_read += bytes.len();
+                    decoder.push(&bytes)
+                }
+                .map_err(BeelayError::InvalidSnapshot)?;
+
+                progress.commits_read += commits.len();
+                let (applied, _heads_version) =
+                    apply_commits(self.id, &doc_id, &commits, CommitOrigin::Import, None).await?;
+                progress.commits_applied += applied;
+
+                if let Some(on_progress) = &on_progress {
+                    let event = serde_wasm_bindgen::to_value(&progress).map_err(JsValue::from)?;
+                    on_progress.call1(&JsValue::NULL, &event)?;
+                }
+
+                if done {
+                    return Ok::<_, JsValue>(());
+                }
+            }
+        }
+        .await;
+
+        if result.is_err() {
+            // Stop the producer; the error we report is the one that made us give up.
+            call_method(&reader, "cancel").ok();
+        }
+        call_method(&reader, "releaseLock").ok();
+        result?;
+
+        serde_wasm_bindgen::to_value(&progress).map_err(JsValue::from)
+    }
+}
+
+/// Call a zero-argument method on a JS object.
+fn call_method(target: &JsValue, name: &str) -> Result<JsValue, JsValue> {
+    js_sys::Reflect::get(target, &name.into())?
+        .dyn_into::<js_sys::Function>()?
+        .call0(target)
+}
@@ -1,48 +1,278 @@
 //! WebAssembly bindings exposing the Subduction synchronization engine.
+//!
+//! Everything a minimal app needs is always built. Optional parts of the API are
+//! behind features, all on by default, so that an app can leave out what it
+//! doesn't use and have it stripped from its bundle:
+//!
+//! * `rtc`: `Beelay.connectPeer`, syncing directly with other browsers.
+//! * `accept`: `Beelay.acceptConnection`, serving sync clients from a JS host such
+//!   as Node.
+//! * `backup`: `exportEncryptedBackup` and `restoreEncryptedBackup`, with their
+//!   encryption dependencies.
+//! * `encryption`: documents created with `encrypted: true`, end-to-end encrypted
+//!   with Keyhive, and contact cards (`createContactCard` and `parseContactCard`),
+//!   with `addMember`, which takes one.
+//! * `import`: `importHistoryStream`.
+//! * `compat`: helpers for apps ported from the original Beelay API, such as
+//!   `MemorySigner` and `MemoryStorageAdapter`.
+//!
+//! Methods throw `BeelayError`s carrying a stable `code`, listed in the `error` module.
 
 use std::{
     cell::RefCell,
     collections::{HashMap, HashSet},
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, SystemTime},
 };
 
-use futures::{future::LocalBoxFuture, FutureExt};
-use js_sys::{Math, Uint8Array};
+use ed25519_dalek::SigningKey;
+use js_sys::Uint8Array;
 use sedimentree_core::{
     future::Local,
-    storage::MemoryStorage,
+    storage::{
+        header::{Codec, MAGIC},
+        Storage,
+    },
     Blob, Digest, LooseCommit, Sedimentree, SedimentreeId,
 };
 use serde::{Deserialize, Serialize};
-use subduction_core::{connection::Connection, peer::id::PeerId, Subduction};
+use subduction_core::{
+    connection::handshake::Handshake,
+    lifecycle::{LifecycleAction, LifecycleRules},
+    peer::id::PeerId,
+    sync::summary_cache::SummaryCache,
+    Subduction,
+};
 use wasm_bindgen::prelude::*;
 
+#[cfg(feature = "accept")]
+mod accept;
+mod access;
+mod adapter;
+mod backup;
+mod bundle;
+mod cache;
+mod checkout;
+#[cfg(feature = "compat")]
+mod compat;
+mod conflict;
+mod connection;
+#[cfg(feature = "encryption")]
+mod contact;
+mod cursor;
+mod diagnostics;
+mod encryption;
+mod envelope;
+mod error;
+mod export;
+mod facade;
+mod fanout;
+mod freeze;
+mod gc;
+mod graph;
+mod feed;
+mod handoff;
+mod handshake;
+mod idb;
+#[cfg(feature = "import")]
+mod import;
+mod members;
+mod merge;
+mod meta;
+mod notify;
+mod order;
+mod ownership;
+mod peers;
+#[cfg(feature = "perf-trace")]
+mod perf;
+mod privacy;
+mod quota;
+mod random;
+mod reconnect;
+#[cfg(feature = "rtc")]
+mod rtc;
+mod signature;
+mod socket;
+mod stats;
+mod storage;
+mod strict;
+mod sync_status;
+mod tabs;
+mod tasks;
+mod typescript;
+mod view;
+
+use access::{Access, AccessRequest, DocumentFilter, Group, Membership};
+use backup::{DocumentSnapshot, Snapshot};
+use bundle::Bundle;
+use cache::LruCache;
+use checkout::Checkouts;
+use conflict::{CommitOrigin, Conflict};
+use connection::PeerConnection;
+use cursor::{Cursor, CursorKind, PageOptions};
+use diagnostics::ResourceKind;
+use encryption::{Encryption, Keys};
+use envelope::Extensions;
+use error::BeelayError;
+use feed::ChangeFeed;
+use freeze::FreezeProof;
+use handoff::DocState;
+use merge::MergePolicy;
+use meta::CommitMeta;
+use notify::{PendingDelivery, SubscribeOptions, Subscription};
+use order::Order;
+use ownership::Owners;
+use privacy::PrivacyOptions;
+use quota::Limits;
+use random::RandomSource;
+use socket::SyncSocket;
+use stats::DocStats;
+use storage::{Backend, DocStorage};
+use sync_status::{PeerAck, RoundReports};
+use tabs::TabChannel;
+use tasks::TaskKind;
+
+pub use view::BlobView;
 
 thread_local! {
     static HANDLES: RefCell<HashMap<u32, HandleCtx>> = RefCell::new(HashMap::new());
-    static NEXT_ID: RefCell<u32> = RefCell::new(1);
+    static NEXT_ID: RefCell<u32> = const { RefCell::new(1) };
 }
 
 #[wasm_bindgen]
 pub struct Beelay {
     id: u32,
+    /// Which of the handle's facades this is (see [`facade`]), or [`facade::ROOT`].
+    facade: u32,
 }
 
 struct HandleCtx {
+    signing_key: SigningKey,
+    random: RandomSource,
     documents: HashMap<String, DocumentCtx>,
+    groups: HashMap<String, Group>,
+    subscriptions: HashMap<u32, Subscription>,
+    conflict_listeners: HashMap<u32, js_sys::Function>,
+    /// `watchAll` callbacks, called whenever any document's heads change.
+    heads_watchers: HashMap<u32, js_sys::Function>,
+    /// `watchSyncReports` callbacks, called whenever a batch sync round finishes.
+    report_watchers: HashMap<u32, js_sys::Function>,
+    next_subscription_id: u32,
+    /// The facade each subscription was made through, by subscription ID.
+    subscription_owners: HashMap<u32, u32>,
+    next_facade_id: u32,
+    /// The `Beelay` objects keeping the handle running (see [`ownership`]).
+    owners: Owners,
+    load_cache: LruCache<(String, u64, Order), JsValue>,
+    /// The connection to the sync server, if the handle was loaded with `syncServerUrl`
+    /// or connected with `connect`.
+    sync: Option<Rc<SyncSocket>>,
+    /// Whether `connect` is opening a connection to a sync server.
+    connecting: bool,
+    /// The clients served over ports accepted with `acceptConnection`, by peer ID.
+    #[cfg(feature = "accept")]
+    accepted: HashMap<PeerId, Rc<accept::AcceptedPort>>,
+    changes: ChangeFeed,
+    /// Where documents are persisted, if the handle was loaded with persistent `storage`.
+    backend: Option<Backend>,
+    /// How documents reconcile their heads after a sync, by document ID.
+    merge_policies: HashMap<String, MergePolicy>,
+    /// The handles in other tabs sharing this one's IndexedDB database.
+    tabs: Option<TabChannel>,
+    /// Sedimentree summaries, shared by every document's engine.
+    summaries: Arc<SummaryCache>,
+    /// Whether to throw on API misuse, rather than tolerate it.
+    strict: bool,
+    /// Whether to reject commits whose hash doesn't match their contents.
+    strict_hashes: bool,
+    /// Whether to sign the commits the handle makes (see [`signature`]).
+    sign_commits: bool,
+    /// How much the handle may hold (see [`quota`]).
+    limits: Limits,
+    /// The handle's Keyhive agent, once it has encrypted documents (see [`encryption`]).
+    keys: Option<Keys>,
+    /// Access requests received for documents, awaiting a decision.
+    access_requests: access::Inbox,
+    /// Documents taken out while commits are applied to them.
+    checked_out: Checkouts,
 }
 
 struct DocumentCtx {
     sed_id: SedimentreeId,
-    subduction: Subduction<Local, MemoryStorage, NullConnection>,
+    subduction: Subduction<Local, DocStorage, PeerConnection>,
+    /// Whether the engine's run loop has been started, by its first connection.
+    listening: bool,
     commits: Vec<CommitRecord>,
     seen: HashSet<String>,
+    members: Membership,
+    /// Bumped whenever a commit is applied, so derived data can be cached per version.
+    version: u64,
+    /// Hashes of local commits that no synced commit builds on yet.
+    unsynced: HashSet<String>,
+    stats: DocStats,
+    /// Runs of commits stored as one chunk, made by `compact` or received from peers.
+    bundles: Vec<Bundle>,
+    /// How long the document's commits live, if it's ephemeral, as last saved.
+    ttl: Option<Duration>,
+    /// What each peer the document has synced with has acknowledged of it.
+    peers: HashMap<PeerId, PeerAck>,
+    /// The proof the document was frozen with, if it's frozen.
+    frozen: Option<FreezeProof>,
+    /// How the document's commits are encrypted, if they are.
+    encryption: Option<Encryption>,
 }
 
 #[derive(Clone, Debug)]
 struct CommitRecord {
     parents: Vec<String>,
     hash: String,
-    contents: Vec<u8>,
+    /// Shared so that blob views can outlive the record without a copy.
+    contents: Rc<[u8]>,
+    meta: CommitMeta,
+    extensions: Extensions,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadConfig {
+    /// How many `loadDocument` results to keep cached.
+    #[serde(default)]
+    load_cache_size: Option<usize>,
+    /// A Subduction WebSocket server to sync every document with, reconnecting
+    /// whenever it closes the socket.
+    #[serde(default)]
+    sync_server_url: Option<String>,
+    /// How to keep what the sync server learns to a minimum (see [`privacy`]).
+    #[serde(default)]
+    sync_privacy: PrivacyOptions,
+    /// Where to keep documents: `"memory"`, `"indexeddb"`, or a storage adapter.
+    #[serde(default, with = "serde_wasm_bindgen::preserve")]
+    storage: JsValue,
+    /// The IndexedDB database to use, if not the default.
+    #[serde(default)]
+    database_name: Option<String>,
+    /// The namespace to keep this handle's data in, within shared storage.
+    #[serde(default)]
+    namespace: Option<String>,
+    /// Throw on API misuse (see [`strict`]) instead of tolerating it.
+    #[serde(default)]
+    strict: bool,
+    /// Whether to reject commits whose hash doesn't match their contents (the default).
+    #[serde(default)]
+    strict_hashes: Option<bool>,
+    /// Sign every commit the handle makes (see [`signature`]).
+    #[serde(default)]
+    sign_commits: bool,
+    /// A `MemorySigner` to be the handle's identity in place of its own key.
+    #[serde(default, with = "serde_wasm_bindgen::preserve")]
+    signer: JsValue,
+    /// The most a commit, a document, and the handle may hold (see [`quota`]).
+    #[serde(default)]
+    limits: Limits,
+    /// A handle saved with `save`, to restore instead of what's in storage.
+    #[serde(default, with = "serde_wasm_bindgen::preserve")]
+    snapshot: JsValue,
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,14 +282,33 @@ struct CreateDocArgs {
     initial_commit: CommitInput,
     #[serde(default)]
     _other_parents: Vec<serde_json::Value>,
+    /// How to encode the document's commit contents at rest: `"none"`, `"deflate"`,
+    /// or `"zstd"`.
+    #[serde(default)]
+    codec: Option<String>,
+    /// Whether to encrypt the document's commits end to end (see [`encryption`]).
+    #[serde(default)]
+    encrypted: bool,
 }
 
-#[derive(Debug, Deserialize, Clone)]
+/// A commit as the JS API takes it (see [`envelope`]).
+#[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
 struct CommitInput {
+    /// The version of the commit's shape.
+    #[serde(default = "envelope::unversioned")]
+    v: u32,
     parents: Vec<String>,
     hash: String,
+    /// A `Uint8Array`, or an array of numbers.
+    #[serde(deserialize_with = "envelope::contents::deserialize")]
     contents: Vec<u8>,
+    /// Who made the commit, when, and its tags (see [`meta`]).
+    #[serde(flatten)]
+    meta: CommitMeta,
+    /// Fields this version doesn't know, kept so that they aren't lost.
+    #[serde(flatten)]
+    extensions: Extensions,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,18 +317,107 @@ struct AddCommitArgs {
     #[serde(rename = "docId")]
     doc_id: String,
     commits: Vec<CommitInput>,
+    /// Only apply the commits if the document is still at this heads version.
+    #[serde(default)]
+    if_heads_version: Option<u64>,
+    /// Whether the commits are local edits or were received from another peer.
+    #[serde(default)]
+    origin: CommitOrigin,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AddCommitsResult {
+    heads_version: u64,
 }
 
+/// A commit as the JS API returns it (see [`envelope`]). Convert it with
+/// [`envelope::to_js`].
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct CommitOutput {
     #[serde(rename = "type")]
     kind: &'static str,
+    v: u32,
     parents: Vec<String>,
     hash: String,
-    contents: Vec<u8>,
+    /// A `Uint8Array`.
+    #[serde(serialize_with = "envelope::contents::serialize")]
+    contents: Rc<[u8]>,
+    #[serde(flatten)]
+    meta: CommitMeta,
+    #[serde(flatten)]
+    extensions: Extensions,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct StorageInfo {
+    magic: String,
+    format_version: u16,
+    digest_algorithm: String,
+    codec: String,
+    created_by: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadDocumentOptions {
+    #[serde(default)]
+    order: Order,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LoadPageOptions {
+    #[serde(default)]
+    order: Order,
+    /// Where the previous page left off.
+    #[serde(default)]
+    cursor: Option<String>,
+    /// The most commits to return. Unlimited if unset.
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentListing {
+    doc_id: String,
+    /// This handle's access, or `None` if it has lost access to the document.
+    access: Option<Access>,
+    created_by: String,
+    commit_count: usize,
+    heads: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentsPage {
+    documents: Vec<DocumentListing>,
+    /// Where the next page starts, or `None` after the last one.
+    next_cursor: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryPage {
+    commits: Vec<CommitOutput>,
+    /// Where the next page starts, or `None` after the last one.
+    next_cursor: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct GroupInfo {
+    group_id: String,
+    name: String,
+    members: Vec<String>,
+}
+
+/// How long `waitUntilSynced` waits between batch syncs with a peer, in milliseconds.
+const SYNC_RETRY_MS: f64 = 1_000.0;
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct WaitResult {
@@ -89,325 +427,2808 @@ struct WaitResult {
 #[wasm_bindgen]
 impl Beelay {
     /// Mimics the original `Beelay.load` entrypoint and returns a handle to the runtime.
+    ///
+    /// With `storage: "indexeddb"`, documents are persisted to the IndexedDB database
+    /// `databaseName` (`"subduction"` by default). `storage` may also be an object with
+    /// `load`, `save`, `remove`, and `listOneLevel` methods, to persist them anywhere
+    /// else. Either way, the next load from the same storage reopens them under the
+    /// same identity.
+    ///
+    /// Handles or apps sharing one database or adapter should each pass a `namespace`,
+    /// such as the app's name or a handle's peer ID, so their keys don't collide.
+    /// Each namespace holds its own identity and documents, and `listNamespaces`
+    /// lists them. Without one, a handle uses the storage's unnamed area.
+    ///
+    /// Pass `strict: true` to throw a `MisuseError` on common mistakes, such as reusing
+    /// a commit digest for different contents, that are otherwise tolerated.
+    ///
+    /// Commits whose hash doesn't match their contents (see `commitHash`) are rejected,
+    /// since peers would never agree on them. Pass `strictHashes: false` to accept any
+    /// hash, such as for data written before hashes were checked.
+    ///
+    /// Pass `signCommits: true` to sign every commit the handle makes with its key.
+    /// Signed commits from any peer are checked before they're applied, and rejected
+    /// with `InvalidSignature` if their author didn't sign them. Pass a `MemorySigner`
+    /// as `signer` for the handle to take its key as its own, peer ID included, in
+    /// place of a generated or saved one.
+    ///
+    /// Pass `limits: { maxCommitBytes, maxDocumentBytes, maxDocuments }` to refuse
+    /// commits and documents beyond them with a `QuotaExceeded` error; `getUsage`
+    /// reports how close the handle is.
+    ///
+    /// With a `syncServerUrl`, `syncPrivacy` takes the same options as `connect`'s
+    /// `privacy`.
+    ///
+    /// Pass a `snapshot` returned by `save` to restore that handle, identity included,
+    /// such as where there's no IndexedDB and the embedder persists it instead. It
+    /// replaces anything the storage held.
     #[wasm_bindgen(js_name = load)]
-    pub async fn load(_config: JsValue) -> Result<Beelay, JsValue> {
+    pub async fn load(
+        #[wasm_bindgen(unchecked_param_type = "LoadConfig | undefined")] config: JsValue,
+    ) -> Result<Beelay, JsValue> {
+        #[cfg(feature = "perf-trace")]
+        perf::install_clock();
+        let random = RandomSource::from_config(&config)?;
+        let config: LoadConfig = if config.is_undefined() || config.is_null() {
+            LoadConfig::default()
+        } else {
+            serde_wasm_bindgen::from_value(config).map_err(BeelayError::invalid_argument)?
+        };
+        let backend = Backend::open(
+            config.storage.clone(),
+            config.database_name.as_deref(),
+            config.namespace.as_deref(),
+        )
+        .await?;
+        let restored = if config.snapshot.is_undefined() || config.snapshot.is_null() {
+            None
+        } else {
+            let bytes = config
+                .snapshot
+                .dyn_ref::<Uint8Array>()
+                .ok_or_else(|| BeelayError::invalid_argument("snapshot must be a Uint8Array"))?
+                .to_vec();
+            Some(Snapshot::decode(&bytes).map_err(BeelayError::InvalidSnapshot)?)
+        };
+        let saved = match &backend {
+            Some(backend) if restored.is_none() => backend.load_handle().await?,
+            _ => None,
+        };
+        let signing_key = if !config.signer.is_undefined() && !config.signer.is_null() {
+            signature::signer_key(&config.signer)?
+        } else if let Some(saved) = restored.as_ref().or(saved.as_ref()) {
+            SigningKey::from_bytes(&saved.signing_key)
+        } else {
+            SigningKey::from_bytes(&random.bytes()?)
+        };
         let id = NEXT_ID.with(|counter| {
             let mut c = counter.borrow_mut();
             let id = *c;
             *c += 1;
             id
         });
+        let sync = match &config.sync_server_url {
+            Some(url) => {
+                let handshake = Handshake::new(&signing_key, random.bytes()?);
+                Some(SyncSocket::connect(url, id, &handshake, &config.sync_privacy).await?)
+            }
+            None => None,
+        };
+        let tabs = backend
+            .as_ref()
+            .and_then(Backend::database_name)
+            .map(|name| TabChannel::open(&name, id))
+            .transpose()?;
+        let changes = match &backend {
+            Some(backend) => backend.load_feed().await?,
+            None => None,
+        };
+        let changes = match changes {
+            Some(changes) => changes,
+            None => ChangeFeed::new(random.hex_string(16)?),
+        };
+        let reconnecting = sync.clone();
 
         HANDLES.with(|handles| {
             handles.borrow_mut().insert(
                 id,
                 HandleCtx {
+                    signing_key,
+                    random,
                     documents: HashMap::new(),
+                    groups: HashMap::new(),
+                    subscriptions: HashMap::new(),
+                    conflict_listeners: HashMap::new(),
+                    heads_watchers: HashMap::new(),
+                    report_watchers: HashMap::new(),
+                    next_subscription_id: 1,
+                    subscription_owners: HashMap::new(),
+                    next_facade_id: facade::ROOT + 1,
+                    owners: Owners::new(),
+                    load_cache: LruCache::new(
+                        config
+                            .load_cache_size
+                            .unwrap_or(cache::DEFAULT_LOAD_CACHE_SIZE),
+                    ),
+                    sync,
+                    connecting: false,
+                    #[cfg(feature = "accept")]
+                    accepted: HashMap::new(),
+                    changes,
+                    backend,
+                    merge_policies: HashMap::new(),
+                    tabs,
+                    summaries: Arc::new(SummaryCache::new()),
+                    strict: config.strict,
+                    strict_hashes: config.strict_hashes.unwrap_or(true),
+                    sign_commits: config.sign_commits,
+                    limits: config.limits,
+                    keys: None,
+                    access_requests: access::Inbox::default(),
+                    checked_out: Checkouts::default(),
                 },
             );
         });
+        diagnostics::track(ResourceKind::Handle, id, "");
+        diagnostics::track_object(id, facade::ROOT);
+        schedule_expiry(id);
+        if let (Some(url), Some(sync)) = (config.sync_server_url, &reconnecting) {
+            reconnect::reconnect_on_close(id, url, config.sync_privacy, sync);
+        }
 
-        Ok(Beelay { id })
+        match (restored, saved) {
+            (Some(snapshot), _) => drop(restore_snapshot(id, snapshot).await?),
+            (None, Some(saved)) => reopen_documents(id, saved).await?,
+            (None, None) => HANDLES
+                .with(|handles| handles.borrow().get(&id).map_or(Ok(()), HandleCtx::persist))?,
+        }
+        Ok(Beelay {
+            id,
+            facade: facade::ROOT,
+        })
     }
 
     /// Create a new document with the provided initial commit.
-    #[wasm_bindgen(js_name = createDoc)]
-    pub async fn create_doc(&self, args: JsValue) -> Result<JsValue, JsValue> {
-        let args: CreateDocArgs = serde_wasm_bindgen::from_value(args)
-            .map_err(JsValue::from)?;
-    let doc_id = random_doc_id();
-    let sed_id = SedimentreeId::new(random_bytes_array());
+    ///
+    /// Pass `codec: "zstd" | "deflate" | "none"` to compress the document's commits at rest.
+    ///
+    /// Pass `encrypted: true` to encrypt the document's commits with the handle's
+    /// Keyhive keys before they're stored or synced, so that storage, the sync server,
+    /// and other peers only ever see ciphertext. Only this handle, or one restored
+    /// from it, can read the document. Encrypted documents can't be compacted.
+    #[wasm_bindgen(js_name = createDoc, unchecked_return_type = "DocumentId")]
+    pub async fn create_doc(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "CreateDocArgs")] args: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let args: CreateDocArgs =
+            serde_wasm_bindgen::from_value(args).map_err(BeelayError::invalid_argument)?;
+        let doc_id = create_documents(self.id, vec![args])
+            .await?
+            .pop()
+            .ok_or_else(|| BeelayError::invalid_argument("no document was created"))?;
+
+        Ok(JsValue::from_str(&doc_id))
+    }
 
-        let mut doc_ctx = DocumentCtx::new(sed_id);
-        doc_ctx.apply_commit(&args.initial_commit).await?;
+    /// Create many documents at once, each from `{ initialCommit, codec, encrypted }` as
+    /// for `createDoc`, resolving to their IDs in the same order.
+    ///
+    /// The documents are written to storage together, in one transaction for IndexedDB,
+    /// so either all of them are stored or none are. They start syncing together once
+    /// they've been stored.
+    #[wasm_bindgen(js_name = createDocs, unchecked_return_type = "DocumentId[]")]
+    pub async fn create_docs(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "CreateDocArgs[]")] batch: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let batch: Vec<CreateDocArgs> =
+            serde_wasm_bindgen::from_value(batch).map_err(BeelayError::invalid_argument)?;
+        let doc_ids = create_documents(self.id, batch).await?;
 
-        HANDLES.with(|handles| {
+        serde_wasm_bindgen::to_value(&doc_ids).map_err(JsValue::from)
+    }
+
+    /// Delete a document, along with everything stored for it.
+    ///
+    /// Returns false if the handle had no such document.
+    #[wasm_bindgen(js_name = deleteDoc)]
+    pub async fn delete_doc(&self, doc_id: String) -> Result<bool, JsValue> {
+        let deleted = HANDLES.with(|handles| {
             let mut handles = handles.borrow_mut();
             let ctx = handles
                 .get_mut(&self.id)
-                .ok_or_else(|| JsValue::from_str("invalid handle"))?;
-            ctx.documents.insert(doc_id.clone(), doc_ctx);
-            Ok::<_, JsValue>(())
+                .ok_or(BeelayError::InvalidHandle)?;
+            // A document having commits applied is put back afterwards unless deleted
+            let sed_id = match ctx.documents.remove(&doc_id) {
+                Some(doc) => doc.sed_id,
+                None => match ctx.checked_out.delete(&doc_id) {
+                    Some(sed_id) => sed_id,
+                    None => return Ok(None),
+                },
+            };
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            ctx.merge_policies.remove(&doc_id);
+            ctx.changes.remove(&doc_id);
+            ctx.save_feed()?;
+            ctx.summaries.forget(sed_id);
+            if let Some(sync) = &ctx.sync {
+                sync.forget(sed_id);
+            }
+            ctx.persist()?;
+            Ok::<_, JsValue>(Some((sed_id, ctx.backend.clone())))
         })?;
+        let Some((sed_id, backend)) = deleted else {
+            return Ok(false);
+        };
+        diagnostics::untrack(ResourceKind::Document, self.id, &doc_id);
 
-        Ok(JsValue::from_str(&doc_id))
+        if let Some(backend) = backend {
+            backend.delete_document(sed_id).await?;
+        }
+        Ok(true)
+    }
+
+    /// The namespaces handles have saved anything in, in the storage this handle uses,
+    /// sorted. Handles without a namespace aren't listed, and neither is anything when
+    /// documents are kept in memory.
+    #[wasm_bindgen(js_name = listNamespaces)]
+    pub async fn list_namespaces(&self) -> Result<Vec<String>, JsValue> {
+        let backend = HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(|ctx| ctx.backend.clone())
+                .ok_or_else(|| JsValue::from(BeelayError::InvalidHandle))
+        })?;
+        match backend {
+            Some(backend) => Ok(backend.namespaces().await?),
+            None => Ok(Vec::new()),
+        }
     }
 
     /// Load all commits for a document.
-    #[wasm_bindgen(js_name = loadDocument)]
-    pub async fn load_document(&self, doc_id: String) -> Result<JsValue, JsValue> {
+    ///
+    /// `options.order` is `"causal"` (the default), which puts every commit after its
+    /// parents, or `"insertion"`, the order the commits were added in.
+    ///
+    /// Once the document has been compacted (see `compact`), each bundle is listed in
+    /// place of the commits it covers, as `{ type: "bundle", start, end, checkpoints,
+    /// contents }`. Decode its commits with `bundleCommits(contents)`.
+    ///
+    /// Results are cached until the document changes, so repeated calls may return the
+    /// same array. Treat it as read-only. For documents with long histories, use
+    /// `loadDocumentPage` to pull the commits a page at a time instead.
+    #[wasm_bindgen(js_name = loadDocument, unchecked_return_type = "(Commit | Bundle)[]")]
+    pub async fn load_document(
+        &self,
+        doc_id: String,
+        #[wasm_bindgen(unchecked_param_type = "LoadDocumentOptions | undefined")] options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let options: LoadDocumentOptions = if options.is_undefined() || options.is_null() {
+            LoadDocumentOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options).map_err(BeelayError::invalid_argument)?
+        };
         HANDLES.with(|handles| {
-            let handles = handles.borrow();
+            let mut handles = handles.borrow_mut();
             let ctx = handles
-                .get(&self.id)
-                .ok_or_else(|| JsValue::from_str("invalid handle"))?;
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
             let doc = ctx
                 .documents
                 .get(&doc_id)
-                .ok_or_else(|| JsValue::from_str("unknown document"))?;
+                .ok_or(BeelayError::UnknownDocument)?;
 
-            let commits = doc
-                .commits
-                .iter()
-                .map(|record| CommitOutput {
-                    kind: "commit",
-                    parents: record.parents.clone(),
-                    hash: record.hash.clone(),
-                    contents: record.contents.clone(),
-                })
-                .collect::<Vec<_>>();
+            let key = (doc_id, doc.version, options.order);
+            if let Some(cached) = ctx.load_cache.get(&key) {
+                return Ok(cached);
+            }
 
-            serde_wasm_bindgen::to_value(&commits).map_err(JsValue::from)
+            let entries = bundle::with_bundles(options.order.apply(&doc.commits), &doc.bundles);
+            let value = envelope::to_js(&entries).map_err(JsValue::from)?;
+            ctx.load_cache.insert(key, value.clone());
+            Ok(value)
         })
     }
 
-    /// Add commits produced by a client.
-    #[wasm_bindgen(js_name = addCommits)]
-    pub async fn add_commits(&self, args: JsValue) -> Result<JsValue, JsValue> {
-        let args: AddCommitArgs = serde_wasm_bindgen::from_value(args)
-            .map_err(JsValue::from)?;
-        let doc_id = args.doc_id.clone();
-
-        let mut doc_ctx = HANDLES.with(|handles| {
-            let mut handles = handles.borrow_mut();
-            let ctx = handles
-                .get_mut(&self.id)
-                .ok_or_else(|| JsValue::from_str("invalid handle"))?;
-            ctx.documents
-                .remove(&doc_id)
-                .ok_or_else(|| JsValue::from_str("unknown document"))
-        })?;
+    /// A document's commits a page at a time, in the same order as `loadDocument`.
+    ///
+    /// `options` may set `order`, `cursor` (the `nextCursor` of the previous page), and
+    /// `limit`. Resolves to `{ commits, nextCursor }`, where `nextCursor` is `null` after
+    /// the last page. Only the page's contents are copied out, so memory stays bounded
+    /// however long the history is. Commits added while paging may sort before the
+    /// cursor; pick them up with `on`. A cursor whose commit is gone fails with a
+    /// `CursorExpired` error.
+    #[wasm_bindgen(js_name = loadDocumentPage, unchecked_return_type = "CommitPage")]
+    pub fn load_document_page(
+        &self,
+        doc_id: String,
+        #[wasm_bindgen(unchecked_param_type = "LoadPageOptions | undefined")] options: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let options: LoadPageOptions = if options.is_undefined() || options.is_null() {
+            LoadPageOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options).map_err(BeelayError::invalid_argument)?
+        };
+        let page = PageOptions {
+            cursor: options.cursor,
+            limit: options.limit,
+        };
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
 
-        for commit in &args.commits {
-            if let Err(err) = doc_ctx.apply_commit(commit).await {
-                HANDLES.with(|handles| {
-                    let mut handles = handles.borrow_mut();
-                    let ctx = handles
-                        .get_mut(&self.id)
-                        .ok_or_else(|| JsValue::from_str("invalid handle"))?;
-                    ctx.documents.insert(doc_id.clone(), doc_ctx);
-                    Ok::<_, JsValue>(())
-                })?;
-                return Err(err);
-            }
-        }
+            let ordered = options.order.apply(&doc.commits);
+            let from = match &page.cursor {
+                Some(token) => Cursor::decode(token, CursorKind::Load, &doc_id)?
+                    .resume(ordered.len(), |i, hash| ordered[i].hash == hash)?,
+                None => 0,
+            };
+            let to = page.end(from, ordered.len());
+            let next_cursor = (to > from && to < ordered.len()).then(|| {
+                let hash = ordered[to - 1].hash.clone();
+                Cursor::after(CursorKind::Load, &doc_id, hash, to - 1).encode()
+            });
 
-        HANDLES.with(|handles| {
-            let mut handles = handles.borrow_mut();
-            let ctx = handles
-                .get_mut(&self.id)
-                .ok_or_else(|| JsValue::from_str("invalid handle"))?;
-            ctx.documents.insert(doc_id, doc_ctx);
-            serde_wasm_bindgen::to_value(&Vec::<serde_json::Value>::new())
-                .map_err(JsValue::from)
+            let page = HistoryPage {
+                commits: ordered[from..to]
+                    .iter()
+                    .map(|record| record.to_output())
+                    .collect(),
+                next_cursor,
+            };
+            envelope::to_js(&page).map_err(JsValue::from)
         })
     }
 
-    /// Graceful shutdown.
-    pub fn stop(&self) {
+    /// The commits of a document that aren't reachable from `heads`, parents first:
+    /// what it has gained since a reader last saw it at `heads`.
+    ///
+    /// Pass the heads a previous read ended at (such as from `getHeads`) to apply just
+    /// the new commits. Heads the document doesn't have are ignored, so at worst every
+    /// commit is returned.
+    #[wasm_bindgen(js_name = loadCommitsSince, unchecked_return_type = "Commit[]")]
+    pub fn load_commits_since(
+        &self,
+        doc_id: String,
+        heads: Vec<String>,
+    ) -> Result<JsValue, JsValue> {
         HANDLES.with(|handles| {
-            handles.borrow_mut().remove(&self.id);
-        });
-    }
-
-    /// Mock contact card support for compatibility with existing worker code.
-    #[wasm_bindgen(js_name = createContactCard)]
-    pub fn create_contact_card(&self) -> String {
-        random_hex_string(32)
-    }
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
 
-    /// Wait until synced – no-op in the single-node WASM runtime.
-    #[wasm_bindgen(js_name = waitUntilSynced)]
-    pub async fn wait_until_synced(&self, _peer_id: String) -> Result<JsValue, JsValue> {
-        serde_wasm_bindgen::to_value(&WaitResult { synced: true })
-            .map_err(JsValue::from)
+            let seen = graph::ancestors(&doc.commits, &heads);
+            let commits = Order::Causal
+                .apply(&doc.commits)
+                .into_iter()
+                .filter(|record| !seen.contains(&record.hash))
+                .map(CommitRecord::to_output)
+                .collect::<Vec<_>>();
+            envelope::to_js(&commits).map_err(JsValue::from)
+        })
     }
-}
-
-impl DocumentCtx {
-    fn new(sed_id: SedimentreeId) -> Self {
-        let tree = Sedimentree::new(Vec::new(), Vec::new());
-        let subduction = Subduction::new(
-            HashMap::from([(sed_id, tree)]),
-            MemoryStorage::default(),
-            HashMap::new(),
-        );
 
-        Self {
-            sed_id,
-            subduction,
-            commits: Vec::new(),
-            seen: HashSet::new(),
+    /// Add commits produced by a client.
+    ///
+    /// Commits may carry `authorPeerId`, `timestamp`, and `tags` alongside their
+    /// contents, which are kept with them through storage and sync (see [`meta`]).
+    ///
+    /// Returns `{ headsVersion }`, a fencing token derived from the document's heads, so
+    /// it changes whenever they do and is the same after the handle is reloaded or
+    /// restored. Passing it back as `ifHeadsVersion` makes the write conditional: it
+    /// fails, without applying anything, if another write got there first.
+    #[wasm_bindgen(js_name = addCommits, unchecked_return_type = "AddCommitsResult")]
+    pub async fn add_commits(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "AddCommitsArgs")] args: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let args: AddCommitArgs =
+            serde_wasm_bindgen::from_value(args).map_err(BeelayError::invalid_argument)?;
+        strict::check_running(self.id, "addCommits")?;
+        let (applied, heads_version) = apply_commits(
+            self.id,
+            &args.doc_id,
+            &args.commits,
+            args.origin,
+            args.if_heads_version,
+        )
+        .await?;
+        if applied > 0 && args.origin == CommitOrigin::Local {
+            HANDLES.with(|handles| {
+                let handles = handles.borrow();
+                match handles.get(&self.id).and_then(|ctx| ctx.tabs.as_ref()) {
+                    Some(tabs) => tabs.post(&args.doc_id, &args.commits),
+                    None => Ok(()),
+                }
+            })?;
         }
-    }
 
-    async fn apply_commit(&mut self, commit: &CommitInput) -> Result<(), JsValue> {
-        if !self.seen.insert(commit.hash.clone()) {
-            return Ok(());
-        }
+        serde_wasm_bindgen::to_value(&AddCommitsResult { heads_version }).map_err(JsValue::from)
+    }
 
-        let blob = Blob::new(commit.contents.clone());
-        let blob_meta = blob.meta();
-        let parents = commit
-            .parents
-            .iter()
-            .map(|parent| parse_digest(parent))
-            .collect::<Result<Vec<_>, _>>()?;
-        let digest = parse_digest(&commit.hash)?;
-        let loose = LooseCommit::new(digest, parents, blob_meta);
+    /// The document's heads, the hashes of the commits that no other commit names as a
+    /// parent, sorted. New commits should name them as their parents.
+    ///
+    /// They come from the document's sedimentree, so they take chunks into account.
+    #[wasm_bindgen(js_name = getHeads, unchecked_return_type = "Hash[]")]
+    pub async fn get_heads(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        let (engine, sed_id) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            Ok::<_, JsValue>((doc.subduction.clone(), doc.sed_id))
+        })?;
 
-        self.subduction
-            .add_commit(self.sed_id, &loose, blob.clone())
+        let mut heads = engine
+            .get_heads(sed_id)
             .await
-            .map_err(|err| JsValue::from_str(&format!("{err:?}")))?;
-
-        self.commits.push(CommitRecord {
-            parents: commit.parents.clone(),
-            hash: commit.hash.clone(),
-            contents: commit.contents.clone(),
-        });
-
-        Ok(())
-    }
-}
-
-fn parse_digest(hex_str: &str) -> Result<Digest, JsValue> {
-    let bytes = hex::decode(hex_str)
-        .map_err(|_| JsValue::from_str("digest must be 64 hex characters"))?;
-    if bytes.len() != 32 {
-        return Err(JsValue::from_str("digest must be 32 bytes"));
+            .unwrap_or_default()
+            .iter()
+            .map(|head| hex::encode(head.as_bytes()))
+            .collect::<Vec<_>>();
+        heads.sort();
+        heads.dedup();
+        serde_wasm_bindgen::to_value(&heads).map_err(JsValue::from)
     }
-    let mut arr = [0u8; 32];
-    arr.copy_from_slice(&bytes);
-    Ok(Digest::from(arr))
-}
-
-fn random_doc_id() -> String {
-    random_hex_string(16)
-}
-
-fn random_hex_string(length: usize) -> String {
-    let bytes = random_bytes_vec(length);
-    hex::encode(bytes)
-}
 
-fn random_bytes_array() -> [u8; 32] {
-    let mut bytes = [0u8; 32];
-    fill_random_bytes(&mut bytes);
-    bytes
-}
+    /// Bundle a document's history, so that it syncs and loads a run of commits at a time
+    /// rather than one by one.
+    ///
+    /// Every run of commits between two checkpoints that isn't bundled yet becomes a
+    /// bundle, and is sent to the document's peers. Checkpoints are commits whose hash,
+    /// read as a number, ends in two zeros in base 10, so runs are about a hundred
+    /// commits long. Commits since the last checkpoint stay as they are, and so do those
+    /// before the first. Resolves to the number of bundles made.
+    ///
+    /// `loadDocument` lists bundles in place of their commits from then on, though
+    /// `loadDocumentPage` still pages through the commits themselves. Encrypted
+    /// documents can't be compacted, and throw `Unsupported`.
+    #[wasm_bindgen(js_name = compact)]
+    pub async fn compact(&self, doc_id: String) -> Result<usize, JsValue> {
+        strict::check_running(self.id, "compact")?;
+        let (engine, sed_id, commits) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            if doc.encryption.is_some() {
+                // Bundles would hold the commits in the clear
+                return Err(BeelayError::Unsupported(
+                    "encrypted documents can't be compacted".to_string(),
+                )
+                .into());
+            }
+            Ok::<_, JsValue>((doc.subduction.clone(), doc.sed_id, doc.commits.clone()))
+        })?;
 
-fn random_bytes_vec(length: usize) -> Vec<u8> {
-    let mut bytes = vec![0u8; length];
-    fill_random_bytes(&mut bytes);
-    bytes
-}
+        let mut contents = Vec::new();
+        let chunks = engine
+            .compact(sed_id, |spec| {
+                // A deeper stratum, spanning bundles of its own
+                if !spec.checkpoints().is_empty() {
+                    return None;
+                }
+                let end = hex::encode(spec.boundary().first().as_bytes());
+                let covered = bundle::covered(&commits, &end);
+                let encoded = bundle::encode(&covered).ok().filter(|_| !covered.is_empty())?;
+                contents.push(encoded.clone());
+                Some(Blob::new(encoded))
+            })
+            .await
+            .map_err(BeelayError::storage)?;
 
-fn fill_random_bytes(buffer: &mut [u8]) {
-    for byte in buffer.iter_mut() {
-        *byte = random_u8();
+        let mut bundles = Vec::with_capacity(chunks.len());
+        for (chunk, contents) in chunks.iter().zip(&contents) {
+            bundles.push(Bundle::from_chunk(chunk, contents)?.0);
+        }
+        let made = bundles.len();
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get_mut(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            for bundle in bundles {
+                doc.record_bundle(bundle);
+            }
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            Ok::<_, JsValue>(())
+        })?;
+        Ok(made)
     }
-}
-
-fn random_u8() -> u8 {
-    (Math::random() * 256.0).floor() as u8
-}
-
-/// Minimal `Connection` implementation – the WASM runtime is single-node, so this is unused.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-struct NullConnection;
 
-impl Connection<Local> for NullConnection {
-    type DisconnectionError = std::convert::Infallible;
-    type SendError = std::convert::Infallible;
-    type RecvError = std::convert::Infallible;
-    type CallError = std::convert::Infallible;
+    /// Make a document ephemeral, such as a presence board or a session scratchpad, so
+    /// that each commit expires `ttlSeconds` after it was first seen, or make it
+    /// permanent again with `null`. Requires admin access.
+    ///
+    /// The TTL is saved with the document and passed on to its peers, the sync server
+    /// included, which each drop the same commits, heads included. Expired commits are
+    /// dropped from loads and storage within a few seconds, and refused from peers that
+    /// still have them. Commits in bundles made by `compact` don't expire.
+    #[wasm_bindgen(js_name = setDocumentTtl)]
+    pub async fn set_document_ttl(
+        &self,
+        doc_id: String,
+        ttl_seconds: Option<u32>,
+    ) -> Result<(), JsValue> {
+        if ttl_seconds == Some(0) {
+            return Err(BeelayError::invalid_argument("ttlSeconds must be at least 1").into());
+        }
+        let ttl = ttl_seconds.map(|secs| Duration::from_secs(secs.into()));
+        let (engine, sed_id) = self.with_administered_doc(&doc_id, |_, doc| {
+            doc.ttl = ttl;
+            Ok((doc.subduction.clone(), doc.sed_id))
+        })?;
+        engine
+            .set_ttl(sed_id, ttl)
+            .await
+            .map_err(|err| BeelayError::StorageFailure(format!("{err:?}")).into())
+    }
 
-    fn peer_id(&self) -> PeerId {
-        PeerId::new([0; 32])
+    /// How many seconds a document's commits live, or `null` if it isn't ephemeral.
+    ///
+    /// This includes a TTL set by a peer the document has synced with.
+    #[wasm_bindgen(js_name = documentTtl)]
+    pub async fn document_ttl(&self, doc_id: String) -> Result<Option<f64>, JsValue> {
+        let (engine, sed_id) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            Ok::<_, JsValue>((doc.subduction.clone(), doc.sed_id))
+        })?;
+        Ok(engine.ttl(sed_id).await.map(|ttl| ttl.as_secs_f64()))
     }
 
-    fn disconnect(&mut self) -> LocalBoxFuture<'_, Result<(), Self::DisconnectionError>> {
-        async { Ok(()) }.boxed_local()
+    /// The document's current heads version, as returned by `addCommits`.
+    #[wasm_bindgen(js_name = headsVersion, unchecked_return_type = "number")]
+    pub fn heads_version(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            serde_wasm_bindgen::to_value(&doc.heads_version()).map_err(JsValue::from)
+        })
     }
 
-    fn send(
-        &self,
-        _message: subduction_core::connection::message::Message,
-    ) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
-        async { Ok(()) }.boxed_local()
+    /// Statistics about a document, as `{ commits, contentBytes, bundles, heads,
+    /// firstActivity, lastActivity, authors }`.
+    ///
+    /// These are kept up to date as commits are applied, so this is cheap to call
+    /// for many documents. Activity times are when commits were applied here, in
+    /// milliseconds since the epoch, and restart from the restore time after
+    /// `restoreEncryptedBackup`.
+    #[wasm_bindgen(js_name = docStats, unchecked_return_type = "DocStats")]
+    pub fn doc_stats(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            serde_wasm_bindgen::to_value(&doc.stats.summary()).map_err(JsValue::from)
+        })
     }
 
-    fn recv(
-        &self,
-    ) -> LocalBoxFuture<'_, Result<subduction_core::connection::message::Message, Self::RecvError>> {
-        async { std::future::pending().await }.boxed_local()
+    /// How often syncing reused a cached document summary, as `{ hits, misses,
+    /// entries }`.
+    ///
+    /// Every document's engine shares one cache, which holds a document's summary until
+    /// the document changes.
+    #[wasm_bindgen(js_name = summaryCacheStats)]
+    pub fn summary_cache_stats(&self) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            serde_wasm_bindgen::to_value(&ctx.summaries.stats()).map_err(JsValue::from)
+        })
     }
 
-    fn next_request_id(
-        &self,
-    ) -> LocalBoxFuture<'_, subduction_core::connection::message::RequestId> {
-        async {
-            subduction_core::connection::message::RequestId {
-                requestor: self.peer_id(),
-                nonce: 0,
+    /// How the sync server has slowed this handle's sends, as `{ window, unacked,
+    /// waiting, throttled, throttledMs }`, or `null` without a `syncServerUrl`.
+    ///
+    /// An overloaded server grants credit for a window of bytes at a time, and sends
+    /// wait for more rather than going past it. `window` is `null` until it does;
+    /// `throttled` counts the sends that have had to wait, and `throttledMs` the time
+    /// they spent waiting.
+    #[wasm_bindgen(js_name = flowStats)]
+    pub fn flow_stats(&self) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            match &ctx.sync {
+                Some(sync) => {
+                    serde_wasm_bindgen::to_value(&sync.flow_stats()).map_err(JsValue::from)
+                }
+                None => Ok(JsValue::NULL),
             }
-        }
-        .boxed_local()
+        })
     }
 
-    fn call(
+    /// Every commit applied to this handle's documents since `sinceToken`, oldest first,
+    /// or at most `limit` of them.
+    ///
+    /// Resolves to `{ entries, nextToken }`, where each entry is `{ docId, hash,
+    /// parents, size }`. Pass `nextToken` to the next call to pick up where this one
+    /// left off, or omit `sinceToken` to start from the oldest entry kept. Only the
+    /// most recent 10,000 entries are kept, and deleting a document drops its entries.
+    /// A token that fell behind the entries kept fails with a `CursorExpired` error.
+    /// The feed is saved with the handle, so tokens resume across reloads of a handle
+    /// that persists; restoring a snapshot or backup adds the restored documents'
+    /// commits as new entries.
+    #[wasm_bindgen(js_name = changesFeed)]
+    pub fn changes_feed(
         &self,
-        _req: subduction_core::connection::message::BatchSyncRequest,
-        _timeout: Option<std::time::Duration>,
-    ) -> LocalBoxFuture<'_, Result<subduction_core::connection::message::BatchSyncResponse, Self::CallError>> {
-        async { std::future::pending().await }.boxed_local()
+        since_token: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let page = ctx.changes.since(&PageOptions {
+                cursor: since_token,
+                limit,
+            })?;
+            serde_wasm_bindgen::to_value(&page).map_err(JsValue::from)
+        })
     }
-}
 
-// -- Compatibility helpers --------------------------------------------------
-
-/// Simple in-memory signer placeholder to reduce TypeScript churn.
-#[wasm_bindgen]
-pub struct MemorySigner {
-    _opaque: bool,
-}
+    /// A document's commits a page at a time, in the order they were applied, which
+    /// puts every commit after its parents.
+    ///
+    /// `options` may set `cursor` (the `nextCursor` of the previous page) and `limit`.
+    /// Resolves to `{ commits, nextCursor }`, where `nextCursor` is `null` after the last
+    /// page. Cursors survive reloads; one whose commit is gone fails with a
+    /// `CursorExpired` error.
+    #[wasm_bindgen(js_name = history)]
+    pub fn history(&self, doc_id: String, options: JsValue) -> Result<JsValue, JsValue> {
+        let page = PageOptions::from_js(options)?;
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
 
-#[wasm_bindgen]
-impl MemorySigner {
-    #[wasm_bindgen(constructor)]
-    pub fn new() -> MemorySigner {
-        MemorySigner { _opaque: false }
-    }
+            let from = match &page.cursor {
+                Some(token) => Cursor::decode(token, CursorKind::History, &doc_id)?
+                    .resume(doc.commits.len(), |i, hash| doc.commits[i].hash == hash)?,
+                None => 0,
+            };
+            let to = page.end(from, doc.commits.len());
+            let next_cursor = (to > from && to < doc.commits.len()).then(|| {
+                let hash = doc.commits[to - 1].hash.clone();
+                Cursor::after(CursorKind::History, &doc_id, hash, to - 1).encode()
+            });
 
-    #[wasm_bindgen(js_name = verifyingKey)]
-    pub fn verifying_key(&self) -> Uint8Array {
-        Uint8Array::new_with_length(32)
+            let page = HistoryPage {
+                commits: doc.commits[from..to]
+                    .iter()
+                    .map(CommitRecord::to_output)
+                    .collect(),
+                next_cursor,
+            };
+            envelope::to_js(&page).map_err(JsValue::from)
+        })
     }
 
-    #[wasm_bindgen(js_name = sign)]
-    pub async fn sign(&self, message: Uint8Array) -> Uint8Array {
-        // Echo the message – this signer is only used for demo/testing flows.
-        message
+    /// A view of a commit's contents that reads WASM memory directly, without a copy.
+    ///
+    /// See [`BlobView`] for when the view's bytes are invalidated.
+    #[wasm_bindgen(js_name = getBlobView)]
+    pub fn get_blob_view(&self, doc_id: String, hash: String) -> Result<BlobView, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            let commit = doc
+                .commits
+                .iter()
+                .find(|commit| commit.hash == hash)
+                .ok_or(BeelayError::UnknownCommit)?;
+            Ok(BlobView::new(self.id, Rc::clone(&commit.contents)))
+        })
     }
-}
 
-/// Minimal storage adapter placeholder for compatibility with the worker code.
-#[wasm_bindgen]
-pub struct MemoryStorageAdapter {
-    _opaque: bool,
-}
+    /// Subscribe to commits applied to a document.
+    ///
+    /// Notifications are coalesced for `options.windowMs` milliseconds and delivered to
+    /// `callback` as `{ docId, commits, coalescedCount }`. Returns a subscription ID.
+    #[wasm_bindgen(js_name = subscribe)]
+    pub fn subscribe(
+        &self,
+        doc_id: String,
+        #[wasm_bindgen(unchecked_param_type = "(batch: NotificationBatch) => void")]
+        callback: js_sys::Function,
+        #[wasm_bindgen(unchecked_param_type = "SubscribeOptions | undefined")] options: JsValue,
+    ) -> Result<u32, JsValue> {
+        let options: SubscribeOptions = if options.is_undefined() || options.is_null() {
+            SubscribeOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(options).map_err(BeelayError::invalid_argument)?
+        };
 
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if !ctx.documents.contains_key(&doc_id) {
+                return Err(BeelayError::UnknownDocument.into());
+            }
 
-#[wasm_bindgen]
-impl MemoryStorageAdapter {
-    #[wasm_bindgen(constructor)]
-    pub fn new() -> MemoryStorageAdapter {
-        MemoryStorageAdapter { _opaque: false }
+            let id = ctx.new_subscription(self.facade);
+            ctx.subscriptions
+                .insert(id, Subscription::new(doc_id, callback, &options));
+            diagnostics::track_owned(
+                ResourceKind::Subscription,
+                self.id,
+                self.facade,
+                id.to_string(),
+            );
+            Ok(id)
+        })
+    }
+
+    /// Call `callback` with the array of commits (`{ type, v, parents, hash, contents }`,
+    /// and any metadata) that each change to a document adds, as soon as it's applied.
+    ///
+    /// That includes local edits, synced commits, and, for handles persisting to
+    /// IndexedDB, local edits made by a handle in another tab. Returns a subscription ID
+    /// that can be passed to `off`.
+    #[wasm_bindgen(js_name = on)]
+    pub fn on(
+        &self,
+        doc_id: String,
+        #[wasm_bindgen(unchecked_param_type = "(commits: Commit[]) => void")]
+        callback: js_sys::Function,
+    ) -> Result<u32, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if !ctx.documents.contains_key(&doc_id) {
+                return Err(BeelayError::UnknownDocument.into());
+            }
+
+            let id = ctx.new_subscription(self.facade);
+            ctx.subscriptions
+                .insert(id, Subscription::listener(doc_id, callback));
+            diagnostics::track_owned(ResourceKind::Listener, self.id, self.facade, id.to_string());
+            Ok(id)
+        })
+    }
+
+    /// Listen for synced commits that diverge from local work.
+    ///
+    /// When `addCommits` with `origin: "sync"` introduces heads that don't build on
+    /// every unsynced local head, `callback` is called with a `conflict-detected` event:
+    /// `{ type, docId, localHeads, remoteHeads, commonAncestors, localOnly, remoteOnly }`.
+    /// Returns a subscription ID that can be passed to `unsubscribe`.
+    #[wasm_bindgen(js_name = onConflict)]
+    pub fn on_conflict(&self, callback: js_sys::Function) -> Result<u32, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+
+            let id = ctx.new_subscription(self.facade);
+            ctx.conflict_listeners.insert(id, callback);
+            diagnostics::track_owned(ResourceKind::Listener, self.id, self.facade, id.to_string());
+            Ok(id)
+        })
+    }
+
+    /// Call `callback` with `(docId, heads)` whenever the heads of any document change,
+    /// whether by a local edit, a sync, or the document being created or adopted.
+    ///
+    /// `heads` is the document's new array of head hashes, sorted. One subscription
+    /// covers every document, so lists of many documents can stay up to date without
+    /// subscribing to each. Returns a subscription ID that can be passed to `unsubscribe`.
+    #[wasm_bindgen(js_name = watchAll)]
+    pub fn watch_all(&self, callback: js_sys::Function) -> Result<u32, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+
+            let id = ctx.new_subscription(self.facade);
+            ctx.heads_watchers.insert(id, callback);
+            diagnostics::track_owned(ResourceKind::Listener, self.id, self.facade, id.to_string());
+            Ok(id)
+        })
+    }
+
+    /// Merge a document's heads automatically whenever synced commits leave it with
+    /// more than one.
+    ///
+    /// `policy` is `"emptyCommit"` to merge with empty contents, `"preferNewest"` to merge
+    /// with the contents of the head applied most recently, or a function called with
+    /// `{ docId, heads }` that returns the merge's contents, or `undefined` to leave the
+    /// heads alone. The callback runs while the document is being updated, so it can't
+    /// call back into the document. Pass `null` to go back to merging manually.
+    #[wasm_bindgen(js_name = setMergePolicy)]
+    pub fn set_merge_policy(&self, doc_id: String, policy: JsValue) -> Result<(), JsValue> {
+        let policy = MergePolicy::from_js(&policy)?;
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if !ctx.documents.contains_key(&doc_id) {
+                return Err(BeelayError::UnknownDocument.into());
+            }
+
+            match policy {
+                Some(policy) => ctx.merge_policies.insert(doc_id, policy),
+                None => ctx.merge_policies.remove(&doc_id),
+            };
+            Ok(())
+        })
+    }
+
+    /// Add a commit with `contents` on top of all of a document's current heads, and
+    /// return its hash, which is the document's only head afterwards.
+    ///
+    /// The commit's parents and hash are worked out here, so apps reconciling divergent
+    /// heads by hand don't have to. It's a local edit like any passed to `addCommits`:
+    /// it's signed on handles loaded with `signCommits`, and syncs out to peers.
+    #[wasm_bindgen(js_name = createMergeCommit, unchecked_return_type = "Hash")]
+    pub async fn create_merge_commit(
+        &self,
+        doc_id: String,
+        contents: &[u8],
+    ) -> Result<String, JsValue> {
+        strict::check_running(self.id, "createMergeCommit")?;
+        let (parents, version) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            Ok::<_, JsValue>((sorted_heads(&doc.commits), doc.heads_version()))
+        })?;
+        let merge = CommitInput {
+            v: envelope::COMMIT_VERSION,
+            hash: commit_hash(&parents, contents),
+            parents,
+            contents: contents.to_vec(),
+            meta: CommitMeta::default(),
+            extensions: Extensions::new(),
+        };
+
+        let commits = std::slice::from_ref(&merge);
+        let (applied, _) = apply_commits(
+            self.id,
+            &doc_id,
+            commits,
+            CommitOrigin::Local,
+            Some(version),
+        )
+        .await?;
+        if applied > 0 {
+            HANDLES.with(|handles| {
+                let handles = handles.borrow();
+                match handles.get(&self.id).and_then(|ctx| ctx.tabs.as_ref()) {
+                    Some(tabs) => tabs.post(&doc_id, commits),
+                    None => Ok(()),
+                }
+            })?;
+        }
+        Ok(merge.hash)
+    }
+
+    /// Remove a subscription. Any notifications still buffered for it are dropped.
+    #[wasm_bindgen(js_name = unsubscribe)]
+    pub fn unsubscribe(&self, subscription_id: u32) -> bool {
+        let removed = HANDLES.with(|handles| {
+            handles.borrow_mut().get_mut(&self.id).is_some_and(|ctx| {
+                ctx.subscription_owners.remove(&subscription_id);
+                ctx.subscriptions.remove(&subscription_id).is_some()
+                    || ctx.conflict_listeners.remove(&subscription_id).is_some()
+                    || ctx.heads_watchers.remove(&subscription_id).is_some()
+                    || ctx.report_watchers.remove(&subscription_id).is_some()
+            })
+        });
+        diagnostics::untrack_subscription(self.id, &subscription_id.to_string());
+        removed
+    }
+
+    /// Remove a listener added with `on`.
+    #[wasm_bindgen(js_name = off)]
+    pub fn off(&self, subscription_id: u32) -> bool {
+        self.unsubscribe(subscription_id)
+    }
+
+    /// Deliver all buffered notifications now, without waiting for their windows to elapse.
+    ///
+    /// Every subscriber gets its batch, even if another's callback throws. What the
+    /// callbacks threw is thrown afterwards: the error itself if only one threw, or
+    /// an `Error` listing them all in its `errors`.
+    #[wasm_bindgen(js_name = flushNotifications)]
+    pub fn flush_notifications(&self) -> Result<(), JsValue> {
+        flush_notifications(self.id, None)
+    }
+
+    /// The documents this handle holds, as `{ docId, access, createdBy, commitCount,
+    /// heads }`, sorted by ID.
+    ///
+    /// `filter` may set `access` (the least access this handle must have), `sharedWith`
+    /// (a peer other than the creator that must have access), and `createdBy`. For
+    /// example, `{ sharedWith: me }` lists documents shared with me, and
+    /// `{ createdBy: me }` those owned by me.
+    ///
+    /// Pass `page` (`{ cursor, limit }`) to get the listing a page at a time, as
+    /// `{ documents, nextCursor }`, where `nextCursor` is `null` after the last page.
+    /// Cursors hold the last document ID listed, so they never expire.
+    #[wasm_bindgen(
+        js_name = listDocuments,
+        unchecked_return_type = "DocumentListing[] | DocumentsPage"
+    )]
+    pub fn list_documents(
+        &self,
+        #[wasm_bindgen(unchecked_param_type = "DocumentFilter | undefined")] filter: JsValue,
+        #[wasm_bindgen(unchecked_param_type = "PageOptions | undefined")] page: JsValue,
+    ) -> Result<JsValue, JsValue> {
+        let filter: DocumentFilter = if filter.is_undefined() || filter.is_null() {
+            DocumentFilter::default()
+        } else {
+            serde_wasm_bindgen::from_value(filter).map_err(BeelayError::invalid_argument)?
+        };
+        let paged = !(page.is_undefined() || page.is_null());
+        let page = PageOptions::from_js(page)?;
+        let after = page
+            .cursor
+            .as_deref()
+            .map(|token| Cursor::decode(token, CursorKind::Documents, ""))
+            .transpose()?;
+
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let peer_id = ctx.peer_id();
+
+            let mut listing = ctx
+                .documents
+                .iter()
+                .filter_map(|(doc_id, doc)| {
+                    let access = doc.members.access(&peer_id, &ctx.groups);
+                    filter
+                        .matches(&doc.members, access, &ctx.groups)
+                        .then(|| DocumentListing {
+                            doc_id: doc_id.clone(),
+                            access,
+                            created_by: doc.members.creator().to_string(),
+                            commit_count: doc.commits.len(),
+                            heads: conflict::heads(&doc.commits),
+                        })
+                })
+                .collect::<Vec<_>>();
+            listing.sort_by(|a, b| a.doc_id.cmp(&b.doc_id));
+            if !paged {
+                return serde_wasm_bindgen::to_value(&listing).map_err(JsValue::from);
+            }
+
+            let from = after.as_ref().and_then(Cursor::key).map_or(0, |last| {
+                listing.partition_point(|listed| listed.doc_id.as_str() <= last)
+            });
+            let to = page.end(from, listing.len());
+            let next_cursor = (to > from && to < listing.len()).then(|| {
+                let last = listing[to - 1].doc_id.clone();
+                Cursor::after(CursorKind::Documents, "", last, to - 1).encode()
+            });
+            let documents = listing.drain(from..to).collect();
+
+            serde_wasm_bindgen::to_value(&DocumentsPage {
+                documents,
+                next_cursor,
+            })
+            .map_err(JsValue::from)
+        })
+    }
+
+    /// Describe the storage format backing a document.
+    #[wasm_bindgen(js_name = storageInfo)]
+    pub fn storage_info(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+
+            let header = Storage::<Local>::header(doc.subduction.storage());
+            let info = StorageInfo {
+                magic: String::from_utf8_lossy(&MAGIC).into_owned(),
+                format_version: header.format_version(),
+                digest_algorithm: header.digest_algorithm().to_string(),
+                codec: header.codec().to_string(),
+                created_by: header.created_by().to_string(),
+            };
+
+            serde_wasm_bindgen::to_value(&info).map_err(JsValue::from)
+        })
+    }
+
+    /// Ask the admins of a document for access to it.
+    ///
+    /// The request is signed with this handle's key and sent to the sync server, which
+    /// passes it on to the other peers connected to it. Returns the request ID.
+    #[wasm_bindgen(js_name = requestAccess)]
+    pub async fn request_access(&self, doc_id: String, message: String) -> Result<String, JsValue> {
+        let request_id = random_source(self.id)?.hex_string(16)?;
+        let (request, sync) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let sync = ctx.sync.clone().ok_or_else(|| {
+                BeelayError::ConnectionFailure("not connected to a sync server".into())
+            })?;
+            let request = AccessRequest::new(
+                request_id,
+                doc_id,
+                message,
+                js_sys::Date::now(),
+                &ctx.signing_key,
+            );
+            Ok::<_, JsValue>((request, sync))
+        })?;
+
+        sync.send_access_request(access::document_key(&request.doc_id), &request)
+            .await
+            .map_err(|err| BeelayError::ConnectionFailure(err.to_string()))?;
+        Ok(request.request_id)
+    }
+
+    /// Access requests awaiting a decision for documents this handle administers.
+    #[wasm_bindgen(js_name = pendingAccessRequests)]
+    pub fn pending_access_requests(&self) -> Result<JsValue, JsValue> {
+        let pending = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            ctx.collect_access_requests();
+            Ok::<_, JsValue>(
+                ctx.access_requests
+                    .pending(|doc_id| ctx.is_admin_of(doc_id)),
+            )
+        })?;
+
+        serde_wasm_bindgen::to_value(&pending).map_err(JsValue::from)
+    }
+
+    /// Approve an access request, adding the requester as a member with `access`
+    /// (`"read"`, `"write"`, or `"admin"`; defaults to `"write"`).
+    #[wasm_bindgen(js_name = approve)]
+    pub fn approve(
+        &self,
+        request_id: String,
+        #[wasm_bindgen(unchecked_param_type = "Access | undefined")] access: JsValue,
+    ) -> Result<(), JsValue> {
+        let access: Access = if access.is_undefined() || access.is_null() {
+            Access::Write
+        } else {
+            serde_wasm_bindgen::from_value(access).map_err(BeelayError::invalid_argument)?
+        };
+
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let request = ctx
+                .take_access_request(&request_id)
+                .ok_or(BeelayError::UnknownAccessRequest)?;
+            if !request.verify() {
+                return Err(
+                    BeelayError::AccessDenied("access request has an invalid signature").into(),
+                );
+            }
+
+            let doc = ctx
+                .documents
+                .get_mut(&request.doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            doc.members.insert(request.requester, access);
+            ctx.persist()?;
+            Ok::<_, JsValue>(request.doc_id)
+        })
+        .and_then(|doc_id| members::enforce(self.id, &doc_id, &[]))
+    }
+
+    /// Deny an access request, removing it from the pending list.
+    #[wasm_bindgen(js_name = deny)]
+    pub fn deny(&self, request_id: String) -> Result<(), JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            ctx.take_access_request(&request_id)
+                .map(|_| ())
+                .ok_or_else(|| BeelayError::UnknownAccessRequest.into())
+        })
+    }
+
+    /// Create a group (team) of peers that can be made a member of documents as a unit.
+    ///
+    /// This handle owns the group and is its first member. Returns the group ID.
+    #[wasm_bindgen(js_name = createGroup)]
+    pub fn create_group(&self, name: String) -> Result<String, JsValue> {
+        let group_id = random_source(self.id)?.hex_string(16)?;
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let group = Group::new(&group_id, name, &ctx.signing_key);
+            ctx.groups.insert(group_id.clone(), group);
+            ctx.persist()?;
+            Ok(group_id)
+        })
+    }
+
+    /// Add a peer to a group this handle owns.
+    ///
+    /// The peer immediately gains the group's access to every document it is a member of.
+    #[wasm_bindgen(js_name = addGroupMember)]
+    pub fn add_group_member(&self, group_id: String, peer_id: String) -> Result<bool, JsValue> {
+        let added = self.with_owned_group(&group_id, |group, owner| {
+            group.insert(&group_id, peer_id, owner)
+        })?;
+        members::enforce_all(self.id, &[])?;
+        Ok(added)
+    }
+
+    /// Remove a peer from a group this handle owns.
+    ///
+    /// The peer immediately loses any access it only had through the group.
+    #[wasm_bindgen(js_name = removeGroupMember)]
+    pub fn remove_group_member(&self, group_id: String, peer_id: String) -> Result<bool, JsValue> {
+        let removed = self.with_owned_group(&group_id, |group, owner| {
+            group.remove(&group_id, &peer_id, owner)
+        })?;
+        members::enforce_all(self.id, &[peer_id])?;
+        Ok(removed)
+    }
+
+    /// The members of a group, as `{ groupId, name, members }`.
+    #[wasm_bindgen(js_name = groupInfo)]
+    pub fn group_info(&self, group_id: String) -> Result<JsValue, JsValue> {
+        HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let group = ctx.groups.get(&group_id).ok_or(BeelayError::UnknownGroup)?;
+
+            let mut members = group.members().cloned().collect::<Vec<_>>();
+            members.sort();
+            let info = GroupInfo {
+                group_id,
+                name: group.name().to_string(),
+                members,
+            };
+            serde_wasm_bindgen::to_value(&info).map_err(JsValue::from)
+        })
+    }
+
+    /// Make a group a member of a document with `access` (defaults to `"write"`).
+    ///
+    /// Requires admin access to the document.
+    #[wasm_bindgen(js_name = addGroupToDoc)]
+    pub fn add_group_to_doc(
+        &self,
+        doc_id: String,
+        group_id: String,
+        #[wasm_bindgen(unchecked_param_type = "Access | undefined")] access: JsValue,
+    ) -> Result<(), JsValue> {
+        let access: Access = if access.is_undefined() || access.is_null() {
+            Access::Write
+        } else {
+            serde_wasm_bindgen::from_value(access).map_err(BeelayError::invalid_argument)?
+        };
+
+        self.with_administered_doc(&doc_id, |ctx_groups, doc| {
+            if !ctx_groups.contains_key(&group_id) {
+                return Err(BeelayError::UnknownGroup.into());
+            }
+            doc.members.insert_group(group_id, access);
+            Ok(())
+        })?;
+        members::enforce(self.id, &doc_id, &[])
+    }
+
+    /// Remove a group from a document's members. Requires admin access to the document.
+    #[wasm_bindgen(js_name = removeGroupFromDoc)]
+    pub fn remove_group_from_doc(&self, doc_id: String, group_id: String) -> Result<bool, JsValue> {
+        let (removed, peers) = self.with_administered_doc(&doc_id, |groups, doc| {
+            let peers = groups
+                .get(&group_id)
+                .map(|group| group.members().cloned().collect::<Vec<_>>())
+                .unwrap_or_default();
+            Ok((doc.members.remove_group(&group_id), peers))
+        })?;
+        members::enforce(self.id, &doc_id, &peers)?;
+        Ok(removed)
+    }
+
+    /// Pack up a document for another handle to take over with `adoptDocState`, such as
+    /// when moving work from a SharedWorker to a dedicated worker.
+    ///
+    /// The result includes the indexes built up as the document's commits were applied,
+    /// and can be transferred to another worker. This handle keeps the document, so
+    /// nothing is lost if adopting it fails; stop it once the other side has. Encrypted
+    /// documents can't be handed over, and throw `Unsupported`.
+    #[wasm_bindgen(js_name = exportDocState)]
+    pub fn export_doc_state(&self, doc_id: String) -> Result<Uint8Array, JsValue> {
+        let state = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            if doc.encryption.is_some() {
+                // The adopting handle would store the commits in the clear
+                return Err(BeelayError::Unsupported(
+                    "encrypted documents can't be handed over".to_string(),
+                )
+                .into());
+            }
+            Ok::<_, JsValue>(DocState::of(doc_id.clone(), doc))
+        })?;
+
+        let bytes = state.encode().map_err(BeelayError::InvalidSnapshot)?;
+        Ok(Uint8Array::from(bytes.as_slice()))
+    }
+
+    /// Take over a document exported with `exportDocState` by another handle.
+    ///
+    /// The document keeps its ID, membership, heads version, statistics, and unsynced
+    /// local work, none of which is rebuilt from its history. Returns the document's ID.
+    #[wasm_bindgen(js_name = adoptDocState)]
+    pub async fn adopt_doc_state(&self, bytes: Uint8Array) -> Result<String, JsValue> {
+        let state = DocState::decode(&bytes.to_vec()).map_err(BeelayError::InvalidSnapshot)?;
+        let doc_id = state.doc_id.clone();
+        let (owner, storage, summaries) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            if ctx.documents.contains_key(&doc_id) {
+                return Err(JsValue::from(BeelayError::DocumentExists));
+            }
+            ctx.limits.check_documents(ctx.documents.len(), 1)?;
+            ctx.limits.check_commits(0, &state.commits)?;
+            let storage = DocStorage::new(ctx.backend.as_ref(), state.sed_id, state.codec);
+            Ok((ctx.peer_id(), storage, ctx.summaries.clone()))
+        })?;
+
+        let reports = RoundReports::new(self.id, doc_id.clone());
+        let doc_ctx = DocumentCtx::adopt(state, owner, storage, summaries, reports).await?;
+        let heads = sorted_heads(&doc_ctx.commits);
+
+        let watchers = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if ctx.documents.contains_key(&doc_id) {
+                return Err(JsValue::from(BeelayError::DocumentExists));
+            }
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            ctx.changes.append(&doc_id, &doc_ctx.commits);
+            ctx.documents.insert(doc_id.clone(), doc_ctx);
+            ctx.persist()?;
+            ctx.save_feed()?;
+            Ok(ctx.heads_watchers.values().cloned().collect::<Vec<_>>())
+        })?;
+        diagnostics::track(ResourceKind::Document, self.id, doc_id.clone());
+        start_syncing(self.id, &doc_id)?;
+        notify_heads(&doc_id, &heads, &watchers)?;
+
+        Ok(doc_id)
+    }
+
+    /// Serialize the whole handle, its identity, groups, and every document's commits,
+    /// seen commits, and heads, for `load` to restore from its `snapshot` option.
+    ///
+    /// This is for embedders without IndexedDB, such as React Native WebViews or
+    /// Electron, to persist the handle however they can. The snapshot isn't encrypted
+    /// and holds the handle's signing key, so keep it somewhere safe, or use
+    /// `exportEncryptedBackup` instead.
+    #[wasm_bindgen(js_name = save)]
+    pub fn save(&self) -> Result<Uint8Array, JsValue> {
+        let snapshot = HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(|ctx| ctx.snapshot(true))
+                .ok_or(BeelayError::InvalidHandle)
+        })?;
+
+        let bytes = snapshot.encode().map_err(BeelayError::InvalidSnapshot)?;
+        Ok(Uint8Array::from(bytes.as_slice()))
+    }
+
+    /// Graceful shutdown. Freeing the handle's last object does the same.
+    pub fn stop(&self) {
+        stop_handle(self.id);
+    }
+
+    /// Count the live handles, documents, subscriptions, listeners, connections, and blob
+    /// views, across every handle, and list the ones that look leaked, each with a
+    /// `reason`.
+    ///
+    /// A resource looks leaked when something holds on to it that shouldn't: a stopped
+    /// handle whose `Beelay` objects are still referenced (`"stoppedButReferenced"`), a
+    /// subscription or listener made through an object freed without `close()`
+    /// (`"ownerFreed"`), or a connection still open after its handle stopped
+    /// (`"outlivedHandle"`). Build with the `debug` feature to include the JS stack each
+    /// one was created from.
+    #[wasm_bindgen(js_name = diagnostics)]
+    pub fn diagnostics() -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(&diagnostics::report()).map_err(JsValue::from)
+    }
+
+    /// Counts and latencies of the engine's internal tasks, across every handle, as
+    /// `{ storage, sync, hooks }`.
+    ///
+    /// Each is `{ inFlight, completed, failed, meanMs, maxMs }`: storage operations on
+    /// documents' data, sync rounds with the sync server, and calls into the app's
+    /// callbacks. A stall shows up as tasks piling up in flight, or a high `maxMs`,
+    /// under whichever of the three is to blame.
+    #[wasm_bindgen(js_name = taskStats)]
+    pub fn task_stats() -> Result<JsValue, JsValue> {
+        serde_wasm_bindgen::to_value(&tasks::report()).map_err(JsValue::from)
+    }
+
+    /// Start (or, with `false`, stop) recording every internal task as a User Timing
+    /// measure named `subduction:storage`, `subduction:sync`, or `subduction:hook`, so
+    /// that tasks show up in the browser's performance tools.
+    #[wasm_bindgen(js_name = traceTasks)]
+    pub fn trace_tasks(enabled: bool) {
+        tasks::set_trace(enabled);
+    }
+
+    /// Wait until every document syncing with a peer is in sync with it: no batch sync
+    /// with the peer is outstanding, and the peer is known to have the document's heads.
+    ///
+    /// `peerId` names a peer connected with `connectPeer`. Any other value, or `null`,
+    /// means the sync server. Documents are batch synced with the peer until they're in
+    /// sync, which also confirms commits pushed to it since the last batch sync.
+    ///
+    /// Resolves to `{ synced: true }` once they are, or `{ synced: false }` if
+    /// `timeoutMs` passes first. Without a timeout, it waits as long as it takes.
+    #[wasm_bindgen(js_name = waitUntilSynced, unchecked_return_type = "WaitResult")]
+    pub async fn wait_until_synced(
+        &self,
+        peer_id: Option<String>,
+        timeout_ms: Option<u32>,
+    ) -> Result<JsValue, JsValue> {
+        let deadline = timeout_ms.map(|timeout_ms| js_sys::Date::now() + f64::from(timeout_ms));
+        let direct = peer_id
+            .and_then(|peer_id| hex::decode(peer_id).ok())
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(PeerId::new);
+        let (server, documents) = HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(|ctx| {
+                    let documents = ctx
+                        .documents
+                        .iter()
+                        .map(|(doc_id, doc)| (doc_id.clone(), doc.subduction.clone(), doc.sed_id))
+                        .collect::<Vec<_>>();
+                    (ctx.sync.as_ref().map(|sync| sync.server()), documents)
+                })
+                .ok_or_else(|| JsValue::from(BeelayError::InvalidHandle))
+        })?;
+
+        let mut peer = server;
+        if let Some(direct) = direct {
+            for (_, engine, _) in &documents {
+                if engine.peer_ids().await.contains(&direct) {
+                    peer = Some(direct);
+                    break;
+                }
+            }
+        }
+        // Without a server or the peer, nothing is syncing with it
+        let Some(peer) = peer else {
+            return serde_wasm_bindgen::to_value(&WaitResult { synced: true })
+                .map_err(JsValue::from);
+        };
+        let mut waiting = Vec::new();
+        for (doc_id, engine, sed_id) in documents {
+            if engine.peer_ids().await.contains(&peer) {
+                waiting.push((doc_id, engine, sed_id));
+            }
+        }
+
+        loop {
+            let mut unsynced = Vec::with_capacity(waiting.len());
+            for (doc_id, engine, sed_id) in waiting {
+                if !engine.is_synced_with(peer, sed_id).await {
+                    let timeout = deadline.map(|deadline| {
+                        Duration::from_secs_f64((deadline - js_sys::Date::now()).max(0.0) / 1000.0)
+                    });
+                    tasks::timed(
+                        TaskKind::Sync,
+                        engine.request_peer_batch_sync(&peer, sed_id, timeout),
+                    )
+                    .await
+                    .map_err(BeelayError::sync)?;
+                    pull_engine_commits(self.id, &doc_id).await?;
+                }
+                if !engine.is_synced_with(peer, sed_id).await {
+                    unsynced.push((doc_id, engine, sed_id));
+                }
+            }
+            waiting = unsynced;
+
+            let synced = waiting.is_empty();
+            let remaining_ms = deadline.map(|deadline| deadline - js_sys::Date::now());
+            if synced || remaining_ms.is_some_and(|remaining_ms| remaining_ms <= 0.0) {
+                return serde_wasm_bindgen::to_value(&WaitResult { synced })
+                    .map_err(JsValue::from);
+            }
+            let pause_ms = remaining_ms.map_or(SYNC_RETRY_MS, |remaining_ms| {
+                SYNC_RETRY_MS.min(remaining_ms)
+            });
+            socket::sleep(Duration::from_secs_f64(pause_ms / 1000.0)).await;
+        }
+    }
+
+    /// This handle's peer ID, for the app to pass to `connectPeer` on the other end of a
+    /// data channel.
+    #[wasm_bindgen(js_name = peerId)]
+    pub fn peer_id(&self) -> Result<String, JsValue> {
+        HANDLES.with(|handles| {
+            handles
+                .borrow()
+                .get(&self.id)
+                .map(HandleCtx::peer_id)
+                .ok_or_else(|| BeelayError::InvalidHandle.into())
+        })
+    }
+}
+
+impl Beelay {
+    fn with_owned_group<T>(
+        &self,
+        group_id: &str,
+        f: impl FnOnce(&mut Group, &SigningKey) -> T,
+    ) -> Result<T, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let owner = ctx.peer_id();
+            let group = ctx
+                .groups
+                .get_mut(group_id)
+                .ok_or(BeelayError::UnknownGroup)?;
+            if group.owner() != owner {
+                return Err(BeelayError::AccessDenied(
+                    "only the group owner can change its members",
+                )
+                .into());
+            }
+            let result = f(group, &ctx.signing_key);
+            ctx.persist()?;
+            Ok(result)
+        })
+    }
+
+    fn with_administered_doc<T>(
+        &self,
+        doc_id: &str,
+        f: impl FnOnce(&HashMap<String, Group>, &mut DocumentCtx) -> Result<T, JsValue>,
+    ) -> Result<T, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if !ctx.is_admin_of(doc_id) {
+                return Err(if ctx.documents.contains_key(doc_id) {
+                    JsValue::from(BeelayError::AccessDenied("admin access required"))
+                } else {
+                    JsValue::from(BeelayError::UnknownDocument)
+                });
+            }
+
+            let HandleCtx {
+                documents, groups, ..
+            } = ctx;
+            let doc = documents
+                .get_mut(doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            let result = f(groups, doc)?;
+            ctx.persist()?;
+            Ok(result)
+        })
     }
 }
 
-#[wasm_bindgen(js_name = createMemoryStorageAdapter)]
-pub fn create_memory_storage_adapter() -> MemoryStorageAdapter {
-    MemoryStorageAdapter::new()
+impl HandleCtx {
+    /// A new subscription ID, for a subscription made through facade `owner`.
+    fn new_subscription(&mut self, owner: u32) -> u32 {
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.subscription_owners.insert(id, owner);
+        id
+    }
+
+    /// Keep the access requests the sync server has passed on since the last call.
+    fn collect_access_requests(&mut self) {
+        if let Some(sync) = &self.sync {
+            for request in sync.take_access_requests() {
+                self.access_requests.insert(request);
+            }
+        }
+    }
+
+    /// Take a received access request out to decide it, if it's for a document this
+    /// handle administers.
+    fn take_access_request(&mut self, request_id: &str) -> Option<AccessRequest> {
+        self.collect_access_requests();
+        let mut inbox = std::mem::take(&mut self.access_requests);
+        let request = inbox.take(request_id, |doc_id| self.is_admin_of(doc_id));
+        self.access_requests = inbox;
+        request
+    }
+
+    /// This handle's identity: its hex-encoded verifying key.
+    fn peer_id(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().as_bytes())
+    }
+
+    /// Everything needed to recreate the handle, with or without the documents' commits.
+    fn snapshot(&self, with_commits: bool) -> Snapshot {
+        let documents = self
+            .documents
+            .iter()
+            .map(|(doc_id, doc)| {
+                let (commits, seen, heads) = if with_commits {
+                    let mut seen = doc.seen.iter().cloned().collect::<Vec<_>>();
+                    seen.sort();
+                    // Collected commits are ancestors of the rest, so they go first
+                    let mut commits = doc.collected_commits();
+                    commits.extend(doc.commits.iter().map(CommitRecord::to_input));
+                    (commits, seen, sorted_heads(&doc.commits))
+                } else {
+                    (Vec::new(), Vec::new(), Vec::new())
+                };
+                DocumentSnapshot {
+                    doc_id: doc_id.clone(),
+                    sed_id: doc.sed_id,
+                    commits,
+                    members: doc.members.clone(),
+                    codec: Storage::<Local>::header(doc.subduction.storage()).codec(),
+                    ttl_secs: doc.ttl.map(|ttl| ttl.as_secs()),
+                    seen,
+                    heads,
+                    frozen: doc.frozen.clone(),
+                    encryption: doc.encryption.as_ref().map(Encryption::id),
+                }
+            })
+            .collect();
+
+        Snapshot::new(
+            self.signing_key.to_bytes(),
+            documents,
+            self.groups.clone(),
+            self.keys.as_ref().map(Keys::archive),
+        )
+    }
+
+    /// Save the handle's identity, groups, and documents to its storage, if it persists them.
+    ///
+    /// Commits are left out: each document's storage saves those as they're applied.
+    fn persist(&self) -> Result<(), JsValue> {
+        let Some(backend) = &self.backend else {
+            return Ok(());
+        };
+        Ok(backend.save_handle(&self.snapshot(false))?)
+    }
+
+    /// Save the changes feed to the handle's storage, if it persists.
+    fn save_feed(&self) -> Result<(), JsValue> {
+        let Some(backend) = &self.backend else {
+            return Ok(());
+        };
+        Ok(backend.save_feed(&self.changes)?)
+    }
+
+    fn is_admin_of(&self, doc_id: &str) -> bool {
+        self.documents
+            .get(doc_id)
+            .is_some_and(|doc| doc.members.is_admin(&self.peer_id(), &self.groups))
+    }
+
+    /// Buffer newly applied commits for every subscriber to `doc_id`.
+    ///
+    /// Subscriptions with a non-zero window get a flush scheduled on the JS event loop.
+    /// Returns true if some subscription wants its notifications delivered immediately.
+    fn notify(&mut self, doc_id: &str, commits: &[CommitRecord], handle_id: u32) -> bool {
+        if commits.is_empty() {
+            return false;
+        }
+
+        let mut immediate = false;
+        for (sub_id, sub) in &mut self.subscriptions {
+            if sub.doc_id != doc_id {
+                continue;
+            }
+
+            let mut delay = None;
+            for record in commits {
+                delay = delay.or(sub.enqueue(record.to_output()));
+            }
+
+            match delay {
+                Some(0) => immediate = true,
+                Some(delay_ms) => {
+                    let sub_id = *sub_id;
+                    notify::schedule(delay_ms, move || {
+                        let _ = flush_notifications(handle_id, Some(sub_id));
+                    });
+                }
+                None => {}
+            }
+        }
+        immediate
+    }
+}
+
+/// Remove handle `handle_id` from the registry and shut down its connections.
+fn stop_handle(handle_id: u32) {
+    let handle = HANDLES.with(|handles| handles.borrow_mut().remove(&handle_id));
+    if handle.as_ref().is_some_and(|ctx| ctx.strict) {
+        strict::note_stopped(handle_id);
+    }
+    if let Some(tabs) = handle.as_ref().and_then(|ctx| ctx.tabs.as_ref()) {
+        tabs.close();
+    }
+    #[cfg(feature = "accept")]
+    for accepted in handle.iter().flat_map(|ctx| ctx.accepted.values()) {
+        accepted.shutdown();
+    }
+    if let Some(sync) = handle.and_then(|ctx| ctx.sync) {
+        sync.shutdown();
+    }
+    diagnostics::untrack_handle(handle_id);
+}
+
+/// Apply commits to a document and notify its subscribers.
+///
+/// Commits applied before a failing one are kept (and notified). If `if_heads_version`
+/// is given and the document has moved on from it, nothing is applied. Synced commits
+/// that diverge from unsynced local work are reported to the conflict listeners.
+///
+/// Returns the number of previously unseen commits that were applied, and the
+/// document's heads version afterwards.
+async fn apply_commits(
+    handle_id: u32,
+    doc_id: &str,
+    commits: &[CommitInput],
+    origin: CommitOrigin,
+    if_heads_version: Option<u64>,
+) -> Result<(usize, u64), JsValue> {
+    let (mut doc_ctx, peer_id, signer, signed, merge_policy, watched) = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        let merge_policy = ctx.merge_policies.get(doc_id).cloned();
+        let watched = !ctx.heads_watchers.is_empty();
+        let doc = ctx
+            .documents
+            .get(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        if ctx.strict && origin != CommitOrigin::Remote {
+            let target = format!("document {doc_id}");
+            strict::check_commits(doc_id, &target, &ctx.documents, commits)?;
+        }
+        // Remote commits were checked as they were pulled from the engine
+        if ctx.strict_hashes && origin != CommitOrigin::Remote {
+            commits.iter().try_for_each(check_hash)?;
+        }
+        let signer = ctx.sign_commits.then(|| ctx.signing_key.clone());
+        let signed = match &signer {
+            Some(signer) if origin == CommitOrigin::Local => Some(
+                commits
+                    .iter()
+                    .map(|commit| signature::sign(commit, signer))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+            _ if origin != CommitOrigin::Remote => {
+                commits.iter().try_for_each(signature::verify)?;
+                None
+            }
+            _ => None,
+        };
+        doc.check_unfrozen(commits.iter().map(|commit| &commit.hash))?;
+        ctx.limits.check_commits(
+            doc.stats.content_bytes(),
+            commits.iter().filter(|commit| !doc.seen.contains(&commit.hash)),
+        )?;
+        if let Some(expected) = if_heads_version {
+            let actual = doc.heads_version();
+            if actual != expected {
+                return Err(JsValue::from(BeelayError::HeadsVersionConflict { expected, actual }));
+            }
+        }
+        let doc = ctx
+            .documents
+            .remove(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        ctx.checked_out.take(doc_id, doc.sed_id);
+        Ok((doc, ctx.peer_id(), signer, signed, merge_policy, watched))
+    })?;
+    let commits = signed.as_deref().unwrap_or(commits);
+    let local_author = (origin == CommitOrigin::Local).then_some(peer_id.as_str());
+    let heads_before = watched.then(|| sorted_heads(&doc_ctx.commits));
+
+    let applied_from = doc_ctx.commits.len();
+    let mut result = Ok(());
+    for commit in commits {
+        if origin == CommitOrigin::Remote {
+            doc_ctx.record_commit(commit, None);
+            continue;
+        }
+        result = doc_ctx.apply_commit(commit, local_author).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let synced = matches!(origin, CommitOrigin::Sync | CommitOrigin::Remote)
+        && doc_ctx.commits.len() > applied_from;
+
+    let conflict = conflict::track(
+        doc_id,
+        origin,
+        &doc_ctx.commits,
+        applied_from,
+        &mut doc_ctx.unsynced,
+    );
+    if let Some(policy) = merge_policy.filter(|_| synced && result.is_ok()) {
+        result = merge_heads(&policy, doc_id, &mut doc_ctx, &peer_id, signer.as_ref()).await;
+    }
+    let applied = doc_ctx.commits.len() - applied_from;
+    let moved_heads = heads_before.and_then(|before| {
+        let after = sorted_heads(&doc_ctx.commits);
+        (after != before).then_some(after)
+    });
+
+    let (immediate, heads_version, listeners, watchers) = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        if !ctx.checked_out.give_back(doc_id) {
+            return Err(JsValue::from(BeelayError::UnknownDocument));
+        }
+        if applied > 0 {
+            ctx.load_cache.invalidate(|(cached, _, _)| cached == doc_id);
+        }
+        ctx.changes.append(doc_id, &doc_ctx.commits[applied_from..]);
+        let immediate = ctx.notify(doc_id, &doc_ctx.commits[applied_from..], handle_id);
+        let heads_version = doc_ctx.heads_version();
+        ctx.documents.insert(doc_id.to_string(), doc_ctx);
+        // Encrypting under a new key changes the keys the handle saves
+        if ctx.keys.as_ref().is_some_and(Keys::take_changed) {
+            ctx.persist()?;
+        }
+        if applied > 0 {
+            ctx.save_feed()?;
+        }
+        let listeners = if conflict.is_some() {
+            ctx.conflict_listeners.values().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        let watchers = if moved_heads.is_some() {
+            ctx.heads_watchers.values().cloned().collect()
+        } else {
+            Vec::new()
+        };
+        Ok::<_, JsValue>((immediate, heads_version, listeners, watchers))
+    })?;
+
+    if immediate {
+        flush_notifications(handle_id, None)?;
+    }
+    if let Some(heads) = &moved_heads {
+        notify_heads(doc_id, heads, &watchers)?;
+    }
+    if let Some(conflict) = conflict {
+        notify_conflict(&conflict, &listeners)?;
+    }
+    result.map(|()| (applied, heads_version))
+}
+
+/// Merge a document's heads with `policy`, if it has more than one.
+///
+/// The merge is local work, so it stays unsynced until a synced commit builds on it.
+async fn merge_heads(
+    policy: &MergePolicy,
+    doc_id: &str,
+    doc_ctx: &mut DocumentCtx,
+    peer_id: &str,
+    signer: Option<&SigningKey>,
+) -> Result<(), JsValue> {
+    let heads = conflict::heads(&doc_ctx.commits);
+    if heads.len() < 2 {
+        return Ok(());
+    }
+    let heads = doc_ctx
+        .commits
+        .iter()
+        .filter(|record| heads.contains(&record.hash))
+        .collect::<Vec<_>>();
+    let Some(mut merge) = policy.merge(doc_id, &heads)? else {
+        return Ok(());
+    };
+    if let Some(signer) = signer {
+        merge = signature::sign(&merge, signer)?;
+    }
+
+    doc_ctx.apply_commit(&merge, Some(peer_id)).await?;
+    doc_ctx.unsynced.insert(merge.hash);
+    Ok(())
+}
+
+/// Create a document from each of `batch`, storing them together and then starting to
+/// sync them all. Returns their IDs, in the same order.
+async fn create_documents(
+    handle_id: u32,
+    batch: Vec<CreateDocArgs>,
+) -> Result<Vec<String>, JsValue> {
+    strict::check_running(handle_id, "createDoc")?;
+    let random = random_source(handle_id)?;
+    let (owner, backend, summaries, signer) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        if ctx.strict {
+            for args in &batch {
+                let initial = std::slice::from_ref(&args.initial_commit);
+                strict::check_commits("", "a new document", &ctx.documents, initial)?;
+            }
+        }
+        if ctx.strict_hashes {
+            batch
+                .iter()
+                .try_for_each(|args| check_hash(&args.initial_commit))?;
+        }
+        ctx.limits.check_documents(ctx.documents.len(), batch.len())?;
+        for args in &batch {
+            ctx.limits.check_commits(0, std::iter::once(&args.initial_commit))?;
+        }
+        let backend = ctx.backend.as_ref().map(Backend::batched);
+        let signer = ctx.sign_commits.then(|| ctx.signing_key.clone());
+        Ok::<_, JsValue>((ctx.peer_id(), backend, ctx.summaries.clone(), signer))
+    })?;
+    let keys = if batch.iter().any(|args| args.encrypted) {
+        Some(encryption::handle_keys(handle_id).await?)
+    } else {
+        None
+    };
+
+    let mut created = Vec::with_capacity(batch.len());
+    for mut args in batch {
+        let codec = args
+            .codec
+            .as_deref()
+            .map(str::parse::<Codec>)
+            .transpose()
+            .map_err(BeelayError::invalid_argument)?
+            .unwrap_or_default();
+        let doc_id = random.hex_string(16)?;
+        let sed_id = SedimentreeId::new(random.bytes()?);
+
+        let storage = DocStorage::new(backend.as_ref(), sed_id, codec);
+        let reports = RoundReports::new(handle_id, doc_id.clone());
+        let mut doc_ctx =
+            DocumentCtx::new(sed_id, owner.clone(), storage, summaries.clone(), reports);
+        if let Some(keys) = keys.as_ref().filter(|_| args.encrypted) {
+            let initial = parse_digest(&args.initial_commit.hash)?;
+            doc_ctx.encryption = Some(Encryption::create(keys, initial).await?);
+        }
+        if let Some(signer) = &signer {
+            args.initial_commit = signature::sign(&args.initial_commit, signer)?;
+        }
+        doc_ctx
+            .apply_commit(&args.initial_commit, Some(&owner))
+            .await?;
+        created.push((doc_id, doc_ctx));
+    }
+    if let Some(backend) = &backend {
+        backend.commit().await?;
+    }
+
+    let doc_ids = created
+        .iter()
+        .map(|(doc_id, _)| doc_id.clone())
+        .collect::<Vec<_>>();
+    let heads = created
+        .iter()
+        .map(|(_, doc_ctx)| sorted_heads(&doc_ctx.commits))
+        .collect::<Vec<_>>();
+    let watchers = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        for (doc_id, doc_ctx) in created {
+            ctx.changes.append(&doc_id, &doc_ctx.commits);
+            ctx.documents.insert(doc_id, doc_ctx);
+        }
+        ctx.persist()?;
+        ctx.save_feed()?;
+        Ok::<_, JsValue>(ctx.heads_watchers.values().cloned().collect::<Vec<_>>())
+    })?;
+    for (doc_id, heads) in doc_ids.iter().zip(&heads) {
+        diagnostics::track(ResourceKind::Document, handle_id, doc_id.clone());
+        start_syncing(handle_id, doc_id)?;
+        notify_heads(doc_id, heads, &watchers)?;
+    }
+    Ok(doc_ids)
+}
+
+/// Reopen the documents in a handle saved to its storage by a previous load.
+async fn reopen_documents(handle_id: u32, saved: Snapshot) -> Result<(), JsValue> {
+    let (signing_key, random) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        Ok::<_, JsValue>((ctx.signing_key.clone(), ctx.random.clone()))
+    })?;
+    let keys = encryption::restore_keys(saved.keyhive.as_deref(), &signing_key, random).await?;
+    let (owner, backend, summaries) = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        ctx.groups = access::verified_groups(saved.groups, &ctx.signing_key);
+        ctx.keys = keys.clone();
+        Ok::<_, JsValue>((ctx.peer_id(), ctx.backend.clone(), ctx.summaries.clone()))
+    })?;
+
+    for document in saved.documents {
+        let storage = DocStorage::new(backend.as_ref(), document.sed_id, document.codec);
+        let reports = RoundReports::new(handle_id, document.doc_id.clone());
+        let mut doc_ctx = DocumentCtx::new(
+            document.sed_id,
+            owner.clone(),
+            storage,
+            summaries.clone(),
+            reports,
+        );
+        doc_ctx.encryption = encryption::restore(keys.as_ref(), document.encryption).await?;
+        doc_ctx.subduction.hydrate().await?;
+        doc_ctx.restore_ttl(document.ttl_secs).await?;
+        doc_ctx.restore_freeze(document.frozen);
+        doc_ctx.members = document.members;
+
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&handle_id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            ctx.documents.insert(document.doc_id.clone(), doc_ctx);
+            Ok::<_, JsValue>(())
+        })?;
+        diagnostics::track(ResourceKind::Document, handle_id, document.doc_id.clone());
+        // Commits left out for their signatures were already reported when they synced
+        pull_signed_commits(handle_id, &document.doc_id).await?;
+        start_syncing(handle_id, &document.doc_id)?;
+    }
+    Ok(())
+}
+
+/// Replace the identity, documents, and groups of handle `handle_id` with those in
+/// `snapshot`, which holds the documents' commits, and start syncing them.
+///
+/// Returns the IDs of the restored documents. Subscriptions are kept.
+async fn restore_snapshot(handle_id: u32, snapshot: Snapshot) -> Result<Vec<String>, JsValue> {
+    let signing_key = SigningKey::from_bytes(&snapshot.signing_key);
+    let owner = hex::encode(signing_key.verifying_key().as_bytes());
+    let (backend, summaries, random) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        ctx.limits.check_documents(0, snapshot.documents.len())?;
+        for document in &snapshot.documents {
+            ctx.limits.check_commits(0, &document.commits)?;
+        }
+        Ok::<_, JsValue>((
+            ctx.backend.clone(),
+            ctx.summaries.clone(),
+            ctx.random.clone(),
+        ))
+    })?;
+    let keys = encryption::restore_keys(snapshot.keyhive.as_deref(), &signing_key, random).await?;
+    if let Some(backend) = &backend {
+        backend.clear_documents().await?;
+    }
+
+    let mut documents = HashMap::with_capacity(snapshot.documents.len());
+    for document in snapshot.documents {
+        let storage = DocStorage::new(backend.as_ref(), document.sed_id, document.codec);
+        let reports = RoundReports::new(handle_id, document.doc_id.clone());
+        let mut doc_ctx = DocumentCtx::new(
+            document.sed_id,
+            owner.clone(),
+            storage,
+            summaries.clone(),
+            reports,
+        );
+        doc_ctx.encryption = encryption::restore(keys.as_ref(), document.encryption).await?;
+        for commit in &document.commits {
+            doc_ctx.apply_commit(commit, None).await?;
+        }
+        // Snapshots from before heads were kept don't have any to check
+        if !document.heads.is_empty() && sorted_heads(&doc_ctx.commits) != document.heads {
+            return Err(BeelayError::InvalidSnapshot(format!(
+                "document {}'s commits don't end in its heads",
+                document.doc_id
+            ))
+            .into());
+        }
+        doc_ctx.restore_ttl(document.ttl_secs).await?;
+        doc_ctx.restore_freeze(document.frozen);
+        doc_ctx.seen.extend(document.seen);
+        doc_ctx.members = document.members;
+        documents.insert(document.doc_id, doc_ctx);
+    }
+
+    let mut doc_ids = documents.keys().cloned().collect::<Vec<_>>();
+    doc_ids.sort();
+
+    HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        ctx.signing_key = signing_key;
+        ctx.keys = keys;
+        // The restored commits are new to the feed, even where they were in it before
+        for doc_id in ctx.documents.keys() {
+            ctx.changes.remove(doc_id);
+        }
+        for doc_id in &doc_ids {
+            ctx.changes.append(doc_id, &documents[doc_id].commits);
+        }
+        ctx.documents = documents;
+        ctx.groups = access::verified_groups(snapshot.groups, &ctx.signing_key);
+        // Restored documents restart their versions, so cached loads could collide.
+        ctx.load_cache.invalidate(|_| true);
+        ctx.save_feed()?;
+        ctx.persist()
+    })?;
+    diagnostics::untrack_documents(handle_id);
+    for doc_id in &doc_ids {
+        diagnostics::track(ResourceKind::Document, handle_id, doc_id.clone());
+        start_syncing(handle_id, doc_id)?;
+    }
+
+    Ok(doc_ids)
+}
+
+/// How often each handle expires the commits of its ephemeral documents.
+const EXPIRY_INTERVAL_MS: u32 = 5_000;
+
+/// Expire the commits of the handle's ephemeral documents every [`EXPIRY_INTERVAL_MS`],
+/// until the handle is stopped.
+fn schedule_expiry(handle_id: u32) {
+    notify::schedule(EXPIRY_INTERVAL_MS, move || {
+        if !HANDLES.with(|handles| handles.borrow().contains_key(&handle_id)) {
+            return;
+        }
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = expire_commits(handle_id).await;
+            schedule_expiry(handle_id);
+        });
+    });
+}
+
+/// Drop the commits of the handle's ephemeral documents that have outlived their TTL,
+/// from the documents and from storage.
+///
+/// A TTL that peers set is saved with the document here too.
+async fn expire_commits(handle_id: u32) -> Result<(), JsValue> {
+    let documents = HANDLES.with(|handles| {
+        handles.borrow().get(&handle_id).map_or_else(Vec::new, |ctx| {
+            ctx.documents
+                .iter()
+                .map(|(doc_id, doc)| (doc_id.clone(), doc.subduction.clone(), doc.sed_id))
+                .collect()
+        })
+    });
+    let now = SystemTime::UNIX_EPOCH + Duration::from_secs_f64(js_sys::Date::now() / 1000.0);
+
+    for (doc_id, engine, sed_id) in documents {
+        let ttl = engine.ttl(sed_id).await;
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let Some(ctx) = handles.get_mut(&handle_id) else {
+                return Ok(());
+            };
+            match ctx.documents.get_mut(&doc_id) {
+                Some(doc) if doc.ttl != ttl => {
+                    doc.ttl = ttl;
+                    ctx.persist()
+                }
+                _ => Ok(()),
+            }
+        })?;
+        if ttl.is_none() {
+            continue;
+        }
+
+        let report = engine
+            .run_lifecycle(&LifecycleRules::default(), now, None::<&DocStorage>)
+            .await
+            .map_err(BeelayError::storage)?;
+        let expired = report
+            .actions
+            .iter()
+            .filter_map(|(_, action)| match action {
+                LifecycleAction::CommitsExpired { commits } => Some(commits),
+                _ => None,
+            })
+            .flatten()
+            .map(|digest| hex::encode(digest.as_bytes()))
+            .collect::<HashSet<_>>();
+        if expired.is_empty() {
+            continue;
+        }
+
+        let backend = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles.get_mut(&handle_id)?;
+            ctx.documents.get_mut(&doc_id)?.forget_commits(&expired);
+            ctx.load_cache.invalidate(|(cached, _, _)| *cached == doc_id);
+            ctx.backend.clone()
+        });
+        if let Some(backend) = backend {
+            rewrite_storage(&backend, &engine, sed_id).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Replace everything stored for a document with what its engine still holds, so that
+/// dropped commits and their blobs go.
+async fn rewrite_storage(
+    backend: &Backend,
+    engine: &Subduction<Local, DocStorage, PeerConnection>,
+    sed_id: SedimentreeId,
+) -> Result<(), JsValue> {
+    let blob_of = |digest| async move {
+        engine
+            .get_local_blob(digest)
+            .await
+            .map_err(BeelayError::storage)
+    };
+    let mut commits = Vec::new();
+    for commit in engine.get_commits(sed_id).await.unwrap_or_default() {
+        let blob = blob_of(commit.blob().digest()).await?;
+        commits.push((commit, blob));
+    }
+    let mut chunks = Vec::new();
+    for chunk in engine.get_chunks(sed_id).await.unwrap_or_default() {
+        let blob = blob_of(chunk.summary().blob_meta().digest()).await?;
+        chunks.push((chunk, blob));
+    }
+
+    backend.delete_document(sed_id).await?;
+    let storage = engine.storage();
+    for (commit, blob) in commits {
+        storage.save_loose_commit(commit).await?;
+        if let Some(blob) = blob {
+            storage.save_blob(blob).await?;
+        }
+    }
+    for (chunk, blob) in chunks {
+        storage.save_chunk(chunk).await?;
+        if let Some(blob) = blob {
+            storage.save_blob(blob).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Start syncing a new or reopened document with the handle's sync server and the
+/// clients it serves.
+fn start_syncing(handle_id: u32, doc_id: &str) -> Result<(), JsValue> {
+    #[cfg(feature = "accept")]
+    accept::serve_document(handle_id, doc_id)?;
+    sync_with_server(handle_id, doc_id)
+}
+
+/// Start syncing a document over the handle's sync connection, if it has one.
+///
+/// The document's engine listens on its own connection from then on, and an initial
+/// batch sync brings in whatever the server already has.
+fn sync_with_server(handle_id: u32, doc_id: &str) -> Result<(), JsValue> {
+    let started = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles
+            .get_mut(&handle_id)
+            .ok_or(BeelayError::InvalidHandle)?;
+        let Some(sync) = ctx.sync.clone() else {
+            return Ok(None);
+        };
+        let doc = ctx
+            .documents
+            .get_mut(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        let conn = sync.connection(doc.sed_id, handle_id, doc_id.to_string());
+        let start_listening = !std::mem::replace(&mut doc.listening, true);
+        Ok::<_, JsValue>(Some((conn, doc.subduction.clone(), doc.sed_id, start_listening)))
+    })?;
+    let Some((conn, engine, sed_id, start_listening)) = started else {
+        return Ok(());
+    };
+
+    let doc_id = doc_id.to_string();
+    wasm_bindgen_futures::spawn_local(async move {
+        if engine.register(PeerConnection::Server(conn)).await.is_err() {
+            return;
+        }
+        if start_listening {
+            let listener = engine.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = listener.run().await;
+            });
+        }
+        let _ = tasks::timed(TaskKind::Sync, engine.request_all_batch_sync(sed_id, None)).await;
+        let _ = pull_engine_commits(handle_id, &doc_id).await;
+    });
+    Ok(())
+}
+
+/// Add the commits in a document's engine that the document hasn't seen yet, such as
+/// those stored by the sync connection or reloaded from IndexedDB, so they show up in
+/// loads and notifications like any synced commit. The commits inside bundles the
+/// document doesn't know yet are added too, and the bundles recorded.
+///
+/// Returns the number of commits added. Commits whose hash doesn't match their contents,
+/// unless the handle was loaded with `strictHashes: false`, and commits not signed by
+/// their author are left out, and then reported as a `HashMismatch` or
+/// `InvalidSignature` error.
+pub(crate) async fn pull_engine_commits(handle_id: u32, doc_id: &str) -> Result<usize, JsValue> {
+    match pull_signed_commits(handle_id, doc_id).await? {
+        (_, Some(rejected)) => Err(rejected.into()),
+        (applied, None) => Ok(applied),
+    }
+}
+
+/// [`pull_engine_commits`], returning the error for the first commit left out for
+/// its hash or signature, if any, along with the number of commits added.
+async fn pull_signed_commits(
+    handle_id: u32,
+    doc_id: &str,
+) -> Result<(usize, Option<BeelayError>), JsValue> {
+    let (engine, sed_id, known_bundles, encryption, strict_hashes) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles
+            .get(&handle_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        let doc = ctx
+            .documents
+            .get(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        let known = doc.bundles.iter().map(Bundle::chunk).collect::<HashSet<_>>();
+        let encryption = doc.encryption.clone();
+        Ok::<_, JsValue>((
+            doc.subduction.clone(),
+            doc.sed_id,
+            known,
+            encryption,
+            ctx.strict_hashes,
+        ))
+    })?;
+
+    let stored = engine.get_commits(sed_id).await.unwrap_or_default();
+    let unseen = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let seen = handles
+            .get(&handle_id)
+            .and_then(|ctx| ctx.documents.get(doc_id))
+            .map(|doc| &doc.seen);
+        stored
+            .into_iter()
+            .filter(|commit| {
+                seen.is_some_and(|seen| !seen.contains(&hex::encode(commit.digest().as_bytes())))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    let mut commits = Vec::with_capacity(unseen.len());
+    for commit in unseen {
+        let blob = engine
+            .get_local_blob(commit.blob().digest())
+            .await
+            .map_err(BeelayError::storage)?
+            .ok_or_else(|| BeelayError::storage("synced commit is missing its blob"))?;
+        let blob = match &encryption {
+            // Commits the handle can't decrypt are left out, as if they hadn't synced
+            Some(encryption) => match encryption.open_blob(blob.as_slice()).await {
+                Some(opened) => opened,
+                None => continue,
+            },
+            None => blob.into_contents(),
+        };
+        let (contents, meta) = meta::unframe(blob);
+        commits.push(CommitInput {
+            v: envelope::COMMIT_VERSION,
+            parents: commit
+                .parents()
+                .iter()
+                .map(|parent| hex::encode(parent.as_bytes()))
+                .collect(),
+            hash: hex::encode(commit.digest().as_bytes()),
+            contents,
+            meta,
+            extensions: Extensions::new(),
+        });
+    }
+
+    let mut bundles = Vec::new();
+    // Bundles hold their commits in the clear, so encrypted documents don't take them
+    let chunks = match encryption {
+        Some(_) => Vec::new(),
+        None => engine.get_chunks(sed_id).await.unwrap_or_default(),
+    };
+    for chunk in chunks {
+        if known_bundles.contains(&chunk.digest()) {
+            continue;
+        }
+        let Some(blob) = engine
+            .get_local_blob(chunk.summary().blob_meta().digest())
+            .await
+            .map_err(BeelayError::storage)?
+        else {
+            continue;
+        };
+        // Chunks that some other kind of peer made hold something else
+        if let Ok((bundle, inside)) = Bundle::from_chunk(&chunk, blob.as_slice()) {
+            commits.extend(inside);
+            bundles.push(bundle);
+        }
+    }
+
+    let mut hashes = HashSet::new();
+    commits.retain(|commit| hashes.insert(commit.hash.clone()));
+    // Peers can push anything, so commits whose hash doesn't match their contents or
+    // that aren't signed by their author are left out, and reported once the rest are
+    // applied
+    let mut rejected = None;
+    commits.retain(|commit| {
+        let checked = if strict_hashes {
+            subduction_client::commit::check_hash(&commit.hash, &commit.parents, &commit.contents)
+                .map_err(BeelayError::from)
+        } else {
+            Ok(())
+        };
+        match checked.and_then(|()| signature::verify(commit)) {
+            Ok(()) => true,
+            Err(err) => {
+                rejected.get_or_insert(err);
+                false
+            }
+        }
+    });
+    let commits = parents_first(commits);
+    let (applied, _) =
+        apply_commits(handle_id, doc_id, &commits, CommitOrigin::Remote, None).await?;
+
+    if !bundles.is_empty() {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            if let Some(ctx) = handles.get_mut(&handle_id) {
+                if let Some(doc) = ctx.documents.get_mut(doc_id) {
+                    for bundle in bundles {
+                        doc.record_bundle(bundle);
+                    }
+                }
+                ctx.load_cache.invalidate(|(cached, _, _)| cached == doc_id);
+            }
+        });
+    }
+    sync_status::note_peer_syncs(handle_id, doc_id).await;
+    Ok((applied, rejected))
+}
+
+/// Order commits so that each comes after any of its parents in the batch.
+fn parents_first(mut pending: Vec<CommitInput>) -> Vec<CommitInput> {
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let waiting = pending
+            .iter()
+            .map(|commit| commit.hash.clone())
+            .collect::<HashSet<_>>();
+        let (ready, blocked): (Vec<_>, Vec<_>) = pending
+            .into_iter()
+            .partition(|commit| !commit.parents.iter().any(|parent| waiting.contains(parent)));
+        if ready.is_empty() {
+            // A cycle can't come from real hashes; keep the rest in the order given.
+            ordered.extend(blocked);
+            break;
+        }
+        ordered.extend(ready);
+        pending = blocked;
+    }
+    ordered
+}
+
+/// A handle's random source, cloned so that a custom source may call back into the handle.
+fn random_source(handle_id: u32) -> Result<RandomSource, JsValue> {
+    HANDLES.with(|handles| {
+        handles
+            .borrow()
+            .get(&handle_id)
+            .map(|ctx| ctx.random.clone())
+            .ok_or_else(|| BeelayError::InvalidHandle.into())
+    })
+}
+
+/// Call every conflict listener with a `conflict-detected` event.
+fn notify_conflict(conflict: &Conflict, listeners: &[js_sys::Function]) -> Result<(), JsValue> {
+    let event = serde_wasm_bindgen::to_value(conflict).map_err(JsValue::from)?;
+    listeners
+        .iter()
+        .try_for_each(|listener| tasks::hook(|| listener.call1(&JsValue::NULL, &event)).map(drop))
+}
+
+/// A document's heads, sorted so that they can be compared.
+fn sorted_heads(commits: &[CommitRecord]) -> Vec<String> {
+    let mut heads = conflict::heads(commits);
+    heads.sort();
+    heads
+}
+
+/// The heads version of a document with `heads` (sorted): the first 53 bits of their
+/// digest, which a JS number holds exactly.
+fn heads_version(heads: &[String]) -> u64 {
+    let mut bytes = Vec::new();
+    for head in heads {
+        bytes.extend_from_slice(&(head.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(head.as_bytes());
+    }
+    let digest = Digest::hash(&bytes);
+    let mut first = [0; 8];
+    first.copy_from_slice(&digest.as_bytes()[..8]);
+    u64::from_le_bytes(first) & ((1 << 53) - 1)
+}
+
+/// Call every `watchAll` callback with a document's new heads.
+fn notify_heads(
+    doc_id: &str,
+    heads: &[String],
+    watchers: &[js_sys::Function],
+) -> Result<(), JsValue> {
+    let doc_id = JsValue::from_str(doc_id);
+    let heads = serde_wasm_bindgen::to_value(heads).map_err(JsValue::from)?;
+    watchers
+        .iter()
+        .try_for_each(|watcher| {
+            tasks::hook(|| watcher.call2(&JsValue::NULL, &doc_id, &heads)).map(drop)
+        })
+}
+
+/// Deliver buffered notifications for one subscription, or for all of them.
+fn flush_notifications(handle_id: u32, subscription_id: Option<u32>) -> Result<(), JsValue> {
+    // Take the batches first so that callbacks may call back into the handle.
+    let deliveries = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let Some(ctx) = handles.get_mut(&handle_id) else {
+            return Vec::new();
+        };
+
+        ctx.subscriptions
+            .iter_mut()
+            .filter(|(id, _)| subscription_id.is_none_or(|wanted| wanted == **id))
+            .filter_map(|(_, sub)| sub.take())
+            .collect::<Vec<PendingDelivery>>()
+    });
+
+    notify::deliver_all(deliveries)
+}
+
+impl CommitRecord {
+    fn to_output(&self) -> CommitOutput {
+        CommitOutput {
+            kind: "commit",
+            v: envelope::COMMIT_VERSION,
+            parents: self.parents.clone(),
+            hash: self.hash.clone(),
+            contents: Rc::clone(&self.contents),
+            meta: self.meta.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+
+    fn to_input(&self) -> CommitInput {
+        CommitInput {
+            v: envelope::COMMIT_VERSION,
+            parents: self.parents.clone(),
+            hash: self.hash.clone(),
+            contents: self.contents.to_vec(),
+            meta: self.meta.clone(),
+            extensions: self.extensions.clone(),
+        }
+    }
+}
+
+impl DocumentCtx {
+    /// A new, empty document, whose engine keeps its summaries in `summaries` and
+    /// reports its sync rounds to `reports`.
+    fn new(
+        sed_id: SedimentreeId,
+        owner: String,
+        storage: DocStorage,
+        summaries: Arc<SummaryCache>,
+        reports: RoundReports,
+    ) -> Self {
+        // Anything cached for the sedimentree describes an engine this one replaces
+        summaries.forget(sed_id);
+        let tree = Sedimentree::new(Vec::new(), Vec::new());
+        let subduction = Subduction::new(HashMap::from([(sed_id, tree)]), storage, HashMap::new())
+            .with_summary_cache(summaries)
+            .with_reports(Arc::new(reports));
+
+        Self {
+            sed_id,
+            subduction,
+            listening: false,
+            commits: Vec::new(),
+            seen: HashSet::new(),
+            members: Membership::with_owner(owner),
+            version: 0,
+            unsynced: HashSet::new(),
+            stats: DocStats::default(),
+            bundles: Vec::new(),
+            ttl: None,
+            peers: HashMap::new(),
+            frozen: None,
+            encryption: None,
+        }
+    }
+
+    /// Make the document ephemeral with the TTL it was saved with, if any, before it has
+    /// any connections.
+    async fn restore_ttl(&mut self, ttl_secs: Option<u64>) -> Result<(), JsValue> {
+        self.ttl = ttl_secs.map(Duration::from_secs);
+        self.subduction
+            .set_ttl(self.sed_id, self.ttl)
+            .await
+            .map_err(|err| BeelayError::StorageFailure(format!("{err:?}")).into())
+    }
+
+    /// Drop commits that have expired from the document.
+    fn forget_commits(&mut self, expired: &HashSet<String>) {
+        self.commits.retain(|record| !expired.contains(&record.hash));
+        for hash in expired {
+            self.seen.remove(hash);
+            self.unsynced.remove(hash);
+        }
+        self.version += 1;
+    }
+
+    /// Drop the commits `collected` by `gc`, which stay seen since their bundles hold them.
+    fn collect_commits(&mut self, collected: &HashSet<String>) {
+        self.commits.retain(|record| !collected.contains(&record.hash));
+        for hash in collected {
+            self.unsynced.remove(hash);
+        }
+    }
+
+    /// The commits `gc` collected, read back out of the bundles holding them.
+    fn collected_commits(&self) -> Vec<CommitInput> {
+        let recorded = self
+            .commits
+            .iter()
+            .map(|record| record.hash.as_str())
+            .collect::<HashSet<_>>();
+        self.bundles
+            .iter()
+            .filter_map(|bundle| bundle.commits().ok())
+            .flatten()
+            .filter(|commit| !recorded.contains(commit.hash.as_str()))
+            .collect()
+    }
+
+    /// Record a bundle made here or received from a peer, unless it's known already.
+    fn record_bundle(&mut self, bundle: Bundle) {
+        if self.bundles.iter().all(|known| known.chunk() != bundle.chunk()) {
+            self.bundles.push(bundle);
+            self.stats.record_bundle();
+        }
+    }
+
+    /// Apply a commit, attributing it to `local_author` if it doesn't name its author.
+    async fn apply_commit(
+        &mut self,
+        commit: &CommitInput,
+        local_author: Option<&str>,
+    ) -> Result<(), JsValue> {
+        if self.seen.contains(&commit.hash) {
+            return Ok(());
+        }
+
+        self.store_commit(commit, &commit.meta.attributed(local_author))
+            .await?;
+        self.record_commit(commit, local_author);
+        Ok(())
+    }
+
+    /// Add a commit to the document's engine with `meta`, without recording it in the
+    /// document.
+    async fn store_commit(
+        &mut self,
+        commit: &CommitInput,
+        meta: &CommitMeta,
+    ) -> Result<(), JsValue> {
+        let parents = commit
+            .parents
+            .iter()
+            .map(|parent| parse_digest(parent))
+            .collect::<Result<Vec<_>, _>>()?;
+        let digest = parse_digest(&commit.hash)?;
+        let framed = meta::frame(&commit.contents, meta)?;
+        let blob = match &self.encryption {
+            Some(encryption) => Blob::new(encryption.seal(digest, &parents, &framed).await?),
+            None => Blob::new(framed),
+        };
+        let loose = LooseCommit::new(digest, parents, blob.meta());
+
+        self.subduction
+            .add_commit(self.sed_id, &loose, blob.clone())
+            .await
+            .map(drop)
+            .map_err(|err| BeelayError::StorageFailure(format!("{err:?}")).into())
+    }
+
+    /// Recreate a document handed over with `exportDocState`, storing its commits in
+    /// `storage` and taking the rest of its state as it is.
+    async fn adopt(
+        state: DocState,
+        owner: String,
+        storage: DocStorage,
+        summaries: Arc<SummaryCache>,
+        reports: RoundReports,
+    ) -> Result<Self, JsValue> {
+        let mut doc = Self::new(state.sed_id, owner, storage, summaries, reports);
+        for commit in &state.commits {
+            doc.store_commit(commit, &commit.meta).await?;
+        }
+
+        doc.seen = state
+            .commits
+            .iter()
+            .map(|commit| commit.hash.clone())
+            .collect();
+        doc.commits = state
+            .commits
+            .into_iter()
+            .map(|commit| CommitRecord {
+                parents: commit.parents,
+                hash: commit.hash,
+                contents: Rc::from(commit.contents),
+                meta: commit.meta,
+                extensions: envelope::carried(&commit.extensions),
+            })
+            .collect();
+        doc.members = state.members;
+        doc.version = state.heads_version;
+        doc.unsynced = state.unsynced;
+        doc.stats = state.stats;
+        Ok(doc)
+    }
+
+    /// The token `addCommits` returns for the document's current heads.
+    fn heads_version(&self) -> u64 {
+        heads_version(&sorted_heads(&self.commits))
+    }
+
+    /// Add a commit that is already in the engine to the document.
+    fn record_commit(&mut self, commit: &CommitInput, local_author: Option<&str>) {
+        if !self.seen.insert(commit.hash.clone()) {
+            return;
+        }
+
+        let record = CommitRecord {
+            parents: commit.parents.clone(),
+            hash: commit.hash.clone(),
+            contents: Rc::from(commit.contents.as_slice()),
+            meta: commit.meta.attributed(local_author),
+            extensions: envelope::carried(&commit.extensions),
+        };
+        self.stats.record(&record, js_sys::Date::now());
+        self.commits.push(record);
+        self.version += 1;
+    }
+}
+
+fn parse_digest(hex_str: &str) -> Result<Digest, JsValue> {
+    #[cfg(feature = "perf-trace")]
+    let _span = sedimentree_core::perf::span(sedimentree_core::perf::Op::DigestParse);
+    subduction_client::commit::parse_digest(hex_str).map_err(|err| BeelayError::from(err).into())
+}
+
+/// A commit's hash: the digest of its parents' hashes, in order, then its contents.
+///
+/// Without parents, that's just the digest of the contents.
+pub(crate) fn commit_hash(parents: &[String], contents: &[u8]) -> String {
+    subduction_client::commit::commit_hash(parents, contents)
+}
+
+/// Check that `commit`'s hash is the digest of its contents, either alone or after its
+/// parents' hashes as [`commit_hash`] makes it.
+fn check_hash(commit: &CommitInput) -> Result<(), JsValue> {
+    subduction_client::commit::check_hash(&commit.hash, &commit.parents, &commit.contents)
+        .map_err(|err| BeelayError::from(err).into())
+}
+
+/// The hash of a commit with `parents` and `contents`, as handles check it.
+///
+/// Hashing the parents in as well keeps two commits making the same change in
+/// different places apart.
+#[wasm_bindgen(js_name = commitHash)]
+pub fn commit_hash_for(parents: Vec<String>, contents: &[u8]) -> String {
+    commit_hash(&parents, contents)
+}
+
+/// The commits inside a bundle's `contents`, as listed by `loadDocument`, parents first.
+#[wasm_bindgen(js_name = bundleCommits)]
+pub fn bundle_commits(contents: &[u8]) -> Result<JsValue, JsValue> {
+    let commits = bundle::decode(contents)?
+        .into_iter()
+        .map(|commit| CommitOutput {
+            kind: "commit",
+            v: envelope::COMMIT_VERSION,
+            extensions: envelope::carried(&commit.extensions),
+            parents: commit.parents,
+            hash: commit.hash,
+            contents: Rc::from(commit.contents),
+            meta: commit.meta,
+        })
+        .collect::<Vec<_>>();
+    envelope::to_js(&commits).map_err(JsValue::from)
 }
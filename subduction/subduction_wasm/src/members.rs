@@ -0,0 +1,162 @@
+//! Managing a document's members from JS: `addMember`, `removeMember`, and
+//! `listMembers`.
+//!
+//! Members are added by contact card (see [`crate::contact`]), which names the
+//! peer it belongs to and can't be forged, so `addMember` needs the `encryption`
+//! feature. A document's members are also who it syncs with: clients accepted with
+//! `acceptConnection` and peers connected with `connectPeer` only sync the
+//! documents they're members of, directly or through a group, and stop syncing a
+//! document as soon as they stop being one. Peers are matched by the key they
+//! proved in their connection's handshake, not the id the app passed in. The sync
+//! server is a relay rather than a peer, and syncs every document; a relay run
+//! with a members list (see `subduction-server --members`) enforces it itself.
+
+use serde::Serialize;
+use subduction_core::peer::id::PeerId;
+use wasm_bindgen::prelude::*;
+
+#[cfg(feature = "accept")]
+use crate::accept;
+#[cfg(feature = "encryption")]
+use crate::contact;
+use crate::{access::Access, error::BeelayError, Beelay, HANDLES};
+
+/// One entry of the result of `Beelay.listMembers`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MemberInfo {
+    peer_id: String,
+    access: Access,
+}
+
+/// Bring who document `doc_id` of handle `handle_id` syncs with in line with its
+/// members: start syncing it with accepted clients that have become members, and
+/// stop syncing it with those, and with the `removed` peers, that no longer are.
+pub(crate) fn enforce(handle_id: u32, doc_id: &str, removed: &[String]) -> Result<(), JsValue> {
+    #[cfg(feature = "accept")]
+    accept::serve_document(handle_id, doc_id)?;
+
+    let (mut engine, dropped) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        let doc = ctx
+            .documents
+            .get(doc_id)
+            .ok_or(BeelayError::UnknownDocument)?;
+        let dropped = removed
+            .iter()
+            .filter(|peer| doc.members.access(peer, &ctx.groups).is_none())
+            .filter_map(|peer| parse_peer_id(peer).ok())
+            .collect::<Vec<_>>();
+        Ok::<_, JsValue>((doc.subduction.clone(), dropped))
+    })?;
+    if !dropped.is_empty() {
+        wasm_bindgen_futures::spawn_local(async move {
+            for peer in dropped {
+                let _ = engine.disconnect_from_peer(&peer).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+/// [`enforce`] for every document of handle `handle_id`, such as when a group changes.
+pub(crate) fn enforce_all(handle_id: u32, removed: &[String]) -> Result<(), JsValue> {
+    let doc_ids = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let ctx = handles.get(&handle_id).ok_or(BeelayError::InvalidHandle)?;
+        Ok::<_, JsValue>(ctx.documents.keys().cloned().collect::<Vec<_>>())
+    })?;
+    doc_ids
+        .iter()
+        .try_for_each(|doc_id| enforce(handle_id, doc_id, removed))
+}
+
+/// A hex-encoded peer ID.
+fn parse_peer_id(peer_id: &str) -> Result<PeerId, BeelayError> {
+    hex::decode(peer_id)
+        .ok()
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .map(PeerId::new)
+        .ok_or(BeelayError::InvalidPeerId)
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Make the peer a contact card (from its `createContactCard`) belongs to a member
+    /// of a document, with `access` (`"read"`, `"write"`, or `"admin"`; defaults to
+    /// `"write"`), or change the access it has. The peer starts syncing the document
+    /// if it's connected.
+    ///
+    /// Requires admin access to the document. Throws `InvalidArgument` if the card
+    /// can't be read or isn't signed by the peer it names, and `AccessDenied` if it
+    /// would leave the document without an admin.
+    #[cfg(feature = "encryption")]
+    #[wasm_bindgen(js_name = addMember)]
+    pub fn add_member(
+        &self,
+        doc_id: String,
+        contact_card: String,
+        #[wasm_bindgen(unchecked_param_type = "Access | undefined")] access: JsValue,
+    ) -> Result<(), JsValue> {
+        let access: Access = if access.is_undefined() || access.is_null() {
+            Access::Write
+        } else {
+            serde_wasm_bindgen::from_value(access).map_err(BeelayError::invalid_argument)?
+        };
+        let card = contact::decode(&contact_card)?;
+        let peer = hex::encode(card.op().issuer().as_bytes());
+
+        self.with_administered_doc(&doc_id, |_, doc| {
+            if access != Access::Admin && doc.members.is_last_admin(&peer) {
+                return Err(BeelayError::AccessDenied("a document must keep an admin").into());
+            }
+            doc.members.insert(peer, access);
+            Ok(())
+        })?;
+        enforce(self.id, &doc_id, &[])
+    }
+
+    /// Remove a peer from a document's members, and stop syncing the document with it
+    /// unless it still has access through a group. Returns whether it was a member.
+    ///
+    /// Requires admin access to the document. Throws `AccessDenied` if the peer is the
+    /// document's only admin.
+    #[wasm_bindgen(js_name = removeMember)]
+    pub fn remove_member(&self, doc_id: String, peer_id: String) -> Result<bool, JsValue> {
+        let peer = hex::encode(parse_peer_id(&peer_id)?.as_bytes());
+        let removed = self.with_administered_doc(&doc_id, |_, doc| {
+            if doc.members.is_last_admin(&peer) {
+                return Err(BeelayError::AccessDenied("a document must keep an admin").into());
+            }
+            Ok(doc.members.remove(&peer))
+        })?;
+        enforce(self.id, &doc_id, &[peer])?;
+        Ok(removed)
+    }
+
+    /// A document's members, as `{ peerId, access }` sorted by peer ID. Groups made
+    /// members with `addGroupToDoc` aren't included.
+    #[wasm_bindgen(js_name = listMembers, unchecked_return_type = "Member[]")]
+    pub fn list_members(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        let mut members = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            Ok::<_, JsValue>(
+                doc.members
+                    .members()
+                    .map(|(peer, access)| MemberInfo {
+                        peer_id: peer.clone(),
+                        access,
+                    })
+                    .collect::<Vec<_>>(),
+            )
+        })?;
+        members.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        serde_wasm_bindgen::to_value(&members).map_err(JsValue::from)
+    }
+}
@@ -0,0 +1,113 @@
+//! Merge policies, which reconcile a document's heads automatically.
+//!
+//! When synced commits leave a document with more than one head, a document
+//! with a merge policy gets a merge commit on top of all of them straight away,
+//! so simple apps converge on a single head without a merge UI. The merge is a
+//! local edit by this peer, and syncs out like any other. Its hash only depends
+//! on the heads and its contents, so peers with the same deterministic policy
+//! make the same merge instead of merging each other's merges.
+//!
+//! Apps that merge by hand make the same kind of commit with `createMergeCommit`.
+
+use js_sys::Uint8Array;
+use serde::Serialize;
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::{
+    commit_hash,
+    envelope::{self, Extensions},
+    error::BeelayError,
+    meta::CommitMeta,
+    tasks, CommitInput, CommitOutput, CommitRecord,
+};
+
+/// How a document reconciles its heads.
+#[derive(Debug, Clone)]
+pub(crate) enum MergePolicy {
+    /// Merge with empty contents.
+    EmptyCommit,
+
+    /// Merge with the contents of the head applied most recently.
+    PreferNewest,
+
+    /// Merge with the contents returned by a JS callback.
+    Callback(js_sys::Function),
+}
+
+/// The argument to a merge callback.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MergeRequest<'a> {
+    doc_id: &'a str,
+    heads: Vec<CommitOutput>,
+}
+
+impl MergePolicy {
+    /// Parse the policy passed to `setMergePolicy`, or `None` to merge manually.
+    pub(crate) fn from_js(value: &JsValue) -> Result<Option<Self>, JsValue> {
+        if value.is_undefined() || value.is_null() {
+            return Ok(None);
+        }
+        if let Some(callback) = value.dyn_ref::<js_sys::Function>() {
+            return Ok(Some(Self::Callback(callback.clone())));
+        }
+        match value.as_string().as_deref() {
+            Some("emptyCommit") => Ok(Some(Self::EmptyCommit)),
+            Some("preferNewest") => Ok(Some(Self::PreferNewest)),
+            _ => Err(BeelayError::invalid_argument(
+                "unknown merge policy: expected \"emptyCommit\", \"preferNewest\", or a function",
+            )
+            .into()),
+        }
+    }
+
+    /// The commit merging `heads` of document `doc_id`, in the order they were applied,
+    /// or `None` if a callback declined to merge them.
+    ///
+    /// Callbacks are called synchronously, with `{ docId, heads }`, and return the merge's
+    /// contents or `undefined`.
+    pub(crate) fn merge(
+        &self,
+        doc_id: &str,
+        heads: &[&CommitRecord],
+    ) -> Result<Option<CommitInput>, JsValue> {
+        let contents = match self {
+            Self::EmptyCommit => Vec::new(),
+            Self::PreferNewest => heads
+                .last()
+                .map(|head| head.contents.to_vec())
+                .unwrap_or_default(),
+            Self::Callback(callback) => {
+                let request = MergeRequest {
+                    doc_id,
+                    heads: heads.iter().map(|head| head.to_output()).collect(),
+                };
+                let request = envelope::to_js(&request).map_err(JsValue::from)?;
+                let contents = tasks::hook(|| callback.call1(&JsValue::NULL, &request))?;
+                if contents.is_undefined() || contents.is_null() {
+                    return Ok(None);
+                }
+                contents
+                    .dyn_into::<Uint8Array>()
+                    .map_err(|_| {
+                        BeelayError::invalid_argument("merge callback must return a Uint8Array")
+                    })?
+                    .to_vec()
+            }
+        };
+
+        let mut parents = heads
+            .iter()
+            .map(|head| head.hash.clone())
+            .collect::<Vec<_>>();
+        parents.sort();
+        Ok(Some(CommitInput {
+            v: envelope::COMMIT_VERSION,
+            hash: commit_hash(&parents, &contents),
+            parents,
+            contents,
+            meta: CommitMeta::default(),
+            extensions: Extensions::new(),
+        }))
+    }
+}
@@ -0,0 +1,46 @@
+//! Commit metadata: who made a commit, when, and whatever tags the app gives it.
+//!
+//! Metadata sits alongside a commit's other fields, as `authorPeerId`,
+//! `timestamp` (milliseconds since the epoch), `tags` (any JSON value), and
+//! `signature` (see [`crate::signature`]), and doesn't count towards its hash. Local edits that don't name their author are
+//! attributed to the handle that made them.
+//!
+//! So that metadata survives the document being reopened and reaches peers, a
+//! commit that has any is stored and synced with its metadata framed in front of
+//! its contents in its loose blob, as `subduction_client` frames it, so that native
+//! clients read it too. Blobs without the frame are contents alone, as every
+//! commit's was before metadata, and peers that predate it take a framed blob for
+//! the commit's contents.
+
+pub(crate) use subduction_client::commit::CommitMeta;
+
+use crate::storage::StorageError;
+
+/// The loose blob for a commit with `contents` and `meta`.
+pub(crate) fn frame(contents: &[u8], meta: &CommitMeta) -> Result<Vec<u8>, StorageError> {
+    subduction_client::commit::frame(contents, meta)
+        .map_err(|err| StorageError::new(err.to_string()))
+}
+
+/// The contents and metadata of a commit whose loose blob is `blob`.
+pub(crate) fn unframe(blob: Vec<u8>) -> (Vec<u8>, CommitMeta) {
+    subduction_client::commit::unframe(blob)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CommitInput;
+
+    #[test]
+    fn commits_read_the_old_author_field() {
+        let json = r#"{"parents":[],"hash":"bb","contents":[],"author":"aa","tags":{"n":1}}"#;
+        let commit: CommitInput = serde_json::from_str(json).unwrap();
+        assert_eq!(commit.meta.author.as_deref(), Some("aa"));
+        assert!(commit.extensions.is_empty());
+
+        let written = serde_json::to_value(&commit).unwrap();
+        assert_eq!(written["authorPeerId"], "aa");
+        assert_eq!(written["tags"]["n"], 1);
+        assert!(written.get("author").is_none());
+    }
+}
@@ -0,0 +1,161 @@
+//! Coalesced change notifications delivered to JS subscribers.
+//!
+//! A sync burst can apply thousands of commits at once. Rather than invoking a
+//! JS callback per commit, each subscription buffers commits for a configurable
+//! window and then delivers them as a single batch. Listeners registered with
+//! `Beelay.on` skip the window, and get the commits each call applies as soon
+//! as it returns.
+
+use js_sys::Function;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::{envelope, tasks, CommitOutput};
+
+/// The default coalescing window, in milliseconds.
+pub(crate) const DEFAULT_WINDOW_MS: u32 = 16;
+
+#[wasm_bindgen]
+extern "C" {
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &JsValue, timeout: i32) -> JsValue;
+}
+
+/// Options accepted by `Beelay.subscribe`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct SubscribeOptions {
+    /// How long to buffer notifications before delivering them.
+    /// A window of zero delivers once per `addCommits` call.
+    pub(crate) window_ms: Option<u32>,
+}
+
+/// A batch of notifications handed to a subscriber's callback.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct NotificationBatch {
+    pub(crate) doc_id: String,
+    pub(crate) commits: Vec<CommitOutput>,
+    pub(crate) coalesced_count: usize,
+}
+
+/// What a subscriber's callback is called with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Payload {
+    /// A [`NotificationBatch`], for `Beelay.subscribe`.
+    Batch,
+
+    /// Just the commits, for `Beelay.on`.
+    Commits,
+}
+
+/// A registered JS callback and the notifications buffered for it.
+pub(crate) struct Subscription {
+    pub(crate) doc_id: String,
+    callback: Function,
+    window_ms: u32,
+    payload: Payload,
+    pending: Vec<CommitOutput>,
+    timer_scheduled: bool,
+}
+
+impl Subscription {
+    pub(crate) fn new(doc_id: String, callback: Function, options: &SubscribeOptions) -> Self {
+        Self {
+            doc_id,
+            callback,
+            window_ms: options.window_ms.unwrap_or(DEFAULT_WINDOW_MS),
+            payload: Payload::Batch,
+            pending: Vec::new(),
+            timer_scheduled: false,
+        }
+    }
+
+    /// A listener called with the array of new commits after every call that applies some.
+    pub(crate) fn listener(doc_id: String, callback: Function) -> Self {
+        Self {
+            doc_id,
+            callback,
+            window_ms: 0,
+            payload: Payload::Commits,
+            pending: Vec::new(),
+            timer_scheduled: false,
+        }
+    }
+
+    /// Buffer a commit for delivery.
+    ///
+    /// Returns the delay after which the caller should schedule a flush,
+    /// or `None` if a flush is already scheduled.
+    pub(crate) fn enqueue(&mut self, commit: CommitOutput) -> Option<u32> {
+        self.pending.push(commit);
+        if self.timer_scheduled {
+            None
+        } else {
+            self.timer_scheduled = true;
+            Some(self.window_ms)
+        }
+    }
+
+    /// Take everything buffered so far, ready to be delivered.
+    pub(crate) fn take(&mut self) -> Option<PendingDelivery> {
+        self.timer_scheduled = false;
+        if self.pending.is_empty() {
+            return None;
+        }
+
+        let commits = std::mem::take(&mut self.pending);
+        Some(PendingDelivery {
+            callback: self.callback.clone(),
+            payload: self.payload,
+            batch: NotificationBatch {
+                doc_id: self.doc_id.clone(),
+                coalesced_count: commits.len(),
+                commits,
+            },
+        })
+    }
+}
+
+/// A batch taken from a [`Subscription`], to be delivered once no state is borrowed.
+pub(crate) struct PendingDelivery {
+    callback: Function,
+    payload: Payload,
+    batch: NotificationBatch,
+}
+
+impl PendingDelivery {
+    /// Invoke the subscriber's callback with the batch, or its commits.
+    pub(crate) fn deliver(self) -> Result<(), JsValue> {
+        let value = match self.payload {
+            Payload::Batch => envelope::to_js(&self.batch),
+            Payload::Commits => envelope::to_js(&self.batch.commits),
+        }
+        .map_err(JsValue::from)?;
+        tasks::hook(|| self.callback.call1(&JsValue::NULL, &value))?;
+        Ok(())
+    }
+}
+
+/// Deliver every batch in `deliveries`, even after a callback throws, and then throw
+/// what they threw: the one error, or an `Error` listing each in its `errors`, as an
+/// `AggregateError` does.
+pub(crate) fn deliver_all(deliveries: Vec<PendingDelivery>) -> Result<(), JsValue> {
+    let mut errors = deliveries
+        .into_iter()
+        .filter_map(|delivery| delivery.deliver().err())
+        .collect::<Vec<_>>();
+    if errors.len() <= 1 {
+        return errors.pop().map_or(Ok(()), Err);
+    }
+    let error = js_sys::Error::new(&format!("{} subscribers' callbacks threw", errors.len()));
+    let list = errors.into_iter().collect::<js_sys::Array>();
+    js_sys::Reflect::set(&error, &"errors".into(), &list)?;
+    Err(error.into())
+}
+
+/// Schedule `task` to run after `delay_ms` on the JS event loop.
+pub(crate) fn schedule(delay_ms: u32, task: impl FnOnce() + 'static) {
+    let handler = Closure::once_into_js(task);
+    set_timeout(&handler, i32::try_from(delay_ms).unwrap_or(i32::MAX));
+}
@@ -0,0 +1,22 @@
+//! The order `loadDocument` returns a document's commits in.
+//!
+//! A document keeps its commits in the order they were added, but sync can add
+//! a child before its parents when batches arrive out of order. Causal order
+//! puts every commit after all of its parents, breaking ties by insertion
+//! order, so that consumers can replay the commits one by one. The ordering
+//! itself is `subduction_client`'s, shared with native clients.
+
+use subduction_client::order::Node;
+pub(crate) use subduction_client::order::Order;
+
+use crate::CommitRecord;
+
+impl Node for CommitRecord {
+    fn hash(&self) -> &str {
+        &self.hash
+    }
+
+    fn parents(&self) -> &[String] {
+        &self.parents
+    }
+}
@@ -0,0 +1,153 @@
+//! Who keeps a handle running: its `Beelay` objects, and any handed over with
+//! `detach`.
+//!
+//! Handles live in a registry local to the WASM instance that loaded them, keyed
+//! by a numeric handle ID. Every `Beelay` object for a handle, the one `load`
+//! returns and any facades from `handle()`, is one of its owners, and a handle
+//! stops once the last of them is freed, whether by `free()` or by JS garbage
+//! collection, so a dropped handle doesn't stay in the registry for good.
+//!
+//! A SharedWorker serving several pages from one instance can hand a handle from
+//! one agent to another: `detach()` gives up an object without stopping the handle
+//! and returns its `handleId`, and `Beelay.attach(handleId)` takes it back up as a
+//! new object, with the detached one's subscriptions. A handle with objects in
+//! transit keeps running, and each `detach()` can be attached exactly once. Handle
+//! IDs only mean something in the instance that loaded the handle.
+
+use std::{collections::VecDeque, mem::ManuallyDrop};
+
+use wasm_bindgen::prelude::*;
+
+use crate::{diagnostics, error::BeelayError, stop_handle, Beelay, HANDLES};
+
+/// The owners of a handle.
+#[derive(Debug)]
+pub(crate) struct Owners {
+    /// How many `Beelay` objects for the handle are alive.
+    live: u32,
+    /// The facades of objects detached and not yet attached again, oldest first.
+    detached: VecDeque<u32>,
+}
+
+impl Owners {
+    /// The owners of a handle just loaded, which is the object `load` returns.
+    pub(crate) fn new() -> Self {
+        Self {
+            live: 1,
+            detached: VecDeque::new(),
+        }
+    }
+
+    /// Count a new object for the handle.
+    pub(crate) fn retain(&mut self) {
+        self.live += 1;
+    }
+
+    /// Stop counting an object that was freed. Returns whether that leaves the handle
+    /// without owners.
+    fn release(&mut self) -> bool {
+        self.live = self.live.saturating_sub(1);
+        self.live == 0 && self.detached.is_empty()
+    }
+
+    /// Hand over an object for `facade`.
+    fn detach(&mut self, facade: u32) {
+        self.live = self.live.saturating_sub(1);
+        self.detached.push_back(facade);
+    }
+
+    /// Take up the object detached longest ago, returning its facade.
+    fn attach(&mut self) -> Option<u32> {
+        let facade = self.detached.pop_front()?;
+        self.live += 1;
+        Some(facade)
+    }
+}
+
+impl Drop for Beelay {
+    fn drop(&mut self) {
+        diagnostics::untrack_object(self.id, self.facade);
+        // The registry is already gone if the thread is exiting
+        let orphaned = HANDLES
+            .try_with(|handles| {
+                handles
+                    .borrow_mut()
+                    .get_mut(&self.id)
+                    .is_some_and(|ctx| ctx.owners.release())
+            })
+            .unwrap_or(false);
+        if orphaned {
+            stop_handle(self.id);
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// The ID of the handle this object belongs to, which `attach` takes.
+    #[wasm_bindgen(getter, js_name = handleId)]
+    pub fn handle_id(&self) -> u32 {
+        self.id
+    }
+
+    /// Free this object without stopping the handle, and return the handle's ID, for
+    /// another agent to pass to `Beelay.attach`. Subscriptions made through this
+    /// object are kept for the object `attach` returns.
+    pub fn detach(self) -> Result<u32, JsValue> {
+        let this = ManuallyDrop::new(self);
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&this.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            ctx.owners.detach(this.facade);
+            Ok(this.id)
+        })
+    }
+
+    /// Take up an object given up with `detach()`, in the same WASM instance. Each
+    /// detached object can be attached once.
+    ///
+    /// Throws `InvalidHandle` if the handle was stopped, and `AccessDenied` if none of
+    /// its objects is waiting to be attached.
+    pub fn attach(handle_id: u32) -> Result<Beelay, JsValue> {
+        let facade = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&handle_id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            ctx.owners.attach().ok_or(BeelayError::AccessDenied(
+                "the handle has no detached object",
+            ))
+        })?;
+        Ok(Self {
+            id: handle_id,
+            facade,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_last_owner_stops_the_handle() {
+        let mut owners = Owners::new();
+        owners.retain();
+        assert!(!owners.release());
+        assert!(owners.release());
+    }
+
+    #[test]
+    fn detached_objects_keep_the_handle_until_attached() {
+        let mut owners = Owners::new();
+        owners.retain();
+        owners.detach(0);
+        assert!(!owners.release());
+
+        assert_eq!(owners.attach(), Some(0));
+        assert_eq!(owners.attach(), None);
+        assert!(owners.release());
+    }
+}
@@ -0,0 +1,239 @@
+//! Managing a handle's connections from JS: `connect`, `disconnect`, and `listPeers`.
+//!
+//! A handle has at most one sync server, which every document syncs with, and any
+//! number of peers connected directly, each syncing the documents `connectPeer` was
+//! called for, and any number of clients accepted with `acceptConnection`, which
+//! every document syncs with. The server's connection outlives the socket closing, as
+//! `closed`, until it is [reopened](crate::reconnect), or, for a socket the app opened,
+//! so that the app can see it went and `connect` again.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+use subduction_core::{connection::handshake::Handshake, peer::id::PeerId};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::WebSocket;
+
+use crate::{
+    error::BeelayError,
+    privacy::PrivacyOptions,
+    random_source, reconnect,
+    socket::SyncSocket,
+    sync_with_server, Beelay, HANDLES,
+};
+
+/// The state of a connection, as `listPeers` reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ConnectionState {
+    Connecting,
+    Connected,
+    Closed,
+}
+
+/// One entry of the result of `Beelay.listPeers`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerInfo {
+    /// The peer's ID, or `null` for the sync server.
+    peer_id: Option<String>,
+    state: ConnectionState,
+    /// The documents syncing with the peer.
+    documents: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Connect the handle to a sync server, and sync every document with it, as loading
+    /// it with `syncServerUrl` does.
+    ///
+    /// `transport` is the server's URL, or a `WebSocket` the app has opened to it.
+    /// Resolves once the socket is open. Throws if the handle is already connected, or
+    /// connecting, to a server; `disconnect` it first. A socket opened from a URL is
+    /// reopened whenever the server closes it, waiting up to a minute between attempts.
+    ///
+    /// `privacy` optionally limits what the server learns about the handle's documents:
+    /// `{ blindIds, salt, decoys, padTo, batchMs }`. `blindIds` has the server know each
+    /// document by an ID derived from its own and `salt` (by default, the server's
+    /// URL), which every client of the document has to agree on; `decoys` syncs that
+    /// many empty decoy documents too; `padTo` pads frames to a multiple of that many
+    /// bytes; and `batchMs` holds frames back to send them together that often.
+    #[wasm_bindgen(js_name = connect)]
+    pub async fn connect(&self, transport: JsValue, privacy: JsValue) -> Result<(), JsValue> {
+        let privacy: PrivacyOptions = if privacy.is_undefined() || privacy.is_null() {
+            PrivacyOptions::default()
+        } else {
+            serde_wasm_bindgen::from_value(privacy).map_err(BeelayError::invalid_argument)?
+        };
+        let nonce = random_source(self.id)?.bytes()?;
+        let handshake = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            if ctx.connecting || ctx.sync.as_ref().is_some_and(|sync| sync.is_open()) {
+                return Err(JsValue::from(BeelayError::AlreadyConnected(
+                    "already connected to a sync server",
+                )));
+            }
+            ctx.connecting = true;
+            Ok(Handshake::new(&ctx.signing_key, nonce))
+        })?;
+
+        let url = transport.as_string();
+        let opened = match &url {
+            Some(url) => SyncSocket::connect(url, self.id, &handshake, &privacy).await,
+            None => match transport.dyn_into::<WebSocket>() {
+                Ok(ws) => SyncSocket::adopt(ws, self.id, &handshake, &privacy).await,
+                Err(_) => Err(BeelayError::invalid_argument(
+                    "transport must be a URL or a WebSocket",
+                )
+                .into()),
+            },
+        };
+        let (opened, doc_ids) = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let Some(ctx) = handles.get_mut(&self.id) else {
+                if let Ok(sync) = &opened {
+                    sync.shutdown();
+                }
+                return Err(JsValue::from(BeelayError::InvalidHandle));
+            };
+            ctx.connecting = false;
+            if let Some(closed) = ctx.sync.take() {
+                closed.shutdown();
+            }
+            let opened = opened?;
+            ctx.sync = Some(opened.clone());
+            Ok((opened, ctx.documents.keys().cloned().collect::<Vec<_>>()))
+        })?;
+        if let Some(url) = url {
+            reconnect::reconnect_on_close(self.id, url, privacy, &opened);
+        }
+        for doc_id in doc_ids {
+            sync_with_server(self.id, &doc_id)?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect from a peer: the sync server if `peerId` is `null`, or else the peer
+    /// connected directly, or the client accepted, with that ID, for every document
+    /// syncing with it.
+    ///
+    /// Resolves to whether the handle was connected to it.
+    #[wasm_bindgen(js_name = disconnect)]
+    pub async fn disconnect(&self, peer_id: Option<String>) -> Result<bool, JsValue> {
+        let direct = peer_id
+            .map(|peer_id| {
+                hex::decode(&peer_id)
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .map(PeerId::new)
+                    .ok_or(BeelayError::InvalidPeerId)
+            })
+            .transpose()?;
+        let (engines, sync, peer, accepted) = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let engines = ctx
+                .documents
+                .values()
+                .map(|doc| doc.subduction.clone())
+                .collect::<Vec<_>>();
+            let sync = if direct.is_none() {
+                ctx.sync.take()
+            } else {
+                None
+            };
+            let Some(peer) = direct.or_else(|| sync.as_ref().map(|sync| sync.server())) else {
+                return Ok((engines, sync, None, false));
+            };
+            #[cfg(feature = "accept")]
+            let accepted = ctx
+                .accepted
+                .remove(&peer)
+                .inspect(|accepted| accepted.shutdown())
+                .is_some();
+            #[cfg(not(feature = "accept"))]
+            let accepted = false;
+            Ok::<_, JsValue>((engines, sync, Some(peer), accepted))
+        })?;
+
+        let mut disconnected = accepted || sync.is_some();
+        if let Some(sync) = sync {
+            sync.shutdown();
+        }
+        let Some(peer) = peer else {
+            return Ok(disconnected);
+        };
+        for mut engine in engines {
+            disconnected |= engine
+                .disconnect_from_peer(&peer)
+                .await
+                .map_err(BeelayError::sync)?;
+        }
+        Ok(disconnected)
+    }
+
+    /// The handle's connections: an array of `{ peerId, state, documents }`, with the
+    /// sync server first.
+    ///
+    /// `peerId` is `null` for the sync server. `state` is `"connecting"`,
+    /// `"connected"`, or `"closed"`, for a server connection whose socket has closed.
+    /// `documents` lists the IDs of the documents syncing with the peer.
+    #[wasm_bindgen(js_name = listPeers)]
+    pub async fn list_peers(&self) -> Result<JsValue, JsValue> {
+        let (server, server_id, documents) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let server_id = ctx.sync.as_ref().map(|sync| sync.server());
+            let server = if ctx.connecting {
+                Some(ConnectionState::Connecting)
+            } else {
+                ctx.sync.as_ref().map(|sync| {
+                    if sync.is_open() {
+                        ConnectionState::Connected
+                    } else {
+                        ConnectionState::Closed
+                    }
+                })
+            };
+            let documents = ctx
+                .documents
+                .iter()
+                .map(|(doc_id, doc)| (doc_id.clone(), doc.subduction.clone()))
+                .collect::<Vec<_>>();
+            Ok::<_, JsValue>((server, server_id, documents))
+        })?;
+
+        let mut syncing = BTreeMap::<PeerId, Vec<String>>::new();
+        for (doc_id, engine) in documents {
+            for peer in engine.peer_ids().await {
+                syncing.entry(peer).or_default().push(doc_id.clone());
+            }
+        }
+        for doc_ids in syncing.values_mut() {
+            doc_ids.sort();
+        }
+
+        let server_documents = server_id
+            .and_then(|server_id| syncing.remove(&server_id))
+            .unwrap_or_default();
+        let server = server.map(|state| PeerInfo {
+            peer_id: None,
+            state,
+            documents: server_documents,
+        });
+        let peers = server
+            .into_iter()
+            .chain(syncing.into_iter().map(|(peer, documents)| PeerInfo {
+                peer_id: Some(hex::encode(peer.as_bytes())),
+                state: ConnectionState::Connected,
+                documents,
+            }))
+            .collect::<Vec<_>>();
+        serde_wasm_bindgen::to_value(&peers).map_err(JsValue::from)
+    }
+}
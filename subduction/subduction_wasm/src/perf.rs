@@ -0,0 +1,28 @@
+//! `perfReport`, the timings the `perf-trace` feature records.
+//!
+//! The timings are those of `sedimentree_core::perf`: digest parsing, blob
+//! hashing, storage puts, summary builds, and serialization into JS values, each
+//! as a histogram of power-of-two microsecond buckets. They're taken on
+//! `performance.now()`'s clock from when the first handle loads, and are for the
+//! whole module, across handles.
+
+use sedimentree_core::perf;
+use wasm_bindgen::prelude::*;
+
+/// Time operations on `performance.now()`'s clock.
+pub(crate) fn install_clock() {
+    perf::set_clock(crate::tasks::now);
+}
+
+/// The timings of internal operations so far, as
+/// `{ ops: [{ op, count, totalUs, maxUs, buckets: [{ underUs, count }] }] }`, with
+/// `underUs` `null` for the bucket of the slowest. Pass `true` to start over
+/// afterwards.
+#[wasm_bindgen(js_name = perfReport)]
+pub fn perf_report(reset: Option<bool>) -> Result<JsValue, JsValue> {
+    let report = perf::report();
+    if reset.unwrap_or_default() {
+        perf::reset();
+    }
+    serde_wasm_bindgen::to_value(&report).map_err(JsValue::from)
+}
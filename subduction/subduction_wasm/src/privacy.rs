@@ -0,0 +1,124 @@
+//! Keeping what a sync server learns about a handle's documents to a minimum.
+//!
+//! A server syncs documents it can't make sense of, but it still sees which
+//! documents each client syncs, how many there are, how big each message is, and
+//! when it's sent. For apps whose threat model includes the server's operator, a
+//! connection can be opened with [`PrivacyOptions`] that
+//!
+//! * blind document IDs, so that the server only sees an ID derived from the
+//!   document's and a salt (the server's URL, unless one is given). Every client
+//!   that knows a document derives the same blinded ID, so they still sync with each
+//!   other through the server, but other servers holding the document see a
+//!   different one, and can't link it to this one. All of a document's clients
+//!   syncing through a server have to agree on blinding it, or they won't meet;
+//! * sync decoy documents alongside the real ones, so that the server only learns
+//!   an upper bound on how many the client has. Decoys are derived from the
+//!   handle's identity, so the same ones turn up on every connection;
+//! * pad every frame up to a multiple of `padTo` bytes, so that its size only says
+//!   roughly how much was sent; and
+//! * hold frames back and send them together every `batchMs` milliseconds, so that
+//!   when they're sent says less about when edits were made.
+//!
+//! Padding relies on the server decoding each frame's message and ignoring what
+//! follows it, as Subduction servers do. None of this hides the contents of
+//! commits, which are only as private as the app makes them.
+
+use sedimentree_core::{Digest, SedimentreeId};
+use serde::Deserialize;
+use subduction_core::peer::id::PeerId;
+
+/// Prefixed to what blinded IDs are hashed from, so they can't collide with other
+/// digests.
+const BLINDING_DOMAIN: &[u8] = b"subduction blinded document ID\0";
+
+/// Prefixed to what decoy IDs are hashed from.
+const DECOY_DOMAIN: &[u8] = b"subduction decoy document ID\0";
+
+/// What `connect` and `syncPrivacy` accept.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PrivacyOptions {
+    /// Whether to blind document IDs.
+    #[serde(default)]
+    blind_ids: bool,
+    /// What to derive blinded IDs from along with each document's, if not the
+    /// server's URL.
+    #[serde(default)]
+    salt: Option<String>,
+    /// How many decoy documents to sync.
+    #[serde(default)]
+    decoys: u32,
+    /// The multiple of bytes to pad frames to.
+    #[serde(default)]
+    pad_to: Option<u32>,
+    /// How long to hold frames back for, in milliseconds.
+    #[serde(default)]
+    batch_ms: Option<u32>,
+}
+
+/// The privacy measures one socket takes.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Privacy {
+    /// The salt blinded IDs are derived with, if IDs are blinded.
+    salt: Option<Vec<u8>>,
+    decoys: u32,
+    pad_to: Option<u32>,
+    batch_ms: Option<u32>,
+}
+
+impl Privacy {
+    /// The measures `options` ask for, for a socket to the server at `url`.
+    pub(crate) fn new(options: &PrivacyOptions, url: &str) -> Self {
+        let salt = options
+            .blind_ids
+            .then(|| options.salt.as_deref().unwrap_or(url).as_bytes().to_vec());
+        Self {
+            salt,
+            decoys: options.decoys,
+            pad_to: options.pad_to.filter(|&pad_to| pad_to > 1),
+            batch_ms: options.batch_ms.filter(|&batch_ms| batch_ms > 0),
+        }
+    }
+
+    /// The ID the server knows document `id` by.
+    pub(crate) fn blind(&self, id: SedimentreeId) -> SedimentreeId {
+        let Some(salt) = &self.salt else {
+            return id;
+        };
+        let mut input = Vec::with_capacity(BLINDING_DOMAIN.len() + 8 + salt.len() + 32);
+        input.extend_from_slice(BLINDING_DOMAIN);
+        input.extend_from_slice(&(salt.len() as u64).to_le_bytes());
+        input.extend_from_slice(salt);
+        input.extend_from_slice(id.as_bytes());
+        SedimentreeId::new(*Digest::hash(&input).as_bytes())
+    }
+
+    /// Whether document IDs are blinded.
+    pub(crate) const fn blinds(&self) -> bool {
+        self.salt.is_some()
+    }
+
+    /// The decoy documents the client with identity `requestor` syncs.
+    pub(crate) fn decoys(&self, requestor: PeerId) -> impl Iterator<Item = SedimentreeId> {
+        (0..self.decoys).map(move |index| {
+            let mut input = Vec::with_capacity(DECOY_DOMAIN.len() + 32 + 4);
+            input.extend_from_slice(DECOY_DOMAIN);
+            input.extend_from_slice(requestor.as_bytes());
+            input.extend_from_slice(&index.to_le_bytes());
+            SedimentreeId::new(*Digest::hash(&input).as_bytes())
+        })
+    }
+
+    /// Pad an encoded frame with zeros up to the next multiple of `padTo` bytes.
+    pub(crate) fn pad(&self, frame: &mut Vec<u8>) {
+        if let Some(pad_to) = self.pad_to {
+            let pad_to = pad_to as usize;
+            frame.resize(frame.len().div_ceil(pad_to) * pad_to, 0);
+        }
+    }
+
+    /// How long to hold frames back for, if they're batched.
+    pub(crate) const fn batch_ms(&self) -> Option<u32> {
+        self.batch_ms
+    }
+}
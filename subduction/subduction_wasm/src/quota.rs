@@ -0,0 +1,181 @@
+//! Limits on what a handle holds, so that a misbehaving caller can't exhaust the
+//! WASM heap.
+//!
+//! `Beelay.load` takes `limits: { maxCommitBytes, maxDocumentBytes, maxDocuments }`,
+//! each unlimited if left out. A commit whose contents are over `maxCommitBytes`,
+//! commits that would take a document's contents past `maxDocumentBytes`, and
+//! documents beyond `maxDocuments` are refused with a `QuotaExceeded` error, whose
+//! `details` name the limit, its value, and what would have been reached. Nothing is
+//! applied from a call that's refused.
+//!
+//! The limits apply to what comes in through the API: `createDoc`, `addCommits`,
+//! `importDoc`, `adoptDocState`, and restoring a snapshot or backup. Commits pulled
+//! in by a document's engine from its peers have been stored by the time the handle
+//! sees them, so they count towards usage but aren't refused, and neither are the
+//! documents a handle reopens from its own storage. `getUsage()` reports what the
+//! handle holds against its limits.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+use crate::{error::BeelayError, Beelay, CommitInput, HANDLES};
+
+/// The limits a handle was loaded with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Limits {
+    /// The most bytes of contents a commit may have.
+    #[serde(default)]
+    max_commit_bytes: Option<u64>,
+    /// The most bytes of contents a document's commits may have between them.
+    #[serde(default)]
+    max_document_bytes: Option<u64>,
+    /// The most documents the handle may hold.
+    #[serde(default)]
+    max_documents: Option<u64>,
+}
+
+impl Limits {
+    /// Check that `commits` can be added to a document whose commits' contents take
+    /// `document_bytes`.
+    pub(crate) fn check_commits<'a>(
+        &self,
+        document_bytes: u64,
+        commits: impl IntoIterator<Item = &'a CommitInput>,
+    ) -> Result<(), BeelayError> {
+        let mut total = document_bytes;
+        for commit in commits {
+            let size = commit.contents.len() as u64;
+            check("maxCommitBytes", self.max_commit_bytes, size)?;
+            total = total.saturating_add(size);
+            check("maxDocumentBytes", self.max_document_bytes, total)?;
+        }
+        Ok(())
+    }
+
+    /// Check that a handle holding `held` documents can take `adding` more.
+    pub(crate) fn check_documents(&self, held: usize, adding: usize) -> Result<(), BeelayError> {
+        check(
+            "maxDocuments",
+            self.max_documents,
+            held.saturating_add(adding) as u64,
+        )
+    }
+}
+
+fn check(limit: &'static str, max: Option<u64>, requested: u64) -> Result<(), BeelayError> {
+    match max {
+        Some(max) if requested > max => Err(BeelayError::QuotaExceeded {
+            limit,
+            max,
+            requested,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// The result of `getUsage`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Usage {
+    documents: usize,
+    /// The bytes of contents of every document's commits.
+    content_bytes: u64,
+    /// Largest first.
+    by_document: Vec<DocumentUsage>,
+    limits: Limits,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DocumentUsage {
+    doc_id: String,
+    content_bytes: u64,
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// What the handle holds against the limits it was loaded with, as
+    /// `{ documents, contentBytes, byDocument, limits }`.
+    ///
+    /// `byDocument` lists each document as `{ docId, contentBytes }`, largest first, and
+    /// `limits` is `{ maxCommitBytes, maxDocumentBytes, maxDocuments }`, with `null`
+    /// for no limit.
+    #[wasm_bindgen(js_name = getUsage)]
+    pub fn get_usage(&self) -> Result<JsValue, JsValue> {
+        let usage = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let mut by_document = ctx
+                .documents
+                .iter()
+                .map(|(doc_id, doc)| DocumentUsage {
+                    doc_id: doc_id.clone(),
+                    content_bytes: doc.stats.content_bytes(),
+                })
+                .collect::<Vec<_>>();
+            by_document.sort_by(|a, b| {
+                b.content_bytes
+                    .cmp(&a.content_bytes)
+                    .then_with(|| a.doc_id.cmp(&b.doc_id))
+            });
+            Ok::<_, JsValue>(Usage {
+                documents: by_document.len(),
+                content_bytes: by_document.iter().map(|doc| doc.content_bytes).sum(),
+                by_document,
+                limits: ctx.limits,
+            })
+        })?;
+        serde_wasm_bindgen::to_value(&usage).map_err(JsValue::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{envelope, meta::CommitMeta, Extensions};
+
+    fn commit(size: usize) -> CommitInput {
+        CommitInput {
+            v: envelope::COMMIT_VERSION,
+            parents: Vec::new(),
+            hash: String::new(),
+            contents: vec![0; size],
+            meta: CommitMeta::default(),
+            extensions: Extensions::new(),
+        }
+    }
+
+    #[test]
+    fn commits_are_checked_one_by_one_and_together() {
+        let limits = Limits {
+            max_commit_bytes: Some(10),
+            max_document_bytes: Some(25),
+            max_documents: None,
+        };
+        assert_eq!(limits.check_commits(0, &[commit(10), commit(10)]), Ok(()));
+        assert_eq!(
+            limits.check_commits(0, &[commit(11)]),
+            Err(BeelayError::QuotaExceeded {
+                limit: "maxCommitBytes",
+                max: 10,
+                requested: 11
+            })
+        );
+        assert_eq!(
+            limits.check_commits(10, &[commit(10), commit(10)]),
+            Err(BeelayError::QuotaExceeded {
+                limit: "maxDocumentBytes",
+                max: 25,
+                requested: 30
+            })
+        );
+    }
+
+    #[test]
+    fn no_limits_by_default() {
+        let limits = Limits::default();
+        assert_eq!(limits.check_commits(u64::MAX, &[commit(1 << 20)]), Ok(()));
+        assert_eq!(limits.check_documents(usize::MAX, 1), Ok(()));
+    }
+}
@@ -0,0 +1,108 @@
+//! The single source of randomness for IDs, nonces, and keys.
+//!
+//! By default this is the platform's secure generator (`crypto.getRandomValues`
+//! in browsers). A handle can be loaded with a custom `randomSource` instead, for
+//! example to make document IDs reproducible in tests. Keyhive draws its keys from
+//! the same source, through [`KeyRng`].
+
+use js_sys::{Function, Uint8Array};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::error::BeelayError;
+
+/// Where a handle gets its random bytes from.
+#[derive(Debug, Clone, Default)]
+pub(crate) enum RandomSource {
+    /// The platform's cryptographically secure generator.
+    #[default]
+    Crypto,
+
+    /// A JS function that fills the `Uint8Array` it is given, like `crypto.getRandomValues`.
+    Custom(Function),
+}
+
+impl RandomSource {
+    /// Read the `randomSource` option from a `Beelay.load` config, if there is one.
+    pub(crate) fn from_config(config: &JsValue) -> Result<Self, JsValue> {
+        if config.is_undefined() || config.is_null() {
+            return Ok(Self::Crypto);
+        }
+
+        let source = js_sys::Reflect::get(config, &"randomSource".into())?;
+        if source.is_undefined() || source.is_null() {
+            return Ok(Self::Crypto);
+        }
+        source
+            .dyn_into::<Function>()
+            .map(Self::Custom)
+            .map_err(|_| BeelayError::invalid_argument("randomSource must be a function").into())
+    }
+
+    /// Fill `buffer` with random bytes.
+    pub(crate) fn fill(&self, buffer: &mut [u8]) -> Result<(), JsValue> {
+        match self {
+            Self::Crypto => getrandom::getrandom(buffer).map_err(|err| {
+                BeelayError::Unsupported(format!("no secure random source available: {err}")).into()
+            }),
+            Self::Custom(source) => {
+                let length = u32::try_from(buffer.len()).map_err(|_| {
+                    BeelayError::invalid_argument("too many random bytes requested")
+                })?;
+                let array = Uint8Array::new_with_length(length);
+                source.call1(&JsValue::NULL, &array)?;
+                array.copy_to(buffer);
+                Ok(())
+            }
+        }
+    }
+
+    /// `N` random bytes.
+    pub(crate) fn bytes<const N: usize>(&self) -> Result<[u8; N], JsValue> {
+        let mut bytes = [0; N];
+        self.fill(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// `length` random bytes, hex-encoded.
+    pub(crate) fn hex_string(&self, length: usize) -> Result<String, JsValue> {
+        let mut bytes = vec![0; length];
+        self.fill(&mut bytes)?;
+        Ok(hex::encode(bytes))
+    }
+}
+
+/// A [`RandomSource`] as the `rand` generator Keyhive makes its keys with.
+#[cfg(feature = "encryption")]
+#[derive(Debug, Clone)]
+pub(crate) struct KeyRng(pub(crate) RandomSource);
+
+#[cfg(feature = "encryption")]
+impl rand::RngCore for KeyRng {
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut bytes = [0; 8];
+        self.fill_bytes(&mut bytes);
+        u64::from_le_bytes(bytes)
+    }
+
+    /// Like `OsRng`, panics if the source fails, since keys can't be made without it.
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        if let Err(err) = self.try_fill_bytes(dest) {
+            panic!("the random source failed: {err}");
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.0
+            .fill(dest)
+            .map_err(|err| rand::Error::new(format!("{err:?}")))
+    }
+}
+
+#[cfg(feature = "encryption")]
+impl rand::CryptoRng for KeyRng {}
@@ -0,0 +1,147 @@
+//! Reopening a handle's socket to its sync server when the server closes it.
+//!
+//! A socket opened from a URL, by `syncServerUrl` or by `connect`, is reopened after
+//! [`MIN_RETRY`], then after twice as long each time that fails, up to [`MAX_RETRY`],
+//! and every document syncs over the new one. Reconnecting stops once the app
+//! disconnects, connects elsewhere, or stops the handle. A socket the app opened
+//! itself and handed to `connect` is the app's to reopen.
+
+use std::{
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use subduction_core::connection::handshake::Handshake;
+
+use crate::{
+    privacy::PrivacyOptions,
+    random_source,
+    socket::{sleep, SyncSocket},
+    sync_with_server, HANDLES,
+};
+
+/// How long to wait before the first attempt to reopen a socket.
+pub(crate) const MIN_RETRY: Duration = Duration::from_secs(1);
+
+/// The longest to wait between attempts.
+pub(crate) const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// The waits between attempts to reopen a socket.
+#[derive(Debug)]
+pub(crate) struct Backoff {
+    next: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { next: MIN_RETRY }
+    }
+}
+
+impl Backoff {
+    /// How long to wait before the next attempt.
+    pub(crate) fn next(&mut self) -> Duration {
+        let wait = self.next;
+        self.next = (self.next * 2).min(MAX_RETRY);
+        wait
+    }
+}
+
+/// Reopen `socket`, handle `handle_id`'s socket to `url`, whenever the server closes it.
+pub(crate) fn reconnect_on_close(
+    handle_id: u32,
+    url: String,
+    privacy: PrivacyOptions,
+    socket: &Rc<SyncSocket>,
+) {
+    let mut closed = socket.closed();
+    let mut current = Rc::downgrade(socket);
+    wasm_bindgen_futures::spawn_local(async move {
+        loop {
+            // Shut down by the handle rather than closed by the server
+            if !closed.await.unwrap_or(false) {
+                return;
+            }
+            let mut backoff = Backoff::default();
+            let reopened = loop {
+                sleep(backoff.next()).await;
+                if !is_current(handle_id, &current) {
+                    return;
+                }
+                if let Ok(reopened) = reopen(handle_id, &url, &privacy).await {
+                    break reopened;
+                }
+            };
+            let Some(doc_ids) = replace(handle_id, &current, &reopened) else {
+                reopened.shutdown();
+                return;
+            };
+            for doc_id in doc_ids {
+                let _ = sync_with_server(handle_id, &doc_id);
+            }
+            closed = reopened.closed();
+            current = Rc::downgrade(&reopened);
+        }
+    });
+}
+
+/// Whether `socket` is still handle `handle_id`'s socket, and the handle isn't
+/// connecting another.
+fn is_current(handle_id: u32, socket: &Weak<SyncSocket>) -> bool {
+    HANDLES.with(|handles| {
+        handles.borrow().get(&handle_id).is_some_and(|ctx| {
+            !ctx.connecting
+                && ctx
+                    .sync
+                    .as_ref()
+                    .is_some_and(|sync| std::ptr::eq(Rc::as_ptr(sync), socket.as_ptr()))
+        })
+    })
+}
+
+/// Open a new socket to `url` for handle `handle_id`.
+async fn reopen(
+    handle_id: u32,
+    url: &str,
+    privacy: &PrivacyOptions,
+) -> Result<Rc<SyncSocket>, wasm_bindgen::JsValue> {
+    let nonce = random_source(handle_id)?.bytes()?;
+    let handshake = HANDLES.with(|handles| {
+        handles
+            .borrow()
+            .get(&handle_id)
+            .map(|ctx| Handshake::new(&ctx.signing_key, nonce))
+    });
+    let handshake = handshake.ok_or(crate::error::BeelayError::InvalidHandle)?;
+    SyncSocket::connect(url, handle_id, &handshake, privacy).await
+}
+
+/// Make `reopened` handle `handle_id`'s socket in place of `closed`, returning the
+/// documents to sync over it, unless the handle has moved on while it was reopened.
+fn replace(
+    handle_id: u32,
+    closed: &Weak<SyncSocket>,
+    reopened: &Rc<SyncSocket>,
+) -> Option<Vec<String>> {
+    if !is_current(handle_id, closed) {
+        return None;
+    }
+    HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles.get_mut(&handle_id)?;
+        ctx.sync = Some(reopened.clone());
+        Some(ctx.documents.keys().cloned().collect())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn waits_twice_as_long_after_each_failure_up_to_a_limit() {
+        let mut backoff = Backoff::default();
+        let waits = (0..8).map(|_| backoff.next().as_secs()).collect::<Vec<_>>();
+        assert_eq!(waits, [1, 2, 4, 8, 16, 32, 60, 60]);
+    }
+}
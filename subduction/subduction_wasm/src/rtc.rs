@@ -0,0 +1,425 @@
+//! Syncing a document directly with another browser over a WebRTC data channel.
+//!
+//! The app sets up the `RTCDataChannel` itself (signaling, ICE, and so on are up to
+//! it) and hands the open channel to `Beelay.connectPeer`, which syncs one document
+//! over it. The channel must be ordered and reliable, which is the default.
+//!
+//! Messages are bincode-encoded as over the sync server's socket, but browsers
+//! don't reliably deliver data channel messages larger than 16 KiB, so each one is
+//! split into frames of at most [`FRAME_BYTES`]. A frame is a byte saying whether
+//! more of the message follows, then the next piece of it. The
+//! [handshake](crate::handshake) comes first, in frames of its own.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either, LocalBoxFuture},
+    lock::Mutex,
+    FutureExt, StreamExt,
+};
+use js_sys::{ArrayBuffer, Uint8Array};
+use sedimentree_core::future::Local;
+use subduction_core::{
+    connection::{
+        handshake::Handshake,
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{MessageEvent, RtcDataChannel, RtcDataChannelState, RtcDataChannelType};
+
+use crate::{
+    connection::PeerConnection,
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+    handshake::{self, Greeting},
+    pull_engine_commits, random_source,
+    socket::{self, SocketError, DEFAULT_CALL_TIMEOUT},
+    tasks::{self, TaskKind},
+    Beelay, WaitResult, HANDLES,
+};
+
+/// The largest frame sent, header included.
+const FRAME_BYTES: usize = 16 * 1024;
+
+/// The header of a frame that ends its message.
+const LAST: u8 = 0;
+
+/// The header of a frame that more of its message follows.
+const MORE: u8 = 1;
+
+/// The state shared by a connection's clones.
+struct Channel {
+    channel: RtcDataChannel,
+    /// The peer at the other end.
+    peer: PeerId,
+    /// This handle's identity, used to tag its requests.
+    requestor: PeerId,
+    next_nonce: Cell<u128>,
+    handle_id: u32,
+    /// The channel's ID among the resources tracked (see [`diagnostics`]).
+    diagnostics_id: u32,
+    doc_id: String,
+    /// Batch sync calls waiting for their response.
+    pending: RefCell<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>,
+    /// Where received messages go, until the channel closes.
+    inbox: RefCell<Option<mpsc::UnboundedSender<Message>>>,
+    inbound: Mutex<mpsc::UnboundedReceiver<Message>>,
+    /// The frames received so far of a message that isn't complete yet.
+    partial: RefCell<Vec<u8>>,
+    /// Told when the channel closes.
+    on_closed: RefCell<Option<oneshot::Sender<()>>>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+impl Channel {
+    fn send(&self, message: &Message) -> Result<(), SocketError> {
+        let bytes = bincode::serde::encode_to_vec(message, bincode::config::standard())
+            .map_err(|err| SocketError::Encode(err.to_string()))?;
+        if self.channel.ready_state() != RtcDataChannelState::Open {
+            return Err(SocketError::Closed);
+        }
+        let mut pieces = bytes.chunks(FRAME_BYTES - 1).peekable();
+        // An empty message still takes a frame
+        if pieces.peek().is_none() {
+            return self.send_frame(&[LAST]);
+        }
+        while let Some(piece) = pieces.next() {
+            let mut frame = Vec::with_capacity(piece.len() + 1);
+            frame.push(if pieces.peek().is_some() { MORE } else { LAST });
+            frame.extend_from_slice(piece);
+            self.send_frame(&frame)?;
+        }
+        Ok(())
+    }
+
+    fn send_frame(&self, frame: &[u8]) -> Result<(), SocketError> {
+        self.channel
+            .send_with_u8_array(frame)
+            .map_err(|err| SocketError::Send(format!("{err:?}")))
+    }
+
+    /// Add an incoming frame to the message it belongs to, and deliver the message
+    /// once it's complete.
+    fn receive(&self, event: &MessageEvent) {
+        let Ok(buffer) = event.data().dyn_into::<ArrayBuffer>() else {
+            return;
+        };
+        let frame = Uint8Array::new(&buffer).to_vec();
+        let Some((&header, piece)) = frame.split_first() else {
+            return;
+        };
+        // What's left of the other side's handshake, sent again in case ours went unheard
+        if matches!(header, handshake::HELLO | handshake::PROOF) {
+            return;
+        }
+        let mut partial = self.partial.borrow_mut();
+        partial.extend_from_slice(piece);
+        if header == MORE {
+            return;
+        }
+        let bytes = std::mem::take(&mut *partial);
+        drop(partial);
+        let Ok((message, _)) =
+            bincode::serde::decode_from_slice::<Message, _>(&bytes, bincode::config::standard())
+        else {
+            return;
+        };
+
+        if let Message::BatchSyncResponse(response) = &message {
+            if let Some(waiting) = self.pending.borrow_mut().remove(&response.req_id) {
+                let _ = waiting.send(response.clone());
+                return;
+            }
+        }
+        let pull = socket::brings_commits(&message);
+        if let Some(inbox) = self.inbox.borrow().as_ref() {
+            let _ = inbox.unbounded_send(message);
+        }
+        if pull {
+            socket::pull_soon(self.handle_id, self.doc_id.clone());
+        }
+    }
+
+    /// Stop delivering messages, fail pending calls, and say the channel has closed.
+    fn close(&self) {
+        diagnostics::untrack(
+            ResourceKind::Connection,
+            self.handle_id,
+            &self.diagnostics_id.to_string(),
+        );
+        self.inbox.borrow_mut().take();
+        self.pending.borrow_mut().clear();
+        if let Some(closed) = self.on_closed.borrow_mut().take() {
+            let _ = closed.send(());
+        }
+    }
+}
+
+impl Drop for Channel {
+    /// The app owns the channel, so leave it open, but stop calling into this one.
+    fn drop(&mut self) {
+        self.channel.set_onmessage(None);
+        self.channel.set_onclose(None);
+        self.close();
+    }
+}
+
+/// A document's connection to another peer over an `RTCDataChannel`.
+#[derive(Clone)]
+pub(crate) struct RtcDataChannelConnection {
+    channel: Rc<Channel>,
+}
+
+impl RtcDataChannelConnection {
+    /// Sync document `doc_id` of handle `handle_id` with `peer` over `channel`, which
+    /// must be open, once the peer has proved it holds `peer`'s key in the handshake
+    /// we run as `ours`.
+    ///
+    /// Also returns a receiver told when the channel closes.
+    pub(crate) async fn new(
+        channel: RtcDataChannel,
+        peer: PeerId,
+        ours: &Handshake,
+        handle_id: u32,
+        doc_id: String,
+    ) -> Result<(Self, oneshot::Receiver<()>), JsValue> {
+        if channel.ready_state() != RtcDataChannelState::Open {
+            return Err(BeelayError::ConnectionFailure("data channel is not open".into()).into());
+        }
+        let ordered = js_sys::Reflect::get(&channel, &"ordered".into())
+            .ok()
+            .and_then(|ordered| ordered.as_bool());
+        if ordered == Some(false) {
+            return Err(BeelayError::invalid_argument("data channel must be ordered").into());
+        }
+        channel.set_binary_type(RtcDataChannelType::Arraybuffer);
+        let mut greeting = Greeting::listen_channel(&channel);
+        let proved = greeting.hello(ours).await?;
+        drop(greeting);
+        if proved != peer {
+            return Err(
+                BeelayError::AccessDenied("peer proved another key than its peer ID").into(),
+            );
+        }
+        let requestor = ours.hello().peer_id;
+
+        let (inbox, inbound) = mpsc::unbounded();
+        let (on_closed, closed) = oneshot::channel();
+        let diagnostics_id = diagnostics::next_id();
+        diagnostics::track(
+            ResourceKind::Connection,
+            handle_id,
+            diagnostics_id.to_string(),
+        );
+        let shared = Rc::new_cyclic(|weak: &Weak<Channel>| {
+            let on_message = {
+                let weak = weak.clone();
+                Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                    if let Some(shared) = weak.upgrade() {
+                        shared.receive(&event);
+                    }
+                })
+            };
+            let on_close = {
+                let weak = weak.clone();
+                Closure::<dyn FnMut()>::new(move || {
+                    if let Some(shared) = weak.upgrade() {
+                        shared.close();
+                    }
+                })
+            };
+            channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            channel.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+            Channel {
+                channel,
+                peer,
+                requestor,
+                next_nonce: Cell::new(0),
+                handle_id,
+                diagnostics_id,
+                doc_id,
+                pending: RefCell::new(HashMap::new()),
+                inbox: RefCell::new(Some(inbox)),
+                inbound: Mutex::new(inbound),
+                partial: RefCell::new(Vec::new()),
+                on_closed: RefCell::new(Some(on_closed)),
+                _on_message: on_message,
+                _on_close: on_close,
+            }
+        });
+        Ok((Self { channel: shared }, closed))
+    }
+}
+
+impl fmt::Debug for RtcDataChannelConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RtcDataChannelConnection")
+            .field("label", &self.channel.channel.label())
+            .field("peer", &self.channel.peer)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for RtcDataChannelConnection {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.channel, &other.channel)
+    }
+}
+
+impl Connection<Local> for RtcDataChannelConnection {
+    type DisconnectionError = SocketError;
+    type SendError = SocketError;
+    type RecvError = SocketError;
+    type CallError = SocketError;
+
+    fn peer_id(&self) -> PeerId {
+        self.channel.peer
+    }
+
+    fn disconnect(&mut self) -> LocalBoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn send(&self, message: Message) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
+        let result = self.channel.send(&message);
+        async { result }.boxed_local()
+    }
+
+    /// Once the channel closes, this never resolves: the connection is disconnected
+    /// instead, so that the peer going away doesn't stop the document's other
+    /// connections.
+    fn recv(&self) -> LocalBoxFuture<'_, Result<Message, Self::RecvError>> {
+        async {
+            match self.channel.inbound.lock().await.next().await {
+                Some(message) => Ok(message),
+                None => future::pending().await,
+            }
+        }
+        .boxed_local()
+    }
+
+    fn next_request_id(&self) -> LocalBoxFuture<'_, RequestId> {
+        let nonce = self.channel.next_nonce.get();
+        self.channel.next_nonce.set(nonce.wrapping_add(1));
+        let requestor = self.channel.requestor;
+        async move { RequestId { requestor, nonce } }.boxed_local()
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        async move {
+            let (respond, response) = oneshot::channel();
+            let req_id = req.req_id;
+            self.channel.pending.borrow_mut().insert(req_id, respond);
+            if let Err(err) = self.channel.send(&Message::BatchSyncRequest(req)) {
+                self.channel.pending.borrow_mut().remove(&req_id);
+                return Err(err);
+            }
+
+            let timeout = socket::sleep(timeout.unwrap_or(DEFAULT_CALL_TIMEOUT));
+            match future::select(response, Box::pin(timeout)).await {
+                Either::Left((response, _)) => response.map_err(|_| SocketError::Closed),
+                Either::Right(((), _)) => {
+                    self.channel.pending.borrow_mut().remove(&req_id);
+                    Err(SocketError::Timeout)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Sync a document directly with another browser over `channel`, an open, ordered
+    /// `RTCDataChannel` the app has set up with the peer whose `peerId` is `peerId`.
+    ///
+    /// The peer must be a member of the document, and prove it holds the key `peerId`
+    /// names when the channel's handshake runs, or this throws `AccessDenied`. The
+    /// other end should connect its copy of the document over the same channel.
+    /// Resolves to `{ synced }` once a first batch sync with the peer has finished, and
+    /// keeps syncing until the channel closes. The channel stays the app's to close.
+    #[wasm_bindgen(js_name = connectPeer, unchecked_return_type = "WaitResult")]
+    pub async fn connect_peer(
+        &self,
+        doc_id: String,
+        channel: web_sys::RtcDataChannel,
+        peer_id: String,
+    ) -> Result<JsValue, JsValue> {
+        let peer = hex::decode(&peer_id)
+            .ok()
+            .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+            .map(PeerId::new)
+            .ok_or(BeelayError::InvalidPeerId)?;
+        let member = hex::encode(peer.as_bytes());
+        let nonce = random_source(self.id)?.bytes()?;
+        let (engine, sed_id, handshake, start_listening) = HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+            let handshake = Handshake::new(&ctx.signing_key, nonce);
+            let doc = ctx
+                .documents
+                .get_mut(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            if doc.members.access(&member, &ctx.groups).is_none() {
+                return Err(
+                    BeelayError::AccessDenied("peer isn't a member of the document").into(),
+                );
+            }
+            let start_listening = !std::mem::replace(&mut doc.listening, true);
+            Ok::<_, JsValue>((
+                doc.subduction.clone(),
+                doc.sed_id,
+                handshake,
+                start_listening,
+            ))
+        })?;
+
+        let (conn, closed) =
+            RtcDataChannelConnection::new(channel, peer, &handshake, self.id, doc_id.clone())
+                .await?;
+        let (_, conn_id) = engine
+            .register(PeerConnection::Direct(conn))
+            .await
+            .map_err(BeelayError::sync)?;
+        wasm_bindgen_futures::spawn_local({
+            let engine = engine.clone();
+            async move {
+                let _ = closed.await;
+                let _ = engine.disconnect(&conn_id).await;
+            }
+        });
+        if start_listening {
+            let listener = engine.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let _ = listener.run().await;
+            });
+        }
+
+        let (synced, _) = tasks::timed(
+            TaskKind::Sync,
+            engine.request_peer_batch_sync(&peer, sed_id, None),
+        )
+        .await
+        .map_err(BeelayError::sync)?;
+        pull_engine_commits(self.id, &doc_id).await?;
+        serde_wasm_bindgen::to_value(&WaitResult { synced }).map_err(JsValue::from)
+    }
+}
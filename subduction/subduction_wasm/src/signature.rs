@@ -0,0 +1,157 @@
+//! Signed commits.
+//!
+//! A handle loaded with `signCommits: true` signs every commit it makes with its
+//! signing key: a detached ed25519 signature over [`DOMAIN`], the commit's digest,
+//! and its parents' digests, in order. The signature is part of the commit's
+//! metadata (see [`crate::meta`]), as `signature`, hex-encoded, so it's stored and
+//! synced along with the commit and doesn't count towards its hash. A signed
+//! commit's `authorPeerId` is the handle that signed it, whose peer ID is its
+//! verifying key.
+//!
+//! Every handle checks the signature of a commit that has one before applying it,
+//! whether it arrives over a connection or through `addCommits`, and rejects it
+//! with `InvalidSignature` if its author didn't sign it. Commits without a
+//! signature are applied as before.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use js_sys::Uint8Array;
+use wasm_bindgen::{JsCast, JsValue};
+
+use crate::{error::BeelayError, CommitInput};
+
+/// What every signed payload starts with, so that a commit signature can't be
+/// passed off as any other kind.
+const DOMAIN: &[u8] = b"subduction/commit-signature/v1";
+
+/// What a commit's signature covers.
+fn payload(commit: &CommitInput) -> Result<Vec<u8>, BeelayError> {
+    let mut payload = DOMAIN.to_vec();
+    for digest in std::iter::once(&commit.hash).chain(&commit.parents) {
+        let bytes = hex::decode(digest)
+            .ok()
+            .filter(|bytes| bytes.len() == 32)
+            .ok_or(BeelayError::InvalidDigest("32 bytes, hex encoded"))?;
+        payload.extend_from_slice(&bytes);
+    }
+    Ok(payload)
+}
+
+/// `commit`, signed by `signer` and attributed to it.
+///
+/// Throws `InvalidArgument` if the commit names some other author.
+pub(crate) fn sign(commit: &CommitInput, signer: &SigningKey) -> Result<CommitInput, BeelayError> {
+    let author = hex::encode(signer.verifying_key().as_bytes());
+    if commit
+        .meta
+        .author
+        .as_ref()
+        .is_some_and(|named| !named.eq_ignore_ascii_case(&author))
+    {
+        return Err(BeelayError::InvalidArgument(format!(
+            "commit {} names another author, so the handle can't sign it",
+            commit.hash
+        )));
+    }
+
+    let signature = signer.sign(&payload(commit)?);
+    let mut signed = commit.clone();
+    signed.meta.author = Some(author);
+    signed.meta.signature = Some(hex::encode(signature.to_bytes()));
+    Ok(signed)
+}
+
+/// The key of `signer`, a `MemorySigner` passed as `signer` to `Beelay.load`, or any
+/// object with its `export` method.
+///
+/// It's read through that method, leaving the JS object usable.
+pub(crate) fn signer_key(signer: &JsValue) -> Result<SigningKey, JsValue> {
+    let export = js_sys::Reflect::get(signer, &"export".into())?
+        .dyn_into::<js_sys::Function>()
+        .map_err(|_| BeelayError::invalid_argument("signer must be a MemorySigner"))?;
+    let secret = export
+        .call0(signer)?
+        .dyn_into::<Uint8Array>()
+        .map_err(|_| BeelayError::invalid_argument("signer must be a MemorySigner"))?;
+    Ok(signing_key(secret.to_vec())?)
+}
+
+/// The signing key whose secret is `secret`.
+pub(crate) fn signing_key(secret: Vec<u8>) -> Result<SigningKey, BeelayError> {
+    let secret: [u8; 32] = secret
+        .try_into()
+        .map_err(|_| BeelayError::invalid_argument("a signing key must be 32 bytes"))?;
+    Ok(SigningKey::from_bytes(&secret))
+}
+
+/// Check the signature of `commit`, if it has one, against the author it names.
+pub(crate) fn verify(commit: &CommitInput) -> Result<(), BeelayError> {
+    let Some(signature) = &commit.meta.signature else {
+        return Ok(());
+    };
+    let invalid = || BeelayError::InvalidSignature {
+        hash: commit.hash.clone(),
+    };
+    let key = commit
+        .meta
+        .author
+        .as_deref()
+        .and_then(|author| hex::decode(author).ok())
+        .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+        .and_then(|bytes| VerifyingKey::from_bytes(&bytes).ok())
+        .ok_or_else(invalid)?;
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or_else(invalid)?;
+    key.verify(&payload(commit)?, &signature)
+        .map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn commit(parents: &[&str]) -> CommitInput {
+        let json = serde_json::json!({
+            "parents": parents,
+            "hash": "11".repeat(32),
+            "contents": [1, 2, 3],
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn signed_commits_verify() {
+        let signer = SigningKey::from_bytes(&[7; 32]);
+        let signed = sign(&commit(&[&"22".repeat(32)]), &signer).unwrap();
+        assert_eq!(
+            signed.meta.author,
+            Some(hex::encode(signer.verifying_key().as_bytes()))
+        );
+        assert!(verify(&signed).is_ok());
+        assert!(verify(&commit(&[])).is_ok());
+    }
+
+    #[test]
+    fn tampered_commits_are_rejected() {
+        let signer = SigningKey::from_bytes(&[7; 32]);
+        let signed = sign(&commit(&[&"22".repeat(32)]), &signer).unwrap();
+
+        let mut moved = signed.clone();
+        moved.parents = vec!["33".repeat(32)];
+        assert!(verify(&moved).is_err());
+
+        let mut forged = signed;
+        forged.meta.author = Some(hex::encode(
+            SigningKey::from_bytes(&[8; 32]).verifying_key().as_bytes(),
+        ));
+        assert!(verify(&forged).is_err());
+    }
+
+    #[test]
+    fn signer_keys_are_32_bytes() {
+        let key = SigningKey::from_bytes(&[7; 32]);
+        assert_eq!(signing_key(key.to_bytes().to_vec()).unwrap(), key);
+        assert!(signing_key(vec![7; 31]).is_err());
+    }
+}
@@ -0,0 +1,808 @@
+//! Syncing documents with a server over the browser's `WebSocket` API.
+//!
+//! A handle loaded with `syncServerUrl`, or connected with `connect`, has one
+//! socket, which all of its documents share. Each document's engine talks to the
+//! server through its own [`WebSocketConnection`], which only receives the messages
+//! about that document. Those that aren't about any document in particular, like blob
+//! requests, are shared among the engines as [`fanout`](crate::fanout) describes.
+//! Frames are single bincode-encoded messages, as described in
+//! `subduction_websocket/schema/messages.md`, after the [handshake](crate::handshake)
+//! the socket opens with. A socket the server closes is reopened as
+//! [`reconnect`](crate::reconnect) describes.
+//!
+//! A server may flow control the socket by granting credit. Once it has, sends
+//! wait for credit rather than going past the window it set, so an overloaded
+//! server slows its clients down instead of their calls timing out.
+//!
+//! Requests for access to documents (see [`access`](crate::access)) also go over the
+//! socket. The server passes on the ones other peers send, which the socket holds
+//! for the handle until it next looks at its pending requests.
+//!
+//! A socket can also be opened with [`PrivacyOptions`], to keep what the server
+//! learns about the handle's documents to a minimum; see [`privacy`](crate::privacy).
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    fmt,
+    rc::{Rc, Weak},
+    time::Duration,
+};
+
+use futures::{
+    channel::{mpsc, oneshot},
+    future::{self, Either, LocalBoxFuture},
+    lock::Mutex,
+    FutureExt, StreamExt,
+};
+use js_sys::{ArrayBuffer, Uint8Array};
+use sedimentree_core::{future::Local, SedimentreeId, SedimentreeSummary};
+use serde::Serialize;
+use subduction_core::{
+    connection::{
+        handshake::Handshake,
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+};
+use wasm_bindgen::{prelude::*, JsCast};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{BinaryType, MessageEvent, WebSocket};
+
+use crate::{
+    access::AccessRequest,
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+    fanout::{Fanouts, GATHER_TIMEOUT_MS},
+    handshake::Greeting,
+    privacy::{Privacy, PrivacyOptions},
+};
+
+/// How long to wait for a batch sync response when the caller doesn't say.
+pub(crate) const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where a document's messages go, and who to tell when it receives commits.
+struct Route {
+    /// The connection the route belongs to, so a stale one can't remove its successor.
+    serial: u64,
+    handle_id: u32,
+    doc_id: String,
+    inbox: mpsc::UnboundedSender<Message>,
+}
+
+/// One socket to the sync server, shared by all of a handle's documents.
+pub(crate) struct SyncSocket {
+    ws: WebSocket,
+    handle_id: u32,
+    /// The socket's ID among the resources tracked (see [`diagnostics`]).
+    diagnostics_id: u32,
+    /// The server's identity, the key it proved it holds in the handshake.
+    server: PeerId,
+    /// This handle's identity, used to tag its requests.
+    requestor: PeerId,
+    next_nonce: Cell<u128>,
+    next_serial: Cell<u64>,
+    routes: RefCell<HashMap<SedimentreeId, Route>>,
+    privacy: Privacy,
+    /// The documents routed, by the blinded ID the server knows them by, if IDs are
+    /// blinded.
+    blinded: RefCell<HashMap<SedimentreeId, SedimentreeId>>,
+    /// Frames held back to be sent together, if frames are batched.
+    outbox: RefCell<Vec<Vec<u8>>>,
+    /// The socket itself, for flushing the outbox later.
+    this: Weak<Self>,
+    /// Batch sync calls waiting for their response.
+    pending: RefCell<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>,
+    /// Access requests passed on by the server, whose signatures check out.
+    access_requests: RefCell<Vec<AccessRequest>>,
+    flow: RefCell<Flow>,
+    /// The messages about no document in particular, shared among the routes.
+    fanouts: RefCell<Fanouts>,
+    /// Told when the socket closes, whether the server closed it.
+    closed: RefCell<Vec<oneshot::Sender<bool>>>,
+    shut_down: Cell<bool>,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+    _on_close: Closure<dyn FnMut()>,
+}
+
+impl fmt::Debug for SyncSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SyncSocket")
+            .field("url", &self.ws.url())
+            .field("documents", &self.routes.borrow().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl SyncSocket {
+    /// Connect handle `handle_id` to `url`, resolving once the socket is open and the
+    /// server has proved its key in the handshake we run as `ours`.
+    pub(crate) async fn connect(
+        url: &str,
+        handle_id: u32,
+        ours: &Handshake,
+        privacy: &PrivacyOptions,
+    ) -> Result<Rc<Self>, JsValue> {
+        Self::adopt(WebSocket::new(url)?, handle_id, ours, privacy).await
+    }
+
+    /// Sync handle `handle_id` over `ws`, a socket the app opened, resolving once it's
+    /// open and the server has proved its key in the handshake we run as `ours`.
+    ///
+    /// The server greets the socket as soon as it opens, so `ws` must be handed over
+    /// while it's still connecting, or in the same task it opened in.
+    pub(crate) async fn adopt(
+        ws: WebSocket,
+        handle_id: u32,
+        ours: &Handshake,
+        privacy: &PrivacyOptions,
+    ) -> Result<Rc<Self>, JsValue> {
+        ws.set_binary_type(BinaryType::Arraybuffer);
+        match ws.ready_state() {
+            WebSocket::OPEN => {}
+            WebSocket::CONNECTING => {
+                let opened = js_sys::Promise::new(&mut |resolve, reject| {
+                    ws.set_onopen(Some(&resolve));
+                    ws.set_onerror(Some(&reject));
+                });
+                let result = JsFuture::from(opened).await;
+                ws.set_onopen(None);
+                ws.set_onerror(None);
+                result.map_err(|_| {
+                    BeelayError::ConnectionFailure(format!("could not connect to {}", ws.url()))
+                })?;
+            }
+            _ => return Err(BeelayError::ConnectionFailure("socket is closed".into()).into()),
+        }
+        let sender = ws.clone();
+        let mut greeting =
+            Greeting::listen(&ws, Box::new(move |bytes| sender.send_with_u8_array(bytes)));
+        let server = greeting.hello(ours).await?;
+        drop(greeting);
+        let requestor = ours.hello().peer_id;
+
+        let privacy = Privacy::new(privacy, &ws.url());
+        let diagnostics_id = diagnostics::next_id();
+        diagnostics::track(
+            ResourceKind::Connection,
+            handle_id,
+            diagnostics_id.to_string(),
+        );
+        let socket = Rc::new_cyclic(|socket: &Weak<Self>| {
+            let on_message = {
+                let socket = socket.clone();
+                Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+                    if let Some(socket) = socket.upgrade() {
+                        socket.receive(&event);
+                    }
+                })
+            };
+            let on_close = {
+                let socket = socket.clone();
+                Closure::<dyn FnMut()>::new(move || {
+                    if let Some(socket) = socket.upgrade() {
+                        socket.close();
+                    }
+                })
+            };
+            ws.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+            ws.set_onclose(Some(on_close.as_ref().unchecked_ref()));
+
+            Self {
+                ws,
+                handle_id,
+                diagnostics_id,
+                server,
+                requestor,
+                next_nonce: Cell::new(0),
+                next_serial: Cell::new(0),
+                routes: RefCell::new(HashMap::new()),
+                privacy,
+                blinded: RefCell::new(HashMap::new()),
+                outbox: RefCell::new(Vec::new()),
+                this: socket.clone(),
+                pending: RefCell::new(HashMap::new()),
+                access_requests: RefCell::new(Vec::new()),
+                flow: RefCell::new(Flow::default()),
+                fanouts: RefCell::new(Fanouts::default()),
+                closed: RefCell::new(Vec::new()),
+                shut_down: Cell::new(false),
+                _on_message: on_message,
+                _on_close: on_close,
+            }
+        });
+        socket.sync_decoys();
+        Ok(socket)
+    }
+
+    /// Ask the server to sync each decoy document, ignoring its responses.
+    fn sync_decoys(self: &Rc<Self>) {
+        let decoys = self.privacy.decoys(self.requestor).collect::<Vec<_>>();
+        if decoys.is_empty() {
+            return;
+        }
+        let socket = self.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            for id in decoys {
+                let nonce = socket.next_nonce.get();
+                socket.next_nonce.set(nonce.wrapping_add(1));
+                let request = BatchSyncRequest {
+                    id,
+                    req_id: RequestId {
+                        requestor: socket.requestor,
+                        nonce,
+                    },
+                    sedimentree_summary: SedimentreeSummary::default(),
+                    summary_token: None,
+                    have: None,
+                    since: Vec::new(),
+                };
+                if socket.send(&request.into()).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Open a connection for one document, replacing any earlier one for the same document.
+    ///
+    /// Commits that arrive for it are pulled into document `doc_id` of handle `handle_id`.
+    pub(crate) fn connection(
+        self: &Rc<Self>,
+        id: SedimentreeId,
+        handle_id: u32,
+        doc_id: String,
+    ) -> WebSocketConnection {
+        let (inbox, inbound) = mpsc::unbounded();
+        let serial = self.next_serial.get();
+        self.next_serial.set(serial + 1);
+        if self.privacy.blinds() {
+            self.blinded.borrow_mut().insert(self.privacy.blind(id), id);
+        }
+        let replaced = self.routes.borrow_mut().insert(
+            id,
+            Route {
+                serial,
+                handle_id,
+                doc_id,
+                inbox,
+            },
+        );
+        if let Some(replaced) = replaced {
+            self.route_gone(replaced.serial);
+        }
+        WebSocketConnection {
+            socket: self.clone(),
+            id,
+            serial,
+            inbound: Rc::new(Mutex::new(inbound)),
+        }
+    }
+
+    /// End a document's connection, so its messages are no longer delivered.
+    pub(crate) fn forget(&self, id: SedimentreeId) {
+        let removed = self.routes.borrow_mut().remove(&id);
+        self.blinded.borrow_mut().remove(&self.privacy.blind(id));
+        if let Some(removed) = removed {
+            self.route_gone(removed.serial);
+        }
+    }
+
+    /// Stop waiting for the route with serial `serial` to answer requests, sending
+    /// any answers that were only waiting for it.
+    fn route_gone(&self, serial: u64) {
+        let ready = self.fanouts.borrow_mut().route_gone(serial);
+        self.send_soon(ready);
+    }
+
+    /// Send `messages` from outside a task.
+    fn send_soon(&self, messages: Vec<Message>) {
+        let Some(socket) = self.this.upgrade().filter(|_| !messages.is_empty()) else {
+            return;
+        };
+        wasm_bindgen_futures::spawn_local(async move {
+            for message in messages {
+                if socket.send(&message).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Resolves once the socket has closed, to whether the server closed it rather
+    /// than the handle shutting it down.
+    pub(crate) fn closed(&self) -> oneshot::Receiver<bool> {
+        let (tx, rx) = oneshot::channel();
+        if self.is_open() {
+            self.closed.borrow_mut().push(tx);
+        } else {
+            let _ = tx.send(!self.shut_down.get());
+        }
+        rx
+    }
+
+    /// The server's peer ID.
+    pub(crate) const fn server(&self) -> PeerId {
+        self.server
+    }
+
+    /// Whether the socket is still open.
+    pub(crate) fn is_open(&self) -> bool {
+        self.ws.ready_state() == WebSocket::OPEN
+    }
+
+    /// Close the socket, ending every document's connection.
+    pub(crate) fn shutdown(&self) {
+        self.shut_down.set(true);
+        self.ws.set_onmessage(None);
+        self.ws.set_onclose(None);
+        let _ = self.ws.close();
+        self.close();
+    }
+
+    /// Send a request for access to a document, under the document's `key` (see
+    /// [`access::document_key`](crate::access::document_key)).
+    pub(crate) async fn send_access_request(
+        &self,
+        key: SedimentreeId,
+        request: &AccessRequest,
+    ) -> Result<(), SocketError> {
+        let request = request
+            .encode()
+            .map_err(|err| SocketError::Encode(err.to_string()))?;
+        self.send(&Message::AccessRequest { id: key, request })
+            .await
+    }
+
+    /// Take the access requests the server has passed on since the last call.
+    pub(crate) fn take_access_requests(&self) -> Vec<AccessRequest> {
+        std::mem::take(&mut *self.access_requests.borrow_mut())
+    }
+
+    /// How the server has throttled what this socket sends.
+    pub(crate) fn flow_stats(&self) -> FlowStats {
+        let flow = self.flow.borrow();
+        FlowStats {
+            window: flow.window,
+            unacked: flow.unacked,
+            waiting: flow.waiting.len(),
+            throttled: flow.throttled,
+            throttled_ms: flow.throttled_ms,
+        }
+    }
+
+    /// Send `message`, after waiting for the server's credit if it flow controls us.
+    ///
+    /// If frames are batched, this only queues it to be sent with the next batch.
+    async fn send(&self, message: &Message) -> Result<(), SocketError> {
+        let mut bytes = if self.privacy.blinds() {
+            let mut message = message.clone();
+            if let Some(id) = document_mut(&mut message) {
+                *id = self.privacy.blind(*id);
+            }
+            bincode::serde::encode_to_vec(&message, bincode::config::standard())
+        } else {
+            bincode::serde::encode_to_vec(message, bincode::config::standard())
+        }
+        .map_err(|err| SocketError::Encode(err.to_string()))?;
+        self.privacy.pad(&mut bytes);
+        self.reserve(bytes.len() as u64).await;
+
+        let Some(batch_ms) = self.privacy.batch_ms() else {
+            return self.send_frame(&bytes);
+        };
+        let mut outbox = self.outbox.borrow_mut();
+        if outbox.is_empty() {
+            let socket = self.this.clone();
+            crate::notify::schedule(batch_ms, move || {
+                if let Some(socket) = socket.upgrade() {
+                    socket.flush();
+                }
+            });
+        }
+        outbox.push(bytes);
+        Ok(())
+    }
+
+    /// Send one encoded frame.
+    fn send_frame(&self, bytes: &[u8]) -> Result<(), SocketError> {
+        self.ws
+            .send_with_u8_array(bytes)
+            .map_err(|err| SocketError::Send(format!("{err:?}")))
+    }
+
+    /// Send every frame held back for the current batch.
+    fn flush(&self) {
+        let frames = std::mem::take(&mut *self.outbox.borrow_mut());
+        for frame in frames {
+            if self.send_frame(&frame).is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Wait until `bytes` more fit in the server's window, then count them as sent.
+    ///
+    /// A message larger than the whole window goes once nothing else is outstanding.
+    async fn reserve(&self, bytes: u64) {
+        let mut throttled_since: Option<f64> = None;
+        loop {
+            let credit = {
+                let mut flow = self.flow.borrow_mut();
+                let fits = flow
+                    .window
+                    .is_none_or(|window| flow.unacked == 0 || flow.unacked + bytes <= window);
+                if fits {
+                    flow.unacked += bytes;
+                    if let Some(since) = throttled_since {
+                        flow.throttled_ms += js_sys::Date::now() - since;
+                    }
+                    return;
+                }
+                if throttled_since.is_none() {
+                    flow.throttled += 1;
+                    throttled_since = Some(js_sys::Date::now());
+                }
+                let (tx, rx) = oneshot::channel();
+                flow.waiting.push(tx);
+                rx
+            };
+            // Woken by credit, or by the socket closing
+            let _ = credit.await;
+        }
+    }
+
+    /// Take credit from the server, waking any sends waiting for it. The first
+    /// credit sets the window.
+    fn credit(&self, bytes: u64) {
+        let mut flow = self.flow.borrow_mut();
+        if flow.window.is_some() {
+            flow.unacked = flow.unacked.saturating_sub(bytes);
+        } else {
+            flow.window = Some(bytes);
+            flow.unacked = 0;
+        }
+        for waiting in flow.waiting.drain(..) {
+            let _ = waiting.send(());
+        }
+    }
+
+    /// Route an incoming frame to the call or document waiting for it.
+    fn receive(&self, event: &MessageEvent) {
+        let Ok(buffer) = event.data().dyn_into::<ArrayBuffer>() else {
+            return;
+        };
+        let bytes = Uint8Array::new(&buffer).to_vec();
+        let Ok((mut message, _)) =
+            bincode::serde::decode_from_slice::<Message, _>(&bytes, bincode::config::standard())
+        else {
+            return;
+        };
+        if self.privacy.blinds() {
+            if let Some(id) = document_mut(&mut message) {
+                // Anything else is about a decoy
+                let Some(&unblinded) = self.blinded.borrow().get(id) else {
+                    return;
+                };
+                *id = unblinded;
+            }
+        }
+
+        if let Message::Credit { bytes } = message {
+            self.credit(bytes);
+            return;
+        }
+        if let Message::AccessRequest { request, .. } = &message {
+            if let Some(request) = AccessRequest::decode(request) {
+                self.access_requests.borrow_mut().push(request);
+            }
+            return;
+        }
+        if let Message::BatchSyncResponse(response) = &message {
+            if let Some(waiting) = self.pending.borrow_mut().remove(&response.req_id) {
+                let _ = waiting.send(response.clone());
+                return;
+            }
+        }
+
+        let routes = self.routes.borrow();
+        let Some(id) = document_of(&message) else {
+            let serials = routes.values().map(|route| route.serial);
+            let mut fanouts = self.fanouts.borrow_mut();
+            if let Some(gathering) = fanouts.request(&message, serials) {
+                for route in routes.values() {
+                    let _ = route.inbox.unbounded_send(message.clone());
+                }
+                let socket = self.this.clone();
+                crate::notify::schedule(GATHER_TIMEOUT_MS, move || {
+                    if let Some(socket) = socket.upgrade() {
+                        let answer = socket.fanouts.borrow_mut().expire(gathering);
+                        socket.send_soon(answer.into_iter().collect());
+                    }
+                });
+                return;
+            }
+            for (serial, message) in fanouts.deliver(message) {
+                if let Some(route) = routes.values().find(|route| route.serial == serial) {
+                    let _ = route.inbox.unbounded_send(message);
+                }
+            }
+            return;
+        };
+        let Some(route) = routes.get(&id) else {
+            return;
+        };
+        let pull = brings_commits(&message);
+        let _ = route.inbox.unbounded_send(message);
+        if pull {
+            pull_soon(route.handle_id, route.doc_id.clone());
+        }
+    }
+
+    /// Drop every route and pending call, so that their receivers see the socket closed.
+    ///
+    /// Sends waiting for credit go ahead, and fail on the closed socket.
+    fn close(&self) {
+        diagnostics::untrack(
+            ResourceKind::Connection,
+            self.handle_id,
+            &self.diagnostics_id.to_string(),
+        );
+        self.routes.borrow_mut().clear();
+        self.blinded.borrow_mut().clear();
+        self.outbox.borrow_mut().clear();
+        self.pending.borrow_mut().clear();
+        self.fanouts.borrow_mut().clear();
+        let server_closed = !self.shut_down.get();
+        for closed in self.closed.borrow_mut().drain(..) {
+            let _ = closed.send(server_closed);
+        }
+        let mut flow = self.flow.borrow_mut();
+        flow.window = None;
+        flow.waiting.clear();
+    }
+}
+
+/// The document a message is about, if any.
+pub(crate) fn document_of(message: &Message) -> Option<SedimentreeId> {
+    match message {
+        Message::LooseCommit { id, .. }
+        | Message::Chunk { id, .. }
+        | Message::CommitUpload { id, .. }
+        | Message::BatchSyncRequest(BatchSyncRequest { id, .. })
+        | Message::BatchSyncResponse(BatchSyncResponse { id, .. })
+        | Message::Backfill { id, .. }
+        | Message::RelaySignal { id, .. }
+        | Message::Signal { id, .. }
+        | Message::DocumentTtl { id, .. } => Some(*id),
+        Message::BlobsRequest(_)
+        | Message::BlobsResponse(_)
+        | Message::BlobRangeRequest { .. }
+        | Message::BlobRangeResponse { .. }
+        | Message::BlobRangeNotFound { .. }
+        | Message::AccessRequest { .. }
+        | Message::CommitUploadAck { .. }
+        | Message::Credit { .. }
+        | Message::Subscribe { .. }
+        | Message::Subscribed { .. } => None,
+    }
+}
+
+/// The ID of the document a message is about, if any, to rewrite.
+fn document_mut(message: &mut Message) -> Option<&mut SedimentreeId> {
+    match message {
+        Message::LooseCommit { id, .. }
+        | Message::Chunk { id, .. }
+        | Message::CommitUpload { id, .. }
+        | Message::BatchSyncRequest(BatchSyncRequest { id, .. })
+        | Message::BatchSyncResponse(BatchSyncResponse { id, .. })
+        | Message::Backfill { id, .. }
+        | Message::RelaySignal { id, .. }
+        | Message::Signal { id, .. }
+        | Message::DocumentTtl { id, .. } => Some(id),
+        Message::BlobsRequest(_)
+        | Message::BlobsResponse(_)
+        | Message::BlobRangeRequest { .. }
+        | Message::BlobRangeResponse { .. }
+        | Message::BlobRangeNotFound { .. }
+        | Message::AccessRequest { .. }
+        | Message::CommitUploadAck { .. }
+        | Message::Credit { .. }
+        | Message::Subscribe { .. }
+        | Message::Subscribed { .. } => None,
+    }
+}
+
+/// Credit from the server, once it has set a window.
+#[derive(Debug, Default)]
+struct Flow {
+    window: Option<u64>,
+    /// Bytes sent that the server hasn't given credit back for yet.
+    unacked: u64,
+    /// Sends waiting for credit.
+    waiting: Vec<oneshot::Sender<()>>,
+    throttled: u64,
+    throttled_ms: f64,
+}
+
+/// The result of `Beelay.flowStats`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FlowStats {
+    /// The window the server set, or `null` if it doesn't flow control us.
+    window: Option<u64>,
+    unacked: u64,
+    waiting: usize,
+    /// Sends that have had to wait for credit.
+    throttled: u64,
+    /// The total time sends have spent waiting for credit, in milliseconds.
+    throttled_ms: f64,
+}
+
+/// Whether `message` brings commits for its document's engine to store.
+pub(crate) const fn brings_commits(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::LooseCommit { .. }
+            | Message::Chunk { .. }
+            | Message::CommitUpload { .. }
+            | Message::BatchSyncResponse(_)
+            | Message::Backfill { .. }
+    )
+}
+
+/// Pull a document's new commits into it from its engine, once the engine has had a
+/// chance to store them.
+pub(crate) fn pull_soon(handle_id: u32, doc_id: String) {
+    crate::notify::schedule(0, move || {
+        wasm_bindgen_futures::spawn_local(async move {
+            let _ = crate::pull_engine_commits(handle_id, &doc_id).await;
+        });
+    });
+}
+
+/// Wait for `duration` on the JS event loop.
+pub(crate) async fn sleep(duration: Duration) {
+    let (done, wait) = oneshot::channel();
+    let delay_ms = u32::try_from(duration.as_millis()).unwrap_or(u32::MAX);
+    crate::notify::schedule(delay_ms, move || {
+        let _ = done.send(());
+    });
+    let _ = wait.await;
+}
+
+/// Errors from a [`WebSocketConnection`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum SocketError {
+    /// A message couldn't be encoded.
+    #[error("could not encode message: {0}")]
+    Encode(String),
+
+    /// The browser refused to send a frame.
+    #[error("could not send message: {0}")]
+    Send(String),
+
+    /// The socket closed.
+    #[error("connection closed")]
+    Closed,
+
+    /// The server didn't answer a call in time.
+    #[error("timed out waiting for a response")]
+    Timeout,
+}
+
+/// One document's view of a [`SyncSocket`].
+#[derive(Clone)]
+pub(crate) struct WebSocketConnection {
+    socket: Rc<SyncSocket>,
+    id: SedimentreeId,
+    serial: u64,
+    inbound: Rc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+}
+
+impl fmt::Debug for WebSocketConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebSocketConnection")
+            .field("socket", &self.socket)
+            .field("id", &self.id)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PartialEq for WebSocketConnection {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.socket, &other.socket) && self.serial == other.serial
+    }
+}
+
+impl Connection<Local> for WebSocketConnection {
+    type DisconnectionError = SocketError;
+    type SendError = SocketError;
+    type RecvError = SocketError;
+    type CallError = SocketError;
+
+    fn peer_id(&self) -> PeerId {
+        self.socket.server
+    }
+
+    fn disconnect(&mut self) -> LocalBoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        let mut routes = self.socket.routes.borrow_mut();
+        if routes
+            .get(&self.id)
+            .is_some_and(|route| route.serial == self.serial)
+        {
+            routes.remove(&self.id);
+            drop(routes);
+            self.socket.route_gone(self.serial);
+        }
+        async { Ok(()) }.boxed_local()
+    }
+
+    fn send(&self, message: Message) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
+        async move {
+            let answered = self
+                .socket
+                .fanouts
+                .borrow_mut()
+                .answer(self.serial, &message);
+            let Some(ready) = answered else {
+                self.socket
+                    .fanouts
+                    .borrow_mut()
+                    .asking(self.serial, &message);
+                return self.socket.send(&message).await;
+            };
+            for message in ready {
+                self.socket.send(&message).await?;
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn recv(&self) -> LocalBoxFuture<'_, Result<Message, Self::RecvError>> {
+        async {
+            self.inbound
+                .lock()
+                .await
+                .next()
+                .await
+                .ok_or(SocketError::Closed)
+        }
+        .boxed_local()
+    }
+
+    fn next_request_id(&self) -> LocalBoxFuture<'_, RequestId> {
+        let nonce = self.socket.next_nonce.get();
+        self.socket.next_nonce.set(nonce.wrapping_add(1));
+        let requestor = self.socket.requestor;
+        async move { RequestId { requestor, nonce } }.boxed_local()
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        timeout: Option<Duration>,
+    ) -> LocalBoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        async move {
+            let (respond, response) = oneshot::channel();
+            let req_id = req.req_id;
+            self.socket.pending.borrow_mut().insert(req_id, respond);
+            if let Err(err) = self.socket.send(&Message::BatchSyncRequest(req)).await {
+                self.socket.pending.borrow_mut().remove(&req_id);
+                return Err(err);
+            }
+
+            let timeout = sleep(timeout.unwrap_or(DEFAULT_CALL_TIMEOUT));
+            match future::select(response, Box::pin(timeout)).await {
+                Either::Left((response, _)) => response.map_err(|_| SocketError::Closed),
+                Either::Right(((), _)) => {
+                    self.socket.pending.borrow_mut().remove(&req_id);
+                    Err(SocketError::Timeout)
+                }
+            }
+        }
+        .boxed_local()
+    }
+}
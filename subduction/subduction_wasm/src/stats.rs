@@ -0,0 +1,90 @@
+//! Per-document statistics, kept up to date as commits are applied.
+//!
+//! Everything `docStats` reports is updated incrementally in [`DocStats::record`],
+//! so dashboards can poll it for many documents without their histories ever
+//! being scanned or copied out.
+
+use std::collections::HashSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::CommitRecord;
+
+/// Running statistics for one document.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct DocStats {
+    commits: usize,
+    content_bytes: u64,
+    bundles: usize,
+    /// Commits that no applied commit has as a parent.
+    heads: HashSet<String>,
+    /// Every hash that some applied commit has as a parent.
+    referenced: HashSet<String>,
+    authors: HashSet<String>,
+    first_activity_ms: Option<f64>,
+    last_activity_ms: Option<f64>,
+}
+
+/// The result of `Beelay.docStats`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DocStatsSummary {
+    commits: usize,
+    content_bytes: u64,
+    /// Runs of history stored as a unit, made by `compact` here or by a peer.
+    bundles: usize,
+    heads: usize,
+    /// When the first commit was applied here, in milliseconds since the epoch.
+    first_activity: Option<f64>,
+    /// When the latest commit was applied here, in milliseconds since the epoch.
+    last_activity: Option<f64>,
+    /// The number of distinct peers known to have made commits.
+    authors: usize,
+}
+
+impl DocStats {
+    /// Account for a newly applied commit, applied at `now_ms`.
+    ///
+    /// Commits may arrive before their parents, so a commit only counts as a head
+    /// if nothing applied so far builds on it.
+    pub(crate) fn record(&mut self, commit: &CommitRecord, now_ms: f64) {
+        self.commits += 1;
+        self.content_bytes += commit.contents.len() as u64;
+
+        for parent in &commit.parents {
+            self.heads.remove(parent);
+            self.referenced.insert(parent.clone());
+        }
+        if !self.referenced.contains(&commit.hash) {
+            self.heads.insert(commit.hash.clone());
+        }
+
+        if let Some(author) = &commit.meta.author {
+            self.authors.insert(author.clone());
+        }
+        self.first_activity_ms.get_or_insert(now_ms);
+        self.last_activity_ms = Some(now_ms);
+    }
+
+    /// Account for a newly made or received bundle.
+    pub(crate) fn record_bundle(&mut self) {
+        self.bundles += 1;
+    }
+
+    /// The bytes of contents of the commits applied so far.
+    pub(crate) const fn content_bytes(&self) -> u64 {
+        self.content_bytes
+    }
+
+    pub(crate) fn summary(&self) -> DocStatsSummary {
+        DocStatsSummary {
+            commits: self.commits,
+            content_bytes: self.content_bytes,
+            bundles: self.bundles,
+            heads: self.heads.len(),
+            first_activity: self.first_activity_ms,
+            last_activity: self.last_activity_ms,
+            authors: self.authors.len(),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Where a handle keeps its documents, chosen by `Beelay.load`'s `storage` option.
+//!
+//! Documents live in memory unless the handle is given somewhere to persist
+//! them: IndexedDB (`storage: "indexeddb"`), or a storage adapter object (see
+//! [`adapter`](crate::adapter)). Either way, the handle's identity, groups,
+//! and documents (with their membership, but no commits) are saved as a
+//! CBOR-encoded [`Snapshot`] next to the documents' data, and the next load
+//! from the same place reopens them. The handle's [changes feed](crate::feed) is
+//! saved the same way, in a record of its own.
+//!
+//! Handles (or apps) sharing a database or adapter pass `namespace` to keep
+//! apart: everything a handle saves is keyed under its namespace, and loading
+//! reopens only what's there. Handles without one share a single unnamed area,
+//! as before namespaces existed.
+
+use std::convert::Infallible;
+
+use futures::{future::LocalBoxFuture, FutureExt};
+use sedimentree_core::{
+    future::Local,
+    storage::{
+        header::{Codec, StorageHeader},
+        MemoryStorage, Storage,
+    },
+    Blob, Chunk, Digest, LooseCommit, SedimentreeId,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
+
+use crate::{
+    adapter::{self, AdapterStorage, JsStorageAdapter},
+    backup::Snapshot,
+    error::BeelayError,
+    feed::ChangeFeed,
+    idb::{self, Database, IndexedDbStorage},
+    tasks::{self, TaskKind},
+};
+
+/// An error from a storage backend, or from encoding what's stored there.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("storage error: {0}")]
+pub(crate) struct StorageError(String);
+
+impl StorageError {
+    pub(crate) fn new(message: impl Into<String>) -> Self {
+        Self(message.into())
+    }
+}
+
+impl From<JsValue> for StorageError {
+    fn from(value: JsValue) -> Self {
+        Self(
+            value
+                .dyn_ref::<js_sys::Error>()
+                .map(|err| String::from(err.message()))
+                .or_else(|| value.as_string())
+                .unwrap_or_else(|| format!("{value:?}")),
+        )
+    }
+}
+
+impl From<StorageError> for JsValue {
+    fn from(err: StorageError) -> Self {
+        BeelayError::from(err).into()
+    }
+}
+
+/// Somewhere a handle persists its documents.
+#[derive(Debug, Clone)]
+pub(crate) enum Backend {
+    IndexedDb(Database),
+    Adapter(JsStorageAdapter),
+}
+
+impl Backend {
+    /// Open the backend chosen by the `storage` option, or `None` to keep documents in memory.
+    ///
+    /// The option may be `"memory"`, `"indexeddb"` (using the database `database_name`),
+    /// or a storage adapter object. Keys are kept in `namespace` if there is one.
+    pub(crate) async fn open(
+        option: JsValue,
+        database_name: Option<&str>,
+        namespace: Option<&str>,
+    ) -> Result<Option<Self>, StorageError> {
+        if let Some(namespace) = namespace {
+            if namespace.is_empty() || namespace.contains('/') {
+                return Err(StorageError::new(format!(
+                    "invalid namespace {namespace:?}: expected a non-empty name without '/'"
+                )));
+            }
+        }
+        if option.is_undefined() || option.is_null() {
+            return Ok(None);
+        }
+        match option.as_string().as_deref() {
+            Some("memory") => Ok(None),
+            Some("indexeddb") => {
+                let name = database_name.unwrap_or(idb::DEFAULT_DATABASE_NAME);
+                Ok(Some(Self::IndexedDb(Database::open(name, namespace).await?)))
+            }
+            Some(other) => Err(StorageError::new(format!(
+                "unknown storage {other:?}: expected \"memory\", \"indexeddb\", or an adapter"
+            ))),
+            None => Ok(Some(Self::Adapter(JsStorageAdapter::new(option, namespace)?))),
+        }
+    }
+
+    /// The name of the IndexedDB database, and the handle's namespace in it, which
+    /// other tabs may share.
+    pub(crate) fn database_name(&self) -> Option<String> {
+        match self {
+            Self::IndexedDb(database) => Some(database.name()),
+            Self::Adapter(_) => None,
+        }
+    }
+
+    /// The same backend, but with the documents' writes held back until
+    /// [`Backend::commit`], so they can be applied together.
+    ///
+    /// IndexedDB applies them in one transaction. Storage adapters have no
+    /// transactions, so their writes go through straight away.
+    pub(crate) fn batched(&self) -> Self {
+        match self {
+            Self::IndexedDb(database) => Self::IndexedDb(database.batched()),
+            Self::Adapter(adapter) => Self::Adapter(adapter.clone()),
+        }
+    }
+
+    /// Apply the writes held back by a [batched](Backend::batched) backend.
+    pub(crate) async fn commit(&self) -> Result<(), StorageError> {
+        match self {
+            Self::IndexedDb(database) => database.commit().await,
+            Self::Adapter(_) => Ok(()),
+        }
+    }
+
+    /// The handle last saved with [`Backend::save_handle`], if any.
+    pub(crate) async fn load_handle(&self) -> Result<Option<Snapshot>, StorageError> {
+        let saved = match self {
+            Self::IndexedDb(database) => database.load_handle().await?,
+            Self::Adapter(adapter) => adapter.load(&[adapter::HANDLE_KEY]).await?,
+        };
+        saved.as_deref().map(from_cbor).transpose()
+    }
+
+    /// Replace the saved handle.
+    ///
+    /// The write is started rather than awaited, so this can be called from
+    /// synchronous methods. Writes are applied in the order they're started.
+    pub(crate) fn save_handle(&self, snapshot: &Snapshot) -> Result<(), StorageError> {
+        let bytes = to_cbor(snapshot)?;
+        match self {
+            Self::IndexedDb(database) => database.save_handle(&bytes),
+            Self::Adapter(adapter) => start_saving(adapter, adapter::HANDLE_KEY, &bytes),
+        }
+    }
+
+    /// The changes feed last saved with [`Backend::save_feed`], if any.
+    pub(crate) async fn load_feed(&self) -> Result<Option<ChangeFeed>, StorageError> {
+        let saved = match self {
+            Self::IndexedDb(database) => database.load_feed().await?,
+            Self::Adapter(adapter) => adapter.load(&[adapter::FEED_KEY]).await?,
+        };
+        saved.as_deref().map(from_cbor).transpose()
+    }
+
+    /// Replace the saved changes feed, started like [`Backend::save_handle`].
+    pub(crate) fn save_feed(&self, feed: &ChangeFeed) -> Result<(), StorageError> {
+        let bytes = to_cbor(feed)?;
+        match self {
+            Self::IndexedDb(database) => database.save_feed(&bytes),
+            Self::Adapter(adapter) => start_saving(adapter, adapter::FEED_KEY, &bytes),
+        }
+    }
+
+    /// The namespaces handles have saved anything in, not counting the unnamed one.
+    pub(crate) async fn namespaces(&self) -> Result<Vec<String>, StorageError> {
+        let mut namespaces = match self {
+            Self::IndexedDb(database) => database.namespaces().await?,
+            Self::Adapter(adapter) => adapter.namespaces().await?,
+        };
+        namespaces.sort();
+        namespaces.dedup();
+        Ok(namespaces)
+    }
+
+    /// Delete every document's data in the handle's namespace, keeping the saved handle.
+    pub(crate) async fn clear_documents(&self) -> Result<(), StorageError> {
+        match self {
+            Self::IndexedDb(database) => database.clear_documents(),
+            Self::Adapter(adapter) => {
+                for key in adapter.list_one_level(&[]).await? {
+                    let first = key.first().map(String::as_str);
+                    let kept = [
+                        adapter::HANDLE_KEY,
+                        adapter::FEED_KEY,
+                        adapter::NAMESPACES_KEY,
+                    ];
+                    if !first.is_some_and(|first| kept.contains(&first)) {
+                        adapter.remove_all(key).await?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Delete one document's data.
+    pub(crate) async fn delete_document(&self, id: SedimentreeId) -> Result<(), StorageError> {
+        match self {
+            Self::IndexedDb(database) => database.delete_document(id),
+            Self::Adapter(adapter) => adapter.remove_all(vec![id.to_string()]).await,
+        }
+    }
+}
+
+/// Start saving `bytes` under `key` through `adapter`, without waiting for it.
+fn start_saving(adapter: &JsStorageAdapter, key: &str, bytes: &[u8]) -> Result<(), StorageError> {
+    let saved = adapter.save(&[key], bytes)?;
+    wasm_bindgen_futures::spawn_local(async move {
+        let _ = saved.await;
+    });
+    Ok(())
+}
+
+/// The storage behind one document.
+///
+/// Every operation is timed as a storage task for `Beelay.taskStats`.
+#[derive(Debug, Clone)]
+pub(crate) enum DocStorage {
+    Memory(MemoryStorage),
+    IndexedDb(IndexedDbStorage),
+    Adapter(AdapterStorage),
+}
+
+impl DocStorage {
+    /// Storage for sedimentree `id`, in `backend` if there is one.
+    pub(crate) fn new(backend: Option<&Backend>, id: SedimentreeId, codec: Codec) -> Self {
+        match backend {
+            Some(Backend::IndexedDb(database)) => {
+                Self::IndexedDb(IndexedDbStorage::new(database.clone(), id, codec))
+            }
+            Some(Backend::Adapter(adapter)) => {
+                Self::Adapter(AdapterStorage::new(adapter.clone(), id, codec))
+            }
+            None => Self::Memory(MemoryStorage::with_codec(codec)),
+        }
+    }
+}
+
+impl Storage<Local> for DocStorage {
+    type Error = StorageError;
+
+    fn header(&self) -> StorageHeader {
+        match self {
+            Self::Memory(storage) => Storage::<Local>::header(storage),
+            Self::IndexedDb(storage) => storage.header(),
+            Self::Adapter(storage) => storage.header(),
+        }
+    }
+
+    fn load_loose_commits(&self) -> LocalBoxFuture<'_, Result<Vec<LooseCommit>, Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::load_loose_commits(storage)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.load_loose_commits(),
+            Self::Adapter(storage) => storage.load_loose_commits(),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn save_loose_commit(
+        &self,
+        loose_commit: LooseCommit,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::save_loose_commit(storage, loose_commit)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.save_loose_commit(loose_commit),
+            Self::Adapter(storage) => storage.save_loose_commit(loose_commit),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn save_chunk(&self, chunk: Chunk) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::save_chunk(storage, chunk)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.save_chunk(chunk),
+            Self::Adapter(storage) => storage.save_chunk(chunk),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn load_chunks(&self) -> LocalBoxFuture<'_, Result<Vec<Chunk>, Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::load_chunks(storage)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.load_chunks(),
+            Self::Adapter(storage) => storage.load_chunks(),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn save_blob(&self, blob: Blob) -> LocalBoxFuture<'_, Result<Digest, Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::save_blob(storage, blob)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.save_blob(blob),
+            Self::Adapter(storage) => storage.save_blob(blob),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn load_blob(
+        &self,
+        blob_digest: Digest,
+    ) -> LocalBoxFuture<'_, Result<Option<Blob>, Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::load_blob(storage, blob_digest)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.load_blob(blob_digest),
+            Self::Adapter(storage) => storage.load_blob(blob_digest),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+
+    fn delete_blobs(
+        &self,
+        blob_digests: Vec<Digest>,
+    ) -> LocalBoxFuture<'_, Result<(), Self::Error>> {
+        let operation = match self {
+            Self::Memory(storage) => Storage::<Local>::delete_blobs(storage, blob_digests)
+                .map(infallible)
+                .boxed_local(),
+            Self::IndexedDb(storage) => storage.delete_blobs(blob_digests),
+            Self::Adapter(storage) => storage.delete_blobs(blob_digests),
+        };
+        tasks::timed(TaskKind::Storage, operation).boxed_local()
+    }
+}
+
+pub(crate) fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, StorageError> {
+    let mut bytes = Vec::new();
+    ciborium::into_writer(value, &mut bytes)
+        .map_err(|err| StorageError::new(format!("failed to encode record: {err}")))?;
+    Ok(bytes)
+}
+
+pub(crate) fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, StorageError> {
+    ciborium::from_reader(bytes)
+        .map_err(|err| StorageError::new(format!("failed to decode record: {err}")))
+}
+
+fn infallible<T>(result: Result<T, Infallible>) -> Result<T, StorageError> {
+    Ok(result.unwrap_or_else(|never| match never {}))
+}
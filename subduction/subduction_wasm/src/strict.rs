@@ -0,0 +1,150 @@
+//! Opt-in detection of common API misuse.
+//!
+//! By default a handle is lenient: it skips commits it has already seen, lets
+//! commits name parents it doesn't know, and accepts digests in any case. A
+//! handle loaded with `strict: true` throws a `MisuseError` instead, when
+//!
+//! * a commit names a parent that belongs to another of the handle's documents,
+//! * a commit reuses a digest that already stands for different contents,
+//! * a digest isn't 64 lowercase hex characters, the encoding every API returns, or
+//! * commits are added after the handle was stopped.
+
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+};
+
+use wasm_bindgen::JsValue;
+
+use crate::{CommitInput, DocumentCtx};
+
+thread_local! {
+    /// Strict handles that have been stopped.
+    static STOPPED: RefCell<HashSet<u32>> = RefCell::new(HashSet::new());
+}
+
+/// A misuse of the API caught by strict mode.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum Misuse {
+    #[error(
+        "commit {hash} names parent {parent}, which belongs to document {other}, not {target}; \
+         commits can only build on commits in the same document"
+    )]
+    ForeignParent {
+        hash: String,
+        parent: String,
+        other: String,
+        target: String,
+    },
+
+    #[error(
+        "commit {hash} was already added to {target} with different contents; \
+         a digest must be the hash of exactly one commit, so compute a new one for changed contents"
+    )]
+    ReusedDigest { hash: String, target: String },
+
+    #[error(
+        "digest {digest:?} isn't 64 lowercase hex characters; \
+         use the same encoding that loadDocument returns for every hash and parent"
+    )]
+    DigestEncoding { digest: String },
+
+    #[error("{call} was called after stop(); load a new handle with Beelay.load to keep working")]
+    Stopped { call: &'static str },
+}
+
+impl From<Misuse> for JsValue {
+    fn from(misuse: Misuse) -> Self {
+        let error = js_sys::Error::new(&misuse.to_string());
+        error.set_name("MisuseError");
+        error.into()
+    }
+}
+
+/// Remember that strict handle `handle_id` was stopped.
+pub(crate) fn note_stopped(handle_id: u32) {
+    STOPPED.with(|stopped| stopped.borrow_mut().insert(handle_id));
+}
+
+/// Fail if strict handle `handle_id` has been stopped.
+pub(crate) fn check_running(handle_id: u32, call: &'static str) -> Result<(), Misuse> {
+    if STOPPED.with(|stopped| stopped.borrow().contains(&handle_id)) {
+        return Err(Misuse::Stopped { call });
+    }
+    Ok(())
+}
+
+/// Check commits about to be added to `target` (`"document <id>"`, or a description
+/// of a document not created yet), which is `documents[doc_id]` if it exists.
+pub(crate) fn check_commits(
+    doc_id: &str,
+    target: &str,
+    documents: &HashMap<String, DocumentCtx>,
+    commits: &[CommitInput],
+) -> Result<(), Misuse> {
+    for commit in commits {
+        for digest in std::iter::once(&commit.hash).chain(&commit.parents) {
+            if !is_canonical(digest) {
+                return Err(Misuse::DigestEncoding {
+                    digest: digest.clone(),
+                });
+            }
+        }
+    }
+
+    let doc = documents.get(doc_id);
+    let mut contents = HashMap::<&str, &[u8]>::new();
+    if let Some(doc) = doc {
+        let added = commits
+            .iter()
+            .map(|commit| commit.hash.as_str())
+            .collect::<HashSet<_>>();
+        contents.extend(
+            doc.commits
+                .iter()
+                .filter(|record| added.contains(record.hash.as_str()))
+                .map(|record| (record.hash.as_str(), &*record.contents)),
+        );
+    }
+    for commit in commits {
+        match contents.insert(&commit.hash, &commit.contents) {
+            Some(existing) if existing != commit.contents.as_slice() => {
+                return Err(Misuse::ReusedDigest {
+                    hash: commit.hash.clone(),
+                    target: target.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    for commit in commits {
+        for parent in &commit.parents {
+            let known = doc.is_some_and(|doc| doc.seen.contains(parent))
+                || contents.contains_key(parent.as_str());
+            if known {
+                continue;
+            }
+            if let Some((other, _)) = documents
+                .iter()
+                .find(|(other, doc)| other.as_str() != doc_id && doc.seen.contains(parent))
+            {
+                return Err(Misuse::ForeignParent {
+                    hash: commit.hash.clone(),
+                    parent: parent.clone(),
+                    other: other.clone(),
+                    target: target.to_string(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Whether `digest` is in the encoding the API hands out: 64 lowercase hex characters.
+fn is_canonical(digest: &str) -> bool {
+    digest.len() == 64
+        && digest
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+}
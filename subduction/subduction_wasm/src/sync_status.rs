@@ -0,0 +1,318 @@
+//! Where a document stands with each of its peers, for `Beelay.syncStatus`.
+//!
+//! The engine knows the heads each peer has acknowledged and how many batch syncs
+//! with it have finished, but not when they finished, having no clock. So each
+//! document keeps a [`PeerAck`] per peer it has synced with, catching it up with the
+//! engine whenever commits are pulled from it, and stamping the time when it sees
+//! that another batch sync has finished.
+//!
+//! Each document's engine also reports every batch sync round it finishes to a
+//! [`RoundReports`], which keeps the last one per peer for `syncStatus` and passes
+//! each on to the callbacks registered with `Beelay.watchSyncReports`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use serde::Serialize;
+use subduction_core::sync::report::{Reconciliation, ReportSink, SyncReport, SyncRole};
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+    notify, tasks, Beelay, DocumentCtx, HANDLES,
+};
+
+/// What a document last learned of one peer.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PeerAck {
+    /// The heads the peer has acknowledged, or `None` before any batch sync.
+    heads: Option<Vec<String>>,
+    /// The engine's count of finished batch syncs, as of when `synced_at` was stamped.
+    syncs: u64,
+    /// When a batch sync with the peer was last seen to have finished, in
+    /// milliseconds since the epoch.
+    synced_at: Option<f64>,
+    /// The last batch sync round with the peer that the engine reported.
+    last_round: Option<RoundStatus>,
+}
+
+/// One batch sync round, as reported to `Beelay.watchSyncReports` callbacks and in
+/// `syncStatus`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RoundStatus {
+    /// `"requester"` if this handle asked for the round, or `"responder"`.
+    role: &'static str,
+    /// `"inSync"` if the two sides' summaries matched, `"notModified"` if the
+    /// responder's summary token hadn't changed, `"filter"` if they swapped Bloom
+    /// filters of their loose commits, or `"summaryDiff"`.
+    strategy: &'static str,
+    commits_sent: usize,
+    chunks_sent: usize,
+    commits_received: usize,
+    chunks_received: usize,
+    bytes_sent: u64,
+    bytes_received: u64,
+    duration_ms: f64,
+    /// When the round finished, in milliseconds since the epoch.
+    finished_at: f64,
+}
+
+impl RoundStatus {
+    fn new(report: &SyncReport, finished_at: f64) -> Self {
+        Self {
+            role: match report.role {
+                SyncRole::Requester => "requester",
+                SyncRole::Responder => "responder",
+            },
+            strategy: match report.strategy {
+                Reconciliation::InSync => "inSync",
+                Reconciliation::SummaryDiff => "summaryDiff",
+                Reconciliation::NotModified => "notModified",
+                Reconciliation::Filter => "filter",
+            },
+            commits_sent: report.commits_sent,
+            chunks_sent: report.chunks_sent,
+            commits_received: report.commits_received,
+            chunks_received: report.chunks_received,
+            bytes_sent: report.bytes_sent,
+            bytes_received: report.bytes_received,
+            duration_ms: report.duration.as_secs_f64() * 1000.0,
+            finished_at,
+        }
+    }
+}
+
+/// Where a document's engine reports its batch sync rounds.
+#[derive(Debug, Clone)]
+pub(crate) struct RoundReports {
+    handle_id: u32,
+    doc_id: String,
+}
+
+impl RoundReports {
+    pub(crate) const fn new(handle_id: u32, doc_id: String) -> Self {
+        Self { handle_id, doc_id }
+    }
+}
+
+impl ReportSink for RoundReports {
+    fn now(&self) -> Duration {
+        Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
+    }
+
+    fn report(&self, report: SyncReport) {
+        // The engine may report while the handle is borrowed, so deliver afterwards
+        let (handle_id, doc_id) = (self.handle_id, self.doc_id.clone());
+        notify::schedule(0, move || {
+            let _ = deliver(handle_id, &doc_id, &report);
+        });
+    }
+}
+
+/// Keep `report` as the document's last round with its peer, and pass it on to the
+/// handle's `watchSyncReports` callbacks.
+fn deliver(handle_id: u32, doc_id: &str, report: &SyncReport) -> Result<(), JsValue> {
+    let round = RoundStatus::new(report, js_sys::Date::now());
+    let Some((server, watchers)) = HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let ctx = handles.get_mut(&handle_id)?;
+        let doc = ctx.documents.get_mut(doc_id)?;
+        doc.peers.entry(report.peer).or_default().last_round = Some(round.clone());
+        let server = ctx.sync.as_ref().map(|sync| sync.server());
+        Some((server, ctx.report_watchers.values().cloned().collect::<Vec<_>>()))
+    }) else {
+        return Ok(());
+    };
+    if watchers.is_empty() {
+        return Ok(());
+    }
+
+    let peer_id = if server == Some(report.peer) {
+        JsValue::NULL
+    } else {
+        JsValue::from_str(&hex::encode(report.peer.as_bytes()))
+    };
+    let doc_id = JsValue::from_str(doc_id);
+    let round = serde_wasm_bindgen::to_value(&round).map_err(JsValue::from)?;
+    watchers.iter().try_for_each(|watcher| {
+        tasks::hook(|| watcher.call3(&JsValue::NULL, &doc_id, &peer_id, &round)).map(drop)
+    })
+}
+
+/// One peer's entry in the result of `Beelay.syncStatus`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PeerStatus {
+    /// The peer's ID, or `null` for the sync server.
+    peer_id: Option<String>,
+    /// Whether the peer is believed to have exactly the document's heads, with no
+    /// batch sync with it outstanding.
+    up_to_date: bool,
+    /// Commits here that the peer isn't known to have, or `null` before any batch sync.
+    pending_upload: Option<usize>,
+    /// Heads the peer has acknowledged that haven't reached the document yet.
+    pending_download: Option<usize>,
+    /// When a batch sync with the peer last finished, in milliseconds since the epoch.
+    last_synced_at: Option<f64>,
+    /// The last batch sync round with the peer, or `null` before any.
+    last_round: Option<RoundStatus>,
+}
+
+/// Catch the document's record of its peers up with its engine.
+pub(crate) async fn note_peer_syncs(handle_id: u32, doc_id: &str) {
+    let Some((engine, sed_id)) = HANDLES.with(|handles| {
+        let handles = handles.borrow();
+        let doc = handles.get(&handle_id)?.documents.get(doc_id)?;
+        Some((doc.subduction.clone(), doc.sed_id))
+    }) else {
+        return;
+    };
+    let statuses = engine
+        .peer_ids()
+        .await
+        .into_iter()
+        .map(|peer| (peer, engine.peer_sync_status(peer, sed_id)))
+        .collect::<Vec<_>>();
+
+    let now = js_sys::Date::now();
+    HANDLES.with(|handles| {
+        let mut handles = handles.borrow_mut();
+        let Some(doc) = handles
+            .get_mut(&handle_id)
+            .and_then(|ctx| ctx.documents.get_mut(doc_id))
+        else {
+            return;
+        };
+        for (peer, status) in statuses {
+            let ack = doc.peers.entry(peer).or_default();
+            if status.syncs > ack.syncs {
+                ack.syncs = status.syncs;
+                ack.synced_at = Some(now);
+            }
+            ack.heads = status.heads.map(|heads| {
+                heads
+                    .iter()
+                    .map(|head| hex::encode(head.as_bytes()))
+                    .collect()
+            });
+        }
+    });
+}
+
+/// How many of the document's commits the peer that acknowledged `heads` lacks, and
+/// how many of those heads the document lacks.
+fn pending(doc: &DocumentCtx, heads: &[String]) -> (usize, usize) {
+    let parents = doc
+        .commits
+        .iter()
+        .map(|record| (record.hash.as_str(), record.parents.as_slice()))
+        .collect::<HashMap<_, _>>();
+    let mut acknowledged = HashSet::new();
+    let mut stack = heads.iter().map(String::as_str).collect::<Vec<_>>();
+    while let Some(hash) = stack.pop() {
+        let Some(&hash_parents) = parents.get(hash) else {
+            continue;
+        };
+        if acknowledged.insert(hash) {
+            stack.extend(hash_parents.iter().map(String::as_str));
+        }
+    }
+    let download = heads
+        .iter()
+        .filter(|head| !doc.seen.contains(*head))
+        .count();
+    (doc.commits.len() - acknowledged.len(), download)
+}
+
+#[wasm_bindgen]
+impl Beelay {
+    /// Where a document stands with each peer it's connected to: an array of
+    /// `{ peerId, upToDate, pendingUpload, pendingDownload, lastSyncedAt, lastRound }`.
+    ///
+    /// `peerId` is `null` for the sync server. `pendingUpload` counts the document's
+    /// commits the peer isn't known to have, and `pendingDownload` the heads it has
+    /// acknowledged that haven't reached the document yet; both are `null` until a
+    /// batch sync with the peer has finished. Commits pushed to a peer only count as
+    /// acknowledged once a batch sync confirms them. `lastSyncedAt` is when the last
+    /// one finished, in milliseconds since the epoch, and `lastRound` is the report
+    /// `watchSyncReports` was last given for the peer, or `null`.
+    #[wasm_bindgen(js_name = syncStatus, unchecked_return_type = "SyncStatus[]")]
+    pub async fn sync_status(&self, doc_id: String) -> Result<JsValue, JsValue> {
+        note_peer_syncs(self.id, &doc_id).await;
+        let (engine, sed_id) = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            Ok::<_, JsValue>((doc.subduction.clone(), doc.sed_id))
+        })?;
+
+        let mut peers = engine.peer_ids().await.into_iter().collect::<Vec<_>>();
+        peers.sort();
+        let mut up_to_date = Vec::with_capacity(peers.len());
+        for peer in &peers {
+            up_to_date.push(engine.is_synced_with(*peer, sed_id).await);
+        }
+
+        let statuses = HANDLES.with(|handles| {
+            let handles = handles.borrow();
+            let ctx = handles.get(&self.id).ok_or(BeelayError::InvalidHandle)?;
+            let doc = ctx
+                .documents
+                .get(&doc_id)
+                .ok_or(BeelayError::UnknownDocument)?;
+            let server = ctx.sync.as_ref().map(|sync| sync.server());
+            let statuses = peers
+                .into_iter()
+                .zip(up_to_date)
+                .map(|(peer, up_to_date)| {
+                    let ack = doc.peers.get(&peer).cloned().unwrap_or_default();
+                    let pending = ack.heads.as_deref().map(|heads| pending(doc, heads));
+                    PeerStatus {
+                        peer_id: (server != Some(peer)).then(|| hex::encode(peer.as_bytes())),
+                        up_to_date,
+                        pending_upload: pending.map(|(upload, _)| upload),
+                        pending_download: pending.map(|(_, download)| download),
+                        last_synced_at: ack.synced_at,
+                        last_round: ack.last_round,
+                    }
+                })
+                .collect::<Vec<_>>();
+            Ok::<_, JsValue>(statuses)
+        })?;
+        serde_wasm_bindgen::to_value(&statuses).map_err(JsValue::from)
+    }
+
+    /// Call `callback` with `(docId, peerId, report)` each time a batch sync round
+    /// between any document and a peer finishes, on either side.
+    ///
+    /// `peerId` is `null` for the sync server. `report` is `{ role, strategy,
+    /// commitsSent, chunksSent, commitsReceived, chunksReceived, bytesSent,
+    /// bytesReceived, durationMs, finishedAt }`: `role` is `"requester"` if this handle
+    /// asked for the round and `"responder"` if the peer did, and `strategy` is
+    /// `"inSync"` if the two already matched, `"notModified"` if the responder hadn't
+    /// changed since the last round, `"filter"` if each sent what the other's Bloom
+    /// filter of loose commits lacked, or `"summaryDiff"` if what one lacked was worked
+    /// out and sent. Bytes count commit and chunk contents. Returns a
+    /// subscription ID that can be passed to `unsubscribe`.
+    #[wasm_bindgen(js_name = watchSyncReports)]
+    pub fn watch_sync_reports(&self, callback: js_sys::Function) -> Result<u32, JsValue> {
+        HANDLES.with(|handles| {
+            let mut handles = handles.borrow_mut();
+            let ctx = handles
+                .get_mut(&self.id)
+                .ok_or(BeelayError::InvalidHandle)?;
+
+            let id = ctx.new_subscription(self.facade);
+            ctx.report_watchers.insert(id, callback);
+            diagnostics::track_owned(ResourceKind::Listener, self.id, self.facade, id.to_string());
+            Ok(id)
+        })
+    }
+}
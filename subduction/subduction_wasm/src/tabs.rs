@@ -0,0 +1,81 @@
+//! Telling other tabs about local commits.
+//!
+//! Handles in different tabs that persist to the same IndexedDB database share
+//! their identity and documents, but each keeps its own copy of the documents'
+//! commits in memory. When one of them adds local commits, it posts them on a
+//! `BroadcastChannel` named after the database, and the others apply them as
+//! their own local work, which notifies their subscribers in turn.
+
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{prelude::*, JsCast};
+use web_sys::{BroadcastChannel, MessageEvent};
+
+use crate::{conflict::CommitOrigin, envelope, CommitInput};
+
+/// Commits added locally by a handle in another tab.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TabCommits {
+    doc_id: String,
+    commits: Vec<CommitInput>,
+}
+
+/// A handle's channel to the handles in other tabs sharing its database.
+pub(crate) struct TabChannel {
+    channel: BroadcastChannel,
+    _on_message: Closure<dyn FnMut(MessageEvent)>,
+}
+
+impl std::fmt::Debug for TabChannel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TabChannel")
+            .field("name", &self.channel.name())
+            .finish_non_exhaustive()
+    }
+}
+
+impl TabChannel {
+    /// Join the channel for the database called `database_name`, applying the commits
+    /// other tabs post there to the matching documents of handle `handle_id`.
+    pub(crate) fn open(database_name: &str, handle_id: u32) -> Result<Self, JsValue> {
+        let channel = BroadcastChannel::new(&format!("subduction:{database_name}"))?;
+        let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+            let Ok(posted) = serde_wasm_bindgen::from_value::<TabCommits>(event.data()) else {
+                return;
+            };
+            wasm_bindgen_futures::spawn_local(async move {
+                // Documents this tab hasn't opened are picked up from storage on the next load
+                let _ = crate::apply_commits(
+                    handle_id,
+                    &posted.doc_id,
+                    &posted.commits,
+                    CommitOrigin::Local,
+                    None,
+                )
+                .await;
+            });
+        });
+        channel.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+        Ok(Self {
+            channel,
+            _on_message: on_message,
+        })
+    }
+
+    /// Tell the other tabs about commits added locally to `doc_id`.
+    pub(crate) fn post(&self, doc_id: &str, commits: &[CommitInput]) -> Result<(), JsValue> {
+        let message = TabCommits {
+            doc_id: doc_id.to_string(),
+            commits: commits.to_vec(),
+        };
+        let message = envelope::to_js(&message).map_err(JsValue::from)?;
+        self.channel.post_message(&message)
+    }
+
+    /// Leave the channel.
+    pub(crate) fn close(&self) {
+        self.channel.set_onmessage(None);
+        self.channel.close();
+    }
+}
@@ -0,0 +1,211 @@
+//! Counts and latencies of the engine's internal tasks, for `Beelay.taskStats`.
+//!
+//! Storage operations, sync rounds, and calls into the app's hooks (subscriptions,
+//! watchers, conflict listeners, and merge callbacks) are each timed from start to
+//! finish, so that a stall can be put down to storage, the network, or the app's
+//! own code rather than guessed at. With `Beelay.traceTasks(true)`, every task is
+//! also recorded as a User Timing measure named `subduction:<kind>`, which shows
+//! up in the browser's performance tools.
+
+use std::{cell::RefCell, collections::HashMap};
+
+use serde::Serialize;
+use wasm_bindgen::{prelude::*, JsCast};
+
+thread_local! {
+    static TASKS: RefCell<Tasks> = RefCell::new(Tasks::default());
+}
+
+/// The kinds of task that are timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum TaskKind {
+    Storage,
+    Sync,
+    Hook,
+}
+
+impl TaskKind {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Storage => "subduction:storage",
+            Self::Sync => "subduction:sync",
+            Self::Hook => "subduction:hook",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tasks {
+    counters: HashMap<TaskKind, Counters>,
+    trace: bool,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Counters {
+    in_flight: usize,
+    completed: u64,
+    failed: u64,
+    total_ms: f64,
+    max_ms: f64,
+}
+
+/// The result of `Beelay.taskStats`.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct TaskStats {
+    storage: KindStats,
+    sync: KindStats,
+    hooks: KindStats,
+}
+
+/// Statistics for one kind of task.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct KindStats {
+    /// Started but not finished yet.
+    in_flight: usize,
+    /// Finished, including those that failed.
+    completed: u64,
+    failed: u64,
+    /// The mean time from start to finish, in milliseconds, or `null` before any finish.
+    mean_ms: Option<f64>,
+    /// The longest time from start to finish, in milliseconds.
+    max_ms: f64,
+}
+
+impl From<&Counters> for KindStats {
+    #[allow(clippy::cast_precision_loss)]
+    fn from(counters: &Counters) -> Self {
+        Self {
+            in_flight: counters.in_flight,
+            completed: counters.completed,
+            failed: counters.failed,
+            mean_ms: (counters.completed > 0)
+                .then(|| counters.total_ms / counters.completed as f64),
+            max_ms: counters.max_ms,
+        }
+    }
+}
+
+/// A task that has started, recorded as finished when dropped.
+pub(crate) struct Task {
+    kind: TaskKind,
+    started_ms: f64,
+    failed: bool,
+}
+
+impl Task {
+    pub(crate) fn start(kind: TaskKind) -> Self {
+        TASKS.with(|tasks| tasks.borrow_mut().counters.entry(kind).or_default().in_flight += 1);
+        Self {
+            kind,
+            started_ms: now(),
+            failed: false,
+        }
+    }
+
+    /// Finish the task, counting it as failed.
+    pub(crate) fn fail(mut self) {
+        self.failed = true;
+    }
+}
+
+impl Drop for Task {
+    fn drop(&mut self) {
+        let finished_ms = now();
+        let elapsed = finished_ms - self.started_ms;
+        let trace = TASKS.with(|tasks| {
+            let mut tasks = tasks.borrow_mut();
+            let counters = tasks.counters.entry(self.kind).or_default();
+            counters.in_flight = counters.in_flight.saturating_sub(1);
+            counters.completed += 1;
+            counters.failed += u64::from(self.failed);
+            counters.total_ms += elapsed;
+            counters.max_ms = counters.max_ms.max(elapsed);
+            tasks.trace
+        });
+        if trace {
+            measure(self.kind.name(), self.started_ms, finished_ms);
+        }
+    }
+}
+
+/// Run `future` as a task of kind `kind`, counting it as failed if it fails.
+pub(crate) async fn timed<T, E>(
+    kind: TaskKind,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let task = Task::start(kind);
+    let result = future.await;
+    if result.is_err() {
+        task.fail();
+    }
+    result
+}
+
+/// Call an app hook, timing it as a task.
+///
+/// Only the call itself is timed, so a hook that returns a promise is timed until it
+/// returns, not until the promise settles.
+pub(crate) fn hook<T>(call: impl FnOnce() -> Result<T, JsValue>) -> Result<T, JsValue> {
+    let task = Task::start(TaskKind::Hook);
+    let result = call();
+    if result.is_err() {
+        task.fail();
+    }
+    result
+}
+
+/// Statistics for every kind of task, across every handle.
+pub(crate) fn report() -> TaskStats {
+    TASKS.with(|tasks| {
+        let tasks = tasks.borrow();
+        let stats = |kind| KindStats::from(&tasks.counters.get(&kind).cloned().unwrap_or_default());
+        TaskStats {
+            storage: stats(TaskKind::Storage),
+            sync: stats(TaskKind::Sync),
+            hooks: stats(TaskKind::Hook),
+        }
+    })
+}
+
+/// Start or stop recording tasks as User Timing measures.
+pub(crate) fn set_trace(trace: bool) {
+    TASKS.with(|tasks| tasks.borrow_mut().trace = trace);
+}
+
+/// The global `performance` object, if there is one.
+fn performance() -> Option<JsValue> {
+    js_sys::Reflect::get(&js_sys::global(), &"performance".into())
+        .ok()
+        .filter(|performance| !performance.is_undefined())
+}
+
+/// The current time in milliseconds, on `performance.now()`'s clock if it's available.
+pub(crate) fn now() -> f64 {
+    performance()
+        .and_then(|performance| {
+            let now = js_sys::Reflect::get(&performance, &"now".into()).ok()?;
+            now.dyn_into::<js_sys::Function>()
+                .ok()?
+                .call0(&performance)
+                .ok()?
+                .as_f64()
+        })
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+/// Record a User Timing measure from `start_ms` to `end_ms`.
+fn measure(name: &str, start_ms: f64, end_ms: f64) {
+    let Some(performance) = performance() else {
+        return;
+    };
+    let options = js_sys::Object::new();
+    let _ = js_sys::Reflect::set(&options, &"start".into(), &start_ms.into());
+    let _ = js_sys::Reflect::set(&options, &"end".into(), &end_ms.into());
+    if let Ok(measure) = js_sys::Reflect::get(&performance, &"measure".into())
+        .and_then(JsCast::dyn_into::<js_sys::Function>)
+    {
+        let _ = measure.call2(&performance, &name.into(), &options);
+    }
+}
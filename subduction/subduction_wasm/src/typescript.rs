@@ -0,0 +1,229 @@
+//! TypeScript definitions for the values the JS API takes and returns.
+//!
+//! Options, commits, and results cross the boundary as plain objects through
+//! `serde_wasm_bindgen`, which wasm-bindgen only knows as `any`. The interfaces
+//! here are added to the generated `.d.ts`, and methods name them with
+//! `unchecked_param_type` and `unchecked_return_type`, so TypeScript callers see
+//! the shapes the Rust side reads and writes. Nothing checks them at runtime: an
+//! interface here has to change along with the struct it describes.
+
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen(typescript_custom_section)]
+const TYPES: &str = r#"
+/** 32 bytes, hex-encoded: a commit's hash, or one of a document's heads. */
+export type Hash = string;
+
+/** A handle's hex-encoded verifying key, as `peerId()` returns it. */
+export type PeerId = string;
+
+export type DocumentId = string;
+
+export type Access = "read" | "write" | "admin";
+
+/** How `loadDocument` and `loadDocumentPage` order commits. */
+export type Order = "causal" | "insertion";
+
+/** Who made a commit, when, and what the app tagged it with. None of it counts towards its hash. */
+export interface CommitMeta {
+  authorPeerId?: PeerId;
+  /** Milliseconds since the epoch. */
+  timestamp?: number;
+  tags?: unknown;
+  /** The author's signature, hex-encoded, on handles loaded with `signCommits`. */
+  signature?: string;
+}
+
+/** A commit as methods take it. Fields this release doesn't know are kept as extensions. */
+export interface CommitInput extends CommitMeta {
+  /** The version of the commit's shape; 1 if left out. */
+  v?: number;
+  parents: Hash[];
+  hash: Hash;
+  contents: Uint8Array | number[];
+  [extension: string]: unknown;
+}
+
+/** A commit as methods return it. */
+export interface Commit extends CommitMeta {
+  type: "commit";
+  v: number;
+  parents: Hash[];
+  hash: Hash;
+  contents: Uint8Array;
+  [extension: string]: unknown;
+}
+
+/** A run of commits made into a unit by `compact`. Decode it with `bundleCommits`. */
+export interface Bundle {
+  type: "bundle";
+  start: Hash;
+  end: Hash;
+  checkpoints: Hash[];
+  contents: Uint8Array;
+}
+
+/** What app-supplied storage has to implement. */
+export interface StorageAdapter {
+  load(key: string[]): Promise<Uint8Array | undefined>;
+  save(key: string[], data: Uint8Array): Promise<void>;
+  remove(key: string[]): Promise<void>;
+  listOneLevel(prefix: string[]): Promise<string[][]>;
+}
+
+export interface PrivacyOptions {
+  blindIds?: boolean;
+  salt?: string;
+  decoys?: number;
+  padTo?: number;
+  batchMs?: number;
+}
+
+export interface Limits {
+  maxCommitBytes?: number;
+  maxDocumentBytes?: number;
+  maxDocuments?: number;
+}
+
+export interface LoadConfig {
+  loadCacheSize?: number;
+  syncServerUrl?: string;
+  syncPrivacy?: PrivacyOptions;
+  storage?: "memory" | "indexeddb" | StorageAdapter;
+  databaseName?: string;
+  namespace?: string;
+  strict?: boolean;
+  strictHashes?: boolean;
+  signCommits?: boolean;
+  /** The handle's identity, in place of a key of its own. */
+  signer?: MemorySigner;
+  limits?: Limits;
+  /** What `save` returned. */
+  snapshot?: Uint8Array;
+  /** Fills the array it's given with random bytes. */
+  randomSource?: (buffer: Uint8Array) => void;
+}
+
+export interface CreateDocArgs {
+  initialCommit: CommitInput;
+  codec?: "none" | "deflate" | "zstd";
+  encrypted?: boolean;
+}
+
+export interface AddCommitsArgs {
+  docId: DocumentId;
+  commits: CommitInput[];
+  ifHeadsVersion?: number;
+  origin?: "local" | "sync";
+}
+
+export interface AddCommitsResult {
+  headsVersion: number;
+}
+
+export interface LoadDocumentOptions {
+  order?: Order;
+}
+
+export interface LoadPageOptions {
+  order?: Order;
+  cursor?: string;
+  limit?: number;
+}
+
+export interface CommitPage {
+  commits: Commit[];
+  /** Left out after the last page. */
+  nextCursor?: string;
+}
+
+export interface GcOptions {
+  /** How many parents back from the heads to keep commits; 0 if left out. */
+  keepDepth?: number;
+}
+
+export interface DocStats {
+  commits: number;
+  contentBytes: number;
+  bundles: number;
+  heads: number;
+  firstActivity?: number;
+  lastActivity?: number;
+  authors: number;
+}
+
+export interface SubscribeOptions {
+  windowMs?: number;
+}
+
+export interface NotificationBatch {
+  docId: DocumentId;
+  commits: Commit[];
+  coalescedCount: number;
+}
+
+export interface DocumentFilter {
+  access?: Access;
+  sharedWith?: PeerId;
+  createdBy?: PeerId;
+}
+
+export interface PageOptions {
+  cursor?: string;
+  limit?: number;
+}
+
+export interface DocumentListing {
+  docId: DocumentId;
+  /** Left out if this handle has lost access to the document. */
+  access?: Access;
+  createdBy: PeerId;
+  commitCount: number;
+  heads: Hash[];
+}
+
+export interface DocumentsPage {
+  documents: DocumentListing[];
+  /** Left out after the last page. */
+  nextCursor?: string;
+}
+
+/** One batch sync round, as `watchSyncReports` callbacks get it. */
+export interface SyncRound {
+  role: "requester" | "responder";
+  strategy: "inSync" | "notModified" | "filter" | "summaryDiff";
+  commitsSent: number;
+  chunksSent: number;
+  commitsReceived: number;
+  chunksReceived: number;
+  bytesSent: number;
+  bytesReceived: number;
+  durationMs: number;
+  finishedAt: number;
+}
+
+/** Where a document stands with one peer. Fields that aren't known yet are left out. */
+export interface SyncStatus {
+  /** Left out for the sync server. */
+  peerId?: PeerId;
+  upToDate: boolean;
+  pendingUpload?: number;
+  pendingDownload?: number;
+  lastSyncedAt?: number;
+  lastRound?: SyncRound;
+}
+
+export interface WaitResult {
+  synced: boolean;
+}
+
+export interface Member {
+  peerId: PeerId;
+  access: Access;
+}
+
+export interface ContactCardInfo {
+  peerId: PeerId;
+  verifyingKey: Uint8Array;
+}
+"#;
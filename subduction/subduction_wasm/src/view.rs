@@ -0,0 +1,81 @@
+//! Zero-copy views of commit contents.
+
+use std::rc::Rc;
+
+use js_sys::Uint8Array;
+use wasm_bindgen::prelude::*;
+
+use crate::{
+    diagnostics::{self, ResourceKind},
+    error::BeelayError,
+};
+
+/// A commit's contents, readable from JS without copying them out of WASM memory.
+///
+/// The view keeps the contents alive until it is released (or garbage collected),
+/// even if the document is deleted in the meantime.
+///
+/// # Invalidation
+///
+/// The array returned by [`BlobView::bytes`] points straight into WASM memory. It
+/// is detached whenever that memory grows, which can happen on *any* call into
+/// this module, so use it immediately (e.g. `new Blob([view.bytes()])`) and call
+/// `bytes()` again rather than holding on to it. Use `.slice()` for a copy that
+/// stays valid.
+#[wasm_bindgen]
+#[derive(Debug)]
+pub struct BlobView {
+    handle: u32,
+    id: u32,
+    contents: Option<Rc<[u8]>>,
+}
+
+impl BlobView {
+    pub(crate) fn new(handle: u32, contents: Rc<[u8]>) -> Self {
+        let id = diagnostics::next_id();
+        diagnostics::track(ResourceKind::BlobView, handle, id.to_string());
+        Self {
+            handle,
+            id,
+            contents: Some(contents),
+        }
+    }
+
+    fn untrack(&self) {
+        diagnostics::untrack(ResourceKind::BlobView, self.handle, &self.id.to_string());
+    }
+}
+
+impl Drop for BlobView {
+    fn drop(&mut self) {
+        self.untrack();
+    }
+}
+
+#[wasm_bindgen]
+impl BlobView {
+    /// A `Uint8Array` over the contents, valid until WASM memory next grows.
+    pub fn bytes(&self) -> Result<Uint8Array, JsValue> {
+        let contents = self
+            .contents
+            .as_ref()
+            .ok_or(BeelayError::Released("blob view was released"))?;
+
+        // SAFETY: the contents are immutable and kept alive by `self`, and the
+        // invalidation rules above are documented for callers.
+        Ok(unsafe { Uint8Array::view(contents) })
+    }
+
+    /// The length of the contents in bytes, or 0 once released.
+    #[wasm_bindgen(getter)]
+    pub fn length(&self) -> usize {
+        self.contents.as_ref().map_or(0, |contents| contents.len())
+    }
+
+    /// Drop this view's hold on the contents. `bytes()` fails afterwards, and arrays
+    /// it returned earlier may then show unrelated memory, so must not be used.
+    pub fn release(&mut self) {
+        self.contents = None;
+        self.untrack();
+    }
+}
@@ -1,8 +1,48 @@
 //! Error types.
 
 use futures::channel::oneshot;
+use subduction_core::{connection::handshake::HandshakeError, peer::id::PeerId};
 use thiserror::Error;
 
+/// Problem while opening a connection.
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    /// WebSocket error.
+    #[error("WebSocket error: {0}")]
+    WebSocket(#[from] tungstenite::Error),
+
+    /// Serialization error.
+    #[error("Bincode error: {0}")]
+    Serialization(#[from] bincode::error::EncodeError),
+
+    /// Deserialization error.
+    #[error("Bincode deserialize error: {0}")]
+    Deserialize(#[from] bincode::error::DecodeError),
+
+    /// The peer closed the connection, or sent something other than a handshake.
+    #[error("Connection closed during the handshake")]
+    Closed,
+
+    /// Timed out waiting for the peer's handshake.
+    #[error("Timed out waiting for the handshake")]
+    Timeout,
+
+    /// The peer speaks another version of the wire format, or didn't prove it holds
+    /// the key it claimed.
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+
+    /// A reconnection reached a different peer than the connection it replaces.
+    #[error("Reconnected to peer {found}, not {expected}")]
+    PeerChanged {
+        /// The peer the connection was with.
+        expected: PeerId,
+
+        /// The peer that answered instead.
+        found: PeerId,
+    },
+}
+
 /// Problem while attempting to send a message.
 #[derive(Debug, Error)]
 pub enum SendError {
@@ -13,6 +53,10 @@ pub enum SendError {
     /// Serialization error.
     #[error("Bincode error: {0}")]
     Serialization(#[from] bincode::error::EncodeError),
+
+    /// I/O error on a plain HTTP transport.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
 }
 
 /// Problem while attempting to make a roundtrip call.
@@ -33,6 +77,10 @@ pub enum CallError {
     /// Timed out waiting for response.
     #[error("Timed out waiting for response")]
     Timeout,
+
+    /// I/O error on a plain HTTP transport.
+    #[error("I/O error: {0}")]
+    Io(std::io::Error),
 }
 
 /// Problem while attempting to receive a message.
@@ -66,4 +114,16 @@ pub enum RunError {
     /// Deserialization error.
     #[error("Bincode deserialize error: {0}")]
     Deserialize(#[from] bincode::error::DecodeError),
+
+    /// I/O error on a plain HTTP transport.
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    /// Reconnecting failed.
+    #[error("Reconnect error: {0}")]
+    Reconnect(#[from] ConnectError),
+
+    /// A message was signed, but not by the peer.
+    #[error("message from peer {0} isn't signed by it")]
+    BadSignature(PeerId),
 }
@@ -0,0 +1,180 @@
+//! # Credit-based flow control
+//!
+//! A receiver that can't keep up tells its peer to slow down with
+//! [`Message::Credit`]: its first credit is the window of unacknowledged bytes it
+//! will hold, and it gives bytes back as it works through them. A sender that has
+//! been given a window waits for credit rather than sending past it, so an
+//! overloaded relay sees its clients back off instead of their calls timing out.
+//!
+//! Only one side of a connection should set a receive window. If both do, each
+//! may wait on the other for credit while handling a message that sends.
+//!
+//! [`Message::Credit`]: subduction_core::connection::message::Message::Credit
+
+use futures::channel::oneshot;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+/// How a connection's sends have been throttled by its peer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowStats {
+    /// The window the peer announced, or `None` if it isn't flow controlled.
+    pub window: Option<u64>,
+
+    /// Bytes sent that the peer hasn't given credit back for yet.
+    pub unacked: u64,
+
+    /// Sends waiting for credit right now.
+    pub waiting: usize,
+
+    /// Sends that have had to wait for credit.
+    pub throttled: u64,
+
+    /// The total time sends have spent waiting for credit.
+    pub throttled_for: Duration,
+}
+
+/// The sending half: bytes the peer has been sent but not yet given credit for.
+#[derive(Debug, Default)]
+pub(crate) struct SendWindow {
+    state: Mutex<SendState>,
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    window: Option<u64>,
+    unacked: u64,
+    waiting: Vec<oneshot::Sender<()>>,
+    throttled: u64,
+    throttled_for: Duration,
+}
+
+impl SendWindow {
+    /// Wait until `bytes` more may be sent, then count them as unacknowledged.
+    ///
+    /// A message larger than the whole window is sent once nothing else is
+    /// outstanding, so that it can't wait forever.
+    pub(crate) async fn reserve(&self, bytes: u64) {
+        let mut throttled_since: Option<Instant> = None;
+        loop {
+            let credit = {
+                let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+                let fits = state
+                    .window
+                    .is_none_or(|window| state.unacked == 0 || state.unacked + bytes <= window);
+                if fits {
+                    state.unacked += bytes;
+                    if let Some(since) = throttled_since {
+                        state.throttled_for += since.elapsed();
+                    }
+                    return;
+                }
+                if throttled_since.is_none() {
+                    state.throttled += 1;
+                    throttled_since = Some(Instant::now());
+                    tracing::info!(
+                        "throttled: {} bytes unacknowledged, waiting for credit",
+                        state.unacked
+                    );
+                }
+                let (tx, rx) = oneshot::channel();
+                state.waiting.push(tx);
+                rx
+            };
+            // Woken by credit, or by the window being lifted
+            let _ = credit.await;
+        }
+    }
+
+    /// Take `bytes` of credit from the peer, waking any sends waiting for it.
+    ///
+    /// The first credit sets the window. Credit the peer gives back for bytes sent
+    /// before it set one is ignored.
+    pub(crate) fn credit(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if state.window.is_some() {
+            state.unacked = state.unacked.saturating_sub(bytes);
+        } else {
+            tracing::info!("peer set a window of {bytes} bytes");
+            state.window = Some(bytes);
+            state.unacked = 0;
+        }
+        for waiting in state.waiting.drain(..) {
+            let _ = waiting.send(());
+        }
+    }
+
+    /// Stop limiting sends, waking any that are waiting, for example because the
+    /// connection has closed and no more credit will come.
+    pub(crate) fn lift(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.window = None;
+        state.unacked = 0;
+        for waiting in state.waiting.drain(..) {
+            let _ = waiting.send(());
+        }
+    }
+
+    pub(crate) fn stats(&self) -> FlowStats {
+        let state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        FlowStats {
+            window: state.window,
+            unacked: state.unacked,
+            waiting: state.waiting.len(),
+            throttled: state.throttled,
+            throttled_for: state.throttled_for,
+        }
+    }
+}
+
+/// The receiving half: bytes finished with but not yet given back to the peer.
+#[derive(Debug, Default)]
+pub(crate) struct ReceiveWindow {
+    window: Option<u64>,
+    announced: AtomicBool,
+    consumed: AtomicU64,
+}
+
+impl ReceiveWindow {
+    pub(crate) const fn new(window: u64) -> Self {
+        Self {
+            window: Some(window),
+            announced: AtomicBool::new(false),
+            consumed: AtomicU64::new(0),
+        }
+    }
+
+    /// The window, if the peer is flow controlled.
+    pub(crate) const fn window(&self) -> Option<u64> {
+        self.window
+    }
+
+    /// The window to announce, the first time this is called.
+    pub(crate) fn announce(&self) -> Option<u64> {
+        self.window
+            .filter(|_| !self.announced.swap(true, Ordering::AcqRel))
+    }
+
+    /// Account for `bytes` finished with, returning the credit to give back once a
+    /// quarter of the window has built up.
+    pub(crate) fn consume(&self, bytes: u64) -> Option<u64> {
+        let window = self.window?;
+        let consumed = self.consumed.fetch_add(bytes, Ordering::AcqRel) + bytes;
+        if consumed >= window / 4 {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    /// The credit to give back for everything finished with so far, if any.
+    pub(crate) fn flush(&self) -> Option<u64> {
+        self.window?;
+        Some(self.consumed.swap(0, Ordering::AcqRel)).filter(|bytes| *bytes > 0)
+    }
+}
@@ -28,6 +28,7 @@
 #![allow(clippy::multiple_crate_versions)]
 
 pub mod error;
+pub mod flow;
 pub mod websocket;
 
 #[cfg(feature = "tokio")]
@@ -7,3 +7,6 @@ pub mod client;
 
 #[cfg(feature = "tokio_server")]
 pub mod server;
+
+#[cfg(feature = "tokio_server")]
+pub mod sse;
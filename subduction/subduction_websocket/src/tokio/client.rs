@@ -1,9 +1,10 @@
 //! # Subduction [`WebSocket`] client for Tokio
 
 use crate::{
-    error::{CallError, DisconnectionError, RecvError, RunError, SendError},
+    error::{CallError, ConnectError, DisconnectionError, RecvError, RunError, SendError},
+    flow::FlowStats,
     tokio::start::Unstarted,
-    websocket::WebSocket,
+    websocket::{handshake, WebSocket},
 };
 use async_tungstenite::tokio::{connect_async, ConnectStream};
 use futures::{future::BoxFuture, FutureExt};
@@ -11,6 +12,7 @@ use sedimentree_core::future::Sendable;
 use std::time::Duration;
 use subduction_core::{
     connection::{
+        handshake::SigningKey,
         message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
         Connection, Reconnect,
     },
@@ -25,25 +27,33 @@ use super::start::Start;
 #[derive(Debug, Clone)]
 pub struct TokioWebSocketClient {
     address: Uri,
+    /// The key this side proves it holds in the handshake.
+    key: SigningKey,
     socket: WebSocket<ConnectStream>,
 }
 
 impl TokioWebSocketClient {
-    /// Create a new [`WebSocketClient`] connection.
+    /// Create a new [`WebSocketClient`] connection, proving this side holds `key`.
+    ///
+    /// The connection's peer ID is the key the server proved it holds.
     ///
     /// # Errors
     ///
-    /// Returns an error if the connection could not be established.
+    /// Returns an error if the connection could not be established, or if the
+    /// server speaks another version of the wire format or doesn't prove it holds
+    /// its key.
     pub async fn new(
         address: Uri,
         timeout: Duration,
-        peer_id: PeerId,
-    ) -> Result<Unstarted<Self>, tungstenite::Error> {
+        key: &SigningKey,
+    ) -> Result<Unstarted<Self>, ConnectError> {
         tracing::info!("Connecting to WebSocket server at {address}");
-        let (ws_stream, _resp) = connect_async(address.clone()).await?;
+        let (mut ws_stream, _resp) = connect_async(address.clone()).await?;
+        let peer_id = handshake(&mut ws_stream, timeout, key).await?;
         Ok(Unstarted(TokioWebSocketClient {
             address,
-            socket: WebSocket::<_>::new(ws_stream, timeout, peer_id),
+            key: key.clone(),
+            socket: WebSocket::<_>::new(ws_stream, timeout, peer_id).with_signing_key(key),
         }))
     }
 
@@ -58,6 +68,12 @@ impl TokioWebSocketClient {
     pub async fn listen(&self) -> Result<(), RunError> {
         self.socket.listen().await
     }
+
+    /// How this connection's sends have been throttled by the server.
+    #[must_use]
+    pub fn flow_stats(&self) -> FlowStats {
+        self.socket.flow_stats()
+    }
 }
 
 impl Start for TokioWebSocketClient {
@@ -112,21 +128,29 @@ impl Connection<Sendable> for TokioWebSocketClient {
         }
         .boxed()
     }
+
+    fn sender_signature(&self, message: &Message) -> Option<Vec<u8>> {
+        Connection::<Sendable>::sender_signature(&self.socket, message)
+    }
 }
 
 impl Reconnect<Sendable> for TokioWebSocketClient {
-    type ConnectError = tungstenite::Error;
+    type ConnectError = ConnectError;
     type RunError = RunError;
 
     fn reconnect(&mut self) -> BoxFuture<'_, Result<(), Self::ConnectError>> {
         async move {
-            *self = TokioWebSocketClient::new(
-                self.address.clone(),
-                self.socket.timeout,
-                self.socket.peer_id,
-            )
-            .await?
-            .start();
+            let Unstarted(next) =
+                TokioWebSocketClient::new(self.address.clone(), self.socket.timeout, &self.key)
+                    .await?;
+            // Whoever answers at the address now isn't the peer the engine knows
+            if next.socket.peer_id != self.socket.peer_id {
+                return Err(ConnectError::PeerChanged {
+                    expected: self.socket.peer_id,
+                    found: next.socket.peer_id,
+                });
+            }
+            *self = Unstarted(next).start();
 
             Ok(())
         }
@@ -1,8 +1,9 @@
 //! # Subduction WebSocket server for Tokio
 
 use crate::{
-    error::{CallError, DisconnectionError, RecvError, RunError, SendError},
-    websocket::WebSocket,
+    error::{CallError, ConnectError, DisconnectionError, RecvError, RunError, SendError},
+    flow::FlowStats,
+    websocket::{handshake, WebSocket},
 };
 use async_tungstenite::{
     tokio::{accept_async, TokioAdapter},
@@ -14,6 +15,7 @@ use sedimentree_core::future::Sendable;
 use std::time::Duration;
 use subduction_core::{
     connection::{
+        handshake::SigningKey,
         message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
         Connection, Reconnect,
     },
@@ -30,20 +32,35 @@ use super::start::{Start, Unstarted};
 #[derive(Debug, Clone)]
 pub struct TokioWebSocketServer {
     address: SocketAddr,
+    /// The key this side proves it holds in the handshake.
+    key: SigningKey,
     socket: WebSocket<TokioAdapter<TcpStream>>,
 }
 
 impl TokioWebSocketServer {
-    /// Create a new [`WebSocketServer`] connection from an accepted TCP stream.
-    pub fn new(
+    /// Create a new [`WebSocketServer`] connection from an accepted TCP stream,
+    /// once the client has completed the handshake, proving this side holds `key`.
+    ///
+    /// The connection's peer ID is the key the client proved it holds.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the client doesn't complete the handshake, speaks
+    /// another version of the wire format, or doesn't prove it holds its key.
+    pub async fn new(
         address: SocketAddr,
         timeout: Duration,
-        peer_id: PeerId,
-        ws_stream: WebSocketStream<TokioAdapter<TcpStream>>,
-    ) -> Unstarted<Self> {
-        let socket = WebSocket::<_>::new(ws_stream, timeout, peer_id);
-        tracing::info!("Accepting WebSocket connections at {address}");
-        Unstarted(TokioWebSocketServer { address, socket })
+        key: &SigningKey,
+        mut ws_stream: WebSocketStream<TokioAdapter<TcpStream>>,
+    ) -> Result<Unstarted<Self>, ConnectError> {
+        let peer_id = handshake(&mut ws_stream, timeout, key).await?;
+        let socket = WebSocket::<_>::new(ws_stream, timeout, peer_id).with_signing_key(key);
+        tracing::info!("Accepted peer {peer_id} at {address}");
+        Ok(Unstarted(TokioWebSocketServer {
+            address,
+            key: key.clone(),
+            socket,
+        }))
     }
 
     /// Create a new [`WebSocketServer`] connection.
@@ -55,13 +72,15 @@ impl TokioWebSocketServer {
     pub async fn setup(
         address: SocketAddr,
         timeout: Duration,
-        peer_id: PeerId,
-    ) -> Result<Unstarted<Self>, tungstenite::Error> {
+        key: &SigningKey,
+    ) -> Result<Unstarted<Self>, ConnectError> {
         tracing::info!("Starting WebSocket server on {address}");
-        let listener = TcpListener::bind(address).await?;
-        let (tcp, _peer) = listener.accept().await?;
+        let listener = TcpListener::bind(address)
+            .await
+            .map_err(tungstenite::Error::Io)?;
+        let (tcp, _peer) = listener.accept().await.map_err(tungstenite::Error::Io)?;
         let ws_stream = accept_async(tcp).await?;
-        Ok(Self::new(address, timeout, peer_id, ws_stream))
+        Self::new(address, timeout, key, ws_stream).await
     }
 
     /// Start listening for incoming messages.
@@ -75,6 +94,25 @@ impl TokioWebSocketServer {
     pub async fn listen(&self) -> Result<(), RunError> {
         self.socket.listen().await
     }
+
+    /// How this connection's sends have been throttled by the client.
+    #[must_use]
+    pub fn flow_stats(&self) -> FlowStats {
+        self.socket.flow_stats()
+    }
+}
+
+impl Unstarted<TokioWebSocketServer> {
+    /// Flow control the client, holding at most `bytes` of its messages that haven't
+    /// been received yet. See [`WebSocket::with_receive_window`].
+    #[must_use]
+    pub fn with_receive_window(self, bytes: u64) -> Self {
+        let Unstarted(server) = self;
+        Unstarted(TokioWebSocketServer {
+            socket: server.socket.with_receive_window(bytes),
+            ..server
+        })
+    }
 }
 
 impl Start for TokioWebSocketServer {
@@ -129,18 +167,31 @@ impl Connection<Sendable> for TokioWebSocketServer {
         }
         .boxed()
     }
+
+    fn sender_signature(&self, message: &Message) -> Option<Vec<u8>> {
+        Connection::<Sendable>::sender_signature(&self.socket, message)
+    }
 }
 
 impl Reconnect<Sendable> for TokioWebSocketServer {
-    type ConnectError = tungstenite::Error;
+    type ConnectError = ConnectError;
     type RunError = RunError;
 
     fn reconnect(&mut self) -> BoxFuture<'_, Result<(), Self::ConnectError>> {
         async {
-            *self =
-                TokioWebSocketServer::setup(self.address, self.socket.timeout, self.socket.peer_id)
-                    .await?
-                    .start();
+            let mut next =
+                TokioWebSocketServer::setup(self.address, self.socket.timeout, &self.key).await?;
+            let Unstarted(accepted) = &next;
+            if accepted.socket.peer_id != self.socket.peer_id {
+                return Err(ConnectError::PeerChanged {
+                    expected: self.socket.peer_id,
+                    found: accepted.socket.peer_id,
+                });
+            }
+            if let Some(bytes) = self.socket.receive_window.window() {
+                next = next.with_receive_window(bytes);
+            }
+            *self = next.start();
 
             Ok(())
         }
@@ -0,0 +1,574 @@
+//! # Subduction server-sent events server for Tokio
+//!
+//! A minimal transport for clients that can't open a WebSocket, but for
+//! which long-polling would be too chatty.
+//!
+//! The client subscribes to `GET /events` (e.g. with an `EventSource`), and
+//! the server pushes each [`Message`] to it as an SSE `message` event whose
+//! data is the hex-encoded bincode message. The client uploads its own
+//! messages, including responses to the server's requests, as bincode request
+//! bodies to `POST /messages`.
+//!
+//! Before either, the client runs the [handshake] over two requests. It sends its
+//! [`Hello`] as the body of `POST /hello`, and gets back the server's [`Hello`]
+//! and [`Proof`], or `400 Bad Request` if the two speak different versions of the
+//! wire format. It then sends its own [`Proof`] to `POST /proof?session=<nonce>`,
+//! where `<nonce>` is the hex-encoded nonce of the server's [`Hello`], which
+//! names the session from then on. The server answers `204 No Content` once the
+//! proof checks out, and `403 Forbidden` if it doesn't. Every later request
+//! carries `?session=<nonce>`, and gets `401 Unauthorized` without an
+//! authenticated session.
+//!
+//! The server syncs with one client, the first to authenticate; a session
+//! proving another key gets `409 Conflict`. It holds one event stream, keyed by
+//! the session it was opened under. When the client reconnects under the same
+//! session (as an `EventSource` does automatically), the new stream replaces the
+//! old one, but one opened under another session gets `409 Conflict` until the
+//! first is closed, so a second tab can't take over the first's stream.
+//!
+//! [handshake]: subduction_core::connection::handshake
+
+use crate::{
+    error::{CallError, DisconnectionError, RecvError, RunError, SendError},
+    websocket::read_hello,
+};
+use core::net::SocketAddr;
+use futures::{
+    channel::{mpsc, oneshot},
+    future::BoxFuture,
+    lock::Mutex,
+    FutureExt, SinkExt, StreamExt,
+};
+use sedimentree_core::future::Sendable;
+use std::{
+    collections::HashMap,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use subduction_core::{
+    connection::{
+        handshake::{Handshake, Hello, Proof, SigningKey},
+        message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
+        Connection,
+    },
+    peer::id::PeerId,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::watch,
+    task::JoinHandle,
+};
+
+use super::start::{Start, Unstarted};
+
+/// The largest `POST /hello` or `POST /messages` body that will be accepted.
+const MAX_BODY_BYTES: usize = 16 * 1024 * 1024;
+
+/// A client's progress through the handshake, by the nonce of the server's [`Hello`].
+#[derive(Debug)]
+enum Session {
+    /// Waiting for the client's [`Proof`].
+    Pending {
+        ours: Box<Handshake>,
+        theirs: Hello,
+        started: Instant,
+    },
+
+    /// The client proved it holds the key the server syncs with.
+    Authenticated,
+}
+
+/// The client's event stream.
+#[derive(Debug)]
+struct EventStream {
+    /// The session it was opened under.
+    session: [u8; 32],
+    writer: OwnedWriteHalf,
+    /// Waits for the client to close the stream, and then forgets it.
+    watcher: JoinHandle<()>,
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        self.watcher.abort();
+    }
+}
+
+/// A Tokio-flavoured server-sent events server implementation.
+#[derive(Debug, Clone)]
+pub struct TokioSseServer {
+    address: SocketAddr,
+    /// The key this side proves it holds in the handshake.
+    key: SigningKey,
+    /// The client the server syncs with, once one has authenticated.
+    peer: Arc<watch::Sender<Option<PeerId>>>,
+    timeout: Duration,
+    listener: Arc<TcpListener>,
+    sessions: Arc<Mutex<HashMap<[u8; 32], Session>>>,
+
+    req_id_counter: Arc<Mutex<u128>>,
+    events: Arc<Mutex<Option<EventStream>>>,
+    pending: Arc<Mutex<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>>,
+
+    inbound_writer: mpsc::UnboundedSender<Message>,
+    inbound_reader: Arc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+}
+
+impl TokioSseServer {
+    /// Create a new [`TokioSseServer`] that serves HTTP on an already bound listener.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the listener's local address can't be read.
+    pub fn new(
+        listener: TcpListener,
+        timeout: Duration,
+        key: &SigningKey,
+    ) -> Result<Unstarted<Self>, io::Error> {
+        let address = listener.local_addr()?;
+        let (inbound_writer, inbound_reader) = mpsc::unbounded();
+        tracing::info!("Accepting SSE connections at {address}");
+        Ok(Unstarted(TokioSseServer {
+            address,
+            key: key.clone(),
+            peer: Arc::new(watch::Sender::new(None)),
+            timeout,
+            listener: Arc::new(listener),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            req_id_counter: Arc::new(Mutex::new(rand::random::<u128>())),
+            events: Arc::new(Mutex::new(None)),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            inbound_writer,
+            inbound_reader: Arc::new(Mutex::new(inbound_reader)),
+        }))
+    }
+
+    /// Create a new [`TokioSseServer`] listening on `address`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket could not be bound.
+    pub async fn setup(
+        address: SocketAddr,
+        timeout: Duration,
+        key: &SigningKey,
+    ) -> Result<Unstarted<Self>, io::Error> {
+        tracing::info!("Starting SSE server on {address}");
+        let listener = TcpListener::bind(address).await?;
+        Self::new(listener, timeout, key)
+    }
+
+    /// The address the server is listening on.
+    #[must_use]
+    pub const fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Wait for a client to authenticate, returning the key it proved it holds.
+    ///
+    /// The server must be started, and is the client's [`Connection`] from then on.
+    pub async fn authenticated(&self) -> PeerId {
+        let mut peer = self.peer.subscribe();
+        loop {
+            if let Some(peer) = *peer.borrow_and_update() {
+                return peer;
+            }
+            // The sender lives as long as `self`, so this only fails once it's gone
+            if peer.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Accept HTTP requests until the listener fails.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if accepting a TCP connection fails.
+    pub async fn listen(&self) -> Result<(), RunError> {
+        loop {
+            let (tcp, peer) = self.listener.accept().await?;
+            let inner = self.clone();
+            tokio::spawn(async move {
+                if let Err(e) = inner.handle(tcp).await {
+                    tracing::warn!("SSE request from {peer} failed: {e}");
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, tcp: TcpStream) -> Result<(), RunError> {
+        let (reader, mut writer) = tcp.into_split();
+        let mut reader = BufReader::new(reader);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if reader.read_line(&mut header).await? == 0 || header.trim().is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+
+        let (route, query) = path.split_once('?').unwrap_or((path.as_str(), ""));
+        let session = query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("session="))
+            .and_then(|nonce| hex::decode(nonce).ok())
+            .and_then(|nonce| <[u8; 32]>::try_from(nonce).ok());
+        let authenticated = match session {
+            Some(nonce) => matches!(
+                self.sessions.lock().await.get(&nonce),
+                Some(Session::Authenticated)
+            )
+            .then_some(nonce),
+            None => None,
+        };
+
+        match (method.as_str(), route, authenticated) {
+            ("GET", "/events", None) | ("POST", "/messages", None) => {
+                respond(&mut writer, "401 Unauthorized").await?;
+            }
+            ("GET", "/events", Some(session)) => self.subscribe(session, reader, writer).await?,
+            ("POST", "/hello" | "/proof" | "/messages", _) if content_length > MAX_BODY_BYTES => {
+                respond(&mut writer, "413 Payload Too Large").await?;
+            }
+            ("POST", "/hello", _) => {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await?;
+                match self.greet(&body).await {
+                    Ok(reply) => respond_with(&mut writer, "200 OK", &reply).await?,
+                    Err(e) => {
+                        tracing::warn!("refused SSE handshake: {e}");
+                        respond(&mut writer, "400 Bad Request").await?;
+                    }
+                }
+            }
+            ("POST", "/proof", _) => {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await?;
+                let status = match session {
+                    Some(nonce) => self.prove(nonce, &body).await,
+                    None => "400 Bad Request",
+                };
+                respond(&mut writer, status).await?;
+            }
+            ("POST", "/messages", _) => {
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).await?;
+                match bincode::serde::decode_from_slice(&body, bincode::config::standard()) {
+                    Ok((msg, _size)) => {
+                        respond(&mut writer, "204 No Content").await?;
+                        self.dispatch(msg).await?;
+                    }
+                    Err(e) => {
+                        tracing::warn!("invalid message body: {e}");
+                        respond(&mut writer, "400 Bad Request").await?;
+                    }
+                }
+            }
+            ("OPTIONS", _, _) => respond(&mut writer, "204 No Content").await?,
+            _ => respond(&mut writer, "404 Not Found").await?,
+        }
+
+        Ok(())
+    }
+
+    /// Hold `writer` as the event stream of `session`, unless another session's is open.
+    async fn subscribe(
+        &self,
+        session: [u8; 32],
+        reader: BufReader<OwnedReadHalf>,
+        mut writer: OwnedWriteHalf,
+    ) -> Result<(), io::Error> {
+        // Hold the lock until the headers are out, so the stream is registered by the
+        // time the client sees them
+        let mut events = self.events.lock().await;
+        if events
+            .as_ref()
+            .is_some_and(|stream| stream.session != session)
+        {
+            drop(events);
+            return respond(&mut writer, "409 Conflict").await;
+        }
+        writer
+            .write_all(
+                b"HTTP/1.1 200 OK\r\n\
+                  Content-Type: text/event-stream\r\n\
+                  Cache-Control: no-cache\r\n\
+                  Connection: keep-alive\r\n\
+                  Access-Control-Allow-Origin: *\r\n\r\n",
+            )
+            .await?;
+        writer.flush().await?;
+        tracing::info!("SSE client subscribed");
+        *events = Some(EventStream {
+            session,
+            writer,
+            watcher: tokio::spawn(self.clone().watch(reader)),
+        });
+        Ok(())
+    }
+
+    /// Answer a client's [`Hello`] with ours and our [`Proof`], and wait for theirs.
+    async fn greet(&self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let theirs = read_hello(body)
+            .map_err(|e| e.to_string())?
+            .ok_or("malformed hello")?;
+        let ours = Handshake::new(&self.key, rand::random());
+        let proof = ours.answer(&theirs).map_err(|e| e.to_string())?;
+        let reply =
+            bincode::serde::encode_to_vec((ours.hello(), &proof), bincode::config::standard())
+                .map_err(|e| e.to_string())?;
+
+        let mut sessions = self.sessions.lock().await;
+        // Forget the handshakes that were never finished
+        sessions.retain(|_, session| match session {
+            Session::Pending { started, .. } => started.elapsed() < self.timeout,
+            Session::Authenticated => true,
+        });
+        sessions.insert(
+            ours.hello().nonce,
+            Session::Pending {
+                ours: Box::new(ours),
+                theirs,
+                started: Instant::now(),
+            },
+        );
+        Ok(reply)
+    }
+
+    /// Check the client's [`Proof`] for the session `nonce`, returning the status to
+    /// answer with.
+    async fn prove(&self, nonce: [u8; 32], body: &[u8]) -> &'static str {
+        let mut sessions = self.sessions.lock().await;
+        let Some(Session::Pending { ours, theirs, .. }) = sessions.get(&nonce) else {
+            return "401 Unauthorized";
+        };
+        let verified =
+            bincode::serde::decode_from_slice::<Proof, _>(body, bincode::config::standard())
+                .ok()
+                .and_then(|(proof, _size)| ours.verify(theirs, &proof).ok());
+        let Some(peer_id) = verified else {
+            sessions.remove(&nonce);
+            return "403 Forbidden";
+        };
+
+        let mut owner = peer_id;
+        self.peer.send_if_modified(|peer| {
+            if let Some(existing) = peer {
+                owner = *existing;
+                false
+            } else {
+                *peer = Some(peer_id);
+                true
+            }
+        });
+        if owner != peer_id {
+            sessions.remove(&nonce);
+            return "409 Conflict";
+        }
+        tracing::info!("SSE client {peer_id} authenticated");
+        sessions.insert(nonce, Session::Authenticated);
+        "204 No Content"
+    }
+
+    async fn dispatch(&self, msg: Message) -> Result<(), RunError> {
+        match msg {
+            Message::BatchSyncResponse(resp) => {
+                let req_id = resp.req_id;
+                if let Some(waiting) = self.pending.lock().await.remove(&req_id) {
+                    tracing::info!("dispatching to waiter {:?}", req_id);
+                    if waiting.send(resp).is_err() {
+                        tracing::error!(
+                            "oneshot channel closed before sending response for req_id {:?}",
+                            req_id
+                        );
+                    }
+                } else {
+                    self.inbound_writer
+                        .clone()
+                        .send(Message::BatchSyncResponse(resp))
+                        .await?;
+                }
+            }
+            other => self.inbound_writer.clone().send(other).await?,
+        }
+        Ok(())
+    }
+
+    /// Forget the event stream once the client closes it, which it reads from
+    /// `reader`, so that another session can open one.
+    async fn watch(self, mut reader: BufReader<OwnedReadHalf>) {
+        let mut buf = [0; 256];
+        while matches!(reader.read(&mut buf).await, Ok(read) if read > 0) {}
+        tracing::info!("SSE client closed its event stream");
+        // Dropping the stream aborts this task, so there's nothing left to do after
+        self.events.lock().await.take();
+    }
+
+    /// Push a message to the subscribed client as an SSE event.
+    async fn push(&self, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut events = self.events.lock().await;
+        let stream = events.as_mut().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotConnected, "no SSE client subscribed")
+        })?;
+
+        let frame = format!("event: message\ndata: {}\n\n", hex::encode(bytes));
+        let written = async {
+            stream.writer.write_all(frame.as_bytes()).await?;
+            stream.writer.flush().await
+        }
+        .await;
+
+        if written.is_err() {
+            // The client went away; wait for it to subscribe again
+            *events = None;
+        }
+        written
+    }
+}
+
+async fn respond(writer: &mut OwnedWriteHalf, status: &str) -> Result<(), io::Error> {
+    respond_with(writer, status, &[]).await
+}
+
+async fn respond_with(
+    writer: &mut OwnedWriteHalf,
+    status: &str,
+    body: &[u8],
+) -> Result<(), io::Error> {
+    writer
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\n\
+                 Access-Control-Allow-Origin: *\r\n\
+                 Access-Control-Allow-Methods: GET, POST, OPTIONS\r\n\
+                 Access-Control-Allow-Headers: Content-Type\r\n\
+                 Content-Length: {}\r\n\
+                 Connection: close\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    writer.write_all(body).await?;
+    writer.flush().await
+}
+
+impl Start for TokioSseServer {
+    fn start(&self) -> JoinHandle<Result<(), RunError>> {
+        let inner = self.clone();
+        tokio::spawn(async move { inner.listen().await })
+    }
+}
+
+impl Connection<Sendable> for TokioSseServer {
+    type SendError = SendError;
+    type RecvError = RecvError;
+    type CallError = CallError;
+    type DisconnectionError = DisconnectionError;
+
+    /// The client's key, once one has [authenticated](Self::authenticated), and all
+    /// zeroes until then.
+    fn peer_id(&self) -> PeerId {
+        (*self.peer.borrow()).unwrap_or(PeerId::new([0; 32]))
+    }
+
+    fn next_request_id(&self) -> BoxFuture<'_, RequestId> {
+        async {
+            let mut counter = self.req_id_counter.lock().await;
+            *counter = counter.wrapping_add(1);
+            RequestId {
+                requestor: Connection::<Sendable>::peer_id(self),
+                nonce: *counter,
+            }
+        }
+        .boxed()
+    }
+
+    fn disconnect(&mut self) -> BoxFuture<'_, Result<(), Self::DisconnectionError>> {
+        async {
+            *self.events.lock().await = None;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn send(&self, message: Message) -> BoxFuture<'_, Result<(), Self::SendError>> {
+        async move {
+            tracing::debug!("SSE server sending message id {:?}", message.request_id());
+            let bytes = bincode::serde::encode_to_vec(&message, bincode::config::standard())?;
+            self.push(&bytes).await?;
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn recv(&self) -> BoxFuture<'_, Result<Message, Self::RecvError>> {
+        async {
+            let mut chan = self.inbound_reader.lock().await;
+            let msg = chan.next().await.ok_or(RecvError::ReadFromClosed)?;
+            tracing::info!("SSE server received message id {:?}", msg.request_id());
+            Ok(msg)
+        }
+        .boxed()
+    }
+
+    fn call(
+        &self,
+        req: BatchSyncRequest,
+        override_timeout: Option<Duration>,
+    ) -> BoxFuture<'_, Result<BatchSyncResponse, Self::CallError>> {
+        async move {
+            let req_id = req.req_id;
+            let bytes = bincode::serde::encode_to_vec(
+                Message::BatchSyncRequest(req),
+                bincode::config::standard(),
+            )
+            .map_err(CallError::Serialization)?;
+
+            // Pre-register channel
+            let (tx, rx) = oneshot::channel();
+            self.pending.lock().await.insert(req_id, tx);
+
+            if let Err(e) = self.push(&bytes).await {
+                self.pending.lock().await.remove(&req_id);
+                return Err(CallError::Io(e));
+            }
+
+            let req_timeout = override_timeout.unwrap_or(self.timeout);
+            match tokio::time::timeout(req_timeout, rx).await {
+                Ok(Ok(resp)) => Ok(resp),
+                Ok(Err(e)) => Err(CallError::ChanCanceled(e)),
+                Err(_elapsed) => {
+                    self.pending.lock().await.remove(&req_id);
+                    tracing::error!("request {:?} timed out", req_id);
+                    Err(CallError::Timeout)
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+impl PartialEq for TokioSseServer {
+    fn eq(&self, other: &Self) -> bool {
+        self.address == other.address && Arc::ptr_eq(&self.peer, &other.peer)
+    }
+}
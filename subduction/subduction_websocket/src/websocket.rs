@@ -1,6 +1,9 @@
 //! # Generic WebSocket connection for Subduction
 
-use crate::error::{CallError, DisconnectionError, RecvError, RunError, SendError};
+use crate::{
+    error::{CallError, ConnectError, DisconnectionError, RecvError, RunError, SendError},
+    flow::{FlowStats, ReceiveWindow, SendWindow},
+};
 use async_tungstenite::{WebSocketReceiver, WebSocketSender, WebSocketStream};
 use futures::{
     channel::{mpsc, oneshot},
@@ -10,21 +13,44 @@ use futures::{
 };
 use futures_timer::Delay;
 use futures_util::{AsyncRead, AsyncWrite, StreamExt};
-use sedimentree_core::future::{Local, Sendable};
+use sedimentree_core::{
+    future::{Local, Sendable},
+    Digest,
+};
 use std::{collections::HashMap, sync::Arc, time::Duration};
 use subduction_core::{
     connection::{
+        handshake::{
+            check_version, sign_message, verify_message, Handshake, HandshakeError, Hello, Proof,
+            SigningKey, VersionMismatch, PROTOCOL_VERSION,
+        },
         message::{BatchSyncRequest, BatchSyncResponse, Message, RequestId},
         Connection,
     },
     peer::id::PeerId,
 };
 
+/// A received message's digest and its sender's signature over it.
+type Signed = (Digest, Vec<u8>);
+
+/// A received message, with its size on the wire and signature.
+type Inbound = (Message, u64, Option<Signed>);
+
 /// A WebSocket implementation for [`Connection`].
+///
+/// A message frame may end with the sender's signature over the message (see
+/// [`sign_message`]), which is checked against the peer's key, and which
+/// [`Connection::sender_signature`] returns for the message once received.
 #[derive(Debug)]
 pub struct WebSocket<T: AsyncRead + AsyncWrite + Unpin> {
     pub(crate) peer_id: PeerId,
 
+    /// Sign every message sent with this key, the one the handshake proved.
+    pub(crate) key: Option<SigningKey>,
+
+    /// The signature of the message last received, if it was signed.
+    pub(crate) last_signature: Arc<std::sync::Mutex<Option<Signed>>>,
+
     pub(crate) req_id_counter: Arc<Mutex<u128>>,
     pub(crate) timeout: Duration,
 
@@ -33,8 +59,12 @@ pub struct WebSocket<T: AsyncRead + AsyncWrite + Unpin> {
 
     pub(crate) pending: Arc<Mutex<HashMap<RequestId, oneshot::Sender<BatchSyncResponse>>>>,
 
-    pub(crate) inbound_writer: mpsc::UnboundedSender<Message>,
-    pub(crate) inbound_reader: Arc<Mutex<mpsc::UnboundedReceiver<Message>>>,
+    /// Inbound messages, with their size on the wire and signature.
+    pub(crate) inbound_writer: mpsc::UnboundedSender<Inbound>,
+    pub(crate) inbound_reader: Arc<Mutex<mpsc::UnboundedReceiver<Inbound>>>,
+
+    pub(crate) send_window: Arc<SendWindow>,
+    pub(crate) receive_window: Arc<ReceiveWindow>,
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
@@ -50,6 +80,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
 
         Self {
             peer_id,
+            key: None,
+            last_signature: Arc::new(std::sync::Mutex::new(None)),
 
             req_id_counter: Arc::new(Mutex::new(starting_counter)),
             timeout,
@@ -59,27 +91,73 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
             pending,
             inbound_writer,
             inbound_reader: Arc::new(Mutex::new(inbound_rx)),
+            send_window: Arc::new(SendWindow::default()),
+            receive_window: Arc::new(ReceiveWindow::default()),
         }
     }
 
+    /// Flow control what the peer sends, holding at most `bytes` of its messages that
+    /// haven't been received with [`Connection::recv`] yet.
+    ///
+    /// The window is announced to the peer when the connection starts listening, and
+    /// bytes are given back as they are received, so a peer that respects it slows
+    /// down to the pace this side works at. See [`crate::flow`].
+    #[must_use]
+    pub fn with_receive_window(mut self, bytes: u64) -> Self {
+        self.receive_window = Arc::new(ReceiveWindow::new(bytes));
+        self
+    }
+
+    /// Sign every message sent with `key`, which should be the one the handshake
+    /// proved, so that the peer can check and keep the signatures.
+    #[must_use]
+    pub fn with_signing_key(mut self, key: &SigningKey) -> Self {
+        self.key = Some(key.clone());
+        self
+    }
+
+    /// How this connection's sends have been throttled by the peer.
+    #[must_use]
+    pub fn flow_stats(&self) -> FlowStats {
+        self.send_window.stats()
+    }
+
     /// Listen for incoming messages and dispatch them appropriately.
     ///
     /// # Errors
     ///
     /// If there is an error reading from the WebSocket or processing messages.
     pub async fn listen(&self) -> Result<(), RunError> {
+        self.grant(self.receive_window.announce()).await;
+        let result = self.dispatch_inbound().await;
+        // No more credit is coming, so nothing should wait for it
+        self.send_window.lift();
+        result
+    }
+
+    async fn dispatch_inbound(&self) -> Result<(), RunError> {
         while let Some(msg) = self.ws_reader.lock().await.next().await {
             tracing::debug!("received ws message");
             match msg {
                 Ok(tungstenite::Message::Binary(bytes)) => {
-                    let (msg, _size): (Message, usize) =
+                    let (msg, len): (Message, usize) =
                         bincode::serde::decode_from_slice(&bytes, bincode::config::standard())?;
+                    let size = bytes.len() as u64;
+                    let signed = Self::frame_signature(&bytes, len);
+                    let forged = signed.as_ref().is_some_and(|(digest, signature)| {
+                        !verify_message(self.peer_id, *digest, signature)
+                    });
+                    if forged {
+                        return Err(RunError::BadSignature(self.peer_id));
+                    }
 
                     match msg {
+                        Message::Credit { bytes } => self.send_window.credit(bytes),
                         Message::BatchSyncResponse(resp) => {
                             let req_id = resp.req_id;
                             if let Some(waiting) = self.pending.lock().await.remove(&req_id) {
                                 tracing::info!("dispatching to waiter {:?}", req_id);
+                                self.grant(self.receive_window.consume(size)).await;
                                 let result = waiting.send(resp);
                                 debug_assert!(result.is_ok());
                                 if result.is_err() {
@@ -92,12 +170,15 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
                                 tracing::info!("dispatching to inbound channel {:?}", resp.req_id);
                                 self.inbound_writer
                                     .clone()
-                                    .send(Message::BatchSyncResponse(resp))
+                                    .send((Message::BatchSyncResponse(resp), size, signed))
                                     .await?;
                             }
                         }
                         other => {
-                            self.inbound_writer.clone().send(other).await?;
+                            self.inbound_writer
+                                .clone()
+                                .send((other, size, signed))
+                                .await?;
                         }
                     }
                 }
@@ -132,12 +213,95 @@ impl<T: AsyncRead + AsyncWrite + Unpin> WebSocket<T> {
 
         Ok(())
     }
+
+    /// The signature at the end of `frame`, after the `len` bytes of its message,
+    /// along with the message's digest, if the frame is signed.
+    fn frame_signature(frame: &[u8], len: usize) -> Option<Signed> {
+        let (message, signature) = frame.split_at(len);
+        (!signature.is_empty()).then(|| (Digest::hash(message), signature.to_vec()))
+    }
+
+    /// Encode `message`, signed if the connection has a key, waiting for the peer's
+    /// credit to send it unless it is credit itself.
+    async fn encode_for_send(
+        &self,
+        message: &Message,
+    ) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        let mut bytes = bincode::serde::encode_to_vec(message, bincode::config::standard())?;
+        if let Some(key) = &self.key {
+            let signature = sign_message(key, Digest::hash(&bytes));
+            bytes.extend_from_slice(&signature);
+        }
+        if !matches!(message, Message::Credit { .. }) {
+            self.send_window.reserve(bytes.len() as u64).await;
+        }
+        Ok(bytes)
+    }
+
+    /// Send `message`, after waiting for the peer's credit.
+    async fn send_message(&self, message: &Message) -> Result<(), SendError> {
+        tracing::debug!("sending outbound message id {:?}", message.request_id());
+        let bytes = self.encode_for_send(message).await?;
+        self.outbound
+            .lock()
+            .await
+            .send(tungstenite::Message::Binary(bytes.into()))
+            .await?;
+        Ok(())
+    }
+
+    /// Give `credit` back to the peer, if there is any.
+    async fn grant(&self, credit: Option<u64>) {
+        let Some(bytes) = credit else {
+            return;
+        };
+        if let Err(e) = self.send_message(&Message::Credit { bytes }).await {
+            tracing::warn!("failed to grant {bytes} bytes of credit: {e}");
+        }
+    }
+
+    /// Take the next inbound message, giving the peer credit for it.
+    ///
+    /// Credit is given back a quarter of the window at a time, or all at once if
+    /// nothing else is waiting, so a peer isn't kept waiting on an idle receiver.
+    async fn next_inbound(&self) -> Result<Message, RecvError> {
+        tracing::debug!("Waiting for inbound message");
+        let mut chan = self.inbound_reader.lock().await;
+        let (msg, size, signed) = match chan.try_next() {
+            Ok(next) => next.ok_or(RecvError::ReadFromClosed)?,
+            Err(_empty) => {
+                self.grant(self.receive_window.flush()).await;
+                chan.next().await.ok_or(RecvError::ReadFromClosed)?
+            }
+        };
+        drop(chan);
+        *self
+            .last_signature
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner) = signed;
+        self.grant(self.receive_window.consume(size)).await;
+        tracing::info!("Received inbound message id {:?}", msg.request_id());
+        Ok(msg)
+    }
+
+    /// The signature of `message`, if it's the message last received and was signed.
+    fn signature_of(&self, message: &Message) -> Option<Vec<u8>> {
+        let last = self
+            .last_signature
+            .lock()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+        let (digest, signature) = last.as_ref()?;
+        let bytes = bincode::serde::encode_to_vec(message, bincode::config::standard()).ok()?;
+        (Digest::hash(&bytes) == *digest).then(|| signature.clone())
+    }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin> Clone for WebSocket<T> {
     fn clone(&self) -> Self {
         Self {
             peer_id: self.peer_id,
+            key: self.key.clone(),
+            last_signature: self.last_signature.clone(),
             req_id_counter: self.req_id_counter.clone(),
             timeout: self.timeout,
             ws_reader: self.ws_reader.clone(),
@@ -145,6 +309,8 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Clone for WebSocket<T> {
             pending: self.pending.clone(),
             inbound_writer: self.inbound_writer.clone(),
             inbound_reader: self.inbound_reader.clone(),
+            send_window: self.send_window.clone(),
+            receive_window: self.receive_window.clone(),
         }
     }
 }
@@ -177,30 +343,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Connection<Local> for WebSocket<T> {
     }
 
     fn send(&self, message: Message) -> LocalBoxFuture<'_, Result<(), Self::SendError>> {
-        async move {
-            tracing::debug!("sending outbound message id {:?}", message.request_id());
-            self.outbound
-                .lock()
-                .await
-                .send(tungstenite::Message::Binary(
-                    bincode::serde::encode_to_vec(&message, bincode::config::standard())?.into(),
-                ))
-                .await?;
-
-            Ok(())
-        }
-        .boxed_local()
+        async move { self.send_message(&message).await }.boxed_local()
     }
 
     fn recv(&self) -> LocalBoxFuture<'_, Result<Message, Self::RecvError>> {
-        async {
-            tracing::debug!("Waiting for inbound message");
-            let mut chan = self.inbound_reader.lock().await;
-            let msg = chan.next().await.ok_or(RecvError::ReadFromClosed)?;
-            tracing::info!("Received inbound message id {:?}", msg.request_id());
-            Ok(msg)
-        }
-        .boxed_local()
+        async { self.next_inbound().await }.boxed_local()
     }
 
     fn call(
@@ -216,17 +363,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Connection<Local> for WebSocket<T> {
             let (tx, rx) = oneshot::channel();
             self.pending.lock().await.insert(req_id, tx);
 
+            let bytes = self
+                .encode_for_send(&Message::BatchSyncRequest(req))
+                .await
+                .map_err(CallError::Serialization)?;
             self.outbound
                 .lock()
                 .await
-                .send(tungstenite::Message::Binary(
-                    bincode::serde::encode_to_vec(
-                        Message::BatchSyncRequest(req),
-                        bincode::config::standard(),
-                    )
-                    .map_err(CallError::Serialization)?
-                    .into(),
-                ))
+                .send(tungstenite::Message::Binary(bytes.into()))
                 .await?;
 
             tracing::info!("sent request {:?}", req_id);
@@ -251,6 +395,10 @@ impl<T: AsyncRead + AsyncWrite + Unpin> Connection<Local> for WebSocket<T> {
         }
         .boxed_local()
     }
+
+    fn sender_signature(&self, message: &Message) -> Option<Vec<u8>> {
+        self.signature_of(message)
+    }
 }
 
 impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection<Sendable> for WebSocket<T> {
@@ -281,30 +429,11 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection<Sendable> for WebSocke
     }
 
     fn send(&self, message: Message) -> BoxFuture<'_, Result<(), Self::SendError>> {
-        async move {
-            tracing::debug!("sending outbound message id {:?}", message.request_id());
-            self.outbound
-                .lock()
-                .await
-                .send(tungstenite::Message::Binary(
-                    bincode::serde::encode_to_vec(&message, bincode::config::standard())?.into(),
-                ))
-                .await?;
-
-            Ok(())
-        }
-        .boxed()
+        async move { self.send_message(&message).await }.boxed()
     }
 
     fn recv(&self) -> BoxFuture<'_, Result<Message, Self::RecvError>> {
-        async {
-            tracing::debug!("Waiting for inbound message");
-            let mut chan = self.inbound_reader.lock().await;
-            let msg = chan.next().await.ok_or(RecvError::ReadFromClosed)?;
-            tracing::info!("Received inbound message id {:?}", msg.request_id());
-            Ok(msg)
-        }
-        .boxed()
+        async { self.next_inbound().await }.boxed()
     }
 
     fn call(
@@ -320,17 +449,14 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection<Sendable> for WebSocke
             let (tx, rx) = oneshot::channel();
             self.pending.lock().await.insert(req_id, tx);
 
+            let bytes = self
+                .encode_for_send(&Message::BatchSyncRequest(req))
+                .await
+                .map_err(CallError::Serialization)?;
             self.outbound
                 .lock()
                 .await
-                .send(tungstenite::Message::Binary(
-                    bincode::serde::encode_to_vec(
-                        Message::BatchSyncRequest(req),
-                        bincode::config::standard(),
-                    )
-                    .map_err(CallError::Serialization)?
-                    .into(),
-                ))
+                .send(tungstenite::Message::Binary(bytes.into()))
                 .await?;
 
             tracing::info!("sent request {:?}", req_id);
@@ -355,6 +481,86 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection<Sendable> for WebSocke
         }
         .boxed()
     }
+
+    fn sender_signature(&self, message: &Message) -> Option<Vec<u8>> {
+        self.signature_of(message)
+    }
+}
+
+/// Run the [handshake] over a freshly opened `ws` as the holder of `key`, before it
+/// carries any [`Message`].
+///
+/// Returns the [`PeerId`] the other side proved it holds.
+///
+/// # Errors
+///
+/// * [`ConnectError`] if the peer doesn't finish the handshake within `timeout`,
+///   speaks another version of the wire format, or doesn't prove it holds the key
+///   it claims.
+///
+/// [handshake]: subduction_core::connection::handshake
+pub async fn handshake<T: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut WebSocketStream<T>,
+    timeout_after: Duration,
+    key: &SigningKey,
+) -> Result<PeerId, ConnectError> {
+    let ours = Handshake::new(key, rand::random());
+    let exchange = async {
+        send_frame(ws, ours.hello()).await?;
+        let theirs = read_hello(&next_binary(ws).await?)
+            .map_err(HandshakeError::from)?
+            .ok_or(ConnectError::Closed)?;
+        send_frame(ws, &ours.answer(&theirs)?).await?;
+        let (proof, _size): (Proof, usize) =
+            bincode::serde::decode_from_slice(&next_binary(ws).await?, bincode::config::standard())?;
+        Ok(ours.verify(&theirs, &proof)?)
+    };
+    timeout(timeout_after, Box::pin(exchange))
+        .await
+        .map_err(|TimedOut| ConnectError::Timeout)?
+}
+
+/// Decode the other side's [`Hello`], checking its version before the rest, whose
+/// layout is the version's. Returns `None` if `bytes` aren't a [`Hello`].
+///
+/// # Errors
+///
+/// * [`VersionMismatch`] if it speaks another version.
+pub fn read_hello(bytes: &[u8]) -> Result<Option<Hello>, VersionMismatch> {
+    let Ok((version, _size)) =
+        bincode::serde::decode_from_slice::<u32, _>(bytes, bincode::config::standard())
+    else {
+        return Ok(None);
+    };
+    check_version(PROTOCOL_VERSION, version)?;
+    Ok(bincode::serde::decode_from_slice(bytes, bincode::config::standard())
+        .ok()
+        .map(|(hello, _size)| hello))
+}
+
+async fn send_frame<T: AsyncRead + AsyncWrite + Unpin, F: serde::Serialize>(
+    ws: &mut WebSocketStream<T>,
+    frame: &F,
+) -> Result<(), ConnectError> {
+    let bytes = bincode::serde::encode_to_vec(frame, bincode::config::standard())?;
+    ws.send(tungstenite::Message::Binary(bytes.into())).await?;
+    Ok(())
+}
+
+/// The next binary frame from `ws`, skipping control frames.
+async fn next_binary<T: AsyncRead + AsyncWrite + Unpin>(
+    ws: &mut WebSocketStream<T>,
+) -> Result<Vec<u8>, ConnectError> {
+    while let Some(frame) = ws.next().await {
+        match frame? {
+            tungstenite::Message::Binary(bytes) => return Ok(bytes.to_vec()),
+            tungstenite::Message::Ping(_)
+            | tungstenite::Message::Pong(_)
+            | tungstenite::Message::Frame(_) => {}
+            tungstenite::Message::Text(_) | tungstenite::Message::Close(_) => break,
+        }
+    }
+    Err(ConnectError::Closed)
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -1,21 +1,43 @@
-use async_tungstenite::tokio::accept_async;
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, sync::OnceLock, time::Duration};
+use async_tungstenite::tokio::{accept_async, connect_async};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    sync::OnceLock,
+    time::{Duration, SystemTime},
+};
 use testresult::TestResult;
 
 use arbitrary::{Arbitrary, Unstructured};
+use nonempty::nonempty;
 use rand::Rng;
 use sedimentree_core::{
     future::Sendable,
     storage::{MemoryStorage, Storage},
-    Blob, BlobMeta, Digest, LooseCommit, Sedimentree,
+    Blob, BlobMeta, Chunk, Depth, Digest, LooseCommit, Sedimentree,
 };
 use subduction_core::{
-    connection::{message::Message, Connection},
+    connection::{
+        handshake::{
+            verify_message, HandshakeError, Hello, SigningKey, VersionMismatch, PROTOCOL_VERSION,
+        },
+        message::Message,
+        Connection,
+    },
+    lifecycle::{LifecyclePolicy, LifecycleRules},
     peer::id::PeerId,
+    sync::{
+        bootstrap::{BootstrapPhase, BootstrapProgress, ProgressSink},
+        membership::{Members, Membership},
+    },
     Subduction,
 };
-use subduction_websocket::tokio::{client::TokioWebSocketClient, server::TokioWebSocketServer};
+use subduction_websocket::{
+    error::ConnectError,
+    tokio::{client::TokioWebSocketClient, server::TokioWebSocketServer},
+};
 use tokio::{net::TcpListener, sync::oneshot};
+use tungstenite::Message as WsMessage;
 
 static TRACING: OnceLock<()> = OnceLock::new();
 
@@ -42,11 +64,18 @@ async fn rend_receive() -> TestResult {
             let server_ws = TokioWebSocketServer::new(
                 bound,
                 Duration::from_secs(5),
-                PeerId::new([0; 32]),
+                &SigningKey::from_bytes(&[0; 32]),
                 ws_stream,
             )
+            .await?
             .start();
 
+            // Each side knows the other by the key it proved it holds
+            assert_eq!(
+                server_ws.peer_id(),
+                PeerId::new(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes())
+            );
+
             let msg = server_ws.recv().await?;
             tracing::info!("Server received: {msg:?}");
             tx.send(msg).unwrap();
@@ -56,9 +85,18 @@ async fn rend_receive() -> TestResult {
     });
 
     let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
-    let client_ws = TokioWebSocketClient::new(uri, Duration::from_secs(5), PeerId::new([1; 32]))
-        .await?
-        .start();
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+
+    assert_eq!(
+        client_ws.peer_id(),
+        PeerId::new(SigningKey::from_bytes(&[0; 32]).verifying_key().to_bytes())
+    );
 
     let expected = Message::BlobsRequest(Vec::new());
     client_ws.send(expected).await?;
@@ -67,6 +105,95 @@ async fn rend_receive() -> TestResult {
     Ok(())
 }
 
+#[tokio::test]
+async fn signs_each_message() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let (tcp, _peer) = listener.accept().await?;
+        let ws_stream = accept_async(tcp).await?;
+        let server_ws = TokioWebSocketServer::new(
+            bound,
+            Duration::from_secs(5),
+            &SigningKey::from_bytes(&[0; 32]),
+            ws_stream,
+        )
+        .await?
+        .start();
+
+        let msg = server_ws.recv().await?;
+        let signature = server_ws.sender_signature(&msg);
+        let other = server_ws.sender_signature(&Message::BlobsResponse(Vec::new()));
+        Ok::<_, anyhow::Error>((msg, signature, other))
+    });
+
+    let uri = format!("ws://{bound}").parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+    let sent = Message::BlobsRequest(vec![Digest::from([3; 32])]);
+    client_ws.send(sent.clone()).await?;
+
+    let (received, signature, other) = server.await??;
+    assert_eq!(received, sent);
+    let bytes = bincode::serde::encode_to_vec(&sent, bincode::config::standard())?;
+    let client = PeerId::new(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes());
+    let signature = signature.ok_or("the message wasn't signed")?;
+    assert!(verify_message(client, Digest::hash(&bytes), &signature));
+    assert_eq!(other, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_another_version() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+
+    let server = tokio::spawn(async move {
+        let (tcp, _peer) = listener.accept().await?;
+        let ws_stream = accept_async(tcp).await?;
+        let refused = TokioWebSocketServer::new(
+            bound,
+            Duration::from_secs(5),
+            &SigningKey::from_bytes(&[0; 32]),
+            ws_stream,
+        )
+        .await
+        .err();
+        Ok::<_, anyhow::Error>(refused)
+    });
+
+    let (mut client, _resp) = connect_async(format!("ws://{bound}")).await?;
+    let newer = Hello {
+        version: PROTOCOL_VERSION + 1,
+        peer_id: PeerId::new([1; 32]),
+        nonce: [0; 32],
+    };
+    let bytes = bincode::serde::encode_to_vec(newer, bincode::config::standard())?;
+    client.send(WsMessage::Binary(bytes.into())).await?;
+
+    let refused = server.await??;
+    assert!(matches!(
+        refused,
+        Some(ConnectError::Handshake(HandshakeError::Version(VersionMismatch { theirs, .. })))
+            if theirs == PROTOCOL_VERSION + 1
+    ));
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn batch_sync() -> TestResult {
     init_tracing();
@@ -117,9 +244,10 @@ async fn batch_sync() -> TestResult {
             let server_ws = TokioWebSocketServer::new(
                 bound,
                 Duration::from_secs(5),
-                PeerId::new([0; 32]),
+                &SigningKey::from_bytes(&[0; 32]),
                 ws_stream,
             )
+            .await?
             .start();
 
             inner_server.register(server_ws).await?;
@@ -147,9 +275,13 @@ async fn batch_sync() -> TestResult {
     ));
 
     let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
-    let client_ws = TokioWebSocketClient::new(uri, Duration::from_secs(5), PeerId::new([1; 32]))
-        .await?
-        .start();
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
 
     client.register(client_ws).await?;
     rx.await.unwrap();
@@ -189,3 +321,754 @@ async fn batch_sync() -> TestResult {
 
     Ok(())
 }
+
+#[derive(Debug, Default)]
+struct Progress(std::sync::Mutex<Vec<BootstrapProgress>>);
+
+impl ProgressSink for Progress {
+    fn progress(&self, progress: BootstrapProgress) {
+        self.0.lock().expect("not poisoned").push(progress);
+    }
+}
+
+/// A chunk of its own contents whose head lands at `depth`.
+fn chunk_at(depth: u32) -> Option<(Chunk, Blob)> {
+    let head = (0..=u16::MAX)
+        .map(|n| Digest::hash(&n.to_le_bytes()))
+        .find(|digest| Depth::from(digest) == Depth(depth))?;
+    let blob = Blob::new(depth.to_le_bytes().to_vec());
+    Some((
+        Chunk::new(head, nonempty![Digest::hash(&[0])], Vec::new(), blob.meta()),
+        blob,
+    ))
+}
+
+#[tokio::test]
+async fn backfills_older_strata_after_the_response() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+
+    let chunks = [0, 1, 2]
+        .into_iter()
+        .map(chunk_at)
+        .collect::<Option<Vec<_>>>()
+        .ok_or("no digest at the wanted depth")?;
+    let server_storage = MemoryStorage::default();
+    for (chunk, blob) in &chunks {
+        <MemoryStorage as Storage<Sendable>>::save_chunk(&server_storage, chunk.clone()).await?;
+        <MemoryStorage as Storage<Sendable>>::save_blob(&server_storage, blob.clone()).await?;
+    }
+    let sed_id = sedimentree_core::SedimentreeId::new([0u8; 32]);
+    let server_tree = Sedimentree::new(
+        chunks.iter().map(|(chunk, _)| chunk.clone()).collect(),
+        vec![],
+    );
+    let server = Arc::new(
+        Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+            HashMap::from_iter([(sed_id, server_tree)]),
+            server_storage,
+            HashMap::new(),
+        ),
+    );
+
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn({
+        let inner_server = server.clone();
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let ws_stream = accept_async(tcp).await?;
+
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[0; 32]),
+                ws_stream,
+            )
+            .await?
+            .start();
+
+            inner_server.register(server_ws).await?;
+            tx.send(()).unwrap();
+            inner_server.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let progress = Arc::new(Progress::default());
+    let client = Arc::new(
+        Subduction::new(
+            HashMap::from_iter([(sed_id, Sedimentree::default())]),
+            MemoryStorage::default(),
+            HashMap::new(),
+        )
+        .with_progress(progress.clone()),
+    );
+    let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+    client.register(client_ws).await?;
+    rx.await.unwrap();
+    tokio::spawn({
+        let inner_client = client.clone();
+        async move {
+            inner_client.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    client.request_all_batch_sync_all(None).await?;
+    for _ in 0..100 {
+        if client.get_chunks(sed_id).await.unwrap_or_default().len() == chunks.len() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut held = client.get_chunks(sed_id).await.unwrap_or_default();
+    held.sort_by_key(Chunk::depth);
+    let depths = held.iter().map(Chunk::depth).collect::<Vec<_>>();
+    assert_eq!(depths, vec![Depth(0), Depth(1), Depth(2)]);
+    for (_, blob) in &chunks {
+        assert!(client.get_local_blob(blob.meta().digest()).await?.is_some());
+    }
+
+    // The response brought the newest stratum, and the other two followed it
+    let reported = progress
+        .0
+        .lock()
+        .expect("not poisoned")
+        .iter()
+        .map(|p| (p.phase, p.chunks_applied, p.chunks_total))
+        .collect::<Vec<_>>();
+    assert_eq!(reported.first(), Some(&(BootstrapPhase::HeadsReady, 0, 1)));
+    assert_eq!(reported.last(), Some(&(BootstrapPhase::Complete, 3, 3)));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn drops_commits_whose_blob_doesnt_match() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+
+    let sed_id = sedimentree_core::SedimentreeId::new([0u8; 32]);
+    let server = Arc::new(
+        Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+            HashMap::from_iter([(sed_id, Sedimentree::default())]),
+            MemoryStorage::default(),
+            HashMap::new(),
+        ),
+    );
+
+    tokio::spawn({
+        let inner_server = server.clone();
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let ws_stream = accept_async(tcp).await?;
+
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[0; 32]),
+                ws_stream,
+            )
+            .await?
+            .start();
+
+            inner_server.register(server_ws).await?;
+            inner_server.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+
+    // A commit naming one blob but sent with another, then an intact one
+    let named = Blob::new(b"named".to_vec());
+    let forged = LooseCommit::new(
+        Digest::hash(b"forged"),
+        vec![],
+        BlobMeta::new(named.as_slice()),
+    );
+    client_ws
+        .send(Message::LooseCommit {
+            id: sed_id,
+            commit: forged.clone(),
+            blob: Blob::new(b"other".to_vec()),
+        })
+        .await?;
+    let intact = LooseCommit::new(
+        Digest::hash(b"intact"),
+        vec![],
+        BlobMeta::new(named.as_slice()),
+    );
+    client_ws
+        .send(Message::LooseCommit {
+            id: sed_id,
+            commit: intact.clone(),
+            blob: named,
+        })
+        .await?;
+
+    // Messages are handled in order, so once the intact commit is in, the forged one
+    // has been dropped
+    let stored = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let commits = server.get_commits(sed_id).await.unwrap_or_default();
+            if commits.contains(&intact) {
+                return commits;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(stored, vec![intact]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_documents_to_non_members() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+
+    let blob1 = Blob::arbitrary(&mut Unstructured::new(&rand::rng().random::<[u8; 64]>()))?;
+    let blob2 = Blob::arbitrary(&mut Unstructured::new(&rand::rng().random::<[u8; 64]>()))?;
+
+    let commit_digest1 =
+        Digest::arbitrary(&mut Unstructured::new(&rand::rng().random::<[u8; 32]>()))?;
+    let commit1 = LooseCommit::new(commit_digest1, vec![], BlobMeta::new(blob1.as_slice()));
+
+    let commit_digest2 =
+        Digest::arbitrary(&mut Unstructured::new(&rand::rng().random::<[u8; 32]>()))?;
+    let commit2 = LooseCommit::new(commit_digest2, vec![], BlobMeta::new(blob2.as_slice()));
+
+    let server_storage = MemoryStorage::default();
+    <MemoryStorage as Storage<Sendable>>::save_loose_commit(&server_storage, commit1.clone())
+        .await?;
+    <MemoryStorage as Storage<Sendable>>::save_blob(&server_storage, blob1.clone()).await?;
+
+    let sed_id = sedimentree_core::SedimentreeId::new([0u8; 32]);
+
+    // Only the holder of key 1 may sync the document, and the client holds key 2
+    let member = PeerId::new(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes());
+    let server = Arc::new(
+        Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+            HashMap::from_iter([(sed_id, Sedimentree::new(vec![], vec![commit1.clone()]))]),
+            server_storage,
+            HashMap::new(),
+        )
+        .with_membership(Arc::new(Members::from_iter([(sed_id, member)]))),
+    );
+
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn({
+        let inner_server = server.clone();
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let ws_stream = accept_async(tcp).await?;
+
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[0; 32]),
+                ws_stream,
+            )
+            .await?
+            .start();
+
+            inner_server.register(server_ws).await?;
+            tx.send(()).unwrap();
+            inner_server.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let client_storage = MemoryStorage::default();
+    <MemoryStorage as Storage<Sendable>>::save_loose_commit(&client_storage, commit2.clone())
+        .await?;
+    <MemoryStorage as Storage<Sendable>>::save_blob(&client_storage, blob2.clone()).await?;
+
+    let client = Arc::new(Subduction::new(
+        HashMap::from_iter([(sed_id, Sedimentree::new(vec![], vec![commit2.clone()]))]),
+        client_storage,
+        HashMap::new(),
+    ));
+
+    let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[2; 32]),
+    )
+    .await?
+    .start();
+
+    client.register(client_ws).await?;
+    rx.await.unwrap();
+
+    tokio::spawn({
+        let inner_client = client.clone();
+        async move {
+            inner_client.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    // The server drops the request unanswered, so the sync times out
+    let synced = client
+        .request_all_batch_sync_all(Some(Duration::from_millis(500)))
+        .await
+        .unwrap_or(false);
+    assert!(!synced);
+
+    assert_eq!(server.get_commits(sed_id).await, Some(vec![commit1]));
+    assert_eq!(client.get_commits(sed_id).await, Some(vec![commit2]));
+
+    Ok(())
+}
+
+/// Members that can change while the server runs.
+#[derive(Debug, Default)]
+struct SharedMembers(std::sync::Mutex<Members>);
+
+impl Membership for SharedMembers {
+    fn is_member(&self, peer: PeerId, id: sedimentree_core::SedimentreeId) -> bool {
+        self.0
+            .lock()
+            .is_ok_and(|members| members.is_member(peer, id))
+    }
+}
+
+#[tokio::test]
+async fn refuses_blobs_to_removed_members() -> TestResult {
+    init_tracing();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let bound = listener.local_addr()?;
+
+    let blob = Blob::new(vec![7; 64]);
+    let commit = LooseCommit::new(
+        Digest::from([1; 32]),
+        vec![],
+        BlobMeta::new(blob.as_slice()),
+    );
+    let server_storage = MemoryStorage::default();
+    <MemoryStorage as Storage<Sendable>>::save_loose_commit(&server_storage, commit.clone())
+        .await?;
+    <MemoryStorage as Storage<Sendable>>::save_blob(&server_storage, blob.clone()).await?;
+
+    let sed_id = sedimentree_core::SedimentreeId::new([0u8; 32]);
+    let member = PeerId::new(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes());
+    let members = Arc::new(SharedMembers::default());
+    if let Ok(mut listed) = members.0.lock() {
+        listed.insert(sed_id, member);
+    }
+    let server = Arc::new(
+        Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+            HashMap::from_iter([(sed_id, Sedimentree::new(vec![], vec![commit]))]),
+            server_storage,
+            HashMap::new(),
+        )
+        .with_membership(members.clone()),
+    );
+    tokio::spawn({
+        let inner_server = server.clone();
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[0; 32]),
+                accept_async(tcp).await?,
+            )
+            .await?
+            .start();
+            inner_server.register(server_ws).await?;
+            inner_server.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let uri = format!("ws://{bound}").parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+    let digest = blob.meta().digest();
+
+    client_ws.send(Message::BlobsRequest(vec![digest])).await?;
+    assert_eq!(
+        client_ws.recv().await?,
+        Message::BlobsResponse(vec![blob.clone()])
+    );
+
+    // Once removed, the peer is answered as if the blob weren't held
+    if let Ok(mut listed) = members.0.lock() {
+        listed.remove(sed_id, member);
+    }
+    client_ws.send(Message::BlobsRequest(vec![digest])).await?;
+    assert_eq!(client_ws.recv().await?, Message::BlobsResponse(vec![]));
+    client_ws
+        .send(Message::BlobRangeRequest {
+            digest,
+            offset: 0,
+            length: 64,
+        })
+        .await?;
+    assert_eq!(
+        client_ws.recv().await?,
+        Message::BlobRangeNotFound { digest }
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn sends_wait_for_credit() -> TestResult {
+    init_tracing();
+
+    let addr: SocketAddr = "127.0.0.1:0".parse()?;
+    let listener = TcpListener::bind(addr).await?;
+    let bound: SocketAddr = listener.local_addr()?;
+    let (tx, rx) = oneshot::channel();
+
+    tokio::spawn({
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let ws_stream = accept_async(tcp).await?;
+
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[0; 32]),
+                ws_stream,
+            )
+            .await?
+            .with_receive_window(64)
+            .start();
+
+            let mut received = Vec::new();
+            for _ in 0..4 {
+                received.push(server_ws.recv().await?);
+            }
+            tx.send(received).unwrap();
+
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let uri = format!("ws://{}:{}", bound.ip(), bound.port()).parse()?;
+    let client_ws = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[1; 32]),
+    )
+    .await?
+    .start();
+    while client_ws.flow_stats().window.is_none() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(client_ws.flow_stats().window, Some(64));
+
+    // Each request is 66 bytes, more than the window, so each waits for the last
+    let sent = (0..4u8)
+        .map(|n| Message::BlobsRequest(vec![Digest::from([n; 32]), Digest::from([n; 32])]))
+        .collect::<Vec<_>>();
+    for message in &sent {
+        client_ws.send(message.clone()).await?;
+    }
+
+    assert_eq!(rx.await?, sent);
+    assert!(client_ws.flow_stats().throttled >= 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn fetches_the_rest_of_a_blob_once_a_peer_goes_away() -> TestResult {
+    init_tracing();
+
+    let blob = Blob::new((0..=255u8).cycle().take(10 * 1024).collect());
+    let meta = BlobMeta::new(blob.as_slice());
+    let holding = || async {
+        let storage = MemoryStorage::default();
+        <MemoryStorage as Storage<Sendable>>::save_blob(&storage, blob.clone()).await?;
+        Ok::<_, anyhow::Error>(storage)
+    };
+
+    // Both hold the blob, but the second never answers
+    let (answering, answering_addr) = serve_ranges(holding().await?, 1, true).await?;
+    let (silent, silent_addr) = serve_ranges(holding().await?, 2, false).await?;
+    let (lacking, lacking_addr) = serve_ranges(MemoryStorage::default(), 3, true).await?;
+
+    let client = Arc::new(Subduction::<Sendable, MemoryStorage, _>::new(
+        HashMap::new(),
+        MemoryStorage::default(),
+        HashMap::new(),
+    ));
+    let mut conns = Vec::new();
+    for (addr, server) in [(answering_addr, &answering), (silent_addr, &silent)] {
+        let uri = format!("ws://{addr}").parse()?;
+        let conn = TokioWebSocketClient::new(
+            uri,
+            Duration::from_secs(5),
+            &SigningKey::from_bytes(&[9; 32]),
+        )
+        .await?
+        .start();
+        conns.push(client.register(conn).await?.1);
+        while server.peer_ids().await.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+    tokio::spawn({
+        let inner_client = client.clone();
+        async move {
+            inner_client.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    assert!(client.fetch_blob_from_peers(meta, 1024).await?);
+    let half = loop {
+        let received = client.pending_blob_downloads().await;
+        if received.get(&meta.digest()) == Some(&(5 * 1024)) {
+            break received;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    assert_eq!(half.len(), 1);
+
+    // The ranges left waiting on the silent peer go to the one still connected
+    assert!(client.disconnect(&conns[1]).await?);
+    for _ in 0..100 {
+        if client.get_local_blob(meta.digest()).await?.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        client.get_local_blob(meta.digest()).await?,
+        Some(blob.clone())
+    );
+    assert!(client.pending_blob_downloads().await.is_empty());
+
+    // A peer without the blob says so, and the fetch is dropped with no one left
+    let other = Blob::new(vec![7; 10 * 1024]);
+    let other_meta = BlobMeta::new(other.as_slice());
+    assert!(client.disconnect(&conns[0]).await?);
+    let uri = format!("ws://{lacking_addr}").parse()?;
+    let conn = TokioWebSocketClient::new(
+        uri,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[9; 32]),
+    )
+    .await?
+    .start();
+    client.register(conn).await?;
+    while lacking.peer_ids().await.is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(client.fetch_blob_from_peers(other_meta, 1024).await?);
+    for _ in 0..100 {
+        if client.pending_blob_downloads().await.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(client.pending_blob_downloads().await.is_empty());
+    assert_eq!(client.get_local_blob(other_meta.digest()).await?, None);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn passes_access_requests_on_to_other_peers() -> TestResult {
+    init_tracing();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let bound = listener.local_addr()?;
+    let relay = Arc::new(
+        Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+            HashMap::new(),
+            MemoryStorage::default(),
+            HashMap::new(),
+        ),
+    );
+    tokio::spawn({
+        let inner_relay = relay.clone();
+        async move {
+            for _ in 0..2 {
+                let (tcp, _peer) = listener.accept().await?;
+                let server_ws = TokioWebSocketServer::new(
+                    bound,
+                    Duration::from_secs(5),
+                    &SigningKey::from_bytes(&[0; 32]),
+                    accept_async(tcp).await?,
+                )
+                .await?
+                .start();
+                inner_relay.register(server_ws).await?;
+            }
+            inner_relay.run().await?;
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+
+    let mut peers = Vec::new();
+    for key in [1, 2] {
+        let uri = format!("ws://{bound}").parse()?;
+        let conn = TokioWebSocketClient::new(
+            uri,
+            Duration::from_secs(5),
+            &SigningKey::from_bytes(&[key; 32]),
+        )
+        .await?
+        .start();
+        peers.push(conn);
+    }
+    while relay.peer_ids().await.len() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let request = Message::AccessRequest {
+        id: sedimentree_core::SedimentreeId::new([3; 32]),
+        request: b"let me in".to_vec(),
+    };
+    peers[0].send(request.clone()).await?;
+    let passed_on = tokio::time::timeout(Duration::from_secs(5), peers[1].recv()).await??;
+    assert_eq!(passed_on, request);
+
+    // Nor is it sent back to the requester, or passed on a second time
+    peers[0].send(request).await?;
+    let recv = |peer| {
+        tokio::time::timeout(Duration::from_millis(200), async move {
+            Connection::<Sendable>::recv(peer).await
+        })
+    };
+    assert!(recv(&peers[0]).await.is_err());
+    assert!(recv(&peers[1]).await.is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn deletes_expired_documents_from_storage() -> TestResult {
+    init_tracing();
+
+    let (only_idle, shared) = (Blob::new(b"idle".to_vec()), Blob::new(b"shared".to_vec()));
+    let commit = |name: &[u8], blob: &Blob| LooseCommit::new(Digest::hash(name), vec![], blob.meta());
+    let idle_commits = vec![commit(b"idle", &only_idle), commit(b"idle shared", &shared)];
+    let busy_commits = vec![commit(b"busy shared", &shared)];
+
+    let storage = MemoryStorage::default();
+    for blob in [&only_idle, &shared] {
+        <MemoryStorage as Storage<Sendable>>::save_blob(&storage, blob.clone()).await?;
+    }
+    for commit in idle_commits.iter().chain(&busy_commits) {
+        <MemoryStorage as Storage<Sendable>>::save_loose_commit(&storage, commit.clone()).await?;
+    }
+    let (idle, busy) = (
+        sedimentree_core::SedimentreeId::new([1; 32]),
+        sedimentree_core::SedimentreeId::new([2; 32]),
+    );
+    let relay = Subduction::<Sendable, MemoryStorage, TokioWebSocketServer>::new(
+        HashMap::from([
+            (idle, Sedimentree::new(vec![], idle_commits)),
+            (busy, Sedimentree::new(vec![], busy_commits.clone())),
+        ]),
+        storage.clone(),
+        HashMap::new(),
+    );
+
+    let month = Duration::from_secs(30 * 24 * 60 * 60);
+    let rules = LifecycleRules {
+        default: LifecyclePolicy {
+            expire_after_idle: Some(month),
+            ..LifecyclePolicy::default()
+        },
+        documents: HashMap::from([(busy, LifecyclePolicy::default())]),
+        ..LifecycleRules::default()
+    };
+    let start = SystemTime::UNIX_EPOCH;
+    relay
+        .run_lifecycle::<MemoryStorage>(&rules, start, None)
+        .await?;
+    relay
+        .run_lifecycle::<MemoryStorage>(&rules, start + month, None)
+        .await?;
+
+    assert_eq!(relay.sedimentree_ids().await, vec![busy]);
+    let load_blob = |blob: &Blob| {
+        <MemoryStorage as Storage<Sendable>>::load_blob(&storage, blob.meta().digest())
+    };
+    assert_eq!(load_blob(&only_idle).await?, None);
+    assert_eq!(load_blob(&shared).await?, Some(shared.clone()));
+    assert_eq!(
+        <MemoryStorage as Storage<Sendable>>::load_loose_commits(&storage).await?,
+        busy_commits
+    );
+
+    Ok(())
+}
+
+/// Accept one connection from a peer with a server holding `key` and `storage`,
+/// handling the peer's messages only if `answers`.
+async fn serve_ranges(
+    storage: MemoryStorage,
+    key: u8,
+    answers: bool,
+) -> anyhow::Result<(
+    Arc<Subduction<Sendable, MemoryStorage, TokioWebSocketServer>>,
+    SocketAddr,
+)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let bound = listener.local_addr()?;
+    let server = Arc::new(Subduction::new(HashMap::new(), storage, HashMap::new()));
+    tokio::spawn({
+        let inner_server = server.clone();
+        async move {
+            let (tcp, _peer) = listener.accept().await?;
+            let ws_stream = accept_async(tcp).await?;
+            let server_ws = TokioWebSocketServer::new(
+                bound,
+                Duration::from_secs(5),
+                &SigningKey::from_bytes(&[key; 32]),
+                ws_stream,
+            )
+            .await?
+            .start();
+            inner_server.register(server_ws).await?;
+            if answers {
+                inner_server.run().await?;
+            }
+            Ok::<(), anyhow::Error>(())
+        }
+    });
+    Ok((server, bound))
+}
@@ -0,0 +1,256 @@
+use std::{net::SocketAddr, time::Duration};
+use testresult::TestResult;
+
+use subduction_core::{
+    connection::{
+        handshake::{Handshake, Hello, Proof, SigningKey, PROTOCOL_VERSION},
+        message::Message,
+        Connection,
+    },
+    peer::id::PeerId,
+};
+use subduction_websocket::tokio::sse::TokioSseServer;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+#[tokio::test]
+async fn push_over_events_and_upload_over_post() -> TestResult {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>()?).await?;
+    let server = TokioSseServer::new(
+        listener,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[0; 32]),
+    )?
+    .start();
+    let addr = server.address();
+
+    let client = SigningKey::from_bytes(&[1; 32]);
+    let session = authenticate(addr, &client).await?;
+    assert_eq!(
+        server.authenticated().await,
+        PeerId::new(client.verifying_key().to_bytes())
+    );
+    assert_eq!(
+        server.peer_id(),
+        PeerId::new(client.verifying_key().to_bytes())
+    );
+
+    // Subscribe to the event stream, and wait for the headers so we know it's registered
+    let (_status, mut events) = subscribe(addr, &session).await?;
+
+    // Server to client
+    let pushed = Message::BlobsRequest(Vec::new());
+    server.send(pushed.clone()).await?;
+
+    let mut event = String::new();
+    events.read_line(&mut event).await?;
+    assert_eq!(event, "event: message\n");
+    let mut data = String::new();
+    events.read_line(&mut data).await?;
+    let hex_data = data
+        .strip_prefix("data: ")
+        .ok_or("missing data field")?
+        .trim_end();
+    let (received, _size): (Message, usize) =
+        bincode::serde::decode_from_slice(&hex::decode(hex_data)?, bincode::config::standard())?;
+    assert_eq!(received, pushed);
+
+    // Client to server
+    let uploaded = Message::BlobsResponse(Vec::new());
+    let body = bincode::serde::encode_to_vec(&uploaded, bincode::config::standard())?;
+    let (status, _) = post(addr, &format!("/messages?session={session}"), &body).await?;
+    assert!(status.starts_with("HTTP/1.1 204"));
+    assert_eq!(server.recv().await?, uploaded);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_another_version() -> TestResult {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>()?).await?;
+    let server = TokioSseServer::new(
+        listener,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[0; 32]),
+    )?
+    .start();
+
+    let newer = Hello {
+        version: PROTOCOL_VERSION + 1,
+        peer_id: PeerId::new([1; 32]),
+        nonce: [0; 32],
+    };
+    let hello = bincode::serde::encode_to_vec(newer, bincode::config::standard())?;
+    let (status, _) = post(server.address(), "/hello", &hello).await?;
+    assert!(status.starts_with("HTTP/1.1 400"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn refuses_unauthenticated_clients() -> TestResult {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>()?).await?;
+    let server = TokioSseServer::new(
+        listener,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[0; 32]),
+    )?
+    .start();
+    let addr = server.address();
+
+    // No session
+    let body = bincode::serde::encode_to_vec(
+        Message::BlobsResponse(Vec::new()),
+        bincode::config::standard(),
+    )?;
+    let (status, _) = post(addr, "/messages", &body).await?;
+    assert!(status.starts_with("HTTP/1.1 401"));
+
+    // A client claiming a key it can't sign with
+    let impostor = Handshake::new(&SigningKey::from_bytes(&[2; 32]), [2; 32]);
+    let claimed = Hello {
+        peer_id: PeerId::new(SigningKey::from_bytes(&[1; 32]).verifying_key().to_bytes()),
+        ..*impostor.hello()
+    };
+    let hello = bincode::serde::encode_to_vec(claimed, bincode::config::standard())?;
+    let (_, reply) = post(addr, "/hello", &hello).await?;
+    let ((theirs, _), _size): ((Hello, Proof), usize) =
+        bincode::serde::decode_from_slice(&reply, bincode::config::standard())?;
+    let forged =
+        bincode::serde::encode_to_vec(impostor.answer(&theirs)?, bincode::config::standard())?;
+    let session = hex::encode(theirs.nonce);
+    let (status, _) = post(addr, &format!("/proof?session={session}"), &forged).await?;
+    assert!(status.starts_with("HTTP/1.1 403"));
+    let (status, _) = post(addr, &format!("/messages?session={session}"), &body).await?;
+    assert!(status.starts_with("HTTP/1.1 401"));
+
+    // Once one client has authenticated, another is turned away
+    authenticate(addr, &SigningKey::from_bytes(&[1; 32])).await?;
+    assert!(authenticate(addr, &SigningKey::from_bytes(&[3; 32]))
+        .await
+        .is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn keeps_one_event_stream() -> TestResult {
+    let listener = TcpListener::bind("127.0.0.1:0".parse::<SocketAddr>()?).await?;
+    let server = TokioSseServer::new(
+        listener,
+        Duration::from_secs(5),
+        &SigningKey::from_bytes(&[0; 32]),
+    )?
+    .start();
+    let addr = server.address();
+
+    // The same client, authenticated twice, as from two tabs
+    let client = SigningKey::from_bytes(&[1; 32]);
+    let first = authenticate(addr, &client).await?;
+    let second = authenticate(addr, &client).await?;
+
+    let (status, stream) = subscribe(addr, &first).await?;
+    assert!(status.starts_with("HTTP/1.1 200"));
+
+    // Another session can't take over the stream while it's open
+    let (status, _) = subscribe(addr, &second).await?;
+    assert!(status.starts_with("HTTP/1.1 409"));
+
+    // The same session reconnecting replaces it
+    let (status, stream) = {
+        drop(stream);
+        subscribe(addr, &first).await?
+    };
+    assert!(status.starts_with("HTTP/1.1 200"));
+
+    // Once it's closed, another session may open one
+    drop(stream);
+    let mut status = String::new();
+    for _ in 0..50 {
+        let (opened, _stream) = subscribe(addr, &second).await?;
+        status = opened;
+        if !status.starts_with("HTTP/1.1 409") {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(status.starts_with("HTTP/1.1 200"));
+
+    Ok(())
+}
+
+/// Open the event stream of `session`, returning the status line and the stream
+/// past its headers.
+async fn subscribe(
+    addr: SocketAddr,
+    session: &str,
+) -> Result<(String, BufReader<TcpStream>), std::io::Error> {
+    let mut events = BufReader::new(TcpStream::connect(addr).await?);
+    events
+        .get_mut()
+        .write_all(
+            format!("GET /events?session={session} HTTP/1.1\r\nAccept: text/event-stream\r\n\r\n")
+                .as_bytes(),
+        )
+        .await?;
+    let mut status = String::new();
+    events.read_line(&mut status).await?;
+    let mut line = String::new();
+    while events.read_line(&mut line).await? > 0 && line != "\r\n" {
+        line.clear();
+    }
+    Ok((status, events))
+}
+
+/// Run the handshake as the holder of `key`, returning the session to send requests with.
+async fn authenticate(
+    addr: SocketAddr,
+    key: &SigningKey,
+) -> Result<String, Box<dyn std::error::Error>> {
+    let ours = Handshake::new(key, rand::random());
+    let hello = bincode::serde::encode_to_vec(ours.hello(), bincode::config::standard())?;
+    let (status, reply) = post(addr, "/hello", &hello).await?;
+    assert!(status.starts_with("HTTP/1.1 200"));
+    let ((theirs, proof), _size): ((Hello, Proof), usize) =
+        bincode::serde::decode_from_slice(&reply, bincode::config::standard())?;
+    ours.verify(&theirs, &proof)?;
+
+    let session = hex::encode(theirs.nonce);
+    let proof = bincode::serde::encode_to_vec(ours.answer(&theirs)?, bincode::config::standard())?;
+    let (status, _) = post(addr, &format!("/proof?session={session}"), &proof).await?;
+    if !status.starts_with("HTTP/1.1 204") {
+        return Err(status.into());
+    }
+    Ok(session)
+}
+
+/// POST `body` to `path`, returning the status line and the response body.
+async fn post(
+    addr: SocketAddr,
+    path: &str,
+    body: &[u8],
+) -> Result<(String, Vec<u8>), std::io::Error> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream
+        .write_all(
+            format!(
+                "POST {path} HTTP/1.1\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    stream.write_all(body).await?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    let split = response
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map_or(response.len(), |at| at + 4);
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or_default().to_string();
+    Ok((status, response[split..].to_vec()))
+}
@@ -0,0 +1,416 @@
+//! Golden vectors for the wire format described in `schema/messages.md`.
+//!
+//! The vectors are for the version of the format in `tests/vectors/VERSION`. They
+//! can only be regenerated with changes once [`PROTOCOL_VERSION`] has been bumped
+//! past it, so the format never changes under the same version.
+
+use std::path::PathBuf;
+
+use nonempty::nonempty;
+use sedimentree_core::{
+    Blob, BlobMeta, Chunk, Digest, LooseCommit, SedimentreeId, SedimentreeSummary,
+};
+use subduction_core::{
+    connection::{
+        handshake::{Handshake, HandshakeError, Hello, Proof, SigningKey, PROTOCOL_VERSION},
+        message::{
+            BatchSyncRequest, BatchSyncResponse, Have, Message, RequestId, Signal, SummaryToken,
+            SyncDiff,
+        },
+    },
+    peer::id::PeerId,
+    sync::{
+        filter::CommitFilter,
+        subscription::{DocumentFilter, SubscriptionDenied},
+    },
+};
+use testresult::TestResult;
+
+fn digest(byte: u8) -> Digest {
+    Digest::from([byte; 32])
+}
+
+fn commit(byte: u8, parents: Vec<Digest>, contents: &[u8]) -> (LooseCommit, Blob) {
+    (
+        LooseCommit::new(digest(byte), parents, BlobMeta::new(contents)),
+        Blob::new(contents.to_vec()),
+    )
+}
+
+fn chunk(contents: &[u8]) -> (Chunk, Blob) {
+    (
+        Chunk::new(
+            digest(0x30),
+            nonempty![digest(0x31), digest(0x32)],
+            vec![digest(0x33)],
+            BlobMeta::new(contents),
+        ),
+        Blob::new(contents.to_vec()),
+    )
+}
+
+/// Every vector, with a description of what it covers.
+fn vectors() -> Vec<(&'static str, &'static str, Message)> {
+    let id = SedimentreeId::new([0x11; 32]);
+    let req_id = RequestId {
+        requestor: PeerId::new([0x22; 32]),
+        nonce: u128::MAX,
+    };
+    let (root, root_blob) = commit(0x01, Vec::new(), b"hello");
+    let (child, child_blob) = commit(0x02, vec![root.digest()], b"world");
+    let (chunk, chunk_blob) = chunk(b"a chunk of history");
+
+    vec![
+        (
+            "loose_commit",
+            "A commit with one parent and a 5 byte blob.",
+            Message::LooseCommit {
+                id,
+                commit: child.clone(),
+                blob: child_blob.clone(),
+            },
+        ),
+        (
+            "chunk",
+            "A chunk with a two-commit boundary and one checkpoint.",
+            Message::Chunk {
+                id,
+                chunk: chunk.clone(),
+                blob: chunk_blob.clone(),
+            },
+        ),
+        (
+            "blobs_request",
+            "A request for two blobs.",
+            Message::BlobsRequest(vec![root.blob().digest(), child.blob().digest()]),
+        ),
+        (
+            "blobs_response",
+            "An empty blob followed by a 300 byte one (a two-byte length).",
+            Message::BlobsResponse(vec![Blob::new(Vec::new()), Blob::new(vec![0xab; 300])]),
+        ),
+        (
+            "blob_range_request",
+            "A four-byte varint offset and a one-byte length.",
+            Message::BlobRangeRequest {
+                digest: digest(0x40),
+                offset: 70_000,
+                length: 250,
+            },
+        ),
+        (
+            "blob_range_response",
+            "An offset needing a full u64 varint.",
+            Message::BlobRangeResponse {
+                digest: digest(0x40),
+                offset: u64::MAX,
+                data: b"range".to_vec(),
+                proof: vec![Digest::hash(b"left"), Digest::hash(b"right")],
+            },
+        ),
+        (
+            "batch_sync_request",
+            "A request with a u128::MAX nonce, summarising one chunk and two commits, with a token and a frontier.",
+            Message::BatchSyncRequest(BatchSyncRequest {
+                id,
+                req_id,
+                sedimentree_summary: SedimentreeSummary::new(
+                    [chunk.summary().clone()].into(),
+                    [root.clone(), child.clone()].into(),
+                ),
+                summary_token: Some(SummaryToken::new(digest(0x50))),
+                have: None,
+                since: vec![digest(0x53)],
+            }),
+        ),
+        (
+            "batch_sync_response",
+            "A response to a filtered request, with one missing commit, one missing chunk, and the responder's filter.",
+            Message::BatchSyncResponse(BatchSyncResponse {
+                req_id,
+                id,
+                diff: SyncDiff {
+                    missing_commits: vec![(root.clone(), root_blob)],
+                    missing_chunks: vec![(chunk.clone(), chunk_blob.clone())],
+                },
+                summary_token: SummaryToken::new(digest(0x51)),
+                have: Some(Have {
+                    heads: vec![child.digest()],
+                    filter: CommitFilter::new([root.digest(), child.digest()].iter()),
+                    need: vec![digest(0x52)],
+                }),
+                resumed: true,
+                backfill: 1,
+            }),
+        ),
+        (
+            "relay_signal",
+            "An SDP offer for a relay to pass on.",
+            Message::RelaySignal {
+                id,
+                to: PeerId::new([0x33; 32]),
+                signal: Signal::Offer("v=0".to_string()),
+            },
+        ),
+        (
+            "signal",
+            "An ICE candidate passed on by a relay, with one optional field unset.",
+            Message::Signal {
+                id,
+                from: PeerId::new([0x22; 32]),
+                signal: Signal::IceCandidate {
+                    candidate: "candidate:1 1 udp 1 127.0.0.1 9 typ host".to_string(),
+                    sdp_mid: Some("0".to_string()),
+                    sdp_m_line_index: None,
+                },
+            },
+        ),
+        (
+            "commit_upload",
+            "The last piece of a commit's 5 byte blob, pushed 3 bytes at a time.",
+            Message::CommitUpload {
+                id,
+                commit: child.clone(),
+                offset: 3,
+                data: b"ld".to_vec(),
+                chunk_digest: Digest::hash(b"ld"),
+            },
+        ),
+        (
+            "commit_upload_ack",
+            "An acknowledgement of the first 3 bytes of a blob.",
+            Message::CommitUploadAck {
+                digest: child.blob().digest(),
+                received: 3,
+            },
+        ),
+        (
+            "credit",
+            "A grant of a 256 KiB window, a four-byte varint.",
+            Message::Credit { bytes: 262_144 },
+        ),
+        (
+            "document_ttl",
+            "The TTL of an ephemeral sedimentree, an hour.",
+            Message::DocumentTtl {
+                id,
+                ttl_secs: Some(3600),
+            },
+        ),
+        (
+            "subscribe",
+            "A subscription to one sedimentree and to those whose IDs start with 0x5a5b.",
+            Message::Subscribe {
+                filters: vec![DocumentFilter::Id(id), DocumentFilter::Prefix(vec![0x5a, 0x5b])],
+            },
+        ),
+        (
+            "subscribed",
+            "The answer to it, refusing the prefix.",
+            Message::Subscribed {
+                denied: vec![SubscriptionDenied {
+                    filter: DocumentFilter::Prefix(vec![0x5a, 0x5b]),
+                    reason: "no".to_string(),
+                }],
+            },
+        ),
+        (
+            "blob_range_not_found",
+            "The answer to a range request for a blob the sender doesn't hold.",
+            Message::BlobRangeNotFound {
+                digest: digest(0x40),
+            },
+        ),
+        (
+            "access_request",
+            "A request for access to a sedimentree, passed on as it was signed.",
+            Message::AccessRequest {
+                id,
+                request: b"let me in".to_vec(),
+            },
+        ),
+        (
+            "backfill",
+            "The chunk of an older stratum, following the response announcing it.",
+            Message::Backfill {
+                id,
+                chunk,
+                blob: chunk_blob,
+            },
+        ),
+    ]
+}
+
+/// The frames of a handshake between two fixed keys: one side's [`Hello`], and its
+/// [`Proof`] in answer to the other's.
+fn handshake() -> Result<(Hello, Proof), HandshakeError> {
+    let ours = Handshake::new(&SigningKey::from_bytes(&[1; 32]), [2; 32]);
+    let theirs = Handshake::new(&SigningKey::from_bytes(&[3; 32]), [4; 32]);
+    let proof = ours.answer(theirs.hello())?;
+    Ok((*ours.hello(), proof))
+}
+
+const HELLO: (&str, &str) = ("hello", "The first frame each side sends, for this version.");
+
+const PROOF: (&str, &str) = (
+    "proof",
+    "The second frame each side sends, signing the other's nonce and both keys.",
+);
+
+fn path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/vectors")
+        .join(format!("{name}.hex"))
+}
+
+fn version_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/vectors/VERSION")
+}
+
+fn encode<T: serde::Serialize>(message: &T) -> Result<Vec<u8>, bincode::error::EncodeError> {
+    bincode::serde::encode_to_vec(message, bincode::config::standard())
+}
+
+/// A vector's name, description, and encoding.
+type Encoded = (&'static str, &'static str, Vec<u8>);
+
+/// Every vector, encoded.
+fn encoded_vectors() -> Result<Vec<Encoded>, Box<dyn std::error::Error>> {
+    let (hello, proof) = handshake()?;
+    let mut encoded = vec![
+        (HELLO.0, HELLO.1, encode(&hello)?),
+        (PROOF.0, PROOF.1, encode(&proof)?),
+    ];
+    for (name, description, message) in vectors() {
+        encoded.push((name, description, encode(&message)?));
+    }
+    Ok(encoded)
+}
+
+/// Rewrite the vectors, as long as [`PROTOCOL_VERSION`] has been bumped if any changed.
+fn update_vectors() -> Result<(), Box<dyn std::error::Error>> {
+    let recorded = std::fs::read_to_string(version_path())?
+        .trim()
+        .parse::<u32>()?;
+    let vectors = encoded_vectors()?;
+    let changed = vectors.iter().any(|(name, description, encoded)| {
+        std::fs::read_to_string(path(name)).ok() != Some(render(description, encoded))
+    });
+    if changed && recorded == PROTOCOL_VERSION {
+        return Err(format!(
+            "the wire format changed, so bump PROTOCOL_VERSION past {recorded} first"
+        )
+        .into());
+    }
+
+    for (name, description, encoded) in vectors {
+        std::fs::write(path(name), render(description, &encoded))?;
+    }
+    std::fs::write(version_path(), format!("{PROTOCOL_VERSION}\n"))?;
+    Ok(())
+}
+
+/// Render a vector file: the description as comments, then the hex wrapped at 64 characters.
+fn render(description: &str, bytes: &[u8]) -> String {
+    let hex = hex::encode(bytes);
+    let mut out = format!("# {description}\n");
+    for line in hex.as_bytes().chunks(64) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
+    }
+    out
+}
+
+/// Parse a vector file, ignoring comments and line breaks.
+fn parse(file: &str) -> Result<Vec<u8>, hex::FromHexError> {
+    let hex = file
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<String>();
+    hex::decode(hex.trim())
+}
+
+#[test]
+fn messages_match_golden_vectors() -> TestResult {
+    if std::env::var_os("UPDATE_VECTORS").is_some() {
+        if let Err(err) = update_vectors() {
+            panic!("{err}");
+        }
+        return Ok(());
+    }
+
+    let recorded = std::fs::read_to_string(version_path())?
+        .trim()
+        .parse::<u32>()?;
+    assert_eq!(
+        recorded, PROTOCOL_VERSION,
+        "the vectors are for another version of the wire format"
+    );
+
+    for (name, _, message) in vectors() {
+        let encoded = encode(&message)?;
+        let golden = parse(&std::fs::read_to_string(path(name))?)?;
+        assert_eq!(hex::encode(&encoded), hex::encode(&golden), "{name}");
+
+        let (decoded, read): (Message, usize) =
+            bincode::serde::decode_from_slice(&golden, bincode::config::standard())?;
+        assert_eq!(read, golden.len(), "{name} has trailing bytes");
+        assert_eq!(decoded, message, "{name}");
+    }
+    Ok(())
+}
+
+#[test]
+fn handshake_matches_golden_vectors() -> TestResult {
+    // `messages_match_golden_vectors` rewrites these too
+    if std::env::var_os("UPDATE_VECTORS").is_some() {
+        return Ok(());
+    }
+
+    let (hello, proof) = handshake()?;
+
+    let golden = parse(&std::fs::read_to_string(path(HELLO.0))?)?;
+    assert_eq!(hex::encode(encode(&hello)?), hex::encode(&golden), "hello");
+    let (decoded, read): (Hello, usize) =
+        bincode::serde::decode_from_slice(&golden, bincode::config::standard())?;
+    assert_eq!(read, golden.len(), "hello has trailing bytes");
+    assert_eq!(decoded, hello, "hello");
+
+    let golden = parse(&std::fs::read_to_string(path(PROOF.0))?)?;
+    assert_eq!(hex::encode(encode(&proof)?), hex::encode(&golden), "proof");
+    let (decoded, read): (Proof, usize) =
+        bincode::serde::decode_from_slice(&golden, bincode::config::standard())?;
+    assert_eq!(read, golden.len(), "proof has trailing bytes");
+    assert_eq!(decoded, proof, "proof");
+    Ok(())
+}
+
+#[test]
+fn variant_indices_are_stable() -> TestResult {
+    let expected = [
+        ("loose_commit", 0),
+        ("chunk", 1),
+        ("blobs_request", 2),
+        ("blobs_response", 3),
+        ("blob_range_request", 4),
+        ("blob_range_response", 5),
+        ("batch_sync_request", 6),
+        ("batch_sync_response", 7),
+        ("relay_signal", 8),
+        ("signal", 9),
+        ("commit_upload", 10),
+        ("commit_upload_ack", 11),
+        ("credit", 12),
+        ("document_ttl", 13),
+        ("subscribe", 14),
+        ("subscribed", 15),
+        ("blob_range_not_found", 16),
+        ("access_request", 17),
+        ("backfill", 18),
+    ];
+    for ((name, _, message), (expected_name, index)) in vectors().iter().zip(expected) {
+        assert_eq!(*name, expected_name);
+        assert_eq!(encode(message)?.first(), Some(&index), "{name}");
+    }
+    Ok(())
+}